@@ -0,0 +1,51 @@
+// Copyright (c) RoochNetwork
+// SPDX-License-Identifier: Apache-2.0
+
+//! Compares `SMTree::get`, which returns an owned value along the way of building a proof, and
+//! `SMTree::get_with`, which lends the value, on values of 64 KiB.
+//! Run with `cargo bench --bench get_with`.
+
+use smt::{InMemoryNodeStore, SMTree};
+use std::time::{Duration, Instant};
+
+const NUM_LEAVES: usize = 1000;
+const VALUE_SIZE: usize = 64 * 1024;
+const ITERATIONS: u32 = 10;
+
+fn time(name: &str, mut f: impl FnMut()) -> Duration {
+    // Warm up.
+    f();
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    let elapsed = start.elapsed() / ITERATIONS;
+    println!("{:<10} {:>10.3?} per {} lookups", name, elapsed, NUM_LEAVES);
+    elapsed
+}
+
+fn main() {
+    let smt: SMTree<u64, Vec<u8>, _> = SMTree::from_pairs(
+        InMemoryNodeStore::default(),
+        (0..NUM_LEAVES as u64).map(|i| (i, vec![i as u8; VALUE_SIZE])),
+    )
+    .unwrap();
+
+    println!("Looking up {} values of {} bytes:", NUM_LEAVES, VALUE_SIZE);
+    let owned = time("get", || {
+        for i in 0..NUM_LEAVES as u64 {
+            let value = smt.get(i).unwrap().unwrap();
+            assert_eq!(value[0], i as u8);
+        }
+    });
+    let lent = time("get_with", || {
+        for i in 0..NUM_LEAVES as u64 {
+            let first = smt.get_with(i, |value| value[0]).unwrap().unwrap();
+            assert_eq!(first, i as u8);
+        }
+    });
+    println!(
+        "speedup    {:>10.2}x",
+        owned.as_secs_f64() / lent.as_secs_f64()
+    );
+}
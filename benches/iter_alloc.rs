@@ -0,0 +1,73 @@
+// Copyright (c) RoochNetwork
+// SPDX-License-Identifier: Apache-2.0
+
+//! Counts the allocations made by seeking an iterator, which pushes the internal nodes on the path
+//! to the starting key onto its stack, and by iterating all the leaves. The stack has room for
+//! `ROOT_NIBBLE_HEIGHT` nodes from the start, so it never accounts for more than one allocation.
+//! Run with `cargo bench --bench iter_alloc`.
+
+use smt::{InMemoryNodeStore, SMTree, ROOT_NIBBLE_HEIGHT};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const NUM_LEAVES: u64 = 100_000;
+const NUM_SEEKS: u64 = 1000;
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+fn count_allocations(f: impl FnOnce()) -> usize {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    f();
+    ALLOCATIONS.load(Ordering::Relaxed) - before
+}
+
+fn main() {
+    let smt: SMTree<u64, u64, _> = SMTree::from_pairs(
+        InMemoryNodeStore::default(),
+        (0..NUM_LEAVES).map(|i| (i, i)),
+    )
+    .unwrap();
+
+    println!(
+        "Tree of {} leaves, stack capacity of {} nodes:",
+        NUM_LEAVES, ROOT_NIBBLE_HEIGHT
+    );
+    let seeks = count_allocations(|| {
+        for i in 0..NUM_SEEKS {
+            let mut iter = smt.iter(Some(i * (NUM_LEAVES / NUM_SEEKS))).unwrap();
+            iter.next().unwrap().unwrap();
+        }
+    });
+    println!(
+        "seek       {:>10.2} allocations per seek",
+        seeks as f64 / NUM_SEEKS as f64
+    );
+    let leaves = count_allocations(|| {
+        assert_eq!(smt.iter(None).unwrap().count() as u64, NUM_LEAVES);
+    });
+    println!(
+        "iterate    {:>10.2} allocations per leaf",
+        leaves as f64 / NUM_LEAVES as f64
+    );
+}
@@ -0,0 +1,57 @@
+// Copyright (c) RoochNetwork
+// SPDX-License-Identifier: Apache-2.0
+
+//! Compares `SparseMerkleMultiProof::verify` and `verify_par` on a proof of 1000 keys.
+//! Run with `cargo bench --features rayon --bench multiproof`.
+
+use smt::{InMemoryNodeStore, SMTree};
+use std::time::{Duration, Instant};
+
+const NUM_LEAVES: usize = 100_000;
+const NUM_KEYS: usize = 1000;
+const ITERATIONS: u32 = 100;
+
+fn time(name: &str, mut f: impl FnMut()) -> Duration {
+    // Warm up.
+    f();
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    let elapsed = start.elapsed() / ITERATIONS;
+    println!("{:<12} {:>10.3?} per proof", name, elapsed);
+    elapsed
+}
+
+fn main() {
+    let smt: SMTree<u64, u64, _> = SMTree::from_pairs(
+        InMemoryNodeStore::default(),
+        (0..NUM_LEAVES as u64).map(|i| (i, i)),
+    )
+    .unwrap();
+    let root = smt.root_hash();
+    let keys = (0..NUM_KEYS as u64)
+        .map(|i| i * (NUM_LEAVES / NUM_KEYS) as u64)
+        .collect::<Vec<_>>();
+    let proof = smt.get_multiproof(keys.clone()).unwrap();
+    assert_eq!(
+        proof.verify(root, keys.clone()).unwrap(),
+        proof.verify_par(root, keys.clone()).unwrap()
+    );
+
+    println!(
+        "Verifying a proof of {} keys in a tree of {} leaves:",
+        NUM_KEYS, NUM_LEAVES
+    );
+    let sequential = time("verify", || {
+        proof.verify(root, keys.clone()).unwrap();
+    });
+    let parallel = time("verify_par", || {
+        proof.verify_par(root, keys.clone()).unwrap();
+    });
+    println!(
+        "speedup      {:>10.2}x on {} threads",
+        sequential.as_secs_f64() / parallel.as_secs_f64(),
+        rayon::current_num_threads()
+    );
+}
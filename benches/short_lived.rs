@@ -0,0 +1,53 @@
+// Copyright (c) RoochNetwork
+// SPDX-License-Identifier: Apache-2.0
+
+//! Measures the throughput of short-lived operations, whose setup cost is not amortized: iterators
+//! constructed at a starting key to read a single leaf, and single-key lookups, both descending
+//! from the root along the nibbles of a key hash.
+//! Run with `cargo bench --bench short_lived`.
+
+use smt::{InMemoryNodeStore, SMTree};
+use std::time::{Duration, Instant};
+
+const NUM_LEAVES: u64 = 100_000;
+const NUM_OPERATIONS: u64 = 10_000;
+const ITERATIONS: u32 = 5;
+
+fn time(name: &str, mut f: impl FnMut()) -> Duration {
+    // Warm up.
+    f();
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    let elapsed = start.elapsed() / ITERATIONS;
+    println!(
+        "{:<10} {:>10.3?} per {} operations, {:>10.0} operations/s",
+        name,
+        elapsed,
+        NUM_OPERATIONS,
+        NUM_OPERATIONS as f64 / elapsed.as_secs_f64()
+    );
+    elapsed
+}
+
+fn main() {
+    let smt: SMTree<u64, u64, _> = SMTree::from_pairs(
+        InMemoryNodeStore::default(),
+        (0..NUM_LEAVES).map(|i| (i, i)),
+    )
+    .unwrap();
+
+    println!("Tree of {} leaves:", NUM_LEAVES);
+    time("iter", || {
+        for i in 0..NUM_OPERATIONS {
+            let mut iter = smt.iter(Some(i % NUM_LEAVES)).unwrap();
+            iter.next().unwrap().unwrap();
+        }
+    });
+    time("get", || {
+        for i in 0..NUM_OPERATIONS {
+            assert_eq!(smt.get(i % NUM_LEAVES).unwrap(), Some(i % NUM_LEAVES));
+        }
+    });
+}
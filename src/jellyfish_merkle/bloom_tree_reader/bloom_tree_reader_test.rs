@@ -0,0 +1,81 @@
+// Copyright (c) RoochNetwork
+// SPDX-License-Identifier: Apache-2.0
+
+use super::*;
+use crate::jellyfish_merkle::{
+    commit,
+    mock_tree_store::{CountingTreeReader, MockTestStore, TestKey, TestValue},
+    test_helper::init_random_tree,
+    JellyfishMerkleTree,
+};
+use crate::EncodeToObject;
+use rand::{rngs::StdRng, SeedableRng};
+
+#[test]
+fn test_present_keys_found() {
+    let db = MockTestStore::new_test();
+    let (root, kvs) = init_random_tree(&db, 500, 59);
+    let reader = BloomTreeReader::<_>::new(db, 1000, 0.01).unwrap();
+    reader.populate::<TestKey, TestValue>(root).unwrap();
+
+    for (key, value) in &kvs {
+        let key = key.into_object();
+        assert!(reader
+            .contains_key::<TestKey, TestValue>(root, &key)
+            .unwrap());
+        assert_eq!(
+            reader.get::<TestKey, TestValue>(root, &key).unwrap(),
+            Some(value.clone().into_object())
+        );
+    }
+    assert_eq!(reader.skipped(), 0);
+}
+
+#[test]
+fn test_filter_misses_skip_reads() {
+    let db = MockTestStore::new_test();
+    let (root, _) = init_random_tree(&db, 500, 59);
+    let reader = BloomTreeReader::<_>::new(CountingTreeReader::new(db), 1000, 0.01).unwrap();
+    reader.populate::<TestKey, TestValue>(root).unwrap();
+
+    let mut rng = StdRng::from_seed([60; 32]);
+    for _ in 0..1000 {
+        let key = TestKey(HashValue::random_with_rng(&mut rng)).into_object();
+        let ruled_out = !reader.may_contain_key_hash(&key.merkle_hash());
+        let reads = reader.inner().reads();
+        assert!(!reader
+            .contains_key::<TestKey, TestValue>(root, &key)
+            .unwrap());
+        assert_eq!(reader.get::<TestKey, TestValue>(root, &key).unwrap(), None);
+        // A false positive descends the tree, a key ruled out reads nothing.
+        assert_eq!(reader.inner().reads() == reads, ruled_out);
+    }
+    // Well below the 1% false positive rate the filter is sized for.
+    assert!(reader.skipped() >= 2 * 950, "{} skipped", reader.skipped());
+}
+
+#[test]
+fn test_writes_update_filter() {
+    let store = BloomTreeReader::<_>::new(MockTestStore::new_test(), 100, 0.01).unwrap();
+    let tree = JellyfishMerkleTree::new(&store);
+    let key = TestKey::random().into_object();
+    let value = TestValue::random().into_object();
+    assert!(!store.may_contain_key_hash(&key.merkle_hash()));
+
+    let (root, batch) = tree
+        .put_blob_set(None, vec![(key.clone(), value.clone())])
+        .unwrap();
+    commit(&store, root, batch.node_batch, batch.stale_node_index_batch).unwrap();
+    assert!(store.may_contain_key_hash(&key.merkle_hash()));
+    assert_eq!(
+        store.get::<TestKey, TestValue>(root, &key).unwrap(),
+        Some(value)
+    );
+}
+
+#[test]
+fn test_invalid_configuration() {
+    assert!(BloomTreeReader::<_>::new(MockTestStore::new_test(), 0, 0.01).is_err());
+    assert!(BloomTreeReader::<_>::new(MockTestStore::new_test(), 100, 0.0).is_err());
+    assert!(BloomTreeReader::<_>::new(MockTestStore::new_test(), 100, 1.0).is_err());
+}
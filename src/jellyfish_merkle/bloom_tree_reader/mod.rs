@@ -0,0 +1,246 @@
+// Copyright (c) RoochNetwork
+// SPDX-License-Identifier: Apache-2.0
+
+//! This module implements [`BloomTreeReader`], a [`TreeReader`] keeping a bloom filter of the key
+//! hashes in the trees it reads. A lookup of a key the filter has never seen is answered without
+//! reading any node, so workloads looking up mostly absent keys save the descent from the root
+//! for nearly all of them.
+//!
+//! [`BloomTreeReader`]: crate::BloomTreeReader
+//! [`TreeReader`]: crate::TreeReader
+
+#[cfg(test)]
+mod bloom_tree_reader_test;
+
+use super::{
+    contains_key, get_with,
+    hash::{HashValue, SMTHash, Sha3TreeHasher, TreeHasher},
+    iterator::JellyfishMerkleIterator,
+    node_type::{Node, NodeKey},
+    NodeBatch, StaleNodeIndexBatch, TreeReader, TreeWriter, Versioned,
+};
+use crate::{Key, SMTObject, Value};
+use anyhow::{ensure, Result};
+use std::{
+    marker::PhantomData,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// A bloom filter of key hashes. Key hashes are uniformly distributed already, so the bits of a
+/// key hash are derived from the key hash itself by double hashing.
+struct BloomFilter {
+    /// The bits of the filter, 64 per word.
+    words: Vec<AtomicU64>,
+
+    /// The number of bits set for each key hash.
+    num_hashes: u64,
+}
+
+impl BloomFilter {
+    /// Creates a filter sized to hold `capacity` key hashes with a false positive rate of
+    /// `false_positive_rate`.
+    fn new(capacity: usize, false_positive_rate: f64) -> Result<Self> {
+        ensure!(
+            capacity > 0,
+            "The capacity of a bloom filter must be positive."
+        );
+        ensure!(
+            false_positive_rate > 0.0 && false_positive_rate < 1.0,
+            "The false positive rate of a bloom filter must be in (0, 1), got {}.",
+            false_positive_rate
+        );
+        let ln2 = std::f64::consts::LN_2;
+        let num_bits = (-(capacity as f64) * false_positive_rate.ln() / (ln2 * ln2)).ceil();
+        let num_words = (num_bits / 64.0).ceil().max(1.0) as usize;
+        let num_hashes = ((num_words * 64) as f64 / capacity as f64 * ln2).round();
+        Ok(Self {
+            words: (0..num_words).map(|_| AtomicU64::new(0)).collect(),
+            num_hashes: num_hashes.clamp(1.0, 32.0) as u64,
+        })
+    }
+
+    /// Returns the positions of the bits of `key_hash`.
+    fn bits(&self, key_hash: &HashValue) -> impl Iterator<Item = usize> {
+        let bytes: &[u8; HashValue::LENGTH] = key_hash.as_ref();
+        let h1 = u64::from_le_bytes(bytes[..8].try_into().expect("Slice of 8 bytes."));
+        // Odd, so that the positions of a key hash are distinct.
+        let h2 = u64::from_le_bytes(bytes[8..16].try_into().expect("Slice of 8 bytes.")) | 1;
+        let num_bits = self.words.len() as u64 * 64;
+        (0..self.num_hashes).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % num_bits) as usize)
+    }
+
+    fn insert(&self, key_hash: &HashValue) {
+        for bit in self.bits(key_hash) {
+            self.words[bit / 64].fetch_or(1 << (bit % 64), Ordering::Relaxed);
+        }
+    }
+
+    fn may_contain(&self, key_hash: &HashValue) -> bool {
+        self.bits(key_hash)
+            .all(|bit| self.words[bit / 64].load(Ordering::Relaxed) & (1 << (bit % 64)) != 0)
+    }
+}
+
+/// A [`TreeReader`](crate::TreeReader) wrapping another one, with a bloom filter of the key
+/// hashes of the leaves it has seen: the leaves of the trees passed to
+/// [`populate`](crate::BloomTreeReader::populate) and the leaves written through its
+/// [`TreeWriter`](crate::TreeWriter) implementation.
+///
+/// [`contains_key`](crate::BloomTreeReader::contains_key) and
+/// [`get`](crate::BloomTreeReader::get) return absence without reading any node when
+/// the filter has not seen the key. Otherwise, including on false positives, they descend the
+/// tree as usual. The answers are thus only correct for the trees whose leaves have all been seen
+/// by the filter. Keys are never removed from the filter, so a deleted key only costs a descent.
+pub struct BloomTreeReader<R, H = Sha3TreeHasher> {
+    /// The reader the nodes are read from.
+    reader: R,
+
+    filter: BloomFilter,
+
+    /// The number of lookups answered by the filter alone.
+    skipped: AtomicU64,
+
+    phantom: PhantomData<H>,
+}
+
+impl<R, H> BloomTreeReader<R, H>
+where
+    H: TreeHasher,
+{
+    /// Creates a `BloomTreeReader` reading from `reader`, with a filter sized to hold `capacity`
+    /// key hashes with a false positive rate of `false_positive_rate`. The false positive rate
+    /// grows past it once the filter holds more than `capacity` key hashes.
+    pub fn new(reader: R, capacity: usize, false_positive_rate: f64) -> Result<Self> {
+        Ok(Self {
+            reader,
+            filter: BloomFilter::new(capacity, false_positive_rate)?,
+            skipped: AtomicU64::new(0),
+            phantom: PhantomData,
+        })
+    }
+
+    /// Returns the wrapped reader.
+    pub fn inner(&self) -> &R {
+        &self.reader
+    }
+
+    /// Adds `key_hash` to the filter.
+    pub fn insert_key_hash(&self, key_hash: &HashValue) {
+        self.filter.insert(key_hash)
+    }
+
+    /// Returns whether the filter may have seen `key_hash`. `false` means the key hash was never
+    /// added.
+    pub fn may_contain_key_hash(&self, key_hash: &HashValue) -> bool {
+        self.filter.may_contain(key_hash)
+    }
+
+    /// Returns the number of lookups answered by the filter without reading any node.
+    pub fn skipped(&self) -> u64 {
+        self.skipped.load(Ordering::Relaxed)
+    }
+
+    /// Returns whether the filter rules out `key`, counting the lookup as skipped if it does.
+    fn rules_out<K: Key>(&self, key: &SMTObject<K>) -> bool {
+        if self.filter.may_contain(&key.merkle_hash_with::<H>()) {
+            return false;
+        }
+        self.skipped.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    /// Adds the key hashes of all the leaves of the tree at `root` to the filter, reading the
+    /// whole tree.
+    pub fn populate<K, V>(&self, root: HashValue) -> Result<()>
+    where
+        R: Versioned<K, V>,
+        K: Key,
+        V: Value,
+    {
+        for key in JellyfishMerkleIterator::<K, V, R, H>::new(&self.reader, root, None)?.keys() {
+            self.filter.insert(&key?.merkle_hash_with::<H>());
+        }
+        Ok(())
+    }
+
+    /// Returns whether `key` is in the tree at `root`, without reading any node if the filter rules
+    /// it out.
+    pub fn contains_key<K, V>(&self, root: HashValue, key: &SMTObject<K>) -> Result<bool>
+    where
+        R: TreeReader<K, V>,
+        K: Key,
+        V: Value,
+    {
+        if self.rules_out(key) {
+            return Ok(false);
+        }
+        contains_key::<K, V, R, H>(&self.reader, root, key)
+    }
+
+    /// Returns the value of `key` in the tree at `root`, or `None` if the key is absent, without
+    /// reading any node if the filter rules it out.
+    pub fn get<K, V>(&self, root: HashValue, key: &SMTObject<K>) -> Result<Option<SMTObject<V>>>
+    where
+        R: TreeReader<K, V>,
+        K: Key,
+        V: Value,
+    {
+        if self.rules_out(key) {
+            return Ok(None);
+        }
+        get_with::<K, V, R, H, _, _>(&self.reader, root, key, SMTObject::clone)
+    }
+}
+
+impl<K, V, R, H> TreeReader<K, V> for BloomTreeReader<R, H>
+where
+    R: TreeReader<K, V>,
+{
+    fn get_node_option(&self, node_key: &NodeKey) -> Result<Option<Node<K, V>>> {
+        self.reader.get_node_option(node_key)
+    }
+
+    fn get_nodes(&self, node_keys: &[NodeKey]) -> Result<Vec<Option<Node<K, V>>>> {
+        self.reader.get_nodes(node_keys)
+    }
+
+    fn with_node<T, F>(&self, node_key: &NodeKey, f: F) -> Result<Option<T>>
+    where
+        F: FnOnce(&Node<K, V>) -> T,
+    {
+        self.reader.with_node(node_key, f)
+    }
+}
+
+impl<K, V, R, H> Versioned<K, V> for BloomTreeReader<R, H> where R: Versioned<K, V> {}
+
+/// Adds the key hashes of the leaves written to the filter, before writing them to the wrapped
+/// writer.
+impl<K, V, R, H> TreeWriter<K, V> for BloomTreeReader<R, H>
+where
+    R: TreeWriter<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    fn write_node_batch(&self, node_batch: &NodeBatch<K, V>) -> Result<()> {
+        for node in node_batch.values() {
+            if let Node::Leaf(leaf_node) = node {
+                self.filter.insert(&leaf_node.key_hash_with::<H>());
+            }
+        }
+        self.reader.write_node_batch(node_batch)
+    }
+
+    fn delete_node_batch(&self, node_keys: &[NodeKey]) -> Result<()> {
+        self.reader.delete_node_batch(node_keys)
+    }
+
+    fn write_stale_node_index_batch(
+        &self,
+        stale_node_index_batch: &StaleNodeIndexBatch,
+    ) -> Result<()> {
+        self.reader
+            .write_stale_node_index_batch(stale_node_index_batch)
+    }
+}
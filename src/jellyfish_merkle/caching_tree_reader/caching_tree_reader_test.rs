@@ -0,0 +1,121 @@
+// Copyright (c) RoochNetwork
+// SPDX-License-Identifier: Apache-2.0
+
+use super::*;
+use crate::jellyfish_merkle::{
+    iterator::JellyfishMerkleIterator,
+    mock_tree_store::{CountingTreeReader, MockTestStore, TestKey, TestValue},
+    JellyfishMerkleTree,
+};
+use crate::{
+    jellyfish_merkle::hash::{HashValue, SMTHash},
+    EncodeToObject,
+};
+use rand::{rngs::StdRng, SeedableRng};
+
+fn random_leaf_with_key() -> (Node<TestKey, TestValue>, NodeKey) {
+    let node = Node::new_leaf(TestKey::random(), TestValue::random());
+    let node_key = node.merkle_hash();
+    (node, node_key)
+}
+
+#[test]
+fn test_lru_eviction() {
+    let db = MockTestStore::new_test();
+    let leaves = (0..3).map(|_| random_leaf_with_key()).collect::<Vec<_>>();
+    for (node, node_key) in &leaves {
+        db.put_node(*node_key, node.clone()).unwrap();
+    }
+    let reader = CachingTreeReader::new(db, 2);
+
+    assert_eq!(reader.get_node(&leaves[0].1).unwrap(), leaves[0].0);
+    assert_eq!(reader.get_node(&leaves[1].1).unwrap(), leaves[1].0);
+    assert_eq!((reader.hits(), reader.misses()), (0, 2));
+    // Use leaf 0, so leaf 1 is the least recently used one.
+    assert_eq!(reader.get_node(&leaves[0].1).unwrap(), leaves[0].0);
+    assert_eq!((reader.hits(), reader.misses()), (1, 2));
+
+    // Reading leaf 2 evicts leaf 1.
+    assert_eq!(reader.get_node(&leaves[2].1).unwrap(), leaves[2].0);
+    assert_eq!(reader.len(), 2);
+    assert_eq!((reader.hits(), reader.misses()), (1, 3));
+    assert_eq!(reader.get_node(&leaves[0].1).unwrap(), leaves[0].0);
+    assert_eq!((reader.hits(), reader.misses()), (2, 3));
+    assert_eq!(reader.get_node(&leaves[1].1).unwrap(), leaves[1].0);
+    assert_eq!((reader.hits(), reader.misses()), (2, 4));
+
+    // Missing nodes are not cached.
+    let (_, missing_node_key) = random_leaf_with_key();
+    assert!(reader.get_node_option(&missing_node_key).unwrap().is_none());
+    assert_eq!(reader.len(), 2);
+    let nodes = reader
+        .get_nodes(&[leaves[1].1, missing_node_key, leaves[2].1])
+        .unwrap();
+    assert_eq!(
+        nodes,
+        vec![Some(leaves[1].0.clone()), None, Some(leaves[2].0.clone())]
+    );
+    assert_eq!((reader.hits(), reader.misses()), (3, 7));
+}
+
+#[test]
+fn test_zero_capacity() {
+    let db = MockTestStore::new_test();
+    let (node, node_key) = random_leaf_with_key();
+    db.put_node(node_key, node.clone()).unwrap();
+    let reader = CachingTreeReader::new(db, 0);
+
+    assert_eq!(reader.get_node(&node_key).unwrap(), node);
+    assert_eq!(reader.get_node(&node_key).unwrap(), node);
+    assert!(reader.is_empty());
+    assert_eq!((reader.hits(), reader.misses()), (0, 2));
+}
+
+#[test]
+fn test_with_node() {
+    let db = MockTestStore::new_test();
+    let (node, node_key) = random_leaf_with_key();
+    db.put_node(node_key, node.clone()).unwrap();
+    let reader = CachingTreeReader::new(db, 2);
+
+    let hash = |node: &Node<TestKey, TestValue>| node.merkle_hash();
+    assert_eq!(reader.with_node(&node_key, hash).unwrap(), Some(node_key));
+    assert_eq!((reader.hits(), reader.misses()), (0, 1));
+    // The node read on the miss was cached, and is now lent from the cache.
+    assert_eq!(reader.with_node(&node_key, hash).unwrap(), Some(node_key));
+    assert_eq!((reader.hits(), reader.misses()), (1, 1));
+
+    let (_, missing_node_key) = random_leaf_with_key();
+    assert_eq!(reader.with_node(&missing_node_key, hash).unwrap(), None);
+    assert_eq!(reader.len(), 1);
+}
+
+#[test]
+fn test_cache_saves_backend_reads() {
+    let db = MockTestStore::new_test();
+    let tree: JellyfishMerkleTree<TestKey, TestValue, MockTestStore> =
+        JellyfishMerkleTree::new(&db);
+    let mut rng = StdRng::from_seed([3; 32]);
+    let kvs = (0..1000)
+        .map(|i: u32| {
+            (
+                TestKey(HashValue::random_with_rng(&mut rng)).into_object(),
+                TestValue::from(i.to_be_bytes().to_vec()).into_object(),
+            )
+        })
+        .collect::<Vec<_>>();
+    let (root, batch) = tree.put_blob_set(None, kvs).unwrap();
+    db.write_tree_update_batch(batch).unwrap();
+
+    // The cache holds the internal nodes near the root, but not the leaves.
+    let reader = CachingTreeReader::new(CountingTreeReader::new(db), 300);
+    let iter = JellyfishMerkleIterator::<_, _, _>::new(&reader, root, None).unwrap();
+    assert_eq!(iter.count(), 1000);
+    let first_reads = reader.inner().reads();
+
+    let iter = JellyfishMerkleIterator::<_, _, _>::new(&reader, root, None).unwrap();
+    assert_eq!(iter.count(), 1000);
+    let second_reads = reader.inner().reads() - first_reads;
+    assert!(second_reads < first_reads);
+    assert!(reader.hits() > 0);
+}
@@ -0,0 +1,233 @@
+// Copyright (c) RoochNetwork
+// SPDX-License-Identifier: Apache-2.0
+
+//! This module implements [`CachingTreeReader`], a [`TreeReader`] keeping the most recently used
+//! nodes in memory. Every traversal of a tree starts from the root and goes through the internal
+//! nodes near it, so even a small cache saves most of the reads of those nodes from the storage.
+//!
+//! [`CachingTreeReader`]: crate::CachingTreeReader
+//! [`TreeReader`]: crate::TreeReader
+
+#[cfg(test)]
+mod caching_tree_reader_test;
+
+use super::{
+    node_type::{Node, NodeKey},
+    TreeReader, Versioned,
+};
+use crate::{Key, Value};
+use anyhow::Result;
+use parking_lot::Mutex;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// A bounded cache of nodes, evicting the least recently used one when it is full.
+struct NodeCache<K, V> {
+    /// The maximum number of nodes in the cache.
+    capacity: usize,
+
+    /// The cached nodes along with the tick of their last use.
+    nodes: HashMap<NodeKey, (Node<K, V>, u64)>,
+
+    /// The keys of the cached nodes ordered by the tick of their last use.
+    recency: BTreeMap<u64, NodeKey>,
+
+    /// Increased on every use of the cache.
+    tick: u64,
+}
+
+impl<K, V> NodeCache<K, V>
+where
+    K: Key,
+    V: Value,
+{
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            nodes: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    fn get(&mut self, node_key: &NodeKey) -> Option<Node<K, V>> {
+        self.get_ref(node_key).cloned()
+    }
+
+    /// Like `get`, lending the cached node instead of cloning it.
+    fn get_ref(&mut self, node_key: &NodeKey) -> Option<&Node<K, V>> {
+        self.tick += 1;
+        let (node, last_used) = self.nodes.get_mut(node_key)?;
+        self.recency.remove(last_used);
+        *last_used = self.tick;
+        self.recency.insert(self.tick, *node_key);
+        Some(node)
+    }
+
+    fn put(&mut self, node_key: NodeKey, node: Node<K, V>) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.tick += 1;
+        if let Some((_, last_used)) = self.nodes.insert(node_key, (node, self.tick)) {
+            self.recency.remove(&last_used);
+        }
+        self.recency.insert(self.tick, node_key);
+        while self.nodes.len() > self.capacity {
+            let (_, evicted) = self
+                .recency
+                .pop_first()
+                .expect("Recency should have an entry for every cached node.");
+            self.nodes.remove(&evicted);
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.nodes.len()
+    }
+}
+
+/// A [`TreeReader`](crate::TreeReader) wrapping another one, serving the nodes it read
+/// recently from a least recently used cache holding up to a given number of nodes.
+pub struct CachingTreeReader<K, V, R> {
+    /// The reader the nodes missing from the cache are read from.
+    reader: R,
+
+    cache: Mutex<NodeCache<K, V>>,
+
+    /// The number of nodes served from the cache.
+    hits: AtomicU64,
+
+    /// The number of nodes read from `reader`.
+    misses: AtomicU64,
+}
+
+impl<K, V, R> CachingTreeReader<K, V, R>
+where
+    K: Key,
+    V: Value,
+    R: TreeReader<K, V>,
+{
+    /// Creates a `CachingTreeReader` caching up to `capacity` nodes read from `reader`.
+    pub fn new(reader: R, capacity: usize) -> Self {
+        Self {
+            reader,
+            cache: Mutex::new(NodeCache::new(capacity)),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the wrapped reader.
+    pub fn inner(&self) -> &R {
+        &self.reader
+    }
+
+    /// Returns the number of nodes in the cache.
+    pub fn len(&self) -> usize {
+        self.cache.lock().len()
+    }
+
+    /// Whether the cache is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the number of nodes served from the cache.
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of nodes which were not in the cache.
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+impl<K, V, R> TreeReader<K, V> for CachingTreeReader<K, V, R>
+where
+    K: Key,
+    V: Value,
+    R: TreeReader<K, V>,
+{
+    fn get_node_option(&self, node_key: &NodeKey) -> Result<Option<Node<K, V>>> {
+        if let Some(node) = self.cache.lock().get(node_key) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(Some(node));
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let node = self.reader.get_node_option(node_key)?;
+        if let Some(node) = &node {
+            self.cache.lock().put(*node_key, node.clone());
+        }
+        Ok(node)
+    }
+
+    fn with_node<T, F>(&self, node_key: &NodeKey, f: F) -> Result<Option<T>>
+    where
+        F: FnOnce(&Node<K, V>) -> T,
+    {
+        {
+            let mut cache = self.cache.lock();
+            if let Some(node) = cache.get_ref(node_key) {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(Some(f(node)));
+            }
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let node = match self.reader.get_node_option(node_key)? {
+            Some(node) => node,
+            None => return Ok(None),
+        };
+        let result = f(&node);
+        self.cache.lock().put(*node_key, node);
+        Ok(Some(result))
+    }
+
+    fn get_nodes(&self, node_keys: &[NodeKey]) -> Result<Vec<Option<Node<K, V>>>> {
+        let mut nodes = {
+            let mut cache = self.cache.lock();
+            node_keys
+                .iter()
+                .map(|node_key| cache.get(node_key))
+                .collect::<Vec<_>>()
+        };
+        let missing_node_keys = node_keys
+            .iter()
+            .zip(nodes.iter())
+            .filter(|(_, node)| node.is_none())
+            .map(|(node_key, _)| *node_key)
+            .collect::<Vec<_>>();
+        self.hits.fetch_add(
+            (node_keys.len() - missing_node_keys.len()) as u64,
+            Ordering::Relaxed,
+        );
+        if missing_node_keys.is_empty() {
+            return Ok(nodes);
+        }
+
+        self.misses
+            .fetch_add(missing_node_keys.len() as u64, Ordering::Relaxed);
+        let mut missing_nodes = self.reader.get_nodes(&missing_node_keys)?.into_iter();
+        let mut cache = self.cache.lock();
+        for (node_key, node) in node_keys.iter().zip(nodes.iter_mut()) {
+            if node.is_none() {
+                *node = missing_nodes.next().flatten();
+                if let Some(node) = node {
+                    cache.put(*node_key, node.clone());
+                }
+            }
+        }
+        Ok(nodes)
+    }
+}
+
+impl<K, V, R> Versioned<K, V> for CachingTreeReader<K, V, R>
+where
+    K: Key,
+    V: Value,
+    R: Versioned<K, V>,
+{
+}
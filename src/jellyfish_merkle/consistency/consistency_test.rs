@@ -0,0 +1,255 @@
+// Copyright (c) RoochNetwork
+// SPDX-License-Identifier: Apache-2.0
+
+use super::{assert_consistent, validate, ValidationReport, ViolationKind};
+use crate::jellyfish_merkle::{
+    extract_subtree,
+    hash::{HashValue, SMTHash, Sha3TreeHasher, SPARSE_MERKLE_PLACEHOLDER_HASH},
+    mock_tree_store::{MockTestStore, TestKey, TestValue},
+    nibble::Nibble,
+    nibble_path::NibblePath,
+    node_type::{Child, Children, LeafNode, Node},
+    test_helper::init_random_tree,
+    JellyfishMerkleTree, NodeBatch, TreeWriter,
+};
+use crate::{EncodeToObject, InMemoryNodeStore, NodeStore};
+
+/// Returns the root and the nodes of a tree of `n` random leaves drawn from `seed`.
+fn init_tree(n: usize, seed: u8) -> (HashValue, NodeBatch<TestKey, TestValue>) {
+    let db = MockTestStore::new_test();
+    let (root, _) = init_random_tree(&db, n, seed);
+    let (_, node_batch) = extract_subtree::<TestKey, TestValue, _, Sha3TreeHasher>(
+        &db,
+        root,
+        NibblePath::new(vec![]),
+    )
+    .unwrap();
+    (root, node_batch)
+}
+
+fn store(node_batch: &NodeBatch<TestKey, TestValue>) -> MockTestStore {
+    let db = MockTestStore::new_test();
+    db.write_node_batch(node_batch).unwrap();
+    db
+}
+
+fn check(a: &MockTestStore, b: &MockTestStore, root: HashValue) -> anyhow::Result<()> {
+    assert_consistent::<TestKey, TestValue, _, _, Sha3TreeHasher>(a, b, root)
+}
+
+#[test]
+fn test_consistent_stores() {
+    let (root, node_batch) = init_tree(1000, 0);
+    let a = store(&node_batch);
+    let b = store(&node_batch);
+    check(&a, &b, root).unwrap();
+    check(&a, &b, *SPARSE_MERKLE_PLACEHOLDER_HASH).unwrap();
+
+    // Nodes unreachable from the root are not compared.
+    let (other_root, other_batch) = init_tree(1, 1);
+    b.write_node_batch(&other_batch).unwrap();
+    check(&a, &b, root).unwrap();
+    assert!(check(&a, &b, other_root).is_err());
+}
+
+#[test]
+fn test_altered_node() {
+    let (root, mut node_batch) = init_tree(1000, 0);
+    let a = store(&node_batch);
+
+    // Alter the value of a single leaf, under the same node key.
+    let (node_key, key_hash) = node_batch
+        .iter()
+        .find_map(|(node_key, node)| match node {
+            Node::Leaf(leaf_node) => Some((*node_key, leaf_node.key_hash())),
+            _ => None,
+        })
+        .unwrap();
+    let altered = match &node_batch[&node_key] {
+        Node::Leaf(leaf_node) => LeafNode::new(
+            leaf_node.key().clone(),
+            TestValue::from(vec![0xff]).into_object(),
+        ),
+        _ => unreachable!(),
+    };
+    assert_ne!(altered.merkle_hash(), node_key);
+    node_batch.insert(node_key, Node::Leaf(altered));
+    let b = store(&node_batch);
+
+    let err = check(&a, &b, root).unwrap_err().to_string();
+    assert!(err.contains(&format!("{:x}", node_key)), "{}", err);
+    assert!(err.contains("second store does not hash"), "{}", err);
+    // The nibble path of the leaf is a prefix of its key hash.
+    let nibble_path = &err[err.find('[').unwrap() + 1..err.find(']').unwrap()];
+    assert!(!nibble_path.is_empty());
+    assert!(
+        format!("{:x}", key_hash).starts_with(nibble_path),
+        "{}",
+        err
+    );
+
+    let err = check(&b, &a, root).unwrap_err().to_string();
+    assert!(err.contains("first store does not hash"), "{}", err);
+}
+
+#[test]
+fn test_missing_node() {
+    let (root, mut node_batch) = init_tree(100, 0);
+    let a = store(&node_batch);
+    let node_key = *node_batch
+        .iter()
+        .find(|(_, node)| node.is_leaf())
+        .unwrap()
+        .0;
+    node_batch.remove(&node_key);
+    let b = store(&node_batch);
+
+    let err = check(&a, &b, root).unwrap_err().to_string();
+    assert!(err.contains(&format!("{:x}", node_key)), "{}", err);
+    assert!(err.contains("missing from the second store"), "{}", err);
+    let err = check(&b, &a, root).unwrap_err().to_string();
+    assert!(err.contains("missing from the first store"), "{}", err);
+}
+
+fn validate_store(db: &MockTestStore, root: HashValue) -> ValidationReport {
+    validate::<TestKey, TestValue, _, Sha3TreeHasher>(db, root)
+}
+
+#[test]
+fn test_validate() {
+    let (root, mut node_batch) = init_tree(1000, 0);
+    let report = validate_store(&store(&node_batch), root);
+    assert!(report.is_valid(), "{:?}", report.violations);
+    report.ensure_valid().unwrap();
+    assert_eq!(report.num_nodes, node_batch.len());
+    assert_eq!(report.num_leaves, 1000);
+    assert!(validate_store(&store(&node_batch), *SPARSE_MERKLE_PLACEHOLDER_HASH).is_valid());
+
+    // An internal node with a single internal child, on the path shared by two keys, is legal.
+    let db = MockTestStore::new_test();
+    let key1 = TestKey::new([0x00u8; HashValue::LENGTH]);
+    let mut key2 = [0x00u8; HashValue::LENGTH];
+    key2[1] = 0x01;
+    let (chain_root, batch) = JellyfishMerkleTree::new(&db)
+        .put_blob_set(
+            None,
+            vec![
+                (key1.into_object(), TestValue::random().into_object()),
+                (
+                    TestKey::new(key2).into_object(),
+                    TestValue::random().into_object(),
+                ),
+            ],
+        )
+        .unwrap();
+    assert_eq!(batch.node_batch.len(), 6);
+    let report = validate_store(&store(&batch.node_batch), chain_root);
+    assert!(report.is_valid(), "{:?}", report.violations);
+
+    // Every broken node is reported, not only the first.
+    let mut leaves = node_batch
+        .iter()
+        .filter(|(_, node)| node.is_leaf())
+        .map(|(node_key, _)| *node_key);
+    let missing = leaves.next().unwrap();
+    let altered = leaves.next().unwrap();
+    node_batch.remove(&missing);
+    let altered_node = Node::new_leaf(TestKey::random(), TestValue::random());
+    let altered_hash = altered_node.merkle_hash();
+    node_batch.insert(altered, altered_node);
+    let report = validate_store(&store(&node_batch), root);
+    assert_eq!(report.violations.len(), 2);
+    assert!(report
+        .violations
+        .iter()
+        .any(|violation| violation.node_key == missing
+            && violation.kind == ViolationKind::MissingNode));
+    assert!(report
+        .violations
+        .iter()
+        .any(|violation| violation.node_key == altered
+            && violation.kind
+                == ViolationKind::HashMismatch {
+                    actual: altered_hash
+                }));
+    let err = report.ensure_valid().unwrap_err().to_string();
+    assert!(err.contains("breaks 2 invariants"), "{}", err);
+}
+
+#[test]
+fn test_validate_single_leaf_child() {
+    // The root has the leaf of key hash 1000.. at nibble 1, and at nibble 0 an internal node whose
+    // only child is the leaf of key hash 0000.., which should have taken its place. Such a node
+    // can only come from a store, as its encoding.
+    let key1 = TestKey::new([0x00u8; HashValue::LENGTH]);
+    let mut key2 = [0x00u8; HashValue::LENGTH];
+    key2[0] = 0x10;
+    let leaf1: Node<TestKey, TestValue> = Node::new_leaf(key1, TestValue::random());
+    let leaf2: Node<TestKey, TestValue> = Node::new_leaf(TestKey::new(key2), TestValue::random());
+    let mut single = vec![1u8];
+    single.extend(1u16.to_le_bytes());
+    single.extend(1u16.to_le_bytes());
+    single.extend(leaf1.merkle_hash().to_vec());
+    // The node does not decode, so its hash is never checked.
+    let single_key = HashValue::random();
+
+    let mut children = Children::new();
+    children.insert(Nibble::from(0), Child::new_internal(single_key, None));
+    children.insert(Nibble::from(1), Child::new(leaf2.merkle_hash(), true));
+    let root: Node<TestKey, TestValue> = Node::new_internal(children);
+    let root_key = root.merkle_hash();
+
+    let db = InMemoryNodeStore::default();
+    for node in [leaf1, leaf2, root] {
+        db.put(node.merkle_hash(), node.encode().unwrap()).unwrap();
+    }
+    db.put(single_key, single).unwrap();
+    let report = validate::<TestKey, TestValue, _, Sha3TreeHasher>(&db, root_key);
+    assert_eq!(report.violations.len(), 1, "{:?}", report.violations);
+    let violation = &report.violations[0];
+    assert_eq!(violation.node_key, single_key);
+    assert_eq!(
+        violation.nibble_path,
+        std::iter::once(Nibble::from(0)).collect::<NibblePath>()
+    );
+    assert!(
+        matches!(&violation.kind, ViolationKind::ReadFailed(err) if err.contains("Single leaf child")),
+        "{:?}",
+        violation.kind
+    );
+    // The leaf below it is not reached.
+    assert_eq!((report.num_nodes, report.num_leaves), (2, 1));
+}
+
+#[test]
+fn test_validate_misplaced_leaf() {
+    // The leaf of key hash 1000.. is at nibble 2 of the root.
+    let key1 = TestKey::new([0x00u8; HashValue::LENGTH]);
+    let mut key2 = [0x00u8; HashValue::LENGTH];
+    key2[0] = 0x10;
+    let leaf1: Node<TestKey, TestValue> = Node::new_leaf(key1, TestValue::random());
+    let leaf2: Node<TestKey, TestValue> = Node::new_leaf(TestKey::new(key2), TestValue::random());
+    let mut children = Children::new();
+    children.insert(Nibble::from(0), Child::new(leaf1.merkle_hash(), true));
+    children.insert(Nibble::from(2), Child::new(leaf2.merkle_hash(), true));
+    let root: Node<TestKey, TestValue> = Node::new_internal(children);
+    let root_key = root.merkle_hash();
+    let leaf2_key = leaf2.merkle_hash();
+
+    let node_batch = [
+        (leaf1.merkle_hash(), leaf1),
+        (leaf2_key, leaf2),
+        (root_key, root),
+    ]
+    .into_iter()
+    .collect::<NodeBatch<_, _>>();
+    let report = validate_store(&store(&node_batch), root_key);
+    assert_eq!(report.violations.len(), 1);
+    assert_eq!(report.violations[0].node_key, leaf2_key);
+    assert_eq!(
+        report.violations[0].kind,
+        ViolationKind::MisplacedLeaf {
+            key_hash: HashValue::new(key2)
+        }
+    );
+}
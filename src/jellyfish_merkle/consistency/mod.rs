@@ -0,0 +1,275 @@
+// Copyright (c) RoochNetwork
+// SPDX-License-Identifier: Apache-2.0
+
+//! This module implements the comparison of the nodes of a tree in two stores, e.g. to validate
+//! the migration of a tree to a new backend, and the check of the structural invariants of a tree
+//! in a single store, e.g. before trusting a tree imported from a snapshot.
+
+#[cfg(test)]
+mod consistency_test;
+
+use super::{
+    hash::{HashValue, SMTHash, TreeHasher},
+    nibble_path::NibblePath,
+    node_type::{Node, NodeKey},
+    TreeReader, ROOT_NIBBLE_HEIGHT,
+};
+use crate::{Key, Value};
+use anyhow::{bail, ensure, Result};
+use std::fmt;
+
+/// Checks that the stores read by `a` and `b` hold the same tree at `root`. Both are walked in
+/// lockstep from the root, depth first and in nibble order, and each node reachable from the
+/// root is compared by its decoded contents. Fails at the first divergence, with the key of the
+/// offending node and the nibble path to it: a node missing from one of the stores, or nodes with
+/// different contents under the same key. The node keys being the hashes of the nodes, the latter
+/// means that one of the stores is corrupted, and the error tells which of the nodes does not
+/// hash to its key.
+pub fn assert_consistent<K, V, R1, R2, H>(a: &R1, b: &R2, root: HashValue) -> Result<()>
+where
+    R1: TreeReader<K, V>,
+    R2: TreeReader<K, V>,
+    K: Key,
+    V: Value + PartialEq,
+    H: TreeHasher,
+{
+    if H::is_empty_root(root) {
+        return Ok(());
+    }
+    let mut stack = vec![(root, NibblePath::new(vec![]))];
+    while let Some((node_key, nibble_path)) = stack.pop() {
+        let node = match (a.get_node_option(&node_key)?, b.get_node_option(&node_key)?) {
+            (Some(node_a), Some(node_b)) => {
+                if node_a != node_b {
+                    bail!(
+                        "Node {:x} at nibble path [{:?}] differs between the stores: {}.",
+                        node_key,
+                        nibble_path,
+                        corrupted_stores::<K, V, H>(&node_key, &node_a, &node_b)
+                    );
+                }
+                node_a
+            }
+            (None, Some(_)) => bail!(
+                "Node {:x} at nibble path [{:?}] is missing from the first store.",
+                node_key,
+                nibble_path
+            ),
+            (Some(_), None) => bail!(
+                "Node {:x} at nibble path [{:?}] is missing from the second store.",
+                node_key,
+                nibble_path
+            ),
+            (None, None) => bail!(
+                "Node {:x} at nibble path [{:?}] is missing from both stores.",
+                node_key,
+                nibble_path
+            ),
+        };
+        if let Node::Internal(internal_node) = node {
+            ensure!(
+                nibble_path.num_nibbles() < ROOT_NIBBLE_HEIGHT,
+                "Should have reached the bottom of the tree at internal node {:x}.",
+                node_key
+            );
+            // Pushed in reverse so that the children are popped in nibble order.
+            for (nibble, child) in internal_node
+                .children()
+                .collect::<Vec<_>>()
+                .into_iter()
+                .rev()
+            {
+                let mut child_nibble_path = nibble_path.clone();
+                child_nibble_path.push(nibble);
+                stack.push((child.hash, child_nibble_path));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Tells which of `node_a` and `node_b`, two different nodes read at `node_key`, do not hash to
+/// `node_key`.
+fn corrupted_stores<K, V, H>(
+    node_key: &NodeKey,
+    node_a: &Node<K, V>,
+    node_b: &Node<K, V>,
+) -> &'static str
+where
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    match (
+        node_a.merkle_hash_with::<H>() == *node_key,
+        node_b.merkle_hash_with::<H>() == *node_key,
+    ) {
+        (true, false) => "the node of the second store does not hash to its key",
+        (false, true) => "the node of the first store does not hash to its key",
+        (false, false) => "neither node hashes to its key",
+        (true, true) => "both nodes hash to their key",
+    }
+}
+
+/// A way in which a node breaks the invariants of a tree, see [`validate`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ViolationKind {
+    /// The node is missing from the store.
+    MissingNode,
+    /// The node could not be read, with the error of the reader. This includes a node whose
+    /// encoding is invalid, e.g. an internal node without children, or whose only child is a leaf
+    /// which should have taken its place: such a node can not be decoded.
+    ReadFailed(String),
+    /// The node hashes to `actual` rather than to its node key.
+    HashMismatch { actual: HashValue },
+    /// The node is an internal node below the last nibble of a key hash.
+    TooDeep,
+    /// The node is a null node below the root.
+    NullBelowRoot,
+    /// The node is a leaf whose key hash does not start with the nibble path to it.
+    MisplacedLeaf { key_hash: HashValue },
+    /// The node is a leaf while its parent records an internal child, or the other way around.
+    ChildKindMismatch,
+}
+
+/// A node breaking the invariants of a tree, found by [`validate`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Violation {
+    /// The key of the offending node.
+    pub node_key: NodeKey,
+    /// The nibble path from the root to the node.
+    pub nibble_path: NibblePath,
+    pub kind: ViolationKind,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Node {:x} at nibble path [{:?}]: {:?}",
+            self.node_key, self.nibble_path, self.kind
+        )
+    }
+}
+
+/// The result of [`validate`]: the nodes breaking the invariants of the tree, if any, and the
+/// numbers of nodes and leaves read.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ValidationReport {
+    pub violations: Vec<Violation>,
+    pub num_nodes: usize,
+    pub num_leaves: usize,
+}
+
+impl ValidationReport {
+    /// Whether the tree holds all its invariants.
+    pub fn is_valid(&self) -> bool {
+        self.violations.is_empty()
+    }
+
+    /// Fails with the violations, if any, e.g. to reject an imported tree.
+    pub fn ensure_valid(&self) -> Result<()> {
+        if let Some(first) = self.violations.first() {
+            bail!(
+                "The tree breaks {} invariants, the first at {}.",
+                self.violations.len(),
+                first
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Checks the structural invariants of the tree at `root` in the store read by `reader`, walking
+/// it depth first from the root, and returns the report of the nodes breaking them:
+///
+/// - every node reachable from the root is in the store and hashes to its node key, so the root
+///   hash is recomputed from the leaves up;
+/// - every internal node has at least two children, or a single child which is an internal node,
+///   on the path shared by the keys below it: a single leaf child takes the place of its parent,
+///   up to the root, which is then the leaf itself. This is checked by the decoding of the nodes,
+///   see [`ViolationKind::ReadFailed`], since an `InternalNode` can not hold other children;
+/// - no internal node is below the last nibble of a key hash, and no null node is below the root;
+/// - every leaf is at a position its key hash starts with, and every child is of the kind, leaf
+///   or internal, recorded by its parent.
+///
+/// The violations are collected rather than failing at the first, with the key of the offending
+/// node and the nibble path to it. The children of a node which could not be read or does not hash
+/// to its key are not walked, since they are not authenticated by the root. This is the check to
+/// run on a tree imported from an untrusted snapshot, see [`import_snapshot`](crate::import_snapshot),
+/// before using its root.
+pub fn validate<K, V, R, H>(reader: &R, root: HashValue) -> ValidationReport
+where
+    R: TreeReader<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    let mut report = ValidationReport::default();
+    if H::is_empty_root(root) {
+        return report;
+    }
+    // The nodes to visit, with the nibble path to them and whether their parent records a leaf.
+    let mut stack = vec![(root, NibblePath::new(vec![]), None)];
+    while let Some((node_key, nibble_path, is_leaf)) = stack.pop() {
+        let mut violation = |kind| {
+            report.violations.push(Violation {
+                node_key,
+                nibble_path: nibble_path.clone(),
+                kind,
+            })
+        };
+        let node = match reader.get_node_option(&node_key) {
+            Ok(Some(node)) => node,
+            Ok(None) => {
+                violation(ViolationKind::MissingNode);
+                continue;
+            }
+            Err(err) => {
+                violation(ViolationKind::ReadFailed(format!("{:#}", err)));
+                continue;
+            }
+        };
+        let actual = node.merkle_hash_with::<H>();
+        if actual != node_key {
+            violation(ViolationKind::HashMismatch { actual });
+            continue;
+        }
+        if is_leaf.is_some_and(|is_leaf| is_leaf != node.is_leaf()) {
+            violation(ViolationKind::ChildKindMismatch);
+        }
+        report.num_nodes += 1;
+        match node {
+            Node::Internal(internal_node) => {
+                if nibble_path.num_nibbles() >= ROOT_NIBBLE_HEIGHT {
+                    violation(ViolationKind::TooDeep);
+                    continue;
+                }
+                // Pushed in reverse so that the children are popped in nibble order.
+                for (nibble, child) in internal_node
+                    .children()
+                    .collect::<Vec<_>>()
+                    .into_iter()
+                    .rev()
+                {
+                    let mut child_nibble_path = nibble_path.clone();
+                    child_nibble_path.push(nibble);
+                    stack.push((child.hash, child_nibble_path, Some(child.is_leaf)));
+                }
+            }
+            Node::Leaf(leaf_node) => {
+                report.num_leaves += 1;
+                let key_hash = leaf_node.key_hash_with::<H>();
+                if !nibble_path
+                    .nibbles()
+                    .enumerate()
+                    .all(|(i, nibble)| u8::from(nibble) == key_hash.nibble(i))
+                {
+                    violation(ViolationKind::MisplacedLeaf { key_hash });
+                }
+            }
+            Node::Null => violation(ViolationKind::NullBelowRoot),
+        }
+    }
+    report
+}
@@ -0,0 +1,356 @@
+// Copyright (c) RoochNetwork
+// SPDX-License-Identifier: Apache-2.0
+
+use super::{changed_since, diff, join, Diff, TreeDiff};
+use crate::jellyfish_merkle::{
+    hash::{HashValue, SMTHash, Sha3TreeHasher, SPARSE_MERKLE_PLACEHOLDER_HASH},
+    mock_tree_store::{CountingTreeReader, MockTestStore, TestKey, TestValue},
+    JellyfishMerkleTree, TreeReader,
+};
+use crate::{EncodeToObject, SMTObject};
+use anyhow::Result;
+use proptest::{collection::btree_map, prelude::*};
+use rand::{rngs::StdRng, SeedableRng};
+use std::collections::BTreeMap;
+
+fn put(
+    db: &MockTestStore,
+    root: Option<HashValue>,
+    updates: Vec<(TestKey, Option<TestValue>)>,
+) -> HashValue {
+    let tree = JellyfishMerkleTree::new(db);
+    let updates: Vec<(SMTObject<TestKey>, Option<SMTObject<TestValue>>)> = updates
+        .into_iter()
+        .map(|(k, v)| (k.into_object(), v.map(|v| v.into_object())))
+        .collect();
+    let (root, batch) = tree.put_batch(root, updates).unwrap();
+    db.write_tree_update_batch(batch).unwrap();
+    root
+}
+
+/// Returns the expected diff between two maps, sorted by key hash like the diff of the trees.
+fn expected_diff(
+    old: &BTreeMap<TestKey, TestValue>,
+    new: &BTreeMap<TestKey, TestValue>,
+) -> TreeDiff<TestKey, TestValue> {
+    let key_hash = |key: &TestKey| key.into_object().merkle_hash();
+    let mut tree_diff = TreeDiff::default();
+    for (key, value) in new {
+        match old.get(key) {
+            None => tree_diff.added.push((*key, value.clone())),
+            Some(old_value) if old_value != value => {
+                tree_diff
+                    .modified
+                    .push((*key, old_value.clone(), value.clone()))
+            }
+            Some(_) => {}
+        }
+    }
+    for (key, value) in old {
+        if !new.contains_key(key) {
+            tree_diff.removed.push((*key, value.clone()));
+        }
+    }
+    tree_diff.added.sort_by_key(|(key, _)| key_hash(key));
+    tree_diff.removed.sort_by_key(|(key, _)| key_hash(key));
+    tree_diff.modified.sort_by_key(|(key, _, _)| key_hash(key));
+    tree_diff
+}
+
+/// Returns the differences of `tree_diff` in the order `join` yields them.
+fn expected_join(tree_diff: TreeDiff<TestKey, TestValue>) -> Vec<Diff<TestKey, TestValue>> {
+    let mut diffs = tree_diff
+        .added
+        .into_iter()
+        .map(|(key, value)| Diff::Added(key, value))
+        .chain(
+            tree_diff
+                .removed
+                .into_iter()
+                .map(|(key, value)| Diff::Removed(key, value)),
+        )
+        .chain(
+            tree_diff
+                .modified
+                .into_iter()
+                .map(|(key, old, new)| Diff::Changed(key, old, new)),
+        )
+        .collect::<Vec<_>>();
+    diffs.sort_by_key(|diff| match diff {
+        Diff::Added(key, _) | Diff::Removed(key, _) | Diff::Changed(key, _, _) => {
+            key.into_object().merkle_hash()
+        }
+    });
+    diffs
+}
+
+#[test]
+fn test_diff_same_root() {
+    let db = MockTestStore::new_test();
+    let root = put(
+        &db,
+        None,
+        vec![(TestKey::random(), Some(TestValue::random()))],
+    );
+    let reader = CountingTreeReader::new(db);
+    let tree_diff = diff::<TestKey, TestValue, _, Sha3TreeHasher>(&reader, root, root).unwrap();
+    assert!(tree_diff.is_empty());
+    let mut iter = join::<TestKey, TestValue, _, Sha3TreeHasher>(&reader, root, root);
+    assert!(iter.next().is_none());
+    assert_eq!(reader.reads(), 0);
+}
+
+#[test]
+fn test_diff_empty_tree() {
+    let db = MockTestStore::new_test();
+    let kvs: BTreeMap<_, _> = (0..10)
+        .map(|_| (TestKey::random(), TestValue::random()))
+        .collect();
+    let root = put(
+        &db,
+        None,
+        kvs.iter().map(|(k, v)| (*k, Some(v.clone()))).collect(),
+    );
+    let empty = *SPARSE_MERKLE_PLACEHOLDER_HASH;
+
+    let tree_diff = diff::<_, _, _, Sha3TreeHasher>(&db, empty, root).unwrap();
+    assert_eq!(tree_diff, expected_diff(&BTreeMap::new(), &kvs));
+    assert_eq!(tree_diff.added.len(), 10);
+
+    let tree_diff = diff::<_, _, _, Sha3TreeHasher>(&db, root, empty).unwrap();
+    assert_eq!(tree_diff, expected_diff(&kvs, &BTreeMap::new()));
+    assert_eq!(tree_diff.removed.len(), 10);
+
+    let diffs = join::<_, _, _, Sha3TreeHasher>(&db, empty, root)
+        .collect::<Result<Vec<_>>>()
+        .unwrap();
+    assert_eq!(diffs, expected_join(expected_diff(&BTreeMap::new(), &kvs)));
+}
+
+#[test]
+fn test_diff_shared_subtree() {
+    let db = MockTestStore::new_test();
+    let mut rng = StdRng::from_seed([2; 32]);
+    let old: BTreeMap<_, _> = (0..1000)
+        .map(|_| {
+            (
+                TestKey::new_with_hash(HashValue::random_with_rng(&mut rng)),
+                TestValue::from(HashValue::random_with_rng(&mut rng).to_vec()),
+            )
+        })
+        .collect();
+    let old_root = put(
+        &db,
+        None,
+        old.iter().map(|(k, v)| (*k, Some(v.clone()))).collect(),
+    );
+
+    // Add, remove and modify a few keys, the rest of the tree is shared.
+    let mut new = old.clone();
+    let mut updates = vec![];
+    for (i, key) in old.keys().step_by(100).enumerate() {
+        match i % 2 {
+            0 => {
+                new.remove(key);
+                updates.push((*key, None));
+            }
+            _ => {
+                let value = TestValue::random();
+                new.insert(*key, value.clone());
+                updates.push((*key, Some(value)));
+            }
+        }
+    }
+    for _ in 0..5 {
+        let key = TestKey::new_with_hash(HashValue::random_with_rng(&mut rng));
+        let value = TestValue::random();
+        new.insert(key, value.clone());
+        updates.push((key, Some(value)));
+    }
+    let new_root = put(&db, Some(old_root), updates);
+    let num_nodes = db.num_nodes();
+
+    let reader = CountingTreeReader::new(db);
+    let tree_diff =
+        diff::<TestKey, TestValue, _, Sha3TreeHasher>(&reader, old_root, new_root).unwrap();
+    assert_eq!(tree_diff, expected_diff(&old, &new));
+    assert_eq!(tree_diff.added.len(), 5);
+    assert_eq!(tree_diff.removed.len(), 5);
+    assert_eq!(tree_diff.modified.len(), 5);
+    // Only the paths to the 15 changed leaves are read, not the 1000 shared leaves.
+    assert!(
+        reader.reads() < 200,
+        "{} of {} nodes read",
+        reader.reads(),
+        num_nodes
+    );
+
+    let tree_diff =
+        diff::<TestKey, TestValue, _, Sha3TreeHasher>(&reader, new_root, old_root).unwrap();
+    assert_eq!(tree_diff, expected_diff(&new, &old));
+
+    // The join reads the same paths, one difference at a time.
+    let reads = reader.reads();
+    let mut iter = join::<TestKey, TestValue, _, Sha3TreeHasher>(&reader, old_root, new_root);
+    let first = iter.next().unwrap().unwrap();
+    assert!(reader.reads() - reads < 20);
+    let mut diffs = vec![first];
+    diffs.extend(iter.map(|diff| diff.unwrap()));
+    assert_eq!(diffs, expected_join(expected_diff(&old, &new)));
+    assert!(reader.reads() - reads < 200);
+}
+
+/// Applies the leaves `changed_since` yields between `base_root` and `current_root` to `base`.
+fn apply_changes<R: TreeReader<TestKey, TestValue>>(
+    reader: &R,
+    base: &BTreeMap<TestKey, TestValue>,
+    base_root: HashValue,
+    current_root: HashValue,
+) -> BTreeMap<TestKey, TestValue> {
+    let mut materialized = base.clone();
+    for item in changed_since::<_, _, _, Sha3TreeHasher>(reader, base_root, current_root) {
+        let (key, value) = item.unwrap();
+        materialized.insert(key.origin, value.origin);
+    }
+    materialized
+}
+
+#[test]
+fn test_changed_since() {
+    let db = MockTestStore::new_test();
+    let mut rng = StdRng::from_seed([65; 32]);
+    let base: BTreeMap<_, _> = (0..1000)
+        .map(|_| {
+            (
+                TestKey::new_with_hash(HashValue::random_with_rng(&mut rng)),
+                TestValue::from(HashValue::random_with_rng(&mut rng).to_vec()),
+            )
+        })
+        .collect();
+    let base_root = put(
+        &db,
+        None,
+        base.iter().map(|(k, v)| (*k, Some(v.clone()))).collect(),
+    );
+
+    // Modify a few keys and add a few others.
+    let mut current = base.clone();
+    let mut updates = vec![];
+    for key in base.keys().step_by(100) {
+        let value = TestValue::random();
+        current.insert(*key, value.clone());
+        updates.push((*key, Some(value)));
+    }
+    for _ in 0..5 {
+        let key = TestKey::new_with_hash(HashValue::random_with_rng(&mut rng));
+        let value = TestValue::random();
+        current.insert(key, value.clone());
+        updates.push((key, Some(value)));
+    }
+    let current_root = put(&db, Some(base_root), updates);
+
+    let reader = CountingTreeReader::new(db);
+    let changes =
+        changed_since::<TestKey, TestValue, _, Sha3TreeHasher>(&reader, base_root, current_root)
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+    assert_eq!(changes.len(), 15);
+    // The changes are sorted by key hash, and only the paths to them are read.
+    assert!(changes
+        .windows(2)
+        .all(|pair| pair[0].0.merkle_hash() < pair[1].0.merkle_hash()));
+    assert!(reader.reads() < 200);
+    assert_eq!(
+        apply_changes(&reader, &base, base_root, current_root),
+        current
+    );
+
+    // Nothing changed since the current tree itself, everything since the empty tree.
+    assert_eq!(
+        changed_since::<TestKey, TestValue, _, Sha3TreeHasher>(&reader, current_root, current_root)
+            .count(),
+        0
+    );
+    assert_eq!(
+        apply_changes(
+            &reader,
+            &BTreeMap::new(),
+            *SPARSE_MERKLE_PLACEHOLDER_HASH,
+            current_root
+        ),
+        current
+    );
+}
+
+#[test]
+fn test_changed_since_skips_removed() {
+    let db = MockTestStore::new_test();
+    let kvs: BTreeMap<_, _> = (0..100)
+        .map(|_| (TestKey::random(), TestValue::random()))
+        .collect();
+    let base_root = put(
+        &db,
+        None,
+        kvs.iter().map(|(k, v)| (*k, Some(v.clone()))).collect(),
+    );
+    // Remove all but one key: the base tree is not read beyond the path to the remaining one.
+    let kept_key = kvs.keys().next().unwrap();
+    let current_root = put(
+        &db,
+        Some(base_root),
+        kvs.keys()
+            .filter(|key| *key != kept_key)
+            .map(|key| (*key, None))
+            .collect(),
+    );
+    let reader = CountingTreeReader::new(db);
+    let changes =
+        changed_since::<TestKey, TestValue, _, Sha3TreeHasher>(&reader, base_root, current_root)
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+    // The remaining leaf moved up to the root but is unchanged, and no removed key is a change.
+    assert!(changes.is_empty());
+    assert!(reader.reads() < 10);
+
+    let changes = changed_since::<TestKey, TestValue, _, Sha3TreeHasher>(
+        &reader,
+        base_root,
+        *SPARSE_MERKLE_PLACEHOLDER_HASH,
+    );
+    assert_eq!(changes.count(), 0);
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(10))]
+
+    #[test]
+    fn test_diff_matches_maps(
+        old in btree_map(any::<TestKey>(), any::<TestValue>(), 0..200),
+        changes in btree_map(any::<TestKey>(), any::<Option<TestValue>>(), 0..200),
+    ) {
+        let db = MockTestStore::new_test();
+        let old_root = put(
+            &db,
+            None,
+            old.iter().map(|(k, v)| (*k, Some(v.clone()))).collect(),
+        );
+        let mut new = old.clone();
+        for (key, value) in &changes {
+            match value {
+                Some(value) => new.insert(*key, value.clone()),
+                None => new.remove(key),
+            };
+        }
+        let new_root = put(&db, Some(old_root), changes.into_iter().collect());
+        let tree_diff = diff::<_, _, _, Sha3TreeHasher>(&db, old_root, new_root).unwrap();
+        prop_assert_eq!(tree_diff, expected_diff(&old, &new));
+        let diffs = join::<_, _, _, Sha3TreeHasher>(&db, old_root, new_root)
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        prop_assert_eq!(diffs, expected_join(expected_diff(&old, &new)));
+        // Applying the changes to the old map yields the new one, but for the removed keys.
+        let mut materialized = apply_changes(&db, &old, old_root, new_root);
+        materialized.retain(|key, _| new.contains_key(key));
+        prop_assert_eq!(materialized, new);
+    }
+}
@@ -0,0 +1,541 @@
+// Copyright (c) RoochNetwork
+// SPDX-License-Identifier: Apache-2.0
+
+//! This module implements [`diff`], computing the leaves that differ between two versions of a
+//! tree. Both trees are walked in lockstep from their roots. Since a node key is the hash of the
+//! node, two subtrees with the same node key are identical and are skipped without being read, so
+//! the cost is proportional to the size of the difference rather than to the size of the trees.
+//! [`join`] walks the trees the same way but yields the differences one at a time, so they need
+//! not be held in memory, and [`changed_since`] only yields the leaves of the new tree among them.
+
+#[cfg(test)]
+mod diff_test;
+
+use super::{
+    get_root_node,
+    hash::{HashValue, Sha3TreeHasher, TreeHasher},
+    nibble::Nibble,
+    node_type::{LeafNode, Node, NodeKey},
+    TreeReader, ROOT_NIBBLE_HEIGHT,
+};
+use crate::{Key, SMTObject, Value};
+use anyhow::{ensure, Result};
+use std::{cmp::Ordering, iter::FusedIterator, marker::PhantomData};
+
+/// The leaves that differ between two trees, see [`SMTree::diff`](crate::SMTree::diff). Each list is sorted by key hash.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TreeDiff<K, V> {
+    /// The key-value pairs that are only in the new tree.
+    pub added: Vec<(K, V)>,
+    /// The key-value pairs that are only in the old tree.
+    pub removed: Vec<(K, V)>,
+    /// The keys in both trees with different values, with the old and the new value.
+    pub modified: Vec<(K, V, V)>,
+}
+
+impl<K, V> Default for TreeDiff<K, V> {
+    fn default() -> Self {
+        Self {
+            added: vec![],
+            removed: vec![],
+            modified: vec![],
+        }
+    }
+}
+
+impl<K, V> TreeDiff<K, V> {
+    /// Returns true if the two trees have the same leaves.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()
+    }
+}
+
+/// Returns the leaves that differ between the tree at `root_a`, the old tree, and the tree at
+/// `root_b`, the new tree. The subtrees with the same node key in both trees are pruned.
+pub fn diff<K, V, R, H>(reader: &R, root_a: HashValue, root_b: HashValue) -> Result<TreeDiff<K, V>>
+where
+    R: TreeReader<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    let mut tree_diff = TreeDiff::default();
+    if root_a != root_b {
+        let node_a = get_root_node::<K, V, R, H>(reader, &root_a)?;
+        let node_b = get_root_node::<K, V, R, H>(reader, &root_b)?;
+        diff_nodes::<K, V, R, H>(reader, node_a, node_b, 0, &mut tree_diff)?;
+    }
+    Ok(tree_diff)
+}
+
+/// Helper function for `diff`, comparing the different subtrees `node_a` and `node_b` at
+/// `nibble_depth`.
+fn diff_nodes<K, V, R, H>(
+    reader: &R,
+    node_a: Node<K, V>,
+    node_b: Node<K, V>,
+    nibble_depth: usize,
+    tree_diff: &mut TreeDiff<K, V>,
+) -> Result<()>
+where
+    R: TreeReader<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    ensure!(
+        nibble_depth <= ROOT_NIBBLE_HEIGHT,
+        "Jellyfish Merkle tree has cyclic graph inside."
+    );
+    match (node_a, node_b) {
+        (Node::Internal(internal_a), Node::Internal(internal_b)) => {
+            for index in 0..16u8 {
+                let nibble = Nibble::from(index);
+                let child_a = internal_a.child(nibble).map(|child| child.hash);
+                let child_b = internal_b.child(nibble).map(|child| child.hash);
+                if child_a == child_b {
+                    continue;
+                }
+                let child_a = match child_a {
+                    Some(node_key) => reader.get_node(&node_key)?,
+                    None => Node::Null,
+                };
+                let child_b = match child_b {
+                    Some(node_key) => reader.get_node(&node_key)?,
+                    None => Node::Null,
+                };
+                diff_nodes::<K, V, R, H>(reader, child_a, child_b, nibble_depth + 1, tree_diff)?;
+            }
+        }
+        // At most one of the subtrees has more than one leaf, so there is nothing left to prune.
+        (node_a, node_b) => {
+            let mut leaves_a = vec![];
+            collect_leaves(reader, node_a, &mut leaves_a)?;
+            let mut leaves_b = vec![];
+            collect_leaves(reader, node_b, &mut leaves_b)?;
+            merge_leaves::<K, V, H>(leaves_a, leaves_b, tree_diff)?;
+        }
+    }
+    Ok(())
+}
+
+/// Helper function for `diff`, appending the leaves of the subtree of `node` to `leaves`, sorted
+/// by key hash.
+fn collect_leaves<K, V, R>(
+    reader: &R,
+    node: Node<K, V>,
+    leaves: &mut Vec<LeafNode<K, V>>,
+) -> Result<()>
+where
+    R: TreeReader<K, V>,
+    K: Key,
+    V: Value,
+{
+    match node {
+        Node::Internal(internal_node) => {
+            for index in 0..16u8 {
+                if let Some(child) = internal_node.child(Nibble::from(index)) {
+                    collect_leaves(reader, reader.get_node(&child.hash)?, leaves)?;
+                }
+            }
+        }
+        Node::Leaf(leaf_node) => leaves.push(leaf_node),
+        Node::Null => {}
+    }
+    Ok(())
+}
+
+/// Helper function for `diff`, adding the difference between `leaves_a` and `leaves_b`, both
+/// sorted by key hash, to `tree_diff`.
+fn merge_leaves<K, V, H>(
+    leaves_a: Vec<LeafNode<K, V>>,
+    leaves_b: Vec<LeafNode<K, V>>,
+    tree_diff: &mut TreeDiff<K, V>,
+) -> Result<()>
+where
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    let mut leaves_a = leaves_a.into_iter().peekable();
+    let mut leaves_b = leaves_b.into_iter().peekable();
+    loop {
+        let ordering = match (leaves_a.peek(), leaves_b.peek()) {
+            (Some(leaf_a), Some(leaf_b)) => leaf_a
+                .key_hash_with::<H>()
+                .cmp(&leaf_b.key_hash_with::<H>()),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => break,
+        };
+        match ordering {
+            Ordering::Less => {
+                let (key, value) = leaves_a
+                    .next()
+                    .expect("Leaf should exist.")
+                    .into_key_value()?;
+                tree_diff.removed.push((key.origin, value.origin));
+            }
+            Ordering::Greater => {
+                let (key, value) = leaves_b
+                    .next()
+                    .expect("Leaf should exist.")
+                    .into_key_value()?;
+                tree_diff.added.push((key.origin, value.origin));
+            }
+            Ordering::Equal => {
+                let leaf_a = leaves_a.next().expect("Leaf should exist.");
+                let leaf_b = leaves_b.next().expect("Leaf should exist.");
+                if leaf_a.value_hash_with::<H>() != leaf_b.value_hash_with::<H>() {
+                    let (key, old_value) = leaf_a.into_key_value()?;
+                    let (_, new_value) = leaf_b.into_key_value()?;
+                    tree_diff
+                        .modified
+                        .push((key.origin, old_value.origin, new_value.origin));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A difference between two trees yielded by [`MergeJoinIterator`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Diff<K, V> {
+    /// The key-value pair is only in the new tree.
+    Added(K, V),
+    /// The key-value pair is only in the old tree.
+    Removed(K, V),
+    /// The key is in both trees with different values, the old and the new value.
+    Changed(K, V, V),
+}
+
+/// Returns an iterator over the leaves that differ between the tree at `root_a`, the old tree,
+/// and the tree at `root_b`, the new tree, in ascending key hash order. This is the streaming
+/// counterpart of [`diff`].
+pub fn join<K, V, R, H>(
+    reader: &R,
+    root_a: HashValue,
+    root_b: HashValue,
+) -> MergeJoinIterator<'_, K, V, R, H>
+where
+    R: TreeReader<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    MergeJoinIterator::new(reader, root_a, root_b)
+}
+
+/// Returns an iterator over the leaves of the tree at `current_root` which are absent from the
+/// tree at `base_root` or have another value there, in ascending key hash order. The leaves only
+/// in the base tree are not yielded, and the subtrees of the base tree which are not in the
+/// current tree are not read. Otherwise the trees are walked as by [`join`], the subtrees with the
+/// same node key in both being skipped, so this is what an incremental indexer needs to bring the
+/// tables derived from the base tree up to date, save for the deleted keys.
+pub fn changed_since<K, V, R, H>(
+    reader: &R,
+    base_root: HashValue,
+    current_root: HashValue,
+) -> ChangedSinceIterator<'_, K, V, R, H>
+where
+    R: TreeReader<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    ChangedSinceIterator::new(reader, base_root, current_root)
+}
+
+/// One side of a pair of subtrees compared by `LeafJoin`.
+enum Side<K, V> {
+    /// A subtree that has not been read yet.
+    Unread(NodeKey),
+    /// A leaf that has been read, which may be the leaf of a tree under an internal node of the
+    /// other tree, compared to the subtrees of its children.
+    Leaf(LeafNode<K, V>),
+    /// No subtree.
+    Null,
+}
+
+/// Two different subtrees compared by `LeafJoin`, and their nibble depth.
+type SubtreePair<K, V> = (Side<K, V>, Side<K, V>, usize);
+
+/// A difference found by `LeafJoin`: the leaf of the old tree, the leaf of the new tree, or the
+/// leaves of both with the same key and different values.
+type LeafPair<K, V> = (Option<LeafNode<K, V>>, Option<LeafNode<K, V>>);
+
+/// The traversal shared by `MergeJoinIterator` and `ChangedSinceIterator`. Both trees are
+/// descended together in a depth first traversal. The subtrees with the same node key in both
+/// trees are skipped without being read. When a subtree is an internal node in one tree but a
+/// single leaf or nothing in the other, the leaf is carried down along the internal node, so only
+/// the nodes on the path to the next difference are ever held.
+struct LeafJoin<'a, K, V, R, H> {
+    reader: &'a R,
+    /// The pairs of subtrees left to compare, in reverse order: the next pair is on top.
+    stack: Vec<SubtreePair<K, V>>,
+    /// The second difference found when comparing two leaves with different keys.
+    pending: Option<LeafPair<K, V>>,
+    /// Whether the leaves only in the old tree are skipped, along with the subtrees only in the
+    /// old tree.
+    only_new: bool,
+    hasher: PhantomData<H>,
+}
+
+impl<'a, K, V, R, H> LeafJoin<'a, K, V, R, H>
+where
+    R: TreeReader<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    fn new(reader: &'a R, root_a: HashValue, root_b: HashValue, only_new: bool) -> Self {
+        let mut stack = vec![];
+        if root_a != root_b {
+            let root_side = |root: HashValue| {
+                if H::is_empty_root(root) {
+                    Side::Null
+                } else {
+                    Side::Unread(root)
+                }
+            };
+            stack.push((root_side(root_a), root_side(root_b), 0));
+        }
+        Self {
+            reader,
+            stack,
+            pending: None,
+            only_new,
+            hasher: PhantomData,
+        }
+    }
+
+    fn read(&self, side: Side<K, V>) -> Result<Node<K, V>> {
+        match side {
+            Side::Unread(node_key) => self.reader.get_node(&node_key),
+            Side::Leaf(leaf_node) => Ok(Node::Leaf(leaf_node)),
+            Side::Null => Ok(Node::Null),
+        }
+    }
+
+    /// Pushes the pairs of children of two subtrees at `nibble_depth` which may differ, in
+    /// reverse nibble order so that they are popped in ascending order.
+    fn push_children(&mut self, node_a: Node<K, V>, node_b: Node<K, V>, nibble_depth: usize) {
+        for index in (0..16u8).rev() {
+            let nibble = Nibble::from(index);
+            let child_a = child_side::<K, V, H>(&node_a, nibble, nibble_depth);
+            let child_b = child_side::<K, V, H>(&node_b, nibble, nibble_depth);
+            match (&child_a, &child_b) {
+                (Side::Unread(key_a), Side::Unread(key_b)) if key_a == key_b => continue,
+                (Side::Null, Side::Null) => continue,
+                (_, Side::Null) if self.only_new => continue,
+                _ => {}
+            }
+            self.stack.push((child_a, child_b, nibble_depth + 1));
+        }
+    }
+
+    fn next_pair(&mut self) -> Result<Option<LeafPair<K, V>>> {
+        if let Some(pair) = self.pending.take() {
+            return Ok(Some(pair));
+        }
+        while let Some((side_a, side_b, nibble_depth)) = self.stack.pop() {
+            ensure!(
+                nibble_depth <= ROOT_NIBBLE_HEIGHT,
+                "Jellyfish Merkle tree has cyclic graph inside."
+            );
+            if self.only_new && matches!(side_b, Side::Null) {
+                continue;
+            }
+            let node_a = self.read(side_a)?;
+            let node_b = self.read(side_b)?;
+            match (node_a, node_b) {
+                (Node::Leaf(leaf_a), Node::Leaf(leaf_b)) => {
+                    match leaf_a
+                        .key_hash_with::<H>()
+                        .cmp(&leaf_b.key_hash_with::<H>())
+                    {
+                        Ordering::Less => {
+                            if self.only_new {
+                                return Ok(Some((None, Some(leaf_b))));
+                            }
+                            self.pending = Some((None, Some(leaf_b)));
+                            return Ok(Some((Some(leaf_a), None)));
+                        }
+                        Ordering::Greater => {
+                            if !self.only_new {
+                                self.pending = Some((Some(leaf_a), None));
+                            }
+                            return Ok(Some((None, Some(leaf_b))));
+                        }
+                        Ordering::Equal => {
+                            if leaf_a.value_hash_with::<H>() != leaf_b.value_hash_with::<H>() {
+                                return Ok(Some((Some(leaf_a), Some(leaf_b))));
+                            }
+                        }
+                    }
+                }
+                (Node::Leaf(leaf_a), Node::Null) => return Ok(Some((Some(leaf_a), None))),
+                (Node::Null, Node::Leaf(leaf_b)) => return Ok(Some((None, Some(leaf_b)))),
+                (Node::Null, Node::Null) => {}
+                // At least one of the subtrees is an internal node.
+                (node_a, node_b) => self.push_children(node_a, node_b, nibble_depth),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Stops the traversal, after an error.
+    fn clear(&mut self) {
+        self.stack.clear();
+        self.pending = None;
+    }
+}
+
+/// Returns the child of `node` at `nibble`, `node` being at `nibble_depth`. A leaf stands for
+/// itself at the nibble of its key hash.
+fn child_side<K, V, H>(node: &Node<K, V>, nibble: Nibble, nibble_depth: usize) -> Side<K, V>
+where
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    match node {
+        Node::Internal(internal_node) => match internal_node.child(nibble) {
+            Some(child) => Side::Unread(child.hash),
+            None => Side::Null,
+        },
+        Node::Leaf(leaf_node)
+            if Nibble::from(leaf_node.key_hash_with::<H>().nibble(nibble_depth)) == nibble =>
+        {
+            Side::Leaf(leaf_node.clone())
+        }
+        Node::Leaf(_) | Node::Null => Side::Null,
+    }
+}
+
+/// The `MergeJoinIterator` implementation, see [`SMTree::join`](crate::SMTree::join).
+pub struct MergeJoinIterator<'a, K, V, R: 'a + TreeReader<K, V>, H = Sha3TreeHasher> {
+    join: LeafJoin<'a, K, V, R, H>,
+}
+
+impl<'a, K, V, R, H> MergeJoinIterator<'a, K, V, R, H>
+where
+    R: TreeReader<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    /// Constructs a new iterator, see [`SMTree::join`](crate::SMTree::join). Nothing is read until the first call to `next`.
+    pub fn new(reader: &'a R, root_a: HashValue, root_b: HashValue) -> Self {
+        Self {
+            join: LeafJoin::new(reader, root_a, root_b, false),
+        }
+    }
+
+    fn next_diff(&mut self) -> Result<Option<Diff<K, V>>> {
+        let diff = match self.join.next_pair()? {
+            Some((Some(leaf_a), None)) => {
+                let (key, value) = leaf_a.into_key_value()?;
+                Diff::Removed(key.origin, value.origin)
+            }
+            Some((None, Some(leaf_b))) => {
+                let (key, value) = leaf_b.into_key_value()?;
+                Diff::Added(key.origin, value.origin)
+            }
+            Some((Some(leaf_a), Some(leaf_b))) => {
+                let (key, old_value) = leaf_a.into_key_value()?;
+                let (_, new_value) = leaf_b.into_key_value()?;
+                Diff::Changed(key.origin, old_value.origin, new_value.origin)
+            }
+            Some((None, None)) | None => return Ok(None),
+        };
+        Ok(Some(diff))
+    }
+}
+
+impl<'a, K, V, R, H> Iterator for MergeJoinIterator<'a, K, V, R, H>
+where
+    R: TreeReader<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    type Item = Result<Diff<K, V>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_diff() {
+            Ok(diff) => diff.map(Ok),
+            Err(err) => {
+                // Stop iterating after an error.
+                self.join.clear();
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+impl<'a, K, V, R, H> FusedIterator for MergeJoinIterator<'a, K, V, R, H>
+where
+    R: TreeReader<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+}
+
+/// The `ChangedSinceIterator` implementation, see
+/// [`SMTree::changed_since`](crate::SMTree::changed_since).
+pub struct ChangedSinceIterator<'a, K, V, R: 'a + TreeReader<K, V>, H = Sha3TreeHasher> {
+    join: LeafJoin<'a, K, V, R, H>,
+}
+
+impl<'a, K, V, R, H> ChangedSinceIterator<'a, K, V, R, H>
+where
+    R: TreeReader<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    /// Constructs a new iterator, see [`SMTree::changed_since`](crate::SMTree::changed_since). Nothing is read until the first call to
+    /// `next`.
+    pub fn new(reader: &'a R, base_root: HashValue, current_root: HashValue) -> Self {
+        Self {
+            join: LeafJoin::new(reader, base_root, current_root, true),
+        }
+    }
+}
+
+impl<'a, K, V, R, H> Iterator for ChangedSinceIterator<'a, K, V, R, H>
+where
+    R: TreeReader<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    type Item = Result<(SMTObject<K>, SMTObject<V>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.join.next_pair() {
+                Ok(Some((_, Some(leaf_node)))) => return Some(leaf_node.into_key_value()),
+                Ok(Some((_, None))) => continue,
+                Ok(None) => return None,
+                Err(err) => {
+                    // Stop iterating after an error.
+                    self.join.clear();
+                    return Some(Err(err));
+                }
+            }
+        }
+    }
+}
+
+impl<'a, K, V, R, H> FusedIterator for ChangedSinceIterator<'a, K, V, R, H>
+where
+    R: TreeReader<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+}
@@ -7,6 +7,8 @@ use proptest_derive::Arbitrary;
 use rand::{rngs::OsRng, Rng};
 use serde::{de, ser};
 use std::{
+    any::TypeId,
+    cell::Cell,
     fmt::{self, Debug},
     str::FromStr,
 };
@@ -30,9 +32,13 @@ impl HashValue {
     pub const LENGTH: usize = 32;
     /// The length of the hash in bits.
     pub const LENGTH_IN_BITS: usize = Self::LENGTH * 8;
+    /// The root hash of an empty tree with the default [`Sha3TreeHasher`]. A fresh tree starts
+    /// from it, and it is never stored, so reading the empty tree needs no storage read.
+    pub const SPARSE_MERKLE_PLACEHOLDER: Self =
+        HashValue::new(*b"SPARSE_MERKLE_PLACEHOLDER_HASH\0\0");
 
     /// Create a new [`HashValue`] from a byte array.
-    pub fn new(hash: [u8; HashValue::LENGTH]) -> Self {
+    pub const fn new(hash: [u8; HashValue::LENGTH]) -> Self {
         HashValue { hash }
     }
 
@@ -386,8 +392,218 @@ impl<'a> std::iter::ExactSizeIterator for HashValueBitIterator<'a> {}
 /// A type that implements `SMTHash` can be hashed by a cryptographic hash function and produce
 /// a `HashValue`.
 pub trait SMTHash {
-    /// Hashes the object and produces a `HashValue`.
-    fn merkle_hash(&self) -> HashValue;
+    /// Hashes the object with the hash functions of `H` and produces a `HashValue`.
+    fn merkle_hash_with<H: TreeHasher>(&self) -> HashValue;
+
+    /// Hashes the object with the default [`Sha3TreeHasher`] and produces a `HashValue`.
+    fn merkle_hash(&self) -> HashValue {
+        self.merkle_hash_with::<Sha3TreeHasher>()
+    }
+}
+
+/// The hash functions a tree is built with. The key hashes, which place the keys in the tree, the
+/// node hashes and the root hash all depend on them, so a tree has to be read and proven with the
+/// hasher it was written with.
+///
+/// A hasher is never instantiated, it is only a type parameter of the tree, the iterators and the
+/// proofs, which derive the usual traits for it.
+pub trait TreeHasher: Clone + Copy + Debug + Default + Eq + Send + Sync + 'static {
+    /// The hash of an empty subtree, which is also the root hash of an empty tree.
+    const SPARSE_MERKLE_PLACEHOLDER: HashValue;
+
+    /// Hashes the encoded bytes of a key or a value.
+    fn hash(data: &[u8]) -> HashValue;
+
+    /// Hashes a leaf from the hashes of its key and value.
+    fn hash_leaf(key_hash: HashValue, value_hash: HashValue) -> HashValue;
+
+    /// Hashes an internal node of the binary tree from the hashes of its children.
+    fn hash_internal(left: HashValue, right: HashValue) -> HashValue;
+
+    /// Returns true if `root` is the root hash of an empty tree.
+    fn is_empty_root(root: HashValue) -> bool {
+        root == Self::SPARSE_MERKLE_PLACEHOLDER
+    }
+}
+
+/// The default [`TreeHasher`]: SHA3-256 of the data, and SHA3-256 of the concatenated child hashes
+/// for both the leaves and the internal nodes.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Sha3TreeHasher;
+
+impl TreeHasher for Sha3TreeHasher {
+    const SPARSE_MERKLE_PLACEHOLDER: HashValue = HashValue::SPARSE_MERKLE_PLACEHOLDER;
+
+    fn hash(data: &[u8]) -> HashValue {
+        HashValue::sha3_256_of(data)
+    }
+
+    fn hash_leaf(key_hash: HashValue, value_hash: HashValue) -> HashValue {
+        merkle_hash(key_hash, value_hash)
+    }
+
+    fn hash_internal(left: HashValue, right: HashValue) -> HashValue {
+        merkle_hash(left, right)
+    }
+}
+
+/// The prefix of the hashed bytes of a leaf with the domain separated hashers.
+#[cfg(any(feature = "sha3", feature = "sha256", feature = "blake3"))]
+const LEAF_DOMAIN: u8 = 0x00;
+/// The prefix of the hashed bytes of an internal node with the domain separated hashers.
+#[cfg(any(feature = "sha3", feature = "sha256", feature = "blake3"))]
+const INTERNAL_DOMAIN: u8 = 0x01;
+
+/// A [`TreeHasher`] with SHA3-256. Unlike [`Sha3TreeHasher`], the leaf and internal node hashes
+/// are domain separated: the child hashes are prefixed with `0x00` for a leaf and `0x01` for an
+/// internal node, so a leaf can not be passed off as an internal node in a proof.
+#[cfg(feature = "sha3")]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Sha3_256Hasher;
+
+#[cfg(feature = "sha3")]
+impl Sha3_256Hasher {
+    fn hash_children(domain: u8, left: HashValue, right: HashValue) -> HashValue {
+        let mut sha3 = Sha3::v256();
+        sha3.update(&[domain]);
+        sha3.update(left.as_ref());
+        sha3.update(right.as_ref());
+        HashValue::from_keccak(sha3)
+    }
+}
+
+#[cfg(feature = "sha3")]
+impl TreeHasher for Sha3_256Hasher {
+    const SPARSE_MERKLE_PLACEHOLDER: HashValue =
+        HashValue::new(*b"SPARSE_MERKLE_PLACEHOLDER_SHA3\0\0");
+
+    fn hash(data: &[u8]) -> HashValue {
+        HashValue::sha3_256_of(data)
+    }
+
+    fn hash_leaf(key_hash: HashValue, value_hash: HashValue) -> HashValue {
+        Self::hash_children(LEAF_DOMAIN, key_hash, value_hash)
+    }
+
+    fn hash_internal(left: HashValue, right: HashValue) -> HashValue {
+        Self::hash_children(INTERNAL_DOMAIN, left, right)
+    }
+}
+
+/// A [`TreeHasher`] with SHA-256, whose leaf and internal node hashes are domain separated the
+/// same way as the ones of [`Sha3_256Hasher`].
+#[cfg(feature = "sha256")]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Sha256Hasher;
+
+#[cfg(feature = "sha256")]
+impl Sha256Hasher {
+    fn hash_children(domain: u8, left: HashValue, right: HashValue) -> HashValue {
+        use sha2::Digest;
+        let digest = sha2::Sha256::new()
+            .chain_update([domain])
+            .chain_update(left.as_ref())
+            .chain_update(right.as_ref())
+            .finalize();
+        HashValue::new(digest.into())
+    }
+}
+
+#[cfg(feature = "sha256")]
+impl TreeHasher for Sha256Hasher {
+    const SPARSE_MERKLE_PLACEHOLDER: HashValue =
+        HashValue::new(*b"SPARSE_MERKLE_PLACEHOLDER_SHA256");
+
+    fn hash(data: &[u8]) -> HashValue {
+        use sha2::Digest;
+        HashValue::new(sha2::Sha256::digest(data).into())
+    }
+
+    fn hash_leaf(key_hash: HashValue, value_hash: HashValue) -> HashValue {
+        Self::hash_children(LEAF_DOMAIN, key_hash, value_hash)
+    }
+
+    fn hash_internal(left: HashValue, right: HashValue) -> HashValue {
+        Self::hash_children(INTERNAL_DOMAIN, left, right)
+    }
+}
+
+/// A [`TreeHasher`] with BLAKE3, whose leaf and internal node hashes are domain separated: the
+/// child hashes are prefixed with `0x00` for a leaf and `0x01` for an internal node.
+#[cfg(feature = "blake3")]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Blake3Hasher;
+
+#[cfg(feature = "blake3")]
+impl Blake3Hasher {
+    fn hash_children(domain: u8, left: HashValue, right: HashValue) -> HashValue {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&[domain]);
+        hasher.update(left.as_ref());
+        hasher.update(right.as_ref());
+        HashValue::new(*hasher.finalize().as_bytes())
+    }
+}
+
+#[cfg(feature = "blake3")]
+impl TreeHasher for Blake3Hasher {
+    const SPARSE_MERKLE_PLACEHOLDER: HashValue =
+        HashValue::new(*b"SPARSE_MERKLE_PLACEHOLDER_BLAKE3");
+
+    fn hash(data: &[u8]) -> HashValue {
+        HashValue::new(*blake3::hash(data).as_bytes())
+    }
+
+    fn hash_leaf(key_hash: HashValue, value_hash: HashValue) -> HashValue {
+        Self::hash_children(LEAF_DOMAIN, key_hash, value_hash)
+    }
+
+    fn hash_internal(left: HashValue, right: HashValue) -> HashValue {
+        Self::hash_children(INTERNAL_DOMAIN, left, right)
+    }
+}
+
+/// The cached hash of an object, along with the [`TreeHasher`] it was computed with, so an object
+/// hashed with another hasher does not get a stale hash.
+#[derive(Clone, Debug, Default)]
+pub struct HashCache(Cell<Option<(TypeId, HashValue)>>);
+
+/// Whether the hash of an object is cached does not change the object, so all caches are equal.
+impl PartialEq for HashCache {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for HashCache {}
+
+impl HashCache {
+    /// Creates a cache already holding `hash` as the hash of the object with `H`.
+    pub fn with_hash<H: TreeHasher>(hash: HashValue) -> Self {
+        HashCache(Cell::new(Some((TypeId::of::<H>(), hash))))
+    }
+
+    /// Returns the cached hash with `H`, computing it with `hash` if it is not cached yet.
+    pub fn get_or_compute<H: TreeHasher>(&self, hash: impl FnOnce() -> HashValue) -> HashValue {
+        match self.0.get() {
+            Some((hasher, hash)) if hasher == TypeId::of::<H>() => hash,
+            _ => {
+                let hash = hash();
+                self.0.set(Some((TypeId::of::<H>(), hash)));
+                hash
+            }
+        }
+    }
+}
+
+#[cfg(any(test, feature = "fuzzing"))]
+impl proptest::arbitrary::Arbitrary for HashCache {
+    type Parameters = ();
+    type Strategy = proptest::strategy::Just<Self>;
+
+    fn arbitrary_with(_args: ()) -> Self::Strategy {
+        proptest::strategy::Just(HashCache::default())
+    }
 }
 
 pub fn create_literal_hash(word: &str) -> HashValue {
@@ -397,6 +613,12 @@ pub fn create_literal_hash(word: &str) -> HashValue {
     HashValue::from_slice(&s).expect("Cannot fail")
 }
 
-/// Placeholder hash of `SparseMerkleTree`.
+/// Placeholder hash of `SparseMerkleTree` with the default [`Sha3TreeHasher`].
 pub static SPARSE_MERKLE_PLACEHOLDER_HASH: Lazy<HashValue> =
-    Lazy::new(|| create_literal_hash("SPARSE_MERKLE_PLACEHOLDER_HASH"));
+    Lazy::new(|| HashValue::SPARSE_MERKLE_PLACEHOLDER);
+
+/// Returns true if `root` is the root hash of an empty tree with the default [`Sha3TreeHasher`],
+/// see [`TreeHasher::is_empty_root`] for the other hashers.
+pub fn is_empty_root(root: HashValue) -> bool {
+    Sha3TreeHasher::is_empty_root(root)
+}
@@ -0,0 +1,220 @@
+// Copyright (c) RoochNetwork
+// SPDX-License-Identifier: Apache-2.0
+
+//! This module implements `IntegrityScan`, the check that every node of a tree hashes to its
+//! `NodeKey`, run a bounded number of nodes at a time and resumable from an `IteratorCursor`, so
+//! a scan of a large store can be rate limited and spread over several runs.
+
+use super::{Direction, IteratorCursor, NodeVisitInfo};
+use crate::jellyfish_merkle::{
+    hash::{HashValue, SMTHash, Sha3TreeHasher, TreeHasher},
+    nibble::Nibble,
+    node_type::{Node, NodeKey},
+    Versioned, ROOT_NIBBLE_HEIGHT,
+};
+use crate::{Key, Value};
+use anyhow::{bail, ensure, format_err, Result};
+use std::{marker::PhantomData, ops::Bound};
+
+/// Walks the tree at a root depth first, in nibble order, recomputing the hash of every node and
+/// reporting the ones whose stored content does not hash to their key. Each node is checked once
+/// before its children, and the children of a node which does not hash to its key are not read,
+/// since its content cannot be trusted.
+///
+/// The scan is run by [`step`](Self::step) calls, each checking a bounded number of nodes. Its
+/// position is taken by [`checkpoint`](Self::checkpoint) as an [`IteratorCursor`] holding the path
+/// of internal nodes to the next node to check, and [`resume`](Self::resume) builds a scan
+/// checking the remaining nodes, each of them once.
+pub struct IntegrityScan<'a, K, V, R, H = Sha3TreeHasher> {
+    reader: &'a R,
+    state_root_hash: HashValue,
+    /// The internal nodes on the path to the next node to check, from the root down, each with
+    /// the child on the path as the next child to visit. The root is the next node to check if
+    /// it is empty and the scan is not done.
+    stack: Vec<NodeVisitInfo>,
+    /// Whether all the nodes have been checked.
+    done: bool,
+    /// The number of nodes checked since the scan was created or resumed.
+    nodes_checked: u64,
+    phantom: PhantomData<(K, V, H)>,
+}
+
+impl<'a, K, V, R, H> IntegrityScan<'a, K, V, R, H>
+where
+    R: 'a + Versioned<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    /// Creates a scan of the tree at `state_root_hash`, starting at the root. Nothing is read
+    /// until the first `step` call.
+    pub fn new(reader: &'a R, state_root_hash: HashValue) -> Self {
+        Self {
+            reader,
+            state_root_hash,
+            stack: Vec::with_capacity(ROOT_NIBBLE_HEIGHT),
+            done: H::is_empty_root(state_root_hash),
+            nodes_checked: 0,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Rebuilds the scan at the position `cursor` was taken at by `checkpoint`. The nodes on the
+    /// path of the cursor were checked before it was taken; they are read again and checked to
+    /// form a path from `state_root_hash` and to still hash to their keys.
+    pub fn resume(
+        reader: &'a R,
+        state_root_hash: HashValue,
+        cursor: IteratorCursor,
+    ) -> Result<Self> {
+        ensure!(
+            cursor.state_root_hash == state_root_hash,
+            "The cursor was taken on root {:x}, not on root {:x}.",
+            cursor.state_root_hash,
+            state_root_hash
+        );
+        ensure!(
+            cursor.direction == Direction::Ascending && cursor.end == Bound::Unbounded,
+            "The cursor was not taken by an integrity scan."
+        );
+        ensure!(
+            cursor.stack.len() <= ROOT_NIBBLE_HEIGHT,
+            "The cursor is deeper than the tree can be."
+        );
+        let mut scan = Self::new(reader, state_root_hash);
+        scan.done |= cursor.done;
+        let mut expected_node_key = state_root_hash;
+        for (node_key, next_child) in cursor.stack {
+            ensure!(
+                node_key == expected_node_key,
+                "The cursor does not follow the tree at node {:x}.",
+                node_key
+            );
+            let node = reader.get_node(&node_key)?;
+            let node_hash = node.merkle_hash_with::<H>();
+            ensure!(
+                node_hash == node_key,
+                "Node {:x} on the path of the cursor hashes to {:x}.",
+                node_key,
+                node_hash
+            );
+            let internal_node = match node {
+                Node::Internal(internal_node) => internal_node,
+                _ => bail!("The cursor expects an internal node at {:x}.", node_key),
+            };
+            expected_node_key = internal_node
+                .child(next_child)
+                .ok_or_else(|| {
+                    format_err!(
+                        "The cursor points to the missing child {:?} of node {:x}.",
+                        next_child,
+                        node_key
+                    )
+                })?
+                .hash;
+            scan.stack.push(NodeVisitInfo::new_next_child_to_visit(
+                node_key,
+                internal_node,
+                next_child,
+                Direction::Ascending,
+            )?);
+        }
+        Ok(scan)
+    }
+
+    /// Checks up to `max_nodes` nodes, returning the keys of the ones which do not hash to their
+    /// key. A failure to read a node is returned as is, and the following call reads it again.
+    pub fn step(&mut self, max_nodes: usize) -> Result<Vec<NodeKey>> {
+        let mut corrupted = vec![];
+        for _ in 0..max_nodes {
+            if self.done {
+                break;
+            }
+            if let Some(node_key) = self.check_next()? {
+                corrupted.push(node_key);
+            }
+        }
+        Ok(corrupted)
+    }
+
+    /// Returns the position of the scan, so that [`resume`](Self::resume) can later check the
+    /// nodes not checked yet.
+    pub fn checkpoint(&self) -> IteratorCursor {
+        IteratorCursor {
+            state_root_hash: self.state_root_hash,
+            stack: self
+                .stack
+                .iter()
+                .map(|info| {
+                    (
+                        info.node_key,
+                        Nibble::from(info.next_child_to_visit.trailing_zeros() as u8),
+                    )
+                })
+                .collect(),
+            done: self.done,
+            direction: Direction::Ascending,
+            end: Bound::Unbounded,
+            front_bound: Bound::Unbounded,
+        }
+    }
+
+    /// Whether all the nodes have been checked.
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// Returns the number of nodes checked since the scan was created or resumed.
+    pub fn nodes_checked(&self) -> u64 {
+        self.nodes_checked
+    }
+
+    /// Checks the next node, returning its key if it does not hash to it.
+    fn check_next(&mut self) -> Result<Option<NodeKey>> {
+        let node_key = match self.stack.last() {
+            Some(info) => {
+                let nibble = Nibble::from(info.next_child_to_visit.trailing_zeros() as u8);
+                info.node
+                    .child(nibble)
+                    .expect("The next child to visit exists.")
+                    .hash
+            }
+            None => self.state_root_hash,
+        };
+        let node = self.reader.get_node(&node_key)?;
+        self.nodes_checked += 1;
+        if node.merkle_hash_with::<H>() != node_key {
+            self.move_past_subtree()?;
+            return Ok(Some(node_key));
+        }
+        match node {
+            Node::Internal(internal_node) => {
+                ensure!(
+                    self.stack.len() < ROOT_NIBBLE_HEIGHT,
+                    "Should have reached the bottom of the tree at internal node {:x}.",
+                    node_key
+                );
+                self.stack.push(NodeVisitInfo::new(
+                    node_key,
+                    internal_node,
+                    Direction::Ascending,
+                )?);
+            }
+            _ => self.move_past_subtree()?,
+        }
+        Ok(None)
+    }
+
+    /// Moves to the node following the subtree of the node just checked, i.e. to the next sibling
+    /// of the nearest node on the path which has one.
+    fn move_past_subtree(&mut self) -> Result<()> {
+        while let Some(info) = self.stack.last_mut() {
+            if !info.is_last(Direction::Ascending) {
+                return info.advance(Direction::Ascending);
+            }
+            self.stack.pop();
+        }
+        self.done = true;
+        Ok(())
+    }
+}
@@ -1,8 +1,2655 @@
 // Copyright (c) The Starcoin Core Contributors
 // SPDX-License-Identifier: Apache-2.0
 
+use super::{
+    count_leaves, depth_histogram, first_key,
+    integrity::IntegrityScan,
+    last_key,
+    merge::{merge_sorted, PeekableLeaf},
+    neighbors, nth_leaf, Direction, JellyfishMerkleIntoIterator, JellyfishMerkleIterator,
+    JellyfishMerkleStructureIterator, NodeVisitInfo, StructuralEvent, Traversal,
+};
+use crate::jellyfish_merkle::{
+    detach_large_values,
+    hash::{HashValue, SMTHash, Sha3TreeHasher, SPARSE_MERKLE_PLACEHOLDER_HASH},
+    mock_tree_store::{CountingTreeReader, MockTestStore, TestKey, TestValue},
+    nibble::Nibble,
+    nibble_path::NibblePath,
+    node_type::{Child, Children, InternalNode, Node, NodeKey},
+    test_helper::{minus_one, plus_one},
+    JellyfishMerkleTree, SmtError, TreeReader, TreeWriter, ValueReader, Versioned,
+    ROOT_NIBBLE_HEIGHT,
+};
+use crate::{EncodeToObject, InMemoryNodeStore, NodeStore, SMTObject};
+use anyhow::Result;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rayon::prelude::*;
+use std::{
+    cell::Cell,
+    collections::{BTreeMap, HashMap, VecDeque},
+    ops::Bound,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+#[cfg(feature = "async")]
+use {
+    super::JellyfishMerkleStream,
+    crate::jellyfish_merkle::AsyncTreeReader,
+    futures_core::Stream,
+    std::{
+        future::{poll_fn, Future},
+        pin::{pin, Pin},
+        task::{Context, Poll, Waker},
+    },
+};
+
+fn init_tree(db: &MockTestStore, n: usize) -> (Option<HashValue>, BTreeMap<HashValue, TestValue>) {
+    let tree: JellyfishMerkleTree<TestKey, TestValue, MockTestStore> = JellyfishMerkleTree::new(db);
+    let mut rng = StdRng::from_seed([1; 32]);
+
+    let mut btree = BTreeMap::new();
+    for i in 0..n {
+        let key = HashValue::random_with_rng(&mut rng);
+        let value = TestValue::from(i.to_be_bytes().to_vec());
+        assert_eq!(btree.insert(key, value), None);
+    }
+    if btree.is_empty() {
+        return (None, btree);
+    }
+
+    let (root_hash, batch) = tree
+        .put_blob_set(
+            None,
+            btree
+                .iter()
+                .map(|(k, v)| (TestKey(*k).into_object(), v.clone().into_object()))
+                .collect(),
+        )
+        .unwrap();
+    db.write_tree_update_batch(batch).unwrap();
+    (Some(root_hash), btree)
+}
+
+fn key_object(key: HashValue) -> SMTObject<TestKey> {
+    TestKey(key).into_object()
+}
+
+fn collect<R: TreeReader<TestKey, TestValue>>(
+    iter: JellyfishMerkleIterator<TestKey, TestValue, R>,
+) -> Vec<(HashValue, TestValue)> {
+    iter.map(|item| item.map(|(k, v)| (k.origin.0, v.origin)))
+        .collect::<Result<Vec<_>>>()
+        .unwrap()
+}
+
+#[test]
+fn test_iterator() {
+    for n in [1, 2, 50] {
+        let db = MockTestStore::new_test();
+        let (root, btree) = init_tree(&db, n);
+        let root = root.unwrap();
+
+        let expected = btree.clone().into_iter().collect::<Vec<_>>();
+        let iter = JellyfishMerkleIterator::new(&db, root, None).unwrap();
+        assert_eq!(collect(iter), expected);
+
+        for (i, key) in btree.keys().enumerate() {
+            let iter = JellyfishMerkleIterator::new(&db, root, Some(key_object(*key))).unwrap();
+            assert_eq!(collect(iter), expected[i..].to_vec());
+
+            let iter =
+                JellyfishMerkleIterator::new(&db, root, Some(key_object(plus_one(*key)))).unwrap();
+            assert_eq!(collect(iter), expected[i + 1..].to_vec());
+        }
+
+        let iter = JellyfishMerkleIterator::new(
+            &db,
+            root,
+            Some(key_object(HashValue::new([0xff; HashValue::LENGTH]))),
+        )
+        .unwrap();
+        assert_eq!(collect(iter), vec![]);
+    }
+}
+
+#[test]
+fn test_iterator_rev() {
+    for n in [1, 2, 50] {
+        let db = MockTestStore::new_test();
+        let (root, btree) = init_tree(&db, n);
+        let root = root.unwrap();
+
+        let expected = btree.clone().into_iter().rev().collect::<Vec<_>>();
+        let iter = JellyfishMerkleIterator::new_rev(&db, root, None).unwrap();
+        assert_eq!(collect(iter), expected);
+
+        for (i, key) in btree.keys().rev().enumerate() {
+            let iter = JellyfishMerkleIterator::new_rev(&db, root, Some(key_object(*key))).unwrap();
+            assert_eq!(collect(iter), expected[i..].to_vec());
+
+            let iter =
+                JellyfishMerkleIterator::new_rev(&db, root, Some(key_object(minus_one(*key))))
+                    .unwrap();
+            assert_eq!(collect(iter), expected[i + 1..].to_vec());
+
+            let iter =
+                JellyfishMerkleIterator::new_rev(&db, root, Some(key_object(plus_one(*key))))
+                    .unwrap();
+            assert_eq!(collect(iter), expected[i..].to_vec());
+        }
+
+        let iter = JellyfishMerkleIterator::new_rev(&db, root, Some(key_object(HashValue::zero())))
+            .unwrap();
+        assert_eq!(collect(iter), vec![]);
+    }
+}
+
+#[test]
+fn test_iterator_rev_empty_tree() {
+    let db = MockTestStore::new_test();
+    let tree: JellyfishMerkleTree<TestKey, TestValue, MockTestStore> =
+        JellyfishMerkleTree::new(&db);
+    let (root, batch) = tree
+        .updates(None, vec![(key_object(HashValue::random()), None)])
+        .unwrap();
+    db.write_tree_update_batch(batch).unwrap();
+
+    let iter = JellyfishMerkleIterator::new(&db, root, None).unwrap();
+    assert_eq!(collect(iter), vec![]);
+    let iter = JellyfishMerkleIterator::new_rev(&db, root, None).unwrap();
+    assert_eq!(collect(iter), vec![]);
+}
+
+#[test]
+fn test_iterator_empty_root_reads_nothing() {
+    let reader = CountingTreeReader::new(MockTestStore::new_test());
+    let root = HashValue::SPARSE_MERKLE_PLACEHOLDER;
+    let iter = JellyfishMerkleIterator::new(&reader, root, None).unwrap();
+    assert_eq!(collect(iter), vec![]);
+    let iter = JellyfishMerkleIterator::new_rev(&reader, root, None).unwrap();
+    assert_eq!(collect(iter), vec![]);
+    assert_eq!(
+        count_leaves::<TestKey, TestValue, _, Sha3TreeHasher>(&reader, root).unwrap(),
+        0
+    );
+    assert_eq!(reader.reads(), 0);
+}
+
+#[test]
+fn test_iterator_double_ended() {
+    let mut rng = StdRng::from_seed([2; 32]);
+    for n in [1, 2, 50] {
+        let db = MockTestStore::new_test();
+        let (root, btree) = init_tree(&db, n);
+        let root = root.unwrap();
+
+        for _ in 0..10 {
+            let mut expected = btree.clone().into_iter().collect::<VecDeque<_>>();
+            let mut iter = JellyfishMerkleIterator::<_, _, _>::new(&db, root, None).unwrap();
+            loop {
+                let (item, expected_item) = if rng.gen::<bool>() {
+                    (iter.next(), expected.pop_front())
+                } else {
+                    (iter.next_back(), expected.pop_back())
+                };
+                let item = item.map(|item| {
+                    let (k, v) = item.unwrap();
+                    (k.origin.0, v.origin)
+                });
+                assert_eq!(item, expected_item);
+                if item.is_none() {
+                    break;
+                }
+            }
+            assert!(iter.next().is_none());
+            assert!(iter.next_back().is_none());
+        }
+    }
+}
+
+#[test]
+fn test_iterator_double_ended_with_starting_key() {
+    let db = MockTestStore::new_test();
+    let (root, btree) = init_tree(&db, 50);
+    let root = root.unwrap();
+    let keys = btree.keys().cloned().collect::<Vec<_>>();
+
+    let iter =
+        JellyfishMerkleIterator::<_, _, _>::new(&db, root, Some(key_object(keys[20]))).unwrap();
+    let expected = btree.clone().into_iter().skip(20).rev().collect::<Vec<_>>();
+    assert_eq!(
+        iter.rev()
+            .map(|item| item.map(|(k, v)| (k.origin.0, v.origin)))
+            .collect::<Result<Vec<_>>>()
+            .unwrap(),
+        expected
+    );
+
+    let mut iter = JellyfishMerkleIterator::new_rev(&db, root, Some(key_object(keys[20]))).unwrap();
+    assert_eq!(iter.next_back().unwrap().unwrap().0.origin.0, keys[0]);
+    assert_eq!(iter.next().unwrap().unwrap().0.origin.0, keys[20]);
+    assert_eq!(collect(iter).len(), 19);
+}
+
 // // Copyright (c) The Diem Core Contributors
 // // SPDX-License-Identifier: Apache-2.0
+fn range(
+    db: &MockTestStore,
+    root: HashValue,
+    start: Bound<HashValue>,
+    end: Bound<HashValue>,
+) -> JellyfishMerkleIterator<'_, TestKey, TestValue, MockTestStore> {
+    JellyfishMerkleIterator::new_range(db, root, start.map(key_object), end.map(key_object))
+        .unwrap()
+}
+
+#[test]
+fn test_iterator_range() {
+    for n in [1, 2, 50] {
+        let db = MockTestStore::new_test();
+        let (root, btree) = init_tree(&db, n);
+        let root = root.unwrap();
+        let keys = btree.keys().cloned().collect::<Vec<_>>();
+
+        let expected = btree.clone().into_iter().collect::<Vec<_>>();
+        let iter = range(&db, root, Bound::Unbounded, Bound::Unbounded);
+        assert_eq!(collect(iter), expected);
+
+        for (i, start) in keys.iter().enumerate() {
+            for (j, end) in keys.iter().enumerate().skip(i) {
+                // The end bound equals an existing key.
+                let iter = range(&db, root, Bound::Included(*start), Bound::Included(*end));
+                assert_eq!(collect(iter), expected[i..=j].to_vec());
+                let iter = range(&db, root, Bound::Included(*start), Bound::Excluded(*end));
+                assert_eq!(collect(iter), expected[i..j].to_vec());
+                let iter = range(&db, root, Bound::Excluded(*start), Bound::Included(*end));
+                assert_eq!(collect(iter), expected[i + 1..=j].to_vec());
+
+                // The end bound falls between two existing keys.
+                let iter = range(
+                    &db,
+                    root,
+                    Bound::Included(*start),
+                    Bound::Excluded(plus_one(*end)),
+                );
+                assert_eq!(collect(iter), expected[i..=j].to_vec());
+                let iter = range(
+                    &db,
+                    root,
+                    Bound::Included(*start),
+                    Bound::Included(minus_one(*end)),
+                );
+                assert_eq!(collect(iter), expected[i..j].to_vec());
+            }
+
+            let iter = range(&db, root, Bound::Unbounded, Bound::Included(*start));
+            assert_eq!(collect(iter), expected[..=i].to_vec());
+            let iter = range(&db, root, Bound::Excluded(*start), Bound::Unbounded);
+            assert_eq!(collect(iter), expected[i + 1..].to_vec());
+        }
+    }
+}
+
+#[test]
+fn test_iterator_after() {
+    for n in [1, 2, 50] {
+        let db = MockTestStore::new_test();
+        let (root, btree) = init_tree(&db, n);
+        let root = root.unwrap();
+        let expected = btree.into_iter().collect::<Vec<_>>();
+
+        // Pages of every size, each one after the last key of the previous one.
+        for page_size in [1, 3, 7, n] {
+            let mut pages = vec![];
+            let mut iter = JellyfishMerkleIterator::<_, _, _>::new(&db, root, None).unwrap();
+            loop {
+                let page = iter
+                    .by_ref()
+                    .take(page_size)
+                    .collect::<Result<Vec<_>>>()
+                    .unwrap();
+                match page.last() {
+                    Some((last_key, _)) => {
+                        iter =
+                            JellyfishMerkleIterator::new_after(&db, root, last_key.clone()).unwrap()
+                    }
+                    None => break,
+                }
+                pages.extend(page.into_iter().map(|(k, v)| (k.origin.0, v.origin)));
+            }
+            assert_eq!(pages, expected);
+        }
+
+        for (i, (key_hash, _)) in expected.iter().enumerate() {
+            let iter =
+                JellyfishMerkleIterator::new_after(&db, root, TestKey(*key_hash).into_object())
+                    .unwrap();
+            assert_eq!(collect(iter), expected[i + 1..].to_vec());
+            // A key which is not in the tree starts at the next key.
+            let iter = JellyfishMerkleIterator::new_after(
+                &db,
+                root,
+                TestKey(minus_one(*key_hash)).into_object(),
+            )
+            .unwrap();
+            assert_eq!(collect(iter), expected[i..].to_vec());
+        }
+    }
+}
+
+#[test]
+fn test_iterators_on_roots_sharing_nodes() {
+    let db = MockTestStore::new_test();
+    let (root1, btree1) = init_tree(&db, 200);
+    let root1 = root1.unwrap();
+    let num_nodes1 = db.num_nodes();
+
+    // The second version updates every tenth key and deletes every seventh one, sharing the
+    // subtrees it does not touch with the first.
+    let tree = JellyfishMerkleTree::new(&db);
+    let mut btree2 = btree1.clone();
+    let mut updates = vec![];
+    for (i, key) in btree1.keys().enumerate() {
+        if i % 7 == 0 {
+            btree2.remove(key);
+            updates.push((key_object(*key), None));
+        } else if i % 10 == 0 {
+            let value = TestValue::from(vec![i as u8]);
+            btree2.insert(*key, value.clone());
+            updates.push((key_object(*key), Some(value.into_object())));
+        }
+    }
+    let (root2, batch) = tree.updates(Some(root1), updates).unwrap();
+    db.write_tree_update_batch(batch).unwrap();
+    assert!(db.num_nodes() - num_nodes1 < num_nodes1);
+
+    // Interleaved, each iterator yields the leaves of its own root.
+    let mut iter1 = JellyfishMerkleIterator::<_, _, _>::new(&db, root1, None).unwrap();
+    let mut iter2 = JellyfishMerkleIterator::<_, _, _>::new(&db, root2, None).unwrap();
+    let (mut leaves1, mut leaves2) = (vec![], vec![]);
+    loop {
+        let (leaf1, leaf2) = (iter1.next(), iter2.next());
+        if leaf1.is_none() && leaf2.is_none() {
+            break;
+        }
+        for (leaf, leaves) in [(leaf1, &mut leaves1), (leaf2, &mut leaves2)] {
+            if let Some(leaf) = leaf {
+                let (key, value) = leaf.unwrap();
+                leaves.push((key.origin.0, value.origin));
+            }
+        }
+    }
+    assert_eq!(leaves1, btree1.into_iter().collect::<Vec<_>>());
+    assert_eq!(leaves2, btree2.into_iter().collect::<Vec<_>>());
+}
+
+#[test]
+fn test_iterator_range_end_below_start() {
+    let db = MockTestStore::new_test();
+    let (root, btree) = init_tree(&db, 50);
+    let root = root.unwrap();
+    let keys = btree.keys().cloned().collect::<Vec<_>>();
+
+    let iter = range(
+        &db,
+        root,
+        Bound::Included(keys[30]),
+        Bound::Included(keys[10]),
+    );
+    assert_eq!(collect(iter), vec![]);
+    let iter = range(
+        &db,
+        root,
+        Bound::Included(keys[30]),
+        Bound::Excluded(keys[30]),
+    );
+    assert_eq!(collect(iter), vec![]);
+    let iter = range(
+        &db,
+        root,
+        Bound::Excluded(keys[30]),
+        Bound::Included(keys[30]),
+    );
+    assert_eq!(collect(iter), vec![]);
+    let mut iter = range(
+        &db,
+        root,
+        Bound::Included(keys[30]),
+        Bound::Included(keys[10]),
+    );
+    assert!(iter.next_back().is_none());
+}
+
+/// A `TreeReader` recording the keys of the nodes read from the store it wraps, one by one or in a
+/// `get_nodes` batch.
+struct RecordingTreeReader {
+    inner: MockTestStore,
+    node_keys: Mutex<Vec<NodeKey>>,
+}
+
+impl TreeReader<TestKey, TestValue> for RecordingTreeReader {
+    fn get_node_option(&self, node_key: &NodeKey) -> Result<Option<Node<TestKey, TestValue>>> {
+        self.node_keys.lock().unwrap().push(*node_key);
+        self.inner.get_node_option(node_key)
+    }
+}
+
+impl Versioned<TestKey, TestValue> for RecordingTreeReader {}
+
+#[test]
+fn test_iterator_range_reads_nothing_past_end() {
+    // An internal node at nibble 0 of the root with a leaf child at each of its 16 nibbles, and a
+    // leaf at nibble 15 of the root.
+    let db = MockTestStore::new_test();
+    let mut rng = StdRng::from_seed([12; 32]);
+    let mut keys = (0..17u8)
+        .map(|i| {
+            let mut bytes = HashValue::random_with_rng(&mut rng).to_vec();
+            bytes[0] = if i < 16 { i } else { 0xf0 };
+            HashValue::from_slice(&bytes).unwrap()
+        })
+        .collect::<Vec<_>>();
+    let tree = JellyfishMerkleTree::new(&db);
+    let (root, batch) = tree
+        .put_blob_set(
+            None,
+            keys.iter()
+                .map(|key| (key_object(*key), TestValue::random().into_object()))
+                .collect(),
+        )
+        .unwrap();
+    db.write_tree_update_batch(batch).unwrap();
+    let other_key = keys.pop().unwrap();
+    let child = |node_key: &NodeKey, nibble: u8| match db.get_node(node_key).unwrap() {
+        Node::Internal(internal_node) => internal_node.child(Nibble::from(nibble)).unwrap().hash,
+        _ => unreachable!(),
+    };
+    let internal_key = child(&root, 0);
+    let leaf_keys = (0..16u8)
+        .map(|nibble| child(&internal_key, nibble))
+        .collect::<Vec<_>>();
+    let reader = RecordingTreeReader {
+        inner: db,
+        node_keys: Mutex::new(vec![]),
+    };
+    // The leaves of the internal node read since the last call.
+    let read_leaves = || {
+        let mut node_keys = std::mem::take(&mut *reader.node_keys.lock().unwrap());
+        node_keys.retain(|node_key| leaf_keys.contains(node_key));
+        node_keys.sort();
+        node_keys.dedup();
+        node_keys
+    };
+    let sorted = |node_keys: &[NodeKey]| {
+        let mut node_keys = node_keys.to_vec();
+        node_keys.sort();
+        node_keys
+    };
+
+    // The end bound falls in the middle of the internal node: the leaves after it are not read
+    // ahead. The leaf at the excluded end bound may hold a smaller key, so it is read.
+    for (end, num_read) in [(Bound::Included(keys[5]), 6), (Bound::Excluded(keys[6]), 7)] {
+        let iter = JellyfishMerkleIterator::<_, _, _>::new_range(
+            &reader,
+            root,
+            Bound::Unbounded,
+            end.map(key_object),
+        )
+        .unwrap();
+        assert_eq!(iter.count(), 6);
+        assert_eq!(read_leaves(), sorted(&leaf_keys[..num_read]));
+    }
+
+    // Same backwards, with the start bound.
+    let iter = JellyfishMerkleIterator::<_, _, _>::new_range(
+        &reader,
+        root,
+        Bound::Included(key_object(keys[10])),
+        Bound::Unbounded,
+    )
+    .unwrap();
+    let yielded = iter
+        .rev()
+        .map(|item| item.unwrap().0.origin.0)
+        .collect::<Vec<_>>();
+    assert_eq!(yielded[0], other_key);
+    assert_eq!(yielded.len(), 7);
+    assert_eq!(read_leaves(), sorted(&leaf_keys[10..]));
+
+    // A prefix stops at the end of its subtree.
+    let iter =
+        JellyfishMerkleIterator::<_, _, _>::new_prefix(&reader, root, prefix(keys[3], 2)).unwrap();
+    assert_eq!(iter.count(), 1);
+    assert_eq!(read_leaves(), vec![leaf_keys[3]]);
+}
+
+#[test]
+fn test_iterator_range_double_ended() {
+    let db = MockTestStore::new_test();
+    let (root, btree) = init_tree(&db, 50);
+    let root = root.unwrap();
+    let keys = btree.keys().cloned().collect::<Vec<_>>();
+
+    let iter = range(
+        &db,
+        root,
+        Bound::Excluded(keys[10]),
+        Bound::Included(keys[30]),
+    );
+    let expected = btree
+        .clone()
+        .into_iter()
+        .skip(11)
+        .take(20)
+        .rev()
+        .collect::<Vec<_>>();
+    assert_eq!(
+        iter.rev()
+            .map(|item| item.map(|(k, v)| (k.origin.0, v.origin)))
+            .collect::<Result<Vec<_>>>()
+            .unwrap(),
+        expected
+    );
+
+    let mut iter = range(
+        &db,
+        root,
+        Bound::Included(keys[10]),
+        Bound::Excluded(keys[30]),
+    );
+    assert_eq!(iter.next_back().unwrap().unwrap().0.origin.0, keys[29]);
+    assert_eq!(iter.next().unwrap().unwrap().0.origin.0, keys[10]);
+    assert_eq!(collect(iter).len(), 18);
+}
+
+#[test]
+fn test_iterator_clone() {
+    let db = MockTestStore::new_test();
+    let (root, btree) = init_tree(&db, 100);
+    let root = root.unwrap();
+    let keys = btree.keys().cloned().collect::<Vec<_>>();
+    let expected = btree.into_iter().collect::<Vec<_>>();
+
+    let reader = CountingTreeReader::new(db);
+    let mut iter = JellyfishMerkleIterator::<_, _, _>::new(&reader, root, None).unwrap();
+    for _ in 0..30 {
+        iter.next().unwrap().unwrap();
+    }
+    // Cloning reads nothing.
+    let reads = reader.reads();
+    let clone = iter.clone();
+    assert_eq!(reader.reads(), reads);
+    assert_eq!(collect(clone), expected[30..].to_vec());
+    assert_eq!(collect(iter), expected[30..].to_vec());
+
+    // The clone keeps the bounds and the position of both ends.
+    let mut iter = JellyfishMerkleIterator::<_, _, _>::new_range(
+        &reader,
+        root,
+        Bound::Excluded(key_object(keys[10])),
+        Bound::Included(key_object(keys[80])),
+    )
+    .unwrap();
+    for _ in 0..5 {
+        iter.next().unwrap().unwrap();
+        iter.next_back().unwrap().unwrap();
+    }
+    let clone = iter.clone();
+    assert_eq!(collect(clone), expected[16..76].to_vec());
+    let clone = iter.clone();
+    assert_eq!(
+        clone
+            .rev()
+            .map(|item| item.map(|(k, v)| (k.origin.0, v.origin)))
+            .collect::<Result<Vec<_>>>()
+            .unwrap(),
+        expected[16..76].iter().rev().cloned().collect::<Vec<_>>()
+    );
+    assert_eq!(collect(iter), expected[16..76].to_vec());
+}
+
+#[test]
+fn test_iterator_cursor() {
+    for n in [1, 2, 100] {
+        let db = MockTestStore::new_test();
+        let (root, btree) = init_tree(&db, n);
+        let root = root.unwrap();
+        let expected = btree.into_iter().collect::<Vec<_>>();
+
+        // A cursor taken at any position resumes with the same remaining leaves, also after a
+        // round trip through its serialized form.
+        let mut iter = JellyfishMerkleIterator::<_, _, _>::new(&db, root, None).unwrap();
+        for i in 0..=n {
+            let cursor = iter.cursor();
+            let cursor = bcs::from_bytes(&bcs::to_bytes(&cursor).unwrap()).unwrap();
+            let resumed = JellyfishMerkleIterator::<_, _, _>::resume(&db, root, cursor).unwrap();
+            assert_eq!(collect(resumed), expected[i..].to_vec());
+            if i < n {
+                iter.next().unwrap().unwrap();
+            }
+        }
+        assert!(iter.next().is_none());
+        let resumed = JellyfishMerkleIterator::<_, _, _>::resume(&db, root, iter.cursor()).unwrap();
+        assert_eq!(collect(resumed), vec![]);
+    }
+}
+
+#[test]
+fn test_iterator_cursor_keeps_bounds() {
+    let db = MockTestStore::new_test();
+    let (root, btree) = init_tree(&db, 100);
+    let root = root.unwrap();
+    let keys = btree.keys().cloned().collect::<Vec<_>>();
+    let expected = btree.into_iter().collect::<Vec<_>>();
+
+    let mut iter = JellyfishMerkleIterator::<_, _, _>::new_range(
+        &db,
+        root,
+        Bound::Excluded(key_object(keys[10])),
+        Bound::Included(key_object(keys[80])),
+    )
+    .unwrap();
+    for _ in 0..5 {
+        iter.next().unwrap().unwrap();
+        iter.next_back().unwrap().unwrap();
+    }
+    let resumed = JellyfishMerkleIterator::<_, _, _>::resume(&db, root, iter.cursor()).unwrap();
+    assert_eq!(collect(resumed), expected[16..76].to_vec());
+    let resumed = JellyfishMerkleIterator::<_, _, _>::resume(&db, root, iter.cursor()).unwrap();
+    assert_eq!(
+        resumed
+            .rev()
+            .map(|item| item.map(|(k, v)| (k.origin.0, v.origin)))
+            .collect::<Result<Vec<_>>>()
+            .unwrap(),
+        expected[16..76].iter().rev().cloned().collect::<Vec<_>>()
+    );
+
+    let mut iter = JellyfishMerkleIterator::<_, _, _>::new_rev(&db, root, None).unwrap();
+    for _ in 0..30 {
+        iter.next().unwrap().unwrap();
+    }
+    let resumed = JellyfishMerkleIterator::<_, _, _>::resume(&db, root, iter.cursor()).unwrap();
+    assert_eq!(collect(resumed), collect(iter));
+}
+
+#[test]
+fn test_integrity_scan() {
+    let db = MockTestStore::new_test();
+    let (root, btree) = init_tree(&db, 300);
+    let root = root.unwrap();
+    // A single version was written, so every node of the store is reachable from the root.
+    let num_nodes = db.num_nodes() as u64;
+
+    let mut scan = IntegrityScan::<_, _, _>::new(&db, root);
+    let mut corrupted = vec![];
+    while !scan.is_done() {
+        corrupted.extend(scan.step(10).unwrap());
+    }
+    assert!(corrupted.is_empty());
+    assert_eq!(scan.nodes_checked(), num_nodes);
+    assert!(scan.step(10).unwrap().is_empty());
+    assert_eq!(scan.nodes_checked(), num_nodes);
+
+    // Store another value under the key of a leaf.
+    let (key, value) = btree.iter().nth(150).unwrap();
+    let leaf_key = Node::<TestKey, TestValue>::new_leaf(key_object(*key), value.clone()).hash();
+    db.delete_node_batch(&[leaf_key]).unwrap();
+    db.put_node(
+        leaf_key,
+        Node::new_leaf(key_object(*key), TestValue::from(vec![0xff])),
+    )
+    .unwrap();
+
+    // Resumed from a checkpoint after every step, the scan still checks every node once.
+    let mut scan = IntegrityScan::<_, _, _>::new(&db, root);
+    let mut corrupted = vec![];
+    let mut nodes_checked = 0;
+    while !scan.is_done() {
+        corrupted.extend(scan.step(7).unwrap());
+        nodes_checked += scan.nodes_checked();
+        let cursor = bcs::from_bytes(&bcs::to_bytes(&scan.checkpoint()).unwrap()).unwrap();
+        scan = IntegrityScan::<_, _, _>::resume(&db, root, cursor).unwrap();
+    }
+    assert_eq!(corrupted, vec![leaf_key]);
+    assert_eq!(nodes_checked, num_nodes);
+
+    // A checkpoint only resumes on its root.
+    let other_root = HashValue::random();
+    assert!(IntegrityScan::<_, _, _>::resume(&db, other_root, scan.checkpoint()).is_err());
+}
+
+#[test]
+fn test_iterator_cursor_stale() {
+    let db = MockTestStore::new_test();
+    let (root, btree) = init_tree(&db, 100);
+    let root = root.unwrap();
+    let mut iter = JellyfishMerkleIterator::<_, _, _>::new(&db, root, None).unwrap();
+    for _ in 0..30 {
+        iter.next().unwrap().unwrap();
+    }
+    let cursor = iter.cursor();
+
+    // The tree changed since the cursor was taken.
+    let tree = JellyfishMerkleTree::<TestKey, TestValue, _>::new(&db);
+    let (new_root, batch) = tree
+        .put_blob_set(
+            Some(root),
+            vec![(
+                TestKey(*btree.keys().next().unwrap()).into_object(),
+                TestValue::random().into_object(),
+            )],
+        )
+        .unwrap();
+    db.write_tree_update_batch(batch).unwrap();
+    assert!(JellyfishMerkleIterator::<_, _, _>::resume(&db, new_root, cursor.clone()).is_err());
+
+    // A cursor whose path does not start at its root.
+    let mut tampered = cursor.clone();
+    tampered.stack.remove(0);
+    assert!(JellyfishMerkleIterator::<_, _, _>::resume(&db, root, tampered).is_err());
+
+    // A cursor pointing to a missing child.
+    let mut tampered = cursor;
+    let last = tampered.stack.len() - 1;
+    let (node_key, _) = tampered.stack[last];
+    let internal_node = match db.get_node(&node_key).unwrap() {
+        Node::Internal(internal_node) => internal_node,
+        _ => panic!("The cursor holds internal nodes."),
+    };
+    let missing = (0..16u8)
+        .map(Nibble::from)
+        .find(|nibble| internal_node.child(*nibble).is_none())
+        .unwrap();
+    tampered.stack[last].1 = missing;
+    assert!(JellyfishMerkleIterator::<_, _, _>::resume(&db, root, tampered).is_err());
+}
+
+/// Reads the values of the detached leaves from a map.
+struct MapValueReader(HashMap<HashValue, SMTObject<TestValue>>);
+
+impl ValueReader<TestValue> for MapValueReader {
+    fn get_value(&self, value_hash: &HashValue) -> Result<SMTObject<TestValue>> {
+        self.0
+            .get(value_hash)
+            .cloned()
+            .ok_or_else(|| anyhow::format_err!("Missing value {:x}.", value_hash))
+    }
+}
+
+/// Fails the test if a structure-only traversal reads a value.
+struct PanickingValueReader;
+
+impl ValueReader<TestValue> for PanickingValueReader {
+    fn get_value(&self, value_hash: &HashValue) -> Result<SMTObject<TestValue>> {
+        panic!("Value {:x} read by a structure-only traversal.", value_hash)
+    }
+}
+
+#[test]
+fn test_iterator_detached_values() {
+    let db = MockTestStore::new_test();
+    let tree: JellyfishMerkleTree<TestKey, TestValue, MockTestStore> =
+        JellyfishMerkleTree::new(&db);
+    let mut rng = StdRng::from_seed([1; 32]);
+    let btree = (0..100)
+        .map(|i| {
+            (
+                HashValue::random_with_rng(&mut rng),
+                TestValue::from(vec![i; 100]),
+            )
+        })
+        .collect::<BTreeMap<_, _>>();
+    let (root, batch) = tree
+        .put_blob_set(
+            None,
+            btree
+                .iter()
+                .map(|(k, v)| (TestKey(*k).into_object(), v.clone().into_object()))
+                .collect(),
+        )
+        .unwrap();
+    // The store keeps the values apart from the nodes.
+    let mut values = HashMap::new();
+    let node_batch = batch
+        .node_batch
+        .into_iter()
+        .map(|(node_key, node)| match node {
+            Node::Leaf(leaf_node) => {
+                let (leaf_node, value) = leaf_node.detach_with::<Sha3TreeHasher>();
+                let value = value.unwrap();
+                values.insert(value.merkle_hash(), value);
+                (node_key, Node::Leaf(leaf_node))
+            }
+            node => (node_key, node),
+        })
+        .collect();
+    db.write_node_batch(&node_batch).unwrap();
+    let expected = btree.into_iter().collect::<Vec<_>>();
+
+    // The values are read when the leaves are yielded.
+    let value_reader = MapValueReader(values);
+    let iter = JellyfishMerkleIterator::<_, _, _>::new(&db, root, None)
+        .unwrap()
+        .with_value_reader(Some(&value_reader));
+    assert_eq!(collect(iter), expected);
+    let iter = JellyfishMerkleIterator::<_, _, _>::new(&db, root, None)
+        .unwrap()
+        .with_value_reader(Some(&value_reader));
+    assert_eq!(
+        iter.rev()
+            .map(|item| item.map(|(k, v)| (k.origin.0, v.origin)))
+            .collect::<Result<Vec<_>>>()
+            .unwrap(),
+        expected.iter().rev().cloned().collect::<Vec<_>>()
+    );
+
+    // The structure-only traversals never read a value.
+    let iter = JellyfishMerkleIterator::<_, _, _>::new(&db, root, None)
+        .unwrap()
+        .with_value_reader(Some(&PanickingValueReader));
+    assert_eq!(
+        iter.keys()
+            .map(|key| key.map(|key| key.origin.0))
+            .collect::<Result<Vec<_>>>()
+            .unwrap(),
+        expected.iter().map(|(k, _)| *k).collect::<Vec<_>>()
+    );
+    assert_eq!(
+        count_leaves::<TestKey, TestValue, _, Sha3TreeHasher>(&db, root).unwrap(),
+        100
+    );
+    assert_eq!(
+        first_key::<TestKey, TestValue, _, Sha3TreeHasher>(&db, root)
+            .unwrap()
+            .unwrap()
+            .origin
+            .0,
+        expected[0].0
+    );
+    let tree: JellyfishMerkleTree<TestKey, TestValue, MockTestStore> =
+        JellyfishMerkleTree::new(&db);
+    let absent_key = TestKey::random();
+    let (value, proof) = tree.get_with_proof(root, absent_key.into_object()).unwrap();
+    assert_eq!(value, None);
+    proof
+        .verify::<_, TestValue>(root, absent_key, None)
+        .unwrap();
+
+    // Without a value reader a detached value is an error, not a panic.
+    let mut iter = JellyfishMerkleIterator::<_, _, _>::new(&db, root, None).unwrap();
+    assert!(iter.next().unwrap().is_err());
+    assert!(tree
+        .get_with_proof(root, key_object(expected[0].0))
+        .is_err());
+}
+
+#[test]
+fn test_iterator_inline_threshold() {
+    let tree_db = MockTestStore::new_test();
+    let tree: JellyfishMerkleTree<TestKey, TestValue, MockTestStore> =
+        JellyfishMerkleTree::new(&tree_db);
+    let mut rng = StdRng::from_seed([2; 32]);
+    let btree = (0..100)
+        .map(|i| {
+            (
+                HashValue::random_with_rng(&mut rng),
+                TestValue::from(vec![i; i as usize]),
+            )
+        })
+        .collect::<BTreeMap<_, _>>();
+    let (root, batch) = tree
+        .put_blob_set(
+            None,
+            btree
+                .iter()
+                .map(|(k, v)| (TestKey(*k).into_object(), v.clone().into_object()))
+                .collect(),
+        )
+        .unwrap();
+    tree_db.write_node_batch(&batch.node_batch).unwrap();
+
+    // The values of more than 32 bytes are stored apart, with the same node keys.
+    let (node_batch, values) =
+        detach_large_values::<_, _, Sha3TreeHasher>(batch.node_batch.clone(), 32);
+    assert_eq!(
+        node_batch.keys().collect::<Vec<_>>(),
+        batch.node_batch.keys().collect::<Vec<_>>()
+    );
+    assert!(values.values().all(|value| value.raw.len() > 32));
+    let inline_values = node_batch
+        .values()
+        .filter_map(|node| match node {
+            Node::Leaf(leaf_node) if !leaf_node.is_detached() => Some(leaf_node.value()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert!(inline_values.iter().all(|value| value.raw.len() <= 32));
+    assert!(!inline_values.is_empty());
+    assert_eq!(values.len() + inline_values.len(), 100);
+    let db = MockTestStore::new_test();
+    db.write_node_batch(&node_batch).unwrap();
+    let value_reader = MapValueReader(values.into_iter().collect());
+
+    // Both stores yield the same values.
+    let iter = JellyfishMerkleIterator::<_, _, _>::new(&db, root, None)
+        .unwrap()
+        .with_value_reader(Some(&value_reader));
+    let expected = btree.into_iter().collect::<Vec<_>>();
+    assert_eq!(collect(iter), expected);
+    let iter = JellyfishMerkleIterator::<_, _, _>::new(&tree_db, root, None).unwrap();
+    assert_eq!(collect(iter), expected);
+}
+
+#[test]
+fn test_iterator_keys() {
+    let db = MockTestStore::new_test();
+    let (root, btree) = init_tree(&db, 50);
+    let root = root.unwrap();
+    let keys = btree.keys().cloned().collect::<Vec<_>>();
+
+    let iter = JellyfishMerkleIterator::<_, _, _>::new(&db, root, None).unwrap();
+    assert_eq!(
+        iter.keys()
+            .map(|key| key.map(|key| key.origin.0))
+            .collect::<Result<Vec<_>>>()
+            .unwrap(),
+        keys
+    );
+
+    let iter =
+        JellyfishMerkleIterator::<_, _, _>::new(&db, root, Some(key_object(keys[20]))).unwrap();
+    assert_eq!(
+        iter.keys()
+            .rev()
+            .map(|key| key.map(|key| key.origin.0))
+            .collect::<Result<Vec<_>>>()
+            .unwrap(),
+        keys[20..].iter().rev().cloned().collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn test_iterator_seek() {
+    let db = MockTestStore::new_test();
+    let (root, btree) = init_tree(&db, 50);
+    let root = root.unwrap();
+    let keys = btree.keys().cloned().collect::<Vec<_>>();
+    let expected = btree.clone().into_iter().collect::<Vec<_>>();
+
+    let mut iter = JellyfishMerkleIterator::new(&db, root, None).unwrap();
+    assert_eq!(iter.next().unwrap().unwrap().0.origin.0, keys[0]);
+    let stack_ptr = iter.traversal.parent_stack.as_ptr();
+
+    // Seek forward, to an existing key and between two keys.
+    iter.seek(&key_object(keys[30])).unwrap();
+    assert_eq!(iter.traversal.parent_stack.as_ptr(), stack_ptr);
+    assert_eq!(iter.next().unwrap().unwrap().0.origin.0, keys[30]);
+    iter.seek(&key_object(plus_one(keys[40]))).unwrap();
+    assert_eq!(iter.next().unwrap().unwrap().0.origin.0, keys[41]);
+
+    // Seek backward.
+    iter.seek(&key_object(keys[10])).unwrap();
+    assert_eq!(iter.next().unwrap().unwrap().0.origin.0, keys[10]);
+
+    // Seek past the last key leaves the iterator exhausted.
+    iter.seek(&key_object(plus_one(keys[49]))).unwrap();
+    assert!(iter.next().is_none());
+    assert!(iter.next().is_none());
+
+    // Seek resets an exhausted iterator.
+    iter.seek(&key_object(minus_one(keys[45]))).unwrap();
+    assert_eq!(collect(iter), expected[45..].to_vec());
+
+    let mut iter = JellyfishMerkleIterator::new_rev(&db, root, None).unwrap();
+    iter.seek(&key_object(minus_one(keys[5]))).unwrap();
+    assert_eq!(
+        collect(iter),
+        expected[..5].iter().rev().cloned().collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn test_iterator_seek_range() {
+    let db = MockTestStore::new_test();
+    let (root, btree) = init_tree(&db, 50);
+    let root = root.unwrap();
+    let keys = btree.keys().cloned().collect::<Vec<_>>();
+    let expected = btree.clone().into_iter().collect::<Vec<_>>();
+
+    let mut iter = range(&db, root, Bound::Unbounded, Bound::Excluded(keys[20]));
+    iter.seek(&key_object(keys[15])).unwrap();
+    assert_eq!(collect(iter), expected[15..20].to_vec());
+
+    let mut iter = range(&db, root, Bound::Unbounded, Bound::Excluded(keys[20]));
+    iter.seek(&key_object(keys[25])).unwrap();
+    assert!(iter.next().is_none());
+
+    let mut iter = JellyfishMerkleIterator::new(&db, root, None).unwrap();
+    assert_eq!(iter.next_back().unwrap().unwrap().0.origin.0, keys[49]);
+    iter.seek(&key_object(keys[45])).unwrap();
+    assert_eq!(collect(iter), expected[45..49].to_vec());
+}
+
+fn prefix(key: HashValue, num_nibbles: usize) -> NibblePath {
+    (0..num_nibbles)
+        .map(|i| Nibble::from(key.nibble(i)))
+        .collect()
+}
+
+fn has_prefix(key: HashValue, prefix: &NibblePath) -> bool {
+    prefix
+        .nibbles()
+        .enumerate()
+        .all(|(i, nibble)| key.nibble(i) == u8::from(nibble))
+}
+
+#[test]
+fn test_iterator_prefix() {
+    let db = MockTestStore::new_test();
+    let (root, btree) = init_tree(&db, 1000);
+    let root = root.unwrap();
+    let keys = btree.keys().cloned().collect::<Vec<_>>();
+
+    let iter = JellyfishMerkleIterator::new_prefix(&db, root, NibblePath::new(vec![])).unwrap();
+    assert_eq!(collect(iter), btree.clone().into_iter().collect::<Vec<_>>());
+
+    let mut rng = StdRng::from_seed([2; 32]);
+    for num_nibbles in 1..=5 {
+        for _ in 0..10 {
+            // Prefixes of existing keys lead to internal nodes or leaves, random ones may lead to
+            // empty children too.
+            let key = if rng.gen() {
+                keys[rng.gen_range(0..keys.len())]
+            } else {
+                HashValue::random_with_rng(&mut rng)
+            };
+            let prefix = prefix(key, num_nibbles);
+            let expected = btree
+                .iter()
+                .filter(|(k, _)| has_prefix(**k, &prefix))
+                .map(|(k, v)| (*k, v.clone()))
+                .collect::<Vec<_>>();
+            let iter = JellyfishMerkleIterator::new_prefix(&db, root, prefix).unwrap();
+            assert_eq!(collect(iter), expected);
+        }
+    }
+
+    // The full key hash is a prefix of itself only.
+    let iter = JellyfishMerkleIterator::new_prefix(&db, root, prefix(keys[7], 64)).unwrap();
+    assert_eq!(collect(iter), vec![(keys[7], btree[&keys[7]].clone())]);
+    let iter =
+        JellyfishMerkleIterator::new_prefix(&db, root, prefix(plus_one(keys[7]), 64)).unwrap();
+    assert_eq!(collect(iter), vec![]);
+}
+
+#[test]
+fn test_iterator_shard() {
+    let db = MockTestStore::new_test();
+    let (root, btree) = init_tree(&db, 1000);
+    let root = root.unwrap();
+
+    // The shards yield the whole tree, in order.
+    let mut leaves = vec![];
+    for shard in 0..16u8 {
+        let shard_leaves =
+            collect(JellyfishMerkleIterator::new_shard(&db, root, Nibble::from(shard)).unwrap());
+        assert!(shard_leaves.iter().all(|(key, _)| key.nibble(0) == shard));
+        leaves.extend(shard_leaves);
+    }
+    assert_eq!(leaves, btree.into_iter().collect::<Vec<_>>());
+
+    // A shard without any key yields nothing.
+    let db = MockTestStore::new_test();
+    let (root, btree) = init_tree(&db, 1);
+    let root = root.unwrap();
+    let key = *btree.keys().next().unwrap();
+    let empty_shard = Nibble::from((key.nibble(0) + 1) % 16);
+    let iter = JellyfishMerkleIterator::new_shard(&db, root, empty_shard).unwrap();
+    assert_eq!(collect(iter), vec![]);
+    let iter = JellyfishMerkleIterator::new_shard(&db, root, Nibble::from(key.nibble(0))).unwrap();
+    assert_eq!(collect(iter), btree.into_iter().collect::<Vec<_>>());
+}
+
+#[test]
+fn test_merge_sorted() {
+    let db = MockTestStore::new_test();
+    let (root, btree) = init_tree(&db, 1000);
+    let root = root.unwrap();
+    let expected = btree.into_iter().collect::<Vec<_>>();
+
+    // Merging the shards, in any order, yields the whole tree in order.
+    let shards = (0..16u8)
+        .rev()
+        .map(|shard| {
+            JellyfishMerkleIterator::<_, _, _>::new_shard(&db, root, Nibble::from(shard)).unwrap()
+        })
+        .collect::<Vec<_>>();
+    let merged = merge_sorted::<_, _, _, Sha3TreeHasher>(shards)
+        .map(|item| item.map(|(k, v)| (k.origin.0, v.origin)))
+        .collect::<Result<Vec<_>>>()
+        .unwrap();
+    assert_eq!(merged, expected);
+
+    // The key-value pairs of overlapping iterators are interleaved, a key of several iterators
+    // being yielded once for each.
+    let iters = vec![
+        JellyfishMerkleIterator::<_, _, _>::new_range(
+            &db,
+            root,
+            Bound::Unbounded,
+            Bound::Excluded(TestKey(expected[600].0).into_object()),
+        )
+        .unwrap(),
+        JellyfishMerkleIterator::<_, _, _>::new(
+            &db,
+            root,
+            Some(TestKey(expected[400].0).into_object()),
+        )
+        .unwrap(),
+    ];
+    let merged = merge_sorted::<_, _, _, Sha3TreeHasher>(iters)
+        .map(|item| item.unwrap().0.origin.0)
+        .collect::<Vec<_>>();
+    let mut expected_keys = expected[..600]
+        .iter()
+        .chain(&expected[400..])
+        .map(|(key_hash, _)| *key_hash)
+        .collect::<Vec<_>>();
+    expected_keys.sort();
+    assert_eq!(merged, expected_keys);
+
+    // A peeked key hash is the one of the next key.
+    let mut peekable = PeekableLeaf::<_, _, _, Sha3TreeHasher>::new(
+        JellyfishMerkleIterator::<_, _, _>::new(&db, root, None).unwrap(),
+    );
+    assert_eq!(peekable.peek_key_hash().unwrap(), Some(expected[0].0));
+    assert_eq!(peekable.peek_key_hash().unwrap(), Some(expected[0].0));
+    assert_eq!(peekable.next().unwrap().unwrap().0.origin.0, expected[0].0);
+    assert_eq!(peekable.peek_key_hash().unwrap(), Some(expected[1].0));
+    assert_eq!(peekable.count(), expected.len() - 1);
+
+    // The merge stops at the first error, after the keys before the one which failed.
+    let (failing_key_hash, failing_value) = expected[500].clone();
+    let failing_key = Node::new_leaf(TestKey(failing_key_hash), failing_value).hash();
+    let shards = (0..16u8)
+        .map(|shard| {
+            JellyfishMerkleIterator::<_, _, _>::new_shard(&db, root, Nibble::from(shard)).unwrap()
+        })
+        .collect::<Vec<_>>();
+    db.fail_reads(failing_key);
+    let mut merged = merge_sorted::<_, _, _, Sha3TreeHasher>(shards);
+    let mut yielded = vec![];
+    for item in merged.by_ref() {
+        match item {
+            Ok((key, _)) => yielded.push(key.origin.0),
+            Err(_) => break,
+        }
+    }
+    assert!(merged.next().is_none());
+    assert!(yielded.len() <= 500);
+    assert!(yielded
+        .iter()
+        .zip(&expected)
+        .all(|(key_hash, (expected_key_hash, _))| key_hash == expected_key_hash));
+}
+
+#[test]
+fn test_iterator_prefix_single_leaf() {
+    let db = MockTestStore::new_test();
+    let (root, btree) = init_tree(&db, 1);
+    let root = root.unwrap();
+    let key = *btree.keys().next().unwrap();
+
+    let iter = JellyfishMerkleIterator::new_prefix(&db, root, prefix(key, 3)).unwrap();
+    assert_eq!(collect(iter), btree.into_iter().collect::<Vec<_>>());
+    let other_prefix = NibblePath::new_odd(vec![((key.nibble(0) + 1) % 16) << 4]);
+    let iter = JellyfishMerkleIterator::new_prefix(&db, root, other_prefix).unwrap();
+    assert_eq!(collect(iter), vec![]);
+}
+
+#[test]
+fn test_iterator_prefix_reads_subtree_only() {
+    let db = MockTestStore::new_test();
+    let (root, btree) = init_tree(&db, 1000);
+    let root = root.unwrap();
+    let key = *btree.keys().nth(500).unwrap();
+    let prefix = prefix(key, 2);
+    let num_keys = btree.keys().filter(|k| has_prefix(**k, &prefix)).count();
+
+    let reader = CountingTreeReader::new(db);
+    let iter = JellyfishMerkleIterator::new_prefix(&reader, root, prefix).unwrap();
+    assert_eq!(collect(iter).len(), num_keys);
+    // The root, the node of the first nibble, the node of the prefix if it is internal, and its
+    // leaves read in one batch, with a single internal node below the prefix at most.
+    assert!(reader.reads() <= 6, "{} reads", reader.reads());
+}
+
+#[test]
+fn test_count_leaves() {
+    let db = MockTestStore::new_test();
+    let tree: JellyfishMerkleTree<TestKey, TestValue, MockTestStore> =
+        JellyfishMerkleTree::new(&db);
+    let (root, batch) = tree
+        .updates(None, vec![(key_object(HashValue::random()), None)])
+        .unwrap();
+    db.write_tree_update_batch(batch).unwrap();
+    assert_eq!(
+        count_leaves::<_, _, _, Sha3TreeHasher>(&db, root).unwrap(),
+        0
+    );
+
+    for n in [1, 2, 10, 1000] {
+        let db = MockTestStore::new_test();
+        let (root, _) = init_tree(&db, n);
+        let root = root.unwrap();
+        let iter = JellyfishMerkleIterator::<_, _, _>::new(&db, root, None).unwrap();
+        assert_eq!(
+            count_leaves::<_, _, _, Sha3TreeHasher>(&db, root).unwrap(),
+            iter.count() as u64
+        );
+
+        // Only the internal nodes are read.
+        let num_internal_nodes = db.num_nodes() - n;
+        let reader = CountingTreeReader::new(db);
+        assert_eq!(
+            count_leaves::<_, _, _, Sha3TreeHasher>(&reader, root).unwrap(),
+            n as u64
+        );
+        assert_eq!(reader.reads(), num_internal_nodes.max(1));
+    }
+}
+
+#[test]
+fn test_first_and_last_key() {
+    let db = MockTestStore::new_test();
+    let empty = *SPARSE_MERKLE_PLACEHOLDER_HASH;
+    assert!(
+        first_key::<TestKey, TestValue, _, Sha3TreeHasher>(&db, empty)
+            .unwrap()
+            .is_none()
+    );
+    assert!(
+        last_key::<TestKey, TestValue, _, Sha3TreeHasher>(&db, empty)
+            .unwrap()
+            .is_none()
+    );
+
+    for n in [1, 2, 10, 1000] {
+        let db = MockTestStore::new_test();
+        let (root, btree) = init_tree(&db, n);
+        let root = root.unwrap();
+        let reader = CountingTreeReader::new(db);
+        let first = first_key::<TestKey, TestValue, _, Sha3TreeHasher>(&reader, root).unwrap();
+        assert_eq!(first.unwrap().origin.0, *btree.keys().next().unwrap());
+        let last = last_key::<TestKey, TestValue, _, Sha3TreeHasher>(&reader, root).unwrap();
+        assert_eq!(last.unwrap().origin.0, *btree.keys().next_back().unwrap());
+        // Only the two paths down from the root are read.
+        assert!(reader.reads() <= 2 * 6, "{} nodes read", reader.reads());
+    }
+}
+
+#[test]
+fn test_neighbors() {
+    let db = MockTestStore::new_test();
+    let neighbors_of = |reader: &CountingTreeReader<MockTestStore>, root, key_hash| {
+        let (below, above) =
+            neighbors::<TestKey, TestValue, _, Sha3TreeHasher>(reader, root, &key_object(key_hash))
+                .unwrap();
+        (below.map(|key| key.origin.0), above.map(|key| key.origin.0))
+    };
+    let reader = CountingTreeReader::new(db);
+    assert_eq!(
+        neighbors_of(&reader, *SPARSE_MERKLE_PLACEHOLDER_HASH, HashValue::zero()),
+        (None, None)
+    );
+
+    let mut rng = StdRng::from_seed([11; 32]);
+    for n in [1, 2, 10, 1000] {
+        let db = MockTestStore::new_test();
+        let (root, btree) = init_tree(&db, n);
+        let root = root.unwrap();
+        let reader = CountingTreeReader::new(db);
+        let keys = btree.keys().copied().collect::<Vec<_>>();
+        let (min, max) = (keys[0], keys[n - 1]);
+
+        // Below the minimum, above the maximum, and equal to an existing key.
+        assert_eq!(
+            neighbors_of(&reader, root, minus_one(min)),
+            (None, Some(min))
+        );
+        assert_eq!(
+            neighbors_of(&reader, root, plus_one(max)),
+            (Some(max), None)
+        );
+        for key in &keys {
+            assert_eq!(neighbors_of(&reader, root, *key), (Some(*key), Some(*key)));
+        }
+        // Between two existing keys.
+        for pair in keys.windows(2) {
+            assert_eq!(
+                neighbors_of(&reader, root, plus_one(pair[0])),
+                (Some(pair[0]), Some(pair[1]))
+            );
+        }
+        for _ in 0..100 {
+            let target = HashValue::random_with_rng(&mut rng);
+            let expected = (
+                btree.range(..=target).next_back().map(|(key, _)| *key),
+                btree.range(target..).next().map(|(key, _)| *key),
+            );
+            let reads = reader.reads();
+            assert_eq!(neighbors_of(&reader, root, target), expected);
+            // Each neighbor reads at most the path of the target and one path down a subtree.
+            let reads = reader.reads() - reads;
+            assert!(reads <= 4 * 6, "{} nodes read", reads);
+        }
+    }
+}
+
+#[test]
+fn test_nth_leaf() {
+    let db = MockTestStore::new_test();
+    let empty = *SPARSE_MERKLE_PLACEHOLDER_HASH;
+    assert!(
+        nth_leaf::<TestKey, TestValue, _, Sha3TreeHasher>(&db, empty, 0)
+            .unwrap()
+            .is_none()
+    );
+
+    for n in [1, 2, 10, 1000] {
+        let db = MockTestStore::new_test();
+        let (root, _) = init_tree(&db, n);
+        let root = root.unwrap();
+        let indices = [0, 1, n / 2, n - 1, n, n + 1];
+        let expected = indices
+            .iter()
+            .map(|index| {
+                let mut iter = JellyfishMerkleIterator::<_, _, _>::new(&db, root, None).unwrap();
+                iter.nth(*index).map(|item| item.unwrap())
+            })
+            .collect::<Vec<_>>();
+        let reader = CountingTreeReader::new(db);
+        for (index, expected) in indices.into_iter().zip(expected) {
+            let reads = reader.reads();
+            let leaf = nth_leaf::<_, _, _, Sha3TreeHasher>(&reader, root, index as u64).unwrap();
+            assert_eq!(leaf, expected);
+            // Only the nodes on the path to the leaf are read.
+            assert!(
+                reader.reads() - reads <= 6,
+                "{} nodes read",
+                reader.reads() - reads
+            );
+        }
+    }
+
+    // The subtrees without leaf counts are counted.
+    let db = MockTestStore::new_test();
+    let (root, btree) = init_tree(&db, 100);
+    let root = root.unwrap();
+    let old_db = copy_without_leaf_counts(&db, root);
+    for (index, key) in btree.keys().enumerate().step_by(7) {
+        let (leaf_key, _) =
+            nth_leaf::<TestKey, TestValue, _, Sha3TreeHasher>(&old_db, root, index as u64)
+                .unwrap()
+                .unwrap();
+        assert_eq!(leaf_key.origin.0, *key);
+    }
+}
+
+/// Copies the nodes on the path from `root` to `key` into a new store, so that reading any other
+/// node of the tree fails.
+fn copy_path(db: &MockTestStore, root: HashValue, key: HashValue) -> MockTestStore {
+    let partial_db = MockTestStore::new_test();
+    let nibble_path = NibblePath::new(key.to_vec());
+    let mut nibbles = nibble_path.nibbles();
+    let mut node_key = root;
+    loop {
+        let node = db.get_node(&node_key).unwrap();
+        partial_db.put_node(node_key, node.clone()).unwrap();
+        match node {
+            Node::Internal(internal_node) => {
+                node_key = internal_node.child(nibbles.next().unwrap()).unwrap().hash;
+            }
+            _ => return partial_db,
+        }
+    }
+}
+
+#[test]
+fn test_iterator_fused() {
+    let db = MockTestStore::new_test();
+    let (root, btree) = init_tree(&db, 50);
+    let root = root.unwrap();
+    let keys = btree.keys().cloned().collect::<Vec<_>>();
+
+    let mut iter =
+        JellyfishMerkleIterator::<_, _, _>::new(&db, root, Some(key_object(keys[48]))).unwrap();
+    assert_eq!(iter.next().unwrap().unwrap().0.origin.0, keys[48]);
+    assert_eq!(iter.next().unwrap().unwrap().0.origin.0, keys[49]);
+    for _ in 0..3 {
+        assert!(iter.next().is_none());
+    }
+
+    let mut iter = JellyfishMerkleIntoIterator::<_, _, _>::new(db, root, keys[49]).unwrap();
+    assert_eq!(iter.next().unwrap().unwrap().0.origin.0, keys[49]);
+    for _ in 0..3 {
+        assert!(iter.next().is_none());
+    }
+}
+
+#[test]
+fn test_iterator_fused_after_error() {
+    let db = MockTestStore::new_test();
+    let (root, btree) = init_tree(&db, 50);
+    let root = root.unwrap();
+    let keys = btree.keys().cloned().collect::<Vec<_>>();
+    let partial_db = copy_path(&db, root, keys[0]);
+
+    let mut iter = JellyfishMerkleIterator::<_, _, _>::new(&partial_db, root, None).unwrap();
+    assert_eq!(iter.next().unwrap().unwrap().0.origin.0, keys[0]);
+    assert!(iter.next().unwrap().is_err());
+    for _ in 0..3 {
+        assert!(iter.next().is_none());
+    }
+
+    let mut iter = JellyfishMerkleIntoIterator::<_, _, _>::new(partial_db, root, keys[0]).unwrap();
+    assert_eq!(iter.next().unwrap().unwrap().0.origin.0, keys[0]);
+    assert!(iter.next().unwrap().is_err());
+    for _ in 0..3 {
+        assert!(iter.next().is_none());
+    }
+}
+
+#[test]
+fn test_iterator_read_error() {
+    let db = MockTestStore::new_test();
+    let (root, btree) = init_tree(&db, 50);
+    let root = root.unwrap();
+    let keys = btree.keys().cloned().collect::<Vec<_>>();
+
+    // Fail the reads of the subtree holding keys[25].
+    let nibble = keys[25].nibble(0);
+    let child = match db.get_node(&root).unwrap() {
+        Node::Internal(internal_node) => internal_node.child(Nibble::from(nibble)).unwrap().hash,
+        _ => unreachable!(),
+    };
+    db.fail_reads(child);
+
+    let mut iter = JellyfishMerkleIterator::<_, _, _>::new(&db, root, None).unwrap();
+    for key in keys.iter().take_while(|key| key.nibble(0) < nibble) {
+        assert_eq!(iter.next().unwrap().unwrap().0.origin.0, *key);
+    }
+    let err = iter.next().unwrap().unwrap_err();
+    assert!(err.to_string().contains("Failed to read node"));
+    assert!(iter.next().is_none());
+
+    // The iterator starting in the subtree fails to be created.
+    assert!(
+        JellyfishMerkleIterator::<_, _, _>::new(&db, root, Some(key_object(keys[25]))).is_err()
+    );
+}
+
+#[test]
+fn test_iterator_size_hint() {
+    let db = MockTestStore::new_test();
+    let (root, btree) = init_tree(&db, 100);
+    let root = root.unwrap();
+    let keys = btree.keys().cloned().collect::<Vec<_>>();
+
+    let mut iter = JellyfishMerkleIterator::<_, _, _>::new(&db, root, None).unwrap();
+    for remaining in (1..=100).rev() {
+        assert_eq!(iter.size_hint(), (remaining, None));
+        iter.next().unwrap().unwrap();
+    }
+    assert_eq!(iter.size_hint().0, 0);
+    assert!(iter.next().is_none());
+    assert_eq!(iter.size_hint(), (0, Some(0)));
+    let iter =
+        JellyfishMerkleIterator::<_, _, _>::new_rev(&db, root, Some(key_object(keys[41]))).unwrap();
+    assert_eq!(iter.size_hint(), (42, None));
+    assert_eq!(collect(iter).len(), 42);
+    let iter = JellyfishMerkleIterator::<_, _, _>::new(&db, root, None)
+        .unwrap()
+        .keys();
+    assert_eq!(iter.size_hint(), (100, None));
+
+    // With an end bound, the leaf counts give no lower bound.
+    let mut iter = JellyfishMerkleIterator::<_, _, _>::new(&db, root, None).unwrap();
+    iter.next_back().unwrap().unwrap();
+    assert_eq!(iter.size_hint(), (0, None));
+    let iter = JellyfishMerkleIterator::<_, _, _>::new_range(
+        &db,
+        root,
+        Bound::Included(key_object(keys[10])),
+        Bound::Excluded(key_object(keys[20])),
+    )
+    .unwrap();
+    assert_eq!(iter.size_hint(), (0, None));
+    assert_eq!(collect(iter).len(), 10);
+
+    // The nodes written without leaf counts give no hint.
+    let old_db = copy_without_leaf_counts(&db, root);
+    let iter = JellyfishMerkleIterator::<_, _, _>::new(&old_db, root, None).unwrap();
+    assert_eq!(iter.size_hint(), (0, None));
+    assert_eq!(collect(iter).len(), 100);
+}
+
+#[test]
+fn test_iterator_with_depth() {
+    let db = MockTestStore::new_test();
+    let tree: JellyfishMerkleTree<TestKey, TestValue, MockTestStore> =
+        JellyfishMerkleTree::new(&db);
+    let isolated = HashValue::new([0xf0; HashValue::LENGTH]);
+    let (root, batch) = tree
+        .put_blob_set(
+            None,
+            vec![(key_object(isolated), TestValue::random().into_object())],
+        )
+        .unwrap();
+    db.write_tree_update_batch(batch).unwrap();
+    let depths = |root| {
+        JellyfishMerkleIterator::<_, _, _>::new(&db, root, None)
+            .unwrap()
+            .with_depth()
+            .map(|item| item.map(|(k, _, depth)| (k.origin.0, depth)))
+            .collect::<Result<Vec<_>>>()
+            .unwrap()
+    };
+    assert_eq!(depths(root), vec![(isolated, 0)]);
+
+    // The two keys only differ in their last nibble.
+    let shared1 = HashValue::new([0x00; HashValue::LENGTH]);
+    let mut bytes = [0x00; HashValue::LENGTH];
+    bytes[HashValue::LENGTH - 1] = 0x01;
+    let shared2 = HashValue::new(bytes);
+    let (root, batch) = tree
+        .put_blob_set(
+            Some(root),
+            vec![
+                (key_object(shared1), TestValue::random().into_object()),
+                (key_object(shared2), TestValue::random().into_object()),
+            ],
+        )
+        .unwrap();
+    db.write_tree_update_batch(batch).unwrap();
+    assert_eq!(
+        depths(root),
+        vec![(shared1, 64), (shared2, 64), (isolated, 1)]
+    );
+
+    let mut iter = JellyfishMerkleIterator::<_, _, _>::new(&db, root, None)
+        .unwrap()
+        .with_depth();
+    let (key, _, depth) = iter.next_back().unwrap().unwrap();
+    assert_eq!((key.origin.0, depth), (isolated, 1));
+    let (key, _, depth) = iter.next().unwrap().unwrap();
+    assert_eq!((key.origin.0, depth), (shared1, 64));
+}
+
+#[test]
+fn test_depth_histogram() {
+    let db = MockTestStore::new_test();
+    let histogram =
+        |root| depth_histogram::<TestKey, TestValue, _, Sha3TreeHasher>(&db, root).unwrap();
+    assert!(histogram(*SPARSE_MERKLE_PLACEHOLDER_HASH).is_empty());
+
+    let (root, _) = init_tree(&db, 1);
+    assert_eq!(histogram(root.unwrap()), BTreeMap::from([(0, 1)]));
+
+    // Four keys share their first 20 nibbles, and differ in the 21st one.
+    let tree: JellyfishMerkleTree<TestKey, TestValue, MockTestStore> =
+        JellyfishMerkleTree::new(&db);
+    let mut rng = StdRng::from_seed([9; 32]);
+    let prefix = HashValue::random_with_rng(&mut rng);
+    let mut kvs = (0..4u8)
+        .map(|nibble| {
+            let mut bytes = prefix.to_vec();
+            bytes[10] = (nibble << 4) | (bytes[10] & 0x0f);
+            (
+                key_object(HashValue::from_slice(&bytes).unwrap()),
+                TestValue::random().into_object(),
+            )
+        })
+        .collect::<Vec<_>>();
+    kvs.extend((0..100).map(|_| {
+        (
+            key_object(HashValue::random_with_rng(&mut rng)),
+            TestValue::random().into_object(),
+        )
+    }));
+    let (root, batch) = tree.put_blob_set(None, kvs).unwrap();
+    db.write_tree_update_batch(batch).unwrap();
+
+    let histogram = histogram(root);
+    assert_eq!(histogram.values().sum::<u64>(), 104);
+    assert_eq!(histogram.range(21..).collect::<Vec<_>>(), vec![(&21, &4)]);
+    let mut expected = BTreeMap::new();
+    for item in JellyfishMerkleIterator::<_, _, _>::new(&db, root, None)
+        .unwrap()
+        .with_depth()
+    {
+        *expected.entry(item.unwrap().2).or_insert(0) += 1;
+    }
+    assert_eq!(histogram, expected);
+}
+
+/// Copies the tree at `root` into a new store, with the internal nodes decoded from the layout
+/// without leaf counts.
+fn copy_without_leaf_counts(db: &MockTestStore, root: HashValue) -> MockTestStore {
+    let old_db = MockTestStore::new_test();
+    for node_key in std::iter::once(root).chain(subtree_node_keys(db, root)) {
+        let node = match db.get_node(&node_key).unwrap() {
+            Node::Internal(internal_node) => {
+                let mut bytes = vec![1];
+                internal_node.serialize(&mut bytes).unwrap();
+                Node::decode(&bytes).unwrap()
+            }
+            node => node,
+        };
+        old_db.put_node(node_key, node).unwrap();
+    }
+    old_db
+}
+
+/// Returns the keys of all the nodes below `root`.
+fn subtree_node_keys(db: &MockTestStore, root: HashValue) -> Vec<HashValue> {
+    let mut node_keys = vec![];
+    let mut pending = vec![root];
+    while let Some(node_key) = pending.pop() {
+        if let Node::Internal(internal_node) = db.get_node(&node_key).unwrap() {
+            let children = internal_node.all_child();
+            node_keys.extend(children.iter().cloned());
+            pending.extend(children);
+        }
+    }
+    node_keys
+}
+
+/// Returns a corrupt store holding an internal node whose children are itself, so a descent never
+/// reaches a leaf, and the key of that node.
+fn cyclic_tree() -> (MockTestStore, HashValue) {
+    let db = MockTestStore::new_test();
+    let node_key = HashValue::random();
+    let mut children = Children::new();
+    children.insert(Nibble::from(0), Child::new(node_key, false));
+    children.insert(Nibble::from(15), Child::new(node_key, false));
+    db.put_node(node_key, Node::new_internal(children)).unwrap();
+    (db, node_key)
+}
+
+#[test]
+fn test_count_leaves_cyclic_tree() {
+    let (db, root) = cyclic_tree();
+    let err = count_leaves::<TestKey, TestValue, _, Sha3TreeHasher>(&db, root).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<SmtError>(),
+        Some(SmtError::CorruptNode(_))
+    ));
+    let err = depth_histogram::<TestKey, TestValue, _, Sha3TreeHasher>(&db, root).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<SmtError>(),
+        Some(SmtError::CorruptNode(_))
+    ));
+}
+
+#[test]
+fn test_leaf_statistics_unexpected_null() {
+    // A null root other than the empty one, and a null child of an internal node.
+    let db = MockTestStore::new_test();
+    let null_key = HashValue::random();
+    db.put_node(null_key, Node::new_null()).unwrap();
+    let mut children = Children::new();
+    children.insert(Nibble::from(0), Child::new(null_key, false));
+    children.insert(Nibble::from(15), Child::new(HashValue::random(), true));
+    let root_node: Node<TestKey, TestValue> = Node::new_internal(children);
+    let root = root_node.merkle_hash();
+    db.put_node(root, root_node).unwrap();
+    for root in [null_key, root] {
+        let err = count_leaves::<TestKey, TestValue, _, Sha3TreeHasher>(&db, root).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<SmtError>(),
+            Some(&SmtError::UnexpectedNull(null_key))
+        );
+        let err = depth_histogram::<TestKey, TestValue, _, Sha3TreeHasher>(&db, root).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<SmtError>(),
+            Some(&SmtError::UnexpectedNull(null_key))
+        );
+    }
+}
+
+// The nodes of a cyclic tree do not hash to their node keys, which `validate` reports first.
+#[cfg(not(feature = "validate"))]
+#[test]
+fn test_iterator_runs_out_of_nibbles() {
+    let (db, root) = cyclic_tree();
+    for starting_key in [None, Some(key_object(HashValue::zero()))] {
+        let err = JellyfishMerkleIterator::<_, _, _>::new(&db, root, starting_key)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("Ran out of nibbles"), "{}", err);
+    }
+    let err = JellyfishMerkleIterator::<_, _, _>::new_rev(&db, root, None)
+        .err()
+        .unwrap();
+    assert!(err.to_string().contains("Ran out of nibbles"), "{}", err);
+}
+
+// The nodes of a cyclic tree do not hash to their node keys, which `validate` reports first.
+#[cfg(not(feature = "validate"))]
+#[test]
+fn test_iterator_corrupt_store() {
+    // The root of a single leaf tree turns into a null node after the seek.
+    let db = MockTestStore::new_test();
+    let (root, _) = init_tree(&db, 1);
+    let root = root.unwrap();
+    let mut iter = JellyfishMerkleIterator::<_, _, _>::new(&db, root, None).unwrap();
+    db.delete_node_batch(&[root]).unwrap();
+    db.put_node(root, Node::new_null()).unwrap();
+    let err = iter.next().unwrap().unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<SmtError>(),
+        Some(&SmtError::UnexpectedNull(root))
+    );
+    assert!(iter.next().is_none());
+
+    // A subtree deeper than a key hash is only found after the seek.
+    let (db, cyclic_node_key) = cyclic_tree();
+    let leaf_node: Node<TestKey, TestValue> =
+        Node::new_leaf(TestKey(HashValue::zero()), TestValue::random());
+    let leaf_node_key = leaf_node.merkle_hash();
+    db.put_node(leaf_node_key, leaf_node).unwrap();
+    let mut children = Children::new();
+    children.insert(Nibble::from(0), Child::new(leaf_node_key, true));
+    children.insert(Nibble::from(15), Child::new(cyclic_node_key, false));
+    let root_node: Node<TestKey, TestValue> = Node::new_internal(children);
+    let root = root_node.merkle_hash();
+    db.put_node(root, root_node).unwrap();
+    let mut iter = JellyfishMerkleIterator::<_, _, _>::new(&db, root, None).unwrap();
+    assert_eq!(iter.next().unwrap().unwrap().0.origin.0, HashValue::zero());
+    let err = iter.next().unwrap().unwrap_err();
+    assert!(err.to_string().contains("bottom of the tree"), "{}", err);
+    assert!(matches!(
+        err.downcast_ref::<SmtError>(),
+        Some(SmtError::CorruptNode(_))
+    ));
+    assert!(iter.next().is_none());
+
+    // A truncated node fails to decode.
+    let db = MockTestStore::new_test();
+    let (root, btree) = init_tree(&db, 50);
+    let root = root.unwrap();
+    let store = copy_tree(&db, root);
+    let child = match db.get_node(&root).unwrap() {
+        Node::Internal(internal_node) => internal_node.child(Nibble::from(8)).unwrap().hash,
+        _ => unreachable!(),
+    };
+    let bytes = store.get(&child).unwrap().unwrap();
+    store.put(child, bytes[..bytes.len() - 1].to_vec()).unwrap();
+    let mut iter =
+        JellyfishMerkleIterator::<TestKey, TestValue, _>::new(&store, root, None).unwrap();
+    for key in btree.keys().take_while(|key| key.nibble(0) < 8) {
+        assert_eq!(iter.next().unwrap().unwrap().0.origin.0, *key);
+    }
+    assert!(iter.next().unwrap().is_err());
+    assert!(iter.next().is_none());
+}
+
+#[test]
+fn test_iterator_inconsistent_children_bitmap() {
+    let db = MockTestStore::new_test();
+    let (root, btree) = init_tree(&db, 50);
+    let root = root.unwrap();
+    let root_node = match db.get_node(&root).unwrap() {
+        Node::Internal(internal_node) => internal_node,
+        _ => panic!("The root should be internal."),
+    };
+    let (children_bitmap, _) = root_node.generate_bitmaps();
+    assert_eq!(children_bitmap, 0xffff);
+    let is_corrupt = |err: &anyhow::Error| {
+        matches!(
+            err.downcast_ref::<SmtError>(),
+            Some(SmtError::CorruptNode(_))
+        )
+    };
+
+    // A children bitmap claiming fewer children than the node has, so that the next child to
+    // visit is past the last child of the bitmap in either direction.
+    for (direction, children_bitmap, next_child_to_visit) in [
+        (
+            Direction::Ascending,
+            0b0000_0000_0000_0011,
+            0b0000_0000_0001_0000,
+        ),
+        (
+            Direction::Descending,
+            0b1100_0000_0000_0000,
+            0b0000_1000_0000_0000,
+        ),
+    ] {
+        let info = NodeVisitInfo {
+            node_key: root,
+            node: root_node.clone(),
+            children_bitmap,
+            next_child_to_visit,
+        };
+        assert!(!info.is_last(direction));
+        assert!(is_corrupt(&info.clone().advance(direction).unwrap_err()));
+
+        // The traversal fails instead of panicking or looping.
+        let mut traversal =
+            Traversal::<TestKey, TestValue, Sha3TreeHasher>::with_direction(direction);
+        traversal.parent_stack.push(info);
+        let mut leaves = vec![];
+        let err = loop {
+            match traversal.next_leaf(&db, root) {
+                Some(Ok(leaf_node)) => leaves.push(leaf_node),
+                Some(Err(err)) => break err,
+                None => panic!("The traversal should fail."),
+            }
+        };
+        assert!(is_corrupt(&err), "{}", err);
+        // The subtree of the next child to visit is visited, and the error is found when moving
+        // on from its last leaf.
+        let nibble = next_child_to_visit.trailing_zeros() as u8;
+        assert_eq!(
+            leaves.len(),
+            btree.keys().filter(|key| key.nibble(0) == nibble).count() - 1
+        );
+        assert!(traversal.next_leaf(&db, root).is_none());
+    }
+
+    // Starting past the last child fails as well.
+    let mut children = Children::new();
+    children.insert(Nibble::from(1), Child::new(HashValue::random(), true));
+    children.insert(Nibble::from(2), Child::new(HashValue::random(), true));
+    let node = InternalNode::new(children);
+    let err = NodeVisitInfo::new_next_child_to_visit(
+        root,
+        node.clone(),
+        Nibble::from(3),
+        Direction::Ascending,
+    )
+    .unwrap_err();
+    assert!(is_corrupt(&err));
+    let err =
+        NodeVisitInfo::new_next_child_to_visit(root, node, Nibble::from(0), Direction::Descending)
+            .unwrap_err();
+    assert!(is_corrupt(&err));
+}
+
+// The nodes of a cyclic tree do not hash to their node keys, which `validate` reports first.
+#[cfg(not(feature = "validate"))]
+#[test]
+fn test_iterator_max_depth() {
+    // A root with a leaf and a child having itself as a child, found after the seek.
+    let (db, cyclic_node_key) = cyclic_tree();
+    let leaf_node: Node<TestKey, TestValue> =
+        Node::new_leaf(TestKey(HashValue::zero()), TestValue::random());
+    let leaf_node_key = leaf_node.merkle_hash();
+    db.put_node(leaf_node_key, leaf_node).unwrap();
+    let mut children = Children::new();
+    children.insert(Nibble::from(0), Child::new(leaf_node_key, true));
+    children.insert(Nibble::from(15), Child::new(cyclic_node_key, false));
+    let root_node: Node<TestKey, TestValue> = Node::new_internal(children);
+    let root = root_node.merkle_hash();
+    db.put_node(root, root_node).unwrap();
+
+    // The descent into the cycle stops at the maximum depth.
+    let reader = CountingTreeReader::new(db);
+    let mut iter = JellyfishMerkleIterator::<_, _, _>::new(&reader, root, None)
+        .unwrap()
+        .with_max_depth(4)
+        .unwrap();
+    assert_eq!(iter.next().unwrap().unwrap().0.origin.0, HashValue::zero());
+    let reads = reader.reads();
+    let err = iter.next().unwrap().unwrap_err();
+    assert!(err.to_string().contains("maximum depth of 4"), "{}", err);
+    assert!(reader.reads() - reads <= 4);
+    assert!(iter.next().is_none());
+
+    // With the default bound, it stops at the nibbles of a key hash.
+    let mut iter = JellyfishMerkleIterator::<_, _, _>::new(&reader, root, None).unwrap();
+    iter.next().unwrap().unwrap();
+    let reads = reader.reads();
+    let err = iter.next().unwrap().unwrap_err();
+    assert!(
+        err.to_string()
+            .contains(&format!("maximum depth of {}", ROOT_NIBBLE_HEIGHT)),
+        "{}",
+        err
+    );
+    assert!(reader.reads() - reads <= ROOT_NIBBLE_HEIGHT);
+
+    // The bound applies to `next_back` as well.
+    let mut iter = JellyfishMerkleIterator::<_, _, _>::new(&reader, root, None)
+        .unwrap()
+        .with_max_depth(4)
+        .unwrap();
+    assert!(iter.next_back().unwrap().is_err());
+
+    // The bound cannot be above the nibbles of a key hash, nor below the depth of the iterator.
+    assert!(JellyfishMerkleIterator::<_, _, _>::new(&reader, root, None)
+        .unwrap()
+        .with_max_depth(ROOT_NIBBLE_HEIGHT + 1)
+        .is_err());
+    let db = MockTestStore::new_test();
+    let (root, btree) = init_tree(&db, 1000);
+    let root = root.unwrap();
+    assert!(JellyfishMerkleIterator::<_, _, _>::new(&db, root, None)
+        .unwrap()
+        .with_max_depth(0)
+        .is_err());
+    // A correct tree is iterated within a bound above its depth.
+    let iter = JellyfishMerkleIterator::<_, _, _>::new(&db, root, None)
+        .unwrap()
+        .with_max_depth(8)
+        .unwrap();
+    assert_eq!(collect(iter), btree.into_iter().collect::<Vec<_>>());
+}
+
+#[cfg(feature = "validate")]
+#[test]
+fn test_iterator_validate() {
+    let db = MockTestStore::new_test();
+    let (root, btree) = init_tree(&db, 50);
+    let root = root.unwrap();
+    let expected = btree.into_iter().collect::<Vec<_>>();
+    assert_eq!(
+        collect(JellyfishMerkleIterator::new(&db, root, None).unwrap()),
+        expected
+    );
+
+    // A leaf of the subtree at nibble 8 is replaced by another leaf, whose node key differs.
+    let mut node_key = match db.get_node(&root).unwrap() {
+        Node::Internal(internal_node) => internal_node.child(Nibble::from(8)).unwrap().hash,
+        _ => unreachable!(),
+    };
+    while let Node::Internal(internal_node) = db.get_node(&node_key).unwrap() {
+        node_key = internal_node.children().next().unwrap().1.hash;
+    }
+    db.delete_node_batch(&[node_key]).unwrap();
+    let leaf_node: Node<TestKey, TestValue> =
+        Node::new_leaf(TestKey::random(), TestValue::random());
+    assert_ne!(leaf_node.merkle_hash(), node_key);
+    db.put_node(node_key, leaf_node).unwrap();
+
+    let mut iter = JellyfishMerkleIterator::<_, _, _>::new(&db, root, None).unwrap();
+    for (key, _) in expected.iter().take_while(|(key, _)| key.nibble(0) < 8) {
+        assert_eq!(iter.next().unwrap().unwrap().0.origin.0, *key);
+    }
+    let err = iter.next().unwrap().unwrap_err();
+    assert!(err.to_string().contains("inconsistent"), "{}", err);
+    assert!(matches!(
+        err.downcast_ref::<SmtError>(),
+        Some(SmtError::HashMismatch { expected, .. }) if *expected == node_key
+    ));
+    assert!(iter.next().is_none());
+
+    // The seek checks the nodes on its path as well.
+    let (key, _) = expected.iter().find(|(key, _)| key.nibble(0) == 8).unwrap();
+    let err = JellyfishMerkleIterator::<_, _, _>::new(&db, root, Some(key_object(*key)))
+        .err()
+        .unwrap();
+    assert!(err.to_string().contains("inconsistent"), "{}", err);
+}
+
+#[test]
+fn test_structure_iterator() {
+    let db = MockTestStore::new_test();
+    let (root, btree) = init_tree(&db, 1000);
+    let root = root.unwrap();
+    let events = JellyfishMerkleStructureIterator::<TestKey, TestValue, _>::new(&db, root)
+        .collect::<Result<Vec<_>>>()
+        .unwrap();
+
+    // The leaf events are the items of the ordinary iterator.
+    let leaves = events
+        .iter()
+        .filter_map(|event| match event {
+            StructuralEvent::Leaf { key, value, .. } => Some((key.origin.0, value.origin.clone())),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        leaves,
+        collect(JellyfishMerkleIterator::new(&db, root, None).unwrap())
+    );
+    assert_eq!(leaves, btree.into_iter().collect::<Vec<_>>());
+    let depths = JellyfishMerkleIterator::<_, _, _>::new(&db, root, None)
+        .unwrap()
+        .with_depth()
+        .map(|item| item.map(|(_, _, depth)| depth))
+        .collect::<Result<Vec<_>>>()
+        .unwrap();
+    let leaf_depths = events
+        .iter()
+        .filter_map(|event| match event {
+            StructuralEvent::Leaf { depth, .. } => Some(*depth),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(leaf_depths, depths);
+
+    // Every internal node is entered with its key and left once all its children were visited.
+    let mut open_nodes: Vec<(usize, u32)> = vec![];
+    let mut num_internal_nodes = 0;
+    for event in &events {
+        if let Some((_, remaining)) = open_nodes.last_mut() {
+            if !matches!(event, StructuralEvent::ExitInternal) {
+                *remaining -= 1;
+            }
+        }
+        match event {
+            StructuralEvent::EnterInternal {
+                node_key,
+                depth,
+                bitmap,
+            } => {
+                assert_eq!(*depth, open_nodes.len());
+                match db.get_node(node_key).unwrap() {
+                    Node::Internal(internal_node) => {
+                        assert_eq!(internal_node.generate_bitmaps().0, *bitmap)
+                    }
+                    _ => panic!("Should be an internal node."),
+                }
+                open_nodes.push((*depth, bitmap.count_ones()));
+                num_internal_nodes += 1;
+            }
+            StructuralEvent::Leaf { depth, .. } => assert_eq!(*depth, open_nodes.len()),
+            StructuralEvent::ExitInternal => {
+                let (_, remaining) = open_nodes.pop().unwrap();
+                assert_eq!(remaining, 0);
+            }
+        }
+    }
+    assert!(open_nodes.is_empty());
+    assert_eq!(num_internal_nodes + 1000, db.num_nodes());
+    assert!(matches!(
+        events.first(),
+        Some(StructuralEvent::EnterInternal { node_key, depth: 0, .. }) if *node_key == root
+    ));
+}
+
+#[test]
+fn test_structure_iterator_small_trees() {
+    let db = MockTestStore::new_test();
+    let events = |root| {
+        JellyfishMerkleStructureIterator::<TestKey, TestValue, _>::new(&db, root)
+            .map(|event| event.map(|event| event.map_leaf(|k, v| (k.origin.0, v.origin))))
+            .collect::<Result<Vec<_>>>()
+            .unwrap()
+    };
+    assert_eq!(events(*SPARSE_MERKLE_PLACEHOLDER_HASH), vec![]);
+
+    let (root, btree) = init_tree(&db, 1);
+    let (key, value) = btree.into_iter().next().unwrap();
+    assert_eq!(
+        events(root.unwrap()),
+        vec![StructuralEvent::Leaf {
+            key,
+            value,
+            depth: 0
+        }]
+    );
+
+    // A cyclic tree is an error, after which the iteration is over.
+    let (db, root) = cyclic_tree();
+    let mut iter = JellyfishMerkleStructureIterator::<TestKey, TestValue, _>::new(&db, root);
+    for _ in 0..ROOT_NIBBLE_HEIGHT {
+        assert!(matches!(
+            iter.next(),
+            Some(Ok(StructuralEvent::EnterInternal { .. }))
+        ));
+    }
+    assert!(iter.next().unwrap().is_err());
+    assert!(iter.next().is_none());
+}
+
+/// Serves the nodes of a `MockTestStore` to a `JellyfishMerkleStream`.
+#[cfg(feature = "async")]
+struct AsyncMockTestStore(Mutex<MockTestStore>);
+
+#[cfg(feature = "async")]
+impl AsyncTreeReader<TestKey, TestValue> for AsyncMockTestStore {
+    fn get_node(
+        &self,
+        node_key: &HashValue,
+    ) -> impl Future<Output = Result<Node<TestKey, TestValue>>> + Send {
+        // The node is read before the future is returned, so the lock is not held across polls.
+        std::future::ready(self.0.lock().unwrap().get_node(node_key))
+    }
+}
+
+/// Polls `future` once, the futures of `AsyncMockTestStore` are always ready.
+#[cfg(feature = "async")]
+fn block_on<F: Future>(future: F) -> F::Output {
+    match pin!(future).poll(&mut Context::from_waker(Waker::noop())) {
+        Poll::Ready(output) => output,
+        Poll::Pending => panic!("AsyncMockTestStore should not be pending."),
+    }
+}
+
+#[cfg(feature = "async")]
+fn collect_stream(
+    mut stream: JellyfishMerkleStream<TestKey, TestValue, AsyncMockTestStore>,
+) -> Vec<(HashValue, TestValue)> {
+    let mut items = vec![];
+    while let Some(item) = block_on(poll_fn(|cx| Pin::new(&mut stream).poll_next(cx))) {
+        let (k, v) = item.unwrap();
+        items.push((k.origin.0, v.origin));
+    }
+    items
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn test_stream() {
+    let db = MockTestStore::new_test();
+    let (root, btree) = init_tree(&db, 1000);
+    let root = root.unwrap();
+    let keys = btree.keys().cloned().collect::<Vec<_>>();
+    let expected = btree.clone().into_iter().collect::<Vec<_>>();
+    let db = AsyncMockTestStore(Mutex::new(db));
+
+    let stream = block_on(JellyfishMerkleStream::new(&db, root, None)).unwrap();
+    assert_eq!(collect_stream(stream), expected);
+    let stream = block_on(JellyfishMerkleStream::new_rev(&db, root, None)).unwrap();
+    assert_eq!(
+        collect_stream(stream),
+        expected.iter().rev().cloned().collect::<Vec<_>>()
+    );
+
+    for i in [0, 1, 500, 998, 999] {
+        let stream = block_on(JellyfishMerkleStream::new(
+            &db,
+            root,
+            Some(key_object(keys[i])),
+        ))
+        .unwrap();
+        assert_eq!(collect_stream(stream), expected[i..]);
+        let stream = block_on(JellyfishMerkleStream::new(
+            &db,
+            root,
+            Some(key_object(plus_one(keys[i]))),
+        ))
+        .unwrap();
+        assert_eq!(collect_stream(stream), expected[i + 1..]);
+        let stream = block_on(JellyfishMerkleStream::new_rev(
+            &db,
+            root,
+            Some(key_object(keys[i])),
+        ))
+        .unwrap();
+        assert_eq!(
+            collect_stream(stream),
+            expected[..=i].iter().rev().cloned().collect::<Vec<_>>()
+        );
+    }
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn test_stream_after_error() {
+    let db = MockTestStore::new_test();
+    let (root, btree) = init_tree(&db, 50);
+    let root = root.unwrap();
+    let keys = btree.keys().cloned().collect::<Vec<_>>();
+    let partial_db = AsyncMockTestStore(Mutex::new(copy_path(&db, root, keys[0])));
+
+    let mut stream = block_on(JellyfishMerkleStream::<_, _, _>::new(
+        &partial_db,
+        root,
+        None,
+    ))
+    .unwrap();
+    assert_eq!(
+        block_on(stream.next()).unwrap().unwrap().0.origin.0,
+        keys[0]
+    );
+    assert!(block_on(stream.next()).unwrap().is_err());
+    for _ in 0..3 {
+        assert!(block_on(stream.next()).is_none());
+    }
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn test_stream_empty_tree() {
+    let db = MockTestStore::new_test();
+    let tree: JellyfishMerkleTree<TestKey, TestValue, MockTestStore> =
+        JellyfishMerkleTree::new(&db);
+    let (root, batch) = tree
+        .updates(None, vec![(key_object(HashValue::random()), None)])
+        .unwrap();
+    db.write_tree_update_batch(batch).unwrap();
+    let db = AsyncMockTestStore(Mutex::new(db));
+
+    let stream = block_on(JellyfishMerkleStream::new(&db, root, None)).unwrap();
+    assert_eq!(collect_stream(stream), vec![]);
+    let stream = block_on(JellyfishMerkleStream::new_rev(&db, root, None)).unwrap();
+    assert_eq!(collect_stream(stream), vec![]);
+}
+
+#[cfg(all(feature = "async", feature = "validate"))]
+#[test]
+fn test_stream_validate() {
+    let db = MockTestStore::new_test();
+    let (root, btree) = init_tree(&db, 50);
+    let root = root.unwrap();
+    let keys = btree.keys().cloned().collect::<Vec<_>>();
+
+    // A leaf of the subtree at nibble 8 is replaced by another leaf, whose node key differs.
+    let mut node_key = match db.get_node(&root).unwrap() {
+        Node::Internal(internal_node) => internal_node.child(Nibble::from(8)).unwrap().hash,
+        _ => unreachable!(),
+    };
+    while let Node::Internal(internal_node) = db.get_node(&node_key).unwrap() {
+        node_key = internal_node.children().next().unwrap().1.hash;
+    }
+    db.delete_node_batch(&[node_key]).unwrap();
+    let leaf_node: Node<TestKey, TestValue> =
+        Node::new_leaf(TestKey::random(), TestValue::random());
+    db.put_node(node_key, leaf_node).unwrap();
+    let db = AsyncMockTestStore(Mutex::new(db));
+
+    let mut stream = block_on(JellyfishMerkleStream::<_, _, _>::new(&db, root, None)).unwrap();
+    for key in keys.iter().take_while(|key| key.nibble(0) < 8) {
+        assert_eq!(block_on(stream.next()).unwrap().unwrap().0.origin.0, *key);
+    }
+    let err = block_on(stream.next()).unwrap().unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<SmtError>(),
+        Some(SmtError::HashMismatch { expected, .. }) if *expected == node_key
+    ));
+    assert!(block_on(stream.next()).is_none());
+
+    // Seeking into the replaced leaf fails as well.
+    let starting_key = keys.iter().find(|key| key.nibble(0) == 8).unwrap();
+    let err = block_on(JellyfishMerkleStream::<_, _, _>::new(
+        &db,
+        root,
+        Some(key_object(*starting_key)),
+    ))
+    .err()
+    .unwrap();
+    assert!(matches!(
+        err.downcast_ref::<SmtError>(),
+        Some(SmtError::HashMismatch { expected, .. }) if *expected == node_key
+    ));
+}
+
+/// Copies all the nodes of the tree at `root` into an `InMemoryNodeStore`, which unlike
+/// `MockTestStore` can be shared between threads.
+fn copy_tree(db: &MockTestStore, root: HashValue) -> InMemoryNodeStore {
+    fn copy_node(
+        db: &MockTestStore,
+        node_key: HashValue,
+        nodes: &mut BTreeMap<HashValue, Vec<u8>>,
+    ) {
+        let node = db.get_node(&node_key).unwrap();
+        if let Node::Internal(internal_node) = &node {
+            for child in internal_node.all_child() {
+                copy_node(db, child, nodes);
+            }
+        }
+        nodes.insert(node_key, node.encode().unwrap());
+    }
+
+    let mut nodes = BTreeMap::new();
+    copy_node(db, root, &mut nodes);
+    let store = InMemoryNodeStore::default();
+    store.write_nodes(nodes).unwrap();
+    store
+}
+
+#[test]
+fn test_into_iterator_split() {
+    let db = MockTestStore::new_test();
+    let (root, btree) = init_tree(&db, 1000);
+    let root = root.unwrap();
+    let keys = btree.keys().cloned().collect::<Vec<_>>();
+    let expected = btree.into_iter().collect::<Vec<_>>();
+    let store = copy_tree(&db, root);
+
+    for i in [0, 1, 500, 998, 999] {
+        let iter: JellyfishMerkleIntoIterator<TestKey, TestValue, _> =
+            JellyfishMerkleIntoIterator::new(store.clone(), root, keys[i]).unwrap();
+        let iters = iter.split();
+        assert!(iters.len() <= 16);
+        let actual = iters
+            .into_par_iter()
+            .map(|iter| {
+                iter.map(|item| item.map(|(k, v)| (k.origin.0, v.origin)))
+                    .collect::<Result<Vec<_>>>()
+            })
+            .collect::<Result<Vec<_>>>()
+            .unwrap()
+            .concat();
+        assert_eq!(actual, expected[i..]);
+    }
+
+    // A partially consumed iterator only splits what it has left to yield.
+    let mut iter: JellyfishMerkleIntoIterator<TestKey, TestValue, _> =
+        JellyfishMerkleIntoIterator::new(store, root, HashValue::zero()).unwrap();
+    for _ in 0..10 {
+        iter.next().unwrap().unwrap();
+    }
+    let actual = iter
+        .split()
+        .into_iter()
+        .flatten()
+        .map(|item| item.map(|(k, v)| (k.origin.0, v.origin)))
+        .collect::<Result<Vec<_>>>()
+        .unwrap();
+    assert_eq!(actual, expected[10..]);
+}
+
+#[test]
+fn test_into_iterator_split_small_trees() {
+    // A tree with a single leaf is not split.
+    let db = MockTestStore::new_test();
+    let (root, btree) = init_tree(&db, 1);
+    let root = root.unwrap();
+    let store = copy_tree(&db, root);
+    let iter: JellyfishMerkleIntoIterator<TestKey, TestValue, _> =
+        JellyfishMerkleIntoIterator::new(store, root, HashValue::zero()).unwrap();
+    let mut iters = iter.split();
+    assert_eq!(iters.len(), 1);
+    let (key, value) = iters.pop().unwrap().next().unwrap().unwrap();
+    assert_eq!(
+        vec![(key.origin.0, value.origin)],
+        btree.into_iter().collect::<Vec<_>>()
+    );
+
+    // Neither is an iterator which is over.
+    let db = MockTestStore::new_test();
+    let (root, btree) = init_tree(&db, 2);
+    let root = root.unwrap();
+    let last_key = *btree.keys().last().unwrap();
+    let store = copy_tree(&db, root);
+    let mut iter: JellyfishMerkleIntoIterator<TestKey, TestValue, _> =
+        JellyfishMerkleIntoIterator::new(store, root, plus_one(last_key)).unwrap();
+    assert!(iter.next().is_none());
+    let mut iters = iter.split();
+    assert_eq!(iters.len(), 1);
+    assert!(iters.pop().unwrap().next().is_none());
+}
+
+/// Reads the nodes of a `MockTestStore` and records when it is dropped.
+struct DropFlagReader<'a> {
+    db: &'a MockTestStore,
+    dropped: Rc<Cell<bool>>,
+}
+
+impl TreeReader<TestKey, TestValue> for DropFlagReader<'_> {
+    fn get_node_option(&self, node_key: &HashValue) -> Result<Option<Node<TestKey, TestValue>>> {
+        self.db.get_node_option(node_key)
+    }
+}
+
+impl Drop for DropFlagReader<'_> {
+    fn drop(&mut self) {
+        self.dropped.set(true);
+    }
+}
+
+#[test]
+fn test_into_iterator_drops_reader() {
+    let db = MockTestStore::new_test();
+    let (root, btree) = init_tree(&db, 10);
+    let root = root.unwrap();
+    let dropped = Rc::new(Cell::new(false));
+    let reader = DropFlagReader {
+        db: &db,
+        dropped: dropped.clone(),
+    };
+    let mut iter =
+        JellyfishMerkleIntoIterator::<_, _, _>::new(reader, root, HashValue::zero()).unwrap();
+    for key in btree.keys() {
+        assert!(!dropped.get());
+        assert_eq!(iter.next().unwrap().unwrap().0.origin.0, *key);
+    }
+    assert!(iter.next().is_none());
+    assert!(dropped.get());
+    assert!(!iter.holds_reader());
+    assert!(iter.next().is_none());
+
+    // Nothing is left to read after a starting key past the last key.
+    let dropped = Rc::new(Cell::new(false));
+    let reader = DropFlagReader {
+        db: &db,
+        dropped: dropped.clone(),
+    };
+    let iter = JellyfishMerkleIntoIterator::<_, _, _>::new(
+        reader,
+        root,
+        HashValue::new([0xff; HashValue::LENGTH]),
+    )
+    .unwrap();
+    assert!(dropped.get());
+    assert_eq!(iter.count(), 0);
+}
+
+#[test]
+fn test_iterator_prefetches_leaves() {
+    let db = MockTestStore::new_test();
+    let (root, btree) = init_tree(&db, 1000);
+    let root = root.unwrap();
+    let num_nodes = db.num_nodes();
+    let reader = CountingTreeReader::new(db);
+
+    let expected = btree.into_iter().collect::<Vec<_>>();
+    let iter = JellyfishMerkleIterator::new(&reader, root, None).unwrap();
+    assert_eq!(collect(iter), expected);
+    let forward_reads = reader.reads();
+
+    let iter = JellyfishMerkleIterator::new_rev(&reader, root, None).unwrap();
+    assert_eq!(
+        collect(iter),
+        expected.iter().rev().cloned().collect::<Vec<_>>()
+    );
+    let backward_reads = reader.reads() - forward_reads;
+
+    // Without reading ahead, every node would take a read of its own.
+    assert!(forward_reads * 3 < num_nodes * 2);
+    assert!(backward_reads * 3 < num_nodes * 2);
+}
+
+/// Reads the nodes of a batch from the last one, as a store fetching them concurrently may complete
+/// them in any order, and counts the calls, each of which a remote store serves in a round trip.
+struct BatchingReader {
+    db: MockTestStore,
+    round_trips: AtomicUsize,
+}
+
+impl TreeReader<TestKey, TestValue> for BatchingReader {
+    fn get_node_option(&self, node_key: &HashValue) -> Result<Option<Node<TestKey, TestValue>>> {
+        self.round_trips.fetch_add(1, Ordering::SeqCst);
+        self.db.get_node_option(node_key)
+    }
+
+    fn get_nodes(&self, node_keys: &[HashValue]) -> Result<Vec<Option<Node<TestKey, TestValue>>>> {
+        self.round_trips.fetch_add(1, Ordering::SeqCst);
+        let mut nodes = node_keys
+            .iter()
+            .rev()
+            .map(|node_key| self.db.get_node_option(node_key))
+            .collect::<Result<Vec<_>>>()?;
+        nodes.reverse();
+        Ok(nodes)
+    }
+}
+
+impl Versioned<TestKey, TestValue> for BatchingReader {}
+
+#[test]
+fn test_iterator_lookahead() {
+    let db = MockTestStore::new_test();
+    let (root, btree) = init_tree(&db, 1000);
+    let root = root.unwrap();
+    let reader = BatchingReader {
+        db,
+        round_trips: AtomicUsize::new(0),
+    };
+    let expected = btree.into_iter().collect::<Vec<_>>();
+
+    let iter = JellyfishMerkleIterator::new(&reader, root, None).unwrap();
+    assert_eq!(collect(iter), expected);
+    let default_round_trips = reader.round_trips.swap(0, Ordering::SeqCst);
+
+    for lookahead in [1, 4, 16] {
+        let iter = JellyfishMerkleIterator::new_prefetched(&reader, root, None, lookahead).unwrap();
+        assert_eq!(collect(iter), expected);
+        let round_trips = reader.round_trips.swap(0, Ordering::SeqCst);
+        if lookahead == 16 {
+            assert!(round_trips < default_round_trips);
+        }
+
+        let iter = JellyfishMerkleIterator::new_rev(&reader, root, None)
+            .unwrap()
+            .with_lookahead(lookahead);
+        assert_eq!(
+            collect(iter),
+            expected.iter().rev().cloned().collect::<Vec<_>>()
+        );
+
+        // Both ends of the iterator read ahead, and meet without skipping a key.
+        let starting_key = TestKey(expected[100].0).into_object();
+        let mut iter = JellyfishMerkleIterator::<_, _, _>::new_prefetched(
+            &reader,
+            root,
+            Some(starting_key),
+            lookahead,
+        )
+        .unwrap();
+        let mut front = vec![];
+        let mut back = vec![];
+        while let Some(item) = iter.next() {
+            front.push(item.unwrap().0.origin.0);
+            if let Some(item) = iter.next_back() {
+                back.push(item.unwrap().0.origin.0);
+            }
+        }
+        back.reverse();
+        front.extend(back);
+        assert!(front
+            .into_iter()
+            .eq(expected[100..].iter().map(|(key_hash, _)| *key_hash)));
+        reader.round_trips.store(0, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn test_iterator_filtered() {
+    let db = MockTestStore::new_test();
+    let (root, btree) = init_tree(&db, 1000);
+    let root = root.unwrap();
+    let pruned_key = match db.get_node(&root).unwrap() {
+        Node::Internal(node) => node.child(Nibble::from(3)).unwrap().hash,
+        _ => panic!("The root should be internal."),
+    };
+    let reader = CountingTreeReader::new(db);
+
+    let iter = JellyfishMerkleIterator::new(&reader, root, None).unwrap();
+    assert_eq!(collect(iter).len(), btree.len());
+    let all_reads = reader.reads();
+
+    // Exactly the leaves below the pruned child of the root are skipped, and none of the nodes
+    // below it is read.
+    let iter = JellyfishMerkleIterator::new_filtered(&reader, root, None, move |node_key, _| {
+        *node_key == pruned_key
+    })
+    .unwrap();
+    let expected = btree
+        .iter()
+        .filter(|(key_hash, _)| key_hash.nibble(0) != 3)
+        .map(|(key_hash, value)| (*key_hash, value.clone()))
+        .collect::<Vec<_>>();
+    assert!(expected.len() < btree.len());
+    assert_eq!(collect(iter), expected);
+    assert!(reader.reads() - all_reads < all_reads);
+
+    // A starting key in a pruned subtree starts the iteration after the subtree.
+    let starting_key = btree
+        .keys()
+        .find(|key_hash| key_hash.nibble(0) == 3)
+        .unwrap();
+    let iter = JellyfishMerkleIterator::new_filtered(
+        &reader,
+        root,
+        Some(TestKey(*starting_key).into_object()),
+        move |node_key, _| *node_key == pruned_key,
+    )
+    .unwrap();
+    let expected_after = expected
+        .iter()
+        .filter(|(key_hash, _)| key_hash > starting_key)
+        .cloned()
+        .collect::<Vec<_>>();
+    assert_eq!(collect(iter), expected_after);
+
+    // Iterating from the back skips the same subtree.
+    let iter = JellyfishMerkleIterator::<_, _, _>::new_filtered(
+        &reader,
+        root,
+        None,
+        move |node_key, _| *node_key == pruned_key,
+    )
+    .unwrap();
+    let back = iter.rev().map(|item| item.unwrap().0.origin.0);
+    assert!(back.eq(expected.iter().rev().map(|(key_hash, _)| *key_hash)));
+
+    // Pruning the root leaves nothing to yield.
+    let iter = JellyfishMerkleIterator::new_filtered(&reader, root, None, |_, _| true).unwrap();
+    assert_eq!(collect(iter), vec![]);
+}
+
+#[test]
+fn test_iterator_resume_filtered() {
+    let db = MockTestStore::new_test();
+    let (root, btree) = init_tree(&db, 1000);
+    let root = root.unwrap();
+    let pruned_key = match db.get_node(&root).unwrap() {
+        Node::Internal(node) => node.child(Nibble::from(3)).unwrap().hash,
+        _ => panic!("The root should be internal."),
+    };
+    let prune = move |node_key: &HashValue, _: &InternalNode| *node_key == pruned_key;
+    let expected = btree
+        .keys()
+        .filter(|key_hash| key_hash.nibble(0) != 3)
+        .cloned()
+        .collect::<Vec<_>>();
+
+    // Stop before the pruned subtree, under the second child of the root.
+    let mut iter =
+        JellyfishMerkleIterator::<_, _, _>::new_filtered(&db, root, None, prune).unwrap();
+    let taken = expected
+        .iter()
+        .take_while(|key_hash| key_hash.nibble(0) < 2)
+        .count();
+    for _ in 0..taken {
+        iter.next().unwrap().unwrap();
+    }
+    let cursor = iter.cursor();
+
+    // The predicate is not in the cursor: a plain resume yields the pruned subtree again.
+    let resumed = JellyfishMerkleIterator::<_, _, _>::resume(&db, root, cursor.clone()).unwrap();
+    assert_eq!(collect(resumed).len(), btree.len() - taken);
+
+    let resumed =
+        JellyfishMerkleIterator::<_, _, _>::resume_filtered(&db, root, cursor, prune).unwrap();
+    let keys = collect(resumed)
+        .into_iter()
+        .map(|(key_hash, _)| key_hash)
+        .collect::<Vec<_>>();
+    assert_eq!(keys, expected[taken..]);
+}
+
 //
 // use super::{
 //     iterator::JellyfishMerkleIterator, mock_tree_store::MockTreeStore, test_helper::plus_one,
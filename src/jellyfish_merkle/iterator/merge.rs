@@ -0,0 +1,182 @@
+// Copyright (c) RoochNetwork
+// SPDX-License-Identifier: Apache-2.0
+
+//! This module implements `merge_sorted`, a k-way merge of iterators over the key-value pairs of
+//! several trees, e.g. the shards of a tree, into one iterator in key hash order, and
+//! `PeekableLeaf`, the wrapper exposing the key hash of the next pair of an iterator it is built
+//! on.
+
+use crate::jellyfish_merkle::hash::{HashValue, SMTHash, Sha3TreeHasher, TreeHasher};
+use crate::SMTObject;
+use anyhow::Result;
+use std::{cmp::Reverse, collections::BinaryHeap, iter::FusedIterator, marker::PhantomData};
+
+/// Wraps an iterator over key-value pairs in ascending key hash order, like
+/// `JellyfishMerkleIterator`, exposing the key hash of the next pair without consuming it.
+pub struct PeekableLeaf<K, V, I, H = Sha3TreeHasher> {
+    iter: I,
+    /// The next pair, read ahead by `peek_key_hash`.
+    peeked: Option<(SMTObject<K>, SMTObject<V>)>,
+    hasher: PhantomData<H>,
+}
+
+impl<K, V, I, H> PeekableLeaf<K, V, I, H>
+where
+    I: Iterator<Item = Result<(SMTObject<K>, SMTObject<V>)>>,
+    H: TreeHasher,
+{
+    /// Wraps `iter`. Nothing is read until the first call to `peek_key_hash` or `next`.
+    pub fn new(iter: I) -> Self {
+        Self {
+            iter,
+            peeked: None,
+            hasher: PhantomData,
+        }
+    }
+
+    /// Returns the key hash of the pair the following `next` call will yield, or `None` if there
+    /// is none left. An error of the wrapped iterator is returned here rather than by `next`.
+    pub fn peek_key_hash(&mut self) -> Result<Option<HashValue>> {
+        if self.peeked.is_none() {
+            self.peeked = self.iter.next().transpose()?;
+        }
+        Ok(self
+            .peeked
+            .as_ref()
+            .map(|(key, _)| key.merkle_hash_with::<H>()))
+    }
+}
+
+impl<K, V, I, H> Iterator for PeekableLeaf<K, V, I, H>
+where
+    I: Iterator<Item = Result<(SMTObject<K>, SMTObject<V>)>>,
+    H: TreeHasher,
+{
+    type Item = Result<(SMTObject<K>, SMTObject<V>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.peeked.take() {
+            Some(pair) => Some(Ok(pair)),
+            None => self.iter.next(),
+        }
+    }
+}
+
+/// Merges `iters`, each of them yielding key-value pairs in ascending key hash order, into one
+/// iterator yielding all their pairs in ascending key hash order, see [`MergeSortedIterator`].
+/// The hasher `H` is the one the key hashes are computed with, i.e. the one of the trees.
+///
+/// [`MergeSortedIterator`]: crate::MergeSortedIterator
+pub fn merge_sorted<K, V, I, H>(iters: Vec<I>) -> MergeSortedIterator<K, V, I, H>
+where
+    I: Iterator<Item = Result<(SMTObject<K>, SMTObject<V>)>>,
+    H: TreeHasher,
+{
+    MergeSortedIterator::new(iters)
+}
+
+/// Merges iterators, each of them yielding key-value pairs in ascending key hash order, into one
+/// iterator yielding all their pairs in ascending key hash order. It keeps the key hash of the
+/// next pair of each iterator in a binary heap, so a pair costs a logarithm of the number of
+/// iterators. A key hash yielded by several iterators is yielded once for each, in the order of
+/// the iterators. Each iterator has to be in ascending key hash order for the output to be, which
+/// is not checked.
+///
+/// The iteration stops after the first error of any iterator. The pairs with a key hash before
+/// the one the failing iterator was about to yield have been yielded by then.
+pub struct MergeSortedIterator<K, V, I, H = Sha3TreeHasher> {
+    sources: Vec<PeekableLeaf<K, V, I, H>>,
+    /// The key hash of the next pair of every source with pairs left, and the index of the source.
+    heap: BinaryHeap<Reverse<(HashValue, usize)>>,
+    /// Whether the heap holds the sources yet, which is only done by the first call to `next`.
+    started: bool,
+    /// The error of a source met after reading the pair to yield, returned by the following call
+    /// to `next`.
+    error: Option<anyhow::Error>,
+    done: bool,
+}
+
+impl<K, V, I, H> MergeSortedIterator<K, V, I, H>
+where
+    I: Iterator<Item = Result<(SMTObject<K>, SMTObject<V>)>>,
+    H: TreeHasher,
+{
+    /// Constructs a new iterator merging `iters`. The hasher `H` is the one the key hashes are
+    /// computed with, i.e. the one of the trees. Nothing is read until the first call to `next`.
+    pub fn new(iters: Vec<I>) -> Self {
+        let num_sources = iters.len();
+        Self {
+            sources: iters.into_iter().map(PeekableLeaf::new).collect(),
+            heap: BinaryHeap::with_capacity(num_sources),
+            started: false,
+            error: None,
+            done: false,
+        }
+    }
+
+    /// Pushes the key hash of the next pair of the source at `index` onto the heap, if it has
+    /// pairs left.
+    fn push_source(&mut self, index: usize) -> Result<()> {
+        if let Some(key_hash) = self.sources[index].peek_key_hash()? {
+            self.heap.push(Reverse((key_hash, index)));
+        }
+        Ok(())
+    }
+
+    fn next_pair(&mut self) -> Result<Option<(SMTObject<K>, SMTObject<V>)>> {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+        if !self.started {
+            self.started = true;
+            for index in 0..self.sources.len() {
+                self.push_source(index)?;
+            }
+        }
+        let index = match self.heap.pop() {
+            Some(Reverse((_, index))) => index,
+            None => return Ok(None),
+        };
+        let pair = self.sources[index]
+            .next()
+            .expect("The key hash of the pair was peeked.")?;
+        if let Err(err) = self.push_source(index) {
+            self.error = Some(err);
+        }
+        Ok(Some(pair))
+    }
+}
+
+impl<K, V, I, H> Iterator for MergeSortedIterator<K, V, I, H>
+where
+    I: Iterator<Item = Result<(SMTObject<K>, SMTObject<V>)>>,
+    H: TreeHasher,
+{
+    type Item = Result<(SMTObject<K>, SMTObject<V>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.next_pair() {
+            Ok(Some(pair)) => Some(Ok(pair)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(err) => {
+                // Stop iterating after an error.
+                self.done = true;
+                self.heap.clear();
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+impl<K, V, I, H> FusedIterator for MergeSortedIterator<K, V, I, H>
+where
+    I: Iterator<Item = Result<(SMTObject<K>, SMTObject<V>)>>,
+    H: TreeHasher,
+{
+}
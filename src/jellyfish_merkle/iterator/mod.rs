@@ -7,26 +7,96 @@
 //! This module implements `JellyfishMerkleIterator`. Initialized with a version and a key, the
 //! iterator generates all the key-value pairs in this version of the tree, starting from the
 //! smallest key that is greater or equal to the given key, by performing a depth first traversal
-//! on the tree.
+//! on the tree. The traversal can also run from right to left, generating the key-value pairs in
+//! descending order starting from the largest key that is less or equal to the given key.
 
+pub mod integrity;
 #[cfg(test)]
 mod iterator_test;
+pub mod merge;
+#[cfg(feature = "async")]
+mod stream;
 
-use super::hash::HashValue;
+#[cfg(feature = "async")]
+pub use stream::JellyfishMerkleStream;
+
+use super::hash::{HashValue, Sha3TreeHasher, TreeHasher};
 use super::{
+    get_root_node,
     hash::SMTHash,
     nibble::Nibble,
     nibble_path::NibblePath,
-    node_type::{InternalNode, Node, NodeKey},
-    TreeReader,
+    node_type::{InternalNode, LeafNode, Node, NodeKey},
+    observer::Observer,
+    pin::RootPin,
+    SmtError, TreeReader, ValueReader, Versioned, ROOT_NIBBLE_HEIGHT,
 };
 use crate::{Key, SMTObject, Value};
-use anyhow::{format_err, Result};
-use std::marker::PhantomData;
+use anyhow::{bail, ensure, format_err, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    iter::FusedIterator,
+    marker::PhantomData,
+    ops::{Bound, ControlFlow},
+    sync::Arc,
+};
+
+/// The order in which a traversal visits the leaves of the tree.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+enum Direction {
+    /// Visits the leaves in ascending order of their key hashes, from left to right.
+    Ascending,
+    /// Visits the leaves in descending order of their key hashes, from right to left.
+    Descending,
+}
+
+impl Direction {
+    /// Moves the only 1-bit of `child_bit` to the next child position in this direction.
+    fn step(self, child_bit: u16) -> u16 {
+        match self {
+            Direction::Ascending => child_bit << 1,
+            Direction::Descending => child_bit >> 1,
+        }
+    }
+
+    /// Returns the opposite direction.
+    fn reverse(self) -> Self {
+        match self {
+            Direction::Ascending => Direction::Descending,
+            Direction::Descending => Direction::Ascending,
+        }
+    }
+
+    /// Returns the key hash a traversal in this direction starts from when no key is given.
+    fn first_key_hash(self) -> HashValue {
+        match self {
+            Direction::Ascending => HashValue::zero(),
+            Direction::Descending => HashValue::new([0xff; HashValue::LENGTH]),
+        }
+    }
+
+    /// Whether `a` is visited strictly before `b` in this direction.
+    fn is_before(self, a: HashValue, b: HashValue) -> bool {
+        match self {
+            Direction::Ascending => a < b,
+            Direction::Descending => a > b,
+        }
+    }
+
+    /// Whether `key_hash` is visited no later than `end` in this direction.
+    fn is_within(self, key_hash: HashValue, end: &Bound<HashValue>) -> bool {
+        match end {
+            Bound::Included(end) => !self.is_before(*end, key_hash),
+            Bound::Excluded(end) => self.is_before(key_hash, *end),
+            Bound::Unbounded => true,
+        }
+    }
+}
 
 /// `NodeVisitInfo` keeps track of the status of an internal node during the iteration process. It
 /// indicates which ones of its children have been visited.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 struct NodeVisitInfo {
     /// The key to this node.
     node_key: NodeKey,
@@ -39,70 +109,101 @@ struct NodeVisitInfo {
     children_bitmap: u16,
 
     /// This integer always has exactly one 1-bit. The position of the 1-bit (from LSB) indicates
-    /// the next child to visit in the iteration process. All the ones on the left have already
-    /// been visited. All the children on the right (including this one) have not been visited yet.
+    /// the next child to visit in the iteration process. All the ones before it in the direction
+    /// of the traversal have already been visited. All the children after it (including this one)
+    /// have not been visited yet.
     next_child_to_visit: u16,
 }
 
 impl NodeVisitInfo {
     /// Constructs a new `NodeVisitInfo` with given node key and node. `next_child_to_visit` will
-    /// be set to the leftmost child.
-    fn new(node_key: NodeKey, node: InternalNode) -> Self {
+    /// be set to the first child in `direction`, i.e. the leftmost child when ascending and the
+    /// rightmost child when descending. Fails if the node has no child.
+    fn new(node_key: NodeKey, node: InternalNode, direction: Direction) -> Result<Self> {
         let (children_bitmap, _) = node.generate_bitmaps();
-        Self {
+        ensure!(
+            children_bitmap != 0,
+            SmtError::CorruptNode(format!("Internal node {:x} has no child.", node_key))
+        );
+        let next_child_to_visit = match direction {
+            Direction::Ascending => 1 << children_bitmap.trailing_zeros(),
+            Direction::Descending => 1 << (15 - children_bitmap.leading_zeros()),
+        };
+        Ok(Self {
             node_key,
             node,
             children_bitmap,
-            next_child_to_visit: 1 << children_bitmap.trailing_zeros(),
-        }
+            next_child_to_visit,
+        })
     }
 
     /// Same as `new` but points `next_child_to_visit` to a specific location. If the child
-    /// corresponding to `next_child_to_visit` does not exist, set it to the next one on the
-    /// right.
+    /// corresponding to `next_child_to_visit` does not exist, set it to the next one in
+    /// `direction`. Fails if there is no child from `next_child_to_visit` on in `direction`.
     fn new_next_child_to_visit(
         node_key: NodeKey,
         node: InternalNode,
         next_child_to_visit: Nibble,
-    ) -> Self {
+        direction: Direction,
+    ) -> Result<Self> {
         let (children_bitmap, _) = node.generate_bitmaps();
-        let mut next_child_to_visit = 1 << u8::from(next_child_to_visit);
-        while next_child_to_visit & children_bitmap == 0 {
-            next_child_to_visit <<= 1;
-        }
-        Self {
+        let mut info = Self {
             node_key,
             node,
             children_bitmap,
-            next_child_to_visit,
+            next_child_to_visit: 1 << u8::from(next_child_to_visit),
+        };
+        info.skip_missing_children(direction)?;
+        Ok(info)
+    }
+
+    /// Whether the next child to visit is the last one in `direction`, i.e. the rightmost one when
+    /// ascending and the leftmost one when descending.
+    fn is_last(&self, direction: Direction) -> bool {
+        match direction {
+            Direction::Ascending => {
+                self.next_child_to_visit.leading_zeros() == self.children_bitmap.leading_zeros()
+            }
+            Direction::Descending => {
+                self.next_child_to_visit.trailing_zeros() == self.children_bitmap.trailing_zeros()
+            }
         }
     }
 
-    /// Whether the next child to visit is the rightmost one.
-    fn is_rightmost(&self) -> bool {
-        assert!(self.next_child_to_visit.leading_zeros() >= self.children_bitmap.leading_zeros());
-        self.next_child_to_visit.leading_zeros() == self.children_bitmap.leading_zeros()
+    /// Advances `next_child_to_visit` to the next child in `direction`. Fails if there is none,
+    /// which `is_last` rules out unless `children_bitmap` does not match the children.
+    fn advance(&mut self, direction: Direction) -> Result<()> {
+        self.next_child_to_visit = direction.step(self.next_child_to_visit);
+        self.skip_missing_children(direction)
     }
 
-    /// Advances `next_child_to_visit` to the next child on the right.
-    fn advance(&mut self) {
-        assert!(!self.is_rightmost(), "Advancing past rightmost child.");
-        self.next_child_to_visit <<= 1;
+    /// Moves `next_child_to_visit` in `direction` until it points to a child. Fails if the bit is
+    /// shifted out of the 16 nibbles first.
+    fn skip_missing_children(&mut self, direction: Direction) -> Result<()> {
         while self.next_child_to_visit & self.children_bitmap == 0 {
-            self.next_child_to_visit <<= 1;
+            ensure!(
+                self.next_child_to_visit != 0,
+                SmtError::CorruptNode(format!(
+                    "Ran past the last child of internal node {:x}, whose children bitmap is \
+                     {:#018b}.",
+                    self.node_key, self.children_bitmap
+                ))
+            );
+            self.next_child_to_visit = direction.step(self.next_child_to_visit);
         }
+        Ok(())
     }
 }
 
-/// The `JellyfishMerkleIterator` implementation.
-pub struct JellyfishMerkleIterator<'a, K, V, R: 'a + TreeReader<K, V>> {
-    /// The storage engine from which we can read nodes using node keys.
-    reader: &'a R,
-
-    /// The root hash of the tree this iterator is running on.
-    state_root_hash: HashValue,
+/// A predicate over the internal nodes of a tree, telling whether the subtree of a node is to be
+/// skipped, see [`JellyfishMerkleIterator::new_filtered`].
+type Prune = Arc<dyn Fn(&NodeKey, &InternalNode) -> bool + Send + Sync>;
 
-    /// The stack used for depth first traversal.
+/// The state of a depth first traversal over the leaves of a tree. This is the descent logic
+/// shared by all the iterators in this module, so that they visit the tree the same way.
+struct Traversal<K, V, H> {
+    /// The stack used for depth first traversal. It never holds more internal nodes than
+    /// `ROOT_NIBBLE_HEIGHT`, which is its capacity from the start.
     parent_stack: Vec<NodeVisitInfo>,
 
     /// Whether the iteration has finished. Usually this can be determined by checking whether
@@ -110,369 +211,2014 @@ pub struct JellyfishMerkleIterator<'a, K, V, R: 'a + TreeReader<K, V>> {
     /// additional bit.
     done: bool,
 
-    key: PhantomData<K>,
-    value: PhantomData<V>,
+    /// The order in which the leaves are visited.
+    direction: Direction,
+
+    /// The last key hash the traversal may yield. Once it reaches a leaf past this bound in
+    /// `direction` the traversal is over.
+    end: Bound<HashValue>,
+
+    /// The children of the internal nodes on the stack that were read ahead of their visit, by
+    /// their node keys.
+    prefetched_nodes: HashMap<NodeKey, Node<K, V>>,
+
+    /// The number of children of an internal node read ahead when it is pushed, whether leaves or
+    /// internal nodes. With 0, only its leaf children are read ahead.
+    lookahead: usize,
+
+    /// The number of internal nodes on the path from the root to the last leaf returned by
+    /// `next_leaf`.
+    leaf_depth: usize,
+
+    /// The number of internal nodes a descent may go through, beyond which the tree is known to
+    /// be corrupt.
+    max_depth: usize,
+
+    /// The predicate telling which internal nodes are skipped along with their subtrees.
+    prune: Option<Prune>,
+
+    hasher: PhantomData<H>,
 }
 
-impl<'a, K, V, R> JellyfishMerkleIterator<'a, K, V, R>
+/// Returns a parent stack holding `infos`, with room for as many internal nodes as a path has, so
+/// it is never reallocated during the traversal.
+fn new_parent_stack(infos: impl Iterator<Item = NodeVisitInfo>) -> Vec<NodeVisitInfo> {
+    let mut parent_stack = Vec::with_capacity(ROOT_NIBBLE_HEIGHT);
+    parent_stack.extend(infos);
+    parent_stack
+}
+
+// Implemented by hand so that the hasher does not need to be `Clone`.
+impl<K: Clone, V: Clone, H> Clone for Traversal<K, V, H> {
+    fn clone(&self) -> Self {
+        Self {
+            parent_stack: new_parent_stack(self.parent_stack.iter().cloned()),
+            done: self.done,
+            direction: self.direction,
+            end: self.end,
+            prefetched_nodes: self.prefetched_nodes.clone(),
+            lookahead: self.lookahead,
+            leaf_depth: self.leaf_depth,
+            max_depth: self.max_depth,
+            prune: self.prune.clone(),
+            hasher: PhantomData,
+        }
+    }
+}
+
+impl<K, V, H> Traversal<K, V, H>
 where
-    R: 'a + TreeReader<K, V>,
     K: Key,
     V: Value,
+    H: TreeHasher,
 {
-    /// Constructs a new iterator. This puts the internal state in the correct position, so the
-    /// following `next` call will yield the smallest key that is greater or equal to
-    /// `starting_key`.
-    pub fn new(
-        reader: &'a R,
+    /// Constructs a new traversal. This puts the internal state in the correct position, so the
+    /// following `next_leaf` call will yield the first key within `start` in `direction`. With
+    /// `Bound::Included(key_hash)` this is the smallest key that is greater or equal to `key_hash`
+    /// when ascending, or the largest key that is less or equal to `key_hash` when descending.
+    fn new<R>(
+        reader: &R,
         state_root_hash: HashValue,
-        starting_key: Option<SMTObject<K>>,
-    ) -> Result<Self> {
-        let mut parent_stack = vec![];
-        let mut done = false;
+        start: Bound<HashValue>,
+        direction: Direction,
+    ) -> Result<Self>
+    where
+        R: TreeReader<K, V>,
+    {
+        let mut traversal = Self::with_direction(direction);
+        traversal.seek(reader, state_root_hash, start)?;
+        Ok(traversal)
+    }
 
+    /// Constructs a traversal in `direction` which is not in position yet. A seek has to put it in
+    /// position before the first `next_leaf` call.
+    fn with_direction(direction: Direction) -> Self {
+        Self {
+            parent_stack: new_parent_stack(std::iter::empty()),
+            done: false,
+            direction,
+            end: Bound::Unbounded,
+            prefetched_nodes: HashMap::new(),
+            lookahead: 0,
+            leaf_depth: 0,
+            max_depth: ROOT_NIBBLE_HEIGHT,
+            prune: None,
+            hasher: PhantomData,
+        }
+    }
+
+    /// Moves the traversal to the position `new` would have put it in for `start`, keeping the
+    /// direction and the end bound. The allocation of `parent_stack` is reused.
+    fn seek<R>(
+        &mut self,
+        reader: &R,
+        state_root_hash: HashValue,
+        start: Bound<HashValue>,
+    ) -> Result<()>
+    where
+        R: TreeReader<K, V>,
+    {
+        let (key_hash, exclusive) = self.reset(start);
         let mut current_node_key = state_root_hash;
-        let starting_key_hash = starting_key
-            .map(|k| k.merkle_hash())
-            .unwrap_or(HashValue::zero());
-        let nibble_path = NibblePath::new(starting_key_hash.to_vec());
-        let mut nibble_iter = nibble_path.nibbles();
-
-        while let Node::Internal(internal_node) = reader.get_node(&current_node_key)? {
-            let child_index = nibble_iter.next().expect("Should have enough nibbles.");
-            match internal_node.child(child_index) {
-                Some(child) => {
-                    // If this child exists, we just push the node onto stack and repeat.
-                    parent_stack.push(NodeVisitInfo::new_next_child_to_visit(
-                        current_node_key,
-                        internal_node.clone(),
-                        child_index,
-                    ));
-                    current_node_key = child.hash;
-                    // current_node_key.gen_child_node_key(child.version, child_index);
+        let mut current_node = checked_node::<_, _, H>(
+            &state_root_hash,
+            get_root_node::<_, _, _, H>(reader, &state_root_hash)?,
+        )?;
+        while let Some(child_node_key) =
+            self.seek_step(current_node_key, current_node, key_hash, exclusive)?
+        {
+            current_node_key = child_node_key;
+            current_node =
+                checked_node::<_, _, H>(&current_node_key, reader.get_node(&current_node_key)?)?;
+        }
+        Ok(())
+    }
+
+    /// Clears the state of the traversal before a seek to `start`. Returns the key hash to seek
+    /// and whether a leaf with exactly this key hash is to be skipped.
+    fn reset(&mut self, start: Bound<HashValue>) -> (HashValue, bool) {
+        self.parent_stack.clear();
+        self.prefetched_nodes.clear();
+        self.done = false;
+
+        match start {
+            Bound::Included(key_hash) => (key_hash, false),
+            Bound::Excluded(key_hash) => (key_hash, true),
+            Bound::Unbounded => (self.direction.first_key_hash(), false),
+        }
+    }
+
+    /// Handles the node read at one level of the descent of a seek to `key_hash`. Returns the key
+    /// of the child to read next, or `None` once the traversal is in position. Fails if the
+    /// descent runs out of the nibbles of `key_hash`, which only happens in a corrupt tree.
+    fn seek_step(
+        &mut self,
+        node_key: NodeKey,
+        node: Node<K, V>,
+        key_hash: HashValue,
+        exclusive: bool,
+    ) -> Result<Option<NodeKey>> {
+        Ok(match node {
+            Node::Internal(internal_node) if self.is_pruned(&node_key, &internal_node) => {
+                // The whole subtree is skipped, as if all its keys were before `key_hash`.
+                self.cleanup_stack()?;
+                if self.parent_stack.is_empty() {
+                    self.done = true;
                 }
-                None => {
-                    let (bitmap, _) = internal_node.generate_bitmaps();
-                    if u32::from(u8::from(child_index)) < 15 - bitmap.leading_zeros() {
-                        // If this child does not exist and there's another child on the right, we
-                        // set the child on the right to be the next one to visit.
-                        parent_stack.push(NodeVisitInfo::new_next_child_to_visit(
-                            current_node_key,
-                            internal_node,
-                            child_index,
-                        ));
-                    } else {
-                        // Otherwise we have done visiting this node. Go backward and clean up the
-                        // stack.
-                        Self::cleanup_stack(&mut parent_stack);
+                None
+            }
+            Node::Internal(internal_node) => {
+                // Every internal node above this one is on the stack, so its length is the depth.
+                let depth = self.parent_stack.len();
+                ensure!(
+                    depth < self.max_depth,
+                    SmtError::CorruptNode(format!(
+                        "Ran out of nibbles of key hash {:x} at internal node {:x}: the tree is \
+                         deeper than the {} nibbles allowed.",
+                        key_hash, node_key, self.max_depth
+                    ))
+                );
+                let child_index = Nibble::from(key_hash.nibble(depth));
+                match internal_node.child(child_index) {
+                    Some(child) => {
+                        // If this child exists, we just push the node onto stack and repeat.
+                        let child_node_key = child.hash;
+                        self.parent_stack
+                            .push(NodeVisitInfo::new_next_child_to_visit(
+                                node_key,
+                                internal_node,
+                                child_index,
+                                self.direction,
+                            )?);
+                        Some(child_node_key)
+                    }
+                    None => {
+                        let (bitmap, _) = internal_node.generate_bitmaps();
+                        let index = u32::from(u8::from(child_index));
+                        let has_next_child = match self.direction {
+                            Direction::Ascending => index < 15 - bitmap.leading_zeros(),
+                            Direction::Descending => index > bitmap.trailing_zeros(),
+                        };
+                        if has_next_child {
+                            // If this child does not exist and there's another child after it, we
+                            // set that child to be the next one to visit.
+                            self.parent_stack
+                                .push(NodeVisitInfo::new_next_child_to_visit(
+                                    node_key,
+                                    internal_node,
+                                    child_index,
+                                    self.direction,
+                                )?);
+                        } else {
+                            // Otherwise we have done visiting this node. Go backward and clean up
+                            // the stack.
+                            self.cleanup_stack()?;
+                        }
+                        None
                     }
-                    return Ok(Self {
-                        reader,
-                        state_root_hash,
-                        parent_stack,
-                        done,
-                        key: PhantomData,
-                        value: PhantomData,
-                    });
                 }
             }
-        }
-
-        match reader.get_node(&current_node_key)? {
-            Node::Internal(_) => unreachable!("Should have reached the bottom of the tree."),
             Node::Leaf(leaf_node) => {
-                if leaf_node.key().merkle_hash() < starting_key_hash {
-                    Self::cleanup_stack(&mut parent_stack);
-                    if parent_stack.is_empty() {
-                        done = true;
+                let leaf_key_hash = leaf_node.key_hash_with::<H>();
+                if self.direction.is_before(leaf_key_hash, key_hash)
+                    || (exclusive && leaf_key_hash == key_hash)
+                {
+                    self.cleanup_stack()?;
+                    if self.parent_stack.is_empty() {
+                        self.done = true;
                     }
                 }
+                None
+            }
+            Node::Null => {
+                self.done = true;
+                None
             }
-            Node::Null => done = true,
-        }
-
-        Ok(Self {
-            reader,
-            state_root_hash,
-            parent_stack,
-            done,
-            key: PhantomData,
-            value: PhantomData,
         })
     }
 
-    fn cleanup_stack(parent_stack: &mut Vec<NodeVisitInfo>) {
-        while let Some(info) = parent_stack.last_mut() {
-            if info.is_rightmost() {
-                parent_stack.pop();
+    /// Pops the nodes whose children have all been visited off the stack, and advances the node
+    /// left on top to its next child. Fails if the stack holds a corrupt node.
+    fn cleanup_stack(&mut self) -> Result<()> {
+        while let Some(info) = self.parent_stack.last_mut() {
+            if info.is_last(self.direction) {
+                self.parent_stack.pop();
             } else {
-                info.advance();
-                break;
-            }
-        }
-    }
-
-    #[cfg(test)]
-    pub fn print(&self) -> Result<()> {
-        let nodes = &self.parent_stack;
-        for node in nodes {
-            println!("internal node key: {:?}", node.node_key.to_hex());
-            if let Ok(Node::Internal(internal)) = self.reader.get_node(&node.node_key) {
-                println!("child: {:?}", internal.all_child());
+                return info.advance(self.direction);
             }
         }
         Ok(())
     }
-}
 
-impl<'a, K, V, R> Iterator for JellyfishMerkleIterator<'a, K, V, R>
-where
-    R: 'a + TreeReader<K, V>,
-    K: Key,
-    V: Value,
-{
-    type Item = Result<(SMTObject<K>, SMTObject<V>)>;
-
-    fn next(&mut self) -> Option<Self::Item> {
+    /// Returns the next leaf of the traversal and moves the internal state past it. Once this
+    /// returns `None` or an error, the traversal is done and keeps returning `None`, except for
+    /// [`SmtError::NodeNotHeld`], after which the next call reads the missing node again.
+    fn next_leaf<R>(
+        &mut self,
+        reader: &R,
+        state_root_hash: HashValue,
+    ) -> Option<Result<LeafNode<K, V>>>
+    where
+        R: TreeReader<K, V>,
+    {
         if self.done {
             return None;
         }
 
         if self.parent_stack.is_empty() {
-            let root_node_key = self.state_root_hash;
-            match self.reader.get_node(&root_node_key) {
-                Ok(Node::Leaf(leaf_node)) => {
-                    // This means the entire tree has a single leaf node. The key of this leaf node
-                    // is greater or equal to `starting_key` (otherwise we would have set `done` to
-                    // true in `new`). Return the node and mark `self.done` so next time we return
-                    // None.
+            return self.visit_root(
+                state_root_hash,
+                get_root_node::<_, _, _, H>(reader, &state_root_hash)
+                    .and_then(|node| checked_node::<_, _, H>(&state_root_hash, node)),
+            );
+        }
+
+        loop {
+            let node_key = self.next_child_key()?;
+            let depth = self.parent_stack.len();
+            let node = match self.prefetched_nodes.remove(&node_key) {
+                Some(node) => Ok(node),
+                None => reader.get_node(&node_key),
+            }
+            .and_then(|node| checked_node::<_, _, H>(&node_key, node));
+            if let ControlFlow::Break(leaf_node) = self.visit_child(node_key, node) {
+                return leaf_node;
+            }
+            // Nothing was pushed if the node was pruned.
+            if self.parent_stack.len() <= depth {
+                continue;
+            }
+            if let Err(err) = self.prefetch_children(reader) {
+                // A child missing from a partial tree is reported when it is visited.
+                if !is_node_not_held(&err) {
                     self.done = true;
-                    return Some(Ok((leaf_node.key().clone(), leaf_node.value().clone())));
-                }
-                Ok(Node::Internal(_)) => {
-                    // This means `starting_key` is bigger than every key in this tree, or we have
-                    // iterated past the last key.
-                    return None;
+                    return Some(Err(err));
                 }
-                Ok(Node::Null) => unreachable!("We would have set done to true in new."),
-                Err(err) => return Some(Err(err)),
             }
         }
+    }
 
-        loop {
-            let last_visited_node_info = self
-                .parent_stack
-                .last()
-                .expect("We have checked that self.parent_stack is not empty.");
-            let child_index =
-                Nibble::from(last_visited_node_info.next_child_to_visit.trailing_zeros() as u8);
-            let node_key = last_visited_node_info
-                .node
-                .child(child_index)
-                .expect("Child should exist.")
-                .hash;
-
-            match self.reader.get_node(&node_key) {
-                Ok(Node::Internal(internal_node)) => {
-                    let visit_info = NodeVisitInfo::new(node_key, internal_node);
-                    self.parent_stack.push(visit_info);
-                }
-                Ok(Node::Leaf(leaf_node)) => {
-                    let ret = (leaf_node.key().clone(), leaf_node.value().clone());
-                    Self::cleanup_stack(&mut self.parent_stack);
-                    return Some(Ok(ret));
-                }
-                Ok(Node::Null) => return Some(Err(format_err!("Should not reach a null node."))),
-                Err(err) => return Some(Err(err)),
+    /// Handles the root node read by `next_leaf` when the stack is empty.
+    fn visit_root(
+        &mut self,
+        root_key: NodeKey,
+        root: Result<Node<K, V>>,
+    ) -> Option<Result<LeafNode<K, V>>> {
+        self.done = true;
+        match root {
+            Ok(Node::Leaf(leaf_node)) => {
+                self.leaf_depth = 0;
+                // This means the entire tree has a single leaf node. The key of this leaf node is
+                // not before `starting_key` (otherwise we would have set `done` to true in `new`).
+                // Return the node, `self.done` is set so next time we return None.
+                self.check_end(leaf_node)
+            }
+            Ok(Node::Internal(_)) => {
+                // This means `starting_key` is after every key in this tree, or we have iterated
+                // past the last key.
+                None
+            }
+            // The root was not null when the traversal was put in position, so the node store was
+            // changed or corrupted since.
+            Ok(Node::Null) => Some(Err(SmtError::UnexpectedNull(root_key).into())),
+            Err(err) => {
+                // The root is read again on the next call.
+                self.done = !is_node_not_held(&err);
+                Some(Err(err))
             }
         }
     }
-}
 
-/// The `JellyfishMerkleIntoIterator` implementation.
-pub struct JellyfishMerkleIntoIterator<K, V, R: TreeReader<K, V>> {
-    /// The storage engine from which we can read nodes using node keys.
-    reader: R,
+    /// Returns the key of the next child to read in `next_leaf`, or `None` if the whole subtree of
+    /// that child is past `self.end`, in which case the traversal is done.
+    fn next_child_key(&mut self) -> Option<NodeKey> {
+        let last_visited_node_info = self
+            .parent_stack
+            .last()
+            .expect("We have checked that self.parent_stack is not empty.");
+        let child_index =
+            Nibble::from(last_visited_node_info.next_child_to_visit.trailing_zeros() as u8);
+        let node_key = last_visited_node_info
+            .node
+            .child(child_index)
+            .expect("Child should exist.")
+            .hash;
 
-    /// The root hash of the tree this iterator is running on.
-    state_root_hash: HashValue,
+        // Stop before reading the child if its whole subtree is past `self.end`.
+        if self.end != Bound::Unbounded
+            && !self
+                .direction
+                .is_within(self.subtree_first_key_hash(None), &self.end)
+        {
+            self.done = true;
+            return None;
+        }
+        Some(node_key)
+    }
 
-    /// The stack used for depth first traversal.
-    parent_stack: Vec<NodeVisitInfo>,
+    /// Whether the subtree of the internal node `node` with `node_key` is skipped by `self.prune`.
+    /// It is asked before any child of the node is read.
+    fn is_pruned(&self, node_key: &NodeKey, node: &InternalNode) -> bool {
+        self.prune
+            .as_ref()
+            .is_some_and(|prune| prune(node_key, node))
+    }
 
-    /// Whether the iteration has finished. Usually this can be determined by checking whether
-    /// `self.parent_stack` is empty. But in case of a tree with a single leaf, we need this
-    /// additional bit.
-    done: bool,
+    /// Handles the child node read by `next_leaf`. An internal node is pushed onto the stack and
+    /// the descent continues, unless it is pruned, anything else ends this `next_leaf` call with
+    /// the returned item.
+    fn visit_child(
+        &mut self,
+        node_key: NodeKey,
+        node: Result<Node<K, V>>,
+    ) -> ControlFlow<Option<Result<LeafNode<K, V>>>> {
+        match node {
+            // An internal node is at most at the depth of the last nibble of a key hash.
+            Ok(Node::Internal(_)) if self.parent_stack.len() >= self.max_depth => {
+                self.done = true;
+                ControlFlow::Break(Some(Err(SmtError::CorruptNode(format!(
+                    "Should have reached the bottom of the tree at internal node {:x}, below the \
+                     maximum depth of {} nibbles.",
+                    node_key, self.max_depth
+                ))
+                .into())))
+            }
+            Ok(Node::Internal(internal_node)) if self.is_pruned(&node_key, &internal_node) => {
+                // The whole subtree is skipped, the traversal goes on with the next sibling.
+                if let Err(err) = self.cleanup_stack() {
+                    return self.fail(err);
+                }
+                if self.parent_stack.is_empty() {
+                    self.done = true;
+                    return ControlFlow::Break(None);
+                }
+                ControlFlow::Continue(())
+            }
+            Ok(Node::Internal(internal_node)) => {
+                match NodeVisitInfo::new(node_key, internal_node, self.direction) {
+                    Ok(info) => {
+                        self.parent_stack.push(info);
+                        ControlFlow::Continue(())
+                    }
+                    Err(err) => self.fail(err),
+                }
+            }
+            Ok(Node::Leaf(leaf_node)) => {
+                self.leaf_depth = self.parent_stack.len();
+                if let Err(err) = self.cleanup_stack() {
+                    return self.fail(err);
+                }
+                ControlFlow::Break(self.check_end(leaf_node))
+            }
+            Ok(Node::Null) => self.fail(SmtError::UnexpectedNull(node_key).into()),
+            // Nothing was changed since the child key was taken, so the next call reads it again.
+            Err(err) if is_node_not_held(&err) => ControlFlow::Break(Some(Err(err))),
+            Err(err) => self.fail(err),
+        }
+    }
 
-    key: PhantomData<K>,
-    value: PhantomData<V>,
+    /// Ends the traversal with `err`, as the result of `visit_child`.
+    fn fail(&mut self, err: anyhow::Error) -> ControlFlow<Option<Result<LeafNode<K, V>>>> {
+        self.done = true;
+        ControlFlow::Break(Some(Err(err)))
+    }
 }
 
-impl<K, V, R> JellyfishMerkleIntoIterator<K, V, R>
+impl<K, V, H> Traversal<K, V, H>
 where
-    R: TreeReader<K, V>,
     K: Key,
     V: Value,
+    H: TreeHasher,
 {
-    /// Constructs a new iterator. This puts the internal state in the correct position, so the
-    /// following `next` call will yield the smallest key that is greater or equal to
-    /// `starting_key`.
-    pub fn new(reader: R, state_root_hash: HashValue, starting_key: HashValue) -> Result<Self> {
-        let mut parent_stack = vec![];
-        let mut done = false;
-
-        let mut current_node_key = state_root_hash;
-        let nibble_path = NibblePath::new(starting_key.to_vec());
-        let mut nibble_iter = nibble_path.nibbles();
-
-        while let Node::Internal(internal_node) = reader.get_node(&current_node_key)? {
-            let child_index = nibble_iter.next().expect("Should have enough nibbles.");
-            match internal_node.child(child_index) {
-                Some(child) => {
-                    // If this child exists, we just push the node onto stack and repeat.
-                    parent_stack.push(NodeVisitInfo::new_next_child_to_visit(
-                        current_node_key,
-                        internal_node.clone(),
-                        child_index,
-                    ));
-                    current_node_key = child.hash;
-                    // current_node_key.gen_child_node_key(child.version, child_index);
-                }
-                None => {
-                    let (bitmap, _) = internal_node.generate_bitmaps();
-                    if u32::from(u8::from(child_index)) < 15 - bitmap.leading_zeros() {
-                        // If this child does not exist and there's another child on the right, we
-                        // set the child on the right to be the next one to visit.
-                        parent_stack.push(NodeVisitInfo::new_next_child_to_visit(
-                            current_node_key,
-                            internal_node,
-                            child_index,
-                        ));
-                    } else {
-                        // Otherwise we have done visiting this node. Go backward and clean up the
-                        // stack.
-                        Self::cleanup_stack(&mut parent_stack);
+    /// Reads the children of the node on top of the stack that are not visited yet with a single
+    /// `get_nodes` call, so visiting them does not need a read each: its leaf children, or with a
+    /// `lookahead` its next `lookahead` children in `self.direction`, including the internal ones,
+    /// whose own children are read ahead in turn when they are pushed. The children whose whole
+    /// subtree is past `self.end` are not read, as the traversal stops before visiting them.
+    fn prefetch_children<R>(&mut self, reader: &R) -> Result<()>
+    where
+        R: TreeReader<K, V>,
+    {
+        let visit_info = self
+            .parent_stack
+            .last()
+            .expect("An internal node was just pushed.");
+        let (children_bitmap, leaf_bitmap) = visit_info.node.generate_bitmaps();
+        let (bitmap, lookahead) = match self.lookahead {
+            0 => (leaf_bitmap, usize::MAX),
+            lookahead => (children_bitmap, lookahead),
+        };
+        let next_child_index = visit_info.next_child_to_visit.trailing_zeros();
+        let mut indices = (0..16u32)
+            .filter(|index| {
+                bitmap & (1 << index) != 0
+                    && match self.direction {
+                        Direction::Ascending => *index >= next_child_index,
+                        Direction::Descending => *index <= next_child_index,
                     }
-                    return Ok(Self {
-                        reader,
-                        state_root_hash,
-                        parent_stack,
-                        done,
-                        key: PhantomData,
-                        value: PhantomData,
-                    });
-                }
+            })
+            .collect::<Vec<_>>();
+        if self.direction == Direction::Descending {
+            indices.reverse();
+        }
+        if self.end != Bound::Unbounded {
+            let within = indices
+                .iter()
+                .take_while(|index| {
+                    self.direction
+                        .is_within(self.subtree_first_key_hash(Some(**index)), &self.end)
+                })
+                .count();
+            indices.truncate(within);
+        }
+        let node_keys = indices
+            .into_iter()
+            .take(lookahead)
+            .map(|index| {
+                visit_info
+                    .node
+                    .child(Nibble::from(index as u8))
+                    .expect("Child should exist.")
+                    .hash
+            })
+            .collect::<Vec<_>>();
+        // Reading a single leaf ahead saves nothing.
+        if node_keys.len() < 2 {
+            return Ok(());
+        }
+        for (node_key, node) in node_keys.iter().zip(reader.get_nodes(&node_keys)?) {
+            // A missing node is reported when it is visited.
+            if let Some(node) = node {
+                self.prefetched_nodes.insert(*node_key, node);
             }
         }
+        Ok(())
+    }
 
-        match reader.get_node(&current_node_key)? {
-            Node::Internal(_) => unreachable!("Should have reached the bottom of the tree."),
-            Node::Leaf(leaf_node) => {
-                if leaf_node.key().merkle_hash() < starting_key {
-                    Self::cleanup_stack(&mut parent_stack);
-                    if parent_stack.is_empty() {
-                        done = true;
-                    }
+    /// Returns the first key hash in `self.direction` that the subtree of the next child to visit
+    /// may hold, or of the child at `child_index` of the node on top of the stack if given. The
+    /// nibbles on the path to that child are the prefix of all its keys.
+    fn subtree_first_key_hash(&self, child_index: Option<u32>) -> HashValue {
+        let filler = match self.direction {
+            Direction::Ascending => 0x00,
+            Direction::Descending => 0xff,
+        };
+        let mut bytes = [filler; HashValue::LENGTH];
+        let top = self.parent_stack.len().saturating_sub(1);
+        for (i, info) in self.parent_stack.iter().enumerate() {
+            let nibble = match child_index {
+                Some(child_index) if i == top => child_index as u8,
+                _ => info.next_child_to_visit.trailing_zeros() as u8,
+            };
+            bytes[i / 2] = if i % 2 == 0 {
+                (nibble << 4) | (bytes[i / 2] & 0x0f)
+            } else {
+                (bytes[i / 2] & 0xf0) | nibble
+            };
+        }
+        HashValue::new(bytes)
+    }
+
+    /// Returns the bounds on the number of leaves left to visit. The lower bound is the sum of the
+    /// leaf counts of the children not visited yet of the internal nodes on the stack, or 0 if
+    /// one of these counts is unknown, e.g. for nodes stored without them, or if `self.end` may
+    /// stop the traversal early. The counts are not covered by the hashes of the nodes, so they
+    /// only give a hint and never an upper bound.
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        if self.done {
+            return (0, Some(0));
+        }
+        let top = match self.parent_stack.len().checked_sub(1) {
+            Some(top) => top,
+            // The root is a leaf not visited yet, or the whole tree was visited.
+            None => return (0, Some(1)),
+        };
+        let mut remaining = Some(0u64);
+        for (i, info) in self.parent_stack.iter().enumerate() {
+            let next_child_index = info.next_child_to_visit.trailing_zeros() as u8;
+            // The next child of the nodes below the top one is the subtree being visited, which is
+            // counted by the nodes above it.
+            let include_next_child = i == top;
+            for index in 0..16u8 {
+                let not_visited = match self.direction {
+                    Direction::Ascending => index > next_child_index,
+                    Direction::Descending => index < next_child_index,
+                } || (include_next_child && index == next_child_index);
+                if !not_visited {
+                    continue;
+                }
+                if let Some(child) = info.node.child(Nibble::from(index)) {
+                    remaining = remaining
+                        .zip(child.leaf_count())
+                        .and_then(|(a, b)| a.checked_add(b));
                 }
             }
-            Node::Null => done = true,
         }
+        match remaining.and_then(|remaining| usize::try_from(remaining).ok()) {
+            Some(remaining) if self.end == Bound::Unbounded => (remaining, None),
+            _ => (0, None),
+        }
+    }
 
-        Ok(Self {
-            reader,
-            state_root_hash,
-            parent_stack,
-            done,
-            key: PhantomData,
-            value: PhantomData,
-        })
+    /// Returns `leaf_node` if it is within `self.end`. Otherwise marks the traversal as done.
+    fn check_end(&mut self, leaf_node: LeafNode<K, V>) -> Option<Result<LeafNode<K, V>>> {
+        if self
+            .direction
+            .is_within(leaf_node.key_hash_with::<H>(), &self.end)
+        {
+            Some(Ok(leaf_node))
+        } else {
+            self.done = true;
+            None
+        }
     }
+}
 
-    fn cleanup_stack(parent_stack: &mut Vec<NodeVisitInfo>) {
-        while let Some(info) = parent_stack.last_mut() {
-            if info.is_rightmost() {
-                parent_stack.pop();
-            } else {
-                info.advance();
-                break;
+/// Returns the number of leaves in the tree at `state_root_hash`, by a depth first traversal of the
+/// tree. Only the internal nodes are read: the leaf children of an internal node are counted from
+/// its bitmap, so no key or value is read, let alone cloned. As with the iterator, a null node
+/// other than the empty root fails with `SmtError::UnexpectedNull`.
+pub fn count_leaves<K, V, R, H>(reader: &R, state_root_hash: HashValue) -> Result<u64>
+where
+    R: TreeReader<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    if H::is_empty_root(state_root_hash) {
+        return Ok(0);
+    }
+    let mut count = 0;
+    let mut node_keys = vec![(state_root_hash, 0)];
+    while let Some((node_key, depth)) = node_keys.pop() {
+        match reader.get_node(&node_key)? {
+            Node::Internal(internal_node) => {
+                ensure!(
+                    depth < ROOT_NIBBLE_HEIGHT,
+                    SmtError::CorruptNode(format!(
+                        "Should have reached the bottom of the tree at internal node {:x}.",
+                        node_key
+                    ))
+                );
+                let (existence_bitmap, leaf_bitmap) = internal_node.generate_bitmaps();
+                count += u64::from(leaf_bitmap.count_ones());
+                let internal_bitmap = existence_bitmap & !leaf_bitmap;
+                node_keys.extend(
+                    (0..16u8)
+                        .filter(|index| internal_bitmap & (1 << index) != 0)
+                        .map(|index| {
+                            let child_key = internal_node
+                                .child(Nibble::from(index))
+                                .expect("Child should exist.")
+                                .hash;
+                            (child_key, depth + 1)
+                        }),
+                );
             }
+            Node::Leaf(_) => count += 1,
+            Node::Null => bail!(SmtError::UnexpectedNull(node_key)),
         }
     }
+    Ok(count)
+}
 
-    #[cfg(test)]
-    pub fn print(&self) -> Result<()> {
-        let nodes = &self.parent_stack;
-        for node in nodes {
-            println!("internal node key: {:?}", node.node_key.to_hex());
-            if let Ok(Node::Internal(internal)) = self.reader.get_node(&node.node_key) {
-                println!("child: {:?}", internal.all_child());
+/// Returns the number of leaves of the tree at `state_root_hash` at each depth, i.e. each number of
+/// internal nodes on the path from the root, the depth yielded by
+/// [`with_depth`](JellyfishMerkleIterator::with_depth). Depths without leaves are left out, and an
+/// empty tree has an empty histogram. As in `count_leaves`, only the internal nodes are read, and a
+/// null node other than the empty root fails with `SmtError::UnexpectedNull`.
+pub fn depth_histogram<K, V, R, H>(
+    reader: &R,
+    state_root_hash: HashValue,
+) -> Result<BTreeMap<usize, u64>>
+where
+    R: TreeReader<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    let mut histogram = BTreeMap::new();
+    if H::is_empty_root(state_root_hash) {
+        return Ok(histogram);
+    }
+    let mut node_keys = vec![(state_root_hash, 0)];
+    while let Some((node_key, depth)) = node_keys.pop() {
+        match reader.get_node(&node_key)? {
+            Node::Internal(internal_node) => {
+                ensure!(
+                    depth < ROOT_NIBBLE_HEIGHT,
+                    SmtError::CorruptNode(format!(
+                        "Should have reached the bottom of the tree at internal node {:x}.",
+                        node_key
+                    ))
+                );
+                let (existence_bitmap, leaf_bitmap) = internal_node.generate_bitmaps();
+                if leaf_bitmap != 0 {
+                    *histogram.entry(depth + 1).or_insert(0) += u64::from(leaf_bitmap.count_ones());
+                }
+                let internal_bitmap = existence_bitmap & !leaf_bitmap;
+                node_keys.extend(
+                    (0..16u8)
+                        .filter(|index| internal_bitmap & (1 << index) != 0)
+                        .map(|index| {
+                            let child_key = internal_node
+                                .child(Nibble::from(index))
+                                .expect("Child should exist.")
+                                .hash;
+                            (child_key, depth + 1)
+                        }),
+                );
             }
+            Node::Leaf(_) => *histogram.entry(depth).or_insert(0) += 1,
+            Node::Null => bail!(SmtError::UnexpectedNull(node_key)),
         }
-        Ok(())
     }
+    Ok(histogram)
 }
 
-impl<K, V, R> Iterator for JellyfishMerkleIntoIterator<K, V, R>
+/// Returns the key with the smallest key hash in the tree at `state_root_hash`, or `None` if the
+/// tree is empty. Only the nodes on the path to its leaf are read.
+pub fn first_key<K, V, R, H>(reader: &R, state_root_hash: HashValue) -> Result<Option<SMTObject<K>>>
 where
     R: TreeReader<K, V>,
     K: Key,
     V: Value,
+    H: TreeHasher,
 {
-    type Item = Result<(SMTObject<K>, SMTObject<V>)>;
+    edge_key::<K, V, R, H>(reader, state_root_hash, Direction::Ascending)
+}
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.done {
-            return None;
-        }
+/// Returns the key with the largest key hash in the tree at `state_root_hash`, or `None` if the
+/// tree is empty. Only the nodes on the path to its leaf are read.
+pub fn last_key<K, V, R, H>(reader: &R, state_root_hash: HashValue) -> Result<Option<SMTObject<K>>>
+where
+    R: TreeReader<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    edge_key::<K, V, R, H>(reader, state_root_hash, Direction::Descending)
+}
 
-        if self.parent_stack.is_empty() {
-            let root_node_key = self.state_root_hash;
-            match self.reader.get_node(&root_node_key) {
-                Ok(Node::Leaf(leaf_node)) => {
-                    // This means the entire tree has a single leaf node. The key of this leaf node
-                    // is greater or equal to `starting_key` (otherwise we would have set `done` to
-                    // true in `new`). Return the node and mark `self.done` so next time we return
-                    // None.
-                    self.done = true;
-                    return Some(Ok((leaf_node.key().clone(), leaf_node.value().clone())));
-                }
-                Ok(Node::Internal(_)) => {
-                    // This means `starting_key` is bigger than every key in this tree, or we have
-                    // iterated past the last key.
-                    return None;
-                }
-                Ok(Node::Null) => unreachable!("We would have set done to true in new."),
-                Err(err) => return Some(Err(err)),
-            }
-        }
+/// The keys returned by `neighbors`, the one below a key hash and the one above it.
+type Neighbors<K> = (Option<SMTObject<K>>, Option<SMTObject<K>>);
 
-        loop {
-            let last_visited_node_info = self
-                .parent_stack
-                .last()
-                .expect("We have checked that self.parent_stack is not empty.");
-            let child_index =
-                Nibble::from(last_visited_node_info.next_child_to_visit.trailing_zeros() as u8);
-            let node_key = last_visited_node_info
-                .node
-                .child(child_index)
-                .expect("Child should exist.")
-                .hash;
+/// Returns the neighbors of `key` in the tree at `state_root_hash`: the existing key with the
+/// largest key hash not above the key hash of `key`, and the one with the smallest key hash not
+/// below it. Both are `key` if it exists, and either is `None` if no key is on that side. Each
+/// neighbor is found by a descent along the path of `key`, then down the nearest subtree on its
+/// side, so at most two paths of the tree are read for each, whatever the size of the tree.
+pub fn neighbors<K, V, R, H>(
+    reader: &R,
+    state_root_hash: HashValue,
+    key: &SMTObject<K>,
+) -> Result<Neighbors<K>>
+where
+    R: TreeReader<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    let key_hash = key.merkle_hash_with::<H>();
+    Ok((
+        neighbor_key::<K, V, R, H>(reader, state_root_hash, key_hash, Direction::Descending)?,
+        neighbor_key::<K, V, R, H>(reader, state_root_hash, key_hash, Direction::Ascending)?,
+    ))
+}
 
-            match self.reader.get_node(&node_key) {
-                Ok(Node::Internal(internal_node)) => {
-                    let visit_info = NodeVisitInfo::new(node_key, internal_node);
-                    self.parent_stack.push(visit_info);
+/// Helper function for `neighbors`, returning the first key in `direction` whose key hash is not
+/// before `key_hash`. The descent follows the nibbles of `key_hash` and keeps the nearest child
+/// after the path in `direction`. If the path ends before a key not before `key_hash`, the answer
+/// is the first key in `direction` of the subtree of that child.
+fn neighbor_key<K, V, R, H>(
+    reader: &R,
+    state_root_hash: HashValue,
+    key_hash: HashValue,
+    direction: Direction,
+) -> Result<Option<SMTObject<K>>>
+where
+    R: TreeReader<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    let mut nibbles = NibblePath::from_slice(key_hash.as_ref()).nibbles();
+    // The nearest subtree after the path of `key_hash` in `direction` seen so far.
+    let mut next_subtree = None;
+    let mut node = get_root_node::<_, _, _, H>(reader, &state_root_hash)?;
+    // We limit the number of loops here deliberately to avoid potential cyclic graph bugs in the
+    // tree structure.
+    for _ in 0..=ROOT_NIBBLE_HEIGHT {
+        match node {
+            Node::Internal(internal_node) => {
+                let nibble = match nibbles.next() {
+                    Some(nibble) => u8::from(nibble),
+                    None => bail!(SmtError::cyclic()),
+                };
+                let (children_bitmap, _) = internal_node.generate_bitmaps();
+                let children_bitmap = u32::from(children_bitmap);
+                let after = match direction {
+                    Direction::Ascending => children_bitmap & !((2 << nibble) - 1),
+                    Direction::Descending => children_bitmap & ((1 << nibble) - 1),
+                };
+                if after != 0 {
+                    let index = match direction {
+                        Direction::Ascending => after.trailing_zeros(),
+                        Direction::Descending => 31 - after.leading_zeros(),
+                    };
+                    next_subtree = internal_node
+                        .child(Nibble::from(index as u8))
+                        .map(|child| child.hash);
                 }
-                Ok(Node::Leaf(leaf_node)) => {
-                    let ret = (leaf_node.key().clone(), leaf_node.value().clone());
-                    Self::cleanup_stack(&mut self.parent_stack);
-                    return Some(Ok(ret));
+                match internal_node.child(Nibble::from(nibble)) {
+                    Some(child) => node = reader.get_node(&child.hash)?,
+                    None => break,
+                }
+            }
+            Node::Leaf(leaf_node) => {
+                if !direction.is_before(leaf_node.key_hash_with::<H>(), key_hash) {
+                    return Ok(Some(leaf_node.into_key()));
                 }
-                Ok(Node::Null) => return Some(Err(format_err!("Should not reach a null node."))),
-                Err(err) => return Some(Err(err)),
+                break;
             }
+            Node::Null => return Ok(None),
         }
     }
+    match next_subtree {
+        Some(subtree_root) => edge_key::<K, V, R, H>(reader, subtree_root, direction),
+        None => Ok(None),
+    }
+}
+
+/// Helper function for `first_key` and `last_key`, descending to the first leaf in `direction` by
+/// following the first child in `direction` of each internal node.
+fn edge_key<K, V, R, H>(
+    reader: &R,
+    state_root_hash: HashValue,
+    direction: Direction,
+) -> Result<Option<SMTObject<K>>>
+where
+    R: TreeReader<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    let mut node = get_root_node::<_, _, _, H>(reader, &state_root_hash)?;
+    // We limit the number of loops here deliberately to avoid potential cyclic graph bugs in the
+    // tree structure.
+    for _ in 0..=ROOT_NIBBLE_HEIGHT {
+        match node {
+            Node::Internal(internal_node) => {
+                let (children_bitmap, _) = internal_node.generate_bitmaps();
+                ensure!(
+                    children_bitmap != 0,
+                    SmtError::CorruptNode("internal node without children".to_string())
+                );
+                let index = match direction {
+                    Direction::Ascending => children_bitmap.trailing_zeros(),
+                    Direction::Descending => 15 - children_bitmap.leading_zeros(),
+                };
+                let child = internal_node
+                    .child(Nibble::from(index as u8))
+                    .expect("Child should exist.");
+                node = reader.get_node(&child.hash)?;
+            }
+            Node::Leaf(leaf_node) => return Ok(Some(leaf_node.into_key())),
+            Node::Null => return Ok(None),
+        }
+    }
+    bail!(SmtError::cyclic());
+}
+
+/// Returns the key-value pair at `index` in the order of the key hashes in the tree at
+/// `state_root_hash`, i.e. the pair `iter.nth(index)` would yield, or `None` if the tree has no
+/// more than `index` leaves. The leaf counts of the internal nodes are used to skip the subtrees
+/// before the target, so only the nodes on the path to its leaf are read. The subtrees whose leaf
+/// count is unknown are counted with [`count_leaves`].
+pub fn nth_leaf<K, V, R, H>(
+    reader: &R,
+    state_root_hash: HashValue,
+    index: u64,
+) -> Result<Option<(SMTObject<K>, SMTObject<V>)>>
+where
+    R: TreeReader<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    let mut remaining = index;
+    let mut node = get_root_node::<_, _, _, H>(reader, &state_root_hash)?;
+    // We limit the number of loops here deliberately to avoid potential cyclic graph bugs in the
+    // tree structure.
+    for _ in 0..=ROOT_NIBBLE_HEIGHT {
+        match node {
+            Node::Internal(internal_node) => {
+                let mut next_node_key = None;
+                for index in 0..16u8 {
+                    let child = match internal_node.child(Nibble::from(index)) {
+                        Some(child) => child,
+                        None => continue,
+                    };
+                    let leaf_count = match child.leaf_count() {
+                        Some(leaf_count) => leaf_count,
+                        None => count_leaves::<K, V, R, H>(reader, child.hash)?,
+                    };
+                    if remaining < leaf_count {
+                        next_node_key = Some(child.hash);
+                        break;
+                    }
+                    remaining -= leaf_count;
+                }
+                match next_node_key {
+                    Some(node_key) => node = reader.get_node(&node_key)?,
+                    None => return Ok(None),
+                }
+            }
+            Node::Leaf(leaf_node) if remaining == 0 => {
+                return Ok(Some(leaf_node.into_key_value()?))
+            }
+            Node::Leaf(_) | Node::Null => return Ok(None),
+        }
+    }
+    bail!(SmtError::cyclic());
+}
+
+/// Returns the first and the last key hash starting with `prefix`.
+pub(crate) fn prefix_key_hash_range(prefix: &NibblePath) -> (HashValue, HashValue) {
+    let mut first = [0x00; HashValue::LENGTH];
+    let mut last = [0xff; HashValue::LENGTH];
+    let bytes = prefix.bytes();
+    first[..bytes.len()].copy_from_slice(bytes);
+    last[..bytes.len()].copy_from_slice(bytes);
+    if prefix.num_nibbles() % 2 == 1 {
+        // The second half of the last byte is not part of the prefix.
+        last[bytes.len() - 1] |= 0x0f;
+    }
+    (HashValue::new(first), HashValue::new(last))
+}
+
+/// Returns `node`, read at `node_key`, after checking with the `validate` feature that it hashes
+/// to `node_key`. A mismatch means that the store is corrupted or that the `TreeReader` is faulty,
+/// and the traversal would otherwise yield wrong leaves. Hashing every node read is not free, so
+/// the check compiles to nothing without the feature.
+fn checked_node<K, V, H>(node_key: &NodeKey, node: Node<K, V>) -> Result<Node<K, V>>
+where
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    if cfg!(feature = "validate") && !matches!(node, Node::Null) {
+        let node_hash = node.merkle_hash_with::<H>();
+        ensure!(
+            node_hash == *node_key,
+            SmtError::HashMismatch {
+                expected: *node_key,
+                actual: node_hash,
+            }
+        );
+    }
+    Ok(node)
+}
+
+/// Whether `err` is a node missing from a partial tree, after which a traversal can go on once
+/// the node is fetched.
+fn is_node_not_held(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<SmtError>(),
+        Some(SmtError::NodeNotHeld(_))
+    )
+}
+
+/// The position of an [`SMTIterator`](crate::SMTIterator), taken by
+/// [`cursor`](crate::SMTIterator::cursor) and turned back into an iterator by
+/// [`resume`](crate::SMTree::resume). It holds the path of internal nodes from the root
+/// to the next leaf with the next child to visit in each, so resuming does not seek from the
+/// starting key again. The fields are opaque, the cursor is meant to be serialized, e.g. as the
+/// page token of a paginated API.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct IteratorCursor {
+    /// The root hash of the tree the iterator was running on.
+    state_root_hash: HashValue,
+
+    /// The internal nodes on the stack of the traversal, from the root down, each with the next
+    /// child to visit.
+    stack: Vec<(NodeKey, Nibble)>,
+
+    /// Whether the iteration has finished.
+    done: bool,
+
+    /// The order in which the leaves are visited.
+    direction: Direction,
+
+    /// The last key hash the iterator may yield.
+    end: Bound<HashValue>,
+
+    /// The bound of the keys `next_back` may yield.
+    front_bound: Bound<HashValue>,
+}
+
+/// The `JellyfishMerkleIterator` implementation. It also implements `DoubleEndedIterator`: the
+/// `next_back` calls consume the keys from the other end of the tree, and the iteration is over
+/// when both ends meet.
+pub struct JellyfishMerkleIterator<'a, K, V, R: 'a + TreeReader<K, V>, H = Sha3TreeHasher> {
+    /// The storage engine from which we can read nodes using node keys.
+    reader: &'a R,
+
+    /// The root hash of the tree this iterator is running on.
+    state_root_hash: HashValue,
+
+    /// The depth first traversal producing the leaves.
+    traversal: Traversal<K, V, H>,
+
+    /// The depth first traversal producing the leaves for `next_back`, in the opposite direction
+    /// of `traversal`. It is created on the first `next_back` call.
+    back_traversal: Option<Traversal<K, V, H>>,
+
+    /// The bound of the keys `next_back` may yield: the starting key until `next` yields a key,
+    /// then the last key yielded by `next`.
+    front_bound: Bound<HashValue>,
+
+    /// The observer the yielded leaves and their depths are reported to.
+    observer: Option<&'a dyn Observer>,
+
+    /// The reader of the values of the detached leaves.
+    value_reader: Option<&'a dyn ValueReader<V>>,
+
+    /// The pin keeping the nodes of the tree from being pruned while the iterator is alive.
+    pin: Option<RootPin>,
+
+    key: PhantomData<K>,
+    value: PhantomData<V>,
+}
+
+/// Cloning an iterator snapshots its position: the clone yields the same remaining leaves within
+/// the same bounds, without descending from the root again. Only the reader reference is shared,
+/// and the clone holds a pin of its own on the root, if the iterator is pinned.
+impl<'a, K: Clone, V: Clone, R: TreeReader<K, V>, H> Clone
+    for JellyfishMerkleIterator<'a, K, V, R, H>
+{
+    fn clone(&self) -> Self {
+        Self {
+            reader: self.reader,
+            state_root_hash: self.state_root_hash,
+            traversal: self.traversal.clone(),
+            back_traversal: self.back_traversal.clone(),
+            front_bound: self.front_bound,
+            observer: self.observer,
+            value_reader: self.value_reader,
+            pin: self.pin.clone(),
+            key: PhantomData,
+            value: PhantomData,
+        }
+    }
+}
+
+impl<'a, K, V, R, H> JellyfishMerkleIterator<'a, K, V, R, H>
+where
+    R: 'a + Versioned<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    /// Constructs a new iterator. This puts the internal state in the correct position, so the
+    /// following `next` call will yield the smallest key that is greater or equal to
+    /// `starting_key`. The reader has to be [`Versioned`](crate::Versioned), so that the
+    /// tree at `state_root_hash` does not change during the iteration, as for the other
+    /// constructors.
+    pub fn new(
+        reader: &'a R,
+        state_root_hash: HashValue,
+        starting_key: Option<SMTObject<K>>,
+    ) -> Result<Self> {
+        let start = match starting_key {
+            Some(key) => Bound::Included(key.merkle_hash_with::<H>()),
+            None => Bound::Unbounded,
+        };
+        Self::new_with_direction(
+            reader,
+            state_root_hash,
+            start,
+            Bound::Unbounded,
+            Direction::Ascending,
+        )
+    }
+
+    /// Same as `new`, but the following `next` call will yield the smallest key strictly greater
+    /// than `key`, whether `key` is in the tree or not. Starting each page after the last key of
+    /// the previous one pages through the tree without repeating or skipping a key.
+    pub fn new_after(reader: &'a R, state_root_hash: HashValue, key: SMTObject<K>) -> Result<Self> {
+        Self::new_with_direction(
+            reader,
+            state_root_hash,
+            Bound::Excluded(key.merkle_hash_with::<H>()),
+            Bound::Unbounded,
+            Direction::Ascending,
+        )
+    }
+
+    /// Constructs a new iterator which yields the keys in descending order. The following `next`
+    /// call will yield the largest key that is less or equal to `starting_key`, or the largest key
+    /// of the tree if `starting_key` is `None`.
+    pub fn new_rev(
+        reader: &'a R,
+        state_root_hash: HashValue,
+        starting_key: Option<SMTObject<K>>,
+    ) -> Result<Self> {
+        let start = match starting_key {
+            Some(key) => Bound::Included(key.merkle_hash_with::<H>()),
+            None => Bound::Unbounded,
+        };
+        Self::new_with_direction(
+            reader,
+            state_root_hash,
+            start,
+            Bound::Unbounded,
+            Direction::Descending,
+        )
+    }
+
+    /// Constructs a new iterator which only yields the keys between `start` and `end`, in
+    /// ascending order. The iteration stops as soon as the traversal passes `end`, without reading
+    /// the subtrees beyond it.
+    pub fn new_range(
+        reader: &'a R,
+        state_root_hash: HashValue,
+        start: Bound<SMTObject<K>>,
+        end: Bound<SMTObject<K>>,
+    ) -> Result<Self> {
+        Self::new_with_direction(
+            reader,
+            state_root_hash,
+            start.map(|key| key.merkle_hash_with::<H>()),
+            end.map(|key| key.merkle_hash_with::<H>()),
+            Direction::Ascending,
+        )
+    }
+
+    /// Same as `new_range`, but the bounds are key hashes, which need not be the key hash of any
+    /// key.
+    pub fn new_key_hash_range(
+        reader: &'a R,
+        state_root_hash: HashValue,
+        start: Bound<HashValue>,
+        end: Bound<HashValue>,
+    ) -> Result<Self> {
+        Self::new_with_direction(reader, state_root_hash, start, end, Direction::Ascending)
+    }
+
+    /// Constructs a new iterator which only yields the keys whose key hash starts with `prefix`, in
+    /// ascending order. It descends straight to the subtree of `prefix` and stops as soon as it
+    /// leaves it, without reading the sibling subtrees. If there is no such subtree, e.g. when
+    /// `prefix` leads to an empty child or to a leaf with another key, it yields nothing.
+    pub fn new_prefix(
+        reader: &'a R,
+        state_root_hash: HashValue,
+        prefix: NibblePath,
+    ) -> Result<Self> {
+        let (first_key_hash, last_key_hash) = prefix_key_hash_range(&prefix);
+        Self::new_with_direction(
+            reader,
+            state_root_hash,
+            Bound::Included(first_key_hash),
+            Bound::Included(last_key_hash),
+            Direction::Ascending,
+        )
+    }
+
+    /// Constructs a new iterator over the shard `shard` of the tree, the subtree of the root child
+    /// at `shard`, for a tree sharded by the first nibble of the key hashes. It is `new_prefix`
+    /// with the single nibble prefix `shard`, so the iterators of the 16 shards together yield
+    /// every key once, in the same order as `new`.
+    pub fn new_shard(reader: &'a R, state_root_hash: HashValue, shard: Nibble) -> Result<Self> {
+        Self::new_prefix(reader, state_root_hash, std::iter::once(shard).collect())
+    }
+
+    /// Same as `new`, but skips the subtrees of the internal nodes for which `prune` returns true,
+    /// e.g. the subtrees known to be unchanged, whose node keys are in a set. The traversal goes
+    /// on with the next sibling of a pruned node, so the iterator yields exactly the leaves outside
+    /// of the pruned subtrees, in the same order. `prune` is given the node key and the node of an
+    /// internal node once it is read and before any of its children is, so none of the nodes below
+    /// a pruned node is read. A pruned root leaves nothing to yield.
+    pub fn new_filtered(
+        reader: &'a R,
+        state_root_hash: HashValue,
+        starting_key: Option<SMTObject<K>>,
+        prune: impl Fn(&NodeKey, &InternalNode) -> bool + Send + Sync + 'static,
+    ) -> Result<Self> {
+        let start = match starting_key {
+            Some(key) => Bound::Included(key.merkle_hash_with::<H>()),
+            None => Bound::Unbounded,
+        };
+        let mut traversal = Traversal::with_direction(Direction::Ascending);
+        traversal.prune = Some(Arc::new(prune));
+        Self::new_with_traversal(reader, state_root_hash, start, Bound::Unbounded, traversal)
+    }
+
+    /// Same as `new`, but reads `lookahead` children of an internal node ahead of their visit, see
+    /// [`with_lookahead`](Self::with_lookahead).
+    pub fn new_prefetched(
+        reader: &'a R,
+        state_root_hash: HashValue,
+        starting_key: Option<SMTObject<K>>,
+        lookahead: usize,
+    ) -> Result<Self> {
+        Ok(Self::new(reader, state_root_hash, starting_key)?.with_lookahead(lookahead))
+    }
+
+    /// Rebuilds the iterator at the position `cursor` was taken at. The nodes on the path of the
+    /// cursor are read again and checked to form a path from `state_root_hash`, so a cursor taken
+    /// on another root, e.g. a stale page token after the tree has changed, or a tampered one
+    /// fails instead of yielding wrong leaves.
+    ///
+    /// A cursor does not hold the predicate of an iterator built by `new_filtered`, so the
+    /// resumed iterator prunes nothing. Use `resume_filtered` to pass the predicate again.
+    pub fn resume(
+        reader: &'a R,
+        state_root_hash: HashValue,
+        cursor: IteratorCursor,
+    ) -> Result<Self> {
+        ensure!(
+            cursor.state_root_hash == state_root_hash,
+            "The cursor was taken on root {:x}, not on root {:x}.",
+            cursor.state_root_hash,
+            state_root_hash
+        );
+        ensure!(
+            cursor.stack.len() <= ROOT_NIBBLE_HEIGHT,
+            "The cursor is deeper than the tree can be."
+        );
+        let mut traversal = Traversal::with_direction(cursor.direction);
+        traversal.done = cursor.done;
+        traversal.end = cursor.end;
+        let mut expected_node_key = state_root_hash;
+        for (node_key, next_child) in cursor.stack {
+            ensure!(
+                node_key == expected_node_key,
+                "The cursor does not follow the tree at node {:x}.",
+                node_key
+            );
+            let node = checked_node::<_, _, H>(&node_key, reader.get_node(&node_key)?)?;
+            let internal_node = match node {
+                Node::Internal(internal_node) => internal_node,
+                _ => bail!("The cursor expects an internal node at {:x}.", node_key),
+            };
+            expected_node_key = internal_node
+                .child(next_child)
+                .ok_or_else(|| {
+                    format_err!(
+                        "The cursor points to the missing child {:?} of node {:x}.",
+                        next_child,
+                        node_key
+                    )
+                })?
+                .hash;
+            traversal
+                .parent_stack
+                .push(NodeVisitInfo::new_next_child_to_visit(
+                    node_key,
+                    internal_node,
+                    next_child,
+                    cursor.direction,
+                )?);
+        }
+        Ok(Self {
+            reader,
+            state_root_hash,
+            traversal,
+            back_traversal: None,
+            front_bound: cursor.front_bound,
+            observer: None,
+            value_reader: None,
+            pin: None,
+            key: PhantomData,
+            value: PhantomData,
+        })
+    }
+
+    /// Same as `resume`, but the resumed iterator skips the internal nodes for which `prune`
+    /// returns true along with their subtrees, as `new_filtered` does. The nodes on the path of
+    /// the cursor are not checked against `prune`, the iterator which took the cursor having
+    /// already entered them.
+    pub fn resume_filtered(
+        reader: &'a R,
+        state_root_hash: HashValue,
+        cursor: IteratorCursor,
+        prune: impl Fn(&NodeKey, &InternalNode) -> bool + Send + Sync + 'static,
+    ) -> Result<Self> {
+        let mut iter = Self::resume(reader, state_root_hash, cursor)?;
+        iter.traversal.prune = Some(Arc::new(prune));
+        Ok(iter)
+    }
+
+    fn new_with_direction(
+        reader: &'a R,
+        state_root_hash: HashValue,
+        start: Bound<HashValue>,
+        end: Bound<HashValue>,
+        direction: Direction,
+    ) -> Result<Self> {
+        Self::new_with_traversal(
+            reader,
+            state_root_hash,
+            start,
+            end,
+            Traversal::with_direction(direction),
+        )
+    }
+
+    /// Puts `traversal`, not in position yet, in position for `start` and wraps it.
+    fn new_with_traversal(
+        reader: &'a R,
+        state_root_hash: HashValue,
+        start: Bound<HashValue>,
+        end: Bound<HashValue>,
+        mut traversal: Traversal<K, V, H>,
+    ) -> Result<Self> {
+        traversal.seek(reader, state_root_hash, start)?;
+        traversal.end = end;
+        Ok(Self {
+            reader,
+            state_root_hash,
+            traversal,
+            back_traversal: None,
+            front_bound: start,
+            observer: None,
+            value_reader: None,
+            pin: None,
+            key: PhantomData,
+            value: PhantomData,
+        })
+    }
+}
+
+impl<'a, K, V, R, H> JellyfishMerkleIterator<'a, K, V, R, H>
+where
+    R: 'a + TreeReader<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    /// Moves the iterator so the following `next` call will yield the same key as the first `next`
+    /// call of an iterator constructed with `key` as the starting key. The end bound of the
+    /// iterator is kept, and the traversal state is rebuilt in place without a new allocation.
+    pub fn seek(&mut self, key: &SMTObject<K>) -> Result<()> {
+        let start = Bound::Included(key.merkle_hash_with::<H>());
+        self.traversal
+            .seek(self.reader, self.state_root_hash, start)?;
+        self.front_bound = start;
+        if let Some(back_traversal) = self.back_traversal.as_mut() {
+            back_traversal.end = start;
+        }
+        Ok(())
+    }
+
+    /// Returns the position of the iterator, so that [`resume`](Self::resume) can later build an
+    /// iterator yielding the same remaining leaves. The leaves read ahead are not kept, they are
+    /// read again after resuming. A `next_back` call after resuming starts from the back end
+    /// again, but never yields a leaf `next_back` has already yielded.
+    pub fn cursor(&self) -> IteratorCursor {
+        IteratorCursor {
+            state_root_hash: self.state_root_hash,
+            stack: self
+                .traversal
+                .parent_stack
+                .iter()
+                .map(|info| {
+                    (
+                        info.node_key,
+                        Nibble::from(info.next_child_to_visit.trailing_zeros() as u8),
+                    )
+                })
+                .collect(),
+            done: self.traversal.done,
+            direction: self.traversal.direction,
+            end: self.traversal.end,
+            front_bound: self.front_bound,
+        }
+    }
+
+    /// Reports the leaves yielded by the iterator and their depths to `observer`. The node reads
+    /// are reported by the reader, see
+    /// [`ObservedTreeReader`](crate::ObservedTreeReader).
+    pub fn with_observer(mut self, observer: Option<&'a dyn Observer>) -> Self {
+        self.observer = observer;
+        self
+    }
+
+    /// Bounds the descents of the iterator to `max_depth` internal nodes, failing beyond instead
+    /// of reading further. A correct tree is never deeper than the nibbles of a key hash, which is
+    /// the default, so a deeper descent means a corrupt tree, e.g. with an internal node having
+    /// itself as a child. A lower bound detects such a tree earlier, if the depth of the tree is
+    /// known. Fails if `max_depth` is above the default, or if the iterator is already deeper,
+    /// since constructing it descends to its first leaf with the default bound.
+    pub fn with_max_depth(mut self, max_depth: usize) -> Result<Self> {
+        ensure!(
+            max_depth <= ROOT_NIBBLE_HEIGHT,
+            "The maximum depth {} is above the {} nibbles of a key hash.",
+            max_depth,
+            ROOT_NIBBLE_HEIGHT
+        );
+        for traversal in std::iter::once(&mut self.traversal).chain(self.back_traversal.as_mut()) {
+            ensure!(
+                traversal.parent_stack.len() <= max_depth,
+                "The iterator is already deeper than the maximum depth {}.",
+                max_depth
+            );
+            traversal.max_depth = max_depth;
+        }
+        Ok(self)
+    }
+
+    /// Reads up to `lookahead` children of an internal node, the next ones to visit, with a single
+    /// [`get_nodes`](crate::TreeReader::get_nodes) call when the descent reaches it,
+    /// rather than a `get_node` call per child when it visits them. For a reader with a high
+    /// latency per call, e.g. over the network, one batched read replaces up to `lookahead`
+    /// serial ones, one level ahead of the descent. The children are still visited in order, so
+    /// the iterator yields the same leaves in the same order, whatever order the reader fetches
+    /// them in. By default, with 0, only the leaf children are read ahead.
+    pub fn with_lookahead(mut self, lookahead: usize) -> Self {
+        for traversal in std::iter::once(&mut self.traversal).chain(self.back_traversal.as_mut()) {
+            traversal.lookahead = lookahead;
+        }
+        self
+    }
+
+    /// Reads the values of the detached leaves with `value_reader`, when the leaves are yielded.
+    /// The iterator over the keys only, see [`keys`](Self::keys), never reads a value.
+    pub fn with_value_reader(mut self, value_reader: Option<&'a dyn ValueReader<V>>) -> Self {
+        self.value_reader = value_reader;
+        self
+    }
+
+    /// Holds `pin` until the iterator is dropped, so that a pruner consulting its
+    /// [`PinnedRoots`](crate::PinnedRoots) keeps the nodes the iterator reads. The
+    /// pin should be taken on the root of the iterator before creating it, so that the nodes read
+    /// by the seek are kept as well.
+    pub fn with_pin(mut self, pin: RootPin) -> Self {
+        self.pin = Some(pin);
+        self
+    }
+
+    #[cfg(test)]
+    pub fn print(&self) -> Result<()> {
+        let nodes = &self.traversal.parent_stack;
+        for node in nodes {
+            println!("internal node key: {:?}", node.node_key.to_hex());
+            if let Ok(Node::Internal(internal)) = self.reader.get_node(&node.node_key) {
+                println!("child: {:?}", internal.all_child());
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns an iterator which only yields the keys of this iterator. The values are moved out
+    /// of the leaf nodes together with them and dropped, so they are never cloned.
+    pub fn keys(self) -> JellyfishMerkleKeyIterator<'a, K, V, R, H> {
+        JellyfishMerkleKeyIterator { iter: self }
+    }
+
+    /// Returns an iterator which yields the key-value pairs of this iterator together with the
+    /// depth of their leaves, i.e. the number of internal nodes on the path from the root. A tree
+    /// made of a single leaf has it at depth 0.
+    pub fn with_depth(self) -> JellyfishMerkleDepthIterator<'a, K, V, R, H> {
+        JellyfishMerkleDepthIterator { iter: self }
+    }
+
+    fn next_leaf(&mut self) -> Option<Result<LeafNode<K, V>>> {
+        let leaf_node = match self
+            .traversal
+            .next_leaf(self.reader, self.state_root_hash)?
+        {
+            Ok(leaf_node) => leaf_node,
+            Err(err) => return Some(Err(err)),
+        };
+        let key_hash = leaf_node.key_hash_with::<H>();
+        if let Some(observer) = self.observer {
+            observer.on_leaf_yielded(&key_hash);
+            observer.on_descent(self.traversal.leaf_depth);
+        }
+        self.front_bound = Bound::Excluded(key_hash);
+        if let Some(back_traversal) = self.back_traversal.as_mut() {
+            back_traversal.end = self.front_bound;
+        }
+        Some(Ok(leaf_node))
+    }
+
+    fn next_back_leaf(&mut self) -> Option<Result<LeafNode<K, V>>> {
+        if self.back_traversal.is_none() {
+            let direction = self.traversal.direction.reverse();
+            let mut back_traversal = Traversal::with_direction(direction);
+            back_traversal.prune = self.traversal.prune.clone();
+            if let Err(err) =
+                back_traversal.seek(self.reader, self.state_root_hash, self.traversal.end)
+            {
+                return Some(Err(err));
+            }
+            back_traversal.end = self.front_bound;
+            back_traversal.max_depth = self.traversal.max_depth;
+            back_traversal.lookahead = self.traversal.lookahead;
+            self.back_traversal = Some(back_traversal);
+        }
+        let back_traversal = self
+            .back_traversal
+            .as_mut()
+            .expect("We have created the back traversal.");
+        let leaf_node = match back_traversal.next_leaf(self.reader, self.state_root_hash)? {
+            Ok(leaf_node) => leaf_node,
+            Err(err) => return Some(Err(err)),
+        };
+        let key_hash = leaf_node.key_hash_with::<H>();
+        if let Some(observer) = self.observer {
+            observer.on_leaf_yielded(&key_hash);
+            observer.on_descent(back_traversal.leaf_depth);
+        }
+        self.traversal.end = Bound::Excluded(key_hash);
+        Some(Ok(leaf_node))
+    }
+
+    /// Returns the key and the value of `leaf_node`, reading the value with the value reader if
+    /// the leaf is detached.
+    fn key_value(&self, leaf_node: LeafNode<K, V>) -> Result<(SMTObject<K>, SMTObject<V>)> {
+        if leaf_node.is_detached() {
+            let value_reader = self.value_reader.ok_or_else(|| {
+                format_err!(
+                    "Detached leaf {:x} without a value reader.",
+                    leaf_node.key_hash_with::<H>()
+                )
+            })?;
+            let value = value_reader.get_value(&leaf_node.value_hash_with::<H>())?;
+            return leaf_node.attach_with::<H>(value)?.into_key_value();
+        }
+        leaf_node.into_key_value()
+    }
+}
+
+impl<'a, K, V, R, H> Iterator for JellyfishMerkleIterator<'a, K, V, R, H>
+where
+    R: 'a + TreeReader<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    type Item = Result<(SMTObject<K>, SMTObject<V>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_leaf()
+            .map(|result| result.and_then(|leaf_node| self.key_value(leaf_node)))
+    }
+
+    /// The lower bound comes from the leaf counts stored in the internal nodes, see
+    /// [`InternalNode::leaf_count`]. It is only set for an iterator without an end bound on which
+    /// `next_back` was never called, and 0 if a count is unknown. The counts are not covered by
+    /// the root hash, so the bound is a hint which a store with wrong counts makes wrong, and
+    /// there is no upper bound until the iterator is exhausted.
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let (lower, upper) = self.traversal.size_hint();
+        let back_upper = match &self.back_traversal {
+            Some(back_traversal) => back_traversal.size_hint().1,
+            None => return (lower, upper),
+        };
+        // Both traversals bound the number of keys left between them.
+        match (upper, back_upper) {
+            (Some(upper), Some(back_upper)) => (lower, Some(upper.min(back_upper))),
+            (upper, back_upper) => (lower, upper.or(back_upper)),
+        }
+    }
+}
+
+impl<'a, K, V, R, H> DoubleEndedIterator for JellyfishMerkleIterator<'a, K, V, R, H>
+where
+    R: 'a + TreeReader<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.next_back_leaf()
+            .map(|result| result.and_then(|leaf_node| self.key_value(leaf_node)))
+    }
+}
+
+impl<'a, K, V, R, H> FusedIterator for JellyfishMerkleIterator<'a, K, V, R, H>
+where
+    R: 'a + TreeReader<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+}
+
+/// The `JellyfishMerkleKeyIterator` implementation. It runs the same traversal as the
+/// `JellyfishMerkleIterator` it is created from, but only yields the keys.
+pub struct JellyfishMerkleKeyIterator<'a, K, V, R: 'a + TreeReader<K, V>, H = Sha3TreeHasher> {
+    iter: JellyfishMerkleIterator<'a, K, V, R, H>,
+}
+
+impl<'a, K: Clone, V: Clone, R: TreeReader<K, V>, H> Clone
+    for JellyfishMerkleKeyIterator<'a, K, V, R, H>
+{
+    fn clone(&self) -> Self {
+        Self {
+            iter: self.iter.clone(),
+        }
+    }
+}
+
+impl<'a, K, V, R, H> Iterator for JellyfishMerkleKeyIterator<'a, K, V, R, H>
+where
+    R: 'a + TreeReader<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    type Item = Result<SMTObject<K>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter
+            .next_leaf()
+            .map(|result| result.map(|leaf_node| leaf_node.into_key()))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+impl<'a, K, V, R, H> DoubleEndedIterator for JellyfishMerkleKeyIterator<'a, K, V, R, H>
+where
+    R: 'a + TreeReader<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.iter
+            .next_back_leaf()
+            .map(|result| result.map(|leaf_node| leaf_node.into_key()))
+    }
+}
+
+impl<'a, K, V, R, H> FusedIterator for JellyfishMerkleKeyIterator<'a, K, V, R, H>
+where
+    R: 'a + TreeReader<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+}
+
+/// The `JellyfishMerkleDepthIterator` implementation. It runs the same traversal as the
+/// `JellyfishMerkleIterator` it is created from, and yields the depth of each leaf as well.
+pub struct JellyfishMerkleDepthIterator<'a, K, V, R: 'a + TreeReader<K, V>, H = Sha3TreeHasher> {
+    iter: JellyfishMerkleIterator<'a, K, V, R, H>,
+}
+
+impl<'a, K: Clone, V: Clone, R: TreeReader<K, V>, H> Clone
+    for JellyfishMerkleDepthIterator<'a, K, V, R, H>
+{
+    fn clone(&self) -> Self {
+        Self {
+            iter: self.iter.clone(),
+        }
+    }
+}
+
+impl<'a, K, V, R, H> Iterator for JellyfishMerkleDepthIterator<'a, K, V, R, H>
+where
+    R: 'a + TreeReader<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    type Item = Result<(SMTObject<K>, SMTObject<V>, usize)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let leaf_node = self.iter.next_leaf()?;
+        let depth = self.iter.traversal.leaf_depth;
+        Some(leaf_node.and_then(|leaf_node| {
+            let (key, value) = self.iter.key_value(leaf_node)?;
+            Ok((key, value, depth))
+        }))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+impl<'a, K, V, R, H> DoubleEndedIterator for JellyfishMerkleDepthIterator<'a, K, V, R, H>
+where
+    R: 'a + TreeReader<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let leaf_node = self.iter.next_back_leaf()?;
+        let depth = self
+            .iter
+            .back_traversal
+            .as_ref()
+            .expect("The back traversal is created by next_back_leaf.")
+            .leaf_depth;
+        Some(leaf_node.and_then(|leaf_node| {
+            let (key, value) = self.iter.key_value(leaf_node)?;
+            Ok((key, value, depth))
+        }))
+    }
+}
+
+impl<'a, K, V, R, H> FusedIterator for JellyfishMerkleDepthIterator<'a, K, V, R, H>
+where
+    R: 'a + TreeReader<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+}
+
+/// An event of the traversal of [`SMTree::structure`](crate::SMTree::structure). The depth of a node is the number of
+/// internal nodes on the path from the root to it, the root being at depth 0.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum StructuralEvent<K, V> {
+    /// The traversal enters the internal node with key `node_key`. Bit `i` of `bitmap` is set if
+    /// the node has a child at nibble `i`, and the events of the children follow in nibble order.
+    EnterInternal {
+        node_key: NodeKey,
+        depth: usize,
+        bitmap: u16,
+    },
+    /// The traversal reaches a leaf.
+    Leaf { key: K, value: V, depth: usize },
+    /// The traversal leaves the internal node of the last `EnterInternal` event not left yet.
+    ExitInternal,
+}
+
+impl<K, V> StructuralEvent<K, V> {
+    /// Maps the key and the value of a `Leaf` event.
+    pub fn map_leaf<NK, NV, F>(self, f: F) -> StructuralEvent<NK, NV>
+    where
+        F: FnOnce(K, V) -> (NK, NV),
+    {
+        match self {
+            StructuralEvent::EnterInternal {
+                node_key,
+                depth,
+                bitmap,
+            } => StructuralEvent::EnterInternal {
+                node_key,
+                depth,
+                bitmap,
+            },
+            StructuralEvent::Leaf { key, value, depth } => {
+                let (key, value) = f(key, value);
+                StructuralEvent::Leaf { key, value, depth }
+            }
+            StructuralEvent::ExitInternal => StructuralEvent::ExitInternal,
+        }
+    }
+}
+
+/// The `JellyfishMerkleStructureIterator` implementation. It runs a depth first traversal of the
+/// whole tree from left to right like the `JellyfishMerkleIterator`, but yields a
+/// [`StructuralEvent`] each time it enters or leaves an internal node as well as for each leaf, so
+/// the shape of the tree can be rebuilt from the events. The leaf events are the key-value pairs
+/// of the `JellyfishMerkleIterator`, in the same order.
+pub struct JellyfishMerkleStructureIterator<'a, K, V, R: 'a + TreeReader<K, V>, H = Sha3TreeHasher>
+{
+    reader: &'a R,
+    /// The node to visit next, if its parent was just given a child to visit, or the root.
+    next_node_key: Option<NodeKey>,
+    /// The internal nodes entered and not left yet, with the bitmap of their children not visited
+    /// yet.
+    parent_stack: Vec<(InternalNode, u16)>,
+    /// Whether the iteration is over, either because all the events were yielded or because of
+    /// an error.
+    done: bool,
+    phantom: PhantomData<(K, V, H)>,
+}
+
+impl<'a, K, V, R, H> JellyfishMerkleStructureIterator<'a, K, V, R, H>
+where
+    R: 'a + TreeReader<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    /// Constructs a new iterator over the structure of the tree at `state_root_hash`.
+    pub fn new(reader: &'a R, state_root_hash: HashValue) -> Self {
+        Self {
+            reader,
+            next_node_key: Some(state_root_hash),
+            parent_stack: Vec::with_capacity(ROOT_NIBBLE_HEIGHT),
+            done: false,
+            phantom: PhantomData,
+        }
+    }
+
+    fn next_event(&mut self) -> Result<Option<StructuralEvent<SMTObject<K>, SMTObject<V>>>> {
+        loop {
+            let node_key = match self.next_node_key.take() {
+                Some(node_key) => node_key,
+                None => match self.parent_stack.last_mut() {
+                    Some((_, 0)) => {
+                        self.parent_stack.pop();
+                        return Ok(Some(StructuralEvent::ExitInternal));
+                    }
+                    Some((internal_node, unvisited_bitmap)) => {
+                        let index = unvisited_bitmap.trailing_zeros() as u8;
+                        *unvisited_bitmap &= !(1 << index);
+                        self.next_node_key = Some(
+                            internal_node
+                                .child(Nibble::from(index))
+                                .expect("Child should exist.")
+                                .hash,
+                        );
+                        continue;
+                    }
+                    None => return Ok(None),
+                },
+            };
+
+            let depth = self.parent_stack.len();
+            let node = if depth == 0 {
+                get_root_node::<_, _, _, H>(self.reader, &node_key)?
+            } else {
+                self.reader.get_node(&node_key)?
+            };
+            return match node {
+                Node::Internal(internal_node) => {
+                    ensure!(
+                        depth < ROOT_NIBBLE_HEIGHT,
+                        SmtError::CorruptNode(format!(
+                            "Should have reached the bottom of the tree at internal node {:x}.",
+                            node_key
+                        ))
+                    );
+                    let (bitmap, _) = internal_node.generate_bitmaps();
+                    self.parent_stack.push((internal_node, bitmap));
+                    Ok(Some(StructuralEvent::EnterInternal {
+                        node_key,
+                        depth,
+                        bitmap,
+                    }))
+                }
+                Node::Leaf(leaf_node) => {
+                    let (key, value) = leaf_node.into_key_value()?;
+                    Ok(Some(StructuralEvent::Leaf { key, value, depth }))
+                }
+                Node::Null => {
+                    ensure!(depth == 0, SmtError::UnexpectedNull(node_key));
+                    Ok(None)
+                }
+            };
+        }
+    }
+}
+
+impl<'a, K, V, R, H> Iterator for JellyfishMerkleStructureIterator<'a, K, V, R, H>
+where
+    R: 'a + TreeReader<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    type Item = Result<StructuralEvent<SMTObject<K>, SMTObject<V>>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let event = self.next_event().transpose();
+        if !matches!(event, Some(Ok(_))) {
+            self.done = true;
+        }
+        event
+    }
+}
+
+impl<'a, K, V, R, H> FusedIterator for JellyfishMerkleStructureIterator<'a, K, V, R, H>
+where
+    R: 'a + TreeReader<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+}
+
+/// The `JellyfishMerkleIntoIterator` implementation. It owns its reader, and drops it as soon as
+/// the iteration is over rather than when the iterator itself is dropped, so a storage holding
+/// resources is released even if the iterator is kept around.
+pub struct JellyfishMerkleIntoIterator<K, V, R: TreeReader<K, V>, H = Sha3TreeHasher> {
+    /// The storage engine from which we can read nodes using node keys. It is dropped once the
+    /// traversal is done.
+    reader: Option<R>,
+
+    /// The root hash of the tree this iterator is running on.
+    state_root_hash: HashValue,
+
+    /// The depth first traversal producing the leaves.
+    traversal: Traversal<K, V, H>,
+
+    key: PhantomData<K>,
+    value: PhantomData<V>,
+}
+
+impl<K, V, R, H> JellyfishMerkleIntoIterator<K, V, R, H>
+where
+    R: TreeReader<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    /// Constructs a new iterator. This puts the internal state in the correct position, so the
+    /// following `next` call will yield the smallest key that is greater or equal to
+    /// `starting_key`.
+    pub fn new(reader: R, state_root_hash: HashValue, starting_key: HashValue) -> Result<Self> {
+        let traversal = Traversal::new(
+            &reader,
+            state_root_hash,
+            Bound::Included(starting_key),
+            Direction::Ascending,
+        )?;
+        // An empty tree, or a starting key past the last key, leaves nothing to read.
+        let reader = (!traversal.done).then_some(reader);
+        Ok(Self {
+            reader,
+            state_root_hash,
+            traversal,
+            key: PhantomData,
+            value: PhantomData,
+        })
+    }
+
+    /// Returns whether the reader is still held, i.e. the iteration is not over.
+    pub fn holds_reader(&self) -> bool {
+        self.reader.is_some()
+    }
+
+    #[cfg(test)]
+    pub fn print(&self) -> Result<()> {
+        let reader = match &self.reader {
+            Some(reader) => reader,
+            None => return Ok(()),
+        };
+        let nodes = &self.traversal.parent_stack;
+        for node in nodes {
+            println!("internal node key: {:?}", node.node_key.to_hex());
+            if let Ok(Node::Internal(internal)) = reader.get_node(&node.node_key) {
+                println!("child: {:?}", internal.all_child());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<K, V, R, H> JellyfishMerkleIntoIterator<K, V, R, H>
+where
+    R: TreeReader<K, V> + Clone,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    /// Splits the iterator into up to 16 iterators, one for each child of the root that has keys
+    /// left to yield, in the order of their nibbles. Each of them only yields the keys of its own
+    /// subtree, so the concatenation of their outputs equals the output of this iterator, and they
+    /// can be driven on different threads. The reader is cloned for each of them.
+    ///
+    /// If the root is not an internal node, i.e. the tree is empty or holds a single leaf, or the
+    /// iteration is over, this iterator is returned as the only element. No node is read.
+    pub fn split(self) -> Vec<Self> {
+        let root_visit_info = match self.traversal.parent_stack.first() {
+            Some(root_visit_info) if !self.traversal.done => root_visit_info,
+            _ => return vec![self],
+        };
+        let root_node_key = root_visit_info.node_key;
+        let root = root_visit_info.node.clone();
+        let end = self.traversal.end;
+        let first_nibble = root_visit_info.next_child_to_visit.trailing_zeros() as u8;
+        let (children_bitmap, _) = root.generate_bitmaps();
+
+        let mut subtrees = vec![];
+        for nibble in first_nibble..16 {
+            if children_bitmap & (1 << nibble) == 0 {
+                continue;
+            }
+            let (first_key_hash, last_key_hash) =
+                prefix_key_hash_range(&NibblePath::new_odd(vec![nibble << 4]));
+            if !Direction::Ascending.is_within(first_key_hash, &end) {
+                break;
+            }
+            let subtree_end = if Direction::Ascending.is_within(last_key_hash, &end) {
+                Bound::Included(last_key_hash)
+            } else {
+                end
+            };
+            subtrees.push((nibble, subtree_end));
+        }
+
+        let mut first = self;
+        let mut split = Vec::with_capacity(subtrees.len());
+        for (nibble, subtree_end) in subtrees {
+            if nibble == first_nibble {
+                // The first subtree may be partially visited already, so it keeps the traversal.
+                first.traversal.end = subtree_end;
+                continue;
+            }
+            let mut traversal = Traversal::with_direction(Direction::Ascending);
+            traversal.parent_stack.push(
+                NodeVisitInfo::new_next_child_to_visit(
+                    root_node_key,
+                    root.clone(),
+                    Nibble::from(nibble),
+                    Direction::Ascending,
+                )
+                .expect("The child at the nibble exists."),
+            );
+            traversal.end = subtree_end;
+            split.push(Self {
+                reader: first.reader.clone(),
+                state_root_hash: first.state_root_hash,
+                traversal,
+                key: PhantomData,
+                value: PhantomData,
+            });
+        }
+        split.insert(0, first);
+        split
+    }
+}
+
+impl<K, V, R, H> Iterator for JellyfishMerkleIntoIterator<K, V, R, H>
+where
+    R: TreeReader<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    type Item = Result<(SMTObject<K>, SMTObject<V>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let item = self
+            .traversal
+            .next_leaf(self.reader.as_ref()?, self.state_root_hash)
+            .map(|result| result.and_then(|leaf_node| leaf_node.into_key_value()));
+        if self.traversal.done {
+            self.reader = None;
+        }
+        item
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.traversal.size_hint()
+    }
+}
+
+impl<K, V, R, H> FusedIterator for JellyfishMerkleIntoIterator<K, V, R, H>
+where
+    R: TreeReader<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
 }
@@ -0,0 +1,213 @@
+// Copyright (c) RoochNetwork
+// SPDX-License-Identifier: Apache-2.0
+
+//! This module implements `JellyfishMerkleStream`, the asynchronous counterpart of
+//! `JellyfishMerkleIterator` reading the tree from an `AsyncTreeReader`. It runs the same depth
+//! first traversal, awaiting each node read instead of blocking on it.
+
+use super::{checked_node, Direction, Traversal};
+use crate::jellyfish_merkle::{
+    hash::{HashValue, SMTHash, Sha3TreeHasher, TreeHasher},
+    node_type::{LeafNode, Node},
+    AsyncTreeReader,
+};
+use crate::{Key, SMTObject, Value};
+use anyhow::Result;
+use futures_core::Stream;
+use std::{
+    future::Future,
+    ops::{Bound, ControlFlow},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// The read of the next leaf in progress, owning the traversal until it is done.
+type NextLeaf<'a, K, V, H> =
+    Pin<Box<dyn Future<Output = (Traversal<K, V, H>, Option<Result<LeafNode<K, V>>>)> + Send + 'a>>;
+
+/// The `JellyfishMerkleStream` implementation. It is a `Stream` of the key-value pairs, in the
+/// same order as `JellyfishMerkleIterator` would yield them, and does not depend on an async
+/// runtime. After an error the stream is over and keeps returning `None`.
+pub struct JellyfishMerkleStream<'a, K, V, R, H = Sha3TreeHasher> {
+    /// The storage engine from which we can read nodes using node keys.
+    reader: &'a R,
+
+    /// The root hash of the tree this stream is running on.
+    state_root_hash: HashValue,
+
+    /// The depth first traversal producing the leaves. It is moved into `next_leaf` while a leaf
+    /// is read.
+    traversal: Option<Traversal<K, V, H>>,
+
+    /// The read of the next leaf polled by `poll_next`, if one is in progress.
+    next_leaf: Option<NextLeaf<'a, K, V, H>>,
+}
+
+// The pending read is boxed, nothing of the stream is pinned in place.
+impl<'a, K, V, R, H> Unpin for JellyfishMerkleStream<'a, K, V, R, H> {}
+
+impl<'a, K, V, R, H> JellyfishMerkleStream<'a, K, V, R, H>
+where
+    R: AsyncTreeReader<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    /// Constructs a new stream. This puts the internal state in the correct position, so the
+    /// following `next` call will yield the smallest key that is greater or equal to
+    /// `starting_key`.
+    pub async fn new(
+        reader: &'a R,
+        state_root_hash: HashValue,
+        starting_key: Option<SMTObject<K>>,
+    ) -> Result<Self> {
+        Self::new_with_direction(reader, state_root_hash, starting_key, Direction::Ascending).await
+    }
+
+    /// Constructs a new stream which yields the keys in descending order. The following `next`
+    /// call will yield the largest key that is less or equal to `starting_key`, or the largest key
+    /// of the tree if `starting_key` is `None`.
+    pub async fn new_rev(
+        reader: &'a R,
+        state_root_hash: HashValue,
+        starting_key: Option<SMTObject<K>>,
+    ) -> Result<Self> {
+        Self::new_with_direction(reader, state_root_hash, starting_key, Direction::Descending).await
+    }
+
+    async fn new_with_direction(
+        reader: &'a R,
+        state_root_hash: HashValue,
+        starting_key: Option<SMTObject<K>>,
+        direction: Direction,
+    ) -> Result<Self> {
+        let start = match starting_key {
+            Some(key) => Bound::Included(key.merkle_hash_with::<H>()),
+            None => Bound::Unbounded,
+        };
+        let mut traversal = Traversal::with_direction(direction);
+        let (key_hash, exclusive) = traversal.reset(start);
+        let mut current_node_key = state_root_hash;
+        let mut current_node = checked_node::<_, _, H>(
+            &state_root_hash,
+            get_root_node::<_, _, _, H>(reader, state_root_hash).await?,
+        )?;
+        while let Some(child_node_key) =
+            traversal.seek_step(current_node_key, current_node, key_hash, exclusive)?
+        {
+            current_node_key = child_node_key;
+            current_node = checked_node::<_, _, H>(
+                &current_node_key,
+                reader.get_node(&current_node_key).await?,
+            )?;
+        }
+        Ok(Self {
+            reader,
+            state_root_hash,
+            traversal: Some(traversal),
+            next_leaf: None,
+        })
+    }
+}
+
+impl<'a, K, V, R, H> JellyfishMerkleStream<'a, K, V, R, H>
+where
+    R: AsyncTreeReader<K, V> + Sync,
+    K: Key + Send + 'a,
+    V: Value + Send + 'a,
+    H: TreeHasher + Send + 'a,
+{
+    /// Returns the next key-value pair, or `None` once all of them have been yielded, same as
+    /// `StreamExt::next`.
+    pub async fn next(&mut self) -> Option<Result<(SMTObject<K>, SMTObject<V>)>> {
+        std::future::poll_fn(|cx| Pin::new(&mut *self).poll_next(cx)).await
+    }
+}
+
+impl<'a, K, V, R, H> Stream for JellyfishMerkleStream<'a, K, V, R, H>
+where
+    R: AsyncTreeReader<K, V> + Sync,
+    K: Key + Send + 'a,
+    V: Value + Send + 'a,
+    H: TreeHasher + Send + 'a,
+{
+    type Item = Result<(SMTObject<K>, SMTObject<V>)>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let next_leaf = match &mut this.next_leaf {
+            Some(next_leaf) => next_leaf,
+            None => {
+                let traversal = this
+                    .traversal
+                    .take()
+                    .expect("The traversal is only taken by a read in progress.");
+                if traversal.done {
+                    this.traversal = Some(traversal);
+                    return Poll::Ready(None);
+                }
+                this.next_leaf.insert(Box::pin(next_leaf(
+                    this.reader,
+                    this.state_root_hash,
+                    traversal,
+                )))
+            }
+        };
+        let (traversal, leaf_node) = match next_leaf.as_mut().poll(cx) {
+            Poll::Ready(output) => output,
+            Poll::Pending => return Poll::Pending,
+        };
+        this.next_leaf = None;
+        this.traversal = Some(traversal);
+        Poll::Ready(leaf_node.map(|result| result.and_then(|leaf_node| leaf_node.into_key_value())))
+    }
+}
+
+/// Reads the root node. The placeholder hash of `H` is the root hash of an empty tree, which is not
+/// stored.
+async fn get_root_node<K, V, R, H>(reader: &R, state_root_hash: HashValue) -> Result<Node<K, V>>
+where
+    R: AsyncTreeReader<K, V>,
+    H: TreeHasher,
+{
+    if H::is_empty_root(state_root_hash) {
+        Ok(Node::Null)
+    } else {
+        reader.get_node(&state_root_hash).await
+    }
+}
+
+/// Reads the next leaf of `traversal`, returning the traversal along with it.
+async fn next_leaf<K, V, R, H>(
+    reader: &R,
+    state_root_hash: HashValue,
+    mut traversal: Traversal<K, V, H>,
+) -> (Traversal<K, V, H>, Option<Result<LeafNode<K, V>>>)
+where
+    R: AsyncTreeReader<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    if traversal.parent_stack.is_empty() {
+        let root = get_root_node::<_, _, _, H>(reader, state_root_hash)
+            .await
+            .and_then(|node| checked_node::<_, _, H>(&state_root_hash, node));
+        let leaf_node = traversal.visit_root(state_root_hash, root);
+        return (traversal, leaf_node);
+    }
+
+    loop {
+        let node_key = match traversal.next_child_key() {
+            Some(node_key) => node_key,
+            None => return (traversal, None),
+        };
+        let node = reader
+            .get_node(&node_key)
+            .await
+            .and_then(|node| checked_node::<_, _, H>(&node_key, node));
+        if let ControlFlow::Break(leaf_node) = traversal.visit_child(node_key, node) {
+            return (traversal, leaf_node);
+        }
+    }
+}
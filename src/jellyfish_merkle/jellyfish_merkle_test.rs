@@ -7,17 +7,20 @@
 use super::hash::{HashValue, *};
 use super::nibble::Nibble;
 use super::node_type::SparseMerkleInternalNode;
+use super::proof::{verify_leaf_set, ProofOutcome, SparseMerkleSibling};
 use super::{mock_tree_store::TestValue, *};
-use crate::jellyfish_merkle::mock_tree_store::{MockTestStore, TestKey};
+use crate::jellyfish_merkle::mock_tree_store::{
+    CountingTreeReader, MockTestStore, MockTreeStore, TestKey,
+};
 use crate::EncodeToObject;
 use proptest::{
     collection::{btree_map, hash_map, vec},
     prelude::*,
 };
 use rand::{rngs::StdRng, Rng, SeedableRng};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::ops::Bound;
-use test_helper::{init_mock_db, plus_one};
+use test_helper::{init_mock_db, minus_one, plus_one};
 
 fn update_nibble(original_key: &TestKey, n: usize, nibble: u8) -> TestKey {
     assert!(nibble < 16);
@@ -92,416 +95,2610 @@ fn test_delete_from_tree() {
 }
 
 #[test]
-fn test_insert_at_leaf_with_internal_created() {
+fn test_delete_collapses_internal_nodes() {
     let db = MockTestStore::new_test();
     let tree = JellyfishMerkleTree::new(&db);
 
+    // key1 and key2 share their first 4 nibbles, so they are under a chain of internal nodes.
     let key1 = TestKey::new([0x00u8; HashValue::LENGTH]);
-    let value1 = TestValue::from(vec![1u8, 2u8]);
+    let key2 = update_nibble(&key1, 4, 1);
+    let key3 = update_nibble(&key1, 0, 15);
+    let value = TestValue::from(vec![1u8, 2u8]);
 
-    let (_root0_hash, batch) = tree
-        .put_blob_set(None, vec![(key1.into(), value1.clone().into())])
+    let (root_without_key2, batch) = tree
+        .put_blob_set(
+            None,
+            vec![
+                (key1.into_object(), value.clone().into_object()),
+                (key3.into_object(), value.clone().into_object()),
+            ],
+        )
+        .unwrap();
+    db.write_tree_update_batch(batch).unwrap();
+    let (root, batch) = tree
+        .put_blob_set(
+            Some(root_without_key2),
+            vec![(key2.into_object(), value.into_object())],
+        )
         .unwrap();
+    db.write_tree_update_batch(batch).unwrap();
+    assert_eq!(db.num_nodes(), 9);
 
+    // The root, the 4 internal nodes between the root and the leaves of key1 and key2, and the
+    // leaf of key2 are stale. key1 moves back up below the root, and its leaf is already stored.
+    let (new_root, batch) = tree.delete(Some(root), key2).unwrap();
+    assert_eq!(new_root, root_without_key2);
+    assert_eq!(batch.num_stale_leaves, 1);
+    assert_eq!(batch.stale_node_index_batch.len(), 6);
+    assert_eq!(batch.num_new_leaves, 0);
+    assert_eq!(batch.node_batch.len(), 1);
+    assert!(batch.node_batch.contains_key(&root_without_key2));
+    assert!(batch
+        .stale_node_index_batch
+        .iter()
+        .all(|index| index.stale_since_version == root_without_key2));
+    assert_eq!(tree.get(new_root, key2).unwrap(), None);
+    assert!(tree.get(new_root, key1).unwrap().is_some());
+    assert!(tree.get(new_root, key3).unwrap().is_some());
+}
+
+#[test]
+fn test_delete_nonexistent_key() {
+    let db = MockTestStore::new_test();
+    let tree = JellyfishMerkleTree::new(&db);
+
+    let key1 = TestKey::new([0x00u8; HashValue::LENGTH]);
+    let (new_root, batch) = tree.delete(None, key1).unwrap();
+    assert_eq!(new_root, *SPARSE_MERKLE_PLACEHOLDER_HASH);
+    assert!(batch.node_batch.is_empty());
     assert!(batch.stale_node_index_batch.is_empty());
-    db.write_tree_update_batch(batch).unwrap();
-    assert_eq!(tree.get(_root0_hash, key1).unwrap().unwrap().origin, value1);
-    assert_eq!(db.num_nodes(), 1);
-    // Insert at the previous leaf node. Should generate an internal node at the root.
-    // Change the 1st nibble to 15.
-    let key2 = update_nibble(&key1, 0, 15);
-    let value2 = TestValue::from(vec![3u8, 4u8]);
 
-    let (_root1_hash, batch) = tree
+    let key2 = update_nibble(&key1, 0, 15);
+    let key3 = update_nibble(&key1, 1, 15);
+    let key4 = update_nibble(&key1, 0, 1);
+    let (root, batch) = tree
         .put_blob_set(
-            Some(_root0_hash),
-            vec![(key2.into(), value2.clone().into())],
+            None,
+            vec![
+                (key1.into_object(), TestValue::random().into_object()),
+                (key2.into_object(), TestValue::random().into_object()),
+            ],
         )
         .unwrap();
-    assert_eq!(batch.stale_node_index_batch.len(), 0);
     db.write_tree_update_batch(batch).unwrap();
 
-    assert_eq!(tree.get(_root1_hash, key1).unwrap().unwrap().origin, value1);
-    assert!(tree.get(_root0_hash, key2).unwrap().is_none());
-    assert_eq!(tree.get(_root1_hash, key2).unwrap().unwrap().origin, value2);
-
-    // get # of nodes
-    assert_eq!(db.num_nodes(), 3);
+    // key3 ends at the leaf of key1, and key4 at an empty child of the root.
+    for key in [key3, key4] {
+        let (new_root, batch) = tree.delete(Some(root), key).unwrap();
+        assert_eq!(new_root, root);
+        assert!(batch.node_batch.is_empty());
+        assert!(batch.stale_node_index_batch.is_empty());
+        assert_eq!(batch.num_stale_leaves, 0);
+    }
+}
 
-    let leaf1 = Node::new_leaf(key1, value1);
-    let leaf2 = Node::new_leaf(key2, value2);
-    let mut children = HashMap::new();
-    children.insert(
-        Nibble::from(0),
-        Child::new(leaf1.merkle_hash(), true /* is_leaf */),
+fn assert_put_batch_matches_updates(
+    tree: &JellyfishMerkleTree<TestKey, TestValue, MockTestStore>,
+    root: Option<HashValue>,
+    updates: Vec<(SMTObject<TestKey>, Option<SMTObject<TestValue>>)>,
+) -> HashValue {
+    let (expected_root, expected_batch) = tree.updates(root, updates.clone()).unwrap();
+    let (new_root, batch) = tree.put_batch(root, updates).unwrap();
+    assert_eq!(new_root, expected_root);
+    assert_eq!(
+        batch.node_batch.keys().collect::<Vec<_>>(),
+        expected_batch.node_batch.keys().collect::<Vec<_>>()
     );
-    children.insert(
-        Nibble::from(15),
-        Child::new(leaf2.merkle_hash(), true /* is_leaf */),
+    assert_eq!(
+        batch.stale_node_index_batch,
+        expected_batch.stale_node_index_batch
     );
-    let internal = Node::new_internal(children);
-    assert_eq!(db.get_node(&leaf1.merkle_hash()).unwrap(), leaf1);
-    assert_eq!(db.get_node(&leaf2.merkle_hash()).unwrap(), leaf2);
-    assert_eq!(db.get_node(&internal.merkle_hash()).unwrap(), internal);
+    assert_eq!(batch.num_new_leaves, expected_batch.num_new_leaves);
+    assert_eq!(batch.num_stale_leaves, expected_batch.num_stale_leaves);
+    new_root
 }
 
 #[test]
-fn test_insert_at_leaf_with_multiple_internals_created() {
+fn test_put_batch() {
     let db = MockTestStore::new_test();
     let tree = JellyfishMerkleTree::new(&db);
 
-    // 1. Insert the first leaf into empty tree
+    // Empty tree, empty batch.
+    let (root, batch) = tree.put_batch(None, vec![]).unwrap();
+    assert_eq!(root, *SPARSE_MERKLE_PLACEHOLDER_HASH);
+    assert!(batch.node_batch.is_empty());
+
     let key1 = TestKey::new([0x00u8; HashValue::LENGTH]);
-    let value1 = TestValue::from(vec![1u8, 2u8]);
+    let key2 = update_nibble(&key1, 0, 15);
+    let key3 = update_nibble(&key1, 2, 3);
+    let value1 = TestValue::from(vec![1u8]);
+    let value2 = TestValue::from(vec![2u8]);
+    let updates = vec![
+        (key1.into_object(), Some(value1.clone().into_object())),
+        (key2.into_object(), Some(value2.clone().into_object())),
+        (key3.into_object(), Some(value1.clone().into_object())),
+        // The last update of a key wins.
+        (key1.into_object(), Some(value2.clone().into_object())),
+    ];
+    let root = assert_put_batch_matches_updates(&tree, None, updates.clone());
+    let (_, batch) = tree.put_batch(None, updates).unwrap();
+    db.write_tree_update_batch(batch).unwrap();
+    assert_eq!(tree.get(root, key1).unwrap().unwrap().origin, value2);
 
-    let (_root0_hash, batch) = tree
-        .put_blob_set(None, vec![(key1.into(), value1.clone().into())])
+    // Writing the same value again changes nothing.
+    let (new_root, batch) = tree
+        .put_batch(
+            Some(root),
+            vec![(key3.into_object(), Some(value1.into_object()))],
+        )
         .unwrap();
-    db.write_tree_update_batch(batch).unwrap();
-    assert_eq!(tree.get(_root0_hash, key1).unwrap().unwrap().origin, value1);
+    assert_eq!(new_root, root);
+    assert!(batch.node_batch.is_empty());
+    assert!(batch.stale_node_index_batch.is_empty());
 
-    // 2. Insert at the previous leaf node. Should generate a branch node at root.
-    // Change the 2nd nibble to 1.
-    let key2 = update_nibble(&key1, 1 /* nibble_index */, 1 /* nibble */);
-    let value2 = TestValue::from(vec![3u8, 4u8]);
+    // Deleting every key leaves an empty tree.
+    let (new_root, batch) = tree
+        .put_batch(
+            Some(root),
+            vec![
+                (key1.into_object(), None),
+                (key2.into_object(), None),
+                (key3.into_object(), None),
+            ],
+        )
+        .unwrap();
+    assert_eq!(new_root, *SPARSE_MERKLE_PLACEHOLDER_HASH);
+    assert!(batch.node_batch.is_empty());
+    assert_eq!(batch.num_stale_leaves, 3);
+}
 
-    let (_root1_hash, batch) = tree
+#[test]
+fn test_extract_subtree() {
+    let db = MockTestStore::new_test();
+    let tree = JellyfishMerkleTree::new(&db);
+    let mut rng = StdRng::from_seed([3; 32]);
+    let kvs: Vec<_> = (0..500)
+        .map(|_| {
+            (
+                TestKey::new_with_hash(HashValue::random_with_rng(&mut rng)),
+                TestValue::from(HashValue::random_with_rng(&mut rng).to_vec()),
+            )
+        })
+        .collect();
+    let (root, batch) = tree
         .put_blob_set(
-            Some(_root0_hash),
-            vec![(key2.into(), value2.clone().into())],
+            None,
+            kvs.iter()
+                .map(|(k, v)| (k.into_object(), v.clone().into_object()))
+                .collect(),
         )
         .unwrap();
     db.write_tree_update_batch(batch).unwrap();
-    assert_eq!(tree.get(_root0_hash, key1).unwrap().unwrap().origin, value1);
-    assert!(tree.get(_root0_hash, key2).unwrap().is_none());
-    assert_eq!(tree.get(_root1_hash, key2).unwrap().unwrap().origin, value2);
-    assert_eq!(tree.get(_root1_hash, key1).unwrap().unwrap().origin, value1);
-
-    assert_eq!(db.num_nodes(), 4);
-    tree.print_tree(_root1_hash, key1).unwrap();
 
-    let leaf1: Node<TestKey, TestValue> = Node::new_leaf(key1, value1);
-    let leaf2: Node<TestKey, TestValue> = Node::new_leaf(key2, value2.clone());
-    let internal = {
-        let mut children = HashMap::new();
-        children.insert(
-            Nibble::from(0),
-            Child::new(leaf1.merkle_hash(), true /* is_leaf */),
-        );
-        children.insert(Nibble::from(1), Child::new(leaf2.merkle_hash(), true));
-        Node::new_internal(children)
-    };
+    for prefix in [
+        NibblePath::new(vec![]),
+        NibblePath::new_odd(vec![0x30]),
+        NibblePath::new(vec![0xa7]),
+    ] {
+        let (subtree_root, node_batch) =
+            extract_subtree::<_, _, _, Sha3TreeHasher>(&db, root, prefix.clone()).unwrap();
 
-    let root_internal = {
-        let mut children = HashMap::new();
-        children.insert(
-            Nibble::from(0),
-            Child::new(internal.merkle_hash(), false /* is_leaf */),
+        // The same leaves in a new tree give the same tree.
+        let sub_kvs: Vec<_> = kvs
+            .iter()
+            .filter(|(k, _)| {
+                prefix
+                    .nibbles()
+                    .enumerate()
+                    .all(|(i, nibble)| k.0.nibble(i) == u8::from(nibble))
+            })
+            .collect();
+        assert!(!sub_kvs.is_empty());
+        let sub_db = MockTestStore::new_test();
+        let sub_tree = JellyfishMerkleTree::new(&sub_db);
+        let (expected_root, expected_batch) = sub_tree
+            .put_blob_set(
+                None,
+                sub_kvs
+                    .iter()
+                    .map(|(k, v)| (k.into_object(), v.clone().into_object()))
+                    .collect(),
+            )
+            .unwrap();
+        assert_eq!(subtree_root, expected_root);
+        assert_eq!(
+            node_batch.keys().collect::<Vec<_>>(),
+            expected_batch.node_batch.keys().collect::<Vec<_>>()
         );
-        Node::new_internal(children)
-    };
 
-    assert_eq!(db.get_node(&internal.merkle_hash()).unwrap(), internal);
-    assert_eq!(
-        db.get_node(&root_internal.merkle_hash()).unwrap(),
-        root_internal,
-    );
+        // The extracted nodes are enough to prove against the new root on their own.
+        sub_db.write_node_batch(&node_batch).unwrap();
+        for (k, v) in sub_kvs {
+            let (value, proof) = sub_tree.get_with_proof(subtree_root, *k).unwrap();
+            assert_eq!(value.unwrap().origin, *v);
+            assert!(proof.verify(subtree_root, *k, Some(v.clone())).is_ok());
+        }
+    }
+}
 
-    // 3. Update leaf2 with new value
-    let value2_update = TestValue::from(vec![5u8, 6u8]);
-    let (_root2_hash, batch) = tree
+#[test]
+fn test_extract_subtree_leaf_and_empty() {
+    let db = MockTestStore::new_test();
+    let tree = JellyfishMerkleTree::new(&db);
+    let key1 = TestKey::new([0x00u8; HashValue::LENGTH]);
+    let key2 = update_nibble(&key1, 0, 15);
+    let (root, batch) = tree
         .put_blob_set(
-            Some(_root1_hash),
-            vec![(key2.into(), value2_update.clone().into())],
+            None,
+            vec![
+                (key1.into_object(), TestValue::random().into_object()),
+                (key2.into_object(), TestValue::random().into_object()),
+            ],
         )
         .unwrap();
     db.write_tree_update_batch(batch).unwrap();
-    assert!(tree.get(_root0_hash, key2,).unwrap().is_none());
-    assert_eq!(
-        tree.get(_root1_hash, key2,).unwrap().unwrap().origin,
-        value2
-    );
-    assert_eq!(
-        tree.get(_root2_hash, key2,).unwrap().unwrap().origin,
-        value2_update
-    );
 
-    tree.print_tree(_root2_hash, key1).unwrap();
-    // Get # of nodes.
-    assert_eq!(db.num_nodes(), 7);
+    // The prefix ends at the leaf of key2, or goes past it and still matches key2.
+    for prefix in [
+        NibblePath::new_odd(vec![0xf0]),
+        NibblePath::new(vec![0xf0, 0x00]),
+    ] {
+        let (subtree_root, node_batch) =
+            extract_subtree::<_, _, _, Sha3TreeHasher>(&db, root, prefix).unwrap();
+        match db.get_node(&subtree_root).unwrap() {
+            Node::Leaf(leaf_node) => assert_eq!(*leaf_node.origin_key(), key2),
+            _ => panic!("The new tree should be a leaf."),
+        }
+        assert_eq!(node_batch.len(), 1);
+    }
 
-    // Purge retired nodes.
-    db.purge_stale_nodes(_root0_hash).unwrap();
-    db.purge_stale_nodes(_root1_hash).unwrap();
-    assert_eq!(db.num_nodes(), 7);
-    db.purge_stale_nodes(_root2_hash).unwrap();
-    tree.print_tree(_root2_hash, key1).unwrap();
-    assert_eq!(db.num_nodes(), 4);
+    // The prefix leads to an empty child, or past a leaf which does not match it.
+    for prefix in [NibblePath::new_odd(vec![0x50]), NibblePath::new(vec![0xf1])] {
+        let (subtree_root, node_batch) =
+            extract_subtree::<_, _, _, Sha3TreeHasher>(&db, root, prefix).unwrap();
+        assert_eq!(subtree_root, *SPARSE_MERKLE_PLACEHOLDER_HASH);
+        assert!(node_batch.is_empty());
+    }
 }
 
 #[test]
-fn test_batch_insertion() {
-    // ```text
-    //                             internal(root)
-    //                            /        \
-    //                       internal       2        <- nibble 0
-    //                      /   |   \
-    //              internal    3    4               <- nibble 1
-    //                 |
-    //              internal                         <- nibble 2
-    //              /      \
-    //        internal      6                        <- nibble 3
-    //           |
-    //        internal                               <- nibble 4
-    //        /      \
-    //       1        5                              <- nibble 5
-    //
-    // Total: 12 nodes
-    // ```
+fn test_update_existing_leaf() {
+    let db = MockTestStore::new_test();
+    let tree = JellyfishMerkleTree::new(&db);
+    let mut kvs = (0..100)
+        .map(|_| (TestKey::random(), TestValue::random()))
+        .collect::<Vec<_>>();
+    // Two keys sharing their first 3 nibbles have their leaves below 4 internal nodes.
     let key1 = TestKey::new([0x00u8; HashValue::LENGTH]);
-    let value1 = TestValue::from(vec![1u8]);
-
-    let key2 = update_nibble(&key1, 0, 2);
-    let value2 = TestValue::from(vec![2u8]);
-    let value2_update = TestValue::from(vec![22u8]);
-
-    let key3 = update_nibble(&key1, 1, 3);
-    let value3 = TestValue::from(vec![3u8]);
+    let key2 = update_nibble(&key1, 3, 1);
+    kvs.push((key1, TestValue::random()));
+    kvs.push((key2, TestValue::random()));
+    let (root, batch) = tree
+        .put_blob_set(
+            None,
+            kvs.iter()
+                .map(|(key, value)| (key.into_object(), value.clone().into_object()))
+                .collect(),
+        )
+        .unwrap();
+    db.write_tree_update_batch(batch).unwrap();
 
-    let key4 = update_nibble(&key1, 1, 4);
-    let value4 = TestValue::from(vec![4u8]);
+    for (key, _) in kvs.iter().step_by(10).chain(kvs.iter().rev().take(2)) {
+        let new_value = TestValue::random();
+        let (new_root, node_batch) = update_existing_leaf::<_, _, _, Sha3TreeHasher>(
+            &db,
+            root,
+            &key.into_object(),
+            new_value.clone().into_object(),
+        )
+        .unwrap();
+        // The root is the one of the general put path.
+        let (expected_root, _) = tree
+            .put_blob_set(
+                Some(root),
+                vec![(key.into_object(), new_value.clone().into_object())],
+            )
+            .unwrap();
+        assert_eq!(new_root, expected_root);
+        // Only the leaf and the internal nodes above it are new.
+        let (_, proof) = tree.get_with_proof(root, *key).unwrap();
+        let depth = node_batch.len() - 1;
+        assert!(depth <= proof.siblings().len());
+        assert_eq!(
+            node_batch
+                .values()
+                .filter(|node| matches!(node, Node::Leaf(_)))
+                .count(),
+            1
+        );
+        if *key == key1 || *key == key2 {
+            assert_eq!(depth, 4);
+        }
 
-    let key5 = update_nibble(&key1, 5, 5);
-    let value5 = TestValue::from(vec![5u8]);
+        let db = MockTestStore::new_test();
+        db.write_node_batch(&node_batch).unwrap();
+        assert_eq!(db.get_node(&new_root).unwrap().merkle_hash(), new_root);
+    }
 
-    let key6 = update_nibble(&key1, 3, 6);
-    let value6 = TestValue::from(vec![6u8]);
+    // An absent key is not updated.
+    let err = update_existing_leaf::<_, _, _, Sha3TreeHasher>(
+        &db,
+        root,
+        &TestKey::random().into_object(),
+        TestValue::random().into_object(),
+    )
+    .unwrap_err();
+    assert!(err.to_string().contains("does not exist"), "{}", err);
+    assert!(update_existing_leaf::<_, _, _, Sha3TreeHasher>(
+        &db,
+        *SPARSE_MERKLE_PLACEHOLDER_HASH,
+        &key1.into_object(),
+        TestValue::random().into_object(),
+    )
+    .is_err());
+
+    // The root of a single leaf tree is the leaf.
+    let db = MockTestStore::new_test();
+    let tree = JellyfishMerkleTree::new(&db);
+    let (root, batch) = tree
+        .put_blob_set(
+            None,
+            vec![(key1.into_object(), TestValue::random().into_object())],
+        )
+        .unwrap();
+    db.write_tree_update_batch(batch).unwrap();
+    let new_value = TestValue::random();
+    let (new_root, node_batch) = update_existing_leaf::<_, _, _, Sha3TreeHasher>(
+        &db,
+        root,
+        &key1.into_object(),
+        new_value.clone().into_object(),
+    )
+    .unwrap();
+    assert_eq!(node_batch.len(), 1);
+    assert_eq!(
+        new_root,
+        tree.put_blob_set(
+            Some(root),
+            vec![(key1.into_object(), new_value.into_object())]
+        )
+        .unwrap()
+        .0
+    );
+}
 
-    let batches: Vec<Vec<(TestKey, TestValue)>> = vec![
-        vec![(key1.into(), value1)],
-        vec![(key2.into(), value2)],
-        vec![(key3.into(), value3)],
-        vec![(key4.into(), value4)],
-        vec![(key5.into(), value5)],
-        vec![(key6.into(), value6)],
-        vec![(key2.into(), value2_update)],
-    ];
-    let one_batch: Vec<(SMTObject<TestKey>, SMTObject<TestValue>)> = batches
-        .iter()
-        .flatten()
-        .cloned()
-        .map(|(k, v)| (k.into(), v.into()))
+#[test]
+fn test_put_with_outcome() {
+    let db = MockTestStore::new_test();
+    let tree = JellyfishMerkleTree::new(&db);
+    let kvs = (0..50)
+        .map(|_| (TestKey::random(), TestValue::random()))
         .collect::<Vec<_>>();
+    let mut root = None;
+    for (key, value) in &kvs {
+        let (new_root, batch, outcome) = tree
+            .put_with_outcome(root, key.into_object(), value.clone().into_object())
+            .unwrap();
+        assert_eq!(outcome, PutOutcome::Inserted);
+        // The root is the one of the general put path.
+        let (expected_root, _) = tree
+            .put_blob_set(root, vec![(key.into_object(), value.clone().into_object())])
+            .unwrap();
+        assert_eq!(new_root, expected_root);
+        db.write_tree_update_batch(batch).unwrap();
+        root = Some(new_root);
+    }
 
-    let mut to_verify = one_batch.clone();
-    // key2 was updated so we remove it.
-    to_verify.remove(1);
-    let verify_fn = |tree: &JellyfishMerkleTree<TestKey, TestValue, MockTestStore>,
-                     root: HashValue| {
-        to_verify
-            .iter()
-            .for_each(|(k, v)| assert_eq!(tree.get(root, k.clone()).unwrap().unwrap(), *v))
+    for (key, old_value) in &kvs {
+        let new_value = TestValue::random();
+        let (new_root, batch, outcome) = tree
+            .put_with_outcome(root, key.into_object(), new_value.clone().into_object())
+            .unwrap();
+        assert_eq!(
+            outcome,
+            PutOutcome::Updated {
+                old_value: old_value.clone().into_object()
+            }
+        );
+        db.write_tree_update_batch(batch).unwrap();
+        assert_eq!(
+            tree.get(new_root, *key).unwrap(),
+            Some(new_value.into_object())
+        );
+        root = Some(new_root);
+    }
+
+    // Putting the same value again leaves the tree as it is.
+    let (key, _) = &kvs[0];
+    let value = tree.get(root.unwrap(), *key).unwrap().unwrap();
+    let (new_root, _, outcome) = tree
+        .put_with_outcome(root, key.into_object(), value.clone())
+        .unwrap();
+    assert_eq!(new_root, root.unwrap());
+    assert_eq!(outcome, PutOutcome::Updated { old_value: value });
+}
+
+#[test]
+fn test_compute_root_after() {
+    let db = MockTestStore::new_test();
+    let tree = JellyfishMerkleTree::new(&db);
+    let compute =
+        |root: HashValue, updates: &Vec<(SMTObject<TestKey>, Option<SMTObject<TestValue>>)>| {
+            compute_root_after::<_, _, _, Sha3TreeHasher>(&db, root, updates.clone()).unwrap()
+        };
+
+    // Nothing changes without updates, and an empty tree takes the inserts.
+    let kvs = (0..100)
+        .map(|_| (TestKey::random(), TestValue::random()))
+        .collect::<Vec<_>>();
+    let updates = kvs
+        .iter()
+        .map(|(key, value)| (key.into_object(), Some(value.clone().into_object())))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        compute(*SPARSE_MERKLE_PLACEHOLDER_HASH, &vec![]),
+        *SPARSE_MERKLE_PLACEHOLDER_HASH
+    );
+    let (root, batch) = tree.put_batch(None, updates.clone()).unwrap();
+    assert_eq!(compute(*SPARSE_MERKLE_PLACEHOLDER_HASH, &updates), root);
+    let node_count = batch.node_batch.len();
+    db.write_tree_update_batch(batch).unwrap();
+    assert_eq!(compute(root, &vec![]), root);
+
+    // Updates, deletes, inserts next to existing leaves and repeated keys.
+    let near_key = update_nibble(&kvs[1].0, 2, (kvs[1].0 .0.nibble(2) + 1) % 16);
+    let mut updates = vec![
+        (
+            kvs[0].0.into_object(),
+            Some(TestValue::random().into_object()),
+        ),
+        (kvs[1].0.into_object(), None),
+        (
+            near_key.into_object(),
+            Some(TestValue::random().into_object()),
+        ),
+        (
+            TestKey::random().into_object(),
+            Some(TestValue::random().into_object()),
+        ),
+        (TestKey::random().into_object(), None),
+        (kvs[2].0.into_object(), None),
+        (
+            kvs[2].0.into_object(),
+            Some(TestValue::random().into_object()),
+        ),
+        (
+            kvs[3].0.into_object(),
+            Some(TestValue::random().into_object()),
+        ),
+        (kvs[3].0.into_object(), None),
+    ];
+    let (expected_root, _) = tree.put_batch(Some(root), updates.clone()).unwrap();
+    assert_eq!(compute(root, &updates), expected_root);
+    // Deleting all the leaves leaves an empty tree.
+    updates = kvs
+        .iter()
+        .map(|(key, _)| (key.into_object(), None))
+        .collect();
+    assert_eq!(compute(root, &updates), *SPARSE_MERKLE_PLACEHOLDER_HASH);
+    // Deleting all but one lifts the last leaf to the root.
+    updates.pop();
+    let (expected_root, _) = tree.put_batch(Some(root), updates.clone()).unwrap();
+    assert_eq!(compute(root, &updates), expected_root);
+    assert!(matches!(
+        db.get_node(&expected_root).unwrap(),
+        Node::Leaf(_)
+    ));
+
+    // Nothing is written.
+    assert_eq!(db.num_nodes(), node_count);
+}
+
+#[test]
+fn test_contains_key() {
+    let db = MockTestStore::new_test();
+    let tree = JellyfishMerkleTree::new(&db);
+    let key1 = TestKey::new([0x00u8; HashValue::LENGTH]);
+    let key2 = update_nibble(&key1, 40, 3);
+    let contains = |root, key: TestKey| {
+        contains_key::<_, TestValue, _, Sha3TreeHasher>(&db, root, &key.into_object()).unwrap()
     };
+    assert!(!contains(*SPARSE_MERKLE_PLACEHOLDER_HASH, key1));
 
-    // Insert as one batch.
-    {
-        let db = MockTestStore::new_test();
-        let tree = JellyfishMerkleTree::new(&db);
+    let (root, batch) = tree
+        .put_blob_set(
+            None,
+            vec![
+                (key1.into_object(), TestValue::random().into_object()),
+                (key2.into_object(), TestValue::random().into_object()),
+            ],
+        )
+        .unwrap();
+    db.write_tree_update_batch(batch).unwrap();
+    assert!(contains(root, key1));
+    assert!(contains(root, key2));
+    // Lands on the leaf of key1, which only differs in the last nibble.
+    assert!(!contains(root, update_nibble(&key1, 63, 1)));
+    // Lands on an empty child of the internal node at depth 40.
+    assert!(!contains(root, update_nibble(&key1, 40, 7)));
+}
 
-        let (root, batch) = tree.put_blob_set(None, one_batch).unwrap();
-        db.write_tree_update_batch(batch).unwrap();
-        verify_fn(&tree, root);
+#[test]
+fn test_get_with() {
+    let mut rng: StdRng = StdRng::from_seed([57; 32]);
+    let db = MockTestStore::new_test();
+    let tree = JellyfishMerkleTree::new(&db);
+    let lookup = |root, key: TestKey| {
+        get_with::<_, TestValue, _, Sha3TreeHasher, _, _>(&db, root, &key.into_object(), |value| {
+            value.origin.clone()
+        })
+        .unwrap()
+    };
+    assert_eq!(
+        lookup(*SPARSE_MERKLE_PLACEHOLDER_HASH, TestKey::random()),
+        None
+    );
 
-        // get # of nodes
-        assert_eq!(db.num_nodes(), 12);
-        tree.print_tree(root, key1).unwrap();
+    let kvs = (0..100)
+        .map(|_| {
+            (
+                TestKey::new_with_hash(HashValue::random_with_rng(&mut rng)),
+                TestValue::random(),
+            )
+        })
+        .collect::<BTreeMap<_, _>>();
+    let (root, batch) = tree
+        .put_blob_set(
+            None,
+            kvs.iter()
+                .map(|(k, v)| (k.into_object(), v.clone().into_object()))
+                .collect(),
+        )
+        .unwrap();
+    db.write_tree_update_batch(batch).unwrap();
+    for (key, value) in &kvs {
+        assert_eq!(lookup(root, *key).as_ref(), Some(value));
+        // Lands on the leaf of key, which only differs in the last nibble.
+        let sibling = update_nibble(key, 63, (key.0.nibble(63) + 1) % 16);
+        assert_eq!(lookup(root, sibling), None);
     }
 
-    // Insert in multiple batches.
-    {
-        let db = MockTestStore::new_test();
-        let tree = JellyfishMerkleTree::new(&db);
-        let mut batches2 = vec![];
+    // A missing node is an error, not an absent key.
+    let key = *kvs.keys().next().unwrap();
+    let reader = MockTestStore::new_test();
+    assert!(get_with::<_, TestValue, _, Sha3TreeHasher, _, _>(
+        &reader,
+        root,
+        &key.into_object(),
+        |_| ()
+    )
+    .is_err());
+}
 
-        for (_idx, sub_vec) in batches.iter().enumerate() {
-            for x in sub_vec {
-                batches2.push(vec![(x.0.into(), Some(x.1.clone().into()))]);
+thread_local! {
+    static CLONES: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// A value counting how many times it is cloned.
+#[derive(Debug, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+struct CountedValue(Vec<u8>);
+
+impl Clone for CountedValue {
+    fn clone(&self) -> Self {
+        CLONES.with(|clones| clones.set(clones.get() + 1));
+        Self(self.0.clone())
+    }
+}
+
+#[test]
+fn test_get_with_lends_the_value() {
+    let db = MockTreeStore::<TestKey, CountedValue>::new();
+    let tree = JellyfishMerkleTree::new(&db);
+    let keys = (0..20).map(|_| TestKey::random()).collect::<Vec<_>>();
+    let (root, batch) = tree
+        .put_blob_set(
+            None,
+            keys.iter()
+                .map(|key| (key.into_object(), CountedValue(key.to_vec()).into_object()))
+                .collect(),
+        )
+        .unwrap();
+    db.write_tree_update_batch(batch).unwrap();
+
+    CLONES.with(|clones| clones.set(0));
+    for key in &keys {
+        let len = get_with::<_, _, _, Sha3TreeHasher, _, _>(
+            &db,
+            root,
+            &key.into_object(),
+            |value: &SMTObject<CountedValue>| value.origin.0.len(),
+        )
+        .unwrap();
+        assert_eq!(len, Some(HashValue::LENGTH));
+    }
+    // The store lends its nodes, so no value was cloned.
+    assert_eq!(CLONES.with(|clones| clones.get()), 0);
+
+    // Reading the value through `get_node_option` clones the leaf node holding it.
+    assert!(tree.get(root, keys[0]).unwrap().is_some());
+    assert!(CLONES.with(|clones| clones.get()) > 0);
+}
+
+#[test]
+fn test_smt_error() {
+    let db = MockTestStore::new_test();
+    let tree = JellyfishMerkleTree::new(&db);
+    let key1 = TestKey::new([0x00u8; HashValue::LENGTH]);
+    let key2 = TestKey::new([0xffu8; HashValue::LENGTH]);
+    let (root, batch) = tree
+        .put_blob_set(
+            None,
+            vec![
+                (key1.into_object(), TestValue::random().into_object()),
+                (key2.into_object(), TestValue::random().into_object()),
+            ],
+        )
+        .unwrap();
+    db.write_tree_update_batch(batch).unwrap();
+    let leaf_node_key = match db.get_node(&root).unwrap() {
+        Node::Internal(internal_node) => internal_node.child(Nibble::from(0)).unwrap().hash,
+        _ => unreachable!(),
+    };
+
+    // A missing node is reported with its node key.
+    db.delete_node_batch(&[leaf_node_key]).unwrap();
+    let err = tree.get(root, key1).unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<SmtError>(),
+        Some(&SmtError::NodeNotFound(leaf_node_key))
+    );
+    let err = contains_key::<_, _, _, Sha3TreeHasher>(&db, root, &key1.into_object()).unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<SmtError>(),
+        Some(&SmtError::NodeNotFound(leaf_node_key))
+    );
+    // The error converts into `anyhow::Error` as well as other errors.
+    let err: anyhow::Error = SmtError::NodeNotFound(leaf_node_key).into();
+    assert!(err.to_string().contains("Missing node"), "{}", err);
+
+    // So is a null node below the root.
+    db.put_node(leaf_node_key, Node::new_null()).unwrap();
+    let err = tree.get(root, key1).unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<SmtError>(),
+        Some(&SmtError::UnexpectedNull(leaf_node_key))
+    );
+    let err = get_with::<_, _, _, Sha3TreeHasher, _, _>(&db, root, &key1.into_object(), |_| ())
+        .unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<SmtError>(),
+        Some(&SmtError::UnexpectedNull(leaf_node_key))
+    );
+    // The other leaf is still found.
+    assert!(tree.get(root, key2).unwrap().is_some());
+}
+
+#[test]
+fn test_insert_runs_out_of_nibbles() {
+    // A corrupt internal node whose only child is itself.
+    let db = MockTestStore::new_test();
+    let node_key = HashValue::random();
+    let mut children = Children::new();
+    children.insert(Nibble::from(0), Child::new(node_key, false));
+    db.put_node(node_key, Node::new_internal(children)).unwrap();
+
+    let tree = JellyfishMerkleTree::new(&db);
+    let key = TestKey::new([0x00u8; HashValue::LENGTH]);
+    let err = tree
+        .put_blob_set(
+            Some(node_key),
+            vec![(key.into_object(), TestValue::random().into_object())],
+        )
+        .unwrap_err();
+    assert!(err.to_string().contains("Ran out of nibbles"), "{}", err);
+    let err = tree
+        .put_batch(
+            Some(node_key),
+            vec![(key.into_object(), Some(TestValue::random().into_object()))],
+        )
+        .unwrap_err();
+    assert!(err.to_string().contains("Ran out of nibbles"), "{}", err);
+}
+
+#[test]
+fn test_nodes_children_first() {
+    let db = MockTestStore::new_test();
+    let tree = JellyfishMerkleTree::new(&db);
+    let mut rng = StdRng::from_seed([4; 32]);
+    let keys = (0..200)
+        .map(|_| TestKey::new_with_hash(HashValue::random_with_rng(&mut rng)))
+        .collect::<Vec<_>>();
+    let (root, batch) = tree
+        .put_blob_set(
+            None,
+            keys.iter()
+                .map(|key| (key.into_object(), TestValue::random().into_object()))
+                .collect(),
+        )
+        .unwrap();
+    db.write_tree_update_batch(batch).unwrap();
+    let (_, batch) = tree
+        .put_blob_set(
+            Some(root),
+            keys.iter()
+                .step_by(10)
+                .map(|key| (key.into_object(), TestValue::random().into_object()))
+                .collect(),
+        )
+        .unwrap();
+
+    let node_batch = batch.node_batch;
+    let ordered = nodes_children_first(&node_batch);
+    assert_eq!(ordered.len(), node_batch.len());
+    let positions = ordered
+        .iter()
+        .enumerate()
+        .map(|(i, (node_key, _))| (**node_key, i))
+        .collect::<HashMap<_, _>>();
+    assert_eq!(positions.len(), node_batch.len());
+    for (node_key, node) in &node_batch {
+        if let Node::Internal(internal_node) = node {
+            for child_key in internal_node.all_child() {
+                if let Some(child_position) = positions.get(&child_key) {
+                    assert!(*child_position < positions[node_key]);
+                }
             }
         }
-        let (mut roots, batch) = tree.puts(None, batches2).unwrap();
+    }
+}
+
+/// Returns the key-value pairs of `kvs` as objects, sorted by key hash.
+fn sorted_leaves(
+    kvs: &HashMap<TestKey, TestValue>,
+) -> Vec<(SMTObject<TestKey>, SMTObject<TestValue>)> {
+    let mut leaves: Vec<_> = kvs
+        .iter()
+        .map(|(k, v)| (k.into_object(), v.clone().into_object()))
+        .collect();
+    leaves.sort_by_key(|(k, _)| k.merkle_hash());
+    leaves
+}
+
+#[test]
+fn test_build_from_sorted() {
+    let (root, node_batch) =
+        build_from_sorted::<TestKey, TestValue, Sha3TreeHasher>(vec![]).unwrap();
+    assert_eq!(root, *SPARSE_MERKLE_PLACEHOLDER_HASH);
+    assert!(node_batch.is_empty());
+
+    let key = TestKey::random();
+    let value = TestValue::random();
+    let (root, node_batch) = build_from_sorted::<_, _, Sha3TreeHasher>(vec![(
+        key.into_object(),
+        value.clone().into_object(),
+    )])
+    .unwrap();
+    assert_eq!(root, Node::new_leaf(key, value).merkle_hash());
+    assert_eq!(node_batch.len(), 1);
+
+    let kvs: HashMap<_, _> = (0..100)
+        .map(|_| (TestKey::random(), TestValue::random()))
+        .collect();
+    let leaves = sorted_leaves(&kvs);
+    let (root, node_batch) =
+        build_from_sorted::<_, _, Sha3TreeHasher>(leaves.iter().cloned()).unwrap();
+    match &node_batch[&root] {
+        Node::Internal(internal_node) => assert_eq!(internal_node.leaf_count(), Some(100)),
+        _ => panic!("The root should be an internal node."),
+    }
+
+    // Out of order and duplicated keys.
+    let mut swapped = leaves.clone();
+    swapped.swap(10, 11);
+    let mut duplicated = leaves.clone();
+    duplicated.insert(50, leaves[50].clone());
+    for leaves in [swapped, duplicated] {
+        let err = build_from_sorted::<_, _, Sha3TreeHasher>(leaves).unwrap_err();
+        assert!(err.to_string().contains("not sorted"), "{}", err);
+    }
+}
+
+#[test]
+fn test_from_pairs() {
+    let kvs: HashMap<_, _> = (0..100)
+        .map(|_| (TestKey::random(), TestValue::random()))
+        .collect();
+    let leaves = sorted_leaves(&kvs);
+    let expected = build_from_sorted::<_, _, Sha3TreeHasher>(leaves.iter().cloned()).unwrap();
+
+    // Any order builds the same tree.
+    let mut pairs = leaves.clone();
+    pairs.reverse();
+    assert_eq!(
+        from_pairs::<_, _, Sha3TreeHasher>(pairs.clone()).unwrap(),
+        expected
+    );
+
+    // The last pair of a key wins.
+    let mut duplicated = vec![];
+    for (key, _) in pairs.iter().step_by(3) {
+        duplicated.push((key.clone(), TestValue::random().into_object()));
+    }
+    duplicated.extend(pairs.iter().cloned());
+    for (key, value) in pairs.iter().step_by(5) {
+        duplicated.push((key.clone(), TestValue::random().into_object()));
+        duplicated.push((key.clone(), value.clone()));
+    }
+    assert_eq!(
+        from_pairs::<_, _, Sha3TreeHasher>(duplicated).unwrap(),
+        expected
+    );
+
+    let (root, node_batch) = from_pairs::<TestKey, TestValue, Sha3TreeHasher>(vec![]).unwrap();
+    assert_eq!(root, *SPARSE_MERKLE_PLACEHOLDER_HASH);
+    assert!(node_batch.is_empty());
+}
+
+#[test]
+fn test_delete_range() {
+    let mut rng = StdRng::from_seed([14; 32]);
+    let btree = (0..500)
+        .map(|_| {
+            (
+                HashValue::random_with_rng(&mut rng),
+                TestValue::from(HashValue::random_with_rng(&mut rng).to_vec()),
+            )
+        })
+        .collect::<BTreeMap<_, _>>();
+    let init_db = || {
+        let db = MockTestStore::new_test();
+        let (root, batch) = JellyfishMerkleTree::new(&db)
+            .put_blob_set(
+                None,
+                btree
+                    .iter()
+                    .map(|(k, v)| (TestKey(*k).into(), v.clone().into()))
+                    .collect(),
+            )
+            .unwrap();
         db.write_tree_update_batch(batch).unwrap();
-        let root_hash = roots.pop().unwrap();
-        verify_fn(&tree, root_hash);
+        (db, root)
+    };
+    let keys = btree.keys().cloned().collect::<Vec<_>>();
+
+    let mut nibble_start = [0x00; HashValue::LENGTH];
+    nibble_start[0] = 0x30;
+    let mut nibble_end = [0xff; HashValue::LENGTH];
+    nibble_end[0] = 0x3f;
+    let ranges = vec![
+        (keys[10], keys[20]),
+        (keys[10], keys[10]),
+        (plus_one(keys[30]), minus_one(keys[300])),
+        // No leaf in the range.
+        (plus_one(keys[70]), minus_one(keys[71])),
+        // Exactly the subtree of a nibble.
+        (HashValue::new(nibble_start), HashValue::new(nibble_end)),
+        // All but one leaf.
+        (HashValue::zero(), minus_one(keys[499])),
+        // The whole tree.
+        (HashValue::zero(), HashValue::new([0xff; HashValue::LENGTH])),
+        // The start is after the end.
+        (keys[90], keys[80]),
+    ];
+    for (start, end) in ranges {
+        let (db, root) = init_db();
+        let tree = JellyfishMerkleTree::new(&db);
+        let (new_root, node_batch, stale_node_index_batch) =
+            delete_range::<TestKey, TestValue, _, Sha3TreeHasher>(&db, root, start, end).unwrap();
+        let deleted = btree
+            .keys()
+            .filter(|key| start <= **key && **key <= end)
+            .collect::<Vec<_>>();
+        if deleted.is_empty() {
+            assert_eq!(new_root, root);
+            assert!(node_batch.is_empty());
+            assert!(stale_node_index_batch.is_empty());
+            continue;
+        }
 
-        // get # of nodes
-        assert_eq!(db.num_nodes(), 23 /* 1 + 2 + 3 + 3 + 7 + 5 + 2 */);
-        tree.print_tree(root_hash, key1).unwrap();
+        // The same as deleting the keys in a single update.
+        let (expected_root, batch) = tree
+            .updates(
+                Some(root),
+                deleted
+                    .iter()
+                    .map(|key| (TestKey(**key).into(), None))
+                    .collect::<Vec<_>>(),
+            )
+            .unwrap();
+        assert_eq!(new_root, expected_root);
+        assert_eq!(node_batch, batch.node_batch);
+        assert_eq!(stale_node_index_batch, batch.stale_node_index_batch);
+
+        // The same as deleting the keys one at a time, which creates many more nodes.
+        let (one_by_one, mut one_by_one_root) = init_db();
+        let mut num_new_nodes = 0;
+        for key in &deleted {
+            let (root, batch) = JellyfishMerkleTree::new(&one_by_one)
+                .updates(Some(one_by_one_root), vec![(TestKey(**key).into(), None)])
+                .unwrap();
+            num_new_nodes += batch.node_batch.len();
+            one_by_one.write_tree_update_batch(batch).unwrap();
+            one_by_one_root = root;
+        }
+        assert_eq!(new_root, one_by_one_root);
+        assert!(node_batch.len() <= num_new_nodes);
+
+        db.write_node_batch(&node_batch).unwrap();
+        for (key, value) in &btree {
+            let expected = (*key < start || end < *key).then(|| value.clone());
+            assert_eq!(
+                tree.get(new_root, TestKey(*key))
+                    .unwrap()
+                    .map(|value| value.origin),
+                expected
+            );
+        }
+    }
+}
+
+#[test]
+fn test_tree_writer() {
+    let db = MockTestStore::new_test();
+    let tree = JellyfishMerkleTree::new(&db);
+    let key1 = TestKey::new([0x00u8; HashValue::LENGTH]);
+    let key2 = update_nibble(&key1, 0, 15);
+    let value1 = TestValue::from(vec![1u8]);
+    let value2 = TestValue::from(vec![2u8]);
+
+    let (root1, batch) = tree
+        .put_blob_set(
+            None,
+            vec![
+                (key1.into(), value1.clone().into()),
+                (key2.into(), value1.into()),
+            ],
+        )
+        .unwrap();
+    db.write_node_batch(&batch.node_batch).unwrap();
+    assert_eq!(db.num_nodes(), 3);
+    // Writing an existing node fails.
+    assert!(db.write_node_batch(&batch.node_batch).is_err());
+
+    let (root2, batch) = tree
+        .put_blob_set(Some(root1), vec![(key1.into(), value2.clone().into())])
+        .unwrap();
+    db.write_node_batch(&batch.node_batch).unwrap();
+    assert_eq!(db.num_nodes(), 5);
+
+    // Delete the old root and the old leaf of key1.
+    let stale_node_keys = batch
+        .stale_node_index_batch
+        .iter()
+        .map(|index| index.node_key)
+        .collect::<Vec<_>>();
+    assert_eq!(stale_node_keys.len(), 2);
+    db.delete_node_batch(&stale_node_keys).unwrap();
+    assert_eq!(db.num_nodes(), 3);
+    assert!(db.get_node_option(&root1).unwrap().is_none());
+    assert_eq!(tree.get(root2, key1).unwrap().unwrap().origin, value2);
+    // Deleting a non-existent node fails.
+    assert!(db.delete_node_batch(&stale_node_keys).is_err());
+}
+
+#[test]
+fn test_get_many() {
+    let mut rng: StdRng = StdRng::from_seed([14; 32]);
+    let db = MockTestStore::new_test();
+    let tree = JellyfishMerkleTree::new(&db);
+
+    // Keys sharing long prefixes, so that their paths share subtrees.
+    let base = TestKey::new_with_hash(HashValue::random_with_rng(&mut rng));
+    let mut present = vec![base];
+    for n in [1, 2, 5, 20] {
+        present.push(update_nibble(&base, n, (base.0.nibble(n) + 1) % 16));
+    }
+    present.extend((0..200).map(|_| TestKey::new_with_hash(HashValue::random_with_rng(&mut rng))));
+    let kvs = present
+        .iter()
+        .map(|key| (*key, TestValue::random()))
+        .collect::<BTreeMap<_, _>>();
+    let (root, batch) = tree
+        .put_blob_set(
+            None,
+            kvs.iter()
+                .map(|(k, v)| (k.into_object(), v.clone().into_object()))
+                .collect(),
+        )
+        .unwrap();
+    db.write_tree_update_batch(batch).unwrap();
+
+    // Absent keys interleaved with the present ones, including keys next to present ones, and a
+    // key asked twice.
+    let mut keys = vec![];
+    for (i, key) in present.iter().enumerate().step_by(3) {
+        keys.push(*key);
+        keys.push(update_nibble(
+            key,
+            3 + i % 60,
+            (key.0.nibble(3 + i % 60) + 7) % 16,
+        ));
+        keys.push(TestKey::new_with_hash(HashValue::random_with_rng(&mut rng)));
+    }
+    keys.push(base);
+    let expected = keys
+        .iter()
+        .map(|key| kvs.get(key).cloned())
+        .collect::<Vec<_>>();
+    assert!(expected.iter().any(Option::is_none));
+
+    let reader = CountingTreeReader::new(db);
+    let key_objects = keys.iter().map(|key| key.into_object()).collect::<Vec<_>>();
+    let values = get_many::<_, TestValue, _, Sha3TreeHasher>(&reader, root, &key_objects)
+        .unwrap()
+        .into_iter()
+        .map(|value| value.map(|value| value.origin))
+        .collect::<Vec<_>>();
+    assert_eq!(values, expected);
+    // A read per level of the tree, rather than per key and per level.
+    assert!(reader.reads() <= 22, "{} reads", reader.reads());
+
+    assert!(
+        get_many::<TestKey, TestValue, _, Sha3TreeHasher>(&reader, root, &[])
+            .unwrap()
+            .is_empty()
+    );
+    assert_eq!(
+        get_many::<TestKey, TestValue, _, Sha3TreeHasher>(
+            &reader,
+            *SPARSE_MERKLE_PLACEHOLDER_HASH,
+            &key_objects
+        )
+        .unwrap(),
+        vec![None; key_objects.len()]
+    );
+}
+
+#[test]
+fn test_commit() {
+    let db = MockTestStore::new_test();
+    let tree = JellyfishMerkleTree::new(&db);
+    let key1 = TestKey::new([0x00u8; HashValue::LENGTH]);
+    let key2 = update_nibble(&key1, 0, 15);
+    let value1 = TestValue::from(vec![1u8]);
+    let value2 = TestValue::from(vec![2u8]);
+
+    let (root1, batch) = tree
+        .put_blob_set(
+            None,
+            vec![
+                (key1.into(), value1.clone().into()),
+                (key2.into(), value1.into()),
+            ],
+        )
+        .unwrap();
+    let committed = commit(
+        &db,
+        root1,
+        batch.node_batch.clone(),
+        batch.stale_node_index_batch.clone(),
+    )
+    .unwrap();
+    assert_eq!(committed, root1);
+    assert_eq!(db.num_nodes(), 3);
+    // Writing an existing node fails, and nothing is committed.
+    assert!(commit(&db, root1, batch.node_batch, batch.stale_node_index_batch).is_err());
+
+    let (root2, batch) = tree
+        .put_blob_set(Some(root1), vec![(key1.into(), value2.clone().into())])
+        .unwrap();
+    // The root must be one of the nodes of the batch.
+    assert!(commit(
+        &db,
+        root1,
+        batch.node_batch.clone(),
+        batch.stale_node_index_batch.clone()
+    )
+    .is_err());
+    assert_eq!(db.num_nodes(), 3);
+    let committed = commit(&db, root2, batch.node_batch, batch.stale_node_index_batch).unwrap();
+    assert_eq!(committed, root2);
+    assert_eq!(db.num_nodes(), 5);
+    assert_eq!(tree.get(root2, key1).unwrap().unwrap().origin, value2);
+
+    // The stale node indices were recorded: the old root and the old leaf of key1 are purged.
+    db.purge_stale_nodes(root2).unwrap();
+    assert_eq!(db.num_nodes(), 3);
+    assert!(db.get_node_option(&root1).unwrap().is_none());
+    assert_eq!(tree.get(root2, key1).unwrap().unwrap().origin, value2);
+}
+
+/// A store silently dropping the writes of one node, as a faulty storage backend would.
+struct DroppingStore {
+    inner: MockTestStore,
+    dropped: NodeKey,
+}
+
+impl TreeReader<TestKey, TestValue> for DroppingStore {
+    fn get_node_option(&self, node_key: &NodeKey) -> Result<Option<Node<TestKey, TestValue>>> {
+        self.inner.get_node_option(node_key)
+    }
+}
+
+impl TreeWriter<TestKey, TestValue> for DroppingStore {
+    fn write_node_batch(&self, node_batch: &NodeBatch<TestKey, TestValue>) -> Result<()> {
+        let mut node_batch = node_batch.clone();
+        node_batch.remove(&self.dropped);
+        self.inner.write_node_batch(&node_batch)
+    }
+
+    fn delete_node_batch(&self, node_keys: &[NodeKey]) -> Result<()> {
+        self.inner.delete_node_batch(node_keys)
+    }
+}
+
+#[test]
+fn test_commit_verified() {
+    let db = MockTestStore::new_test();
+    let tree = JellyfishMerkleTree::new(&db);
+    let kvs = (0..100)
+        .map(|_| (TestKey::random().into(), TestValue::random().into()))
+        .collect::<Vec<_>>();
+    let (root1, batch1) = tree.put_blob_set(None, kvs).unwrap();
+    let committed = commit_verified::<_, _, _, Sha3TreeHasher>(
+        &db,
+        root1,
+        batch1.node_batch.clone(),
+        batch1.stale_node_index_batch.clone(),
+    )
+    .unwrap();
+    assert_eq!(committed, root1);
+
+    // An update keeping the root has no node to write, and the root is still checked.
+    let (root, batch) = tree.delete(Some(root1), TestKey::random()).unwrap();
+    assert_eq!(root, root1);
+    assert!(batch.node_batch.is_empty());
+    commit_verified::<_, _, _, Sha3TreeHasher>(
+        &db,
+        root,
+        batch.node_batch,
+        batch.stale_node_index_batch,
+    )
+    .unwrap();
+    let empty_root = commit_verified::<TestKey, TestValue, _, Sha3TreeHasher>(
+        &db,
+        *SPARSE_MERKLE_PLACEHOLDER_HASH,
+        NodeBatch::new(),
+        StaleNodeIndexBatch::new(),
+    )
+    .unwrap();
+    assert_eq!(empty_root, *SPARSE_MERKLE_PLACEHOLDER_HASH);
+
+    // A store dropping any node of the batch, the root or one below it, fails the commit.
+    let (root2, batch) = tree
+        .put_blob_set(
+            Some(root1),
+            vec![(TestKey::random().into(), TestValue::random().into())],
+        )
+        .unwrap();
+    let store_at_root1 = |dropped: &NodeKey| {
+        let store = DroppingStore {
+            inner: MockTestStore::new_test(),
+            dropped: *dropped,
+        };
+        store.inner.write_tree_update_batch(batch1.clone()).unwrap();
+        store
+    };
+    for dropped in batch.node_batch.keys() {
+        // The plain commit does not notice.
+        let store = store_at_root1(dropped);
+        commit(
+            &store,
+            root2,
+            batch.node_batch.clone(),
+            batch.stale_node_index_batch.clone(),
+        )
+        .unwrap();
+
+        let store = store_at_root1(dropped);
+        let err = commit_verified::<_, _, _, Sha3TreeHasher>(
+            &store,
+            root2,
+            batch.node_batch.clone(),
+            batch.stale_node_index_batch.clone(),
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<SmtError>(),
+            Some(SmtError::NodeNotFound(node_key)) if node_key == dropped
+        ));
+    }
+}
+
+#[test]
+fn test_prune() {
+    let db = MockTestStore::new_test();
+    let tree = JellyfishMerkleTree::new(&db);
+    let mut rng = StdRng::from_seed([4; 32]);
+    let keys: Vec<_> = (0..100)
+        .map(|_| TestKey::new_with_hash(HashValue::random_with_rng(&mut rng)))
+        .collect();
+
+    // Each version updates a third of the keys.
+    let mut root = None;
+    let mut versions: Vec<(HashValue, BTreeMap<TestKey, TestValue>)> = vec![];
+    let mut stale_node_indices = vec![];
+    for version in 0..5 {
+        let kvs: Vec<_> = keys
+            .iter()
+            .skip(version % 3)
+            .step_by(if version == 0 { 1 } else { 3 })
+            .map(|key| (*key, TestValue::random()))
+            .collect();
+        let mut expected = versions
+            .last()
+            .map(|(_, expected)| expected.clone())
+            .unwrap_or_default();
+        expected.extend(kvs.iter().cloned());
+        let (new_root, batch) = tree
+            .put_blob_set(
+                root,
+                kvs.into_iter()
+                    .map(|(k, v)| (k.into_object(), v.into_object()))
+                    .collect(),
+            )
+            .unwrap();
+        stale_node_indices.push(batch.stale_node_index_batch.clone());
+        db.write_tree_update_batch(batch).unwrap();
+        versions.push((new_root, expected));
+        root = Some(new_root);
+    }
+
+    // Prune the versions before version 2, whose nodes became stale at versions 1 and 2.
+    let num_nodes = db.num_nodes();
+    let stale_nodes = stale_node_indices[1..=2]
+        .iter()
+        .flatten()
+        .map(|index| index.node_key);
+    let num_pruned = prune(&db, stale_nodes).unwrap();
+    assert!(num_pruned > 0);
+    assert_eq!(db.num_nodes(), num_nodes - num_pruned);
+
+    assert!(db.get_node_option(&versions[0].0).unwrap().is_none());
+    assert!(db.get_node_option(&versions[1].0).unwrap().is_none());
+    for (root, expected) in &versions[2..] {
+        for (key, value) in expected {
+            let (result, proof) = tree.get_with_proof(*root, *key).unwrap();
+            assert_eq!(result.unwrap().origin, *value);
+            assert!(proof.verify(*root, *key, Some(value.clone())).is_ok());
+        }
+    }
+    // The pruned nodes are gone, so pruning them again fails.
+    assert!(prune(
+        &db,
+        stale_node_indices[2].iter().map(|index| index.node_key)
+    )
+    .is_err());
+}
+
+#[test]
+fn test_insert_at_leaf_with_internal_created() {
+    let db = MockTestStore::new_test();
+    let tree = JellyfishMerkleTree::new(&db);
+
+    let key1 = TestKey::new([0x00u8; HashValue::LENGTH]);
+    let value1 = TestValue::from(vec![1u8, 2u8]);
+
+    let (_root0_hash, batch) = tree
+        .put_blob_set(None, vec![(key1.into(), value1.clone().into())])
+        .unwrap();
+
+    assert!(batch.stale_node_index_batch.is_empty());
+    db.write_tree_update_batch(batch).unwrap();
+    assert_eq!(tree.get(_root0_hash, key1).unwrap().unwrap().origin, value1);
+    assert_eq!(db.num_nodes(), 1);
+    // Insert at the previous leaf node. Should generate an internal node at the root.
+    // Change the 1st nibble to 15.
+    let key2 = update_nibble(&key1, 0, 15);
+    let value2 = TestValue::from(vec![3u8, 4u8]);
+
+    let (_root1_hash, batch) = tree
+        .put_blob_set(
+            Some(_root0_hash),
+            vec![(key2.into(), value2.clone().into())],
+        )
+        .unwrap();
+    assert_eq!(batch.stale_node_index_batch.len(), 0);
+    db.write_tree_update_batch(batch).unwrap();
+
+    assert_eq!(tree.get(_root1_hash, key1).unwrap().unwrap().origin, value1);
+    assert!(tree.get(_root0_hash, key2).unwrap().is_none());
+    assert_eq!(tree.get(_root1_hash, key2).unwrap().unwrap().origin, value2);
+
+    // get # of nodes
+    assert_eq!(db.num_nodes(), 3);
+
+    let leaf1 = Node::new_leaf(key1, value1);
+    let leaf2 = Node::new_leaf(key2, value2);
+    let mut children = HashMap::new();
+    children.insert(
+        Nibble::from(0),
+        Child::new(leaf1.merkle_hash(), true /* is_leaf */),
+    );
+    children.insert(
+        Nibble::from(15),
+        Child::new(leaf2.merkle_hash(), true /* is_leaf */),
+    );
+    let internal = Node::new_internal(children);
+    assert_eq!(db.get_node(&leaf1.merkle_hash()).unwrap(), leaf1);
+    assert_eq!(db.get_node(&leaf2.merkle_hash()).unwrap(), leaf2);
+    assert_eq!(db.get_node(&internal.merkle_hash()).unwrap(), internal);
+}
+
+#[test]
+fn test_insert_at_leaf_with_multiple_internals_created() {
+    let db = MockTestStore::new_test();
+    let tree = JellyfishMerkleTree::new(&db);
+
+    // 1. Insert the first leaf into empty tree
+    let key1 = TestKey::new([0x00u8; HashValue::LENGTH]);
+    let value1 = TestValue::from(vec![1u8, 2u8]);
+
+    let (_root0_hash, batch) = tree
+        .put_blob_set(None, vec![(key1.into(), value1.clone().into())])
+        .unwrap();
+    db.write_tree_update_batch(batch).unwrap();
+    assert_eq!(tree.get(_root0_hash, key1).unwrap().unwrap().origin, value1);
+
+    // 2. Insert at the previous leaf node. Should generate a branch node at root.
+    // Change the 2nd nibble to 1.
+    let key2 = update_nibble(&key1, 1 /* nibble_index */, 1 /* nibble */);
+    let value2 = TestValue::from(vec![3u8, 4u8]);
+
+    let (_root1_hash, batch) = tree
+        .put_blob_set(
+            Some(_root0_hash),
+            vec![(key2.into(), value2.clone().into())],
+        )
+        .unwrap();
+    db.write_tree_update_batch(batch).unwrap();
+    assert_eq!(tree.get(_root0_hash, key1).unwrap().unwrap().origin, value1);
+    assert!(tree.get(_root0_hash, key2).unwrap().is_none());
+    assert_eq!(tree.get(_root1_hash, key2).unwrap().unwrap().origin, value2);
+    assert_eq!(tree.get(_root1_hash, key1).unwrap().unwrap().origin, value1);
+
+    assert_eq!(db.num_nodes(), 4);
+    tree.print_tree(_root1_hash, key1).unwrap();
+
+    let leaf1: Node<TestKey, TestValue> = Node::new_leaf(key1, value1);
+    let leaf2: Node<TestKey, TestValue> = Node::new_leaf(key2, value2.clone());
+    let internal = {
+        let mut children = HashMap::new();
+        children.insert(
+            Nibble::from(0),
+            Child::new(leaf1.merkle_hash(), true /* is_leaf */),
+        );
+        children.insert(Nibble::from(1), Child::new(leaf2.merkle_hash(), true));
+        Node::new_internal(children)
+    };
+
+    let root_internal = {
+        let mut children = HashMap::new();
+        children.insert(
+            Nibble::from(0),
+            Child::new(internal.merkle_hash(), false /* is_leaf */),
+        );
+        Node::new_internal(children)
+    };
+
+    assert_eq!(db.get_node(&internal.merkle_hash()).unwrap(), internal);
+    assert_eq!(
+        db.get_node(&root_internal.merkle_hash()).unwrap(),
+        root_internal,
+    );
+
+    // 3. Update leaf2 with new value
+    let value2_update = TestValue::from(vec![5u8, 6u8]);
+    let (_root2_hash, batch) = tree
+        .put_blob_set(
+            Some(_root1_hash),
+            vec![(key2.into(), value2_update.clone().into())],
+        )
+        .unwrap();
+    db.write_tree_update_batch(batch).unwrap();
+    assert!(tree.get(_root0_hash, key2,).unwrap().is_none());
+    assert_eq!(
+        tree.get(_root1_hash, key2,).unwrap().unwrap().origin,
+        value2
+    );
+    assert_eq!(
+        tree.get(_root2_hash, key2,).unwrap().unwrap().origin,
+        value2_update
+    );
+
+    tree.print_tree(_root2_hash, key1).unwrap();
+    // Get # of nodes.
+    assert_eq!(db.num_nodes(), 7);
+
+    // Purge retired nodes.
+    db.purge_stale_nodes(_root0_hash).unwrap();
+    db.purge_stale_nodes(_root1_hash).unwrap();
+    assert_eq!(db.num_nodes(), 7);
+    db.purge_stale_nodes(_root2_hash).unwrap();
+    tree.print_tree(_root2_hash, key1).unwrap();
+    assert_eq!(db.num_nodes(), 4);
+}
+
+#[test]
+fn test_batch_insertion() {
+    // ```text
+    //                             internal(root)
+    //                            /        \
+    //                       internal       2        <- nibble 0
+    //                      /   |   \
+    //              internal    3    4               <- nibble 1
+    //                 |
+    //              internal                         <- nibble 2
+    //              /      \
+    //        internal      6                        <- nibble 3
+    //           |
+    //        internal                               <- nibble 4
+    //        /      \
+    //       1        5                              <- nibble 5
+    //
+    // Total: 12 nodes
+    // ```
+    let key1 = TestKey::new([0x00u8; HashValue::LENGTH]);
+    let value1 = TestValue::from(vec![1u8]);
+
+    let key2 = update_nibble(&key1, 0, 2);
+    let value2 = TestValue::from(vec![2u8]);
+    let value2_update = TestValue::from(vec![22u8]);
+
+    let key3 = update_nibble(&key1, 1, 3);
+    let value3 = TestValue::from(vec![3u8]);
+
+    let key4 = update_nibble(&key1, 1, 4);
+    let value4 = TestValue::from(vec![4u8]);
+
+    let key5 = update_nibble(&key1, 5, 5);
+    let value5 = TestValue::from(vec![5u8]);
+
+    let key6 = update_nibble(&key1, 3, 6);
+    let value6 = TestValue::from(vec![6u8]);
+
+    let batches: Vec<Vec<(TestKey, TestValue)>> = vec![
+        vec![(key1.into(), value1)],
+        vec![(key2.into(), value2)],
+        vec![(key3.into(), value3)],
+        vec![(key4.into(), value4)],
+        vec![(key5.into(), value5)],
+        vec![(key6.into(), value6)],
+        vec![(key2.into(), value2_update)],
+    ];
+    let one_batch: Vec<(SMTObject<TestKey>, SMTObject<TestValue>)> = batches
+        .iter()
+        .flatten()
+        .cloned()
+        .map(|(k, v)| (k.into(), v.into()))
+        .collect::<Vec<_>>();
+
+    let mut to_verify = one_batch.clone();
+    // key2 was updated so we remove it.
+    to_verify.remove(1);
+    let verify_fn = |tree: &JellyfishMerkleTree<TestKey, TestValue, MockTestStore>,
+                     root: HashValue| {
+        to_verify
+            .iter()
+            .for_each(|(k, v)| assert_eq!(tree.get(root, k.clone()).unwrap().unwrap(), *v))
+    };
+
+    // Insert as one batch.
+    {
+        let db = MockTestStore::new_test();
+        let tree = JellyfishMerkleTree::new(&db);
+
+        let (root, batch) = tree.put_blob_set(None, one_batch).unwrap();
+        db.write_tree_update_batch(batch).unwrap();
+        verify_fn(&tree, root);
+
+        // get # of nodes
+        assert_eq!(db.num_nodes(), 12);
+        tree.print_tree(root, key1).unwrap();
+    }
+
+    // Insert in multiple batches.
+    {
+        let db = MockTestStore::new_test();
+        let tree = JellyfishMerkleTree::new(&db);
+        let mut batches2 = vec![];
+
+        for (_idx, sub_vec) in batches.iter().enumerate() {
+            for x in sub_vec {
+                batches2.push(vec![(x.0.into(), Some(x.1.clone().into()))]);
+            }
+        }
+        let (mut roots, batch) = tree.puts(None, batches2).unwrap();
+        db.write_tree_update_batch(batch).unwrap();
+        let root_hash = roots.pop().unwrap();
+        verify_fn(&tree, root_hash);
+
+        // get # of nodes
+        assert_eq!(db.num_nodes(), 23 /* 1 + 2 + 3 + 3 + 7 + 5 + 2 */);
+        tree.print_tree(root_hash, key1).unwrap();
+
+        // Purge retired nodes('p' means purged and 'a' means added).
+        // The initial state of the tree at version 0
+        // ```test
+        //   1(root)
+        // ```
+        db.purge_stale_nodes(key1.into_object().merkle_hash())
+            .unwrap();
+        // ```text
+        //   1 (p)           internal(a)
+        //           ->     /        \
+        //                 1(a)       2(a)
+        // add 3, prune 1
+        // ```
+        assert_eq!(db.num_nodes(), 23);
+        db.purge_stale_nodes(key2.into_object().merkle_hash())
+            .unwrap();
+        // ```text
+        //     internal(p)             internal(a)
+        //    /        \              /        \
+        //   1(p)       2   ->   internal(a)    2
+        //                       /       \
+        //                      1(a)      3(a)
+        // add 4, prune 2
+        // ```
+        assert_eq!(db.num_nodes(), 23);
+        db.purge_stale_nodes(key3.into_object().merkle_hash())
+            .unwrap();
+        // ```text
+        //         internal(p)                internal(a)
+        //        /        \                 /        \
+        //   internal(p)    2   ->     internal(a)     2
+        //   /       \                /   |   \
+        //  1         3              1    3    4(a)
+        // add 3, prune 2
+        // ```
+        assert_eq!(db.num_nodes(), 23);
+        db.purge_stale_nodes(key4.into_object().merkle_hash())
+            .unwrap();
+        // ```text
+        //            internal(p)                         internal(a)
+        //           /        \                          /        \
+        //     internal(p)     2                    internal(a)    2
+        //    /   |   \                            /   |   \
+        //   1(p) 3    4           ->      internal(a) 3    4
+        //                                     |
+        //                                 internal(a)
+        //                                     |
+        //                                 internal(a)
+        //                                     |
+        //                                 internal(a)
+        //                                 /      \
+        //                                1(a)     5(a)
+        // add 8, prune 3
+        // ```
+        assert_eq!(db.num_nodes(), 23);
+        db.purge_stale_nodes(key5.into_object().merkle_hash())
+            .unwrap();
+        // ```text
+        //                  internal(p)                             internal(a)
+        //                 /        \                              /        \
+        //            internal(p)    2                        internal(a)    2
+        //           /   |   \                               /   |   \
+        //   internal(p) 3    4                      internal(a) 3    4
+        //       |                                      |
+        //   internal(p)                 ->          internal(a)
+        //       |                                   /      \
+        //   internal                          internal      6(a)
+        //       |                                |
+        //   internal                          internal
+        //   /      \                          /      \
+        //  1        5                        1        5
+        // add 5, prune 4
+        // ```
+        assert_eq!(db.num_nodes(), 23);
+        db.purge_stale_nodes(key6.into_object().merkle_hash())
+            .unwrap();
+        // ```text
+        //                         internal(p)                               internal(a)
+        //                        /        \                                /        \
+        //                   internal       2(p)                       internal       2(a)
+        //                  /   |   \                                 /   |   \
+        //          internal    3    4                        internal    3    4
+        //             |                                         |
+        //          internal                      ->          internal
+        //          /      \                                  /      \
+        //    internal      6                           internal      6
+        //       |                                         |
+        //    internal                                  internal
+        //    /      \                                  /      \
+        //   1        5                                1        5
+        // add 2, prune 2
+        // ```
+        assert_eq!(db.num_nodes(), 23);
+        verify_fn(&tree, root_hash);
+    }
+}
+
+#[test]
+fn test_non_existence() {
+    let db = MockTestStore::new_test();
+    let tree = JellyfishMerkleTree::new(&db);
+    // ```text
+    //                     internal(root)
+    //                    /        \
+    //                internal      2
+    //                   |
+    //                internal
+    //                /      \
+    //               1        3
+    // Total: 7 nodes
+    // ```
+    let key1 = TestKey::new([0x00u8; HashValue::LENGTH]);
+    let value1 = TestValue::from(vec![1u8]);
+
+    let key2 = update_nibble(&key1, 0, 15);
+    let value2 = TestValue::from(vec![2u8]);
+
+    let key3 = update_nibble(&key1, 2, 3);
+    let value3 = TestValue::from(vec![3u8]);
+
+    let (root, batch) = tree
+        .put_blob_set(
+            None,
+            vec![
+                (key1.into(), value1.clone().into()),
+                (key2.into(), value2.clone().into()),
+                (key3.into(), value3.clone().into()),
+            ],
+        )
+        .unwrap();
+    db.write_tree_update_batch(batch).unwrap();
+    assert_eq!(tree.get(root, key1).unwrap().unwrap().origin, value1);
+    assert_eq!(tree.get(root, key2).unwrap().unwrap().origin, value2);
+    assert_eq!(tree.get(root, key3).unwrap().unwrap().origin, value3);
+    // get # of nodes
+    assert_eq!(db.num_nodes(), 6);
+
+    // test non-existing nodes.
+    // 1. Non-existing node at root node
+    {
+        let non_existing_key = update_nibble(&key1, 0, 1);
+        let (value, proof) = tree.get_with_proof(root, non_existing_key).unwrap();
+        assert_eq!(value, None);
+        assert!(proof
+            .verify::<TestKey, TestValue>(root, non_existing_key, None)
+            .is_ok());
+    }
+    // 2. Non-existing node at non-root internal node
+    {
+        let non_existing_key = update_nibble(&key1, 1, 15);
+        let (value, proof) = tree.get_with_proof(root, non_existing_key).unwrap();
+        assert_eq!(value, None);
+        assert!(proof
+            .verify::<TestKey, TestValue>(root, non_existing_key, None)
+            .is_ok());
+    }
+    // 3. Non-existing node at leaf node
+    {
+        let non_existing_key = update_nibble(&key1, 2, 4);
+        let (value, proof) = tree.get_with_proof(root, non_existing_key).unwrap();
+        assert_eq!(value, None);
+        assert!(proof
+            .verify::<TestKey, TestValue>(root, non_existing_key, None)
+            .is_ok());
+    }
+}
+
+#[test]
+fn test_get_with_proof_empty_and_single_leaf_tree() {
+    let db = MockTestStore::new_test();
+    let tree = JellyfishMerkleTree::new(&db);
+    let key = TestKey::new([0x00u8; HashValue::LENGTH]);
+    let value = TestValue::from(vec![1u8]);
+
+    // An empty tree proves non-inclusion with an empty subtree at the root.
+    let (root, batch) = tree.updates(None, vec![(key.into(), None)]).unwrap();
+    db.write_tree_update_batch(batch).unwrap();
+    assert_eq!(root, *SPARSE_MERKLE_PLACEHOLDER_HASH);
+    let (value_in_tree, proof) = tree.get_with_proof(root, key).unwrap();
+    assert_eq!(value_in_tree, None);
+    assert_eq!(proof, SparseMerkleProof::new(None, vec![]));
+    assert!(proof.verify::<TestKey, TestValue>(root, key, None).is_ok());
+
+    // A tree with a single leaf proves non-inclusion with that different leaf at the root.
+    let (root, batch) = tree
+        .put_blob_set(Some(root), vec![(key.into(), value.clone().into())])
+        .unwrap();
+    db.write_tree_update_batch(batch).unwrap();
+    let (value_in_tree, proof) = tree.get_with_proof(root, key).unwrap();
+    assert_eq!(value_in_tree.unwrap().origin, value);
+    assert!(proof.siblings().is_empty());
+    assert!(proof.verify(root, key, Some(value.clone())).is_ok());
+
+    let non_existing_key = update_nibble(&key, 0, 1);
+    let (value_in_tree, proof) = tree.get_with_proof(root, non_existing_key).unwrap();
+    assert_eq!(value_in_tree, None);
+    assert_eq!(proof.leaf().unwrap().0, key.0);
+    assert!(proof
+        .verify::<TestKey, TestValue>(root, non_existing_key, None)
+        .is_ok());
+}
+
+#[test]
+fn test_verify_rejects_invalid_proofs() {
+    let db = MockTestStore::new_test();
+    let tree = JellyfishMerkleTree::new(&db);
+    let key1 = TestKey::new([0x00u8; HashValue::LENGTH]);
+    let value1 = TestValue::from(vec![1u8]);
+    let key2 = update_nibble(&key1, 2, 3);
+    let value2 = TestValue::from(vec![2u8]);
+    let key3 = update_nibble(&key1, 0, 15);
+    let value3 = TestValue::from(vec![3u8]);
+
+    let (root, batch) = tree
+        .put_blob_set(
+            None,
+            vec![
+                (key1.into(), value1.clone().into()),
+                (key2.into(), value2.clone().into()),
+                (key3.into(), value3.into()),
+            ],
+        )
+        .unwrap();
+    db.write_tree_update_batch(batch).unwrap();
+
+    let (value_in_tree, proof) = tree.get_with_proof(root, key1).unwrap();
+    let value_in_tree = value_in_tree.unwrap();
+    assert!(proof.verify(root, key1, Some(value1.clone())).is_ok());
+    assert!(proof
+        .verify_object(root, &key1.into_object(), Some(&value_in_tree))
+        .is_ok());
+
+    // Wrong value.
+    assert!(proof.verify(root, key1, Some(value2.clone())).is_err());
+    // Wrong root.
+    assert!(proof
+        .verify(HashValue::random(), key1, Some(value1.clone()))
+        .is_err());
+    // An inclusion proof is not a non-inclusion proof.
+    assert!(proof
+        .verify::<TestKey, TestValue>(root, key1, None)
+        .is_err());
+    // Tampered sibling.
+    let mut tampered = proof.clone();
+    tampered.siblings[0] = HashValue::random();
+    assert!(tampered.verify(root, key1, Some(value1.clone())).is_err());
+    // The key hash claimed in the proof does not match the key.
+    assert!(proof.verify(root, key2, Some(value1.clone())).is_err());
+    let mut tampered = proof.clone();
+    tampered.leaf = Some((key2.0, value1.clone().into_object().merkle_hash()));
+    assert!(tampered.verify(root, key2, Some(value1)).is_err());
+
+    // A non-inclusion proof is not an inclusion proof.
+    let non_existing_key = update_nibble(&key1, 0, 1);
+    let (value_in_tree, proof) = tree.get_with_proof(root, non_existing_key).unwrap();
+    assert_eq!(value_in_tree, None);
+    assert!(proof
+        .verify::<TestKey, TestValue>(root, non_existing_key, None)
+        .is_ok());
+    assert!(proof.verify(root, non_existing_key, Some(value2)).is_err());
+
+    // The leaf of a non-inclusion proof must be on the path of the key.
+    let non_existing_key = update_nibble(&key2, 3, 4);
+    let (_, proof) = tree.get_with_proof(root, non_existing_key).unwrap();
+    assert_eq!(proof.leaf().unwrap().0, key2.0);
+    assert!(proof
+        .verify::<TestKey, TestValue>(root, non_existing_key, None)
+        .is_ok());
+    assert!(proof
+        .verify::<TestKey, TestValue>(root, update_nibble(&key1, 1, 1), None)
+        .is_err());
+}
+
+#[test]
+fn test_proof_compact_encoding() {
+    let mut rng: StdRng = StdRng::from_seed([5; 32]);
+    let db = MockTestStore::new_test();
+    let tree = JellyfishMerkleTree::new(&db);
+    let kvs = (0..100)
+        .map(|_| {
+            (
+                TestKey::new_with_hash(HashValue::random_with_rng(&mut rng)),
+                TestValue::from(vec![rng.gen::<u8>()]),
+            )
+        })
+        .collect::<Vec<_>>();
+    let (root, batch) = tree
+        .put_blob_set(
+            None,
+            kvs.iter()
+                .map(|(key, value)| ((*key).into(), value.clone().into()))
+                .collect(),
+        )
+        .unwrap();
+    db.write_tree_update_batch(batch).unwrap();
+
+    for (key, value) in &kvs {
+        let (_, proof) = tree.get_with_proof(root, *key).unwrap();
+        let bytes = proof.encode_compact();
+        assert!(bytes.len() <= bcs::to_bytes(&proof).unwrap().len());
+        let decoded = SparseMerkleProof::<Sha3TreeHasher>::decode_compact(&bytes).unwrap();
+        assert_eq!(decoded, proof);
+        assert!(decoded.verify(root, *key, Some(value.clone())).is_ok());
+
+        // Truncated or extended bytes are rejected.
+        assert!(
+            SparseMerkleProof::<Sha3TreeHasher>::decode_compact(&bytes[..bytes.len() - 1]).is_err()
+        );
+        let mut extended = bytes.clone();
+        extended.push(0);
+        assert!(SparseMerkleProof::<Sha3TreeHasher>::decode_compact(&extended).is_err());
+    }
+
+    // Non-inclusion proofs, with and without a leaf.
+    for _ in 0..100 {
+        let key = TestKey::new_with_hash(HashValue::random_with_rng(&mut rng));
+        let (_, proof) = tree.get_with_proof(root, key).unwrap();
+        let decoded =
+            SparseMerkleProof::<Sha3TreeHasher>::decode_compact(&proof.encode_compact()).unwrap();
+        assert_eq!(decoded, proof);
+        assert!(decoded
+            .verify::<TestKey, TestValue>(root, key, None)
+            .is_ok());
+    }
+
+    // Two keys sharing a long prefix are proven with siblings which are mostly placeholders.
+    let key1 = kvs[0].0;
+    let key2 = update_nibble(&key1, 30, key1.0.nibble(30) ^ 1);
+    let (root, batch) = tree
+        .put_blob_set(Some(root), vec![(key2.into(), kvs[0].1.clone().into())])
+        .unwrap();
+    db.write_tree_update_batch(batch).unwrap();
+    let (_, proof) = tree.get_with_proof(root, key2).unwrap();
+    let bytes = proof.encode_compact();
+    assert!(bytes.len() * 2 < bcs::to_bytes(&proof).unwrap().len());
+    let decoded = SparseMerkleProof::<Sha3TreeHasher>::decode_compact(&bytes).unwrap();
+    assert!(decoded.verify(root, key2, Some(kvs[0].1.clone())).is_ok());
+
+    let empty = SparseMerkleProof::<Sha3TreeHasher>::new(None, vec![]);
+    assert_eq!(empty.encode_compact(), vec![0, 0, 0]);
+    assert_eq!(
+        SparseMerkleProof::<Sha3TreeHasher>::decode_compact(&empty.encode_compact()).unwrap(),
+        empty
+    );
+
+    // Unknown flags and bits past the last sibling are rejected.
+    assert!(SparseMerkleProof::<Sha3TreeHasher>::decode_compact(&[0x02, 0, 0]).is_err());
+    assert!(SparseMerkleProof::<Sha3TreeHasher>::decode_compact(&[0, 1, 0, 0x02]).is_err());
+    assert!(SparseMerkleProof::<Sha3TreeHasher>::decode_compact(&[]).is_err());
+}
+
+#[test]
+fn test_proof_path_hashes() {
+    let mut rng: StdRng = StdRng::from_seed([6; 32]);
+    let db = MockTestStore::new_test();
+    let tree = JellyfishMerkleTree::new(&db);
+    let kvs = (0..500)
+        .map(|i| {
+            (
+                TestKey::new_with_hash(HashValue::random_with_rng(&mut rng)),
+                TestValue::from(vec![i as u8]),
+            )
+        })
+        .collect::<Vec<_>>();
+    let (root, batch) = tree
+        .put_blob_set(
+            None,
+            kvs.iter()
+                .map(|(key, value)| ((*key).into(), value.clone().into()))
+                .collect(),
+        )
+        .unwrap();
+    db.write_tree_update_batch(batch).unwrap();
+
+    let keys = kvs
+        .iter()
+        .map(|(key, _)| *key)
+        .chain((0..100).map(|_| TestKey::new_with_hash(HashValue::random_with_rng(&mut rng))));
+    for key in keys {
+        let (_, proof) = tree.get_with_proof(root, key).unwrap();
+        let path_hashes = proof.path_hashes(key.0);
+        assert_eq!(path_hashes.len(), proof.siblings().len() + 1);
+        assert_eq!(*path_hashes.last().unwrap(), root);
+
+        // The binary path goes through every internal node on the nibble path of the key.
+        let mut node_key = root;
+        let mut nibble_depth = 0;
+        while let Node::Internal(internal_node) = db.get_node(&node_key).unwrap() {
+            assert_eq!(
+                path_hashes[proof.siblings().len() - 4 * nibble_depth],
+                node_key
+            );
+            match internal_node.child(Nibble::from(key.0.nibble(nibble_depth))) {
+                Some(child) => node_key = child.hash,
+                None => break,
+            }
+            nibble_depth += 1;
+        }
+    }
+}
+
+#[test]
+fn test_get_with_proof_outcome() {
+    let db = MockTestStore::new_test();
+    let tree = JellyfishMerkleTree::new(&db);
+    // The root has children at nibbles 0 and 1 only, both leaves.
+    let key1 = TestKey::new([0x00u8; HashValue::LENGTH]);
+    let key2 = update_nibble(&key1, 0, 1);
+    let (value1, value2) = (TestValue::random(), TestValue::random());
+    let (root, batch) = tree
+        .put_blob_set(
+            None,
+            vec![
+                (key1.into_object(), value1.clone().into_object()),
+                (key2.into_object(), value2.into_object()),
+            ],
+        )
+        .unwrap();
+    db.write_tree_update_batch(batch).unwrap();
+
+    let present = tree.get_with_proof_outcome(root, key1).unwrap();
+    assert!(matches!(&present, ProofOutcome::Present(value, _) if *value == value1.into_object()));
+    present.verify(root, &key1.into_object()).unwrap();
+
+    // Nothing is at nibble 2 of the root.
+    let empty_key = update_nibble(&key1, 0, 2);
+    let absent_empty = tree.get_with_proof_outcome(root, empty_key).unwrap();
+    assert!(matches!(absent_empty, ProofOutcome::AbsentEmpty(_)));
+    absent_empty.verify(root, &empty_key.into_object()).unwrap();
+
+    // The leaf of `key1` is where a key sharing its first nibble would be.
+    let other_leaf_key = update_nibble(&key1, 5, 3);
+    let absent_other_leaf = tree.get_with_proof_outcome(root, other_leaf_key).unwrap();
+    match &absent_other_leaf {
+        ProofOutcome::AbsentOtherLeaf((key_hash, _), _) => assert_eq!(*key_hash, key1.0),
+        outcome => panic!("Unexpected outcome {:?}", outcome),
+    }
+    absent_other_leaf
+        .verify(root, &other_leaf_key.into_object())
+        .unwrap();
+
+    // A proof does not verify as an outcome of another kind.
+    let equivocated: ProofOutcome<TestValue> =
+        ProofOutcome::AbsentEmpty(absent_other_leaf.proof().clone());
+    assert!(equivocated
+        .verify(root, &other_leaf_key.into_object())
+        .is_err());
+    let equivocated: ProofOutcome<TestValue> = ProofOutcome::AbsentOtherLeaf(
+        (key2.0, HashValue::random()),
+        absent_other_leaf.proof().clone(),
+    );
+    assert!(equivocated
+        .verify(root, &other_leaf_key.into_object())
+        .is_err());
+    let equivocated: ProofOutcome<TestValue> = ProofOutcome::Present(
+        TestValue::random().into_object(),
+        absent_empty.proof().clone(),
+    );
+    assert!(equivocated.verify(root, &empty_key.into_object()).is_err());
+}
+
+#[test]
+fn test_get_siblings() {
+    let mut rng: StdRng = StdRng::from_seed([7; 32]);
+    let db = MockTestStore::new_test();
+    let tree = JellyfishMerkleTree::new(&db);
+    let kvs = (0..300)
+        .map(|i| {
+            (
+                TestKey::new_with_hash(HashValue::random_with_rng(&mut rng)),
+                TestValue::from(vec![i as u8]),
+            )
+        })
+        .collect::<Vec<_>>();
+    let (root, batch) = tree
+        .put_blob_set(
+            None,
+            kvs.iter()
+                .map(|(key, value)| ((*key).into(), value.clone().into()))
+                .collect(),
+        )
+        .unwrap();
+    db.write_tree_update_batch(batch).unwrap();
+
+    let keys = kvs
+        .iter()
+        .map(|(key, _)| *key)
+        .chain((0..100).map(|_| TestKey::new_with_hash(HashValue::random_with_rng(&mut rng))));
+    for key in keys {
+        let (leaf_node, siblings) =
+            get_siblings::<TestKey, TestValue, _, Sha3TreeHasher>(&db, root, &key.into_object())
+                .unwrap();
+        match &leaf_node {
+            Some(leaf_node) if kvs.iter().any(|(k, _)| *k == key) => {
+                assert_eq!(leaf_node.key_hash(), key.0)
+            }
+            Some(leaf_node) => assert_ne!(leaf_node.key_hash(), key.0),
+            None => assert!(kvs.iter().all(|(k, _)| *k != key)),
+        }
+
+        // The siblings are root first, the one at index `i` being the sibling at bit depth `i`.
+        let leaf_hash = leaf_node
+            .as_ref()
+            .map_or(*SPARSE_MERKLE_PLACEHOLDER_HASH, |leaf_node| {
+                leaf_node.merkle_hash()
+            });
+        let computed_root =
+            siblings
+                .iter()
+                .enumerate()
+                .rev()
+                .fold(leaf_hash, |hash, (depth, sibling)| {
+                    if key.0.bit(depth) {
+                        SparseMerkleInternalNode::new(*sibling, hash).merkle_hash()
+                    } else {
+                        SparseMerkleInternalNode::new(hash, *sibling).merkle_hash()
+                    }
+                });
+        assert_eq!(computed_root, root);
+
+        // They are the siblings of the proof, in reverse.
+        let (_, proof) = tree.get_with_proof(root, key).unwrap();
+        assert!(siblings.iter().rev().eq(proof.siblings()));
+    }
+
+    // An empty tree has no sibling.
+    let (leaf_node, siblings) = get_siblings::<TestKey, TestValue, _, Sha3TreeHasher>(
+        &db,
+        *SPARSE_MERKLE_PLACEHOLDER_HASH,
+        &TestKey::random().into_object(),
+    )
+    .unwrap();
+    assert!(leaf_node.is_none());
+    assert!(siblings.is_empty());
+}
+
+/// A store enumerating the leaves of a tree in reverse order, standing for one overriding
+/// `enumerate_leaves` in an order of its own.
+struct ReverseLeavesStore(MockTestStore);
+
+impl TreeReader<TestKey, TestValue> for ReverseLeavesStore {
+    fn get_node_option(&self, node_key: &NodeKey) -> Result<Option<Node<TestKey, TestValue>>> {
+        self.0.get_node_option(node_key)
+    }
+}
+
+impl Versioned<TestKey, TestValue> for ReverseLeavesStore {}
+
+impl LeafEnumerable<TestKey, TestValue> for ReverseLeavesStore {
+    fn enumerate_leaves<'a, H: TreeHasher>(
+        &'a self,
+        root: HashValue,
+    ) -> Result<impl Iterator<Item = Result<(SMTObject<TestKey>, SMTObject<TestValue>)>> + 'a>
+    where
+        TestKey: 'a,
+        TestValue: 'a,
+    {
+        let mut leaves = self.0.enumerate_leaves::<H>(root)?.collect::<Vec<_>>();
+        leaves.reverse();
+        Ok(leaves.into_iter())
+    }
+}
+
+fn collect_leaves<S: LeafEnumerable<TestKey, TestValue>>(
+    store: &S,
+    root: HashValue,
+) -> BTreeMap<TestKey, TestValue> {
+    store
+        .enumerate_leaves::<Sha3TreeHasher>(root)
+        .unwrap()
+        .map(|pair| {
+            let (key, value) = pair.unwrap();
+            (key.origin, value.origin)
+        })
+        .collect()
+}
+
+#[test]
+fn test_enumerate_leaves() {
+    let mut rng: StdRng = StdRng::from_seed([8; 32]);
+    let db = MockTestStore::new_test();
+    let tree = JellyfishMerkleTree::new(&db);
+    let kvs = (0..200)
+        .map(|i| {
+            (
+                TestKey::new_with_hash(HashValue::random_with_rng(&mut rng)),
+                TestValue::from(vec![i as u8]),
+            )
+        })
+        .collect::<BTreeMap<_, _>>();
+    let (root, batch) = tree
+        .put_blob_set(
+            None,
+            kvs.iter()
+                .map(|(key, value)| ((*key).into(), value.clone().into()))
+                .collect(),
+        )
+        .unwrap();
+    db.write_tree_update_batch(batch).unwrap();
+
+    // The default implementation yields the pairs of the iterator, in the same order.
+    let enumerated = db
+        .enumerate_leaves::<Sha3TreeHasher>(root)
+        .unwrap()
+        .collect::<Result<Vec<_>>>()
+        .unwrap();
+    let iterated = JellyfishMerkleIterator::<_, _, _>::new(&db, root, None)
+        .unwrap()
+        .collect::<Result<Vec<_>>>()
+        .unwrap();
+    assert_eq!(enumerated, iterated);
+    assert_eq!(collect_leaves(&db, root), kvs);
+
+    // An override may yield the pairs in another order, but yields the same pairs.
+    let store = ReverseLeavesStore(db);
+    let (first_key, _) = store
+        .enumerate_leaves::<Sha3TreeHasher>(root)
+        .unwrap()
+        .next()
+        .unwrap()
+        .unwrap();
+    assert_eq!(first_key, iterated.last().unwrap().0);
+    assert_eq!(collect_leaves(&store, root), kvs);
+}
+
+#[test]
+fn test_multiproof() {
+    let mut rng: StdRng = StdRng::from_seed([9; 32]);
+    let db = MockTestStore::new_test();
+    let tree = JellyfishMerkleTree::new(&db);
+
+    let mut kvs = vec![];
+    for _i in 0..1000 {
+        let key = HashValue::random_with_rng(&mut rng);
+        let value = TestValue::from(HashValue::random_with_rng(&mut rng).to_vec());
+        kvs.push((TestKey(key).into_object(), value.into_object()));
+    }
+    let (root, batch) = tree.put_blob_set(None, kvs.clone()).unwrap();
+    db.write_tree_update_batch(batch).unwrap();
+
+    // Mix existing keys, keys next to existing ones, random keys and a duplicate.
+    let mut keys = vec![];
+    let mut expected = vec![];
+    for (k, v) in kvs.iter().take(100) {
+        keys.push(k.clone());
+        expected.push(Some(v.merkle_hash()));
+    }
+    for (k, _) in kvs.iter().skip(100).take(50) {
+        keys.push(TestKey(plus_one(k.origin.0)).into_object());
+        expected.push(None);
+    }
+    for _i in 0..50 {
+        keys.push(TestKey(HashValue::random_with_rng(&mut rng)).into_object());
+        expected.push(None);
+    }
+    keys.push(keys[0].clone());
+    expected.push(expected[0]);
+
+    let proof = tree.get_multiproof(root, &keys).unwrap();
+    assert_eq!(proof.verify_objects(root, &keys).unwrap(), expected);
+
+    // The shared siblings are only included once.
+    let num_single_proof_siblings: usize = keys
+        .iter()
+        .map(|k| {
+            tree.get_with_proof(root, k.clone())
+                .unwrap()
+                .1
+                .siblings()
+                .len()
+        })
+        .sum();
+    assert!(proof.siblings().len() * 2 < num_single_proof_siblings);
+
+    // Each leaf is the one of the single proof.
+    for (k, (leaf, depth)) in keys.iter().zip(proof.leaves()) {
+        let (_, single_proof) = tree.get_with_proof(root, k.clone()).unwrap();
+        assert_eq!(*leaf, single_proof.leaf());
+        assert_eq!(*depth as usize, single_proof.siblings().len());
+    }
+}
+
+#[cfg(feature = "rayon")]
+#[test]
+fn test_multiproof_verify_par() {
+    let mut rng: StdRng = StdRng::from_seed([15; 32]);
+    let db = MockTestStore::new_test();
+    let tree = JellyfishMerkleTree::new(&db);
+
+    let mut kvs = vec![];
+    for _i in 0..3000 {
+        let key = HashValue::random_with_rng(&mut rng);
+        let value = TestValue::from(HashValue::random_with_rng(&mut rng).to_vec());
+        kvs.push((TestKey(key), value));
+    }
+    let (root, batch) = tree
+        .put_blob_set(
+            None,
+            kvs.iter()
+                .map(|(k, v)| (k.into_object(), v.clone().into_object()))
+                .collect(),
+        )
+        .unwrap();
+    db.write_tree_update_batch(batch).unwrap();
 
-        // Purge retired nodes('p' means purged and 'a' means added).
-        // The initial state of the tree at version 0
-        // ```test
-        //   1(root)
-        // ```
-        db.purge_stale_nodes(key1.into_object().merkle_hash())
-            .unwrap();
-        // ```text
-        //   1 (p)           internal(a)
-        //           ->     /        \
-        //                 1(a)       2(a)
-        // add 3, prune 1
-        // ```
-        assert_eq!(db.num_nodes(), 23);
-        db.purge_stale_nodes(key2.into_object().merkle_hash())
-            .unwrap();
-        // ```text
-        //     internal(p)             internal(a)
-        //    /        \              /        \
-        //   1(p)       2   ->   internal(a)    2
-        //                       /       \
-        //                      1(a)      3(a)
-        // add 4, prune 2
-        // ```
-        assert_eq!(db.num_nodes(), 23);
-        db.purge_stale_nodes(key3.into_object().merkle_hash())
-            .unwrap();
-        // ```text
-        //         internal(p)                internal(a)
-        //        /        \                 /        \
-        //   internal(p)    2   ->     internal(a)     2
-        //   /       \                /   |   \
-        //  1         3              1    3    4(a)
-        // add 3, prune 2
-        // ```
-        assert_eq!(db.num_nodes(), 23);
-        db.purge_stale_nodes(key4.into_object().merkle_hash())
-            .unwrap();
-        // ```text
-        //            internal(p)                         internal(a)
-        //           /        \                          /        \
-        //     internal(p)     2                    internal(a)    2
-        //    /   |   \                            /   |   \
-        //   1(p) 3    4           ->      internal(a) 3    4
-        //                                     |
-        //                                 internal(a)
-        //                                     |
-        //                                 internal(a)
-        //                                     |
-        //                                 internal(a)
-        //                                 /      \
-        //                                1(a)     5(a)
-        // add 8, prune 3
-        // ```
-        assert_eq!(db.num_nodes(), 23);
-        db.purge_stale_nodes(key5.into_object().merkle_hash())
-            .unwrap();
-        // ```text
-        //                  internal(p)                             internal(a)
-        //                 /        \                              /        \
-        //            internal(p)    2                        internal(a)    2
-        //           /   |   \                               /   |   \
-        //   internal(p) 3    4                      internal(a) 3    4
-        //       |                                      |
-        //   internal(p)                 ->          internal(a)
-        //       |                                   /      \
-        //   internal                          internal      6(a)
-        //       |                                |
-        //   internal                          internal
-        //   /      \                          /      \
-        //  1        5                        1        5
-        // add 5, prune 4
-        // ```
-        assert_eq!(db.num_nodes(), 23);
-        db.purge_stale_nodes(key6.into_object().merkle_hash())
-            .unwrap();
-        // ```text
-        //                         internal(p)                               internal(a)
-        //                        /        \                                /        \
-        //                   internal       2(p)                       internal       2(a)
-        //                  /   |   \                                 /   |   \
-        //          internal    3    4                        internal    3    4
-        //             |                                         |
-        //          internal                      ->          internal
-        //          /      \                                  /      \
-        //    internal      6                           internal      6
-        //       |                                         |
-        //    internal                                  internal
-        //    /      \                                  /      \
-        //   1        5                                1        5
-        // add 2, prune 2
-        // ```
-        assert_eq!(db.num_nodes(), 23);
-        verify_fn(&tree, root_hash);
+    // Existing keys, absent keys and a duplicate, in a proof large enough to be split.
+    let mut keys = kvs.iter().take(800).map(|(k, _)| *k).collect::<Vec<_>>();
+    for _i in 0..200 {
+        keys.push(TestKey(HashValue::random_with_rng(&mut rng)));
+    }
+    keys.push(keys[0]);
+    for keys in [&keys[..1], &keys[..10], &keys[..]] {
+        let objects = keys.iter().map(|k| k.into_object()).collect::<Vec<_>>();
+        let proof = tree.get_multiproof(root, &objects).unwrap();
+        let expected = proof.verify(root, keys.to_vec()).unwrap();
+        assert_eq!(proof.verify_par(root, keys.to_vec()).unwrap(), expected);
+        assert!(proof
+            .verify_par(HashValue::random(), keys.to_vec())
+            .is_err());
+
+        // Both agree on tampered proofs.
+        for index in [0, proof.siblings.len() / 2, proof.siblings.len() - 1] {
+            let mut tampered = proof.clone();
+            tampered.siblings[index] = HashValue::random();
+            assert!(tampered.verify(root, keys.to_vec()).is_err());
+            assert!(tampered.verify_par(root, keys.to_vec()).is_err());
+        }
+        let mut tampered = proof.clone();
+        tampered.siblings.pop();
+        assert!(tampered.verify_par(root, keys.to_vec()).is_err());
+        let mut tampered = proof.clone();
+        tampered.siblings.push(HashValue::random());
+        assert!(tampered.verify_par(root, keys.to_vec()).is_err());
     }
 }
 
 #[test]
-fn test_non_existence() {
+fn test_multiproof_small_trees() {
     let db = MockTestStore::new_test();
     let tree = JellyfishMerkleTree::new(&db);
-    // ```text
-    //                     internal(root)
-    //                    /        \
-    //                internal      2
-    //                   |
-    //                internal
-    //                /      \
-    //               1        3
-    // Total: 7 nodes
-    // ```
     let key1 = TestKey::new([0x00u8; HashValue::LENGTH]);
     let value1 = TestValue::from(vec![1u8]);
+    let key2 = update_nibble(&key1, 2, 3);
+    let non_existing_key = update_nibble(&key1, 0, 1);
 
-    let key2 = update_nibble(&key1, 0, 15);
-    let value2 = TestValue::from(vec![2u8]);
+    // Empty tree.
+    let (root, batch) = tree.updates(None, vec![(key1.into(), None)]).unwrap();
+    db.write_tree_update_batch(batch).unwrap();
+    let proof = tree
+        .get_multiproof(root, &[key1.into(), key2.into()])
+        .unwrap();
+    assert!(proof.siblings().is_empty());
+    assert_eq!(
+        proof.verify(root, vec![key1, key2]).unwrap(),
+        vec![None, None]
+    );
 
-    let key3 = update_nibble(&key1, 2, 3);
-    let value3 = TestValue::from(vec![3u8]);
+    // No keys.
+    let proof = tree.get_multiproof(root, &[]).unwrap();
+    assert_eq!(proof, SparseMerkleMultiProof::default());
+    assert!(proof.verify::<TestKey>(root, vec![]).unwrap().is_empty());
+
+    // A single leaf.
+    let (root, batch) = tree
+        .put_blob_set(Some(root), vec![(key1.into(), value1.clone().into())])
+        .unwrap();
+    db.write_tree_update_batch(batch).unwrap();
+    let proof = tree
+        .get_multiproof(root, &[non_existing_key.into(), key1.into()])
+        .unwrap();
+    let value_hash = value1.into_object().merkle_hash();
+    assert_eq!(
+        proof.verify(root, vec![non_existing_key, key1]).unwrap(),
+        vec![None, Some(value_hash)]
+    );
+}
 
+#[test]
+fn test_multiproof_rejects_invalid_proofs() {
+    let mut rng: StdRng = StdRng::from_seed([10; 32]);
+    let db = MockTestStore::new_test();
+    let tree = JellyfishMerkleTree::new(&db);
+
+    let mut kvs = vec![];
+    for _i in 0..100 {
+        let key = HashValue::random_with_rng(&mut rng);
+        let value = TestValue::from(HashValue::random_with_rng(&mut rng).to_vec());
+        kvs.push((TestKey(key).into_object(), value.into_object()));
+    }
+    let (root, batch) = tree.put_blob_set(None, kvs.clone()).unwrap();
+    db.write_tree_update_batch(batch).unwrap();
+
+    let mut keys = kvs
+        .iter()
+        .take(10)
+        .map(|(k, _)| k.origin)
+        .collect::<Vec<_>>();
+    keys.push(TestKey::random());
+    let key_objects = keys.iter().map(|k| k.into_object()).collect::<Vec<_>>();
+    let proof = tree.get_multiproof(root, &key_objects).unwrap();
+    assert!(proof.verify(root, keys.clone()).is_ok());
+
+    // Wrong root.
+    assert!(proof.verify(HashValue::random(), keys.clone()).is_err());
+    // Wrong number of keys.
+    assert!(proof.verify(root, keys[1..].to_vec()).is_err());
+    // Keys in another order.
+    let mut swapped_keys = keys.clone();
+    swapped_keys.swap(0, 1);
+    assert!(proof.verify(root, swapped_keys).is_err());
+    // Tampered sibling.
+    let mut tampered = proof.clone();
+    tampered.siblings[0] = HashValue::random();
+    assert!(tampered.verify(root, keys.clone()).is_err());
+    // Missing or extra sibling.
+    let mut tampered = proof.clone();
+    tampered.siblings.pop();
+    assert!(tampered.verify(root, keys.clone()).is_err());
+    let mut tampered = proof.clone();
+    tampered.siblings.push(HashValue::random());
+    assert!(tampered.verify(root, keys.clone()).is_err());
+    // Tampered leaf.
+    let mut tampered = proof.clone();
+    tampered.leaves[0].0 = Some((keys[0].0, HashValue::random()));
+    assert!(tampered.verify(root, keys.clone()).is_err());
+    // Tampered depth.
+    let mut tampered = proof;
+    tampered.leaves[0].1 += 1;
+    assert!(tampered.verify(root, keys).is_err());
+}
+
+#[test]
+fn test_interval_proof() {
+    let mut rng: StdRng = StdRng::from_seed([11; 32]);
+    let db = MockTestStore::new_test();
+    let tree = JellyfishMerkleTree::new(&db);
+
+    let mut btree = BTreeMap::new();
+    for _i in 0..200 {
+        let key = HashValue::random_with_rng(&mut rng);
+        let value = TestValue::from(HashValue::random_with_rng(&mut rng).to_vec());
+        btree.insert(key, value);
+    }
     let (root, batch) = tree
         .put_blob_set(
             None,
-            vec![
-                (key1.into(), value1.clone().into()),
-                (key2.into(), value2.clone().into()),
-                (key3.into(), value3.clone().into()),
-            ],
+            btree
+                .iter()
+                .map(|(k, v)| (TestKey(*k).into(), v.clone().into()))
+                .collect(),
         )
         .unwrap();
     db.write_tree_update_batch(batch).unwrap();
-    assert_eq!(tree.get(root, key1).unwrap().unwrap().origin, value1);
-    assert_eq!(tree.get(root, key2).unwrap().unwrap().origin, value2);
-    assert_eq!(tree.get(root, key3).unwrap().unwrap().origin, value3);
-    // get # of nodes
-    assert_eq!(db.num_nodes(), 6);
-
-    // test non-existing nodes.
-    // 1. Non-existing node at root node
-    {
-        let non_existing_key = update_nibble(&key1, 0, 1);
-        let (value, proof) = tree.get_with_proof(root, non_existing_key).unwrap();
-        assert_eq!(value, None);
+    let keys = btree.keys().cloned().collect::<Vec<_>>();
+
+    let intervals = vec![
+        // Both ends exist.
+        (keys[10], keys[20]),
+        (keys[10], keys[10]),
+        // Neither end exists.
+        (plus_one(keys[30]), plus_one(keys[60])),
+        // No leaf in the interval.
+        (plus_one(keys[70]), minus_one(keys[71])),
+        // The whole tree.
+        (HashValue::zero(), HashValue::new([0xff; HashValue::LENGTH])),
+        // The start is after the end.
+        (keys[90], keys[80]),
+    ];
+    for (start, end) in intervals {
+        let (leaves, proof) = tree
+            .get_interval_proof(root, TestKey(start).into(), TestKey(end).into())
+            .unwrap();
+        let leaves = leaves
+            .into_iter()
+            .map(|(k, v)| (k.origin, v.origin))
+            .collect::<Vec<_>>();
+        let expected = if start <= end {
+            btree
+                .range(start..=end)
+                .map(|(k, v)| (TestKey(*k), v.clone()))
+                .collect::<Vec<_>>()
+        } else {
+            vec![]
+        };
+        assert_eq!(leaves, expected);
         assert!(proof
-            .verify::<TestKey, TestValue>(root, non_existing_key, None)
+            .verify(root, TestKey(start), TestKey(end), leaves)
             .is_ok());
     }
-    // 2. Non-existing node at non-root internal node
-    {
-        let non_existing_key = update_nibble(&key1, 1, 15);
-        let (value, proof) = tree.get_with_proof(root, non_existing_key).unwrap();
-        assert_eq!(value, None);
-        assert!(proof
-            .verify::<TestKey, TestValue>(root, non_existing_key, None)
-            .is_ok());
+}
+
+#[test]
+fn test_interval_proof_rejects_omitted_leaves() {
+    let mut rng: StdRng = StdRng::from_seed([12; 32]);
+    let db = MockTestStore::new_test();
+    let tree = JellyfishMerkleTree::new(&db);
+
+    let mut kvs = vec![];
+    for _i in 0..200 {
+        let key = HashValue::random_with_rng(&mut rng);
+        let value = TestValue::from(HashValue::random_with_rng(&mut rng).to_vec());
+        kvs.push((TestKey(key).into_object(), value.into_object()));
     }
-    // 3. Non-existing node at leaf node
-    {
-        let non_existing_key = update_nibble(&key1, 2, 4);
-        let (value, proof) = tree.get_with_proof(root, non_existing_key).unwrap();
-        assert_eq!(value, None);
-        assert!(proof
-            .verify::<TestKey, TestValue>(root, non_existing_key, None)
-            .is_ok());
+    let (root, batch) = tree.put_blob_set(None, kvs.clone()).unwrap();
+    db.write_tree_update_batch(batch).unwrap();
+
+    let start = TestKey(HashValue::new([0x40; HashValue::LENGTH]));
+    let end = TestKey(HashValue::new([0x80; HashValue::LENGTH]));
+    let (leaves, proof) = tree
+        .get_interval_proof(root, start.into(), end.into())
+        .unwrap();
+    let leaves = leaves
+        .into_iter()
+        .map(|(k, v)| (k.origin, v.origin))
+        .collect::<Vec<_>>();
+    assert!(leaves.len() > 10);
+    assert!(proof.verify(root, start, end, leaves.clone()).is_ok());
+
+    // Wrong root.
+    assert!(proof
+        .verify(HashValue::random(), start, end, leaves.clone())
+        .is_err());
+    // Wrong value.
+    let mut tampered = leaves.clone();
+    tampered[3].1 = TestValue::random();
+    assert!(proof.verify(root, start, end, tampered).is_err());
+    // Leaves out of order.
+    let mut tampered = leaves.clone();
+    tampered.swap(3, 4);
+    assert!(proof.verify(root, start, end, tampered).is_err());
+    // Another interval.
+    assert!(proof
+        .verify(
+            root,
+            start,
+            TestKey(HashValue::new([0xc0; HashValue::LENGTH])),
+            leaves.clone()
+        )
+        .is_err());
+
+    // Omitting a leaf from a valid proof of the remaining ones is detected: the proof of the
+    // remaining keys has a sibling hash for the subtree of the omitted leaf.
+    for omitted in [0, leaves.len() / 2, leaves.len() - 1] {
+        let mut remaining = leaves.clone();
+        let (omitted_key, _) = remaining.remove(omitted);
+        let mut keys = vec![start.into_object()];
+        keys.extend(remaining.iter().map(|(k, _)| k.into_object()));
+        keys.push(end.into_object());
+        let multiproof = tree.get_multiproof(root, &keys).unwrap();
+        let tampered = SparseMerkleIntervalProof::new(multiproof);
+        assert!(tampered.verify(root, start, end, remaining).is_err());
+
+        // The same proof shows the leaves of the interval that excludes the omitted leaf.
+        if omitted == 0 {
+            let mut keys = vec![TestKey(plus_one(omitted_key.0)).into_object()];
+            keys.extend(leaves[1..].iter().map(|(k, _)| k.into_object()));
+            keys.push(end.into_object());
+            let multiproof = tree.get_multiproof(root, &keys).unwrap();
+            assert!(SparseMerkleIntervalProof::new(multiproof)
+                .verify(
+                    root,
+                    TestKey(plus_one(omitted_key.0)),
+                    end,
+                    leaves[1..].to_vec()
+                )
+                .is_ok());
+        }
+    }
+}
+
+#[test]
+fn test_verify_leaf_set() {
+    let mut rng: StdRng = StdRng::from_seed([13; 32]);
+    let db = MockTestStore::new_test();
+    let tree = JellyfishMerkleTree::new(&db);
+
+    let mut kvs = vec![];
+    for _i in 0..200 {
+        let key = HashValue::random_with_rng(&mut rng);
+        let value = TestValue::from(HashValue::random_with_rng(&mut rng).to_vec());
+        kvs.push((TestKey(key).into_object(), value.into_object()));
     }
+    let (root, batch) = tree.put_blob_set(None, kvs.clone()).unwrap();
+    db.write_tree_update_batch(batch).unwrap();
+
+    let leaves = kvs.iter().step_by(7).cloned().collect::<Vec<_>>();
+    let keys = leaves.iter().map(|(k, _)| k.clone()).collect::<Vec<_>>();
+    let key_hashes = keys.iter().map(|k| k.merkle_hash()).collect::<Vec<_>>();
+    let siblings = tree
+        .get_multiproof(root, &keys)
+        .unwrap()
+        .sibling_nodes(&key_hashes)
+        .unwrap();
+    verify_leaf_set::<_, _, Sha3TreeHasher>(root, &leaves, &siblings).unwrap();
+
+    // The order of the leaves and the siblings does not matter.
+    let mut shuffled = leaves.clone();
+    shuffled.reverse();
+    let mut shuffled_siblings = siblings.clone();
+    shuffled_siblings.reverse();
+    verify_leaf_set::<_, _, Sha3TreeHasher>(root, &shuffled, &shuffled_siblings).unwrap();
+
+    // Wrong root.
+    assert!(
+        verify_leaf_set::<_, _, Sha3TreeHasher>(HashValue::random(), &leaves, &siblings).is_err()
+    );
+    // Altered value.
+    let mut tampered = leaves.clone();
+    tampered[3].1 = TestValue::random().into_object();
+    assert!(verify_leaf_set::<_, _, Sha3TreeHasher>(root, &tampered, &siblings).is_err());
+    // Missing leaf.
+    let mut tampered = leaves.clone();
+    tampered.remove(3);
+    assert!(verify_leaf_set::<_, _, Sha3TreeHasher>(root, &tampered, &siblings).is_err());
+    // Duplicate leaf.
+    let mut tampered = leaves.clone();
+    tampered.push(leaves[3].clone());
+    assert!(verify_leaf_set::<_, _, Sha3TreeHasher>(root, &tampered, &siblings).is_err());
+    // Missing sibling.
+    let mut tampered = siblings.clone();
+    tampered.remove(3);
+    assert!(verify_leaf_set::<_, _, Sha3TreeHasher>(root, &leaves, &tampered).is_err());
+    // Sibling holding a leaf.
+    let mut tampered = siblings.clone();
+    tampered.push(SparseMerkleSibling {
+        key_hash: key_hashes[0],
+        depth: 200,
+        hash: HashValue::random(),
+    });
+    assert!(verify_leaf_set::<_, _, Sha3TreeHasher>(root, &leaves, &tampered).is_err());
+
+    // All the leaves of the tree need no sibling, and no leaf needs the whole tree as a sibling.
+    verify_leaf_set::<_, _, Sha3TreeHasher>(root, &kvs, &[]).unwrap();
+    let whole_tree = SparseMerkleSibling {
+        key_hash: HashValue::zero(),
+        depth: 0,
+        hash: root,
+    };
+    verify_leaf_set::<TestKey, TestValue, Sha3TreeHasher>(root, &[], &[whole_tree]).unwrap();
+    assert!(verify_leaf_set::<_, _, Sha3TreeHasher>(root, &leaves, &[whole_tree]).is_err());
+    verify_leaf_set::<TestKey, TestValue, Sha3TreeHasher>(
+        HashValue::SPARSE_MERKLE_PLACEHOLDER,
+        &[],
+        &[],
+    )
+    .unwrap();
+}
+
+#[test]
+fn test_interval_proof_empty_tree() {
+    let db = MockTestStore::new_test();
+    let tree = JellyfishMerkleTree::new(&db);
+    let start = TestKey(HashValue::zero());
+    let end = TestKey(HashValue::new([0xff; HashValue::LENGTH]));
+    let (root, batch) = tree.updates(None, vec![(start.into(), None)]).unwrap();
+    db.write_tree_update_batch(batch).unwrap();
+
+    let (leaves, proof) = tree
+        .get_interval_proof(root, start.into(), end.into())
+        .unwrap();
+    assert!(leaves.is_empty());
+    assert!(proof
+        .verify::<TestKey, TestValue>(root, start, end, vec![])
+        .is_ok());
+    assert!(proof
+        .verify(
+            root,
+            start,
+            end,
+            vec![(TestKey::random(), TestValue::random())]
+        )
+        .is_err());
 }
 
 #[test]
@@ -722,6 +2919,63 @@ fn test_1000_versions() {
 proptest! {
     #![proptest_config(ProptestConfig::with_cases(10))]
 
+    #[test]
+    fn test_put_batch_matches_updates(
+        kvs in hash_map(any::<TestKey>(), any::<TestValue>(), 1..200),
+        updates in vec((any::<TestKey>(), any::<Option<TestValue>>()), 1..200),
+        num_existing in 0usize..100,
+    ) {
+        let (db, root) = init_mock_db(&kvs);
+        let tree = JellyfishMerkleTree::new(&db);
+        let mut updates: Vec<_> = updates
+            .into_iter()
+            .map(|(k, v)| (k.into_object(), v.map(|v| v.into_object())))
+            .collect();
+        // Also touch keys that are already in the tree.
+        for (i, key) in kvs.keys().take(num_existing).enumerate() {
+            let value = (i % 2 == 0).then(TestValue::random);
+            updates.push((key.into_object(), value.map(|v| v.into_object())));
+        }
+        assert_put_batch_matches_updates(&tree, root, updates);
+    }
+
+    #[test]
+    fn test_compute_root_after_matches_put_batch(
+        kvs in hash_map(any::<TestKey>(), any::<TestValue>(), 1..200),
+        updates in vec((any::<TestKey>(), any::<Option<TestValue>>()), 1..200),
+        num_existing in 0usize..100,
+    ) {
+        let (db, root) = init_mock_db(&kvs);
+        let tree = JellyfishMerkleTree::new(&db);
+        let mut updates: Vec<_> = updates
+            .into_iter()
+            .map(|(k, v)| (k.into_object(), v.map(|v| v.into_object())))
+            .collect();
+        for (i, key) in kvs.keys().take(num_existing).enumerate() {
+            let value = (i % 2 == 0).then(TestValue::random);
+            updates.push((key.into_object(), value.map(|v| v.into_object())));
+        }
+        let (expected_root, _) = tree.put_batch(root, updates.clone()).unwrap();
+        let root = root.unwrap_or(*SPARSE_MERKLE_PLACEHOLDER_HASH);
+        prop_assert_eq!(
+            compute_root_after::<_, _, _, Sha3TreeHasher>(&db, root, updates).unwrap(),
+            expected_root
+        );
+    }
+
+    #[test]
+    fn test_build_from_sorted_matches_inserts(
+        kvs in hash_map(any::<TestKey>(), any::<TestValue>(), 1..300),
+    ) {
+        let (db, root) = init_mock_db(&kvs);
+        let (built_root, node_batch) =
+            build_from_sorted::<_, _, Sha3TreeHasher>(sorted_leaves(&kvs)).unwrap();
+        prop_assert_eq!(Some(built_root), root);
+        for (node_key, node) in node_batch {
+            prop_assert_eq!(db.get_node(&node_key).unwrap(), node);
+        }
+    }
+
     #[test]
     fn test_get_with_proof1(
         (existent_kvs, nonexistent_keys) in hash_map(
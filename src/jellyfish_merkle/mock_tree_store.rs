@@ -7,16 +7,20 @@
 use super::hash::HashValue;
 use super::{
     node_type::{Node, NodeKey},
-    NodeBatch, StaleNodeIndex, TreeReader, TreeUpdateBatch, TreeWriter,
+    LeafEnumerable, NodeBatch, StaleNodeIndex, StaleNodeIndexBatch, TreeReader, TreeUpdateBatch,
+    TreeWriter, Versioned,
 };
 use crate::{DecodeToObject, EncodeToObject, Key, SMTObject, Value};
-use anyhow::{bail, ensure, Result};
+use anyhow::{bail, ensure, format_err, Result};
 #[cfg(any(test, feature = "fuzzing"))]
 use proptest_derive::Arbitrary;
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::{hash_map::Entry, BTreeSet, HashMap},
-    sync::RwLock,
+    collections::{hash_map::Entry, BTreeSet, HashMap, HashSet},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        RwLock,
+    },
 };
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
@@ -86,14 +90,22 @@ impl From<Vec<u8>> for TestValue {
     }
 }
 
+/// An in-memory tree store for tests, holding the nodes and the stale node indices written to it.
+/// Reads of the node keys passed to [`fail_reads`](MockTreeStore::fail_reads) fail, to test how
+/// errors of the storage are propagated.
 #[derive(Default)]
-pub struct MockTreeStore<K, V>(RwLock<(HashMap<NodeKey, Node<K, V>>, BTreeSet<StaleNodeIndex>)>);
+pub struct MockTreeStore<K, V> {
+    nodes: RwLock<HashMap<NodeKey, Node<K, V>>>,
+    stale_indices: RwLock<BTreeSet<StaleNodeIndex>>,
+    /// The node keys whose reads fail.
+    failing_reads: RwLock<HashSet<NodeKey>>,
+}
 
 pub type MockTestStore = MockTreeStore<TestKey, TestValue>;
 
 impl MockTestStore {
     pub fn new_test() -> Self {
-        MockTreeStore(RwLock::new((HashMap::new(), BTreeSet::new())))
+        Self::new()
     }
 }
 
@@ -103,27 +115,89 @@ where
     V: Value,
 {
     fn get_node_option(&self, node_key: &NodeKey) -> Result<Option<Node<K, V>>> {
-        Ok(self.0.read().unwrap().0.get(node_key).cloned())
+        if self.failing_reads.read().unwrap().contains(node_key) {
+            return Err(format_err!("Failed to read node {:?}.", node_key));
+        }
+        Ok(self.nodes.read().unwrap().get(node_key).cloned())
+    }
+
+    fn with_node<T, F>(&self, node_key: &NodeKey, f: F) -> Result<Option<T>>
+    where
+        F: FnOnce(&Node<K, V>) -> T,
+    {
+        if self.failing_reads.read().unwrap().contains(node_key) {
+            return Err(format_err!("Failed to read node {:?}.", node_key));
+        }
+        Ok(self.nodes.read().unwrap().get(node_key).map(f))
     }
 }
 
+impl<K, V> Versioned<K, V> for MockTreeStore<K, V>
+where
+    K: Key,
+    V: Value,
+{
+}
+
+impl<K, V> LeafEnumerable<K, V> for MockTreeStore<K, V>
+where
+    K: Key,
+    V: Value,
+{
+}
+
 impl<K, V> TreeWriter<K, V> for MockTreeStore<K, V>
 where
     K: Key,
     V: Value,
 {
     fn write_node_batch(&self, node_batch: &NodeBatch<K, V>) -> Result<()> {
-        let mut locked = self.0.write().unwrap();
+        let mut nodes = self.nodes.write().unwrap();
         for (node_key, node) in node_batch.clone() {
-            ensure!(locked.0.insert(node_key, node).is_none());
+            ensure!(nodes.insert(node_key, node).is_none());
         }
         Ok(())
     }
+
+    fn delete_node_batch(&self, node_keys: &[NodeKey]) -> Result<()> {
+        let mut nodes = self.nodes.write().unwrap();
+        for node_key in node_keys {
+            ensure!(
+                nodes.remove(node_key).is_some(),
+                "Deleting non-existent node {:?}.",
+                node_key
+            );
+        }
+        Ok(())
+    }
+
+    fn write_stale_node_index_batch(
+        &self,
+        stale_node_index_batch: &StaleNodeIndexBatch,
+    ) -> Result<()> {
+        stale_node_index_batch
+            .iter()
+            .try_for_each(|index| self.put_stale_node_index(index.clone()))
+    }
 }
 
 impl<K, V> MockTreeStore<K, V> {
+    /// Creates an empty store.
+    pub fn new() -> Self {
+        MockTreeStore {
+            nodes: RwLock::new(HashMap::new()),
+            stale_indices: RwLock::new(BTreeSet::new()),
+            failing_reads: RwLock::new(HashSet::new()),
+        }
+    }
+
+    /// Makes the reads of the node with `node_key` fail from now on.
+    pub fn fail_reads(&self, node_key: NodeKey) {
+        self.failing_reads.write().unwrap().insert(node_key);
+    }
+
     pub fn put_node(&self, node_key: NodeKey, node: Node<K, V>) -> Result<()> {
-        match self.0.write().unwrap().0.entry(node_key) {
+        match self.nodes.write().unwrap().entry(node_key) {
             Entry::Occupied(o) => bail!("Key {:?} exists.", o.key()),
             Entry::Vacant(v) => {
                 v.insert(node);
@@ -133,7 +207,7 @@ impl<K, V> MockTreeStore<K, V> {
     }
 
     fn put_stale_node_index(&self, index: StaleNodeIndex) -> Result<()> {
-        let is_new_entry = self.0.write().unwrap().1.insert(index);
+        let is_new_entry = self.stale_indices.write().unwrap().insert(index);
         ensure!(is_new_entry, "Duplicated retire log.");
         Ok(())
     }
@@ -153,27 +227,72 @@ impl<K, V> MockTreeStore<K, V> {
     }
 
     pub fn purge_stale_nodes(&self, state_root_hash: HashValue) -> Result<()> {
-        let mut wlocked = self.0.write().unwrap();
+        let mut nodes = self.nodes.write().unwrap();
+        let mut stale_indices = self.stale_indices.write().unwrap();
 
         // Only records retired before or at `least_readable_version` can be purged in order
         // to keep that version still readable.
-        let to_prune = wlocked
-            .1
+        let to_prune = stale_indices
             .iter()
             .take_while(|log| log.stale_since_version == state_root_hash)
             .cloned()
             .collect::<Vec<_>>();
 
         for log in to_prune {
-            let removed = wlocked.0.remove(&log.node_key).is_some();
+            let removed = nodes.remove(&log.node_key).is_some();
             ensure!(removed, "Stale node index refers to non-existent node.");
-            wlocked.1.remove(&log);
+            stale_indices.remove(&log);
         }
 
         Ok(())
     }
 
     pub fn num_nodes(&self) -> usize {
-        self.0.read().unwrap().0.len()
+        self.nodes.read().unwrap().len()
+    }
+}
+
+/// A `TreeReader` counting the reads from the reader it wraps. A `get_nodes` call counts as a
+/// single read.
+pub struct CountingTreeReader<R> {
+    inner: R,
+    reads: AtomicUsize,
+}
+
+impl<R> CountingTreeReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            reads: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn reads(&self) -> usize {
+        self.reads.load(Ordering::SeqCst)
     }
 }
+
+impl<K, V, R> TreeReader<K, V> for CountingTreeReader<R>
+where
+    R: TreeReader<K, V>,
+{
+    fn get_node_option(&self, node_key: &NodeKey) -> Result<Option<Node<K, V>>> {
+        self.reads.fetch_add(1, Ordering::SeqCst);
+        self.inner.get_node_option(node_key)
+    }
+
+    fn get_nodes(&self, node_keys: &[NodeKey]) -> Result<Vec<Option<Node<K, V>>>> {
+        self.reads.fetch_add(1, Ordering::SeqCst);
+        self.inner.get_nodes(node_keys)
+    }
+
+    fn with_node<T, F>(&self, node_key: &NodeKey, f: F) -> Result<Option<T>>
+    where
+        F: FnOnce(&Node<K, V>) -> T,
+    {
+        self.reads.fetch_add(1, Ordering::SeqCst);
+        self.inner.with_node(node_key, f)
+    }
+}
+
+impl<K, V, R> Versioned<K, V> for CountingTreeReader<R> where R: Versioned<K, V> {}
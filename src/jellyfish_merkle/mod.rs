@@ -73,6 +73,10 @@
 //! [`InternalNode`]: node_type/struct.InternalNode.html
 //! [`LeafNode`]: node_type/struct.LeafNode.html
 
+pub mod bloom_tree_reader;
+pub mod caching_tree_reader;
+pub mod consistency;
+pub mod diff;
 pub mod hash;
 pub mod iterator;
 #[cfg(test)]
@@ -81,59 +85,391 @@ pub mod mock_tree_store;
 pub mod nibble;
 pub mod nibble_path;
 pub mod node_type;
+pub mod observer;
+pub mod partial_tree_reader;
+pub mod pin;
 pub mod proof;
+pub mod proof_cache;
+pub mod snapshot;
+pub mod sync;
 pub mod test_helper;
 pub mod tree_cache;
+pub mod versioned_tree;
+pub mod view;
 
 use crate::{Key, SMTObject, Value};
 use anyhow::{bail, ensure, format_err, Result};
 use backtrace::Backtrace;
-use hash::{HashValue, SMTHash};
+use hash::{HashValue, SMTHash, Sha3TreeHasher, TreeHasher};
+use iterator::JellyfishMerkleIterator;
 use log::debug;
+use nibble::Nibble;
 use nibble_path::{skip_common_prefix, NibbleIterator, NibblePath};
 use node_type::{Child, Children, InternalNode, LeafNode, Node, NodeKey};
-use proof::{SparseMerkleProof, SparseMerkleRangeProof};
-use std::collections::{BTreeMap, BTreeSet};
+use observer::Observer;
+use proof::{
+    ProofOutcome, SparseMerkleIntervalProof, SparseMerkleMultiProof, SparseMerkleProof,
+    SparseMerkleRangeProof,
+};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
 use std::marker::PhantomData;
+use std::ops::Bound;
+use thiserror::Error;
 use tree_cache::TreeCache;
 
-/// The hardcoded maximum height of a [`JellyfishMerkleTree`] in nibbles.
+/// The hardcoded maximum height of a tree in nibbles, i.e. the number of
+/// nibbles of a key hash: a path has at most this many internal nodes above its leaf.
 pub const ROOT_NIBBLE_HEIGHT: usize = HashValue::LENGTH * 2;
 
-/// `TreeReader` defines the interface between
-/// [`JellyfishMerkleTree`](struct.JellyfishMerkleTree.html)
-/// and underlying storage holding nodes.
+/// `TreeReader` defines the interface between the tree, e.g. [`SMTree`](crate::SMTree), and
+/// underlying storage holding nodes.
 pub trait TreeReader<K, V> {
     /// Gets node given a node key. Returns error if the node does not exist.
     fn get_node(&self, node_key: &NodeKey) -> Result<Node<K, V>> {
         self.get_node_option(node_key)?.ok_or_else(|| {
             let backtrace = format!("{:#?}", Backtrace::new());
             debug!("backtrace: {}", backtrace);
-            format_err!("Missing node at {:?}.", node_key)
+            SmtError::NodeNotFound(*node_key).into()
         })
     }
 
     /// Gets node given a node key. Returns `None` if the node does not exist.
     fn get_node_option(&self, node_key: &NodeKey) -> Result<Option<Node<K, V>>>;
+
+    /// Gets the nodes given a list of node keys, in the same order. Returns `None` for each node
+    /// that does not exist. Storages able to read many keys at once should override this, so the
+    /// nodes are read in a single round-trip.
+    fn get_nodes(&self, node_keys: &[NodeKey]) -> Result<Vec<Option<Node<K, V>>>> {
+        node_keys
+            .iter()
+            .map(|node_key| self.get_node_option(node_key))
+            .collect()
+    }
+
+    /// Calls `f` with the node at `node_key` and returns its result, or `None` if the node does not
+    /// exist. The default implementation reads an owned node with `get_node_option`; storages
+    /// holding the decoded nodes in memory should override it to lend `f` the node they own,
+    /// instead of cloning it.
+    fn with_node<T, F>(&self, node_key: &NodeKey, f: F) -> Result<Option<T>>
+    where
+        Self: Sized,
+        F: FnOnce(&Node<K, V>) -> T,
+    {
+        Ok(self.get_node_option(node_key)?.map(|node| f(&node)))
+    }
+}
+
+/// `Versioned` marks a [`TreeReader`](crate::TreeReader) under which the nodes reachable from
+/// a root never change, so a root is a snapshot of the tree: reads at one root are isolated from
+/// the writes of any other root, and two iterators over two roots sharing nodes never see each
+/// other. The [`SMTIterator`](crate::SMTIterator) relies on
+/// it, as it keeps reading the tree below the nodes it already visited.
+///
+/// A store keyed by the node hashes gets it for free, since a node can only be overwritten with
+/// itself; this is how the tree writes its nodes. A store overwriting nodes in place, e.g. keyed
+/// by their position in the tree, must not implement it. Pruning a root being read is not
+/// covered, see [`PinnedRoots`](crate::PinnedRoots).
+pub trait Versioned<K, V>: TreeReader<K, V> {}
+
+/// `AsyncTreeReader` is the asynchronous counterpart of [`TreeReader`](crate::TreeReader),
+/// for storages which read the nodes over the network. It is read by
+/// [`JellyfishMerkleStream`](crate::jellyfish_merkle::iterator::JellyfishMerkleStream). Implementations may use
+/// `async fn`, the returned futures only need to be `Send` so they can run on any executor.
+#[cfg(feature = "async")]
+pub trait AsyncTreeReader<K, V> {
+    /// Gets node given a node key. Returns error if the node does not exist.
+    fn get_node(
+        &self,
+        node_key: &NodeKey,
+    ) -> impl std::future::Future<Output = Result<Node<K, V>>> + Send;
+}
+
+/// `ValueReader` reads the values of detached leaves, for stores keeping the values apart from
+/// the tree nodes, see [`LeafNode::new_detached`](crate::LeafNode::new_detached). Such a store
+/// detaches the leaves with [`LeafNode::detach_with`](crate::LeafNode::detach_with) when
+/// writing the nodes, so that reading the structure of the tree never reads a value. The
+/// iterators read the value of a leaf with it when yielding the leaf, see
+/// [`SMTree::with_value_reader`](crate::SMTree::with_value_reader).
+pub trait ValueReader<V> {
+    /// Gets the value with the given value hash. Returns error if the value does not exist.
+    fn get_value(&self, value_hash: &HashValue) -> Result<SMTObject<V>>;
 }
 
+/// `LeafEnumerable` enumerates all the key-value pairs of a tree, for algorithms which do not
+/// depend on their order, e.g. statistics or rehashing all the values. The default implementation
+/// is an [`SMTIterator`](crate::SMTIterator) over the whole
+/// tree, which yields the pairs in key hash order. Storages able to enumerate the leaves of a
+/// tree without the traversal, e.g. archival stores keeping them apart, should override it; they
+/// may yield the pairs in any order, so callers must not rely on the order.
+pub trait LeafEnumerable<K, V>: Versioned<K, V> + Sized
+where
+    K: Key,
+    V: Value,
+{
+    /// Returns an iterator over the key-value pairs of the tree at `root`, each exactly once, the
+    /// keys being hashed with `H` to place them in the tree.
+    fn enumerate_leaves<'a, H: TreeHasher>(
+        &'a self,
+        root: HashValue,
+    ) -> Result<impl Iterator<Item = Result<(SMTObject<K>, SMTObject<V>)>> + 'a>
+    where
+        K: 'a,
+        V: 'a,
+    {
+        JellyfishMerkleIterator::<K, V, Self, H>::new(self, root, None)
+    }
+}
+
+/// Error returned when the nodes read from a [`TreeReader`](crate::TreeReader) do not form a
+/// well-formed tree. Like the `NodeDecodeError` of a node failing to decode, it is carried
+/// in the `anyhow::Error` of the failing call, so callers can match on it with
+/// `err.downcast_ref::<SmtError>()`.
+#[derive(Debug, Error, Eq, PartialEq)]
+pub enum SmtError {
+    /// No node is stored at the node key.
+    #[error("Missing node at {0:?}.")]
+    NodeNotFound(NodeKey),
+
+    /// The tree is malformed, e.g. cyclic or deeper than the nibbles of a key hash.
+    #[error("{0}")]
+    CorruptNode(String),
+
+    /// A null node was read where only a non-empty node can be.
+    #[error("Should not reach a null node at {0:x}.")]
+    UnexpectedNull(NodeKey),
+
+    /// The node read at `expected` hashes to `actual`.
+    #[error("Node {expected:x} hashes to {actual:x}, the tree store is inconsistent.")]
+    HashMismatch {
+        expected: HashValue,
+        actual: HashValue,
+    },
+
+    /// The node is legitimately missing from a partial tree, see
+    /// [`PartialTreeReader`](crate::PartialTreeReader). Unlike
+    /// `NodeNotFound`, the tree is not corrupted and the read can be retried once the node is
+    /// fetched.
+    #[error("Node {0:x} is not held by the partial tree.")]
+    NodeNotHeld(NodeKey),
+}
+
+impl SmtError {
+    fn cyclic() -> Self {
+        SmtError::CorruptNode("Jellyfish Merkle tree has cyclic graph inside.".to_string())
+    }
+}
+
+/// `TreeWriter` defines the interface between the tree, e.g. [`commit`](crate::commit), and
+/// underlying storage
+/// persisting nodes, symmetric to [`TreeReader`](crate::TreeReader).
 pub trait TreeWriter<K, V> {
     /// Writes a node batch into storage.
     fn write_node_batch(&self, node_batch: &NodeBatch<K, V>) -> Result<()>;
+
+    /// Deletes the nodes with the given node keys from storage, for example the ones in a
+    /// [`StaleNodeIndexBatch`](crate::StaleNodeIndexBatch) when pruning.
+    fn delete_node_batch(&self, node_keys: &[NodeKey]) -> Result<()>;
+
+    /// Records the stale node indices of an update, to prune their nodes later. The default
+    /// implementation drops them, for the stores which are never pruned.
+    fn write_stale_node_index_batch(
+        &self,
+        _stale_node_index_batch: &StaleNodeIndexBatch,
+    ) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Persists one version of the tree with `writer`: writes the nodes of `node_batch` with
+/// [`TreeWriter::write_node_batch`], which a store supporting transactions should implement
+/// atomically, then records the indices of `stale_node_index_batch`. Returns `new_root`, the root
+/// hash the update was computed for, only if both succeeded.
+///
+/// On failure, some of the nodes may have been written. They are harmless: no root references
+/// them until the caller advances its root to `new_root`, which it must only do on success, and
+/// committing the same update again writes the same nodes, since they are content addressed. The
+/// stale nodes of an update must likewise not be pruned before its new root is in use.
+pub fn commit<K, V, W>(
+    writer: &W,
+    new_root: HashValue,
+    node_batch: NodeBatch<K, V>,
+    stale_node_index_batch: StaleNodeIndexBatch,
+) -> Result<HashValue>
+where
+    W: TreeWriter<K, V>,
+{
+    // An update creating any node creates a new root too, so only an update keeping the root
+    // node, if any, has no node to write.
+    ensure!(
+        node_batch.is_empty() || node_batch.contains_key(&new_root),
+        "New root {:x} is not in the node batch.",
+        new_root
+    );
+    writer.write_node_batch(&node_batch)?;
+    writer.write_stale_node_index_batch(&stale_node_index_batch)?;
+    Ok(new_root)
+}
+
+/// Same as [`commit`], but reads the new nodes back from `writer` once written and checks that
+/// they rebuild `new_root`, to catch a store losing or corrupting a write before the caller
+/// advances its root. The descent from `new_root` goes through every node of `node_batch` and
+/// checks that each one was stored and hashes with `H` to its node key, so the hashes of the
+/// internal nodes are recomputed from the children stored under them, up to the root.
+///
+/// This reads the whole batch back, so it is meant for bringing up or debugging a store rather
+/// than for every commit. Returns a [`SmtError::NodeNotFound`] or a [`SmtError::HashMismatch`]
+/// for the first node which was not stored as written.
+pub fn commit_verified<K, V, W, H>(
+    writer: &W,
+    new_root: HashValue,
+    node_batch: NodeBatch<K, V>,
+    stale_node_index_batch: StaleNodeIndexBatch,
+) -> Result<HashValue>
+where
+    K: Key,
+    V: Value,
+    W: TreeReader<K, V> + TreeWriter<K, V>,
+    H: TreeHasher,
+{
+    let new_node_keys = node_batch.keys().copied().collect::<HashSet<_>>();
+    commit(writer, new_root, node_batch, stale_node_index_batch)?;
+
+    // The nodes not in the batch were there before the update, only the new ones are checked.
+    let mut node_keys = vec![new_root];
+    while let Some(node_key) = node_keys.pop() {
+        let node = get_root_node::<K, V, W, H>(writer, &node_key)?;
+        let node_hash = node.merkle_hash_with::<H>();
+        ensure!(
+            node_hash == node_key,
+            SmtError::HashMismatch {
+                expected: node_key,
+                actual: node_hash,
+            }
+        );
+        if let Node::Internal(internal_node) = node {
+            node_keys.extend(
+                internal_node
+                    .children()
+                    .map(|(_, child)| child.hash)
+                    .filter(|child_key| new_node_keys.contains(child_key)),
+            );
+        }
+    }
+    Ok(new_root)
+}
+
+/// Detaches the values of more than `inline_threshold` bytes from the leaves of `node_batch`, see
+/// [`LeafNode::detach_above_with`](crate::LeafNode::detach_above_with), for a store keeping the large
+/// values apart from the nodes while the small ones stay inline. Returns the node batch to write,
+/// where the leaves of the detached values only hold their hash, and the detached values by value
+/// hash, to be read back with a [`ValueReader`](crate::ValueReader). Detaching a value does
+/// not change the hash of its leaf, so the node keys and the root hash are unchanged.
+pub fn detach_large_values<K, V, H>(
+    node_batch: NodeBatch<K, V>,
+    inline_threshold: usize,
+) -> (NodeBatch<K, V>, BTreeMap<HashValue, SMTObject<V>>)
+where
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    let mut values = BTreeMap::new();
+    let node_batch = node_batch
+        .into_iter()
+        .map(|(node_key, node)| match node {
+            Node::Leaf(leaf_node) => {
+                let (leaf_node, value) = leaf_node.detach_above_with::<H>(inline_threshold);
+                if let Some(value) = value {
+                    values.insert(leaf_node.value_hash_with::<H>(), value);
+                }
+                (node_key, Node::Leaf(leaf_node))
+            }
+            node => (node_key, node),
+        })
+        .collect();
+    (node_batch, values)
+}
+
+/// Deletes the `stale_nodes` from storage with `writer`, and returns the number of nodes deleted.
+/// A node key given more than once is deleted once.
+///
+/// To prune all the versions up to a target version, pass the node keys of the
+/// [`StaleNodeIndex`](crate::StaleNodeIndex)es emitted by the updates producing the versions
+/// after the oldest one up to the target. An index records the version since which its node is
+/// stale, not the one it was created at, so the nodes of the target version and of the later
+/// versions are kept. Since nodes are content addressed, a later update may write a node with
+/// the same key as a stale one again, for example when a value is set back: its key is then in a
+/// later [`NodeBatch`](crate::NodeBatch), and its stale index must be dropped instead of pruned.
+pub fn prune<K, V, W>(writer: &W, stale_nodes: impl IntoIterator<Item = NodeKey>) -> Result<usize>
+where
+    W: TreeWriter<K, V>,
+{
+    let node_keys = stale_nodes
+        .into_iter()
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect::<Vec<_>>();
+    writer.delete_node_batch(&node_keys)?;
+    Ok(node_keys.len())
 }
 
+/// An update of [`put_batch`](crate::jellyfish_merkle::JellyfishMerkleTree::put_batch): the hash of the
+/// key, and the key and the blob to put, or `None` to delete the key.
+type BatchUpdate<K, V> = (HashValue, Option<(SMTObject<K>, SMTObject<V>)>);
+
+/// The leaf at the end of the path of a key hash and the siblings along the path, see
+/// [`get_siblings`].
+pub type LeafWithSiblings<K, V> = (Option<LeafNode<K, V>>, Vec<HashValue>);
+
 /// Node batch that will be written into db atomically with other batches.
+///
+/// The nodes are ordered by node key, i.e. by hash, which bears no relation to their position in
+/// the tree: a parent may come before or after its children. Since a node key is the hash of the
+/// node, writing the batch in any order within a single transaction gives the same store. A
+/// backend which can not write atomically, and wants every node it has written to be readable
+/// with its whole subtree, has to write the children of a node before the node instead.
 pub type NodeBatch<K, V> = BTreeMap<NodeKey, Node<K, V>>;
 /// [`StaleNodeIndex`](struct.StaleNodeIndex.html) batch that will be written into db atomically
-/// with other batches.
+/// with other batches. The indices are ordered by the new root, then by node key, so they carry
+/// no order between the nodes either.
 pub type StaleNodeIndexBatch = BTreeSet<StaleNodeIndex>;
 
+/// Returns the nodes of `node_batch` ordered so that every node comes after the children it has
+/// in the batch. Children which are not in the batch were written by earlier batches.
+pub fn nodes_children_first<K, V>(node_batch: &NodeBatch<K, V>) -> Vec<(&NodeKey, &Node<K, V>)> {
+    let mut ordered = Vec::with_capacity(node_batch.len());
+    let mut visited = HashSet::with_capacity(node_batch.len());
+    for node_key in node_batch.keys() {
+        // A post-order traversal of the subtree of each node not visited yet, with an explicit
+        // stack of the nodes and whether their children have been pushed.
+        let mut stack = vec![(node_key, false)];
+        while let Some((node_key, children_pushed)) = stack.pop() {
+            if children_pushed {
+                ordered.push((node_key, &node_batch[node_key]));
+                continue;
+            }
+            if !visited.insert(node_key) {
+                continue;
+            }
+            stack.push((node_key, true));
+            if let Node::Internal(internal_node) = &node_batch[node_key] {
+                for child_key in internal_node.all_child() {
+                    if let Some((child_key, _)) = node_batch.get_key_value(&child_key) {
+                        stack.push((child_key, false));
+                    }
+                }
+            }
+        }
+    }
+    ordered
+}
+
 /// Indicates a node becomes stale since `stale_since_version`.
 #[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub struct StaleNodeIndex {
     /// The version since when the node is overwritten and becomes stale.
     pub stale_since_version: HashValue,
-    /// The [`NodeKey`](node_type/struct.NodeKey.html) identifying the node associated with this
+    /// The [`NodeKey`](crate::NodeKey) identifying the node associated with this
     /// record.
     pub node_key: NodeKey,
 }
@@ -161,11 +497,1029 @@ impl<K, V> Default for TreeUpdateBatch<K, V> {
     }
 }
 
-/// The Jellyfish Merkle tree data structure. See [`crate`] for description.
-pub struct JellyfishMerkleTree<'a, K, V, R: 'a + TreeReader<K, V>> {
+/// What a single-key put did to the key, see
+/// [`SMTree::put_with_outcome`](crate::SMTree::put_with_outcome).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PutOutcome<V> {
+    /// The key was not in the tree, a new leaf was created for it.
+    Inserted,
+    /// The key was in the tree with `old_value`, which was replaced. The value may have been the
+    /// same, in which case the tree is unchanged.
+    Updated { old_value: SMTObject<V> },
+}
+
+/// Reads the root node with `state_root_hash` from `reader`. The placeholder hash of `H` is the
+/// root hash of an empty tree, which is not stored.
+fn get_root_node<K, V, R, H>(reader: &R, state_root_hash: &HashValue) -> Result<Node<K, V>>
+where
+    R: TreeReader<K, V> + ?Sized,
+    H: TreeHasher,
+{
+    if H::is_empty_root(*state_root_hash) {
+        Ok(Node::Null)
+    } else {
+        reader.get_node(state_root_hash)
+    }
+}
+
+/// Extracts the subtree under `prefix` of the tree at `root` as a standalone tree, holding exactly
+/// the leaves whose key hash starts with `prefix`. Returns the root hash of the new tree, and the
+/// nodes to write to store it on its own: the nodes of the subtree, read from `reader`, and the
+/// internal nodes on the path from the new root down to the subtree. Proofs against the new root
+/// can then be generated and verified independently of the original tree.
+///
+/// If `prefix` leads to a single leaf, the new tree is that leaf. If it leads to nothing, the new
+/// tree is empty, with the placeholder root hash of `H` and no node to write.
+pub fn extract_subtree<K, V, R, H>(
+    reader: &R,
+    root: HashValue,
+    prefix: NibblePath,
+) -> Result<(HashValue, NodeBatch<K, V>)>
+where
+    R: TreeReader<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    ensure!(
+        prefix.num_nibbles() <= ROOT_NIBBLE_HEIGHT,
+        "Prefix is longer than a key hash: {} nibbles",
+        prefix.num_nibbles()
+    );
+    let nibbles = prefix.nibbles().collect::<Vec<_>>();
+    let mut node_batch = NodeBatch::new();
+
+    let mut node = get_root_node::<K, V, R, H>(reader, &root)?;
+    for nibble in &nibbles {
+        match node {
+            Node::Internal(internal_node) => match internal_node.child(*nibble) {
+                Some(child) => node = reader.get_node(&child.hash)?,
+                None => return Ok((H::SPARSE_MERKLE_PLACEHOLDER, node_batch)),
+            },
+            Node::Leaf(_) | Node::Null => break,
+        }
+    }
+
+    match node {
+        Node::Internal(internal_node) => {
+            let mut node_key = internal_node.merkle_hash_with::<H>();
+            let leaf_count = internal_node.leaf_count();
+            // Copy the subtree.
+            let mut node_keys = internal_node.all_child();
+            node_batch.insert(node_key, internal_node.into());
+            while let Some(child_key) = node_keys.pop() {
+                let child = reader.get_node(&child_key)?;
+                if let Node::Internal(internal_node) = &child {
+                    node_keys.extend(internal_node.all_child());
+                }
+                node_batch.insert(child_key, child);
+            }
+            // Rebuild the path down to the subtree, which is a chain of internal nodes with a
+            // single child since no other leaf is in the new tree.
+            for nibble in nibbles.into_iter().rev() {
+                let mut children = Children::new();
+                children.insert(nibble, Child::new_internal(node_key, leaf_count));
+                let internal_node = InternalNode::new(children);
+                node_key = internal_node.merkle_hash_with::<H>();
+                node_batch.insert(node_key, internal_node.into());
+            }
+            Ok((node_key, node_batch))
+        }
+        Node::Leaf(leaf_node) => {
+            let key_hash = leaf_node.key_hash_with::<H>();
+            let in_subtree = nibbles
+                .iter()
+                .enumerate()
+                .all(|(i, nibble)| key_hash.nibble(i) == u8::from(*nibble));
+            if !in_subtree {
+                return Ok((H::SPARSE_MERKLE_PLACEHOLDER, node_batch));
+            }
+            let node_key = leaf_node.merkle_hash_with::<H>();
+            node_batch.insert(node_key, Node::Leaf(leaf_node));
+            Ok((node_key, node_batch))
+        }
+        Node::Null => Ok((H::SPARSE_MERKLE_PLACEHOLDER, node_batch)),
+    }
+}
+
+/// Replaces the value of `key`, which must already be in the tree at `root`, with `new_value`.
+/// Returns the new root hash and the new nodes: the leaf and one internal node per level above it.
+/// Since the shape of the tree does not change, only the hashes on the path from the root to the
+/// leaf are recomputed, and the other children of the internal nodes on that path are kept as
+/// they are. The nodes of the old path are left to the caller to mark stale.
+///
+/// Fails if `key` is not in the tree, in which case the general put path has to be used.
+pub fn update_existing_leaf<K, V, R, H>(
+    reader: &R,
+    root: HashValue,
+    key: &SMTObject<K>,
+    new_value: SMTObject<V>,
+) -> Result<(HashValue, NodeBatch<K, V>)>
+where
+    R: TreeReader<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    let key_hash = key.merkle_hash_with::<H>();
+    let mut path = Vec::new();
+    let mut node_key = root;
+    let mut node = get_root_node::<K, V, R, H>(reader, &root)?;
+    // We limit the number of loops here deliberately to avoid potential cyclic graph bugs in the
+    // tree structure.
+    for nibble_depth in 0..=ROOT_NIBBLE_HEIGHT {
+        match node {
+            Node::Internal(internal_node) => {
+                ensure!(nibble_depth < ROOT_NIBBLE_HEIGHT, SmtError::cyclic());
+                let nibble = Nibble::from(key_hash.nibble(nibble_depth));
+                let child_node_key = match internal_node.child(nibble) {
+                    Some(child) => child.hash,
+                    None => bail!("Key {:x} does not exist in the tree.", key_hash),
+                };
+                path.push((internal_node, nibble));
+                node_key = child_node_key;
+                node = reader.get_node(&node_key)?;
+            }
+            Node::Leaf(leaf_node) => {
+                ensure!(
+                    leaf_node.key_hash_with::<H>() == key_hash,
+                    "Key {:x} does not exist in the tree.",
+                    key_hash
+                );
+                let mut node_batch = NodeBatch::new();
+                let new_leaf_node = LeafNode::new(key.clone(), new_value);
+                let mut node_key = new_leaf_node.merkle_hash_with::<H>();
+                node_batch.insert(node_key, Node::Leaf(new_leaf_node));
+                // Each internal node on the path gets the new hash of its child, the leaf counts
+                // are unchanged.
+                for (internal_node, nibble) in path.into_iter().rev() {
+                    let mut children = internal_node
+                        .children()
+                        .map(|(nibble, child)| (nibble, child.clone()))
+                        .collect::<Children>();
+                    if let Some(child) = children.get_mut(&nibble) {
+                        child.hash = node_key;
+                    }
+                    let new_internal_node = InternalNode::new(children);
+                    node_key = new_internal_node.merkle_hash_with::<H>();
+                    node_batch.insert(node_key, new_internal_node.into());
+                }
+                return Ok((node_key, node_batch));
+            }
+            Node::Null => {
+                ensure!(nibble_depth == 0, SmtError::UnexpectedNull(node_key));
+                bail!("Key {:x} does not exist in the tree.", key_hash);
+            }
+        }
+    }
+    bail!(SmtError::cyclic());
+}
+
+/// Returns the root hash the tree at `root` would have after the `updates`, without building the
+/// new nodes: it is the root hash [`put_batch`](crate::jellyfish_merkle::JellyfishMerkleTree::put_batch)
+/// returns for the same updates, and a `None` value deletes the key likewise. The subtrees are
+/// rebuilt bottom-up as by `put_batch`, but only the hashes of the new nodes are computed: the
+/// leaves are hashed from their key and value hashes, the internal nodes from their children,
+/// and neither is kept nor written to a batch.
+pub fn compute_root_after<K, V, R, H>(
+    reader: &R,
+    root: HashValue,
+    updates: Vec<(SMTObject<K>, Option<SMTObject<V>>)>,
+) -> Result<HashValue>
+where
+    R: TreeReader<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    let mut updates = updates
+        .into_iter()
+        .rev()
+        .map(|(key, value)| {
+            (
+                key.merkle_hash_with::<H>(),
+                value.map(|value| value.merkle_hash_with::<H>()),
+            )
+        })
+        .collect::<Vec<_>>();
+    // The sort is stable, so the last update of a key comes first and is the one kept.
+    updates.sort_by_key(|(key_hash, _)| *key_hash);
+    updates.dedup_by_key(|(key_hash, _)| *key_hash);
+    if updates.is_empty() {
+        return Ok(root);
+    }
+    let node = get_root_node::<K, V, R, H>(reader, &root)?;
+    let new_root = root_after_at::<K, V, R, H>(reader, root, node, 0, &updates)?;
+    Ok(new_root.map_or(H::SPARSE_MERKLE_PLACEHOLDER, |child| child.hash))
+}
+
+/// Helper function for `compute_root_after`, the counterpart of `batch_update_at` computing the
+/// new root of the subtree of `node` with `node_key` at `nibble_depth` after the `updates`, which
+/// are the key hashes and the new value hashes, sorted by key hash and all under this subtree.
+fn root_after_at<K, V, R, H>(
+    reader: &R,
+    node_key: NodeKey,
+    node: Node<K, V>,
+    nibble_depth: usize,
+    updates: &[(HashValue, Option<HashValue>)],
+) -> Result<Option<Child>>
+where
+    R: TreeReader<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    ensure!(nibble_depth <= ROOT_NIBBLE_HEIGHT, SmtError::cyclic());
+    let new_leaves = |updates: &[(HashValue, Option<HashValue>)]| {
+        updates
+            .iter()
+            .filter_map(|(key_hash, value_hash)| {
+                value_hash.map(|value_hash| (*key_hash, H::hash_leaf(*key_hash, value_hash)))
+            })
+            .collect::<Vec<_>>()
+    };
+    match node {
+        Node::Internal(internal_node) => {
+            ensure!(
+                nibble_depth < ROOT_NIBBLE_HEIGHT,
+                "Ran out of nibbles at internal node {:x}: the tree is deeper than a key hash.",
+                node_key
+            );
+            let mut children: Children = internal_node.clone().into();
+            let mut changed = false;
+            let mut remaining_updates = updates;
+            while let Some((key_hash, _)) = remaining_updates.first() {
+                let nibble = key_hash.nibble(nibble_depth);
+                let split = remaining_updates
+                    .partition_point(|(key_hash, _)| key_hash.nibble(nibble_depth) <= nibble);
+                let (child_updates, rest) = remaining_updates.split_at(split);
+                remaining_updates = rest;
+
+                let nibble = Nibble::from(nibble);
+                let old_child = internal_node.child(nibble);
+                let new_child = match old_child {
+                    Some(child) => {
+                        let child_node = reader.get_node(&child.hash)?;
+                        root_after_at::<K, V, R, H>(
+                            reader,
+                            child.hash,
+                            child_node,
+                            nibble_depth + 1,
+                            child_updates,
+                        )?
+                    }
+                    None => subtree_hash::<H>(&new_leaves(child_updates), nibble_depth + 1)?,
+                };
+                if old_child == new_child.as_ref() {
+                    continue;
+                }
+                changed = true;
+                match new_child {
+                    Some(child) => children.insert(nibble, child),
+                    None => children.remove(&nibble),
+                };
+            }
+
+            if !changed {
+                return Ok(Some(Child::new_internal(
+                    node_key,
+                    internal_node.leaf_count(),
+                )));
+            }
+            match children.len() {
+                0 => Ok(None),
+                // The only leaf left takes the place of this internal node.
+                1 if children.values().all(|child| child.is_leaf) => {
+                    Ok(children.into_values().next())
+                }
+                _ => {
+                    let internal_node = InternalNode::new(children);
+                    Ok(Some(Child::new_internal(
+                        internal_node.merkle_hash_with::<H>(),
+                        internal_node.leaf_count(),
+                    )))
+                }
+            }
+        }
+        Node::Leaf(leaf_node) => {
+            let leaf_key_hash = leaf_node.key_hash_with::<H>();
+            let mut leaves = new_leaves(updates);
+            // The existing leaf is kept unless it is updated or deleted.
+            if updates
+                .binary_search_by_key(&leaf_key_hash, |(key_hash, _)| *key_hash)
+                .is_err()
+            {
+                let index = leaves.partition_point(|(key_hash, _)| *key_hash < leaf_key_hash);
+                leaves.insert(index, (leaf_key_hash, node_key));
+            }
+            subtree_hash::<H>(&leaves, nibble_depth)
+        }
+        Node::Null => subtree_hash::<H>(&new_leaves(updates), nibble_depth),
+    }
+}
+
+/// Helper function for `compute_root_after`, the counterpart of `build_subtree` computing the root
+/// of the subtree at `nibble_depth` holding exactly the `leaves`, given by key hash and leaf hash
+/// and sorted by key hash.
+fn subtree_hash<H: TreeHasher>(
+    leaves: &[(HashValue, HashValue)],
+    nibble_depth: usize,
+) -> Result<Option<Child>> {
+    match leaves {
+        [] => Ok(None),
+        [(_, leaf_hash)] => Ok(Some(Child::new(*leaf_hash, true /* is_leaf */))),
+        _ => {
+            ensure!(
+                nibble_depth < ROOT_NIBBLE_HEIGHT,
+                "Leaves with the same key hash can not be in the same subtree."
+            );
+            let mut children = Children::new();
+            let mut remaining_leaves = leaves;
+            while let Some((key_hash, _)) = remaining_leaves.first() {
+                let nibble = key_hash.nibble(nibble_depth);
+                let split = remaining_leaves
+                    .partition_point(|(key_hash, _)| key_hash.nibble(nibble_depth) <= nibble);
+                let (child_leaves, rest) = remaining_leaves.split_at(split);
+                remaining_leaves = rest;
+                let child = subtree_hash::<H>(child_leaves, nibble_depth + 1)?
+                    .expect("A subtree with leaves is not empty.");
+                children.insert(Nibble::from(nibble), child);
+            }
+            let internal_node = InternalNode::new(children);
+            Ok(Some(Child::new_internal(
+                internal_node.merkle_hash_with::<H>(),
+                internal_node.leaf_count(),
+            )))
+        }
+    }
+}
+
+/// Removes the leaves whose key hash is in `[start, end]` from the tree at `root`, in a single
+/// bottom-up rebuild of the internal nodes above them. Returns the new root hash, the new nodes
+/// and the nodes of the old tree that became stale. The new tree is the one deleting each key on
+/// its own would produce, but only its final nodes are created.
+///
+/// The subtrees outside of the range are kept without being read, and the internal nodes of the
+/// subtrees inside of it are only read to mark them stale. As with a deletion, an internal node
+/// left with a single leaf is replaced by the leaf, and one left with nothing is removed.
+pub fn delete_range<K, V, R, H>(
+    reader: &R,
+    root: HashValue,
+    start: HashValue,
+    end: HashValue,
+) -> Result<(HashValue, NodeBatch<K, V>, StaleNodeIndexBatch)>
+where
+    R: TreeReader<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    let mut node_batch = NodeBatch::new();
+    let mut stale_node_keys = vec![];
+    if H::is_empty_root(root) || start > end {
+        return Ok((root, node_batch, StaleNodeIndexBatch::new()));
+    }
+    let new_root = match delete_range_at::<K, V, R, H>(
+        reader,
+        root,
+        &mut NibblePath::new(vec![]),
+        (start, end),
+        &mut node_batch,
+        &mut stale_node_keys,
+    )? {
+        RangeDeletion::Unchanged => root,
+        RangeDeletion::Removed => H::SPARSE_MERKLE_PLACEHOLDER,
+        RangeDeletion::Replaced(child) => child.hash,
+    };
+    let stale_node_index_batch = stale_node_keys
+        .into_iter()
+        .map(|node_key| StaleNodeIndex {
+            stale_since_version: new_root,
+            node_key,
+        })
+        .collect();
+    Ok((new_root, node_batch, stale_node_index_batch))
+}
+
+/// What `delete_range_at` did to a subtree.
+enum RangeDeletion {
+    /// No leaf of the subtree is in the range.
+    Unchanged,
+    /// All the leaves of the subtree are in the range.
+    Removed,
+    /// Some leaves of the subtree are in the range, the new subtree is the child.
+    Replaced(Child),
+}
+
+/// Helper function for `delete_range`, removing the leaves in `range` from the subtree of
+/// `node_key` under `path`.
+fn delete_range_at<K, V, R, H>(
+    reader: &R,
+    node_key: NodeKey,
+    path: &mut NibblePath,
+    range: (HashValue, HashValue),
+    node_batch: &mut NodeBatch<K, V>,
+    stale_node_keys: &mut Vec<NodeKey>,
+) -> Result<RangeDeletion>
+where
+    R: TreeReader<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    let (start, end) = range;
+    let (first_key_hash, last_key_hash) = iterator::prefix_key_hash_range(path);
+    if last_key_hash < start || end < first_key_hash {
+        return Ok(RangeDeletion::Unchanged);
+    }
+    let internal_node = match reader.get_node(&node_key)? {
+        Node::Internal(internal_node) => internal_node,
+        Node::Leaf(leaf_node) => {
+            let key_hash = leaf_node.key_hash_with::<H>();
+            if key_hash < start || end < key_hash {
+                return Ok(RangeDeletion::Unchanged);
+            }
+            stale_node_keys.push(node_key);
+            return Ok(RangeDeletion::Removed);
+        }
+        Node::Null => return Ok(RangeDeletion::Unchanged),
+    };
+    if start <= first_key_hash && last_key_hash <= end {
+        // The whole subtree goes, only its internal nodes need to be read to find its nodes.
+        let mut node_keys = vec![(node_key, Node::Internal(internal_node))];
+        while let Some((node_key, node)) = node_keys.pop() {
+            stale_node_keys.push(node_key);
+            if let Node::Internal(internal_node) = node {
+                for (_, child) in internal_node.children() {
+                    if child.is_leaf {
+                        stale_node_keys.push(child.hash);
+                    } else {
+                        node_keys.push((child.hash, reader.get_node(&child.hash)?));
+                    }
+                }
+            }
+        }
+        return Ok(RangeDeletion::Removed);
+    }
+    ensure!(
+        path.num_nibbles() < ROOT_NIBBLE_HEIGHT,
+        "Ran out of nibbles at internal node {:x}: the tree is deeper than a key hash.",
+        node_key
+    );
+
+    let mut children = Children::new();
+    let mut changed = false;
+    for (nibble, child) in internal_node.children() {
+        path.push(nibble);
+        let deletion = delete_range_at::<K, V, R, H>(
+            reader,
+            child.hash,
+            path,
+            range,
+            node_batch,
+            stale_node_keys,
+        )?;
+        path.pop();
+        match deletion {
+            RangeDeletion::Unchanged => {
+                children.insert(nibble, child.clone());
+            }
+            RangeDeletion::Removed => changed = true,
+            RangeDeletion::Replaced(new_child) => {
+                children.insert(nibble, new_child);
+                changed = true;
+            }
+        }
+    }
+    if !changed {
+        return Ok(RangeDeletion::Unchanged);
+    }
+    stale_node_keys.push(node_key);
+    if children.is_empty() {
+        return Ok(RangeDeletion::Removed);
+    }
+    if children.len() == 1 {
+        let (_, child) = children.iter().next().expect("Child should exist.");
+        if child.is_leaf {
+            return Ok(RangeDeletion::Replaced(child.clone()));
+        }
+    }
+    let new_node: Node<K, V> = InternalNode::new(children).into();
+    let new_node_key = new_node.merkle_hash_with::<H>();
+    let new_child = Child::for_node(new_node_key, &new_node);
+    node_batch.insert(new_node_key, new_node);
+    Ok(RangeDeletion::Replaced(new_child))
+}
+
+/// Returns whether `key` is in the tree at `root`. Unlike `JellyfishMerkleTree::get`, this only
+/// descends the nibble path of the key and compares the key hash of the leaf it lands on, so
+/// neither a proof is built nor the value cloned.
+pub fn contains_key<K, V, R, H>(reader: &R, root: HashValue, key: &SMTObject<K>) -> Result<bool>
+where
+    R: TreeReader<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    let key_hash = key.merkle_hash_with::<H>();
+    let mut node_key = root;
+    let mut node = get_root_node::<K, V, R, H>(reader, &root)?;
+    // We limit the number of loops here deliberately to avoid potential cyclic graph bugs in the
+    // tree structure.
+    for nibble_depth in 0..=ROOT_NIBBLE_HEIGHT {
+        match node {
+            Node::Internal(internal_node) => {
+                ensure!(nibble_depth < ROOT_NIBBLE_HEIGHT, SmtError::cyclic());
+                match internal_node.child(Nibble::from(key_hash.nibble(nibble_depth))) {
+                    Some(child) => {
+                        node_key = child.hash;
+                        node = reader.get_node(&node_key)?;
+                    }
+                    None => return Ok(false),
+                }
+            }
+            Node::Leaf(leaf_node) => return Ok(leaf_node.key_hash_with::<H>() == key_hash),
+            Node::Null => {
+                ensure!(nibble_depth == 0, SmtError::UnexpectedNull(node_key));
+                return Ok(false);
+            }
+        }
+    }
+    bail!(SmtError::cyclic());
+}
+
+/// Returns the leaf at the end of the path of `key_hash` in the tree at `root` and the siblings
+/// along this path, see [`SparseMerkleProof`]. The leaf is the one with `key_hash`, another leaf
+/// whose subtree `key_hash` would fall in, or `None` if the path ends at an empty subtree. The
+/// siblings are in the order of the descent, from the root to the leaf, which is the reverse of
+/// the order of [`SparseMerkleProof::siblings`]. Each internal node on the path adds one sibling
+/// per level of its binary subtree the path goes through, up to 4.
+pub fn get_siblings<K, V, R, H>(
+    reader: &R,
+    root: HashValue,
+    key: &SMTObject<K>,
+) -> Result<LeafWithSiblings<K, V>>
+where
+    R: TreeReader<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    let (leaf_with_siblings, _) =
+        descend_with_siblings::<K, V, R, H>(reader, root, key.merkle_hash_with::<H>())?;
+    Ok(leaf_with_siblings)
+}
+
+/// Does the work of `get_siblings`, also returning the nibble depth the descent stopped at.
+fn descend_with_siblings<K, V, R, H>(
+    reader: &R,
+    root: HashValue,
+    key_hash: HashValue,
+) -> Result<(LeafWithSiblings<K, V>, usize)>
+where
+    R: TreeReader<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    let mut next_node_key = root;
+    let mut siblings = vec![];
+    let mut nibble_iter = NibblePath::from_slice(key_hash.as_ref()).nibbles();
+
+    // We limit the number of loops here deliberately to avoid potential cyclic graph bugs
+    // in the tree structure.
+    for nibble_depth in 0..=ROOT_NIBBLE_HEIGHT {
+        let next_node = if nibble_depth == 0 {
+            get_root_node::<_, _, _, H>(reader, &next_node_key)?
+        } else {
+            reader.get_node(&next_node_key)?
+        };
+        match next_node {
+            Node::Internal(internal_node) => {
+                let queried_child_index = nibble_iter
+                    .next()
+                    .ok_or_else(|| SmtError::CorruptNode("ran out of nibbles".to_string()))?;
+                let (child_node_key, mut siblings_in_internal) =
+                    internal_node.get_child_with_siblings::<H>(queried_child_index);
+                siblings.append(&mut siblings_in_internal);
+                match child_node_key {
+                    Some(node_key) => next_node_key = node_key,
+                    None => return Ok(((None, siblings), nibble_depth + 1)),
+                }
+            }
+            Node::Leaf(leaf_node) => return Ok(((Some(leaf_node), siblings), nibble_depth)),
+            Node::Null => {
+                ensure!(nibble_depth == 0, SmtError::UnexpectedNull(next_node_key));
+                return Ok(((None, siblings), 0));
+            }
+        }
+    }
+    bail!(SmtError::cyclic());
+}
+
+/// Where a lookup goes after visiting a node, see `get_with`.
+enum LookupStep<T> {
+    Child(NodeKey),
+    Found(Result<T>),
+    Absent,
+    Null,
+}
+
+/// Looks up `key` in the tree at `root` and returns `f` applied to its value, or `None` if the key
+/// is absent. The nodes are visited with [`TreeReader::with_node`], so with a storage lending the
+/// nodes it holds in memory, `f` borrows the value from the storage and the value is never cloned.
+pub fn get_with<K, V, R, H, T, F>(
+    reader: &R,
+    root: HashValue,
+    key: &SMTObject<K>,
+    f: F,
+) -> Result<Option<T>>
+where
+    R: TreeReader<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+    F: FnOnce(&SMTObject<V>) -> T,
+{
+    if H::is_empty_root(root) {
+        return Ok(None);
+    }
+    let key_hash = key.merkle_hash_with::<H>();
+    let mut f = Some(f);
+    let mut node_key = root;
+    // We limit the number of loops here deliberately to avoid potential cyclic graph bugs in the
+    // tree structure.
+    for nibble_depth in 0..=ROOT_NIBBLE_HEIGHT {
+        let step = reader
+            .with_node(&node_key, |node| match node {
+                Node::Internal(internal_node) => {
+                    match internal_node.child(Nibble::from(key_hash.nibble(nibble_depth))) {
+                        Some(child) => LookupStep::Child(child.hash),
+                        None => LookupStep::Absent,
+                    }
+                }
+                Node::Leaf(leaf_node) if leaf_node.key_hash_with::<H>() == key_hash => {
+                    match f.take() {
+                        Some(f) => LookupStep::Found(leaf_node.try_value().map(f)),
+                        None => LookupStep::Absent,
+                    }
+                }
+                Node::Leaf(_) => LookupStep::Absent,
+                Node::Null => LookupStep::Null,
+            })?
+            .ok_or(SmtError::NodeNotFound(node_key))?;
+        match step {
+            LookupStep::Child(child_node_key) => {
+                ensure!(nibble_depth < ROOT_NIBBLE_HEIGHT, SmtError::cyclic());
+                node_key = child_node_key;
+            }
+            LookupStep::Found(value) => return value.map(Some),
+            LookupStep::Absent => return Ok(None),
+            LookupStep::Null => {
+                ensure!(nibble_depth == 0, SmtError::UnexpectedNull(node_key));
+                return Ok(None);
+            }
+        }
+    }
+    bail!(SmtError::cyclic());
+}
+
+/// Returns the values of `keys` in the tree at `root`, in the order of `keys`, with `None` for the
+/// absent keys. The keys are sorted by key hash and the union of their paths is walked once, level
+/// by level: a node shared by the paths of several keys is read once, and all the nodes of a level
+/// are read with a single `get_nodes` call, so the number of round-trips to the storage is bounded
+/// by the depth of the tree rather than by the number of keys.
+pub fn get_many<K, V, R, H>(
+    reader: &R,
+    root: HashValue,
+    keys: &[SMTObject<K>],
+) -> Result<Vec<Option<SMTObject<V>>>>
+where
+    R: TreeReader<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    let mut sorted_keys = keys
+        .iter()
+        .enumerate()
+        .map(|(index, key)| (key.merkle_hash_with::<H>(), index))
+        .collect::<Vec<_>>();
+    sorted_keys.sort();
+    let mut values = vec![None; keys.len()];
+    if sorted_keys.is_empty() {
+        return Ok(values);
+    }
+
+    // The nodes of the current level, with the keys whose path goes through each of them.
+    let root_node = get_root_node::<K, V, R, H>(reader, &root)?;
+    let mut level = vec![(root, root_node, &sorted_keys[..])];
+    for nibble_depth in 0..=ROOT_NIBBLE_HEIGHT {
+        let mut children = vec![];
+        for (node_key, node, node_keys) in level {
+            match node {
+                Node::Internal(internal_node) => {
+                    ensure!(nibble_depth < ROOT_NIBBLE_HEIGHT, SmtError::cyclic());
+                    // The keys share the nibbles before `nibble_depth`, so they are sorted by the
+                    // nibble at `nibble_depth`.
+                    let mut remaining_keys = node_keys;
+                    while let Some((key_hash, _)) = remaining_keys.first() {
+                        let nibble = key_hash.nibble(nibble_depth);
+                        let split = remaining_keys.partition_point(|(key_hash, _)| {
+                            key_hash.nibble(nibble_depth) <= nibble
+                        });
+                        let (child_keys, rest) = remaining_keys.split_at(split);
+                        remaining_keys = rest;
+                        if let Some(child) = internal_node.child(Nibble::from(nibble)) {
+                            children.push((child.hash, child_keys));
+                        }
+                    }
+                }
+                Node::Leaf(leaf_node) => {
+                    let leaf_key_hash = leaf_node.key_hash_with::<H>();
+                    for (key_hash, index) in node_keys {
+                        if *key_hash == leaf_key_hash {
+                            values[*index] = Some(leaf_node.try_value()?.clone());
+                        }
+                    }
+                }
+                Node::Null => {
+                    ensure!(nibble_depth == 0, SmtError::UnexpectedNull(node_key));
+                }
+            }
+        }
+        if children.is_empty() {
+            return Ok(values);
+        }
+
+        let child_node_keys = children
+            .iter()
+            .map(|(child_node_key, _)| *child_node_key)
+            .collect::<Vec<_>>();
+        let child_nodes = reader.get_nodes(&child_node_keys)?;
+        level = children
+            .into_iter()
+            .zip(child_nodes)
+            .map(|((child_node_key, child_keys), child_node)| {
+                let child_node = child_node.ok_or(SmtError::NodeNotFound(child_node_key))?;
+                Ok((child_node_key, child_node, child_keys))
+            })
+            .collect::<Result<Vec<_>>>()?;
+    }
+    bail!(SmtError::cyclic());
+}
+
+/// Builds the tree holding exactly `leaves`, which must be sorted by key hash, in a single pass.
+/// Returns the root hash of the tree and its nodes, or the placeholder root hash of `H` and an
+/// empty batch if there is no leaf. Fails if a key hash is not greater than the one before it, in
+/// which case the keys are either out of order or duplicated.
+///
+/// The tree is built bottom-up: each leaf only closes the internal nodes it does not share with
+/// the leaf before it, so apart from the returned batch, only the internal nodes on the path to
+/// the last leaf are held in memory.
+pub fn build_from_sorted<K, V, H>(
+    leaves: impl IntoIterator<Item = (SMTObject<K>, SMTObject<V>)>,
+) -> Result<(HashValue, NodeBatch<K, V>)>
+where
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    let mut builder = SortedTreeBuilder::<K, V, H>::new();
+    for (key, value) in leaves {
+        builder.push(key, value)?;
+    }
+    Ok(builder.finish())
+}
+
+/// Builds the tree holding `pairs`, in any order, like `build_from_sorted`. If several pairs have
+/// the same key, the last one wins: the tree holds the value of the last pair with this key, as
+/// if the pairs had been inserted one after the other. The pairs are held in memory to be sorted.
+pub fn from_pairs<K, V, H>(
+    pairs: impl IntoIterator<Item = (SMTObject<K>, SMTObject<V>)>,
+) -> Result<(HashValue, NodeBatch<K, V>)>
+where
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    let mut leaves = BTreeMap::new();
+    for (key, value) in pairs {
+        leaves.insert(key.merkle_hash_with::<H>(), (key, value));
+    }
+    build_from_sorted::<K, V, H>(leaves.into_values())
+}
+
+/// The state of `build_from_sorted`, which can also be fed the leaves a few at a time, taking the
+/// completed nodes out in between so they do not pile up in memory.
+pub(crate) struct SortedTreeBuilder<K, V, H> {
+    /// The completed nodes not taken out yet.
+    node_batch: NodeBatch<K, V>,
+    /// The internal nodes on the path to the last leaf which may still get children, by
+    /// increasing depth, with the children they already have.
+    open_nodes: Vec<(usize, Children)>,
+    /// The key hash of the last leaf, and the subtree holding it which is not attached yet.
+    last: Option<(HashValue, Subtree)>,
+    hasher: PhantomData<H>,
+}
+
+impl<K, V, H> SortedTreeBuilder<K, V, H>
+where
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    pub(crate) fn new() -> Self {
+        Self {
+            node_batch: NodeBatch::new(),
+            open_nodes: vec![],
+            last: None,
+            hasher: PhantomData,
+        }
+    }
+
+    /// Adds the next leaf, whose key hash must be greater than the one of the last leaf.
+    pub(crate) fn push(&mut self, key: SMTObject<K>, value: SMTObject<V>) -> Result<()> {
+        let key_hash = key.merkle_hash_with::<H>();
+        if let Some((last_key_hash, _)) = &self.last {
+            ensure!(
+                *last_key_hash < key_hash,
+                "Leaves are not sorted by key hash: {:x} is not greater than {:x}.",
+                key_hash,
+                last_key_hash
+            );
+        }
+        let leaf_node = Node::new_leaf(key, value);
+        let leaf_node_key = leaf_node.merkle_hash_with::<H>();
+        self.node_batch.insert(leaf_node_key, leaf_node);
+        let leaf = Subtree::Leaf(leaf_node_key);
+
+        let (last_key_hash, mut subtree) = match self.last.replace((key_hash, leaf)) {
+            Some(last) => last,
+            None => return Ok(()),
+        };
+        // The lowest common ancestor of the two leaves is at the depth of their common prefix.
+        let depth = last_key_hash.common_prefix_bits_len(key_hash) / 4;
+        while let Some((open_depth, _)) = self.open_nodes.last() {
+            if *open_depth <= depth {
+                break;
+            }
+            let (open_depth, children) = self.open_nodes.pop().expect("Open node should exist.");
+            subtree = close_internal_node::<K, V, H>(
+                &mut self.node_batch,
+                open_depth,
+                children,
+                last_key_hash,
+                subtree,
+            );
+        }
+        // The lowest common ancestor is open already if the last leaf has a sibling before it.
+        let child = subtree.into_child::<K, V, H>(&mut self.node_batch, depth, last_key_hash);
+        let nibble = Nibble::from(last_key_hash.nibble(depth));
+        match self.open_nodes.last_mut() {
+            Some((open_depth, children)) if *open_depth == depth => {
+                children.insert(nibble, child);
+            }
+            _ => {
+                let mut children = Children::new();
+                children.insert(nibble, child);
+                self.open_nodes.push((depth, children));
+            }
+        }
+        Ok(())
+    }
+
+    /// Takes out the nodes completed so far, which the nodes completed later may reference.
+    pub(crate) fn take_node_batch(&mut self) -> NodeBatch<K, V> {
+        std::mem::take(&mut self.node_batch)
+    }
+
+    /// Completes the tree, returning its root hash and the nodes not taken out yet.
+    pub(crate) fn finish(mut self) -> (HashValue, NodeBatch<K, V>) {
+        let (last_key_hash, mut subtree) = match self.last {
+            Some(last) => last,
+            None => return (H::SPARSE_MERKLE_PLACEHOLDER, self.node_batch),
+        };
+        while let Some((open_depth, children)) = self.open_nodes.pop() {
+            subtree = close_internal_node::<K, V, H>(
+                &mut self.node_batch,
+                open_depth,
+                children,
+                last_key_hash,
+                subtree,
+            );
+        }
+        let root = match subtree {
+            Subtree::Leaf(node_key) => node_key,
+            // The root is at depth 0, the path down to the internal node is a chain of internal
+            // nodes with a single child.
+            Subtree::Internal(depth, child) => {
+                lift::<K, V, H>(&mut self.node_batch, child, depth, 0, last_key_hash).hash
+            }
+        };
+        (root, self.node_batch)
+    }
+}
+
+/// A subtree built by `build_from_sorted` which is not attached to its parent yet.
+enum Subtree {
+    /// A leaf with its node key. A leaf is a child of the node right above it, at any depth.
+    Leaf(NodeKey),
+    /// An internal node at the given depth.
+    Internal(usize, Child),
+}
+
+impl Subtree {
+    /// Returns the subtree as a child of an internal node at `parent_depth`. An internal node
+    /// deeper than the depth right below the parent is put at the end of a chain of internal nodes
+    /// with a single child, which are added to `node_batch`. `key_hash` is any key hash in the
+    /// subtree, which gives the nibbles on the path to it.
+    fn into_child<K, V, H>(
+        self,
+        node_batch: &mut NodeBatch<K, V>,
+        parent_depth: usize,
+        key_hash: HashValue,
+    ) -> Child
+    where
+        K: Key,
+        V: Value,
+        H: TreeHasher,
+    {
+        match self {
+            Subtree::Leaf(node_key) => Child::new(node_key, true /* is_leaf */),
+            Subtree::Internal(depth, child) => {
+                lift::<K, V, H>(node_batch, child, depth, parent_depth + 1, key_hash)
+            }
+        }
+    }
+}
+
+/// Helper function for `build_from_sorted`, returning the internal node at `to_depth` on the path
+/// from it to `child`, the internal node at `from_depth`. The nodes in between have a single child
+/// and are added to `node_batch`.
+fn lift<K, V, H>(
+    node_batch: &mut NodeBatch<K, V>,
+    mut child: Child,
+    from_depth: usize,
+    to_depth: usize,
+    key_hash: HashValue,
+) -> Child
+where
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    for depth in (to_depth..from_depth).rev() {
+        let mut children = Children::new();
+        children.insert(Nibble::from(key_hash.nibble(depth)), child);
+        child = add_internal_node::<K, V, H>(node_batch, children);
+    }
+    child
+}
+
+/// Helper function for `build_from_sorted`, attaching `subtree`, which holds `key_hash`, to the
+/// internal node at `depth` with `children`. The node is then complete and added to `node_batch`.
+fn close_internal_node<K, V, H>(
+    node_batch: &mut NodeBatch<K, V>,
+    depth: usize,
+    mut children: Children,
+    key_hash: HashValue,
+    subtree: Subtree,
+) -> Subtree
+where
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    let child = subtree.into_child::<K, V, H>(node_batch, depth, key_hash);
+    children.insert(Nibble::from(key_hash.nibble(depth)), child);
+    Subtree::Internal(depth, add_internal_node::<K, V, H>(node_batch, children))
+}
+
+/// Helper function for `build_from_sorted`, adding the internal node with `children` to
+/// `node_batch`. Returns it as a child of its parent.
+fn add_internal_node<K, V, H>(node_batch: &mut NodeBatch<K, V>, children: Children) -> Child
+where
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    let internal_node = InternalNode::new(children);
+    let leaf_count = internal_node.leaf_count();
+    let internal_node: Node<K, V> = internal_node.into();
+    let node_key = internal_node.merkle_hash_with::<H>();
+    node_batch.insert(node_key, internal_node);
+    Child::new_internal(node_key, leaf_count)
+}
+
+/// The Jellyfish Merkle tree data structure. See [`crate`] for description. The nodes are hashed
+/// with `H`, see [`TreeHasher`](crate::TreeHasher).
+pub struct JellyfishMerkleTree<'a, K, V, R: 'a + TreeReader<K, V>, H = Sha3TreeHasher> {
     reader: &'a R,
+    observer: Option<&'a dyn Observer>,
     key: PhantomData<K>,
     value: PhantomData<V>,
+    hasher: PhantomData<H>,
 }
 
 impl<'a, K, V, R> JellyfishMerkleTree<'a, K, V, R>
@@ -176,10 +1530,39 @@ where
 {
     /// Creates a `JellyfishMerkleTree` backed by the given [`TreeReader`](trait.TreeReader.html).
     pub fn new(reader: &'a R) -> Self {
+        Self::new_with_hasher(reader)
+    }
+}
+
+impl<'a, K, V, R, H> JellyfishMerkleTree<'a, K, V, R, H>
+where
+    K: Key,
+    V: Value,
+    R: 'a + TreeReader<K, V>,
+    H: TreeHasher,
+{
+    /// Creates a `JellyfishMerkleTree` backed by the given [`TreeReader`](crate::TreeReader)
+    /// whose nodes are hashed with `H`.
+    pub fn new_with_hasher(reader: &'a R) -> Self {
         Self {
             reader,
+            observer: None,
             key: PhantomData,
             value: PhantomData,
+            hasher: PhantomData,
+        }
+    }
+
+    /// Reports the descents of the lookups to `observer`. The node reads are reported by the
+    /// reader, see [`ObservedTreeReader`](crate::ObservedTreeReader).
+    pub fn with_observer(mut self, observer: Option<&'a dyn Observer>) -> Self {
+        self.observer = observer;
+        self
+    }
+
+    fn observe_descent(&self, depth: usize) {
+        if let Some(observer) = self.observer {
+            observer.on_descent(depth);
         }
     }
 
@@ -201,8 +1584,11 @@ where
         &self,
         state_root_hash: HashValue,
         start_key: PK,
-    ) -> Result<()> {
-        let iter = self::iterator::JellyfishMerkleIterator::new(
+    ) -> Result<()>
+    where
+        R: Versioned<K, V>,
+    {
+        let iter = self::iterator::JellyfishMerkleIterator::<_, _, _, H>::new(
             self.reader,
             state_root_hash,
             Some(start_key.into()),
@@ -210,8 +1596,14 @@ where
         iter.print()
     }
 
-    /// Delete a key from the tree. If the key is not found in the tree, nothing happens.
-    #[cfg(test)]
+    /// Delete a key from the tree, return updated root hash and tree updates. If the key is not
+    /// found in the tree, nothing happens.
+    ///
+    /// The leaf of the key and the internal nodes on its path become stale, and are reported in
+    /// the `stale_node_index_batch` of the returned batch so they can be pruned once the new root
+    /// is committed. An internal node left with a single child which is a leaf is replaced by that
+    /// leaf, up to the root, so the tree is the same as if the key had never been inserted. Once
+    /// the last key is deleted the root hash is the placeholder hash of an empty tree.
     pub fn delete<DK: Into<SMTObject<K>>>(
         &self,
         state_root_hash: Option<HashValue>,
@@ -233,6 +1625,42 @@ where
         self.updates(state_root_hash, blob_set)
     }
 
+    /// Applies all the `updates` to the tree in a single traversal, return updated root hash and
+    /// tree updates. A `None` value deletes the key. The updates are sorted by key hash and each
+    /// subtree they touch is visited once, so every node on their paths is read once and every new
+    /// internal node is built once, bottom-up, instead of once per update. If a key is updated
+    /// more than once, the last update wins.
+    ///
+    /// The result is the same as the one of `updates`, which applies the updates one at a time.
+    pub fn put_batch(
+        &self,
+        state_root_hash: Option<HashValue>,
+        updates: Vec<(SMTObject<K>, Option<SMTObject<V>>)>,
+    ) -> Result<(HashValue, TreeUpdateBatch<K, V>)> {
+        let mut updates = updates
+            .into_iter()
+            .rev()
+            .map(|(key, blob)| (key.merkle_hash_with::<H>(), blob.map(|blob| (key, blob))))
+            .collect::<Vec<_>>();
+        // The sort is stable, so the last update of a key comes first and is the one kept.
+        updates.sort_by_key(|(key_hash, _)| *key_hash);
+        updates.dedup_by_key(|(key_hash, _)| *key_hash);
+
+        let mut tree_cache = TreeCache::<_, _, _, H>::new(self.reader, state_root_hash);
+        if !updates.is_empty() {
+            let root_node_key = *tree_cache.get_root_node_key();
+            let root = tree_cache.get_node(&root_node_key)?;
+            let new_root =
+                Self::batch_update_at(root_node_key, root, 0, &mut updates, &mut tree_cache)?;
+            tree_cache.set_root_node_key(
+                new_root.map_or(H::SPARSE_MERKLE_PLACEHOLDER, |child| child.hash),
+            );
+        }
+        tree_cache.freeze()?;
+        let (root_hashes, tree_update_batch) = tree_cache.into();
+        Ok((root_hashes[0], tree_update_batch))
+    }
+
     pub fn updates<S: Into<Vec<(SMTObject<K>, Option<SMTObject<V>>)>>>(
         &self,
         state_root_hash: Option<HashValue>,
@@ -293,7 +1721,7 @@ where
         state_root_hash: Option<HashValue>,
         blob_sets: Vec<Vec<(SMTObject<K>, Option<SMTObject<V>>)>>,
     ) -> Result<(Vec<HashValue>, TreeUpdateBatch<K, V>)> {
-        let mut tree_cache = TreeCache::new(self.reader, state_root_hash);
+        let mut tree_cache = TreeCache::<_, _, _, H>::new(self.reader, state_root_hash);
         for (_idx, blob_set) in blob_sets.into_iter().enumerate() {
             assert!(
                 !blob_set.is_empty(),
@@ -301,22 +1729,58 @@ where
             );
             blob_set
                 .into_iter()
-                .try_for_each(|(key, blob)| Self::put(key, blob, &mut tree_cache))?;
+                .try_for_each(|(key, blob)| Self::put(key, blob, &mut tree_cache).map(drop))?;
             // Freezes the current cache to make all contents in the current cache immutable.
             // TODO: maybe we should not freeze, check here again.
-            tree_cache.freeze();
+            tree_cache.freeze()?;
         }
 
         Ok(tree_cache.into())
     }
 
+    /// Inserts `value` at `key`, return updated root hash and tree updates, and whether the key was
+    /// newly inserted or its value updated, in which case the old value is returned. The outcome
+    /// comes from the leaf met on the way down, so there is no need for a `get` beforehand.
+    ///
+    /// Fails if the old value is detached from its leaf, see
+    /// [`detach_large_values`](crate::jellyfish_merkle::detach_large_values).
+    pub fn put_with_outcome(
+        &self,
+        state_root_hash: Option<HashValue>,
+        key: SMTObject<K>,
+        value: SMTObject<V>,
+    ) -> Result<(HashValue, TreeUpdateBatch<K, V>, PutOutcome<V>)> {
+        let mut tree_cache = TreeCache::<_, _, _, H>::new(self.reader, state_root_hash);
+        let old_leaf = Self::put(key, Some(value), &mut tree_cache)?;
+        let outcome = match old_leaf {
+            Some(old_leaf) => PutOutcome::Updated {
+                old_value: old_leaf.try_value()?.clone(),
+            },
+            None => PutOutcome::Inserted,
+        };
+        tree_cache.freeze()?;
+        let (root_hashes, tree_update_batch) = tree_cache.into();
+        Ok((root_hashes[0], tree_update_batch, outcome))
+    }
+
+    /// Puts `blob` at `key`, or deletes it if `blob` is `None`, returning the leaf the key had
+    /// before if any.
     fn put(
         key: SMTObject<K>,
         blob: Option<SMTObject<V>>,
-        tree_cache: &mut TreeCache<R, K, V>,
-    ) -> Result<()> {
-        let key_hash = key.merkle_hash();
-        let nibble_path = NibblePath::new(key_hash.to_vec());
+        tree_cache: &mut TreeCache<R, K, V, H>,
+    ) -> Result<Option<LeafNode<K, V>>> {
+        let key_hash = key.merkle_hash_with::<H>();
+        let nibble_path = NibblePath::from_slice(key_hash.as_ref());
+        // The descent below relies on every key having a path of the same length, whatever the
+        // length of its encoding.
+        ensure!(
+            nibble_path.num_nibbles() == ROOT_NIBBLE_HEIGHT,
+            "Key hash {:x} gives a path of {} nibbles instead of {}.",
+            key_hash,
+            nibble_path.num_nibbles(),
+            ROOT_NIBBLE_HEIGHT
+        );
 
         // Get the root node. If this is the first operation, it would get the root node from the
         // underlying db. Otherwise it most likely would come from `cache`.
@@ -324,15 +1788,23 @@ where
         let mut nibble_iter = nibble_path.nibbles();
 
         // Start insertion from the root node.
-        let (new_root_node_key, _) =
-            Self::insert_at(*root_node_key, &mut nibble_iter, key, blob, tree_cache)?;
+        let mut old_leaf = None;
+        let (new_root_node_key, _) = Self::insert_at(
+            *root_node_key,
+            &mut nibble_iter,
+            key,
+            blob,
+            tree_cache,
+            &mut old_leaf,
+        )?;
 
         tree_cache.set_root_node_key(new_root_node_key);
-        Ok(())
+        Ok(old_leaf)
     }
 
     /// Helper function for recursive insertion into the subtree that starts from the current
-    /// [`NodeKey`](node_type/struct.NodeKey.html). Returns the newly inserted node.
+    /// [`NodeKey`](crate::NodeKey). Returns the newly inserted node, and sets
+    /// `old_leaf` to the leaf of the key if it is in the subtree.
     /// It is safe to use recursion here because the max depth is limited by the key length which
     /// for this tree is the length of the hash of account addresses.
     fn insert_at(
@@ -340,7 +1812,8 @@ where
         nibble_iter: &mut NibbleIterator,
         key: SMTObject<K>,
         blob: Option<SMTObject<V>>,
-        tree_cache: &mut TreeCache<R, K, V>,
+        tree_cache: &mut TreeCache<R, K, V, H>,
+        old_leaf: &mut Option<LeafNode<K, V>>,
     ) -> Result<(NodeKey, Node<K, V>)> {
         let node = tree_cache.get_node(&node_key)?;
         match node {
@@ -351,10 +1824,17 @@ where
                 key,
                 blob,
                 tree_cache,
+                old_leaf,
+            ),
+            Node::Leaf(leaf_node) => Self::insert_at_leaf_node(
+                node_key,
+                leaf_node,
+                nibble_iter,
+                key,
+                blob,
+                tree_cache,
+                old_leaf,
             ),
-            Node::Leaf(leaf_node) => {
-                Self::insert_at_leaf_node(node_key, leaf_node, nibble_iter, key, blob, tree_cache)
-            }
             Node::Null => match blob {
                 None => Ok((node_key, node)),
                 Some(blob) => {
@@ -374,22 +1854,26 @@ where
         nibble_iter: &mut NibbleIterator,
         key: SMTObject<K>,
         blob: Option<SMTObject<V>>,
-        tree_cache: &mut TreeCache<R, K, V>,
+        tree_cache: &mut TreeCache<R, K, V, H>,
+        old_leaf: &mut Option<LeafNode<K, V>>,
     ) -> Result<(NodeKey, Node<K, V>)> {
         // Find the next node to visit following the next nibble as index.
-        let child_index = nibble_iter.next().expect("Ran out of nibbles");
+        let child_index = nibble_iter.next().ok_or_else(|| {
+            format_err!(
+                "Ran out of nibbles at internal node {:x}: the tree is deeper than a key hash.",
+                node_key
+            )
+        })?;
 
         // Traverse downwards from this internal node recursively to get the `node_key` of the child
         // node at `child_index`.
         let (new_child_key, new_child_node) = match internal_node.child(child_index) {
             Some(child) => {
-                // let child_node_key = node_key.gen_child_node_key(child.version, child_index);
                 let child_node_key = child.hash;
-                Self::insert_at(child_node_key, nibble_iter, key, blob, tree_cache)?
+                Self::insert_at(child_node_key, nibble_iter, key, blob, tree_cache, old_leaf)?
             }
             None if blob.is_some() => {
                 let blob = blob.expect("blob must be some at here");
-                // let new_child_node_key = node_key.gen_child_node_key(version, child_index);
                 Self::create_leaf_node(key, blob, tree_cache)?
             }
             _ => return Ok((node_key, Node::from(internal_node))),
@@ -416,16 +1900,13 @@ where
         match &new_child_node {
             Node::Null => {}
             _ => {
-                children.insert(
-                    child_index,
-                    Child::new(new_child_key, new_child_node.is_leaf()),
-                );
+                children.insert(child_index, Child::for_node(new_child_key, &new_child_node));
             }
         }
 
         if children.is_empty() {
             let empty_node = Node::new_null();
-            Ok((empty_node.merkle_hash(), empty_node))
+            Ok((empty_node.merkle_hash_with::<H>(), empty_node))
         } else if children.len() == 1
             && children
                 .values()
@@ -439,8 +1920,11 @@ where
         } else {
             let new_internal_node: Node<K, V> = InternalNode::new(children).into();
             // Cache this new internal node.
-            tree_cache.put_node(new_internal_node.merkle_hash(), new_internal_node.clone())?;
-            Ok((new_internal_node.merkle_hash(), new_internal_node))
+            tree_cache.put_node(
+                new_internal_node.merkle_hash_with::<H>(),
+                new_internal_node.clone(),
+            )?;
+            Ok((new_internal_node.merkle_hash_with::<H>(), new_internal_node))
         }
     }
 
@@ -453,7 +1937,8 @@ where
         nibble_iter: &mut NibbleIterator,
         key: SMTObject<K>,
         blob: Option<SMTObject<V>>,
-        tree_cache: &mut TreeCache<R, K, V>,
+        tree_cache: &mut TreeCache<R, K, V, H>,
+        old_leaf: &mut Option<LeafNode<K, V>>,
     ) -> Result<(NodeKey, Node<K, V>)> {
         // We are on a leaf node but trying to insert another node, so we may diverge.
         // We always delete the existing leaf node here because it will not be referenced anyway
@@ -463,7 +1948,8 @@ where
         // visited part of the nibble iter of the incoming key and advances the existing leaf
         // nibble iterator by the length of that prefix.
         let mut visited_nibble_iter = nibble_iter.visited_nibbles();
-        let existing_leaf_nibble_path = NibblePath::new(existing_leaf_node.key_hash().to_vec());
+        let existing_leaf_key_hash = existing_leaf_node.key_hash_with::<H>();
+        let existing_leaf_nibble_path = NibblePath::from_slice(existing_leaf_key_hash.as_ref());
         let mut existing_leaf_nibble_iter = existing_leaf_nibble_path.nibbles();
         skip_common_prefix(&mut visited_nibble_iter, &mut existing_leaf_nibble_iter);
 
@@ -486,15 +1972,16 @@ where
         // just need to update its value.
         if nibble_iter.is_finished() {
             assert!(existing_leaf_nibble_iter_below_internal.is_finished());
+            *old_leaf = Some(existing_leaf_node.clone());
             if blob.is_none() {
                 tree_cache.delete_node(&node_key, true);
                 let empty_node = Node::new_null();
-                return Ok((empty_node.merkle_hash(), empty_node));
+                return Ok((empty_node.merkle_hash_with::<H>(), empty_node));
             }
             let blob = blob.expect("blob must some at here");
             // The new leaf node will have the same nibble_path with a new version as node_key.
             // if the blob are same, return directly
-            if blob.merkle_hash() == existing_leaf_node.value_hash() {
+            if blob.merkle_hash_with::<H>() == existing_leaf_node.value_hash_with::<H>() {
                 return Ok((node_key, Node::Leaf(existing_leaf_node)));
             } else {
                 // Else create the new leaf node with the same address but new blob content.
@@ -525,19 +2012,25 @@ where
         let mut children = Children::new();
         children.insert(
             existing_leaf_index,
-            Child::new(existing_leaf_node.merkle_hash(), true /* is_leaf */),
+            Child::new(
+                existing_leaf_node.merkle_hash_with::<H>(),
+                true, /* is_leaf */
+            ),
         );
 
         let (_, new_leaf_node) = Self::create_leaf_node(key, blob, tree_cache)?;
         children.insert(
             new_leaf_index,
-            Child::new(new_leaf_node.merkle_hash(), true /* is_leaf */),
+            Child::new(
+                new_leaf_node.merkle_hash_with::<H>(),
+                true, /* is_leaf */
+            ),
         );
 
         let internal_node = InternalNode::new(children);
         let mut next_internal_node = internal_node.clone();
         let internal_node: Node<K, V> = internal_node.into();
-        tree_cache.put_node(internal_node.merkle_hash(), internal_node)?;
+        tree_cache.put_node(internal_node.merkle_hash_with::<H>(), internal_node)?;
 
         for _i in 0..num_common_nibbles_below_internal {
             let nibble = common_nibble_path
@@ -546,28 +2039,206 @@ where
             let mut children = Children::new();
             children.insert(
                 nibble,
-                Child::new(next_internal_node.merkle_hash(), false /* is_leaf */),
+                Child::new_internal(
+                    next_internal_node.merkle_hash_with::<H>(),
+                    next_internal_node.leaf_count(),
+                ),
             );
             let internal_node = InternalNode::new(children);
             next_internal_node = internal_node.clone();
             let internal_node: Node<K, V> = internal_node.into();
-            tree_cache.put_node(internal_node.merkle_hash(), internal_node)?;
+            tree_cache.put_node(internal_node.merkle_hash_with::<H>(), internal_node)?;
         }
 
         let next_internal_node: Node<K, V> = next_internal_node.into();
-        Ok((next_internal_node.merkle_hash(), next_internal_node))
+        Ok((
+            next_internal_node.merkle_hash_with::<H>(),
+            next_internal_node,
+        ))
+    }
+
+    /// Helper function for `put_batch`, applying the `updates` to the subtree of `node` with
+    /// `node_key` at `nibble_depth`. The updates are sorted by key hash and all under this subtree.
+    /// Returns the new root of the subtree as a child of its parent, or `None` if it is empty.
+    fn batch_update_at(
+        node_key: NodeKey,
+        node: Node<K, V>,
+        nibble_depth: usize,
+        updates: &mut [BatchUpdate<K, V>],
+        tree_cache: &mut TreeCache<R, K, V, H>,
+    ) -> Result<Option<Child>> {
+        ensure!(nibble_depth <= ROOT_NIBBLE_HEIGHT, SmtError::cyclic());
+        match node {
+            Node::Internal(internal_node) => {
+                ensure!(
+                    nibble_depth < ROOT_NIBBLE_HEIGHT,
+                    "Ran out of nibbles at internal node {:x}: the tree is deeper than a key hash.",
+                    node_key
+                );
+                let mut children: Children = internal_node.clone().into();
+                let mut changed = false;
+                let mut remaining_updates = updates;
+                while let Some((key_hash, _)) = remaining_updates.first() {
+                    let nibble = key_hash.nibble(nibble_depth);
+                    let split = remaining_updates
+                        .partition_point(|(key_hash, _)| key_hash.nibble(nibble_depth) <= nibble);
+                    let (child_updates, rest) = remaining_updates.split_at_mut(split);
+                    remaining_updates = rest;
+
+                    let nibble = Nibble::from(nibble);
+                    let old_child = internal_node.child(nibble);
+                    let new_child = match old_child {
+                        Some(child) => {
+                            let child_node = tree_cache.get_node(&child.hash)?;
+                            Self::batch_update_at(
+                                child.hash,
+                                child_node,
+                                nibble_depth + 1,
+                                child_updates,
+                                tree_cache,
+                            )?
+                        }
+                        None => {
+                            let leaves = Self::create_leaf_nodes(child_updates, tree_cache)?;
+                            Self::build_subtree(&leaves, nibble_depth + 1, tree_cache)?
+                        }
+                    };
+                    if old_child == new_child.as_ref() {
+                        continue;
+                    }
+                    changed = true;
+                    match new_child {
+                        Some(child) => children.insert(nibble, child),
+                        None => children.remove(&nibble),
+                    };
+                }
+
+                // Don't need to prune it if no change happens.
+                if !changed {
+                    return Ok(Some(Child::new_internal(
+                        node_key,
+                        internal_node.leaf_count(),
+                    )));
+                }
+                tree_cache.delete_node(&node_key, false /* is_leaf */);
+                match children.len() {
+                    0 => Ok(None),
+                    1 if children.values().all(|child| child.is_leaf) => {
+                        // The only leaf left takes the place of this internal node.
+                        Ok(children.into_values().next())
+                    }
+                    _ => {
+                        let internal_node = InternalNode::new(children);
+                        let leaf_count = internal_node.leaf_count();
+                        let internal_node: Node<K, V> = internal_node.into();
+                        let internal_node_key = internal_node.merkle_hash_with::<H>();
+                        tree_cache.put_node(internal_node_key, internal_node)?;
+                        Ok(Some(Child::new_internal(internal_node_key, leaf_count)))
+                    }
+                }
+            }
+            Node::Leaf(leaf_node) => {
+                let leaf_key_hash = leaf_node.key_hash_with::<H>();
+                let keep_existing_leaf =
+                    match updates.binary_search_by_key(&leaf_key_hash, |(key_hash, _)| *key_hash) {
+                        Err(_) => true,
+                        Ok(index) => match &updates[index].1 {
+                            // If the blob is the same, the leaf is kept as is.
+                            Some((_, blob)) => {
+                                let keep = blob.merkle_hash_with::<H>()
+                                    == leaf_node.value_hash_with::<H>();
+                                if keep {
+                                    updates[index].1 = None;
+                                }
+                                keep
+                            }
+                            None => false,
+                        },
+                    };
+                let mut leaves = Self::create_leaf_nodes(updates, tree_cache)?;
+                if keep_existing_leaf {
+                    if leaves.is_empty() {
+                        return Ok(Some(Child::new(node_key, true /* is_leaf */)));
+                    }
+                    let index = leaves.partition_point(|(key_hash, _)| *key_hash < leaf_key_hash);
+                    leaves.insert(index, (leaf_key_hash, node_key));
+                } else {
+                    tree_cache.delete_node(&node_key, true /* is_leaf */);
+                }
+                Self::build_subtree(&leaves, nibble_depth, tree_cache)
+            }
+            Node::Null => {
+                let leaves = Self::create_leaf_nodes(updates, tree_cache)?;
+                Self::build_subtree(&leaves, nibble_depth, tree_cache)
+            }
+        }
+    }
+
+    /// Helper function for `put_batch`, creating the leaf nodes of the `updates` which are not
+    /// deletes. Returns their key hashes and node keys, sorted by key hash.
+    fn create_leaf_nodes(
+        updates: &mut [BatchUpdate<K, V>],
+        tree_cache: &mut TreeCache<R, K, V, H>,
+    ) -> Result<Vec<(HashValue, NodeKey)>> {
+        updates
+            .iter_mut()
+            .filter_map(|(key_hash, blob)| blob.take().map(|(key, blob)| (*key_hash, key, blob)))
+            .map(|(key_hash, key, blob)| {
+                let (node_key, _) = Self::create_leaf_node(key, blob, tree_cache)?;
+                Ok((key_hash, node_key))
+            })
+            .collect()
+    }
+
+    /// Helper function for `put_batch`, building the subtree at `nibble_depth` which holds exactly
+    /// the `leaves`, sorted by key hash. The leaves are already in `tree_cache`, and the internal
+    /// nodes are created bottom-up. Returns the root of the subtree as a child of its parent, or
+    /// `None` if there is no leaf.
+    fn build_subtree(
+        leaves: &[(HashValue, NodeKey)],
+        nibble_depth: usize,
+        tree_cache: &mut TreeCache<R, K, V, H>,
+    ) -> Result<Option<Child>> {
+        match leaves {
+            [] => Ok(None),
+            [(_, node_key)] => Ok(Some(Child::new(*node_key, true /* is_leaf */))),
+            _ => {
+                ensure!(
+                    nibble_depth < ROOT_NIBBLE_HEIGHT,
+                    "Leaves with the same key hash can not be in the same subtree."
+                );
+                let mut children = Children::new();
+                let mut remaining_leaves = leaves;
+                while let Some((key_hash, _)) = remaining_leaves.first() {
+                    let nibble = key_hash.nibble(nibble_depth);
+                    let split = remaining_leaves
+                        .partition_point(|(key_hash, _)| key_hash.nibble(nibble_depth) <= nibble);
+                    let (child_leaves, rest) = remaining_leaves.split_at(split);
+                    remaining_leaves = rest;
+                    let child = Self::build_subtree(child_leaves, nibble_depth + 1, tree_cache)?
+                        .expect("A subtree with leaves is not empty.");
+                    children.insert(Nibble::from(nibble), child);
+                }
+                let internal_node = InternalNode::new(children);
+                let leaf_count = internal_node.leaf_count();
+                let internal_node: Node<K, V> = internal_node.into();
+                let internal_node_key = internal_node.merkle_hash_with::<H>();
+                tree_cache.put_node(internal_node_key, internal_node)?;
+                Ok(Some(Child::new_internal(internal_node_key, leaf_count)))
+            }
+        }
     }
 
     /// Helper function for creating leaf nodes. Returns the newly created leaf node.
     fn create_leaf_node(
         key: SMTObject<K>,
         blob: SMTObject<V>,
-        tree_cache: &mut TreeCache<R, K, V>,
+        tree_cache: &mut TreeCache<R, K, V, H>,
     ) -> Result<(NodeKey, Node<K, V>)> {
         // Get the underlying bytes of nibble_iter which must be a key, i.e., hashed account address
         // with `HashValue::LENGTH` bytes.
         let new_leaf_node = Node::new_leaf(key, blob);
-        let node_key = new_leaf_node.merkle_hash();
+        let node_key = new_leaf_node.merkle_hash_with::<H>();
         tree_cache.put_node(node_key, new_leaf_node.clone())?;
         Ok((node_key, new_leaf_node))
     }
@@ -577,71 +2248,222 @@ where
         &self,
         state_root_hash: HashValue,
         key: GK,
-    ) -> Result<(Option<SMTObject<V>>, SparseMerkleProof)> {
-        // Empty tree just returns proof with no sibling hash.
-        // let mut next_node_key = NodeKey::new_empty_path(version);
-        let mut next_node_key = state_root_hash;
-        let mut siblings = vec![];
-
+    ) -> Result<(Option<SMTObject<V>>, SparseMerkleProof<H>)> {
         // We use key's hash as nibble_path, not origin key bytes, make smt more distributed
-        let key = key.into();
-        let path_bytes = key.merkle_hash().to_vec();
-        let nibble_path = NibblePath::new(path_bytes);
-        let mut nibble_iter = nibble_path.nibbles();
+        let key_hash = key.into().merkle_hash_with::<H>();
+        let ((leaf_node, mut siblings), depth) =
+            descend_with_siblings::<K, V, R, H>(self.reader, state_root_hash, key_hash)?;
+        self.observe_descent(depth);
+        // The proof lists the siblings from the leaf up.
+        siblings.reverse();
+        match leaf_node {
+            Some(leaf_node) => {
+                let leaf_key_hash = leaf_node.key_hash_with::<H>();
+                let proof = SparseMerkleProof::new(
+                    Some((leaf_key_hash, leaf_node.value_hash_with::<H>())),
+                    siblings,
+                );
+                // The node was read into an owned value, so the blob is moved out of it rather
+                // than cloned.
+                let value = if leaf_key_hash == key_hash {
+                    Some(leaf_node.into_value()?)
+                } else {
+                    None
+                };
+                Ok((value, proof))
+            }
+            None => Ok((None, SparseMerkleProof::new(None, siblings))),
+        }
+    }
 
-        // We limit the number of loops here deliberately to avoid potential cyclic graph bugs
-        // in the tree structure.
-        for nibble_depth in 0..=ROOT_NIBBLE_HEIGHT {
-            let next_node = self.reader.get_node(&next_node_key)?;
-            match next_node {
-                Node::Internal(internal_node) => {
-                    let queried_child_index = nibble_iter
-                        .next()
-                        .ok_or_else(|| format_err!("ran out of nibbles"))?;
-                    let (child_node_key, mut siblings_in_internal) =
-                        internal_node.get_child_with_siblings(queried_child_index);
-                    siblings.append(&mut siblings_in_internal);
-                    if let Some(node_key) = child_node_key {
-                        next_node_key = node_key;
-                    } else {
-                        return Ok((
-                            None,
-                            SparseMerkleProof::new(None, {
-                                siblings.reverse();
-                                siblings
-                            }),
-                        ));
+    /// Same as `get_with_proof`, but tells whether the key is present, absent at an empty
+    /// subtree, or absent at the leaf of another key, see [`ProofOutcome`].
+    ///
+    /// [`ProofOutcome`]: crate::ProofOutcome
+    pub fn get_with_proof_outcome<GK: Into<SMTObject<K>>>(
+        &self,
+        state_root_hash: HashValue,
+        key: GK,
+    ) -> Result<ProofOutcome<V, H>> {
+        let (value, proof) = self.get_with_proof(state_root_hash, key)?;
+        Ok(match (value, proof.leaf()) {
+            (Some(value), _) => ProofOutcome::Present(value, proof),
+            (None, None) => ProofOutcome::AbsentEmpty(proof),
+            (None, Some(other_leaf)) => ProofOutcome::AbsentOtherLeaf(other_leaf, proof),
+        })
+    }
+
+    /// Returns the proof that shows whether each of `keys` exists in the tree or not. The tree is
+    /// traversed once for all the keys, so every node shared by their paths is only read once.
+    pub fn get_multiproof(
+        &self,
+        state_root_hash: HashValue,
+        keys: &[SMTObject<K>],
+    ) -> Result<SparseMerkleMultiProof<H>> {
+        let key_hashes = keys
+            .iter()
+            .map(|key| key.merkle_hash_with::<H>())
+            .collect::<Vec<_>>();
+        self.get_key_hash_multiproof(state_root_hash, &key_hashes)
+    }
+
+    /// Same as `get_multiproof`, but takes the key hashes of the keys.
+    fn get_key_hash_multiproof(
+        &self,
+        state_root_hash: HashValue,
+        key_hashes: &[HashValue],
+    ) -> Result<SparseMerkleMultiProof<H>> {
+        let mut sorted_keys = key_hashes
+            .iter()
+            .enumerate()
+            .map(|(index, key_hash)| (*key_hash, index))
+            .collect::<Vec<_>>();
+        sorted_keys.sort();
+
+        let mut proofs = vec![SparseMerkleProof::default(); key_hashes.len()];
+        if !sorted_keys.is_empty() {
+            let root = get_root_node::<_, _, _, H>(self.reader, &state_root_hash)?;
+            self.collect_proofs(state_root_hash, root, &sorted_keys, 0, vec![], &mut proofs)?;
+        }
+        SparseMerkleMultiProof::new(key_hashes, proofs)
+    }
+
+    /// Returns all the leaves whose keys are between `start` and `end`, both inclusive, in
+    /// ascending order of key hash, and the proof that shows no leaf in between is omitted.
+    #[allow(clippy::type_complexity)]
+    pub fn get_interval_proof(
+        &self,
+        state_root_hash: HashValue,
+        start: SMTObject<K>,
+        end: SMTObject<K>,
+    ) -> Result<(
+        Vec<(SMTObject<K>, SMTObject<V>)>,
+        SparseMerkleIntervalProof<H>,
+    )>
+    where
+        R: Versioned<K, V>,
+    {
+        self.get_key_hash_interval_proof(
+            state_root_hash,
+            start.merkle_hash_with::<H>(),
+            end.merkle_hash_with::<H>(),
+        )
+    }
+
+    /// Same as `get_interval_proof`, but the interval is given by the key hashes of its ends,
+    /// which need not be the key hash of any key. This is how a tree is split into chunks of
+    /// consecutive leaves, see [`SyncApplier`](crate::SyncApplier).
+    #[allow(clippy::type_complexity)]
+    pub fn get_key_hash_interval_proof(
+        &self,
+        state_root_hash: HashValue,
+        start_hash: HashValue,
+        end_hash: HashValue,
+    ) -> Result<(
+        Vec<(SMTObject<K>, SMTObject<V>)>,
+        SparseMerkleIntervalProof<H>,
+    )>
+    where
+        R: Versioned<K, V>,
+    {
+        let leaves = JellyfishMerkleIterator::<_, _, _, H>::new_key_hash_range(
+            self.reader,
+            state_root_hash,
+            Bound::Included(start_hash),
+            Bound::Included(end_hash),
+        )?
+        .collect::<Result<Vec<_>>>()?;
+
+        let mut key_hashes = Vec::with_capacity(leaves.len() + 2);
+        key_hashes.push(start_hash);
+        key_hashes.extend(leaves.iter().map(|(key, _)| key.merkle_hash_with::<H>()));
+        key_hashes.push(end_hash);
+        let proof = self.get_key_hash_multiproof(state_root_hash, &key_hashes)?;
+        Ok((leaves, SparseMerkleIntervalProof::new(proof)))
+    }
+
+    /// Fills in `proofs` the proof of each key in `sorted_keys`, a list of key hashes sorted in
+    /// ascending order along with their indices in `proofs`. All the keys are under `node` with
+    /// `node_key` at `nibble_depth`, and `siblings` are the siblings from the root to that node.
+    /// The children of an internal node on the paths of the keys are read in a single batch.
+    fn collect_proofs(
+        &self,
+        node_key: NodeKey,
+        node: Node<K, V>,
+        sorted_keys: &[(HashValue, usize)],
+        nibble_depth: usize,
+        siblings: Vec<HashValue>,
+        proofs: &mut [SparseMerkleProof<H>],
+    ) -> Result<()> {
+        // We limit the depth here deliberately to avoid potential cyclic graph bugs in the tree
+        // structure.
+        ensure!(nibble_depth <= ROOT_NIBBLE_HEIGHT, SmtError::cyclic());
+        let leaf = match node {
+            Node::Internal(internal_node) => {
+                let mut children = vec![];
+                let mut remaining_keys = sorted_keys;
+                while let Some((key_hash, _)) = remaining_keys.first() {
+                    let nibble = key_hash.nibble(nibble_depth);
+                    let split = remaining_keys
+                        .iter()
+                        .position(|(key_hash, _)| key_hash.nibble(nibble_depth) != nibble)
+                        .unwrap_or(remaining_keys.len());
+                    let (child_keys, rest) = remaining_keys.split_at(split);
+                    remaining_keys = rest;
+
+                    let (child_node_key, siblings_in_internal) =
+                        internal_node.get_child_with_siblings::<H>(Nibble::from(nibble));
+                    let mut child_siblings = siblings.clone();
+                    child_siblings.extend(siblings_in_internal);
+                    match child_node_key {
+                        Some(child_node_key) => {
+                            children.push((child_node_key, child_keys, child_siblings))
+                        }
+                        None => {
+                            child_siblings.reverse();
+                            for (_, index) in child_keys {
+                                proofs[*index] =
+                                    SparseMerkleProof::new(None, child_siblings.clone());
+                            }
+                        }
                     }
                 }
-                Node::Leaf(leaf_node) => {
-                    return Ok((
-                        if leaf_node.key_hash() == key.merkle_hash() {
-                            Some(leaf_node.value().clone())
-                        } else {
-                            None
-                        },
-                        SparseMerkleProof::new(
-                            Some((leaf_node.key_hash(), leaf_node.value_hash())),
-                            {
-                                siblings.reverse();
-                                siblings
-                            },
-                        ),
-                    ));
-                }
-                Node::Null => {
-                    if nibble_depth == 0 {
-                        return Ok((None, SparseMerkleProof::new(None, vec![])));
-                    } else {
-                        bail!(
-                            "Non-root null node exists with node key {:?}",
-                            next_node_key
-                        );
-                    }
+
+                let child_node_keys = children
+                    .iter()
+                    .map(|(child_node_key, _, _)| *child_node_key)
+                    .collect::<Vec<_>>();
+                let child_nodes = self.reader.get_nodes(&child_node_keys)?;
+                for ((child_node_key, child_keys, child_siblings), child_node) in
+                    children.into_iter().zip(child_nodes)
+                {
+                    let child_node = child_node.ok_or(SmtError::NodeNotFound(child_node_key))?;
+                    self.collect_proofs(
+                        child_node_key,
+                        child_node,
+                        child_keys,
+                        nibble_depth + 1,
+                        child_siblings,
+                        proofs,
+                    )?;
                 }
+                return Ok(());
             }
+            Node::Leaf(leaf_node) => Some((
+                leaf_node.key_hash_with::<H>(),
+                leaf_node.value_hash_with::<H>(),
+            )),
+            Node::Null => {
+                ensure!(nibble_depth == 0, SmtError::UnexpectedNull(node_key));
+                None
+            }
+        };
+        let mut siblings = siblings;
+        siblings.reverse();
+        for (_, index) in sorted_keys {
+            proofs[*index] = SparseMerkleProof::new(leaf, siblings.clone());
         }
-        bail!("Jellyfish Merkle tree has cyclic graph inside.");
+        Ok(())
     }
 
     /// Gets the proof that shows a list of keys up to `rightmost_key_to_prove` exist at `version`.
@@ -650,7 +2472,7 @@ where
         state_root_hash: HashValue,
         rightmost_key_to_prove: SMTObject<K>,
     ) -> Result<SparseMerkleRangeProof> {
-        let key_hash = rightmost_key_to_prove.merkle_hash();
+        let key_hash = rightmost_key_to_prove.merkle_hash_with::<H>();
         let (account, proof) = self.get_with_proof(state_root_hash, rightmost_key_to_prove)?;
         ensure!(account.is_some(), "rightmost_key_to_prove must exist.");
 
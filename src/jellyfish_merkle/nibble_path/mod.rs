@@ -12,6 +12,7 @@ mod nibble_path_test;
 
 use super::nibble::Nibble;
 use super::ROOT_NIBBLE_HEIGHT;
+use anyhow::{ensure, format_err, Result};
 #[cfg(any(test, feature = "fuzzing"))]
 use proptest::{collection::vec, prelude::*};
 use serde::{Deserialize, Serialize};
@@ -95,6 +96,24 @@ impl NibblePath {
         NibblePath { bytes, num_nibbles }
     }
 
+    /// Same as `new`, but borrows `bytes` instead of owning them, e.g. to iterate over the nibbles
+    /// of a key hash without copying it.
+    pub fn from_slice(bytes: &[u8]) -> NibbleSlice<'_> {
+        assert!(bytes.len() <= ROOT_NIBBLE_HEIGHT / 2);
+        NibbleSlice {
+            num_nibbles: bytes.len() * 2,
+            bytes,
+        }
+    }
+
+    /// Returns the nibble path borrowing the bytes of this one.
+    pub fn as_nibble_slice(&self) -> NibbleSlice<'_> {
+        NibbleSlice {
+            num_nibbles: self.num_nibbles,
+            bytes: &self.bytes,
+        }
+    }
+
     /// Similar to `new()` but assumes that the bytes have one less nibble.
     pub fn new_odd(bytes: Vec<u8>) -> Self {
         assert!(bytes.len() <= ROOT_NIBBLE_HEIGHT / 2);
@@ -146,9 +165,82 @@ impl NibblePath {
         }
     }
 
-    /// Get the i-th bit.
-    fn get_bit(&self, i: usize) -> bool {
-        assert!(i / 4 < self.num_nibbles);
+    /// Get the i-th bit. The bits are in the order of the nibbles, from the most significant bit
+    /// of each nibble: bit 0 is the most significant bit of nibble 0.
+    pub fn bit(&self, i: usize) -> bool {
+        self.as_nibble_slice().bit(i)
+    }
+
+    /// Get the i-th nibble.
+    fn get_nibble(&self, i: usize) -> Nibble {
+        self.as_nibble_slice().get_nibble(i)
+    }
+
+    /// Get a bit iterator iterates over the whole nibble path.
+    pub fn bits(&self) -> BitIterator {
+        self.as_nibble_slice().bits()
+    }
+
+    /// Get a nibble iterator iterates over the whole nibble path.
+    pub fn nibbles(&self) -> NibbleIterator {
+        self.as_nibble_slice().nibbles()
+    }
+
+    /// Get the total number of nibbles stored.
+    pub fn num_nibbles(&self) -> usize {
+        self.num_nibbles
+    }
+
+    /// Get the total number of bits stored, 4 per nibble.
+    pub fn num_bits(&self) -> usize {
+        self.num_nibbles * 4
+    }
+
+    /// Get the underlying bytes storing nibbles.
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Returns the nibbles as a hex string, one digit per nibble, so an odd path has an odd
+    /// number of digits. For example, [0x12, 0xa0] with 3 nibbles is "12a".
+    pub fn to_hex(&self) -> String {
+        format!("{:?}", self)
+    }
+
+    /// Parses a nibble path written as by [`to_hex`](NibblePath::to_hex), one hex digit per
+    /// nibble. Fails on a non-hex character or on more nibbles than a key hash has.
+    pub fn from_hex(s: &str) -> Result<NibblePath> {
+        ensure!(
+            s.len() <= ROOT_NIBBLE_HEIGHT,
+            "Nibble path {:?} is longer than {} nibbles.",
+            s,
+            ROOT_NIBBLE_HEIGHT
+        );
+        s.chars()
+            .map(|c| {
+                c.to_digit(16)
+                    .map(|digit| Nibble::from(digit as u8))
+                    .ok_or_else(|| format_err!("Invalid hex character {:?} in nibble path.", c))
+            })
+            .collect()
+    }
+}
+
+/// A nibble path borrowing the bytes storing its nibbles, see
+/// [`NibblePath::from_slice`](NibblePath::from_slice). The iterators over the nibbles and bits of
+/// a `NibblePath` iterate over its `NibbleSlice`.
+#[derive(Clone, Copy, Debug)]
+pub struct NibbleSlice<'a> {
+    /// The total number of nibbles in bytes, as in `NibblePath`.
+    num_nibbles: usize,
+    /// The bytes storing the nibbles, 2 nibbles per byte.
+    bytes: &'a [u8],
+}
+
+impl<'a> NibbleSlice<'a> {
+    /// Get the i-th bit, see [`NibblePath::bit`](NibblePath::bit).
+    pub fn bit(&self, i: usize) -> bool {
+        assert!(i < self.num_bits());
         let pos = i / 8;
         let bit = 7 - i % 8;
         ((self.bytes[pos] >> bit) & 1) != 0
@@ -161,18 +253,18 @@ impl NibblePath {
     }
 
     /// Get a bit iterator iterates over the whole nibble path.
-    pub fn bits(&self) -> BitIterator {
+    pub fn bits(&self) -> BitIterator<'a> {
         debug_assert!(self.num_nibbles <= ROOT_NIBBLE_HEIGHT); // invariant
         BitIterator {
-            nibble_path: self,
-            pos: (0..self.num_nibbles * 4),
+            nibble_path: *self,
+            pos: (0..self.num_bits()),
         }
     }
 
     /// Get a nibble iterator iterates over the whole nibble path.
-    pub fn nibbles(&self) -> NibbleIterator {
+    pub fn nibbles(&self) -> NibbleIterator<'a> {
         debug_assert!(self.num_nibbles <= ROOT_NIBBLE_HEIGHT); // invariant
-        NibbleIterator::new(self, 0, self.num_nibbles)
+        NibbleIterator::new(*self, 0, self.num_nibbles)
     }
 
     /// Get the total number of nibbles stored.
@@ -180,9 +272,9 @@ impl NibblePath {
         self.num_nibbles
     }
 
-    /// Get the underlying bytes storing nibbles.
-    pub fn bytes(&self) -> &[u8] {
-        &self.bytes
+    /// Get the total number of bits stored, 4 per nibble.
+    pub fn num_bits(&self) -> usize {
+        self.num_nibbles * 4
     }
 }
 
@@ -193,7 +285,7 @@ pub trait Peekable: Iterator {
 
 /// BitIterator iterates a nibble path by bit.
 pub struct BitIterator<'a> {
-    nibble_path: &'a NibblePath,
+    nibble_path: NibbleSlice<'a>,
     pos: std::ops::Range<usize>,
 }
 
@@ -201,7 +293,7 @@ impl<'a> Peekable for BitIterator<'a> {
     /// Returns the `next()` value without advancing the iterator.
     fn peek(&self) -> Option<Self::Item> {
         if self.pos.start < self.pos.end {
-            Some(self.nibble_path.get_bit(self.pos.start))
+            Some(self.nibble_path.bit(self.pos.start))
         } else {
             None
         }
@@ -213,14 +305,14 @@ impl<'a> Iterator for BitIterator<'a> {
     type Item = bool;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.pos.next().map(|i| self.nibble_path.get_bit(i))
+        self.pos.next().map(|i| self.nibble_path.bit(i))
     }
 }
 
 /// Support iterating bits in reversed order.
 impl<'a> DoubleEndedIterator for BitIterator<'a> {
     fn next_back(&mut self) -> Option<Self::Item> {
-        self.pos.next_back().map(|i| self.nibble_path.get_bit(i))
+        self.pos.next_back().map(|i| self.nibble_path.bit(i))
     }
 }
 
@@ -228,7 +320,7 @@ impl<'a> DoubleEndedIterator for BitIterator<'a> {
 #[derive(Debug)]
 pub struct NibbleIterator<'a> {
     /// The underlying nibble path that stores the nibbles
-    nibble_path: &'a NibblePath,
+    nibble_path: NibbleSlice<'a>,
 
     /// The current index, `pos.start`, will bump by 1 after calling `next()` until `pos.start ==
     /// pos.end`.
@@ -264,7 +356,7 @@ impl<'a> Peekable for NibbleIterator<'a> {
 }
 
 impl<'a> NibbleIterator<'a> {
-    fn new(nibble_path: &'a NibblePath, start: usize, end: usize) -> Self {
+    fn new(nibble_path: NibbleSlice<'a>, start: usize, end: usize) -> Self {
         assert!(start <= end);
         assert!(start <= ROOT_NIBBLE_HEIGHT);
         assert!(end <= ROOT_NIBBLE_HEIGHT);
@@ -343,3 +435,15 @@ where
     }
     count
 }
+
+/// Returns the number of nibbles `a` and `b` have in common from the start, at most the number of
+/// nibbles of the shorter one.
+pub fn common_prefix_nibble_len(a: &NibblePath, b: &NibblePath) -> usize {
+    skip_common_prefix(&mut a.nibbles(), &mut b.nibbles())
+}
+
+/// Returns the number of bits `a` and `b` have in common from the start, at most the number of
+/// bits of the shorter one.
+pub fn common_prefix_bits_len(a: &NibblePath, b: &NibblePath) -> usize {
+    skip_common_prefix(&mut a.bits(), &mut b.bits())
+}
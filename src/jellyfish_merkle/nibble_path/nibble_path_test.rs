@@ -5,7 +5,10 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use super::super::nibble::Nibble;
-use super::{arb_internal_nibble_path, skip_common_prefix, NibblePath};
+use super::{
+    arb_internal_nibble_path, common_prefix_bits_len, common_prefix_nibble_len, skip_common_prefix,
+    NibblePath,
+};
 use proptest::prelude::*;
 
 #[test]
@@ -17,6 +20,27 @@ fn test_nibble_path_fmt() {
     assert_eq!(format!("{:?}", nibble_path), "12345");
 }
 
+#[test]
+fn test_nibble_path_hex() {
+    let nibble_path = NibblePath::new(vec![0x12, 0x34, 0xab]);
+    assert_eq!(nibble_path.to_hex(), "1234ab");
+    assert_eq!(NibblePath::from_hex("1234ab").unwrap(), nibble_path);
+    assert_eq!(NibblePath::from_hex("1234AB").unwrap(), nibble_path);
+
+    let nibble_path = NibblePath::new_odd(vec![0x12, 0x34, 0x50]);
+    assert_eq!(nibble_path.to_hex(), "12345");
+    assert_eq!(NibblePath::from_hex("12345").unwrap(), nibble_path);
+
+    assert_eq!(NibblePath::from_hex("").unwrap(), NibblePath::new(vec![]));
+    assert!(NibblePath::from_hex("12g4").is_err());
+    assert!(NibblePath::from_hex("0x12").is_err());
+    assert!(NibblePath::from_hex(&"0".repeat(65)).is_err());
+    assert_eq!(
+        NibblePath::from_hex(&"f".repeat(64)).unwrap().num_nibbles(),
+        64
+    );
+}
+
 #[test]
 fn test_create_nibble_path_success() {
     let nibble_path = NibblePath::new(vec![0x12, 0x34, 0x56]);
@@ -52,6 +76,24 @@ fn test_get_nibble() {
     assert_eq!(nibble_path.get_nibble(3), Nibble::from(0x04));
 }
 
+#[test]
+fn test_nibble_path_from_slice() {
+    let bytes = [0x12, 0x34, 0xab];
+    let nibble_slice = NibblePath::from_slice(&bytes);
+    let nibble_path = NibblePath::new(bytes.to_vec());
+    assert_eq!(nibble_slice.num_nibbles(), 6);
+    assert!(nibble_slice.nibbles().eq(nibble_path.nibbles()));
+    assert!(nibble_slice.bits().eq(nibble_path.bits()));
+    assert_eq!(nibble_slice.nibbles().get_nibble_path(), nibble_path);
+
+    let mut nibble_iter = nibble_slice.nibbles();
+    nibble_iter.next();
+    nibble_iter.next();
+    assert_eq!(
+        nibble_iter.remaining_nibbles().get_nibble_path(),
+        NibblePath::new(vec![0x34, 0xab])
+    );
+}
 #[test]
 fn test_nibble_iterator() {
     let bytes = vec![0x12, 0x30];
@@ -68,12 +110,25 @@ fn test_nibble_iterator() {
 fn test_get_bit() {
     let bytes = vec![0x01, 0x02];
     let nibble_path = NibblePath::new(bytes);
-    assert_eq!(nibble_path.get_bit(0), false);
-    assert_eq!(nibble_path.get_bit(1), false);
-    assert_eq!(nibble_path.get_bit(2), false);
-    assert_eq!(nibble_path.get_bit(7), true);
-    assert_eq!(nibble_path.get_bit(8), false);
-    assert_eq!(nibble_path.get_bit(14), true);
+    assert_eq!(nibble_path.bit(0), false);
+    assert_eq!(nibble_path.bit(1), false);
+    assert_eq!(nibble_path.bit(2), false);
+    assert_eq!(nibble_path.bit(7), true);
+    assert_eq!(nibble_path.bit(8), false);
+    assert_eq!(nibble_path.bit(14), true);
+    assert_eq!(nibble_path.num_bits(), 16);
+}
+
+#[test]
+fn test_bits_match_nibbles() {
+    let nibble_path = NibblePath::new_odd(vec![0x5a, 0x3c, 0xf0]);
+    assert_eq!(nibble_path.num_bits(), nibble_path.num_nibbles() * 4);
+    for (i, nibble) in nibble_path.nibbles().enumerate() {
+        let nibble_from_bits = (0..4).fold(0u8, |acc, j| {
+            acc << 1 | u8::from(nibble_path.bit(i * 4 + j))
+        });
+        assert_eq!(Nibble::from(nibble_from_bits), nibble);
+    }
 }
 
 #[test]
@@ -170,6 +225,30 @@ prop_compose! {
     }
 }
 
+#[test]
+fn test_common_prefix_len() {
+    let nibble_path = NibblePath::new(vec![0x12, 0x34, 0x56]);
+    // No nibble in common, with 0x1 = 0b0001 and 0x9 = 0b1001.
+    let other = NibblePath::new(vec![0x92, 0x34, 0x56]);
+    assert_eq!(common_prefix_nibble_len(&nibble_path, &other), 0);
+    assert_eq!(common_prefix_bits_len(&nibble_path, &other), 0);
+    // Some nibbles in common, with 0x4 = 0b0100 and 0x5 = 0b0101.
+    let other = NibblePath::new(vec![0x12, 0x35]);
+    assert_eq!(common_prefix_nibble_len(&nibble_path, &other), 3);
+    assert_eq!(common_prefix_bits_len(&nibble_path, &other), 15);
+    // All nibbles in common.
+    assert_eq!(common_prefix_nibble_len(&nibble_path, &nibble_path), 6);
+    assert_eq!(common_prefix_bits_len(&nibble_path, &nibble_path), 24);
+    // All the nibbles of the shorter one.
+    let other = NibblePath::new_odd(vec![0x12, 0x30]);
+    assert_eq!(common_prefix_nibble_len(&nibble_path, &other), 3);
+    assert_eq!(common_prefix_nibble_len(&other, &nibble_path), 3);
+    assert_eq!(common_prefix_bits_len(&other, &nibble_path), 12);
+    let empty = NibblePath::new(vec![]);
+    assert_eq!(common_prefix_nibble_len(&nibble_path, &empty), 0);
+    assert_eq!(common_prefix_bits_len(&empty, &nibble_path), 0);
+}
+
 proptest! {
     #[test]
     fn test_push(
@@ -201,6 +280,11 @@ proptest! {
         prop_assert_eq!(nibble1, nibble2);
     }
 
+    #[test]
+    fn test_nibble_path_hex_roundtrip(nibble_path in any::<NibblePath>()) {
+        prop_assert_eq!(NibblePath::from_hex(&nibble_path.to_hex()).unwrap(), nibble_path);
+    }
+
     #[test]
     fn test_nibble_iter_roundtrip(nibble_path in any::<NibblePath>()) {
         let nibbles = nibble_path.nibbles();
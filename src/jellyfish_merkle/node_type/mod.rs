@@ -28,7 +28,6 @@ use proptest::{collection::hash_map, prelude::*};
 #[cfg(any(test, feature = "fuzzing"))]
 use proptest_derive::Arbitrary;
 use serde::{Deserialize, Serialize};
-use std::cell::Cell;
 use std::{
     collections::hash_map::HashMap,
     io::{prelude::*, Cursor, Read, SeekFrom},
@@ -36,21 +35,73 @@ use std::{
 };
 use thiserror::Error;
 
+/// The key a node is stored under, which is the hash of its content, see [`Node::hash`]. Unlike
+/// the versioned node keys of the original Jellyfish Merkle Tree, made of a version and a nibble
+/// path, it does not depend on where or when the node was written: the same node has the same key
+/// in every tree, and a key identifies a node on its own, e.g. to check a node read from an
+/// untrusted store. It is a plain [`HashValue`], with its byte accessors:
+/// [`HashValue::to_vec`], [`HashValue::to_hex`], [`HashValue::from_slice`] and
+/// [`HashValue::from_hex`].
 pub type NodeKey = HashValue;
 
 /// Each child of [`InternalNode`] encapsulates a nibble forking at this node.
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 #[cfg_attr(any(test, feature = "fuzzing"), derive(Arbitrary))]
 pub struct Child {
     // The hash value of this child node.
     pub hash: HashValue,
     // Whether the child is a leaf node.
     pub is_leaf: bool,
+    // The number of leaves in the subtree of an internal child, if known.
+    #[serde(default)]
+    #[cfg_attr(any(test, feature = "fuzzing"), proptest(value = "None"))]
+    leaf_count: Option<u64>,
 }
 
+/// The leaf count of a child is determined by its hash, so it does not take part in equality.
+impl PartialEq for Child {
+    fn eq(&self, other: &Self) -> bool {
+        self.hash == other.hash && self.is_leaf == other.is_leaf
+    }
+}
+
+impl Eq for Child {}
+
 impl Child {
     pub fn new(hash: HashValue, is_leaf: bool) -> Self {
-        Self { hash, is_leaf }
+        Self {
+            hash,
+            is_leaf,
+            leaf_count: None,
+        }
+    }
+
+    /// Creates the child for `node` with key `hash`, keeping the leaf count of an internal node.
+    pub fn for_node<K, V>(hash: HashValue, node: &Node<K, V>) -> Self {
+        match node {
+            Node::Internal(internal_node) => Self::new_internal(hash, internal_node.leaf_count()),
+            Node::Leaf(_) => Self::new(hash, true),
+            Node::Null => Self::new(hash, false),
+        }
+    }
+
+    /// Creates an internal child with `leaf_count` leaves in its subtree, if known.
+    pub fn new_internal(hash: HashValue, leaf_count: Option<u64>) -> Self {
+        Self {
+            hash,
+            is_leaf: false,
+            leaf_count,
+        }
+    }
+
+    /// Returns the number of leaves in the subtree of this child, if known. A leaf child is
+    /// always known to be a single leaf.
+    pub fn leaf_count(&self) -> Option<u64> {
+        if self.is_leaf {
+            Some(1)
+        } else {
+            self.leaf_count
+        }
     }
 }
 
@@ -68,7 +119,7 @@ pub struct InternalNode {
     // Up to 16 children.
     children: Children,
     //Node's hash cache
-    cached_hash: Cell<Option<HashValue>>,
+    cached_hash: HashCache,
 }
 
 /// Computes the hash of internal node according to [`JellyfishTree`](super::JellyfishTree)
@@ -118,19 +169,14 @@ pub struct InternalNode {
 /// Note: @ denotes placeholder hash.
 /// ```
 impl SMTHash for InternalNode {
-    fn merkle_hash(&self) -> HashValue {
-        match self.cached_hash.get() {
-            Some(hash) => hash,
-            None => {
-                let hash = self.make_hash(
-                    0,  // start index
-                    16, // the number of leaves in the subtree of which we want the hash of root
-                    self.generate_bitmaps(),
-                );
-                self.cached_hash.set(Some(hash));
-                hash
-            }
-        }
+    fn merkle_hash_with<H: TreeHasher>(&self) -> HashValue {
+        self.cached_hash.get_or_compute::<H>(|| {
+            self.make_hash::<H>(
+                0,  // start index
+                16, // the number of leaves in the subtree of which we want the hash of root
+                self.generate_bitmaps(),
+            )
+        })
     }
 }
 
@@ -169,7 +215,7 @@ impl InternalNode {
         }
         Self {
             children,
-            cached_hash: Cell::new(None),
+            cached_hash: HashCache::default(),
         }
     }
 
@@ -189,7 +235,57 @@ impl InternalNode {
 
     pub fn deserialize(data: &[u8]) -> Result<Self> {
         let mut reader = Cursor::new(data);
-        let len = data.len();
+        let internal_node = Self::deserialize_children(&mut reader)?;
+        Self::check_trailing_bytes(&reader)?;
+        Ok(internal_node)
+    }
+
+    /// Serializes the node followed by the leaf counts of its internal children in nibble order,
+    /// as varints. Returns `false` and writes nothing if the node has no internal child, or if the
+    /// leaf count of one of them is unknown.
+    pub(crate) fn serialize_with_leaf_counts(&self, binary: &mut Vec<u8>) -> Result<bool> {
+        let leaf_counts = (0..16u8)
+            .filter_map(|index| self.children.get(&Nibble::from(index)))
+            .filter(|child| !child.is_leaf)
+            .map(|child| child.leaf_count)
+            .collect::<Option<Vec<_>>>();
+        match leaf_counts {
+            Some(leaf_counts) if !leaf_counts.is_empty() => {
+                self.serialize(binary)?;
+                for leaf_count in leaf_counts {
+                    serialize_u64_varint(leaf_count, binary);
+                }
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Recovers a node serialized by `serialize_with_leaf_counts`.
+    pub(crate) fn deserialize_with_leaf_counts(data: &[u8]) -> Result<Self> {
+        let mut reader = Cursor::new(data);
+        let mut internal_node = Self::deserialize_children(&mut reader)?;
+        for index in 0..16u8 {
+            if let Some(child) = internal_node.children.get_mut(&Nibble::from(index)) {
+                if !child.is_leaf {
+                    let leaf_count = deserialize_u64_varint(&mut reader)?;
+                    // The subtree of an internal child has at least two leaves.
+                    ensure!(leaf_count >= 2, "Invalid leaf count: {}", leaf_count);
+                    child.leaf_count = Some(leaf_count);
+                }
+            }
+        }
+        ensure!(
+            internal_node.leaf_count().is_some(),
+            "Internal node without internal child encoded with leaf counts."
+        );
+        Self::check_trailing_bytes(&reader)?;
+        Ok(internal_node)
+    }
+
+    /// Reads the bitmaps and the children of a node from `reader`.
+    fn deserialize_children(reader: &mut Cursor<&[u8]>) -> Result<Self> {
+        let len = reader.get_ref().len();
 
         // Read and validate existence and leaf bitmaps
         let mut existence_bitmap = reader.read_u16::<LittleEndian>()?;
@@ -232,22 +328,62 @@ impl InternalNode {
             existence_bitmap &= !child_bit;
         }
         assert_eq!(existence_bitmap, 0);
+        // Checked here rather than left to the assertion of `new`, as the input may be untrusted.
+        if children.len() == 1 && leaf_bitmap != 0 {
+            return Err(NodeDecodeError::SingleLeafChild.into());
+        }
         Ok(Self::new(children))
     }
 
+    fn check_trailing_bytes(reader: &Cursor<&[u8]>) -> Result<()> {
+        let remaining = reader.get_ref().len() - reader.position() as usize;
+        if remaining != 0 {
+            return Err(NodeDecodeError::TrailingBytes { remaining }.into());
+        }
+        Ok(())
+    }
+
+    /// Returns the number of leaves in the subtree of this node, if the leaf counts of all its
+    /// internal children are known.
+    pub fn leaf_count(&self) -> Option<u64> {
+        self.children.values().map(Child::leaf_count).sum()
+    }
+
     /// Gets the `n`-th child.
     pub fn child(&self, n: Nibble) -> Option<&Child> {
         self.children.get(&n)
     }
 
+    /// Returns the existing children with their nibble, in nibble order.
+    pub fn children(&self) -> impl Iterator<Item = (Nibble, &Child)> + '_ {
+        (0..16u8).filter_map(move |index| {
+            let nibble = Nibble::from(index);
+            self.child(nibble).map(|child| (nibble, child))
+        })
+    }
+
     /// Return the total number of existing children.
     pub fn num_children(&self) -> usize {
         self.children.len()
     }
 
+    /// Returns the bitmap of the existing children: the child at nibble `n` exists if bit `n`,
+    /// counted from the least significant bit, is set. The children are thus in nibble order from
+    /// the lowest bit up, e.g. `0b101` means the children at nibbles 0 and 2.
+    pub fn children_bitmap(&self) -> u16 {
+        self.generate_bitmaps().0
+    }
+
+    /// Returns the bitmap of the leaf children, with the same bit ordering as `children_bitmap`,
+    /// of which it is a subset. A set bit means the hash of the child is a leaf hash, a cleared
+    /// bit of an existing child that it is the hash of an internal node.
+    pub fn leaf_bitmap(&self) -> u16 {
+        self.generate_bitmaps().1
+    }
+
     /// Generates `existence_bitmap` and `leaf_bitmap` as a pair of `u16`s: child at index `i`
     /// exists if `existence_bitmap[i]` is set; child at index `i` is leaf node if
-    /// `leaf_bitmap[i]` is set.
+    /// `leaf_bitmap[i]` is set. See `children_bitmap` for the bit ordering.
     pub fn generate_bitmaps(&self) -> (u16, u16) {
         let mut existence_bitmap = 0;
         let mut leaf_bitmap = 0;
@@ -274,7 +410,7 @@ impl InternalNode {
         (bitmaps.0 & mask, bitmaps.1 & mask)
     }
 
-    fn make_hash(
+    fn make_hash<H: TreeHasher>(
         &self,
         start: u8,
         width: u8,
@@ -285,7 +421,7 @@ impl InternalNode {
             Self::range_bitmaps(start, width, (existence_bitmap, leaf_bitmap));
         if range_existence_bitmap == 0 {
             // No child under this subtree
-            *SPARSE_MERKLE_PLACEHOLDER_HASH
+            H::SPARSE_MERKLE_PLACEHOLDER
         } else if range_existence_bitmap.count_ones() == 1 && (range_leaf_bitmap != 0 || width == 1)
         {
             // Only 1 leaf child under this subtree or reach the lowest level
@@ -301,13 +437,13 @@ impl InternalNode {
                 .unwrap()
                 .hash
         } else {
-            let left_child = self.make_hash(start, width / 2, (existence_bitmap, leaf_bitmap));
-            let right_child = self.make_hash(
+            let left_child = self.make_hash::<H>(start, width / 2, (existence_bitmap, leaf_bitmap));
+            let right_child = self.make_hash::<H>(
                 start + width / 2,
                 width / 2,
                 (existence_bitmap, leaf_bitmap),
             );
-            SparseMerkleInternalNode::new(left_child, right_child).merkle_hash()
+            SparseMerkleInternalNode::new(left_child, right_child).merkle_hash_with::<H>()
         }
     }
 
@@ -331,7 +467,11 @@ impl InternalNode {
     ///     |   MSB|<---------------------- uint 16 ---------------------------->|LSB
     ///  height    chs: `child_half_start`         shs: `sibling_half_start`
     /// ```
-    pub fn get_child_with_siblings(&self, n: Nibble) -> (Option<NodeKey>, Vec<HashValue>) {
+    /// The siblings are hashed with `H`, the hasher of the tree.
+    pub fn get_child_with_siblings<H: TreeHasher>(
+        &self,
+        n: Nibble,
+    ) -> (Option<NodeKey>, Vec<HashValue>) {
         let mut siblings = vec![];
         let (existence_bitmap, leaf_bitmap) = self.generate_bitmaps();
 
@@ -342,7 +482,7 @@ impl InternalNode {
             let width = 1 << h;
             let (child_half_start, sibling_half_start) = get_child_and_sibling_half_start(n, h);
             // Compute the root hash of the subtree rooted at the sibling of `r`.
-            siblings.push(self.make_hash(
+            siblings.push(self.make_hash::<H>(
                 sibling_half_start,
                 width,
                 (existence_bitmap, leaf_bitmap),
@@ -410,9 +550,17 @@ pub(crate) fn get_child_and_sibling_half_start(n: Nibble, height: u8) -> (u8, u8
 pub struct LeafNode<K, V> {
     /// The origin key associated with this leaf node's Value.
     key: SMTObject<K>,
-    /// The blob value associated with `key`.
-    value: SMTObject<V>,
-    cached_hash: Cell<Option<HashValue>>,
+    /// The blob value associated with `key`, or only its hash if the value is detached.
+    value: LeafValue<V>,
+    cached_hash: HashCache,
+}
+
+/// The value of a [`LeafNode`]. A detached leaf only holds the hash of its value, the value itself
+/// is stored apart from the tree and read with a [`ValueReader`](super::ValueReader).
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum LeafValue<V> {
+    Inline(SMTObject<V>),
+    Detached(HashValue),
 }
 
 impl<K, V> LeafNode<K, V>
@@ -424,22 +572,26 @@ where
     pub fn new<NK: Into<SMTObject<K>>, NV: Into<SMTObject<V>>>(key: NK, value: NV) -> Self {
         Self {
             key: key.into(),
-            value: value.into(),
-            cached_hash: Cell::new(None),
+            value: LeafValue::Inline(value.into()),
+            cached_hash: HashCache::default(),
         }
     }
 
-    pub fn cached_hash(&self) -> HashValue {
-        match self.cached_hash.get() {
-            Some(hash) => hash,
-            None => {
-                let hash = self.merkle_hash();
-                self.cached_hash.set(Some(hash));
-                hash
-            }
+    /// Creates a new leaf node holding only the hash of its value, computed with the hasher of
+    /// the tree. The leaf has the same hash as the leaf holding the value, so a tree of detached
+    /// leaves has the same root hash and proofs.
+    pub fn new_detached<NK: Into<SMTObject<K>>>(key: NK, value_hash: HashValue) -> Self {
+        Self {
+            key: key.into(),
+            value: LeafValue::Detached(value_hash),
+            cached_hash: HashCache::default(),
         }
     }
 
+    pub fn cached_hash(&self) -> HashValue {
+        self.merkle_hash()
+    }
+
     /// Gets the key
     pub fn key(&self) -> &SMTObject<K> {
         &self.key
@@ -455,14 +607,110 @@ where
         self.key.merkle_hash()
     }
 
+    /// Gets the hash of origin key with `H`.
+    pub fn key_hash_with<H: TreeHasher>(&self) -> HashValue {
+        self.key.merkle_hash_with::<H>()
+    }
+
     /// Gets the hash of associated blob.
     pub fn value_hash(&self) -> HashValue {
-        self.value.merkle_hash()
+        self.value_hash_with::<Sha3TreeHasher>()
+    }
+
+    /// Gets the hash of associated blob with `H`. The hash of a detached value is the one it was
+    /// detached with, whatever `H` is.
+    pub fn value_hash_with<H: TreeHasher>(&self) -> HashValue {
+        match &self.value {
+            LeafValue::Inline(value) => value.merkle_hash_with::<H>(),
+            LeafValue::Detached(value_hash) => *value_hash,
+        }
+    }
+
+    /// Whether the leaf only holds the hash of its value.
+    pub fn is_detached(&self) -> bool {
+        matches!(self.value, LeafValue::Detached(_))
     }
 
     /// Gets the associated blob itself.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the value is detached, see [`try_value`](Self::try_value).
     pub fn value(&self) -> &SMTObject<V> {
-        &self.value
+        self.try_value()
+            .expect("The value of the leaf is detached.")
+    }
+
+    /// Gets the associated blob itself, or fails if the leaf only holds its hash.
+    pub fn try_value(&self) -> Result<&SMTObject<V>> {
+        match &self.value {
+            LeafValue::Inline(value) => Ok(value),
+            LeafValue::Detached(value_hash) => Err(anyhow::format_err!(
+                "The value {:x} of the leaf is detached, it has to be read with a ValueReader.",
+                value_hash
+            )),
+        }
+    }
+
+    /// Consumes the leaf node and returns only the value, or fails if the leaf only holds its
+    /// hash.
+    pub fn into_value(self) -> Result<SMTObject<V>> {
+        Ok(self.into_key_value()?.1)
+    }
+
+    /// Consumes the leaf node and returns the key and the value, or fails if the leaf only holds
+    /// the hash of its value.
+    pub fn into_key_value(self) -> Result<(SMTObject<K>, SMTObject<V>)> {
+        self.try_value()?;
+        Ok(self.into())
+    }
+
+    /// Splits the value off the leaf: returns the detached leaf, with the value hash computed with
+    /// `H`, and the value, which is `None` if the leaf was detached already.
+    pub fn detach_with<H: TreeHasher>(self) -> (Self, Option<SMTObject<V>>) {
+        let value_hash = self.value_hash_with::<H>();
+        let value = match self.value {
+            LeafValue::Inline(value) => Some(value),
+            LeafValue::Detached(_) => None,
+        };
+        let leaf_node = Self {
+            key: self.key,
+            value: LeafValue::Detached(value_hash),
+            cached_hash: self.cached_hash,
+        };
+        (leaf_node, value)
+    }
+
+    /// Same as [`detach_with`](Self::detach_with), but only detaches a value of more than
+    /// `inline_threshold` bytes. A smaller value is kept inline, where it takes less room than its
+    /// hash and a separate entry, and the leaf is returned as is with `None`.
+    pub fn detach_above_with<H: TreeHasher>(
+        self,
+        inline_threshold: usize,
+    ) -> (Self, Option<SMTObject<V>>) {
+        match &self.value {
+            LeafValue::Inline(value) if value.raw.len() > inline_threshold => {
+                self.detach_with::<H>()
+            }
+            _ => (self, None),
+        }
+    }
+
+    /// Puts `value`, read for a detached leaf, back into the leaf. Fails if `value` does not hash
+    /// to the value hash of the leaf with `H`.
+    pub fn attach_with<H: TreeHasher>(self, value: SMTObject<V>) -> Result<Self> {
+        let value_hash = value.merkle_hash_with::<H>();
+        ensure!(
+            value_hash == self.value_hash_with::<H>(),
+            "The value {:x} does not match the value hash {:x} of the leaf.",
+            value_hash,
+            self.value_hash_with::<H>()
+        );
+        Ok(Self {
+            key: self.key,
+            value: LeafValue::Inline(value),
+            cached_hash: self.cached_hash,
+        })
     }
 
     pub fn serialize(&self, binary: &mut Vec<u8>) -> Result<()> {
@@ -474,8 +722,44 @@ where
         bcs::from_bytes(data).map_err(|e| e.into())
     }
 
+    /// Consumes the leaf node and returns the key and the value.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the value is detached, see [`into_key_value`](Self::into_key_value).
     pub fn into(self) -> (SMTObject<K>, SMTObject<V>) {
-        (self.key, self.value)
+        match self.value {
+            LeafValue::Inline(value) => (self.key, value),
+            LeafValue::Detached(_) => panic!("The value of the leaf is detached."),
+        }
+    }
+
+    /// Consumes the leaf node and returns only the key.
+    pub fn into_key(self) -> SMTObject<K> {
+        self.key
+    }
+}
+
+/// An [`InternalNode`] is serialized as the bytes of its physical storage format, see
+/// [`InternalNode::serialize`], so deserializing it checks the same invariants as decoding it.
+impl Serialize for InternalNode {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let mut binary = vec![];
+        InternalNode::serialize(self, &mut binary).map_err(serde::ser::Error::custom)?;
+        serializer.serialize_bytes(binary.as_slice())
+    }
+}
+
+impl<'de> Deserialize<'de> for InternalNode {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let bytes = Vec::<u8>::deserialize(deserializer)?;
+        InternalNode::deserialize(bytes.as_slice()).map_err(serde::de::Error::custom)
     }
 }
 
@@ -485,6 +769,13 @@ struct RawKV {
     value: Vec<u8>,
 }
 
+/// The physical storage format of a detached [`LeafNode`].
+#[derive(Serialize, Deserialize)]
+struct RawDetachedKV {
+    key: Vec<u8>,
+    value_hash: [u8; HashValue::LENGTH],
+}
+
 impl<K, V> Serialize for LeafNode<K, V>
 where
     K: Key,
@@ -496,7 +787,11 @@ where
     {
         let wrapper = RawKV {
             key: self.key.raw.clone(),
-            value: self.value.raw.clone(),
+            value: self
+                .try_value()
+                .map_err(serde::ser::Error::custom)?
+                .raw
+                .clone(),
         };
         wrapper.serialize(serializer)
     }
@@ -525,8 +820,11 @@ where
     K: Key,
     V: Value,
 {
-    fn merkle_hash(&self) -> HashValue {
-        SparseMerkleLeafNode::new(self.key.merkle_hash(), self.value.merkle_hash()).merkle_hash()
+    fn merkle_hash_with<H: TreeHasher>(&self) -> HashValue {
+        self.cached_hash.get_or_compute::<H>(|| {
+            SparseMerkleLeafNode::new(self.key_hash_with::<H>(), self.value_hash_with::<H>())
+                .merkle_hash_with::<H>()
+        })
     }
 }
 
@@ -536,9 +834,11 @@ enum NodeTag {
     Null = 0,
     Internal = 1,
     Leaf = 2,
+    InternalWithLeafCounts = 3,
+    DetachedLeaf = 4,
 }
 
-/// The concrete node type of [`JellyfishMerkleTree`](super::JellyfishMerkleTree).
+/// The concrete node type of the tree.
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum Node<K, V> {
     /// Represents `null`.
@@ -592,16 +892,72 @@ where
         matches!(self, Node::Leaf(_))
     }
 
-    /// Serializes to bytes for physical storage.
+    /// Returns the [`NodeKey`] of the node in a tree built with the default [`Sha3TreeHasher`],
+    /// which is the hash of its content. The key of a null node is the placeholder hash, the root
+    /// hash of an empty tree, under which nothing is stored.
+    pub fn hash(&self) -> NodeKey {
+        self.hash_with::<Sha3TreeHasher>()
+    }
+
+    /// Same as `hash`, for a tree built with the hasher `H`.
+    pub fn hash_with<H: TreeHasher>(&self) -> NodeKey {
+        self.merkle_hash_with::<H>()
+    }
+
+    /// Serializes to bytes for physical storage. The encoding is canonical: a node has exactly
+    /// one encoding, and decoding then encoding bytes yields the same bytes. The layout is
+    /// (integers are little endian):
+    ///
+    /// ```text
+    /// Null:     0x00
+    /// Internal: 0x01 | existence bitmap (u16) | leaf bitmap (u16) | child hashes (32 bytes each)
+    /// Leaf:     0x02 | key length (ULEB128) | key raw bytes | value length (ULEB128) | value raw bytes
+    /// Internal: 0x03 | same as 0x01 | leaf counts of the internal children (varint each), opt-in
+    /// Leaf:     0x04 | key length (ULEB128) | key raw bytes | value hash (32 bytes)
+    /// ```
+    ///
+    /// The bit `i` of the existence bitmap is set if the internal node has a child at nibble `i`,
+    /// and the same bit of the leaf bitmap if this child is a leaf. The child hashes follow in
+    /// nibble order. The raw bytes of the key and the value are the ones of
+    /// [`SMTObject::raw`](crate::SMTObject), the leaf being the BCS encoding of the two of them.
+    ///
+    /// An internal node is always written with the 0x01 layout, dropping the leaf counts of its
+    /// children. The 0x03 layout is only written by `encode_with_leaf_counts`. A detached leaf,
+    /// see [`LeafNode::new_detached`], is written with the 0x04 layout.
+    ///
+    /// The leading tag byte identifies the layout as well as the variant: a change of the layout
+    /// of a variant gets a new tag, so that the nodes already stored can still be decoded.
     pub fn encode(&self) -> Result<Vec<u8>> {
+        self.encode_impl(false)
+    }
+
+    /// Same as `encode`, but an internal node is written with the 0x03 layout if it has internal
+    /// children whose leaf counts are all known. The leaf counts are written in nibble order, with
+    /// the varint encoding of `serialize_u64_varint`. The node has the same hash either way, but
+    /// these bytes can not be decoded by the versions of this crate predating the 0x03 tag.
+    pub fn encode_with_leaf_counts(&self) -> Result<Vec<u8>> {
+        self.encode_impl(true)
+    }
+
+    fn encode_impl(&self, with_leaf_counts: bool) -> Result<Vec<u8>> {
         let mut out = vec![];
         match self {
             Node::Null => {
                 out.push(NodeTag::Null as u8);
             }
             Node::Internal(internal_node) => {
-                out.push(NodeTag::Internal as u8);
-                internal_node.serialize(&mut out)?;
+                out.push(NodeTag::InternalWithLeafCounts as u8);
+                if !with_leaf_counts || !internal_node.serialize_with_leaf_counts(&mut out)? {
+                    out[0] = NodeTag::Internal as u8;
+                    internal_node.serialize(&mut out)?;
+                }
+            }
+            Node::Leaf(leaf_node) if leaf_node.is_detached() => {
+                out.push(NodeTag::DetachedLeaf as u8);
+                out.extend(bcs::to_bytes(&RawDetachedKV {
+                    key: leaf_node.key.raw.clone(),
+                    value_hash: *leaf_node.value_hash().as_ref(),
+                })?);
             }
             Node::Leaf(leaf_node) => {
                 out.push(NodeTag::Leaf as u8);
@@ -611,6 +967,24 @@ where
         Ok(out)
     }
 
+    /// Returns the length of the bytes `encode` returns, without encoding the node.
+    pub fn encoded_len(&self) -> usize {
+        match self {
+            Node::Null => 1,
+            Node::Internal(internal_node) => {
+                1 + 2 * size_of::<u16>() + internal_node.num_children() * HashValue::LENGTH
+            }
+            Node::Leaf(leaf_node) => {
+                let uleb128_len = |len: usize| u64_varint_len(len as u64);
+                let value_len = match &leaf_node.value {
+                    LeafValue::Inline(value) => uleb128_len(value.raw.len()) + value.raw.len(),
+                    LeafValue::Detached(_) => HashValue::LENGTH,
+                };
+                1 + uleb128_len(leaf_node.key.raw.len()) + leaf_node.key.raw.len() + value_len
+            }
+        }
+    }
+
     /// Recovers from serialized bytes in physical storage.
     pub fn decode(val: &[u8]) -> Result<Node<K, V>> {
         if val.is_empty() {
@@ -622,6 +996,16 @@ where
             Some(NodeTag::Null) => Ok(Node::Null),
             Some(NodeTag::Internal) => Ok(Node::Internal(InternalNode::deserialize(&val[1..])?)),
             Some(NodeTag::Leaf) => Ok(Node::Leaf(LeafNode::deserialize(&val[1..])?)),
+            Some(NodeTag::InternalWithLeafCounts) => Ok(Node::Internal(
+                InternalNode::deserialize_with_leaf_counts(&val[1..])?,
+            )),
+            Some(NodeTag::DetachedLeaf) => {
+                let raw: RawDetachedKV = bcs::from_bytes(&val[1..])?;
+                Ok(Node::Leaf(LeafNode::new_detached(
+                    K::from_raw(raw.key)?,
+                    HashValue::new(raw.value_hash),
+                )))
+            }
             None => Err(NodeDecodeError::UnknownTag { unknown_tag: tag }.into()),
         }
     }
@@ -632,11 +1016,11 @@ where
     K: Key,
     V: Value,
 {
-    fn merkle_hash(&self) -> HashValue {
+    fn merkle_hash_with<H: TreeHasher>(&self) -> HashValue {
         match self {
-            Node::Null => *SPARSE_MERKLE_PLACEHOLDER_HASH,
-            Node::Internal(internal_node) => internal_node.merkle_hash(),
-            Node::Leaf(leaf_node) => leaf_node.merkle_hash(),
+            Node::Null => H::SPARSE_MERKLE_PLACEHOLDER,
+            Node::Internal(internal_node) => internal_node.merkle_hash_with::<H>(),
+            Node::Leaf(leaf_node) => leaf_node.merkle_hash_with::<H>(),
         }
     }
 }
@@ -684,8 +1068,8 @@ impl SparseMerkleInternalNode {
 }
 
 impl SMTHash for SparseMerkleInternalNode {
-    fn merkle_hash(&self) -> HashValue {
-        merkle_hash(self.left_child, self.right_child)
+    fn merkle_hash_with<H: TreeHasher>(&self) -> HashValue {
+        H::hash_internal(self.left_child, self.right_child)
     }
 }
 
@@ -705,8 +1089,8 @@ impl SparseMerkleLeafNode {
 }
 
 impl SMTHash for SparseMerkleLeafNode {
-    fn merkle_hash(&self) -> HashValue {
-        merkle_hash(self.key_hash, self.value_hash)
+    fn merkle_hash_with<H: TreeHasher>(&self) -> HashValue {
+        H::hash_leaf(self.key_hash, self.value_hash)
     }
 }
 
@@ -733,6 +1117,21 @@ pub enum NodeDecodeError {
         leaves
     )]
     ExtraLeaves { existing: u16, leaves: u16 },
+
+    /// Bytes left after the last child of an internal node
+    #[error("{} bytes left after the last child of internal node", remaining)]
+    TrailingBytes { remaining: usize },
+
+    /// The only child of an internal node is a leaf, which should have taken its place
+    #[error("Single leaf child found in internal node")]
+    SingleLeafChild,
+}
+
+/// Returns the number of bytes `serialize_u64_varint` writes for `num`. Up to 2^56 - 1, it is also
+/// the length of the ULEB128 encoding of `num`.
+fn u64_varint_len(num: u64) -> usize {
+    let bits = u64::BITS - num.leading_zeros();
+    (bits.max(1) as usize).div_ceil(7).min(9)
 }
 
 /// Helper function to serialize version in a more efficient encoding.
@@ -4,7 +4,7 @@
 // Copyright (c) The Starcoin Core Contributors
 // SPDX-License-Identifier: Apache-2.0
 
-use super::super::hash::{HashValue, SPARSE_MERKLE_PLACEHOLDER_HASH};
+use super::super::hash::{HashValue, Sha3TreeHasher, SPARSE_MERKLE_PLACEHOLDER_HASH};
 use super::super::nibble_path::NibblePath;
 use super::*;
 use crate::{
@@ -59,9 +59,15 @@ fn test_encode_decode() {
     let nodes = vec![
         Node::new_internal(children),
         Node::new_leaf(account_key, TestValue::from(vec![0x02])),
+        // The length of the value takes two bytes.
+        Node::new_leaf(
+            TestKey(HashValue::random()),
+            TestValue::from(vec![0x03; 200]),
+        ),
     ];
     for n in &nodes {
         let v = n.encode().unwrap();
+        assert_eq!(n.encoded_len(), v.len());
         assert_eq!(*n, Node::decode(&v).unwrap());
     }
     // Error cases
@@ -77,6 +83,163 @@ fn test_encode_decode() {
             NodeDecodeError::UnknownTag { unknown_tag: 100 }
         );
     }
+    let mut internal_bytes = nodes[0].encode().unwrap();
+    internal_bytes.push(0);
+    if let Err(e) = Node::<TestKey, TestValue>::decode(&internal_bytes) {
+        assert_eq!(
+            e.downcast::<NodeDecodeError>().unwrap(),
+            NodeDecodeError::TrailingBytes { remaining: 1 }
+        );
+    }
+    // An internal node whose only child is a leaf is rejected rather than asserted against.
+    let mut single_leaf_child_bytes = vec![NodeTag::Internal as u8];
+    single_leaf_child_bytes.extend(1u16.to_le_bytes());
+    single_leaf_child_bytes.extend(1u16.to_le_bytes());
+    single_leaf_child_bytes.extend(HashValue::random().to_vec());
+    assert_eq!(
+        Node::<TestKey, TestValue>::decode(&single_leaf_child_bytes)
+            .unwrap_err()
+            .downcast::<NodeDecodeError>()
+            .unwrap(),
+        NodeDecodeError::SingleLeafChild
+    );
+}
+
+/// Pins the encoding of the nodes of a small tree, see [`Node::encode`] for the layout.
+#[test]
+fn test_encode_golden() {
+    let leaf_a: Node<String, String> = Node::new_leaf("a".to_string(), "1".to_string());
+    let leaf_b: Node<String, String> = Node::new_leaf("b".to_string(), "2".to_string());
+    let mut children = Children::default();
+    children.insert(Nibble::from(0), Child::new(leaf_a.merkle_hash(), true));
+    children.insert(Nibble::from(15), Child::new(leaf_b.merkle_hash(), true));
+    let internal: Node<String, String> = Node::new_internal(children);
+
+    let golden = [
+        (Node::new_null(), "00"),
+        (leaf_a, "02020161020131"),
+        (leaf_b, "02020162020132"),
+        (
+            internal,
+            concat!(
+                "0101800180",
+                "fd5ee1bc1325ad975b731e987f980948701a34301290816819ef510b81f56258",
+                "374513e447acf4581e416164081a36e45f3a759d9c37edd7c7be1c3edd5f6b16",
+            ),
+        ),
+    ];
+    for (node, expected) in golden {
+        let bytes = node.encode().unwrap();
+        assert_eq!(hex::encode(&bytes), expected);
+        assert_eq!(node.encoded_len(), bytes.len());
+        let decoded = Node::<String, String>::decode(&bytes).unwrap();
+        assert_eq!(decoded, node);
+        assert_eq!(decoded.encode().unwrap(), bytes);
+    }
+}
+
+#[test]
+fn test_encode_leaf_counts() {
+    let leaf_node: Node<TestKey, TestValue> =
+        Node::new_leaf(TestKey(HashValue::random()), TestValue::from(vec![0x01]));
+    let mut children = Children::default();
+    children.insert(Nibble::from(1), Child::new(leaf_node.merkle_hash(), true));
+    children.insert(
+        Nibble::from(3),
+        Child::new_internal(HashValue::random(), Some(2)),
+    );
+    children.insert(
+        Nibble::from(9),
+        Child::new_internal(HashValue::random(), Some(300)),
+    );
+    let internal_node = InternalNode::new(children);
+    assert_eq!(internal_node.leaf_count(), Some(303));
+
+    // The leaf counts are dropped unless asked for, keeping the 0x01 layout.
+    let node: Node<TestKey, TestValue> = internal_node.clone().into();
+    let mut without_leaf_counts = vec![1];
+    internal_node.serialize(&mut without_leaf_counts).unwrap();
+    assert_eq!(node.encode().unwrap(), without_leaf_counts);
+    assert_eq!(node.encoded_len(), without_leaf_counts.len());
+
+    // The leaf counts of the internal children follow the 0x01 layout.
+    let bytes = node.encode_with_leaf_counts().unwrap();
+    assert_eq!(bytes[0], 3);
+    assert_eq!(
+        bytes[1..without_leaf_counts.len()],
+        without_leaf_counts[1..]
+    );
+    assert_eq!(bytes[without_leaf_counts.len()..], [0x02, 0xac, 0x02]);
+    match Node::<TestKey, TestValue>::decode(&bytes).unwrap() {
+        Node::Internal(decoded) => assert_eq!(decoded.leaf_count(), Some(303)),
+        _ => unreachable!(),
+    }
+
+    // A node without leaf counts keeps the 0x01 layout and decodes with unknown counts.
+    match Node::<TestKey, TestValue>::decode(&without_leaf_counts).unwrap() {
+        Node::Internal(decoded) => {
+            assert_eq!(decoded, internal_node);
+            assert_eq!(decoded.leaf_count(), None);
+            assert_eq!(
+                Node::<TestKey, TestValue>::Internal(decoded)
+                    .encode_with_leaf_counts()
+                    .unwrap(),
+                without_leaf_counts
+            );
+        }
+        _ => unreachable!(),
+    }
+
+    // An internal child can not have less than two leaves.
+    let mut invalid = bytes.clone();
+    let len = invalid.len();
+    invalid[len - 3] = 1;
+    assert!(Node::<TestKey, TestValue>::decode(&invalid).is_err());
+    let mut trailing = bytes;
+    trailing.push(0);
+    assert_eq!(
+        Node::<TestKey, TestValue>::decode(&trailing)
+            .unwrap_err()
+            .downcast::<NodeDecodeError>()
+            .unwrap(),
+        NodeDecodeError::TrailingBytes { remaining: 1 }
+    );
+}
+
+#[test]
+fn test_serde_roundtrip() {
+    let leaf_node = LeafNode::new(TestKey(HashValue::random()), TestValue::from(vec![0x01]));
+    let mut children = Children::default();
+    children.insert(Nibble::from(1), Child::new(leaf_node.merkle_hash(), true));
+    children.insert(Nibble::from(9), Child::new(HashValue::random(), false));
+    let internal_node = InternalNode::new(children);
+
+    let nodes: Vec<Node<TestKey, TestValue>> = vec![
+        Node::new_null(),
+        internal_node.clone().into(),
+        Node::Leaf(leaf_node),
+    ];
+    for node in nodes {
+        let bytes = bcs::to_bytes(&node).unwrap();
+        assert_eq!(bcs::from_bytes::<Node<_, _>>(&bytes).unwrap(), node);
+        let json = serde_json::to_string(&node).unwrap();
+        assert_eq!(serde_json::from_str::<Node<_, _>>(&json).unwrap(), node);
+    }
+
+    let bytes = bcs::to_bytes(&internal_node).unwrap();
+    assert_eq!(
+        bcs::from_bytes::<InternalNode>(&bytes).unwrap(),
+        internal_node
+    );
+    let child = Child::new(HashValue::random(), true);
+    let bytes = bcs::to_bytes(&child).unwrap();
+    assert_eq!(bcs::from_bytes::<Child>(&bytes).unwrap(), child);
+    let node_key: NodeKey = HashValue::random();
+    let json = serde_json::to_string(&node_key).unwrap();
+    assert_eq!(serde_json::from_str::<NodeKey>(&json).unwrap(), node_key);
+
+    // Deserializing checks the invariants of an internal node.
+    assert!(bcs::from_bytes::<InternalNode>(&bcs::to_bytes(&vec![0u8; 4]).unwrap()).is_err());
 }
 
 proptest! {
@@ -114,6 +277,32 @@ fn test_internal_validity() {
     assert!(result.is_err());
 }
 
+#[test]
+fn test_internal_node_children() {
+    let leaf_hash = HashValue::random();
+    let internal_hash = HashValue::random();
+    let mut children = Children::default();
+    children.insert(Nibble::from(9), Child::new(internal_hash, false));
+    children.insert(Nibble::from(2), Child::new(leaf_hash, true));
+    let internal_node = InternalNode::new(children);
+
+    assert_eq!(
+        internal_node
+            .children()
+            .map(|(nibble, child)| (u8::from(nibble), child.hash, child.is_leaf))
+            .collect::<Vec<_>>(),
+        vec![(2, leaf_hash, true), (9, internal_hash, false)]
+    );
+    assert_eq!(
+        internal_node.child(Nibble::from(2)).unwrap().hash,
+        leaf_hash
+    );
+    assert!(internal_node.child(Nibble::from(3)).is_none());
+    assert_eq!(internal_node.generate_bitmaps(), (1 << 2 | 1 << 9, 1 << 2));
+    assert_eq!(internal_node.children_bitmap(), 0b10_0000_0100);
+    assert_eq!(internal_node.leaf_bitmap(), 0b100);
+}
+
 #[test]
 fn test_leaf_hash() {
     {
@@ -126,6 +315,124 @@ fn test_leaf_hash() {
     }
 }
 
+#[test]
+fn test_node_hash() {
+    use crate::jellyfish_merkle::{
+        mock_tree_store::MockTestStore, JellyfishMerkleTree, TreeReader,
+    };
+
+    let db = MockTestStore::new_test();
+    let tree = JellyfishMerkleTree::new(&db);
+    let (root, batch) = tree
+        .put_blob_set(
+            None,
+            (0..100u8)
+                .map(|i| (TestKey::random().into(), TestValue::from(vec![i]).into()))
+                .collect(),
+        )
+        .unwrap();
+    db.write_tree_update_batch(batch).unwrap();
+
+    // Every node of the tree is stored under its hash, which its parent refers to it by.
+    let mut node_keys = vec![root];
+    while let Some(node_key) = node_keys.pop() {
+        let node: Node<TestKey, TestValue> = db.get_node(&node_key).unwrap();
+        assert_eq!(node.hash(), node_key);
+        assert_eq!(
+            Node::<TestKey, TestValue>::decode(&node.encode().unwrap())
+                .unwrap()
+                .hash(),
+            node_key
+        );
+        if let Node::Internal(internal_node) = node {
+            node_keys.extend(internal_node.children().map(|(_, child)| child.hash));
+        }
+    }
+
+    assert_eq!(
+        Node::<TestKey, TestValue>::new_null().hash(),
+        *SPARSE_MERKLE_PLACEHOLDER_HASH
+    );
+    let leaf_node: Node<TestKey, TestValue> =
+        Node::new_leaf(TestKey::random(), TestValue::from(vec![1]));
+    assert_eq!(leaf_node.hash_with::<Sha3TreeHasher>(), leaf_node.hash());
+}
+
+#[test]
+fn test_detached_leaf() {
+    let key = TestKey::random().into_object();
+    let value = TestValue::from(vec![0x02; 100]).into_object();
+    let leaf_node: LeafNode<TestKey, TestValue> = LeafNode::new(key.clone(), value.clone());
+    let (detached, detached_value) = leaf_node.clone().detach_with::<Sha3TreeHasher>();
+    assert_eq!(detached_value, Some(value.clone()));
+    assert!(detached.is_detached());
+    assert!(detached.try_value().is_err());
+    assert!(detached.clone().into_value().is_err());
+    // Detaching does not change the hash of the leaf.
+    assert_eq!(detached.value_hash(), value.merkle_hash());
+    assert_eq!(detached.merkle_hash(), leaf_node.merkle_hash());
+    assert_eq!(
+        LeafNode::<TestKey, TestValue>::new_detached(key, value.merkle_hash()),
+        detached
+    );
+
+    let node = Node::Leaf(detached.clone());
+    let bytes = node.encode().unwrap();
+    assert_eq!(bytes[0], 4);
+    assert_eq!(node.encoded_len(), bytes.len());
+    assert!(bytes.len() < Node::Leaf(leaf_node.clone()).encoded_len());
+    let decoded = Node::<TestKey, TestValue>::decode(&bytes).unwrap();
+    assert_eq!(decoded, node);
+    assert_eq!(decoded.merkle_hash(), leaf_node.merkle_hash());
+
+    // Only the value the leaf was detached from can be attached back.
+    assert!(detached
+        .clone()
+        .attach_with::<Sha3TreeHasher>(TestValue::from(vec![0x03]).into_object())
+        .is_err());
+    assert_eq!(
+        detached.attach_with::<Sha3TreeHasher>(value).unwrap(),
+        leaf_node
+    );
+}
+
+#[test]
+fn test_detach_above_threshold() {
+    let key = TestKey::random().into_object();
+    let small_value = TestValue::from(vec![0x02; 8]).into_object();
+    let large_value = TestValue::from(vec![0x02; 100]).into_object();
+
+    // A value up to the threshold, in encoded bytes, stays inline.
+    let threshold = small_value.raw.len();
+    let leaf_node: LeafNode<TestKey, TestValue> = LeafNode::new(key.clone(), small_value);
+    let (inline, value) = leaf_node
+        .clone()
+        .detach_above_with::<Sha3TreeHasher>(threshold);
+    assert_eq!(value, None);
+    assert_eq!(inline, leaf_node);
+    assert_eq!(Node::Leaf(inline).encode().unwrap()[0], 2);
+
+    // A larger one is referenced by its hash, and the leaf hash is the same.
+    let leaf_node: LeafNode<TestKey, TestValue> = LeafNode::new(key, large_value.clone());
+    let (detached, value) = leaf_node
+        .clone()
+        .detach_above_with::<Sha3TreeHasher>(threshold);
+    assert_eq!(value, Some(large_value));
+    assert!(detached.is_detached());
+    assert_eq!(detached.merkle_hash(), leaf_node.merkle_hash());
+    let bytes = Node::Leaf(detached.clone()).encode().unwrap();
+    assert_eq!(bytes[0], 4);
+    assert_eq!(
+        Node::<TestKey, TestValue>::decode(&bytes).unwrap(),
+        Node::Leaf(detached.clone())
+    );
+
+    // A detached leaf stays detached.
+    let (again, value) = detached.clone().detach_above_with::<Sha3TreeHasher>(1000);
+    assert_eq!(value, None);
+    assert_eq!(again, detached);
+}
+
 proptest! {
     #[test]
     fn two_leaves_test1(index1 in (0..8u8).prop_map(Nibble::from), index2 in (8..16u8).prop_map(Nibble::from)) {
@@ -153,13 +460,13 @@ proptest! {
 
         for i in 0..8 {
             prop_assert_eq!(
-                internal_node.get_child_with_siblings( i.into()),
+                internal_node.get_child_with_siblings::<Sha3TreeHasher>( i.into()),
                 (Some(leaf1_node_key.0), vec![hash2])
             );
         }
         for i in 8..16 {
             prop_assert_eq!(
-                internal_node.get_child_with_siblings( i.into()),
+                internal_node.get_child_with_siblings::<Sha3TreeHasher>( i.into()),
                 (Some(leaf2_node_key.0), vec![hash1])
             );
         }
@@ -200,14 +507,14 @@ proptest! {
 
         for i in 0..4 {
             prop_assert_eq!(
-                internal_node.get_child_with_siblings( i.into()),
+                internal_node.get_child_with_siblings::<Sha3TreeHasher>( i.into()),
                 (None, vec![*SPARSE_MERKLE_PLACEHOLDER_HASH, hash_x1])
             );
         }
 
         for i in 4..6 {
             prop_assert_eq!(
-                internal_node.get_child_with_siblings( i.into()),
+                internal_node.get_child_with_siblings::<Sha3TreeHasher>( i.into()),
                 (
                     Some(leaf1_node_key.0),
                     vec![
@@ -221,7 +528,7 @@ proptest! {
 
         for i in 6..8 {
             prop_assert_eq!(
-                internal_node.get_child_with_siblings( i.into()),
+                internal_node.get_child_with_siblings::<Sha3TreeHasher>( i.into()),
                 (
                     Some(leaf2_node_key.0),
                     vec![
@@ -235,7 +542,7 @@ proptest! {
 
         for i in 8..16 {
             prop_assert_eq!(
-                internal_node.get_child_with_siblings( i.into()),
+                internal_node.get_child_with_siblings::<Sha3TreeHasher>( i.into()),
                 (None, vec![hash_x2])
             );
         }
@@ -274,21 +581,21 @@ proptest! {
 
         for i in 0..4 {
             prop_assert_eq!(
-                internal_node.get_child_with_siblings( i.into()),
+                internal_node.get_child_with_siblings::<Sha3TreeHasher>( i.into()),
                 (Some(leaf1_node_key.0),vec![hash3, hash2])
             );
         }
 
         for i in 4..8 {
             prop_assert_eq!(
-                internal_node.get_child_with_siblings( i.into()),
+                internal_node.get_child_with_siblings::<Sha3TreeHasher>( i.into()),
                 (Some(leaf2_node_key.0),vec![hash3, hash1])
             );
         }
 
         for i in 8..16 {
             prop_assert_eq!(
-                internal_node.get_child_with_siblings( i.into()),
+                internal_node.get_child_with_siblings::<Sha3TreeHasher>( i.into()),
                 (Some(leaf3_node_key.0),vec![hash_x])
             );
         }
@@ -339,7 +646,7 @@ proptest! {
 
         for i in 0..2 {
             prop_assert_eq!(
-                internal_node.get_child_with_siblings( i.into()),
+                internal_node.get_child_with_siblings::<Sha3TreeHasher>( i.into()),
                 (
                     Some(leaf1_node_key.0),
                     vec![hash4, hash_x4, hash_x1]
@@ -348,7 +655,7 @@ proptest! {
         }
 
         prop_assert_eq!(
-                internal_node.get_child_with_siblings( 2.into()),
+                internal_node.get_child_with_siblings::<Sha3TreeHasher>( 2.into()),
             (
                 Some(internal2_node_key.0),
                 vec![
@@ -361,7 +668,7 @@ proptest! {
         );
 
         prop_assert_eq!(
-                internal_node.get_child_with_siblings( 3.into()),
+                internal_node.get_child_with_siblings::<Sha3TreeHasher>( 3.into()),
 
             (
                 None,
@@ -371,7 +678,7 @@ proptest! {
 
         for i in 4..6 {
             prop_assert_eq!(
-                internal_node.get_child_with_siblings( i.into()),
+                internal_node.get_child_with_siblings::<Sha3TreeHasher>( i.into()),
                 (
                     None,
                     vec![hash4, hash_x2, hash_x3]
@@ -380,7 +687,7 @@ proptest! {
         }
 
         prop_assert_eq!(
-                internal_node.get_child_with_siblings( 6.into()),
+                internal_node.get_child_with_siblings::<Sha3TreeHasher>( 6.into()),
             (
                 None,
                 vec![
@@ -393,7 +700,7 @@ proptest! {
         );
 
         prop_assert_eq!(
-                internal_node.get_child_with_siblings( 7.into()),
+                internal_node.get_child_with_siblings::<Sha3TreeHasher>( 7.into()),
             (
                 Some(internal3_node_key.0),
                 vec![
@@ -407,7 +714,7 @@ proptest! {
 
         for i in 8..16 {
             prop_assert_eq!(
-                internal_node.get_child_with_siblings( i.into()),
+                internal_node.get_child_with_siblings::<Sha3TreeHasher>( i.into()),
                 (Some(leaf4_node_key.0), vec![hash_x5])
             );
         }
@@ -453,12 +760,12 @@ fn test_internal_hash_and_proof() {
         assert_eq!(internal_node.merkle_hash(), root_hash);
 
         for i in 0..4 {
-            let result = internal_node.get_child_with_siblings(i.into());
+            let result = internal_node.get_child_with_siblings::<Sha3TreeHasher>(i.into());
             assert_eq!(result, (None, vec![hash_x6, hash_x2]));
         }
 
         assert_eq!(
-            internal_node.get_child_with_siblings(5.into()),
+            internal_node.get_child_with_siblings::<Sha3TreeHasher>(5.into()),
             (
                 None,
                 vec![
@@ -471,7 +778,7 @@ fn test_internal_hash_and_proof() {
         );
         for i in 6..8 {
             assert_eq!(
-                internal_node.get_child_with_siblings(i.into()),
+                internal_node.get_child_with_siblings::<Sha3TreeHasher>(i.into()),
                 (
                     None,
                     vec![hash_x6, *SPARSE_MERKLE_PLACEHOLDER_HASH, hash_x1]
@@ -481,14 +788,14 @@ fn test_internal_hash_and_proof() {
 
         for i in 8..12 {
             assert_eq!(
-                internal_node.get_child_with_siblings(i.into()),
+                internal_node.get_child_with_siblings::<Sha3TreeHasher>(i.into()),
                 (None, vec![hash_x3, hash_x5])
             );
         }
 
         for i in 12..14 {
             assert_eq!(
-                internal_node.get_child_with_siblings(i.into()),
+                internal_node.get_child_with_siblings::<Sha3TreeHasher>(i.into()),
                 (
                     None,
                     vec![hash_x3, *SPARSE_MERKLE_PLACEHOLDER_HASH, hash_x4]
@@ -496,7 +803,7 @@ fn test_internal_hash_and_proof() {
             );
         }
         assert_eq!(
-            internal_node.get_child_with_siblings(14.into()),
+            internal_node.get_child_with_siblings::<Sha3TreeHasher>(14.into()),
             (
                 None,
                 vec![
@@ -546,7 +853,7 @@ fn test_internal_hash_and_proof() {
         assert_eq!(internal_node.merkle_hash(), root_hash);
 
         assert_eq!(
-            internal_node.get_child_with_siblings(1.into()),
+            internal_node.get_child_with_siblings::<Sha3TreeHasher>(1.into()),
             (
                 None,
                 vec![
@@ -560,7 +867,7 @@ fn test_internal_hash_and_proof() {
 
         for i in 2..4 {
             assert_eq!(
-                internal_node.get_child_with_siblings(i.into()),
+                internal_node.get_child_with_siblings::<Sha3TreeHasher>(i.into()),
                 (
                     None,
                     vec![*SPARSE_MERKLE_PLACEHOLDER_HASH, hash_x4, hash_x1]
@@ -570,7 +877,7 @@ fn test_internal_hash_and_proof() {
 
         for i in 4..6 {
             assert_eq!(
-                internal_node.get_child_with_siblings(i.into()),
+                internal_node.get_child_with_siblings::<Sha3TreeHasher>(i.into()),
                 (
                     None,
                     vec![*SPARSE_MERKLE_PLACEHOLDER_HASH, hash_x2, hash_x3]
@@ -579,7 +886,7 @@ fn test_internal_hash_and_proof() {
         }
 
         assert_eq!(
-            internal_node.get_child_with_siblings(6.into()),
+            internal_node.get_child_with_siblings::<Sha3TreeHasher>(6.into()),
             (
                 None,
                 vec![
@@ -593,7 +900,7 @@ fn test_internal_hash_and_proof() {
 
         for i in 8..16 {
             assert_eq!(
-                internal_node.get_child_with_siblings(i.into()),
+                internal_node.get_child_with_siblings::<Sha3TreeHasher>(i.into()),
                 (None, vec![hash_x5])
             );
         }
@@ -643,7 +950,7 @@ impl BinaryTreeNode {
 }
 
 impl SMTHash for BinaryTreeNode {
-    fn merkle_hash(&self) -> HashValue {
+    fn merkle_hash_with<H: TreeHasher>(&self) -> HashValue {
         self.hash()
     }
 }
@@ -761,7 +1068,7 @@ proptest! {
     ) {
         for n in 0..16u8 {
             prop_assert_eq!(
-                node.get_child_with_siblings(n.into()),
+                node.get_child_with_siblings::<Sha3TreeHasher>(n.into()),
                 NaiveInternalNode::from_clever_node(&node).get_child_with_siblings(n)
             )
         }
@@ -0,0 +1,124 @@
+// Copyright (c) RoochNetwork
+// SPDX-License-Identifier: Apache-2.0
+
+//! This module defines [`Observer`], a set of callbacks to collect metrics about the reads of a
+//! tree, and [`ObservedTreeReader`], a [`TreeReader`] reporting the nodes it reads to one.
+//!
+//! The trees and iterators take an `Option<&dyn Observer>`, `None` by default, so the cost of an
+//! unobserved operation is a branch per callback. The callbacks are given references and sizes,
+//! never node data, so an observer only pays for what it records.
+//!
+//! [`Observer`]: crate::Observer
+//! [`ObservedTreeReader`]: crate::ObservedTreeReader
+//! [`TreeReader`]: crate::TreeReader
+
+#[cfg(test)]
+mod observer_test;
+
+use super::{
+    hash::HashValue,
+    node_type::{Node, NodeKey},
+    TreeReader, Versioned,
+};
+use crate::{Key, Value};
+use anyhow::Result;
+use std::sync::Arc;
+
+/// Callbacks reporting what the operations on a tree do, e.g. to update monitoring counters. All
+/// the callbacks do nothing by default, an observer implements the ones it is interested in.
+pub trait Observer: Send + Sync {
+    /// A node was read from the storage. `size` is the length of its encoding, see
+    /// [`Node::encoded_len`](crate::Node::encoded_len).
+    fn on_node_read(&self, _node_key: &NodeKey, _size: usize) {}
+
+    /// An iterator yielded the leaf of `key_hash`.
+    fn on_leaf_yielded(&self, _key_hash: &HashValue) {}
+
+    /// A lookup or an iterator reached a leaf or an empty child at `depth`, the number of internal
+    /// nodes on the path from the root.
+    fn on_descent(&self, _depth: usize) {}
+}
+
+/// A [`TreeReader`](crate::TreeReader) wrapping another one, reporting every node it reads
+/// to its observer, if any. It owns the observer, so the iterators borrowing it can outlive the
+/// call creating them, as with [`SMTree::iter`](crate::SMTree::iter).
+#[derive(Clone)]
+pub struct ObservedTreeReader<R> {
+    reader: R,
+    observer: Option<Arc<dyn Observer>>,
+}
+
+impl<R> ObservedTreeReader<R> {
+    /// Creates an `ObservedTreeReader` reading from `reader`, reporting to `observer`.
+    pub fn new(reader: R, observer: Option<Arc<dyn Observer>>) -> Self {
+        Self { reader, observer }
+    }
+
+    /// Returns the wrapped reader.
+    pub fn inner(&self) -> &R {
+        &self.reader
+    }
+
+    /// Returns the wrapped reader, dropping the observer.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /// Returns the observer the reads are reported to, if any.
+    pub fn observer(&self) -> Option<&dyn Observer> {
+        self.observer.as_deref()
+    }
+
+    fn observe<K, V>(&self, node_key: &NodeKey, node: &Option<Node<K, V>>)
+    where
+        K: Key,
+        V: Value,
+    {
+        if let (Some(observer), Some(node)) = (&self.observer, node) {
+            observer.on_node_read(node_key, node.encoded_len());
+        }
+    }
+}
+
+impl<K, V, R> TreeReader<K, V> for ObservedTreeReader<R>
+where
+    K: Key,
+    V: Value,
+    R: TreeReader<K, V>,
+{
+    fn get_node_option(&self, node_key: &NodeKey) -> Result<Option<Node<K, V>>> {
+        let node = self.reader.get_node_option(node_key)?;
+        self.observe(node_key, &node);
+        Ok(node)
+    }
+
+    fn get_nodes(&self, node_keys: &[NodeKey]) -> Result<Vec<Option<Node<K, V>>>> {
+        let nodes = self.reader.get_nodes(node_keys)?;
+        for (node_key, node) in node_keys.iter().zip(&nodes) {
+            self.observe(node_key, node);
+        }
+        Ok(nodes)
+    }
+
+    fn with_node<T, F>(&self, node_key: &NodeKey, f: F) -> Result<Option<T>>
+    where
+        F: FnOnce(&Node<K, V>) -> T,
+    {
+        let observer = match &self.observer {
+            Some(observer) => observer,
+            None => return self.reader.with_node(node_key, f),
+        };
+        self.reader.with_node(node_key, |node| {
+            observer.on_node_read(node_key, node.encoded_len());
+            f(node)
+        })
+    }
+}
+
+impl<K, V, R> Versioned<K, V> for ObservedTreeReader<R>
+where
+    K: Key,
+    V: Value,
+    R: Versioned<K, V>,
+{
+}
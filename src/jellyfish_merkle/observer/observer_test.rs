@@ -0,0 +1,116 @@
+// Copyright (c) RoochNetwork
+// SPDX-License-Identifier: Apache-2.0
+
+use super::*;
+use crate::jellyfish_merkle::{
+    get_with,
+    hash::{SMTHash, Sha3TreeHasher},
+    iterator::JellyfishMerkleIterator,
+    mock_tree_store::{CountingTreeReader, MockTestStore, TestKey, TestValue},
+    test_helper::init_random_tree,
+    JellyfishMerkleTree,
+};
+use parking_lot::Mutex;
+use std::sync::Arc;
+
+/// Records every callback.
+#[derive(Default)]
+struct RecordingObserver {
+    node_reads: Mutex<Vec<(NodeKey, usize)>>,
+    leaves: Mutex<Vec<HashValue>>,
+    descents: Mutex<Vec<usize>>,
+}
+
+impl Observer for RecordingObserver {
+    fn on_node_read(&self, node_key: &NodeKey, size: usize) {
+        self.node_reads.lock().push((*node_key, size));
+    }
+
+    fn on_leaf_yielded(&self, key_hash: &HashValue) {
+        self.leaves.lock().push(*key_hash);
+    }
+
+    fn on_descent(&self, depth: usize) {
+        self.descents.lock().push(depth);
+    }
+}
+
+fn init_tree(db: &MockTestStore, n: usize) -> (HashValue, Vec<TestKey>) {
+    let (root, kvs) = init_random_tree(db, n, 60);
+    (root, kvs.into_iter().map(|(key, _)| key).collect())
+}
+
+#[test]
+fn test_node_reads() {
+    let db = MockTestStore::new_test();
+    let (root, keys) = init_tree(&db, 100);
+    let observer = Arc::new(RecordingObserver::default());
+    let reader = ObservedTreeReader::new(CountingTreeReader::new(db), Some(observer.clone()));
+    let counting = reader.inner();
+
+    let tree: JellyfishMerkleTree<TestKey, TestValue, _> = JellyfishMerkleTree::new(&reader);
+    let (value, _) = tree.get_with_proof(root, keys[0]).unwrap();
+    assert!(value.is_some());
+    assert!(get_with::<TestKey, TestValue, _, Sha3TreeHasher, _, _>(
+        &reader,
+        root,
+        &keys[1].into_object(),
+        |_| ()
+    )
+    .unwrap()
+    .is_some());
+
+    // Every node read is reported once, with the length of its encoding.
+    let node_reads = observer.node_reads.lock();
+    assert_eq!(node_reads.len(), counting.reads());
+    for (node_key, size) in node_reads.iter() {
+        let node: Node<TestKey, TestValue> = counting.get_node(node_key).unwrap();
+        assert_eq!(node.merkle_hash(), *node_key);
+        assert_eq!(*size, node.encode().unwrap().len());
+    }
+}
+
+#[test]
+fn test_descents_and_leaves() {
+    let db = MockTestStore::new_test();
+    let (root, keys) = init_tree(&db, 100);
+    let depths = JellyfishMerkleIterator::<TestKey, TestValue, _>::new(&db, root, None)
+        .unwrap()
+        .with_depth()
+        .map(|item| {
+            let (key, _, depth) = item.unwrap();
+            (key.merkle_hash(), depth)
+        })
+        .collect::<Vec<_>>();
+
+    // A lookup reports the depth of the leaf it reaches.
+    let observer = RecordingObserver::default();
+    let tree: JellyfishMerkleTree<TestKey, TestValue, _> =
+        JellyfishMerkleTree::new(&db).with_observer(Some(&observer));
+    for key in &keys {
+        tree.get_with_proof(root, *key).unwrap();
+        let key_hash = key.into_object().merkle_hash();
+        let (_, depth) = depths.iter().find(|(hash, _)| *hash == key_hash).unwrap();
+        assert_eq!(observer.descents.lock().pop(), Some(*depth));
+    }
+    assert!(observer.leaves.lock().is_empty());
+
+    // An iterator reports the leaves it yields from both ends, along with their depths.
+    let mut iter = JellyfishMerkleIterator::<TestKey, TestValue, _>::new(&db, root, None)
+        .unwrap()
+        .with_observer(Some(&observer));
+    let last = iter.next_back().unwrap().unwrap();
+    assert_eq!(iter.count(), 99);
+    let mut expected = depths.clone();
+    expected.rotate_right(1);
+    assert_eq!(observer.leaves.lock()[0], last.0.merkle_hash());
+    assert_eq!(
+        *observer.leaves.lock(),
+        expected.iter().map(|(hash, _)| *hash).collect::<Vec<_>>()
+    );
+    assert_eq!(
+        *observer.descents.lock(),
+        expected.iter().map(|(_, depth)| *depth).collect::<Vec<_>>()
+    );
+    assert!(observer.node_reads.lock().is_empty());
+}
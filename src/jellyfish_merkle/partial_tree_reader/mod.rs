@@ -0,0 +1,78 @@
+// Copyright (c) RoochNetwork
+// SPDX-License-Identifier: Apache-2.0
+
+//! This module implements [`PartialTreeReader`], a [`TreeReader`] over a store holding only part
+//! of a tree, e.g. a light client keeping the nodes near the root and fetching the others from a
+//! full node when they are needed.
+//!
+//! [`PartialTreeReader`]: crate::PartialTreeReader
+//! [`TreeReader`]: crate::TreeReader
+
+#[cfg(test)]
+mod partial_tree_reader_test;
+
+use super::{
+    node_type::{Node, NodeKey},
+    SmtError, TreeReader, Versioned,
+};
+use crate::{Key, Value};
+use anyhow::Result;
+
+/// A [`TreeReader`](crate::TreeReader) wrapping a store which legitimately misses some
+/// nodes. A node missing from the store is reported as [`SmtError::NodeNotHeld`] rather than read
+/// as `None`, so callers can tell it apart from a node missing from a full store, which is
+/// corruption, and fetch it on demand.
+///
+/// The [`SMTIterator`](crate::SMTIterator) returns this
+/// error without ending the iteration: once the node is added to the wrapped store, the next call
+/// to `next` reads it again and goes on.
+pub struct PartialTreeReader<R> {
+    /// The store holding the nodes of the partial tree.
+    reader: R,
+}
+
+impl<R> PartialTreeReader<R> {
+    /// Creates a `PartialTreeReader` over the nodes held by `reader`.
+    pub fn new(reader: R) -> Self {
+        Self { reader }
+    }
+
+    /// Returns the wrapped store, e.g. to add the nodes fetched on demand.
+    pub fn inner(&self) -> &R {
+        &self.reader
+    }
+}
+
+impl<K, V, R> TreeReader<K, V> for PartialTreeReader<R>
+where
+    K: Key,
+    V: Value,
+    R: TreeReader<K, V>,
+{
+    fn get_node_option(&self, node_key: &NodeKey) -> Result<Option<Node<K, V>>> {
+        match self.reader.get_node_option(node_key)? {
+            Some(node) => Ok(Some(node)),
+            None => Err(SmtError::NodeNotHeld(*node_key).into()),
+        }
+    }
+
+    fn get_nodes(&self, node_keys: &[NodeKey]) -> Result<Vec<Option<Node<K, V>>>> {
+        let nodes = self.reader.get_nodes(node_keys)?;
+        if let Some((node_key, _)) = node_keys
+            .iter()
+            .zip(nodes.iter())
+            .find(|(_, node)| node.is_none())
+        {
+            return Err(SmtError::NodeNotHeld(*node_key).into());
+        }
+        Ok(nodes)
+    }
+}
+
+impl<K, V, R> Versioned<K, V> for PartialTreeReader<R>
+where
+    K: Key,
+    V: Value,
+    R: Versioned<K, V>,
+{
+}
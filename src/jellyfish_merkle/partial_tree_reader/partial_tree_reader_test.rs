@@ -0,0 +1,90 @@
+// Copyright (c) RoochNetwork
+// SPDX-License-Identifier: Apache-2.0
+
+use super::*;
+use crate::jellyfish_merkle::{
+    hash::{HashValue, SMTHash},
+    iterator::JellyfishMerkleIterator,
+    mock_tree_store::{MockTestStore, TestKey, TestValue},
+    nibble::Nibble,
+    JellyfishMerkleTree, TreeWriter,
+};
+use crate::EncodeToObject;
+use rand::{rngs::StdRng, SeedableRng};
+
+#[test]
+fn test_iterate_partial_tree() {
+    let db = MockTestStore::new_test();
+    let tree: JellyfishMerkleTree<TestKey, TestValue, MockTestStore> =
+        JellyfishMerkleTree::new(&db);
+    let mut rng = StdRng::from_seed([7; 32]);
+    let kvs = (0..200)
+        .map(|_| {
+            (
+                TestKey(HashValue::random_with_rng(&mut rng)).into_object(),
+                TestValue::random().into_object(),
+            )
+        })
+        .collect::<Vec<_>>();
+    let (root_hash, batch) = tree.put_blob_set(None, kvs.clone()).unwrap();
+    db.write_tree_update_batch(batch).unwrap();
+
+    // Drop the internal node holding the subtree under nibble 8 of the root, its descendants are
+    // kept but cannot be reached without it.
+    let missing_node_key = match db.get_node(&root_hash).unwrap() {
+        Node::Internal(root) => root.child(Nibble::from(8)).unwrap().hash,
+        _ => panic!("The root should be an internal node."),
+    };
+    let missing_node = db.get_node(&missing_node_key).unwrap();
+    assert!(matches!(missing_node, Node::Internal(_)));
+    db.delete_node_batch(&[missing_node_key]).unwrap();
+    let reader = PartialTreeReader::new(db);
+
+    // Reading a key of the missing subtree tells it apart from corruption.
+    let key_in_missing_subtree = kvs
+        .iter()
+        .map(|(key, _)| key)
+        .find(|key| key.merkle_hash().as_ref()[0] >> 4 == 8)
+        .unwrap();
+    let tree: JellyfishMerkleTree<TestKey, TestValue, PartialTreeReader<MockTestStore>> =
+        JellyfishMerkleTree::new(&reader);
+    let err = tree
+        .get(root_hash, key_in_missing_subtree.clone())
+        .unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<SmtError>(),
+        Some(&SmtError::NodeNotHeld(missing_node_key))
+    );
+
+    let mut iter = JellyfishMerkleIterator::<_, _, _>::new(&reader, root_hash, None).unwrap();
+    let mut leaves = vec![];
+    for item in iter.by_ref() {
+        match item {
+            Ok(leaf) => leaves.push(leaf),
+            Err(err) => {
+                assert_eq!(
+                    err.downcast_ref::<SmtError>(),
+                    Some(&SmtError::NodeNotHeld(missing_node_key))
+                );
+                break;
+            }
+        }
+    }
+    // All the leaves before the missing subtree were yielded.
+    assert!(leaves
+        .iter()
+        .all(|(key, _)| key.merkle_hash().as_ref()[0] >> 4 < 8));
+    assert!(!leaves.is_empty());
+
+    // The iteration goes on once the missing node is fetched.
+    assert!(iter.next().unwrap().is_err());
+    reader
+        .inner()
+        .put_node(missing_node_key, missing_node)
+        .unwrap();
+    leaves.extend(iter.map(Result::unwrap));
+
+    let mut expected = kvs;
+    expected.sort_by_key(|(key, _)| key.merkle_hash());
+    assert_eq!(leaves, expected);
+}
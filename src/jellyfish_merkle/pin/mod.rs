@@ -0,0 +1,123 @@
+// Copyright (c) RoochNetwork
+// SPDX-License-Identifier: Apache-2.0
+
+//! This module implements the pinning of roots, which keeps the nodes of a version from being
+//! pruned while it is being read, e.g. by a long iteration.
+
+#[cfg(test)]
+mod pin_test;
+
+use super::{
+    hash::HashValue,
+    node_type::{Node, NodeKey},
+    TreeReader, TreeWriter,
+};
+use anyhow::Result;
+use parking_lot::Mutex;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+
+/// The set of the roots being read, shared by the readers pinning them and the pruner. Cloning it
+/// shares the same set.
+#[derive(Clone, Debug, Default)]
+pub struct PinnedRoots {
+    /// The number of live pins of each pinned root.
+    pins: Arc<Mutex<HashMap<HashValue, usize>>>,
+}
+
+impl PinnedRoots {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Pins `root` until the returned guard, and all its clones, are dropped.
+    pub fn pin(&self, root: HashValue) -> RootPin {
+        *self.pins.lock().entry(root).or_insert(0) += 1;
+        RootPin {
+            root,
+            pins: self.pins.clone(),
+        }
+    }
+
+    /// Returns whether `root` has a live pin.
+    pub fn is_pinned(&self, root: &HashValue) -> bool {
+        self.pins.lock().contains_key(root)
+    }
+
+    /// Returns the roots with a live pin.
+    pub fn roots(&self) -> Vec<HashValue> {
+        self.pins.lock().keys().copied().collect()
+    }
+}
+
+/// A pin on a root of a [`PinnedRoots`], released when dropped. An iterator holds one to keep
+/// the nodes it has yet to read, see [`SMTree::pinned_roots`](crate::SMTree::pinned_roots).
+#[derive(Debug)]
+pub struct RootPin {
+    root: HashValue,
+    pins: Arc<Mutex<HashMap<HashValue, usize>>>,
+}
+
+impl RootPin {
+    /// Returns the pinned root.
+    pub fn root(&self) -> HashValue {
+        self.root
+    }
+}
+
+/// Cloning a pin pins the root once more, so the root stays pinned until every clone is dropped.
+impl Clone for RootPin {
+    fn clone(&self) -> Self {
+        *self.pins.lock().entry(self.root).or_insert(0) += 1;
+        Self {
+            root: self.root,
+            pins: self.pins.clone(),
+        }
+    }
+}
+
+impl Drop for RootPin {
+    fn drop(&mut self) {
+        let mut pins = self.pins.lock();
+        if let Some(count) = pins.get_mut(&self.root) {
+            *count -= 1;
+            if *count == 0 {
+                pins.remove(&self.root);
+            }
+        }
+    }
+}
+
+/// Same as [`prune`](crate::prune), but the stale nodes reachable from a root of
+/// `pinned_roots` are kept. Returns the number of nodes deleted and the node keys kept, whose
+/// stale indices must be kept to prune them once their roots are unpinned.
+///
+/// Each pinned root is walked from the top, only descending into the nodes to prune: a node
+/// which is not stale at the versions pruned is in the tree of the last of them, so is every node
+/// of its subtree. The pins are read once when the walk starts, so a root must be pinned before
+/// pruning starts to be guarded by it, as the iterators do before they read their root.
+pub fn prune_unpinned<K, V, S>(
+    store: &S,
+    pinned_roots: &PinnedRoots,
+    stale_nodes: impl IntoIterator<Item = NodeKey>,
+) -> Result<(usize, Vec<NodeKey>)>
+where
+    S: TreeReader<K, V> + TreeWriter<K, V>,
+{
+    let stale_nodes = stale_nodes.into_iter().collect::<BTreeSet<_>>();
+    let mut kept = BTreeSet::new();
+    for root in pinned_roots.roots() {
+        let mut node_keys = vec![root];
+        while let Some(node_key) = node_keys.pop() {
+            if !stale_nodes.contains(&node_key) || !kept.insert(node_key) {
+                continue;
+            }
+            if let Node::Internal(internal_node) = store.get_node(&node_key)? {
+                node_keys.extend(internal_node.all_child());
+            }
+        }
+    }
+    let node_keys = stale_nodes.difference(&kept).copied().collect::<Vec<_>>();
+    store.delete_node_batch(&node_keys)?;
+    Ok((node_keys.len(), kept.into_iter().collect()))
+}
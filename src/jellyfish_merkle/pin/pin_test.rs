@@ -0,0 +1,125 @@
+// Copyright (c) RoochNetwork
+// SPDX-License-Identifier: Apache-2.0
+
+use super::{prune_unpinned, PinnedRoots};
+use crate::jellyfish_merkle::{
+    hash::HashValue,
+    iterator::JellyfishMerkleIterator,
+    mock_tree_store::{MockTestStore, TestKey, TestValue},
+    JellyfishMerkleTree, StaleNodeIndexBatch, TreeReader,
+};
+use crate::EncodeToObject;
+use std::collections::BTreeMap;
+
+#[test]
+fn test_root_pin() {
+    let pinned_roots = PinnedRoots::new();
+    let root = HashValue::random();
+    assert!(!pinned_roots.is_pinned(&root));
+
+    let pin = pinned_roots.pin(root);
+    assert_eq!(pin.root(), root);
+    let other_pin = pinned_roots.pin(root);
+    let cloned_pin = pin.clone();
+    assert_eq!(pinned_roots.roots(), vec![root]);
+
+    // The root stays pinned until the last of its pins is dropped.
+    drop(pin);
+    drop(other_pin);
+    assert!(pinned_roots.is_pinned(&root));
+    // The clones of the set share the pins.
+    assert!(pinned_roots.clone().is_pinned(&root));
+    drop(cloned_pin);
+    assert!(!pinned_roots.is_pinned(&root));
+    assert!(pinned_roots.roots().is_empty());
+}
+
+/// Writes 3 versions of a tree of 100 keys, the last 2 updating a third of the keys each, and
+/// returns the roots, the expected contents and the stale node indices of each version.
+fn init_versions(
+    db: &MockTestStore,
+) -> (
+    Vec<HashValue>,
+    Vec<BTreeMap<TestKey, TestValue>>,
+    Vec<StaleNodeIndexBatch>,
+) {
+    let tree = JellyfishMerkleTree::new(db);
+    let keys = (0..100).map(|_| TestKey::random()).collect::<Vec<_>>();
+    let mut root = None;
+    let mut roots = vec![];
+    let mut versions: Vec<BTreeMap<TestKey, TestValue>> = vec![];
+    let mut stale_node_indices = vec![];
+    for version in 0..3 {
+        let kvs = keys
+            .iter()
+            .step_by(if version == 0 { 1 } else { 3 })
+            .map(|key| (*key, TestValue::random()))
+            .collect::<Vec<_>>();
+        let mut expected = versions.last().cloned().unwrap_or_default();
+        expected.extend(kvs.iter().cloned());
+        let (new_root, batch) = tree
+            .put_blob_set(
+                root,
+                kvs.into_iter()
+                    .map(|(k, v)| (k.into_object(), v.into_object()))
+                    .collect(),
+            )
+            .unwrap();
+        stale_node_indices.push(batch.stale_node_index_batch.clone());
+        db.write_tree_update_batch(batch).unwrap();
+        roots.push(new_root);
+        versions.push(expected);
+        root = Some(new_root);
+    }
+    (roots, versions, stale_node_indices)
+}
+
+#[test]
+fn test_prune_unpinned() {
+    let db = MockTestStore::new_test();
+    let (roots, versions, stale_node_indices) = init_versions(&db);
+    let pinned_roots = PinnedRoots::new();
+    let stale_nodes = || stale_node_indices[1].iter().map(|index| index.node_key);
+
+    // An iterator on version 0 pins it, so pruning version 0 is a no-op.
+    let pin = pinned_roots.pin(roots[0]);
+    let mut iter = JellyfishMerkleIterator::<_, _, _>::new(&db, roots[0], None)
+        .unwrap()
+        .with_pin(pin);
+    let first = iter.next().unwrap().unwrap();
+    let num_nodes = db.num_nodes();
+    let (num_pruned, kept) = prune_unpinned(&db, &pinned_roots, stale_nodes()).unwrap();
+    assert_eq!(num_pruned, 0);
+    assert_eq!(kept.len(), stale_nodes().count());
+    assert_eq!(db.num_nodes(), num_nodes);
+
+    // The iteration goes on over the nodes which would have been pruned.
+    let mut leaves = vec![first];
+    leaves.extend(iter.by_ref().map(|result| result.unwrap()));
+    assert_eq!(
+        leaves
+            .into_iter()
+            .map(|(key, value)| (key.origin, value.origin))
+            .collect::<BTreeMap<_, _>>(),
+        versions[0]
+    );
+
+    // Once the iterator is dropped, version 0 is pruned.
+    drop(iter);
+    assert!(!pinned_roots.is_pinned(&roots[0]));
+    let (num_pruned, kept) = prune_unpinned(&db, &pinned_roots, stale_nodes()).unwrap();
+    assert_eq!(num_pruned, stale_nodes().count());
+    assert!(kept.is_empty());
+    assert!(db.get_node_option(&roots[0]).unwrap().is_none());
+
+    // A pin on a later version does not keep the dead nodes of the earlier ones.
+    let _pin = pinned_roots.pin(roots[2]);
+    let num_nodes = db.num_nodes();
+    let stale_nodes = stale_node_indices[2].iter().map(|index| index.node_key);
+    let (num_pruned, kept) = prune_unpinned(&db, &pinned_roots, stale_nodes).unwrap();
+    assert!(num_pruned > 0);
+    assert!(kept.is_empty());
+    assert_eq!(db.num_nodes(), num_nodes - num_pruned);
+    let iter = JellyfishMerkleIterator::<_, _, _>::new(&db, roots[2], None).unwrap();
+    assert_eq!(iter.count(), versions[2].len());
+}
@@ -3,14 +3,28 @@
 
 use super::hash::*;
 use super::node_type::{SparseMerkleInternalNode, SparseMerkleLeafNode};
-use crate::{Key, Value};
-use anyhow::{bail, ensure, Result};
+use crate::{Key, SMTObject, Value};
+use anyhow::{bail, ensure, format_err, Result};
 use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::marker::PhantomData;
 
 /// A proof that can be used to authenticate an element in a Sparse Merkle Tree given trusted root
 /// hash. For example, `TransactionInfoToAccountProof` can be constructed on top of this structure.
+///
+/// The proof is a binary one, with one sibling per bit of the key hash down to the leaf. An
+/// internal node of the tree, with up to 16 children at the nibbles of the key hashes, is hashed
+/// as a binary tree of 4 levels whose leaves are its children, an empty subtree being a
+/// placeholder and a subtree with a single leaf being that leaf, see
+/// [`InternalNode::get_child_with_siblings`]. Put together, these binary trees make up a binary
+/// sparse Merkle tree, where the node at bit depth `4 * n` on the path to a key hash is the
+/// internal node at nibble depth `n`, see `path_hashes`. The proof is verified as a proof of this
+/// binary tree, so a verifier of binary sparse Merkle trees which hashes the nodes the same way,
+/// and lets a leaf stand at the root of a subtree with no other leaf, accepts it as well.
+///
+/// [`InternalNode::get_child_with_siblings`]: crate::InternalNode::get_child_with_siblings
 #[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
-pub struct SparseMerkleProof {
+pub struct SparseMerkleProof<H = Sha3TreeHasher> {
     /// This proof can be used to authenticate whether a given leaf exists in the tree or not.
     ///     - If this is `Some(HashValue, HashValue)`
     ///         - If the first `HashValue` equals requested key, this is an inclusion proof and the
@@ -25,12 +39,20 @@ pub struct SparseMerkleProof {
     /// All siblings in this proof, including the default ones. Siblings are ordered from the bottom
     /// level to the root level.
     pub siblings: Vec<HashValue>,
+
+    /// The hasher the tree was built with.
+    #[serde(skip)]
+    pub hasher: PhantomData<H>,
 }
 
-impl SparseMerkleProof {
+impl<H: TreeHasher> SparseMerkleProof<H> {
     /// Constructs a new `SparseMerkleProof` using leaf and a list of siblings.
     pub fn new(leaf: Option<(HashValue, HashValue)>, siblings: Vec<HashValue>) -> Self {
-        SparseMerkleProof { leaf, siblings }
+        SparseMerkleProof {
+            leaf,
+            siblings,
+            hasher: PhantomData,
+        }
     }
 
     /// Returns the leaf node in this proof.
@@ -43,6 +65,36 @@ impl SparseMerkleProof {
         &self.siblings
     }
 
+    /// Returns the hashes of the nodes of the binary tree on the path from the leaf of this proof,
+    /// or the placeholder if it has no leaf, to the root, assuming it is a proof for
+    /// `element_key_hash`. The hash at index `i` is the one of the node at bit depth
+    /// `siblings.len() - i`, so the last one is the root hash the proof is verified against. The
+    /// hash at bit depth `4 * n` is the node key of the internal node at nibble depth `n` of the
+    /// tree, if the proof is valid.
+    pub fn path_hashes(&self, element_key_hash: HashValue) -> Vec<HashValue> {
+        let leaf_hash = self
+            .leaf
+            .map_or(H::SPARSE_MERKLE_PLACEHOLDER, |(key, value_hash)| {
+                SparseMerkleLeafNode::new(key, value_hash).merkle_hash_with::<H>()
+            });
+        let mut hashes = Vec::with_capacity(self.siblings.len() + 1);
+        hashes.push(leaf_hash);
+        for (sibling_hash, bit) in self.siblings.iter().zip(
+            element_key_hash
+                .iter_bits()
+                .rev()
+                .skip(HashValue::LENGTH_IN_BITS.saturating_sub(self.siblings.len())),
+        ) {
+            let hash = hashes[hashes.len() - 1];
+            hashes.push(if bit {
+                SparseMerkleInternalNode::new(*sibling_hash, hash).merkle_hash_with::<H>()
+            } else {
+                SparseMerkleInternalNode::new(hash, *sibling_hash).merkle_hash_with::<H>()
+            });
+        }
+        hashes
+    }
+
     /// If `element_blob` is present, verifies an element whose key is `element_key` and value is
     /// `element_blob` exists in the Sparse Merkle Tree using the provided proof. Otherwise
     /// verifies the proof is a valid non-inclusion proof that shows this key doesn't exist in the
@@ -52,6 +104,21 @@ impl SparseMerkleProof {
         expected_root_hash: HashValue,
         element_key: K,
         element_blob: Option<V>,
+    ) -> Result<()> {
+        self.verify_object(
+            expected_root_hash,
+            &element_key.into_object(),
+            element_blob.map(|blob| blob.into_object()).as_ref(),
+        )
+    }
+
+    /// Same as `verify`, but takes the key and the blob as `SMTObject`s, so the ones read from the
+    /// tree can be verified without encoding them again.
+    pub fn verify_object<K: Key, V: Value>(
+        &self,
+        expected_root_hash: HashValue,
+        element_key: &SMTObject<K>,
+        element_blob: Option<&SMTObject<V>>,
     ) -> Result<()> {
         ensure!(
             self.siblings.len() <= HashValue::LENGTH_IN_BITS,
@@ -59,8 +126,7 @@ impl SparseMerkleProof {
             HashValue::LENGTH_IN_BITS,
             self.siblings.len(),
         );
-        let element_key = element_key.into_object();
-        let element_key_hash = element_key.merkle_hash();
+        let element_key_hash = element_key.merkle_hash_with::<H>();
 
         match (element_blob, self.leaf) {
             (Some(blob), Some((proof_key, proof_value_hash))) => {
@@ -73,7 +139,7 @@ impl SparseMerkleProof {
                     proof_key,
                     element_key_hash
                 );
-                let hash = blob.into_object().merkle_hash();
+                let hash = blob.merkle_hash_with::<H>();
                 ensure!(
                     hash == proof_value_hash,
                     "Value hashes do not match. Value hash in proof: {:x}. \
@@ -108,8 +174,8 @@ impl SparseMerkleProof {
 
         let current_hash = self
             .leaf
-            .map_or(*SPARSE_MERKLE_PLACEHOLDER_HASH, |(key, value_hash)| {
-                SparseMerkleLeafNode::new(key, value_hash).merkle_hash()
+            .map_or(H::SPARSE_MERKLE_PLACEHOLDER, |(key, value_hash)| {
+                SparseMerkleLeafNode::new(key, value_hash).merkle_hash_with::<H>()
             });
 
         let actual_root_hash = self
@@ -123,9 +189,9 @@ impl SparseMerkleProof {
             )
             .fold(current_hash, |hash, (sibling_hash, bit)| {
                 if bit {
-                    SparseMerkleInternalNode::new(*sibling_hash, hash).merkle_hash()
+                    SparseMerkleInternalNode::new(*sibling_hash, hash).merkle_hash_with::<H>()
                 } else {
-                    SparseMerkleInternalNode::new(hash, *sibling_hash).merkle_hash()
+                    SparseMerkleInternalNode::new(hash, *sibling_hash).merkle_hash_with::<H>()
                 }
             });
         ensure!(
@@ -145,8 +211,8 @@ impl SparseMerkleProof {
         element_key: K,
         element_blob: V,
     ) -> Result<HashValue> {
-        let element_key_hash = element_key.into_object().merkle_hash();
-        let element_hash = element_blob.into_object().merkle_hash();
+        let element_key_hash = element_key.into_object().merkle_hash_with::<H>();
+        let element_hash = element_blob.into_object().merkle_hash_with::<H>();
         let is_non_exists_proof = match self.leaf.as_ref() {
             None => true,
             Some((leaf_key, _leaf_value)) => &element_key_hash != leaf_key,
@@ -159,18 +225,18 @@ impl SparseMerkleProof {
         );
 
         let new_leaf_node = SparseMerkleLeafNode::new(element_key_hash, element_hash);
-        let current_hash = new_leaf_node.merkle_hash();
+        let current_hash = new_leaf_node.merkle_hash_with::<H>();
         if let Some(leaf_node) = self
             .leaf
             .as_ref()
             .map(|(leaf_key, leaf_value)| SparseMerkleLeafNode::new(*leaf_key, *leaf_value))
         {
-            let mut new_siblings = vec![leaf_node.merkle_hash()];
+            let mut new_siblings = vec![leaf_node.merkle_hash_with::<H>()];
             let prefix_len = leaf_node.key_hash.common_prefix_bits_len(element_key_hash);
 
             let place_holder_len = (prefix_len - self.siblings.len()) + 1;
             if place_holder_len > 0 {
-                new_siblings.resize(place_holder_len, *SPARSE_MERKLE_PLACEHOLDER_HASH);
+                new_siblings.resize(place_holder_len, H::SPARSE_MERKLE_PLACEHOLDER);
             }
             new_siblings.extend(self.siblings.iter());
             self.siblings = new_siblings;
@@ -186,14 +252,184 @@ impl SparseMerkleProof {
             )
             .fold(current_hash, |hash, (sibling_hash, bit)| {
                 if bit {
-                    SparseMerkleInternalNode::new(*sibling_hash, hash).merkle_hash()
+                    SparseMerkleInternalNode::new(*sibling_hash, hash).merkle_hash_with::<H>()
                 } else {
-                    SparseMerkleInternalNode::new(hash, *sibling_hash).merkle_hash()
+                    SparseMerkleInternalNode::new(hash, *sibling_hash).merkle_hash_with::<H>()
                 }
             });
         self.leaf = Some((element_key_hash, element_hash));
         Ok(new_root_hash)
     }
+
+    /// Encodes the proof in a compact binary form, read back by `decode_compact`. The siblings of a
+    /// proof in a sparse tree are mostly placeholders, which are left out:
+    ///
+    /// ```text
+    /// | flags: u8 | leaf key hash, leaf value hash if any | siblings: u16 LE | bitmap | hashes |
+    /// ```
+    ///
+    /// Bit 0 of the flags is set if the proof has a leaf. Bit `i` of the bitmap, in byte `i / 8`,
+    /// is set if the sibling `i` is not the placeholder of `H`, and `hashes` are these siblings, in
+    /// the order of `siblings`.
+    pub fn encode_compact(&self) -> Vec<u8> {
+        let num_hashes = self
+            .siblings
+            .iter()
+            .filter(|sibling| **sibling != H::SPARSE_MERKLE_PLACEHOLDER)
+            .count();
+        let mut out = Vec::with_capacity(
+            1 + 2 * HashValue::LENGTH
+                + 2
+                + self.siblings.len().div_ceil(8)
+                + num_hashes * HashValue::LENGTH,
+        );
+        match self.leaf {
+            Some((key_hash, value_hash)) => {
+                out.push(COMPACT_PROOF_HAS_LEAF);
+                out.extend_from_slice(key_hash.as_ref());
+                out.extend_from_slice(value_hash.as_ref());
+            }
+            None => out.push(0),
+        }
+        out.extend_from_slice(&(self.siblings.len() as u16).to_le_bytes());
+        let mut bitmap = vec![0u8; self.siblings.len().div_ceil(8)];
+        for (i, sibling) in self.siblings.iter().enumerate() {
+            if *sibling != H::SPARSE_MERKLE_PLACEHOLDER {
+                bitmap[i / 8] |= 1 << (i % 8);
+            }
+        }
+        out.extend(bitmap);
+        for sibling in &self.siblings {
+            if *sibling != H::SPARSE_MERKLE_PLACEHOLDER {
+                out.extend_from_slice(sibling.as_ref());
+            }
+        }
+        out
+    }
+
+    /// Recovers a proof from the bytes of `encode_compact`. The placeholders left out are those of
+    /// `H`, so the proof has to be decoded with the hasher it was encoded with.
+    pub fn decode_compact(bytes: &[u8]) -> Result<Self> {
+        let mut reader = CompactProofReader { bytes };
+        let flags = reader.take(1)?[0];
+        ensure!(
+            flags & !COMPACT_PROOF_HAS_LEAF == 0,
+            "Unknown flags {:#04x} in compact proof.",
+            flags
+        );
+        let leaf = if flags & COMPACT_PROOF_HAS_LEAF != 0 {
+            Some((reader.take_hash()?, reader.take_hash()?))
+        } else {
+            None
+        };
+        let num_siblings = usize::from(u16::from_le_bytes(
+            reader.take(2)?.try_into().expect("Should be 2 bytes."),
+        ));
+        ensure!(
+            num_siblings <= HashValue::LENGTH_IN_BITS,
+            "Compact proof has more than {} ({}) siblings.",
+            HashValue::LENGTH_IN_BITS,
+            num_siblings,
+        );
+        let bitmap = reader.take(num_siblings.div_ceil(8))?;
+        ensure!(
+            num_siblings % 8 == 0 || bitmap[num_siblings / 8] >> (num_siblings % 8) == 0,
+            "Bits set past the last sibling in the bitmap of compact proof."
+        );
+        let siblings = (0..num_siblings)
+            .map(|i| {
+                if bitmap[i / 8] & (1 << (i % 8)) != 0 {
+                    reader.take_hash()
+                } else {
+                    Ok(H::SPARSE_MERKLE_PLACEHOLDER)
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+        ensure!(
+            reader.bytes.is_empty(),
+            "{} bytes left after the last sibling of compact proof.",
+            reader.bytes.len()
+        );
+        Ok(Self::new(leaf, siblings))
+    }
+}
+
+/// The flag of the compact encoding of a `SparseMerkleProof` telling that the proof has a leaf.
+const COMPACT_PROOF_HAS_LEAF: u8 = 0x01;
+
+/// Reads the compact encoding of a `SparseMerkleProof` from the front.
+struct CompactProofReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> CompactProofReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        ensure!(
+            self.bytes.len() >= len,
+            "Compact proof ends early: {} bytes expected, {} left.",
+            len,
+            self.bytes.len()
+        );
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn take_hash(&mut self) -> Result<HashValue> {
+        Ok(HashValue::from_slice(self.take(HashValue::LENGTH)?)?)
+    }
+}
+
+/// The outcome of a lookup with its proof, telling why a key is absent: either its position in the
+/// tree is empty, or another leaf stands there, the proof being a non-inclusion proof either way.
+/// Verifying the outcome checks the proof is of its kind, so a prover cannot claim one reason for
+/// the absence with a proof of the other.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ProofOutcome<V, H = Sha3TreeHasher> {
+    /// The key is in the tree with the value.
+    Present(SMTObject<V>, SparseMerkleProof<H>),
+    /// The key is absent and the subtree it would be in is empty.
+    AbsentEmpty(SparseMerkleProof<H>),
+    /// The key is absent and the leaf with the given key hash and value hash stands where it would
+    /// be.
+    AbsentOtherLeaf((HashValue, HashValue), SparseMerkleProof<H>),
+}
+
+impl<V: Value, H: TreeHasher> ProofOutcome<V, H> {
+    /// Returns the proof of the outcome.
+    pub fn proof(&self) -> &SparseMerkleProof<H> {
+        match self {
+            Self::Present(_, proof)
+            | Self::AbsentEmpty(proof)
+            | Self::AbsentOtherLeaf(_, proof) => proof,
+        }
+    }
+
+    /// Verifies the outcome for `key` against `expected_root_hash`: the proof is an inclusion
+    /// proof of the value, a non-inclusion proof ending at an empty subtree, or a non-inclusion
+    /// proof ending at the other leaf, depending on the outcome.
+    pub fn verify<K: Key>(&self, expected_root_hash: HashValue, key: &SMTObject<K>) -> Result<()> {
+        match self {
+            Self::Present(value, proof) => {
+                proof.verify_object(expected_root_hash, key, Some(value))
+            }
+            Self::AbsentEmpty(proof) => {
+                ensure!(
+                    proof.leaf().is_none(),
+                    "Expected a proof ending at an empty subtree, found one ending at a leaf."
+                );
+                proof.verify_object::<K, V>(expected_root_hash, key, None)
+            }
+            Self::AbsentOtherLeaf(other_leaf, proof) => {
+                ensure!(
+                    proof.leaf() == Some(*other_leaf),
+                    "Expected a proof ending at leaf {:x}.",
+                    other_leaf.0
+                );
+                proof.verify_object::<K, V>(expected_root_hash, key, None)
+            }
+        }
+    }
 }
 
 /// A proof that can be used authenticate a range of consecutive leaves, from the leftmost leaf to
@@ -233,3 +469,638 @@ impl SparseMerkleRangeProof {
         &self.right_siblings
     }
 }
+
+/// A proof that can be used to authenticate a list of keys in a Sparse Merkle Tree given trusted
+/// root hash, showing for each key whether it exists in the tree or not. It carries the same
+/// information as one `SparseMerkleProof` per key, but a sibling shared by the paths of several
+/// keys is only included once, and a sibling on the path of another key is not included at all.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct SparseMerkleMultiProof<H = Sha3TreeHasher> {
+    /// For each key, in the order they were requested, the leaf of its `SparseMerkleProof` and
+    /// the number of siblings of that proof, which is the depth of the leaf in bits.
+    pub leaves: Vec<(Option<(HashValue, HashValue)>, u16)>,
+
+    /// The siblings which can not be computed from the leaves, in the order of a depth first
+    /// traversal of the tree from left to right.
+    pub siblings: Vec<HashValue>,
+
+    /// The hasher the tree was built with.
+    #[serde(skip)]
+    pub hasher: PhantomData<H>,
+}
+
+/// The position of a key in a `SparseMerkleMultiProof`.
+struct MultiProofPath {
+    index: usize,
+    key_hash: HashValue,
+    leaf: Option<(HashValue, HashValue)>,
+    depth: usize,
+}
+
+impl<H: TreeHasher> SparseMerkleMultiProof<H> {
+    /// Constructs a new `SparseMerkleMultiProof` from the `SparseMerkleProof` of each key in
+    /// `key_hashes`.
+    pub fn new(key_hashes: &[HashValue], proofs: Vec<SparseMerkleProof<H>>) -> Result<Self> {
+        ensure!(
+            key_hashes.len() == proofs.len(),
+            "Got {} proofs for {} keys.",
+            proofs.len(),
+            key_hashes.len(),
+        );
+        let leaves = proofs
+            .iter()
+            .map(|proof| {
+                ensure!(
+                    proof.siblings.len() <= HashValue::LENGTH_IN_BITS,
+                    "Sparse Merkle Tree proof has more than {} ({}) siblings.",
+                    HashValue::LENGTH_IN_BITS,
+                    proof.siblings.len(),
+                );
+                Ok((proof.leaf, proof.siblings.len() as u16))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let mut siblings = vec![];
+        if !key_hashes.is_empty() {
+            let paths = Self::paths(key_hashes, &leaves);
+            Self::fold(&paths, 0, &mut |path, depth| {
+                let proof_siblings = &proofs[path.index].siblings;
+                let sibling = proof_siblings[proof_siblings.len() - 1 - depth];
+                siblings.push(sibling);
+                Ok(sibling)
+            })?;
+        }
+        Ok(Self {
+            leaves,
+            siblings,
+            hasher: PhantomData,
+        })
+    }
+
+    /// Returns the leaf and its depth in bits for each key in this proof.
+    pub fn leaves(&self) -> &[(Option<(HashValue, HashValue)>, u16)] {
+        &self.leaves
+    }
+
+    /// Returns the list of siblings in this proof.
+    pub fn siblings(&self) -> &[HashValue] {
+        &self.siblings
+    }
+
+    /// Returns the siblings of this proof for the keys with `key_hashes`, with their position in
+    /// the tree, in the form taken by [`verify_leaf_set`]. If all the keys exist in the tree, the
+    /// leaves of the keys and these siblings make up the whole tree.
+    pub fn sibling_nodes(&self, key_hashes: &[HashValue]) -> Result<Vec<SparseMerkleSibling>> {
+        ensure!(
+            key_hashes.len() == self.leaves.len(),
+            "Proof has {} leaves for {} keys.",
+            self.leaves.len(),
+            key_hashes.len(),
+        );
+        if let Some((_, depth)) = self
+            .leaves
+            .iter()
+            .find(|(_, depth)| *depth as usize > HashValue::LENGTH_IN_BITS)
+        {
+            bail!(
+                "Sparse Merkle Tree proof has more than {} ({}) siblings.",
+                HashValue::LENGTH_IN_BITS,
+                depth,
+            );
+        }
+        let mut sibling_nodes = vec![];
+        if key_hashes.is_empty() {
+            return Ok(sibling_nodes);
+        }
+
+        let paths = Self::paths(key_hashes, &self.leaves);
+        let mut siblings = self.siblings.iter();
+        Self::fold(&paths, 0, &mut |path, depth| {
+            let hash = siblings
+                .next()
+                .copied()
+                .ok_or_else(|| format_err!("Proof has too few siblings."))?;
+            sibling_nodes.push(SparseMerkleSibling {
+                key_hash: sibling_key_hash_range(path.key_hash, depth).0,
+                depth: depth as u16 + 1,
+                hash,
+            });
+            Ok(hash)
+        })?;
+        ensure!(siblings.next().is_none(), "Proof has too many siblings.");
+        Ok(sibling_nodes)
+    }
+
+    /// Verifies this proof is valid for `keys` in the Sparse Merkle Tree with root hash
+    /// `expected_root_hash`. For each key, returns the hash of its blob if the key exists in the
+    /// tree, or `None` if the proof shows it doesn't exist.
+    pub fn verify<K: Key>(
+        &self,
+        expected_root_hash: HashValue,
+        keys: Vec<K>,
+    ) -> Result<Vec<Option<HashValue>>> {
+        let keys = keys
+            .into_iter()
+            .map(|key| key.into_object())
+            .collect::<Vec<_>>();
+        self.verify_objects(expected_root_hash, &keys)
+    }
+
+    /// Same as `verify`, but takes the keys as `SMTObject`s.
+    pub fn verify_objects<K: Key>(
+        &self,
+        expected_root_hash: HashValue,
+        keys: &[SMTObject<K>],
+    ) -> Result<Vec<Option<HashValue>>> {
+        let key_hashes = keys
+            .iter()
+            .map(|key| key.merkle_hash_with::<H>())
+            .collect::<Vec<_>>();
+        self.verify_key_hashes(
+            expected_root_hash,
+            &key_hashes,
+            &mut |_path, _depth, _sibling| Ok(()),
+        )
+    }
+
+    /// Verifies this proof is valid for the keys with `key_hashes`. `check_sibling` is called for
+    /// each sibling in the proof, with the path and the depth it is the sibling of.
+    fn verify_key_hashes<F>(
+        &self,
+        expected_root_hash: HashValue,
+        key_hashes: &[HashValue],
+        check_sibling: &mut F,
+    ) -> Result<Vec<Option<HashValue>>>
+    where
+        F: FnMut(&MultiProofPath, usize, HashValue) -> Result<()>,
+    {
+        let value_hashes = self.value_hashes(key_hashes)?;
+        if key_hashes.is_empty() {
+            ensure!(
+                self.siblings.is_empty(),
+                "Proof without keys should not have siblings."
+            );
+            return Ok(value_hashes);
+        }
+
+        let paths = Self::paths(key_hashes, &self.leaves);
+        let mut siblings = self.siblings.iter();
+        let actual_root_hash = Self::fold(&paths, 0, &mut |path, depth| {
+            let sibling = siblings
+                .next()
+                .copied()
+                .ok_or_else(|| format_err!("Proof has too few siblings."))?;
+            check_sibling(path, depth, sibling)?;
+            Ok(sibling)
+        })?;
+        ensure!(siblings.next().is_none(), "Proof has too many siblings.");
+        ensure!(
+            actual_root_hash == expected_root_hash,
+            "Root hashes do not match. Actual root hash: {:x}. Expected root hash: {:x}.",
+            actual_root_hash,
+            expected_root_hash,
+        );
+
+        Ok(value_hashes)
+    }
+
+    /// Checks the leaf of each key in this proof against the key, returning the hash of the blob
+    /// of the key if it exists in the tree, or `None` if the leaf shows it doesn't exist.
+    fn value_hashes(&self, key_hashes: &[HashValue]) -> Result<Vec<Option<HashValue>>> {
+        ensure!(
+            key_hashes.len() == self.leaves.len(),
+            "Proof has {} leaves for {} keys.",
+            self.leaves.len(),
+            key_hashes.len(),
+        );
+        let mut value_hashes = Vec::with_capacity(key_hashes.len());
+        for (key_hash, (leaf, depth)) in key_hashes.iter().zip(self.leaves.iter()) {
+            let depth = *depth as usize;
+            ensure!(
+                depth <= HashValue::LENGTH_IN_BITS,
+                "Sparse Merkle Tree proof has more than {} ({}) siblings.",
+                HashValue::LENGTH_IN_BITS,
+                depth,
+            );
+            match leaf {
+                Some((proof_key, proof_value_hash)) if proof_key == key_hash => {
+                    value_hashes.push(Some(*proof_value_hash));
+                }
+                Some((proof_key, _)) => {
+                    ensure!(
+                        key_hash.common_prefix_bits_len(*proof_key) >= depth,
+                        "Key would not have ended up in the subtree where the provided key in \
+                         proof is the only existing key, if it existed. So this is not a valid \
+                         non-inclusion proof.",
+                    );
+                    value_hashes.push(None);
+                }
+                None => value_hashes.push(None),
+            }
+        }
+        Ok(value_hashes)
+    }
+
+    /// Same as `verify`, but the hashes of the subtrees are computed on the rayon thread pool.
+    /// The siblings of the proof are in the order of a depth first traversal, so the number of
+    /// siblings of the left subtree of a split, which only depends on the key hashes and the leaf
+    /// depths, tells which siblings belong to each side. Both sides then only read their own part
+    /// of the siblings and are hashed independently.
+    #[cfg(feature = "rayon")]
+    pub fn verify_par<K: Key>(
+        &self,
+        expected_root_hash: HashValue,
+        keys: Vec<K>,
+    ) -> Result<Vec<Option<HashValue>>> {
+        let key_hashes = keys
+            .into_iter()
+            .map(|key| key.into_object().merkle_hash_with::<H>())
+            .collect::<Vec<_>>();
+        let value_hashes = self.value_hashes(&key_hashes)?;
+        if key_hashes.is_empty() {
+            ensure!(
+                self.siblings.is_empty(),
+                "Proof without keys should not have siblings."
+            );
+            return Ok(value_hashes);
+        }
+
+        let paths = Self::paths(&key_hashes, &self.leaves);
+        let actual_root_hash = Self::fold_par(&paths, 0, &self.siblings)?;
+        ensure!(
+            actual_root_hash == expected_root_hash,
+            "Root hashes do not match. Actual root hash: {:x}. Expected root hash: {:x}.",
+            actual_root_hash,
+            expected_root_hash,
+        );
+        Ok(value_hashes)
+    }
+
+    /// Returns the paths of the keys, sorted by key hash.
+    fn paths(
+        key_hashes: &[HashValue],
+        leaves: &[(Option<(HashValue, HashValue)>, u16)],
+    ) -> Vec<MultiProofPath> {
+        let mut paths = key_hashes
+            .iter()
+            .zip(leaves.iter())
+            .enumerate()
+            .map(|(index, (key_hash, (leaf, depth)))| MultiProofPath {
+                index,
+                key_hash: *key_hash,
+                leaf: *leaf,
+                depth: *depth as usize,
+            })
+            .collect::<Vec<_>>();
+        paths.sort_by_key(|path| path.key_hash);
+        paths
+    }
+
+    /// Same as `fold`, with `siblings` exactly the missing siblings of the subtree, splitting the
+    /// work between the two sides of large subtrees.
+    #[cfg(feature = "rayon")]
+    fn fold_par(
+        paths: &[MultiProofPath],
+        depth: usize,
+        siblings: &[HashValue],
+    ) -> Result<HashValue> {
+        // Below this number of paths, the subtree is not worth splitting.
+        const MIN_PARALLEL_PATHS: usize = 64;
+
+        if paths.len() < MIN_PARALLEL_PATHS || paths.iter().any(|path| path.depth == depth) {
+            let mut siblings = siblings.iter();
+            let hash = Self::fold(paths, depth, &mut |_path, _depth| {
+                siblings
+                    .next()
+                    .copied()
+                    .ok_or_else(|| format_err!("Proof has too few siblings."))
+            })?;
+            ensure!(siblings.next().is_none(), "Proof has too many siblings.");
+            return Ok(hash);
+        }
+
+        let split = paths.partition_point(|path| !path.key_hash.bit(depth));
+        let (left_paths, right_paths) = paths.split_at(split);
+        let (left, right) = if left_paths.is_empty() || right_paths.is_empty() {
+            let (sibling, siblings) = if left_paths.is_empty() {
+                siblings.split_first()
+            } else {
+                siblings.split_last()
+            }
+            .ok_or_else(|| format_err!("Proof has too few siblings."))?;
+            if left_paths.is_empty() {
+                (*sibling, Self::fold_par(right_paths, depth + 1, siblings)?)
+            } else {
+                (Self::fold_par(left_paths, depth + 1, siblings)?, *sibling)
+            }
+        } else {
+            let num_left_siblings = Self::num_missing_siblings(left_paths, depth + 1);
+            ensure!(
+                num_left_siblings <= siblings.len(),
+                "Proof has too few siblings."
+            );
+            let (left_siblings, right_siblings) = siblings.split_at(num_left_siblings);
+            let (left, right) = rayon::join(
+                || Self::fold_par(left_paths, depth + 1, left_siblings),
+                || Self::fold_par(right_paths, depth + 1, right_siblings),
+            );
+            (left?, right?)
+        };
+        Ok(SparseMerkleInternalNode::new(left, right).merkle_hash_with::<H>())
+    }
+
+    /// Returns the number of times `fold` calls `missing_sibling` for `paths` at `depth`, without
+    /// computing any hash.
+    #[cfg(feature = "rayon")]
+    fn num_missing_siblings(paths: &[MultiProofPath], depth: usize) -> usize {
+        if paths.iter().any(|path| path.depth == depth) {
+            return 0;
+        }
+        let split = paths.partition_point(|path| !path.key_hash.bit(depth));
+        let (left_paths, right_paths) = paths.split_at(split);
+        let count = |paths: &[MultiProofPath]| {
+            if paths.is_empty() {
+                1
+            } else {
+                Self::num_missing_siblings(paths, depth + 1)
+            }
+        };
+        count(left_paths) + count(right_paths)
+    }
+
+    /// Computes the root hash of the subtree at `depth` that contains all the `paths`, which must
+    /// not be empty. `missing_sibling` is called for each subtree on the other side of a path
+    /// that no path goes through, with that path and the depth of the split.
+    fn fold<F>(paths: &[MultiProofPath], depth: usize, missing_sibling: &mut F) -> Result<HashValue>
+    where
+        F: FnMut(&MultiProofPath, usize) -> Result<HashValue>,
+    {
+        let first = &paths[0];
+        if paths.iter().any(|path| path.depth == depth) {
+            // All the paths end at the same leaf, or at the same empty subtree.
+            ensure!(
+                paths
+                    .iter()
+                    .all(|path| path.depth == depth && path.leaf == first.leaf),
+                "Proof has a leaf on the path of another key at depth {}.",
+                depth,
+            );
+            return Ok(first
+                .leaf
+                .map_or(H::SPARSE_MERKLE_PLACEHOLDER, |(key, value_hash)| {
+                    SparseMerkleLeafNode::new(key, value_hash).merkle_hash_with::<H>()
+                }));
+        }
+
+        let split = paths.partition_point(|path| !path.key_hash.bit(depth));
+        let (left_paths, right_paths) = paths.split_at(split);
+        let left = if left_paths.is_empty() {
+            missing_sibling(&right_paths[0], depth)?
+        } else {
+            Self::fold(left_paths, depth + 1, missing_sibling)?
+        };
+        let right = if right_paths.is_empty() {
+            missing_sibling(&left_paths[0], depth)?
+        } else {
+            Self::fold(right_paths, depth + 1, missing_sibling)?
+        };
+        Ok(SparseMerkleInternalNode::new(left, right).merkle_hash_with::<H>())
+    }
+}
+
+/// A proof that can be used to authenticate all the leaves of a Sparse Merkle Tree whose keys are
+/// between a start key and an end key, both inclusive. Besides showing that every given leaf
+/// exists in the tree, it shows that no leaf in between is omitted: none of the subtrees that are
+/// only represented by a sibling hash can hold a key between the two keys.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct SparseMerkleIntervalProof<H = Sha3TreeHasher> {
+    /// The proof of the start key, the keys of the leaves in the interval in ascending order and
+    /// the end key, in this order.
+    pub proof: SparseMerkleMultiProof<H>,
+}
+
+impl<H: TreeHasher> SparseMerkleIntervalProof<H> {
+    /// Constructs a new `SparseMerkleIntervalProof` from the proof of the start key, the keys in
+    /// the interval and the end key.
+    pub fn new(proof: SparseMerkleMultiProof<H>) -> Self {
+        Self { proof }
+    }
+
+    /// Returns the proof of all the keys.
+    pub fn proof(&self) -> &SparseMerkleMultiProof<H> {
+        &self.proof
+    }
+
+    /// Verifies `leaves` are exactly the leaves of the tree whose keys are between `start` and
+    /// `end`, both inclusive. The leaves must be sorted by the hash of their keys.
+    pub fn verify<K: Key, V: Value>(
+        &self,
+        expected_root_hash: HashValue,
+        start: K,
+        end: K,
+        leaves: Vec<(K, V)>,
+    ) -> Result<()> {
+        let leaves = leaves
+            .into_iter()
+            .map(|(key, value)| (key.into_object(), value.into_object()))
+            .collect::<Vec<_>>();
+        self.verify_objects(
+            expected_root_hash,
+            &start.into_object(),
+            &end.into_object(),
+            &leaves,
+        )
+    }
+
+    /// Same as `verify`, but takes the keys and the values as `SMTObject`s.
+    pub fn verify_objects<K: Key, V: Value>(
+        &self,
+        expected_root_hash: HashValue,
+        start: &SMTObject<K>,
+        end: &SMTObject<K>,
+        leaves: &[(SMTObject<K>, SMTObject<V>)],
+    ) -> Result<()> {
+        self.verify_key_hash_interval(
+            expected_root_hash,
+            start.merkle_hash_with::<H>(),
+            end.merkle_hash_with::<H>(),
+            leaves,
+        )
+    }
+
+    /// Same as `verify_objects`, but the interval is given by the key hashes of its ends, which
+    /// need not be the key hash of any key.
+    pub fn verify_key_hash_interval<K: Key, V: Value>(
+        &self,
+        expected_root_hash: HashValue,
+        start_hash: HashValue,
+        end_hash: HashValue,
+        leaves: &[(SMTObject<K>, SMTObject<V>)],
+    ) -> Result<()> {
+        let leaf_key_hashes = leaves
+            .iter()
+            .map(|(key, _)| key.merkle_hash_with::<H>())
+            .collect::<Vec<_>>();
+        ensure!(
+            leaf_key_hashes.windows(2).all(|pair| pair[0] < pair[1]),
+            "Leaves are not sorted by key hash."
+        );
+        if let (Some(first), Some(last)) = (leaf_key_hashes.first(), leaf_key_hashes.last()) {
+            ensure!(
+                start_hash <= *first && *last <= end_hash,
+                "Leaves are out of the interval."
+            );
+        }
+
+        let mut key_hashes = Vec::with_capacity(leaves.len() + 2);
+        key_hashes.push(start_hash);
+        key_hashes.extend(leaf_key_hashes.iter());
+        key_hashes.push(end_hash);
+        let value_hashes = self.proof.verify_key_hashes(
+            expected_root_hash,
+            &key_hashes,
+            &mut |path, depth, sibling| {
+                if sibling == H::SPARSE_MERKLE_PLACEHOLDER {
+                    return Ok(());
+                }
+                let (min_key_hash, max_key_hash) = sibling_key_hash_range(path.key_hash, depth);
+                ensure!(
+                    max_key_hash < start_hash || end_hash < min_key_hash,
+                    "Subtree of sibling {:x} may hold keys in the interval.",
+                    sibling,
+                );
+                Ok(())
+            },
+        )?;
+
+        for ((_, value), value_hash) in leaves.iter().zip(value_hashes.iter().skip(1)) {
+            ensure!(
+                *value_hash == Some(value.merkle_hash_with::<H>()),
+                "Leaf does not exist in the tree or its value hash does not match."
+            );
+        }
+        // Every leaf revealed by the proof in the interval, including the ones found on the paths
+        // of the start key and the end key, must be given.
+        for (leaf, _) in self.proof.leaves() {
+            if let Some((key_hash, _)) = leaf {
+                ensure!(
+                    *key_hash < start_hash
+                        || end_hash < *key_hash
+                        || leaf_key_hashes.binary_search(key_hash).is_ok(),
+                    "Leaf {:x} in the interval is omitted.",
+                    key_hash,
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A subtree of a Sparse Merkle Tree that is only known by its root hash, standing for the part of
+/// the tree outside the leaves given to [`verify_leaf_set`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct SparseMerkleSibling {
+    /// A key hash that starts with the path to the subtree. The bits from `depth` on are ignored.
+    pub key_hash: HashValue,
+    /// The depth of the root of the subtree in bits, the root of the tree being at depth 0.
+    pub depth: u16,
+    /// The root hash of the subtree.
+    pub hash: HashValue,
+}
+
+/// Verifies `leaves` and `sibling_nodes` make up exactly the Sparse Merkle Tree with root hash
+/// `root`, without reading the tree. The internal nodes are rebuilt bottom-up: a subtree holding a
+/// single leaf and no sibling is that leaf, a subtree holding nothing is empty, and any other
+/// subtree is an internal node whose children are split on the next bit of the key hashes. So the
+/// empty subtrees need no sibling. The leaves can be given in any order, but not twice.
+///
+/// This is the counterpart of `build_from_sorted`, and what a light client checks the leaves it
+/// was sent against, with the siblings of a `SparseMerkleMultiProof` of their keys, see
+/// [`SparseMerkleMultiProof::sibling_nodes`].
+pub fn verify_leaf_set<K: Key, V: Value, H: TreeHasher>(
+    root: HashValue,
+    leaves: &[(SMTObject<K>, SMTObject<V>)],
+    sibling_nodes: &[SparseMerkleSibling],
+) -> Result<()> {
+    let mut leaves = leaves
+        .iter()
+        .map(|(key, value)| (key.merkle_hash_with::<H>(), value.merkle_hash_with::<H>()))
+        .collect::<Vec<_>>();
+    leaves.sort_by_key(|(key_hash, _)| *key_hash);
+    ensure!(
+        leaves.windows(2).all(|pair| pair[0].0 < pair[1].0),
+        "Leaves have duplicate keys."
+    );
+    let mut siblings = sibling_nodes.to_vec();
+    if let Some(sibling) = siblings
+        .iter()
+        .find(|sibling| sibling.depth as usize > HashValue::LENGTH_IN_BITS)
+    {
+        bail!(
+            "Sibling {:x} is deeper than {} bits ({}).",
+            sibling.hash,
+            HashValue::LENGTH_IN_BITS,
+            sibling.depth,
+        );
+    }
+    // Only the bits before the depth of a sibling are compared while it is not alone in a
+    // subtree, and they order the siblings the same way as the whole key hashes.
+    siblings.sort_by_key(|sibling| sibling.key_hash);
+
+    let actual_root_hash = fold_leaf_set::<H>(&leaves, &siblings, 0)?;
+    ensure!(
+        actual_root_hash == root,
+        "Root hashes do not match. Actual root hash: {:x}. Expected root hash: {:x}.",
+        actual_root_hash,
+        root,
+    );
+    Ok(())
+}
+
+/// Helper function for `verify_leaf_set`, computing the root hash of the subtree at `depth` made up
+/// of `leaves` and `siblings`, both sorted by key hash.
+fn fold_leaf_set<H: TreeHasher>(
+    leaves: &[(HashValue, HashValue)],
+    siblings: &[SparseMerkleSibling],
+    depth: usize,
+) -> Result<HashValue> {
+    match (leaves, siblings) {
+        ([], []) => return Ok(H::SPARSE_MERKLE_PLACEHOLDER),
+        ([(key_hash, value_hash)], []) => {
+            return Ok(SparseMerkleLeafNode::new(*key_hash, *value_hash).merkle_hash_with::<H>())
+        }
+        ([], [sibling]) if sibling.depth as usize == depth => return Ok(sibling.hash),
+        _ => {}
+    }
+    if let Some(sibling) = siblings
+        .iter()
+        .find(|sibling| sibling.depth as usize <= depth)
+    {
+        bail!(
+            "Sibling {:x} at depth {} overlaps another leaf or sibling.",
+            sibling.hash,
+            sibling.depth,
+        );
+    }
+
+    // Either there is a sibling deeper than `depth`, or there are two leaves, which differ in a
+    // bit after `depth`, so `depth` is a valid bit index.
+    let leaf_split = leaves.partition_point(|(key_hash, _)| !key_hash.bit(depth));
+    let sibling_split = siblings.partition_point(|sibling| !sibling.key_hash.bit(depth));
+    let left = fold_leaf_set::<H>(&leaves[..leaf_split], &siblings[..sibling_split], depth + 1)?;
+    let right = fold_leaf_set::<H>(&leaves[leaf_split..], &siblings[sibling_split..], depth + 1)?;
+    Ok(SparseMerkleInternalNode::new(left, right).merkle_hash_with::<H>())
+}
+
+/// Returns the smallest and the largest key hash of the subtree which is the sibling at `depth`
+/// of the path to `key_hash`.
+fn sibling_key_hash_range(key_hash: HashValue, depth: usize) -> (HashValue, HashValue) {
+    let key_hash_with_fill = |fill| {
+        let bits = (0..HashValue::LENGTH_IN_BITS).map(|i| match i.cmp(&depth) {
+            Ordering::Less => key_hash.bit(i),
+            Ordering::Equal => !key_hash.bit(i),
+            Ordering::Greater => fill,
+        });
+        HashValue::from_bit_iter(bits).expect("Should have exactly the bits of a hash.")
+    };
+    (key_hash_with_fill(false), key_hash_with_fill(true))
+}
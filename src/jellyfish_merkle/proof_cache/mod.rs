@@ -0,0 +1,227 @@
+// Copyright (c) RoochNetwork
+// SPDX-License-Identifier: Apache-2.0
+
+//! This module implements [`ProofCache`], a cache of the proofs of the keys served repeatedly. A
+//! proof only verifies against the root it was generated at, so the proofs are cached by root and
+//! key hash, and the proofs of a root are evicted together once it is no longer served.
+//!
+//! [`ProofCache`]: crate::ProofCache
+
+#[cfg(test)]
+mod proof_cache_test;
+
+use super::{
+    hash::{HashValue, SMTHash, Sha3TreeHasher, TreeHasher},
+    proof::SparseMerkleProof,
+    JellyfishMerkleTree, TreeReader,
+};
+use crate::{Key, SMTObject, Value};
+use anyhow::Result;
+use parking_lot::Mutex;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// The cache key of a proof, the root it was generated at and the key hash it proves.
+type ProofKey = (HashValue, HashValue);
+
+/// A bounded cache of proofs, evicting the least recently used one when it is full.
+struct ProofLru<H> {
+    /// The maximum number of proofs in the cache.
+    capacity: usize,
+
+    /// The cached proofs along with the tick of their last use.
+    proofs: HashMap<ProofKey, (SparseMerkleProof<H>, u64)>,
+
+    /// The keys of the cached proofs ordered by the tick of their last use.
+    recency: BTreeMap<u64, ProofKey>,
+
+    /// The key hashes of the cached proofs of each root.
+    roots: HashMap<HashValue, HashSet<HashValue>>,
+
+    /// Increased on every use of the cache.
+    tick: u64,
+}
+
+impl<H: TreeHasher> ProofLru<H> {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            proofs: HashMap::new(),
+            recency: BTreeMap::new(),
+            roots: HashMap::new(),
+            tick: 0,
+        }
+    }
+
+    fn get(&mut self, proof_key: &ProofKey) -> Option<SparseMerkleProof<H>> {
+        self.tick += 1;
+        let (proof, last_used) = self.proofs.get_mut(proof_key)?;
+        self.recency.remove(last_used);
+        *last_used = self.tick;
+        self.recency.insert(self.tick, *proof_key);
+        Some(proof.clone())
+    }
+
+    fn put(&mut self, proof_key: ProofKey, proof: SparseMerkleProof<H>) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.tick += 1;
+        if let Some((_, last_used)) = self.proofs.insert(proof_key, (proof, self.tick)) {
+            self.recency.remove(&last_used);
+        }
+        self.recency.insert(self.tick, proof_key);
+        self.roots
+            .entry(proof_key.0)
+            .or_default()
+            .insert(proof_key.1);
+        while self.proofs.len() > self.capacity {
+            let (_, evicted) = self
+                .recency
+                .pop_first()
+                .expect("Recency should have an entry for every cached proof.");
+            self.remove(&evicted);
+        }
+    }
+
+    /// Removes the proof of `proof_key` from `proofs` and `roots`, leaving `recency` to the caller.
+    fn remove(&mut self, proof_key: &ProofKey) {
+        self.proofs.remove(proof_key);
+        if let Some(key_hashes) = self.roots.get_mut(&proof_key.0) {
+            key_hashes.remove(&proof_key.1);
+            if key_hashes.is_empty() {
+                self.roots.remove(&proof_key.0);
+            }
+        }
+    }
+
+    fn evict_root(&mut self, root: &HashValue) -> usize {
+        let key_hashes = match self.roots.remove(root) {
+            Some(key_hashes) => key_hashes,
+            None => return 0,
+        };
+        for key_hash in &key_hashes {
+            if let Some((_, last_used)) = self.proofs.remove(&(*root, *key_hash)) {
+                self.recency.remove(&last_used);
+            }
+        }
+        key_hashes.len()
+    }
+
+    fn len(&self) -> usize {
+        self.proofs.len()
+    }
+}
+
+/// A cache of the [`SparseMerkleProof`](crate::SparseMerkleProof)s generated by
+/// [`get_with_proof`](crate::SMTree::get_with_proof), holding up to
+/// a given number of proofs and evicting the least recently used one when it is full.
+///
+/// The proofs are cached by root and key hash, so a proof is never served for another root than
+/// the one it was generated at. When the served root advances, [`advance_root`] evicts the proofs
+/// of the older ones at once, rather than leaving them to age out of the cache.
+///
+/// [`advance_root`]: #method.advance_root
+pub struct ProofCache<H = Sha3TreeHasher> {
+    cache: Mutex<ProofLru<H>>,
+
+    /// The number of proofs served from the cache.
+    hits: AtomicU64,
+
+    /// The number of proofs which were not in the cache.
+    misses: AtomicU64,
+}
+
+impl ProofCache {
+    /// Creates a `ProofCache` caching up to `capacity` proofs.
+    pub fn new(capacity: usize) -> Self {
+        Self::new_with_hasher(capacity)
+    }
+}
+
+impl<H: TreeHasher> ProofCache<H> {
+    /// Same as `new`, but for the proofs of a tree hashed with `H`.
+    pub fn new_with_hasher(capacity: usize) -> Self {
+        Self {
+            cache: Mutex::new(ProofLru::new(capacity)),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the proof of `key` in the tree at `root`, from the cache if it is there, otherwise
+    /// generated from `reader` and cached.
+    pub fn get_proof<K, V, R>(
+        &self,
+        reader: &R,
+        root: HashValue,
+        key: SMTObject<K>,
+    ) -> Result<SparseMerkleProof<H>>
+    where
+        K: Key,
+        V: Value,
+        R: TreeReader<K, V>,
+    {
+        let key_hash = key.merkle_hash_with::<H>();
+        if let Some(proof) = self.get(root, key_hash) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(proof);
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let (_, proof) =
+            JellyfishMerkleTree::<K, V, R, H>::new_with_hasher(reader).get_with_proof(root, key)?;
+        self.cache.lock().put((root, key_hash), proof.clone());
+        Ok(proof)
+    }
+
+    /// Returns the cached proof of `key_hash` in the tree at `root`, if any.
+    pub fn get(&self, root: HashValue, key_hash: HashValue) -> Option<SparseMerkleProof<H>> {
+        self.cache.lock().get(&(root, key_hash))
+    }
+
+    /// Caches `proof` as the proof of `key_hash` in the tree at `root`. The caller is trusted to
+    /// give a proof generated at `root`.
+    pub fn insert(&self, root: HashValue, key_hash: HashValue, proof: SparseMerkleProof<H>) {
+        self.cache.lock().put((root, key_hash), proof);
+    }
+
+    /// Evicts the proofs of `root`, returning how many there were.
+    pub fn evict_root(&self, root: &HashValue) -> usize {
+        self.cache.lock().evict_root(root)
+    }
+
+    /// Evicts the proofs of every root but `new_root`, returning how many there were. To be called
+    /// when the served root advances to `new_root`.
+    pub fn advance_root(&self, new_root: HashValue) -> usize {
+        let mut cache = self.cache.lock();
+        let old_roots = cache
+            .roots
+            .keys()
+            .filter(|root| **root != new_root)
+            .copied()
+            .collect::<Vec<_>>();
+        old_roots.iter().map(|root| cache.evict_root(root)).sum()
+    }
+
+    /// Returns the number of proofs in the cache.
+    pub fn len(&self) -> usize {
+        self.cache.lock().len()
+    }
+
+    /// Whether the cache is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the number of proofs `get_proof` served from the cache.
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of proofs `get_proof` generated as they were not in the cache.
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
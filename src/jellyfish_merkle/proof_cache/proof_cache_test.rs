@@ -0,0 +1,98 @@
+// Copyright (c) RoochNetwork
+// SPDX-License-Identifier: Apache-2.0
+
+use super::*;
+use crate::jellyfish_merkle::mock_tree_store::{
+    CountingTreeReader, MockTestStore, TestKey, TestValue,
+};
+use crate::EncodeToObject;
+
+#[test]
+fn test_proof_cache() {
+    let db = MockTestStore::new_test();
+    let tree = JellyfishMerkleTree::new(&db);
+    let kvs = (0..100)
+        .map(|_| (TestKey::random(), TestValue::random()))
+        .collect::<Vec<_>>();
+    let (root1, batch) = tree
+        .put_blob_set(
+            None,
+            kvs.iter()
+                .map(|(key, value)| (key.into_object(), value.clone().into_object()))
+                .collect(),
+        )
+        .unwrap();
+    db.write_tree_update_batch(batch).unwrap();
+    // The root advances with a new value of the first key.
+    let (key, value1) = kvs[0].clone();
+    let value2 = TestValue::random();
+    let (root2, batch) = tree
+        .put_blob_set(
+            Some(root1),
+            vec![(key.into_object(), value2.clone().into_object())],
+        )
+        .unwrap();
+    db.write_tree_update_batch(batch).unwrap();
+
+    let reader = CountingTreeReader::new(db);
+    let cache = ProofCache::new(10);
+    let proof = cache.get_proof(&reader, root1, key.into_object()).unwrap();
+    proof.verify(root1, key, Some(value1.clone())).unwrap();
+    let reads = reader.reads();
+    assert!(reads > 0);
+    assert_eq!((cache.hits(), cache.misses()), (0, 1));
+
+    // A cached proof is served without reading and still verifies.
+    let cached_proof = cache.get_proof(&reader, root1, key.into_object()).unwrap();
+    assert_eq!(cached_proof, proof);
+    cached_proof.verify(root1, key, Some(value1)).unwrap();
+    assert_eq!(reader.reads(), reads);
+    assert_eq!((cache.hits(), cache.misses()), (1, 1));
+
+    // The proof at the new root is not the one of the old root.
+    let proof2 = cache.get_proof(&reader, root2, key.into_object()).unwrap();
+    assert!(reader.reads() > reads);
+    proof2.verify(root2, key, Some(value2)).unwrap();
+    assert!(proof.verify(root2, key, Some(TestValue::random())).is_err());
+    assert_eq!(cache.len(), 2);
+
+    // Advancing the root evicts the proofs of the old roots.
+    for (key, _) in &kvs[1..4] {
+        cache.get_proof(&reader, root1, key.into_object()).unwrap();
+    }
+    assert_eq!(cache.len(), 5);
+    assert_eq!(cache.advance_root(root2), 4);
+    assert_eq!(cache.len(), 1);
+    assert!(cache.get(root1, key.0).is_none());
+    assert_eq!(cache.get(root2, key.0), Some(proof2));
+    assert_eq!(cache.evict_root(&root2), 1);
+    assert!(cache.is_empty());
+}
+
+#[test]
+fn test_proof_cache_lru_eviction() {
+    let cache = ProofCache::new(2);
+    let root = HashValue::random();
+    let key_hashes = (0..3).map(|_| HashValue::random()).collect::<Vec<_>>();
+    let proofs = key_hashes
+        .iter()
+        .map(|key_hash| SparseMerkleProof::new(Some((*key_hash, HashValue::random())), vec![]))
+        .collect::<Vec<_>>();
+
+    cache.insert(root, key_hashes[0], proofs[0].clone());
+    cache.insert(root, key_hashes[1], proofs[1].clone());
+    // Use proof 0, so proof 1 is the least recently used one and evicted by proof 2.
+    assert_eq!(cache.get(root, key_hashes[0]), Some(proofs[0].clone()));
+    cache.insert(root, key_hashes[2], proofs[2].clone());
+    assert_eq!(cache.len(), 2);
+    assert!(cache.get(root, key_hashes[1]).is_none());
+    assert_eq!(cache.get(root, key_hashes[2]), Some(proofs[2].clone()));
+    // The evicted proof is no longer counted as a proof of the root.
+    assert_eq!(cache.evict_root(&root), 2);
+    assert!(cache.is_empty());
+
+    // A cache without capacity keeps nothing.
+    let cache = ProofCache::new(0);
+    cache.insert(root, key_hashes[0], proofs[0].clone());
+    assert!(cache.is_empty());
+}
@@ -0,0 +1,290 @@
+// Copyright (c) RoochNetwork
+// SPDX-License-Identifier: Apache-2.0
+
+//! This module implements the export of all the nodes of a tree to a portable snapshot, and their
+//! import into another store.
+//!
+//! The format of a snapshot is described by [`export_snapshot`].
+//!
+//! With the `zstd` feature, [`export_snapshot_compressed`] writes a snapshot compressed by zstd,
+//! after a header of its own: the bytes of `COMPRESSED_SNAPSHOT_MAGIC`, the `u8` identifying the
+//! compression algorithm and the compression level as a little endian `i32`.
+//! [`import_snapshot_compressed`] reads the algorithm from the header, and takes uncompressed
+//! snapshots as well.
+//!
+//! The module also implements [`export_ndjson`], a dump of the key-value pairs of a tree in
+//! newline delimited JSON, for offline analysis rather than for an import.
+//!
+//! [`export_snapshot`]: crate::export_snapshot
+//! [`export_ndjson`]: crate::export_ndjson
+//! [`export_snapshot_compressed`]: crate::export_snapshot_compressed
+//! [`import_snapshot_compressed`]: crate::import_snapshot_compressed
+
+#[cfg(test)]
+mod snapshot_test;
+
+use super::{
+    get_root_node,
+    hash::{HashValue, SMTHash, TreeHasher},
+    iterator::JellyfishMerkleIterator,
+    node_type::{Node, NodeKey},
+    NodeBatch, TreeReader, TreeWriter, Versioned,
+};
+use crate::{Key, SMTObject, Value};
+use anyhow::{bail, ensure, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::{
+    collections::HashSet,
+    io::{ErrorKind, Read, Write},
+};
+
+/// The bytes a snapshot starts with, the last one being the version of the format.
+const SNAPSHOT_MAGIC: [u8; 8] = *b"JMTSNAP\x01";
+
+/// The bytes a compressed snapshot starts with, the last one being the version of the header.
+#[cfg(feature = "zstd")]
+const COMPRESSED_SNAPSHOT_MAGIC: [u8; 8] = *b"JMTSNPZ\x01";
+
+/// The identifier of zstd in the header of a compressed snapshot.
+#[cfg(feature = "zstd")]
+const ZSTD_ALGORITHM: u8 = 1;
+
+/// The number of nodes imported between two writes to the store.
+const IMPORT_BATCH_SIZE: usize = 1024;
+
+/// The number of lines `export_ndjson` writes between two flushes of its output.
+const NDJSON_FLUSH_INTERVAL: u64 = 1024;
+
+/// Writes the snapshot of the tree at `root` to `out`.
+///
+/// A snapshot starts with the 8 bytes `b"JMTSNAP\x01"`, the last one being the version of the
+/// format, followed by one record per node reachable from the root: the 32 bytes of the node key,
+/// the length of the encoded node as a little endian `u32`, and the node encoded by
+/// [`Node::encode`]. The nodes are in depth first order from left to right, each internal node
+/// before its children, so the first node is the root. The snapshot of an empty tree has no
+/// record.
+pub fn export_snapshot<K, V, R, H>(reader: &R, root: HashValue, out: &mut impl Write) -> Result<()>
+where
+    R: TreeReader<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    out.write_all(&SNAPSHOT_MAGIC)?;
+    let root_node = get_root_node::<K, V, R, H>(reader, &root)?;
+    if let Node::Null = root_node {
+        return Ok(());
+    }
+
+    let mut stack = vec![(root, root_node)];
+    while let Some((node_key, node)) = stack.pop() {
+        if let Node::Internal(internal_node) = &node {
+            // Pushed in reverse so that the children are popped in nibble order.
+            for (_, child) in internal_node
+                .children()
+                .collect::<Vec<_>>()
+                .into_iter()
+                .rev()
+            {
+                stack.push((child.hash, reader.get_node(&child.hash)?));
+            }
+        }
+        let encoded = node.encode()?;
+        ensure!(
+            encoded.len() <= u32::MAX as usize,
+            "Node {:x} is too large for a snapshot.",
+            node_key
+        );
+        let node_key_bytes: &[u8; HashValue::LENGTH] = node_key.as_ref();
+        out.write_all(node_key_bytes)?;
+        out.write_u32::<LittleEndian>(encoded.len() as u32)?;
+        out.write_all(&encoded)?;
+    }
+    Ok(())
+}
+
+/// Writes the key-value pairs of the tree at `root` to `out`, one line per pair in key hash
+/// order, each line being the JSON value `encode` returns for the pair. Returns the number of
+/// lines written. `out` is flushed every 1024 lines and at the end, and the
+/// export stops at the first error of the reader, after the lines of the pairs read before it.
+pub fn export_ndjson<K, V, R, H>(
+    reader: &R,
+    root: HashValue,
+    out: &mut impl Write,
+    encode: impl Fn(&SMTObject<K>, &SMTObject<V>) -> serde_json::Value,
+) -> Result<u64>
+where
+    R: Versioned<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    let mut count = 0;
+    for item in JellyfishMerkleIterator::<K, V, R, H>::new(reader, root, None)? {
+        let (key, value) = item?;
+        serde_json::to_writer(&mut *out, &encode(&key, &value))?;
+        out.write_all(b"\n")?;
+        count += 1;
+        if count % NDJSON_FLUSH_INTERVAL == 0 {
+            out.flush()?;
+        }
+    }
+    out.flush()?;
+    Ok(count)
+}
+
+/// Reads a snapshot written by [`export_snapshot`] from `input`, writes its nodes to `writer` and
+/// returns the root hash of the tree, or the placeholder root hash of `H` for the snapshot of an
+/// empty tree.
+///
+/// Every node is checked to hash to its node key and to be a child of a node read before it, and
+/// the snapshot must hold all the children of its internal nodes, so the snapshot of a whole tree
+/// is the only one accepted. The nodes are written in batches as they are read, so the nodes of a
+/// snapshot rejected half way may have been written already; they are not reachable from any root
+/// and can be pruned. The structural invariants of the tree are not checked: the root of a
+/// snapshot from an untrusted source is to be checked by [`validate`] before it is used.
+///
+/// [`export_snapshot`]: crate::export_snapshot
+/// [`validate`]: crate::validate
+pub fn import_snapshot<K, V, W, H>(writer: &W, input: &mut impl Read) -> Result<HashValue>
+where
+    W: TreeWriter<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    let mut magic = [0; SNAPSHOT_MAGIC.len()];
+    input.read_exact(&mut magic)?;
+    ensure!(magic == SNAPSHOT_MAGIC, "Not a snapshot: {:?}.", magic);
+
+    let mut root = None;
+    // The children of the nodes read so far, which are not read yet.
+    let mut expected = HashSet::new();
+    let mut node_batch = NodeBatch::new();
+    while let Some(node_key) = read_node_key(input)? {
+        let len = input.read_u32::<LittleEndian>()?;
+        let mut encoded = vec![0; len as usize];
+        input.read_exact(&mut encoded)?;
+        let node = Node::<K, V>::decode(&encoded)?;
+        ensure!(
+            node.merkle_hash_with::<H>() == node_key,
+            "Node {:x} of the snapshot does not hash to its node key.",
+            node_key
+        );
+        match root {
+            None => root = Some(node_key),
+            Some(_) => ensure!(
+                expected.remove(&node_key),
+                "Node {:x} of the snapshot is not a child of the nodes before it.",
+                node_key
+            ),
+        }
+        match &node {
+            Node::Internal(internal_node) => {
+                expected.extend(internal_node.children().map(|(_, child)| child.hash))
+            }
+            Node::Leaf(_) => {}
+            Node::Null => bail!("Null node {:x} in the snapshot.", node_key),
+        }
+
+        node_batch.insert(node_key, node);
+        if node_batch.len() >= IMPORT_BATCH_SIZE {
+            writer.write_node_batch(&node_batch)?;
+            node_batch.clear();
+        }
+    }
+    ensure!(
+        expected.is_empty(),
+        "The snapshot misses {} nodes.",
+        expected.len()
+    );
+    if !node_batch.is_empty() {
+        writer.write_node_batch(&node_batch)?;
+    }
+    Ok(root.unwrap_or(H::SPARSE_MERKLE_PLACEHOLDER))
+}
+
+/// Writes the snapshot of the tree at `root` to `out`, compressed by zstd at `level`, see the
+/// module documentation. The levels supported by zstd range from 1 to 22, 0 being its default
+/// level and negative levels trading the ratio for speed.
+#[cfg(feature = "zstd")]
+pub fn export_snapshot_compressed<K, V, R, H>(
+    reader: &R,
+    root: HashValue,
+    level: i32,
+    out: &mut impl Write,
+) -> Result<()>
+where
+    R: TreeReader<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    ensure!(
+        zstd::compression_level_range().contains(&level),
+        "Unsupported zstd compression level {}.",
+        level
+    );
+    out.write_all(&COMPRESSED_SNAPSHOT_MAGIC)?;
+    out.write_u8(ZSTD_ALGORITHM)?;
+    out.write_i32::<LittleEndian>(level)?;
+    let mut encoder = zstd::Encoder::new(out, level)?;
+    export_snapshot::<K, V, R, H>(reader, root, &mut encoder)?;
+    encoder.finish()?;
+    Ok(())
+}
+
+/// Imports the snapshot read from `input` into `writer` like [`import_snapshot`], decompressing it
+/// with the algorithm recorded in its header if it was written by
+/// [`export_snapshot_compressed`]. An uncompressed snapshot is imported as is.
+///
+/// [`import_snapshot`]: crate::import_snapshot
+/// [`export_snapshot_compressed`]: crate::export_snapshot_compressed
+#[cfg(feature = "zstd")]
+pub fn import_snapshot_compressed<K, V, W, H>(
+    writer: &W,
+    input: &mut impl Read,
+) -> Result<HashValue>
+where
+    W: TreeWriter<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    let mut magic = [0; COMPRESSED_SNAPSHOT_MAGIC.len()];
+    input.read_exact(&mut magic)?;
+    if magic == SNAPSHOT_MAGIC {
+        return import_snapshot::<K, V, W, H>(writer, &mut (&magic[..]).chain(input));
+    }
+    ensure!(
+        magic == COMPRESSED_SNAPSHOT_MAGIC,
+        "Not a compressed snapshot: {:?}.",
+        magic
+    );
+    let algorithm = input.read_u8()?;
+    ensure!(
+        algorithm == ZSTD_ALGORITHM,
+        "Unknown snapshot compression algorithm {}.",
+        algorithm
+    );
+    // The level is not needed to decompress, it is only recorded to describe the snapshot.
+    input.read_i32::<LittleEndian>()?;
+    let mut decoder = zstd::Decoder::new(input)?;
+    import_snapshot::<K, V, W, H>(writer, &mut decoder)
+}
+
+/// Reads the node key of the next record, or returns `None` at the end of the snapshot.
+fn read_node_key(input: &mut impl Read) -> Result<Option<NodeKey>> {
+    let mut bytes = [0; HashValue::LENGTH];
+    let mut read = 0;
+    while read < bytes.len() {
+        match input.read(&mut bytes[read..]) {
+            Ok(0) if read == 0 => return Ok(None),
+            Ok(0) => bail!("The snapshot ends within a node key."),
+            Ok(n) => read += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(Some(HashValue::new(bytes)))
+}
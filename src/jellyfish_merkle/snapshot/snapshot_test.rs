@@ -0,0 +1,264 @@
+// Copyright (c) RoochNetwork
+// SPDX-License-Identifier: Apache-2.0
+
+use super::{export_ndjson, export_snapshot, import_snapshot, SNAPSHOT_MAGIC};
+#[cfg(feature = "zstd")]
+use super::{
+    export_snapshot_compressed, import_snapshot_compressed, COMPRESSED_SNAPSHOT_MAGIC,
+    ZSTD_ALGORITHM,
+};
+use crate::jellyfish_merkle::{
+    hash::{HashValue, Sha3TreeHasher, SPARSE_MERKLE_PLACEHOLDER_HASH},
+    iterator::JellyfishMerkleIterator,
+    mock_tree_store::{MockTestStore, TestKey, TestValue},
+    node_type::Node,
+    test_helper::init_random_tree,
+    JellyfishMerkleTree, TreeReader,
+};
+use crate::{EncodeToObject, SMTObject};
+use anyhow::Result;
+
+fn init_tree(db: &MockTestStore, n: usize) -> HashValue {
+    init_random_tree(db, n, 58).0
+}
+
+fn export(db: &MockTestStore, root: HashValue) -> Vec<u8> {
+    let mut snapshot = vec![];
+    export_snapshot::<TestKey, TestValue, _, Sha3TreeHasher>(db, root, &mut snapshot).unwrap();
+    snapshot
+}
+
+fn import(db: &MockTestStore, snapshot: &[u8]) -> Result<HashValue> {
+    import_snapshot::<TestKey, TestValue, _, Sha3TreeHasher>(db, &mut &snapshot[..])
+}
+
+fn leaves(db: &MockTestStore, root: HashValue) -> Vec<(SMTObject<TestKey>, SMTObject<TestValue>)> {
+    JellyfishMerkleIterator::<_, _, _, Sha3TreeHasher>::new(db, root, None)
+        .unwrap()
+        .collect::<Result<Vec<_>>>()
+        .unwrap()
+}
+
+#[test]
+fn test_snapshot_round_trip() {
+    for n in [1, 2, 3000] {
+        let db = MockTestStore::new_test();
+        let root = init_tree(&db, n);
+        let snapshot = export(&db, root);
+
+        let restored = MockTestStore::new_test();
+        assert_eq!(import(&restored, &snapshot).unwrap(), root);
+        // Exactly the nodes reachable from the root are restored.
+        assert_eq!(restored.num_nodes(), db.num_nodes());
+        let expected = leaves(&db, root);
+        assert_eq!(expected.len(), n);
+        assert_eq!(leaves(&restored, root), expected);
+        // Exporting the restored tree gives back the same snapshot.
+        assert_eq!(export(&restored, root), snapshot);
+    }
+}
+
+#[test]
+fn test_snapshot_empty_tree() {
+    let db = MockTestStore::new_test();
+    let snapshot = export(&db, *SPARSE_MERKLE_PLACEHOLDER_HASH);
+    assert_eq!(snapshot, SNAPSHOT_MAGIC.to_vec());
+    assert_eq!(
+        import(&db, &snapshot).unwrap(),
+        *SPARSE_MERKLE_PLACEHOLDER_HASH
+    );
+    assert_eq!(db.num_nodes(), 0);
+}
+
+#[test]
+fn test_snapshot_only_reachable_nodes() {
+    let db = MockTestStore::new_test();
+    let old_root = init_tree(&db, 100);
+    let tree = JellyfishMerkleTree::new(&db);
+    let (root, batch) = tree
+        .put_blob_set(
+            Some(old_root),
+            vec![(
+                TestKey::random().into_object(),
+                TestValue::random().into_object(),
+            )],
+        )
+        .unwrap();
+    db.write_tree_update_batch(batch).unwrap();
+
+    // The stale nodes of the old root are not exported.
+    let restored = MockTestStore::new_test();
+    assert_eq!(import(&restored, &export(&db, root)).unwrap(), root);
+    assert!(restored.num_nodes() < db.num_nodes());
+    assert_eq!(leaves(&restored, root), leaves(&db, root));
+}
+
+#[test]
+fn test_snapshot_rejects_invalid() {
+    let db = MockTestStore::new_test();
+    let root = init_tree(&db, 100);
+    let snapshot = export(&db, root);
+
+    // Not a snapshot.
+    let mut wrong_magic = snapshot.clone();
+    wrong_magic[0] ^= 1;
+    assert!(import(&MockTestStore::new_test(), &wrong_magic).is_err());
+
+    // A node which does not hash to its node key: the last byte is in the value of the last leaf.
+    let mut tampered = snapshot.clone();
+    *tampered.last_mut().unwrap() ^= 1;
+    assert!(import(&MockTestStore::new_test(), &tampered).is_err());
+
+    // Truncated within a record, or missing the last node.
+    assert!(import(&MockTestStore::new_test(), &snapshot[..snapshot.len() - 1]).is_err());
+    let (key, value) = leaves(&db, root).pop().unwrap();
+    let last_record = snapshot.len()
+        - (HashValue::LENGTH
+            + 4
+            + Node::<TestKey, TestValue>::new_leaf(key, value)
+                .encode()
+                .unwrap()
+                .len());
+    assert!(import(&MockTestStore::new_test(), &snapshot[..last_record]).is_err());
+    assert!(import(&MockTestStore::new_test(), &snapshot[..last_record + 10]).is_err());
+
+    // A node which is not part of the tree.
+    let other_db = MockTestStore::new_test();
+    let other_root = init_tree(&other_db, 1);
+    let mut extra = snapshot.clone();
+    extra.extend_from_slice(&export(&other_db, other_root)[SNAPSHOT_MAGIC.len()..]);
+    assert!(import(&MockTestStore::new_test(), &extra).is_err());
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn test_snapshot_compressed_round_trip() {
+    let db = MockTestStore::new_test();
+    let root = init_tree(&db, 3000);
+    let snapshot = export(&db, root);
+    let import_compressed = |db: &MockTestStore, compressed: &[u8]| {
+        import_snapshot_compressed::<TestKey, TestValue, _, Sha3TreeHasher>(
+            db,
+            &mut &compressed[..],
+        )
+    };
+
+    let header_len = COMPRESSED_SNAPSHOT_MAGIC.len() + 1 + 4;
+    for level in [-5, 0, 1, 3, 9, 19] {
+        let mut compressed = vec![];
+        export_snapshot_compressed::<TestKey, TestValue, _, Sha3TreeHasher>(
+            &db,
+            root,
+            level,
+            &mut compressed,
+        )
+        .unwrap();
+        assert_eq!(&compressed[..8], &COMPRESSED_SNAPSHOT_MAGIC);
+        assert_eq!(compressed[8], ZSTD_ALGORITHM);
+        assert_eq!(&compressed[9..header_len], &level.to_le_bytes());
+        assert!(compressed.len() < snapshot.len());
+        // The payload decompresses to the uncompressed snapshot.
+        assert_eq!(
+            zstd::decode_all(&compressed[header_len..]).unwrap(),
+            snapshot
+        );
+
+        let restored = MockTestStore::new_test();
+        assert_eq!(import_compressed(&restored, &compressed).unwrap(), root);
+        // The restored nodes are the original ones, byte for byte.
+        assert_eq!(restored.num_nodes(), db.num_nodes());
+        assert_eq!(export(&restored, root), snapshot);
+    }
+
+    // An uncompressed snapshot is imported as well.
+    let restored = MockTestStore::new_test();
+    assert_eq!(import_compressed(&restored, &snapshot).unwrap(), root);
+    assert_eq!(export(&restored, root), snapshot);
+
+    // An unsupported level, or an unknown algorithm.
+    assert!(
+        export_snapshot_compressed::<TestKey, TestValue, _, Sha3TreeHasher>(
+            &db,
+            root,
+            100,
+            &mut vec![],
+        )
+        .is_err()
+    );
+    let mut compressed = vec![];
+    export_snapshot_compressed::<TestKey, TestValue, _, Sha3TreeHasher>(
+        &db,
+        root,
+        3,
+        &mut compressed,
+    )
+    .unwrap();
+    compressed[8] = 0;
+    assert!(import_compressed(&MockTestStore::new_test(), &compressed).is_err());
+}
+
+#[test]
+fn test_export_ndjson() {
+    let db = MockTestStore::new_test();
+    let tree = JellyfishMerkleTree::new(&db);
+    let (root, batch) = tree
+        .put_blob_set(
+            None,
+            [3u8, 1, 2]
+                .into_iter()
+                .map(|i| {
+                    let mut key = [0; HashValue::LENGTH];
+                    key[0] = i << 4;
+                    (
+                        TestKey::new(key).into_object(),
+                        TestValue::from(vec![i; i.into()]).into_object(),
+                    )
+                })
+                .collect(),
+        )
+        .unwrap();
+    db.write_tree_update_batch(batch).unwrap();
+    let encode = |key: &SMTObject<TestKey>, value: &SMTObject<TestValue>| {
+        serde_json::json!({
+            "key": key.origin.0.to_hex(),
+            "value": hex::encode(&value.origin.value),
+        })
+    };
+
+    let mut out = vec![];
+    let count = export_ndjson::<_, _, _, Sha3TreeHasher>(&db, root, &mut out, encode).unwrap();
+    assert_eq!(count, 3);
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        concat!(
+            r#"{"key":"1000000000000000000000000000000000000000000000000000000000000000","value":"01"}"#,
+            "\n",
+            r#"{"key":"2000000000000000000000000000000000000000000000000000000000000000","value":"0202"}"#,
+            "\n",
+            r#"{"key":"3000000000000000000000000000000000000000000000000000000000000000","value":"030303"}"#,
+            "\n",
+        )
+    );
+
+    // An empty tree has no line.
+    let mut out = vec![];
+    let count = export_ndjson::<_, _, _, Sha3TreeHasher>(
+        &db,
+        *SPARSE_MERKLE_PLACEHOLDER_HASH,
+        &mut out,
+        encode,
+    )
+    .unwrap();
+    assert_eq!(count, 0);
+    assert!(out.is_empty());
+
+    // A read failing half way fails the export, after the lines of the leaves read before it.
+    let leaf_key = match db.get_node(&root).unwrap() {
+        Node::Internal(internal_node) => internal_node.children().last().unwrap().1.hash,
+        _ => panic!("The root should be internal."),
+    };
+    db.fail_reads(leaf_key);
+    let mut out = vec![];
+    assert!(export_ndjson::<_, _, _, Sha3TreeHasher>(&db, root, &mut out, encode).is_err());
+    assert_eq!(String::from_utf8(out).unwrap().lines().count(), 2);
+}
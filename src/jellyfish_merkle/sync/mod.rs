@@ -0,0 +1,140 @@
+// Copyright (c) RoochNetwork
+// SPDX-License-Identifier: Apache-2.0
+
+//! This module implements [`SyncApplier`], rebuilding a tree in a local store from chunks of its
+//! leaves fetched from an untrusted peer. Each chunk covers an interval of key hashes and comes
+//! with a [`SparseMerkleIntervalProof`] against the target root, so a chunk is verified before any
+//! of its leaves is written. The chunks are applied in key hash order, and the completed nodes are
+//! written as soon as a chunk is applied, so only the path to the last leaf is held in memory.
+
+#[cfg(test)]
+mod sync_test;
+
+use super::{
+    hash::{HashValue, Sha3TreeHasher, TreeHasher},
+    proof::SparseMerkleIntervalProof,
+    SortedTreeBuilder, TreeWriter,
+};
+use crate::{Key, SMTObject, Value};
+use anyhow::{bail, ensure, Result};
+
+/// The greatest key hash, which the last chunk must end with.
+const MAX_KEY_HASH: HashValue = HashValue::new([0xff; HashValue::LENGTH]);
+
+/// Applies the chunks of the tree at `target_root_hash` to `writer`, see the module
+/// documentation. The first chunk must start with `HashValue::zero()`, each following chunk must
+/// start right after the end of the previous one, and the last chunk must end with the greatest
+/// key hash.
+pub struct SyncApplier<'a, K, V, W, H = Sha3TreeHasher> {
+    writer: &'a W,
+    target_root_hash: HashValue,
+    /// The key hash the next chunk must start with, or `None` once the whole key space is covered.
+    next_start: Option<HashValue>,
+    builder: SortedTreeBuilder<K, V, H>,
+}
+
+impl<'a, K, V, W, H> SyncApplier<'a, K, V, W, H>
+where
+    K: Key,
+    V: Value,
+    W: TreeWriter<K, V>,
+    H: TreeHasher,
+{
+    /// Creates a new applier writing the tree at `target_root_hash` to `writer`.
+    pub fn new(writer: &'a W, target_root_hash: HashValue) -> Self {
+        Self {
+            writer,
+            target_root_hash,
+            next_start: Some(HashValue::zero()),
+            builder: SortedTreeBuilder::new(),
+        }
+    }
+
+    /// Returns the key hash the next chunk must start with, or `None` if all the chunks have been
+    /// applied.
+    pub fn next_start(&self) -> Option<HashValue> {
+        self.next_start
+    }
+
+    /// Verifies the chunk of `leaves` between `start_hash` and `end_hash`, both included, against
+    /// the target root and writes its nodes. Nothing is written if the chunk is rejected, so a
+    /// chunk failing to verify can be fetched again from another peer.
+    pub fn apply_chunk(
+        &mut self,
+        start_hash: HashValue,
+        end_hash: HashValue,
+        leaves: Vec<(SMTObject<K>, SMTObject<V>)>,
+        proof: &SparseMerkleIntervalProof<H>,
+    ) -> Result<()> {
+        let expected_start = match self.next_start {
+            Some(expected_start) => expected_start,
+            None => bail!("All chunks have been applied already."),
+        };
+        ensure!(
+            start_hash <= end_hash,
+            "Chunk start {:x} is after its end {:x}.",
+            start_hash,
+            end_hash
+        );
+        if start_hash < expected_start {
+            bail!(
+                "Chunk starting at {:x} overlaps the previous chunk, expected start {:x}.",
+                start_hash,
+                expected_start
+            );
+        }
+        if start_hash > expected_start {
+            bail!(
+                "Chunk starting at {:x} leaves a gap after the previous chunk, expected start {:x}.",
+                start_hash,
+                expected_start
+            );
+        }
+        proof.verify_key_hash_interval(self.target_root_hash, start_hash, end_hash, &leaves)?;
+
+        for (key, value) in leaves {
+            self.builder.push(key, value)?;
+        }
+        self.writer
+            .write_node_batch(&self.builder.take_node_batch())?;
+        self.next_start = if end_hash == MAX_KEY_HASH {
+            None
+        } else {
+            Some(successor(end_hash))
+        };
+        Ok(())
+    }
+
+    /// Writes the remaining nodes once all the chunks have been applied, returning the root hash,
+    /// which is the target root hash.
+    pub fn finish(self) -> Result<HashValue> {
+        if let Some(next_start) = self.next_start {
+            bail!(
+                "Chunks are missing from {:x} to the end of the key space.",
+                next_start
+            );
+        }
+        let (root_hash, node_batch) = self.builder.finish();
+        ensure!(
+            root_hash == self.target_root_hash,
+            "Synced root hash {:x} does not match the target root hash {:x}.",
+            root_hash,
+            self.target_root_hash
+        );
+        self.writer.write_node_batch(&node_batch)?;
+        Ok(root_hash)
+    }
+}
+
+/// Returns the key hash right after `key_hash`, which must not be the greatest one.
+fn successor(key_hash: HashValue) -> HashValue {
+    let mut bytes = key_hash.to_vec();
+    for byte in bytes.iter_mut().rev() {
+        let (next, overflow) = byte.overflowing_add(1);
+        *byte = next;
+        if !overflow {
+            break;
+        }
+    }
+    HashValue::from_slice(&bytes).expect("Length should be 32.")
+}
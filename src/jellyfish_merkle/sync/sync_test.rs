@@ -0,0 +1,150 @@
+// Copyright (c) RoochNetwork
+// SPDX-License-Identifier: Apache-2.0
+
+use super::SyncApplier;
+use crate::jellyfish_merkle::{
+    hash::{HashValue, Sha3TreeHasher, SPARSE_MERKLE_PLACEHOLDER_HASH},
+    mock_tree_store::{MockTestStore, TestKey, TestValue},
+    proof::SparseMerkleIntervalProof,
+    test_helper::{init_random_tree, minus_one, plus_one},
+    JellyfishMerkleTree,
+};
+use crate::{EncodeToObject, SMTObject};
+
+type Chunk = (
+    HashValue,
+    HashValue,
+    Vec<(SMTObject<TestKey>, SMTObject<TestValue>)>,
+    SparseMerkleIntervalProof<Sha3TreeHasher>,
+);
+
+/// Splits the key space into `num_chunks` intervals and fetches the chunk of each one.
+fn chunks(db: &MockTestStore, root: HashValue, num_chunks: u8) -> Vec<Chunk> {
+    let tree = JellyfishMerkleTree::new(db);
+    let step = 256 / num_chunks as usize;
+    (0..num_chunks as usize)
+        .map(|i| {
+            let start = match i {
+                0 => HashValue::zero(),
+                _ => {
+                    let mut start = [0; HashValue::LENGTH];
+                    start[0] = (i * step) as u8;
+                    HashValue::new(start)
+                }
+            };
+            let end = if i + 1 == num_chunks as usize {
+                HashValue::new([0xff; HashValue::LENGTH])
+            } else {
+                let mut next = [0; HashValue::LENGTH];
+                next[0] = ((i + 1) * step) as u8;
+                minus_one(HashValue::new(next))
+            };
+            let (leaves, proof) = tree.get_key_hash_interval_proof(root, start, end).unwrap();
+            (start, end, leaves, proof)
+        })
+        .collect()
+}
+
+#[test]
+fn test_sync() {
+    let source = MockTestStore::new_test();
+    let (root, kvs) = init_random_tree(&source, 300, 13);
+
+    for num_chunks in [1, 4, 16] {
+        let target = MockTestStore::new_test();
+        let mut applier = SyncApplier::<_, _, _, Sha3TreeHasher>::new(&target, root);
+        for (start, end, leaves, proof) in chunks(&source, root, num_chunks) {
+            assert_eq!(applier.next_start(), Some(start));
+            applier.apply_chunk(start, end, leaves, &proof).unwrap();
+        }
+        assert_eq!(applier.next_start(), None);
+        assert_eq!(applier.finish().unwrap(), root);
+
+        // The synced store holds the same tree.
+        assert_eq!(target.num_nodes(), source.num_nodes());
+        let tree = JellyfishMerkleTree::new(&target);
+        for (key, value) in &kvs {
+            assert_eq!(
+                tree.get(root, *key).unwrap().map(|value| value.origin),
+                Some(value.clone())
+            );
+        }
+    }
+}
+
+#[test]
+fn test_sync_empty_tree() {
+    let source = MockTestStore::new_test();
+    let root = *SPARSE_MERKLE_PLACEHOLDER_HASH;
+
+    let target = MockTestStore::new_test();
+    let mut applier = SyncApplier::<_, _, _, Sha3TreeHasher>::new(&target, root);
+    for (start, end, leaves, proof) in chunks(&source, root, 2) {
+        applier.apply_chunk(start, end, leaves, &proof).unwrap();
+    }
+    assert_eq!(applier.finish().unwrap(), root);
+    assert_eq!(target.num_nodes(), 0);
+}
+
+#[test]
+fn test_sync_rejects_discontinuous_chunks() {
+    let source = MockTestStore::new_test();
+    let (root, _) = init_random_tree(&source, 200, 13);
+    let chunks = chunks(&source, root, 4);
+    let tree = JellyfishMerkleTree::new(&source);
+
+    // The first chunk does not start at zero.
+    let target = MockTestStore::new_test();
+    let mut applier = SyncApplier::<_, _, _, Sha3TreeHasher>::new(&target, root);
+    let (_, end, _, _) = &chunks[0];
+    let start = plus_one(HashValue::zero());
+    let (leaves, proof) = tree.get_key_hash_interval_proof(root, start, *end).unwrap();
+    assert!(applier.apply_chunk(start, *end, leaves, &proof).is_err());
+
+    // A chunk leaves a gap after the previous one.
+    let (start, end, leaves, proof) = chunks[0].clone();
+    applier.apply_chunk(start, end, leaves, &proof).unwrap();
+    let (start, end, leaves, proof) = chunks[2].clone();
+    assert!(applier.apply_chunk(start, end, leaves, &proof).is_err());
+
+    // A chunk overlaps the previous one.
+    let (_, end, _, _) = &chunks[1];
+    let start = minus_one(chunks[1].0);
+    let (leaves, proof) = tree.get_key_hash_interval_proof(root, start, *end).unwrap();
+    assert!(applier.apply_chunk(start, *end, leaves, &proof).is_err());
+
+    // The rejected chunks did not change the expected start.
+    assert_eq!(applier.next_start(), Some(chunks[1].0));
+    let (start, end, leaves, proof) = chunks[1].clone();
+    applier.apply_chunk(start, end, leaves, &proof).unwrap();
+
+    // Not all the chunks have been applied.
+    assert!(applier.finish().is_err());
+}
+
+#[test]
+fn test_sync_rejects_tampered_chunk() {
+    let source = MockTestStore::new_test();
+    let (root, _) = init_random_tree(&source, 200, 13);
+    let chunks = chunks(&source, root, 4);
+
+    let target = MockTestStore::new_test();
+    let mut applier = SyncApplier::<_, _, _, Sha3TreeHasher>::new(&target, root);
+    let (start, end, mut leaves, proof) = chunks[0].clone();
+    assert!(leaves.len() > 1);
+    leaves[1].1 = TestValue::random().into_object();
+    assert!(applier.apply_chunk(start, end, leaves, &proof).is_err());
+
+    // A leaf is omitted.
+    let (start, end, mut leaves, proof) = chunks[0].clone();
+    leaves.remove(0);
+    assert!(applier.apply_chunk(start, end, leaves, &proof).is_err());
+
+    // The chunk is verified against the target root.
+    let (start, end, leaves, proof) = chunks[0].clone();
+    let mut applier = SyncApplier::<_, _, _, Sha3TreeHasher>::new(&target, HashValue::random());
+    assert!(applier.apply_chunk(start, end, leaves, &proof).is_err());
+
+    // Nothing was written.
+    assert_eq!(target.num_nodes(), 0);
+}
@@ -10,6 +10,7 @@ use super::{
     JellyfishMerkleTree,
 };
 use crate::EncodeToObject;
+use rand::{rngs::StdRng, SeedableRng};
 use std::collections::HashMap;
 
 /// Computes the key immediately after `key`.
@@ -28,6 +29,51 @@ pub fn plus_one(key: HashValue) -> HashValue {
     HashValue::from_slice(&buf).unwrap()
 }
 
+/// Computes the key immediately before `key`.
+pub fn minus_one(key: HashValue) -> HashValue {
+    assert_ne!(key, HashValue::zero());
+
+    let mut buf = key.to_vec();
+    for i in (0..HashValue::LENGTH).rev() {
+        if buf[i] == 0 {
+            buf[i] = 255;
+        } else {
+            buf[i] -= 1;
+            break;
+        }
+    }
+    HashValue::from_slice(&buf).unwrap()
+}
+
+/// Writes a tree of `n` random key-value pairs drawn from `seed` to `db`, in a single update.
+/// Returns the root of the tree and the pairs, in the order they were drawn.
+pub fn init_random_tree(
+    db: &MockTestStore,
+    n: usize,
+    seed: u8,
+) -> (HashValue, Vec<(TestKey, TestValue)>) {
+    let mut rng = StdRng::from_seed([seed; 32]);
+    let kvs = (0..n)
+        .map(|_| {
+            (
+                TestKey(HashValue::random_with_rng(&mut rng)),
+                TestValue::from(HashValue::random_with_rng(&mut rng).to_vec()),
+            )
+        })
+        .collect::<Vec<_>>();
+    let tree = JellyfishMerkleTree::new(db);
+    let (root, batch) = tree
+        .updates(
+            None,
+            kvs.iter()
+                .map(|(k, v)| (k.into_object(), Some(v.clone().into_object())))
+                .collect::<Vec<_>>(),
+        )
+        .unwrap();
+    db.write_tree_update_batch(batch).unwrap();
+    (root, kvs)
+}
+
 /// Initializes a DB with a set of key-value pairs by inserting one key at each version.
 #[allow(clippy::all)]
 pub fn init_mock_db(kvs: &HashMap<TestKey, TestValue>) -> (MockTestStore, Option<HashValue>) {
@@ -72,7 +72,7 @@
 #[cfg(test)]
 mod tree_cache_test;
 
-use super::hash::{HashValue, SPARSE_MERKLE_PLACEHOLDER_HASH};
+use super::hash::{HashValue, Sha3TreeHasher, TreeHasher};
 use super::{
     hash::SMTHash,
     node_type::{Node, NodeKey},
@@ -83,6 +83,7 @@ use anyhow::{bail, Result};
 use std::{
     collections::{hash_map::Entry, BTreeMap, BTreeSet, HashMap, HashSet},
     convert::Into,
+    marker::PhantomData,
 };
 
 /// `FrozenTreeCache` is used as a field of `TreeCache` storing all the nodes and blobs that
@@ -120,7 +121,7 @@ impl<K, V> Default for FrozenTreeCache<K, V> {
 
 /// `TreeCache` is a in-memory cache for per-transaction updates of sparse Merkle nodes and value
 /// blobs.
-pub struct TreeCache<'a, R: 'a + TreeReader<K, V>, K, V> {
+pub struct TreeCache<'a, R: 'a + TreeReader<K, V>, K, V, H = Sha3TreeHasher> {
     /// `NodeKey` of the current root node in cache.
     root_node_key: HashValue,
 
@@ -141,30 +142,30 @@ pub struct TreeCache<'a, R: 'a + TreeReader<K, V>, K, V> {
 
     /// The underlying persistent storage.
     reader: &'a R,
+
+    /// The hasher of the tree.
+    hasher: PhantomData<H>,
 }
 
-impl<'a, R, K, V> TreeCache<'a, R, K, V>
+impl<'a, R, K, V, H> TreeCache<'a, R, K, V, H>
 where
     R: 'a + TreeReader<K, V>,
     K: Key,
     V: Value,
+    H: TreeHasher,
 {
     /// Constructs a new `TreeCache` instance.
     pub fn new(reader: &'a R, state_root_hash: Option<HashValue>) -> Self {
-        let mut node_cache = HashMap::new();
-        let root_node_key = match state_root_hash {
-            None => {
-                node_cache.insert(*SPARSE_MERKLE_PLACEHOLDER_HASH, Node::new_null());
-                *SPARSE_MERKLE_PLACEHOLDER_HASH
-            }
-            Some(root) => root,
-        };
+        // The null root of an empty tree is never stored, `get_node` returns it for the
+        // placeholder hash.
+        let root_node_key = state_root_hash.unwrap_or(H::SPARSE_MERKLE_PLACEHOLDER);
         Self {
-            node_cache,
+            node_cache: HashMap::new(),
             stale_node_index_cache: HashSet::new(),
             frozen_cache: FrozenTreeCache::default(),
             root_node_key,
             reader,
+            hasher: PhantomData,
             num_stale_leaves: 0,
             num_new_leaves: 0,
         }
@@ -172,7 +173,7 @@ where
 
     /// Gets a node with given node key. If it doesn't exist in node cache, read from `reader`.
     pub fn get_node(&self, node_key: &NodeKey) -> Result<Node<K, V>> {
-        if node_key == &*SPARSE_MERKLE_PLACEHOLDER_HASH {
+        if node_key == &H::SPARSE_MERKLE_PLACEHOLDER {
             return Ok(Node::Null);
         }
         Ok(if let Some(node) = self.node_cache.get(node_key) {
@@ -220,6 +221,10 @@ where
 
     /// Deletes a node with given hash.
     pub fn delete_node(&mut self, old_node_key: &NodeKey, is_leaf: bool) {
+        // The null node of an empty tree is not stored, so it never becomes stale.
+        if *old_node_key == H::SPARSE_MERKLE_PLACEHOLDER {
+            return;
+        }
         // If node cache doesn't have this node, it means the node is in the previous version of
         // the tree on the disk.
         if self.node_cache.remove(old_node_key).is_none() {
@@ -233,13 +238,11 @@ where
         }
     }
 
-    /// Freezes all the contents in cache to be immutable and clear `node_cache`.
-    pub fn freeze(&mut self) {
-        let root_node_key = self.get_root_node_key();
-        let root_hash = self
-            .get_node(root_node_key)
-            .unwrap_or_else(|_| unreachable!("Root node with key {:?} must exist", root_node_key))
-            .merkle_hash();
+    /// Freezes all the contents in cache to be immutable and clear `node_cache`. Fails if the root
+    /// node can be neither found in the cache nor read from the underlying reader.
+    pub fn freeze(&mut self) -> Result<()> {
+        let root_node_key = *self.get_root_node_key();
+        let root_hash = self.get_node(&root_node_key)?.merkle_hash_with::<H>();
         self.frozen_cache.root_hashes.push(root_hash);
         self.frozen_cache.node_cache.extend(self.node_cache.drain());
 
@@ -258,11 +261,12 @@ where
         self.num_stale_leaves = 0;
         self.frozen_cache.num_new_leaves += self.num_new_leaves;
         self.num_new_leaves = 0;
+        Ok(())
     }
 }
 
 #[allow(clippy::from_over_into)]
-impl<'a, R, K, V> Into<(Vec<HashValue>, TreeUpdateBatch<K, V>)> for TreeCache<'a, R, K, V>
+impl<'a, R, K, V, H> Into<(Vec<HashValue>, TreeUpdateBatch<K, V>)> for TreeCache<'a, R, K, V, H>
 where
     R: 'a + TreeReader<K, V>,
 {
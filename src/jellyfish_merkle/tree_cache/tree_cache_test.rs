@@ -6,7 +6,7 @@
 
 use crate::jellyfish_merkle::mock_tree_store::{MockTestStore, TestKey, TestValue};
 
-use super::super::{node_type::Node, NodeKey};
+use super::super::{hash::SPARSE_MERKLE_PLACEHOLDER_HASH, node_type::Node, NodeKey};
 use super::*;
 
 fn random_leaf_with_key() -> (Node<TestKey, TestValue>, NodeKey) {
@@ -18,7 +18,7 @@ fn random_leaf_with_key() -> (Node<TestKey, TestValue>, NodeKey) {
 #[test]
 fn test_get_node() {
     let db = MockTestStore::new_test();
-    let cache = TreeCache::new(&db, None);
+    let cache = TreeCache::<_, _, _>::new(&db, None);
 
     let (node, node_key) = random_leaf_with_key();
     db.put_node(node_key, node.clone()).unwrap();
@@ -29,7 +29,7 @@ fn test_get_node() {
 #[test]
 fn test_root_node() {
     let db = MockTestStore::new_test();
-    let mut cache = TreeCache::new(&db, None);
+    let mut cache = TreeCache::<_, _, _>::new(&db, None);
     assert_eq!(*cache.get_root_node_key(), *SPARSE_MERKLE_PLACEHOLDER_HASH);
 
     let (node, node_key) = random_leaf_with_key();
@@ -42,7 +42,7 @@ fn test_root_node() {
 #[test]
 fn test_freeze_with_delete() {
     let db = MockTestStore::new_test();
-    let mut cache = TreeCache::new(&db, None);
+    let mut cache = TreeCache::<_, _, _>::new(&db, None);
 
     assert_eq!(*cache.get_root_node_key(), *SPARSE_MERKLE_PLACEHOLDER_HASH);
 
@@ -52,13 +52,14 @@ fn test_freeze_with_delete() {
     cache.put_node(node2_key, node2.clone()).unwrap();
     assert_eq!(cache.get_node(&node1_key).unwrap(), node1);
     assert_eq!(cache.get_node(&node2_key).unwrap(), node2);
-    cache.freeze();
+    cache.freeze().unwrap();
     assert_eq!(cache.get_node(&node1_key).unwrap(), node1);
     assert_eq!(cache.get_node(&node2_key).unwrap(), node2);
 
     cache.delete_node(&node1_key, true /* is_leaf */);
-    cache.freeze();
+    cache.freeze().unwrap();
     let (_, update_batch) = cache.into();
-    assert_eq!(update_batch.node_batch.len(), 3);
+    // The null root of the empty tree is not part of the batch.
+    assert_eq!(update_batch.node_batch.len(), 2);
     assert_eq!(update_batch.stale_node_index_batch.len(), 1);
 }
@@ -0,0 +1,107 @@
+// Copyright (c) RoochNetwork
+// SPDX-License-Identifier: Apache-2.0
+
+//! This module implements [`VersionedTree`], reading the tree as of a version, e.g. a block
+//! height, from the roots of the versions it was committed at. Every version shares the nodes of
+//! the tree it did not change, so a version is just a root hash and the reads are the usual
+//! root-keyed reads.
+
+#[cfg(test)]
+mod versioned_tree_test;
+
+use super::{
+    get_many,
+    hash::{HashValue, Sha3TreeHasher, TreeHasher},
+    iterator::JellyfishMerkleIterator,
+    TreeReader, Versioned,
+};
+use crate::{Key, SMTIterator, Value};
+use anyhow::Result;
+use std::{collections::BTreeMap, marker::PhantomData};
+
+/// The version of a tree, increasing with each commit.
+pub type Version = u64;
+
+/// The roots of the versions of a tree in `reader`, read as of a version: the tree as of a
+/// version is the one of the latest version at or before it, and the empty tree before the first
+/// version.
+pub struct VersionedTree<'a, K, V, R, H = Sha3TreeHasher> {
+    reader: &'a R,
+    roots: BTreeMap<Version, HashValue>,
+    key: PhantomData<K>,
+    value: PhantomData<V>,
+    hasher: PhantomData<H>,
+}
+
+impl<'a, K, V, R, H> VersionedTree<'a, K, V, R, H>
+where
+    R: 'a + TreeReader<K, V>,
+    K: Key,
+    V: Value,
+    H: TreeHasher,
+{
+    /// Creates a new versioned tree without any version.
+    pub fn new(reader: &'a R) -> Self {
+        Self::with_roots(reader, BTreeMap::new())
+    }
+
+    /// Creates a new versioned tree with the root of each version in `roots`.
+    pub fn with_roots(reader: &'a R, roots: BTreeMap<Version, HashValue>) -> Self {
+        Self {
+            reader,
+            roots,
+            key: PhantomData,
+            value: PhantomData,
+            hasher: PhantomData,
+        }
+    }
+
+    /// Records `root` as the root of the tree at `version`, returning the root it replaces if
+    /// there was one.
+    pub fn insert_root(&mut self, version: Version, root: HashValue) -> Option<HashValue> {
+        self.roots.insert(version, root)
+    }
+
+    /// Returns the roots of the versions, by version.
+    pub fn roots(&self) -> &BTreeMap<Version, HashValue> {
+        &self.roots
+    }
+
+    /// Returns the root of the tree as of `version`, the one of the latest version at or before
+    /// it, or `None` if `version` is before the first version.
+    pub fn root_as_of(&self, version: Version) -> Option<HashValue> {
+        self.roots
+            .range(..=version)
+            .next_back()
+            .map(|(_, root)| *root)
+    }
+
+    /// Returns the value of `key` in the tree as of `version`, or `None` if the key is absent
+    /// from it or if `version` is before the first version.
+    pub fn get_as_of(&self, key: K, version: Version) -> Result<Option<V>> {
+        let root = match self.root_as_of(version) {
+            Some(root) => root,
+            None => return Ok(None),
+        };
+        let mut values = get_many::<K, V, R, H>(self.reader, root, &[key.into_object()])?;
+        Ok(values.pop().flatten().map(|value| value.origin))
+    }
+
+    /// Returns an iterator over the tree as of `version`, starting at `starting_key`. It yields
+    /// nothing if `version` is before the first version.
+    pub fn iter_as_of(
+        &self,
+        version: Version,
+        starting_key: Option<K>,
+    ) -> Result<SMTIterator<'a, K, V, R, H>>
+    where
+        R: Versioned<K, V>,
+    {
+        let root = self
+            .root_as_of(version)
+            .unwrap_or(H::SPARSE_MERKLE_PLACEHOLDER);
+        let iter =
+            JellyfishMerkleIterator::new(self.reader, root, starting_key.map(|k| k.into_object()))?;
+        Ok(SMTIterator { iter })
+    }
+}
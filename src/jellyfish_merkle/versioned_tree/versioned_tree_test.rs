@@ -0,0 +1,82 @@
+// Copyright (c) RoochNetwork
+// SPDX-License-Identifier: Apache-2.0
+
+use super::VersionedTree;
+use crate::jellyfish_merkle::{
+    hash::{HashValue, Sha3TreeHasher},
+    mock_tree_store::{MockTestStore, TestKey, TestValue},
+    JellyfishMerkleTree,
+};
+use crate::EncodeToObject;
+use anyhow::Result;
+
+fn put(
+    db: &MockTestStore,
+    root: Option<HashValue>,
+    updates: Vec<(TestKey, Option<TestValue>)>,
+) -> HashValue {
+    let (root, batch) = JellyfishMerkleTree::new(db)
+        .updates(
+            root,
+            updates
+                .into_iter()
+                .map(|(k, v)| (k.into_object(), v.map(|v| v.into_object())))
+                .collect::<Vec<_>>(),
+        )
+        .unwrap();
+    db.write_tree_update_batch(batch).unwrap();
+    root
+}
+
+#[test]
+fn test_versioned_tree() {
+    let db = MockTestStore::new_test();
+    let key1 = TestKey::random();
+    let key2 = TestKey::random();
+    let value1 = TestValue::random();
+    let value2 = TestValue::random();
+    let value3 = TestValue::random();
+
+    // Versions 10, 20 and 30 with gaps in between.
+    let root10 = put(&db, None, vec![(key1, Some(value1.clone()))]);
+    let root20 = put(&db, Some(root10), vec![(key2, Some(value2.clone()))]);
+    let root30 = put(
+        &db,
+        Some(root20),
+        vec![(key1, Some(value3.clone())), (key2, None)],
+    );
+    let mut tree = VersionedTree::<_, _, _, Sha3TreeHasher>::new(&db);
+    assert_eq!(tree.insert_root(10, root10), None);
+    assert_eq!(tree.insert_root(30, root30), None);
+    assert_eq!(tree.insert_root(20, root20), None);
+
+    assert_eq!(tree.root_as_of(9), None);
+    assert_eq!(tree.root_as_of(10), Some(root10));
+    assert_eq!(tree.root_as_of(29), Some(root20));
+    assert_eq!(tree.root_as_of(u64::MAX), Some(root30));
+
+    let get = |key: TestKey, version| tree.get_as_of(key, version).unwrap();
+    assert_eq!(get(key1, 5), None);
+    assert_eq!(get(key1, 10), Some(value1.clone()));
+    assert_eq!(get(key2, 15), None);
+    assert_eq!(get(key1, 25), Some(value1.clone()));
+    assert_eq!(get(key2, 25), Some(value2.clone()));
+    assert_eq!(get(key1, 30), Some(value3.clone()));
+    assert_eq!(get(key2, 100), None);
+
+    let collect = |version| {
+        let mut kvs = tree
+            .iter_as_of(version, None)
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        kvs.sort_by_key(|(k, _)| *k);
+        kvs
+    };
+    assert!(collect(0).is_empty());
+    assert_eq!(collect(10), vec![(key1, value1.clone())]);
+    let mut expected = vec![(key1, value1), (key2, value2)];
+    expected.sort_by_key(|(k, _)| *k);
+    assert_eq!(collect(20), expected);
+    assert_eq!(collect(31), vec![(key1, value3)]);
+}
@@ -0,0 +1,157 @@
+// Copyright (c) RoochNetwork
+// SPDX-License-Identifier: Apache-2.0
+
+//! This module implements [`TreeView`], the tree at a given root over a given reader, for the code
+//! reading a single version of the tree without passing the reader and the root around.
+
+#[cfg(test)]
+mod view_test;
+
+use super::{
+    contains_key, get_with,
+    hash::{HashValue, Sha3TreeHasher, TreeHasher},
+    iterator::{first_key, last_key, JellyfishMerkleIterator},
+    node_type::{InternalNode, NodeKey},
+    proof::SparseMerkleProof,
+    JellyfishMerkleTree, TreeReader, Versioned,
+};
+use crate::{Key, SMTObject, Value};
+use anyhow::Result;
+use std::marker::PhantomData;
+use std::ops::Bound;
+
+/// A read-only view of the tree at `root` over `reader`. It is a pair of references, cheap to copy
+/// and pass around, and its methods forward to the free functions of the module taking the
+/// reader and the root, which remain for the callers having both at hand.
+pub struct TreeView<'a, K, V, R, H = Sha3TreeHasher> {
+    reader: &'a R,
+    root: HashValue,
+    key: PhantomData<K>,
+    value: PhantomData<V>,
+    hasher: PhantomData<H>,
+}
+
+impl<'a, K, V, R, H> Clone for TreeView<'a, K, V, R, H> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'a, K, V, R, H> Copy for TreeView<'a, K, V, R, H> {}
+
+impl<'a, K, V, R> TreeView<'a, K, V, R>
+where
+    K: Key,
+    V: Value,
+    R: TreeReader<K, V>,
+{
+    /// Creates the view of the tree at `root` over `reader`.
+    pub fn new(reader: &'a R, root: HashValue) -> Self {
+        Self::new_with_hasher(reader, root)
+    }
+}
+
+impl<'a, K, V, R, H> TreeView<'a, K, V, R, H>
+where
+    K: Key,
+    V: Value,
+    R: TreeReader<K, V>,
+    H: TreeHasher,
+{
+    /// Same as `new`, but the nodes are hashed with `H`.
+    pub fn new_with_hasher(reader: &'a R, root: HashValue) -> Self {
+        Self {
+            reader,
+            root,
+            key: PhantomData,
+            value: PhantomData,
+            hasher: PhantomData,
+        }
+    }
+
+    /// Returns the reader of the view.
+    pub fn reader(&self) -> &'a R {
+        self.reader
+    }
+
+    /// Returns the root hash of the view.
+    pub fn root(&self) -> HashValue {
+        self.root
+    }
+
+    /// Returns the value of `key`, or `None` if the key is absent.
+    pub fn get(&self, key: &SMTObject<K>) -> Result<Option<SMTObject<V>>> {
+        get_with::<K, V, R, H, _, _>(self.reader, self.root, key, SMTObject::clone)
+    }
+
+    /// Returns whether `key` is in the tree, without reading a proof or cloning the value.
+    pub fn contains_key(&self, key: &SMTObject<K>) -> Result<bool> {
+        contains_key::<K, V, R, H>(self.reader, self.root, key)
+    }
+
+    /// Returns the value of `key`, if any, with the proof of its inclusion or of its absence.
+    pub fn get_with_proof(
+        &self,
+        key: SMTObject<K>,
+    ) -> Result<(Option<SMTObject<V>>, SparseMerkleProof<H>)> {
+        JellyfishMerkleTree::<K, V, R, H>::new_with_hasher(self.reader)
+            .get_with_proof(self.root, key)
+    }
+
+    /// Returns an iterator over the key-value pairs, from `starting_key` if any, as
+    /// [`SMTree::iter`](crate::SMTree::iter).
+    pub fn iter(
+        &self,
+        starting_key: Option<SMTObject<K>>,
+    ) -> Result<JellyfishMerkleIterator<'a, K, V, R, H>>
+    where
+        R: Versioned<K, V>,
+    {
+        JellyfishMerkleIterator::new(self.reader, self.root, starting_key)
+    }
+
+    /// Returns an iterator over the key-value pairs outside of the subtrees pruned by `prune`. The
+    /// internal nodes for which `prune` returns true are skipped with their whole subtree.
+    pub fn iter_filtered(
+        &self,
+        starting_key: Option<SMTObject<K>>,
+        prune: impl Fn(&NodeKey, &InternalNode) -> bool + Send + Sync + 'static,
+    ) -> Result<JellyfishMerkleIterator<'a, K, V, R, H>>
+    where
+        R: Versioned<K, V>,
+    {
+        JellyfishMerkleIterator::new_filtered(self.reader, self.root, starting_key, prune)
+    }
+
+    /// Returns an iterator over the key-value pairs after `key`, in the order of the key hashes,
+    /// whether `key` is in the tree or not.
+    pub fn iter_after(&self, key: SMTObject<K>) -> Result<JellyfishMerkleIterator<'a, K, V, R, H>>
+    where
+        R: Versioned<K, V>,
+    {
+        JellyfishMerkleIterator::new_after(self.reader, self.root, key)
+    }
+
+    /// Returns an iterator over the key-value pairs between `start` and `end`, as
+    /// [`SMTree::range`](crate::SMTree::range).
+    pub fn iter_range(
+        &self,
+        start: Bound<SMTObject<K>>,
+        end: Bound<SMTObject<K>>,
+    ) -> Result<JellyfishMerkleIterator<'a, K, V, R, H>>
+    where
+        R: Versioned<K, V>,
+    {
+        JellyfishMerkleIterator::new_range(self.reader, self.root, start, end)
+    }
+
+    /// Returns the key with the smallest key hash, or `None` if the tree is empty.
+    pub fn first_key(&self) -> Result<Option<SMTObject<K>>> {
+        first_key::<K, V, R, H>(self.reader, self.root)
+    }
+
+    /// Returns the key with the largest key hash, or `None` if the tree is empty.
+    pub fn last_key(&self) -> Result<Option<SMTObject<K>>> {
+        last_key::<K, V, R, H>(self.reader, self.root)
+    }
+}
@@ -0,0 +1,87 @@
+// Copyright (c) RoochNetwork
+// SPDX-License-Identifier: Apache-2.0
+
+use super::TreeView;
+use crate::jellyfish_merkle::{
+    hash::{HashValue, Sha3TreeHasher, SPARSE_MERKLE_PLACEHOLDER_HASH},
+    iterator::{first_key, last_key, JellyfishMerkleIterator},
+    mock_tree_store::{MockTestStore, TestKey, TestValue},
+    test_helper::init_mock_db,
+    JellyfishMerkleTree,
+};
+use std::collections::HashMap;
+use std::ops::Bound;
+
+#[test]
+fn test_tree_view() {
+    let kvs = (0..100)
+        .map(|_| (TestKey::random(), TestValue::random()))
+        .collect::<HashMap<_, _>>();
+    let (db, root) = init_mock_db(&kvs);
+    let root = root.unwrap();
+    let view = TreeView::new(&db, root);
+    assert_eq!(view.root(), root);
+    let tree = JellyfishMerkleTree::new(&db);
+
+    for (key, value) in &kvs {
+        assert_eq!(
+            view.get(&key.into_object()).unwrap().unwrap().origin,
+            *value
+        );
+        assert!(view.contains_key(&key.into_object()).unwrap());
+        let (result, proof) = view.get_with_proof(key.into_object()).unwrap();
+        assert_eq!(result.unwrap().origin, *value);
+        assert!(proof.verify(root, *key, Some(value.clone())).is_ok());
+    }
+    let absent_key = TestKey::random();
+    assert_eq!(view.get(&absent_key.into_object()).unwrap(), None);
+    assert!(!view.contains_key(&absent_key.into_object()).unwrap());
+    assert_eq!(
+        view.get_with_proof(absent_key.into_object()).unwrap(),
+        tree.get_with_proof(root, absent_key).unwrap()
+    );
+
+    // The iterators and the edge keys are those of the free functions.
+    let expected = JellyfishMerkleIterator::<_, _, _>::new(&db, root, None)
+        .unwrap()
+        .map(|result| result.unwrap())
+        .collect::<Vec<_>>();
+    let view_copy = view;
+    assert_eq!(
+        view_copy
+            .iter(None)
+            .unwrap()
+            .map(|result| result.unwrap())
+            .collect::<Vec<_>>(),
+        expected
+    );
+    let (start, end) = (expected[10].0.clone(), expected[20].0.clone());
+    assert_eq!(
+        view.iter_range(Bound::Included(start), Bound::Excluded(end))
+            .unwrap()
+            .map(|result| result.unwrap())
+            .collect::<Vec<_>>(),
+        expected[10..20]
+    );
+    assert_eq!(
+        view.first_key().unwrap(),
+        first_key::<_, _, _, Sha3TreeHasher>(&db, root).unwrap()
+    );
+    assert_eq!(
+        view.last_key().unwrap(),
+        last_key::<_, _, _, Sha3TreeHasher>(&db, root).unwrap()
+    );
+    assert_eq!(view.last_key().unwrap().unwrap(), expected[99].0);
+
+    // An empty tree.
+    let db = MockTestStore::new_test();
+    let view = TreeView::<TestKey, TestValue, _>::new(&db, *SPARSE_MERKLE_PLACEHOLDER_HASH);
+    assert_eq!(view.first_key().unwrap(), None);
+    assert_eq!(view.last_key().unwrap(), None);
+    assert_eq!(view.iter(None).unwrap().count(), 0);
+    assert_eq!(
+        view.get(&TestKey(HashValue::random()).into_object())
+            .unwrap(),
+        None
+    );
+}
@@ -4,27 +4,83 @@
 // Copyright (c) The Starcoin Core Contributors
 // SPDX-License-Identifier: Apache-2.0
 
-use anyhow::Result;
+use anyhow::{ensure, Result};
+#[cfg(feature = "async")]
+use futures_core::Stream;
 use jellyfish_merkle::{
-    iterator::JellyfishMerkleIterator,
-    node_type::{Node, NodeKey},
-    JellyfishMerkleTree, TreeReader,
+    build_from_sorted, compute_root_after, contains_key, delete_range,
+    diff::{changed_since, diff, join},
+    from_pairs, get_many, get_with,
+    hash::SMTHash,
+    iterator::{
+        count_leaves, depth_histogram, first_key, last_key, neighbors, nth_leaf,
+        JellyfishMerkleDepthIterator, JellyfishMerkleIntoIterator, JellyfishMerkleIterator,
+        JellyfishMerkleKeyIterator, JellyfishMerkleStructureIterator,
+    },
+    update_existing_leaf, JellyfishMerkleTree,
 };
+#[cfg(feature = "async")]
+use jellyfish_merkle::{iterator::JellyfishMerkleStream, AsyncTreeReader};
 use parking_lot::RwLock;
 use std::{
     collections::{BTreeMap, HashMap},
-    marker::PhantomData, sync::Arc,
+    marker::PhantomData,
+    ops::Bound,
+    sync::Arc,
+};
+#[cfg(feature = "async")]
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
 };
 
 mod jellyfish_merkle;
+#[cfg(any(test, feature = "testing"))]
+pub mod mock;
 pub mod smt_object;
 #[cfg(test)]
 mod tests;
 mod update_set;
 
+#[cfg(feature = "blake3")]
+pub use jellyfish_merkle::hash::Blake3Hasher;
+#[cfg(feature = "sha256")]
+pub use jellyfish_merkle::hash::Sha256Hasher;
+#[cfg(feature = "sha3")]
+pub use jellyfish_merkle::hash::Sha3_256Hasher;
+#[cfg(feature = "zstd")]
+pub use jellyfish_merkle::snapshot::{export_snapshot_compressed, import_snapshot_compressed};
 pub use jellyfish_merkle::{
-    hash::{HashValue, SPARSE_MERKLE_PLACEHOLDER_HASH},
-    proof::SparseMerkleProof,
+    bloom_tree_reader::BloomTreeReader,
+    caching_tree_reader::CachingTreeReader,
+    commit, commit_verified,
+    consistency::{assert_consistent, validate, ValidationReport, Violation, ViolationKind},
+    diff::{ChangedSinceIterator, Diff, MergeJoinIterator, TreeDiff},
+    extract_subtree, get_siblings,
+    hash::{is_empty_root, HashValue, Sha3TreeHasher, TreeHasher, SPARSE_MERKLE_PLACEHOLDER_HASH},
+    iterator::{
+        integrity::IntegrityScan,
+        merge::{MergeSortedIterator, PeekableLeaf},
+        IteratorCursor, StructuralEvent,
+    },
+    nibble::Nibble,
+    nibble_path::{common_prefix_bits_len, common_prefix_nibble_len, NibblePath, NibbleSlice},
+    node_type::{Child, InternalNode, LeafNode, Node, NodeKey},
+    observer::{ObservedTreeReader, Observer},
+    partial_tree_reader::PartialTreeReader,
+    pin::{PinnedRoots, RootPin},
+    proof::{
+        verify_leaf_set, ProofOutcome, SparseMerkleIntervalProof, SparseMerkleMultiProof,
+        SparseMerkleProof, SparseMerkleSibling,
+    },
+    proof_cache::ProofCache,
+    prune,
+    snapshot::{export_ndjson, export_snapshot, import_snapshot},
+    sync::SyncApplier,
+    versioned_tree::{Version, VersionedTree},
+    view::TreeView,
+    LeafEnumerable, LeafWithSiblings, NodeBatch, PutOutcome, SmtError, StaleNodeIndex,
+    StaleNodeIndexBatch, TreeReader, TreeWriter, ValueReader, Versioned, ROOT_NIBBLE_HEIGHT,
 };
 pub use smt_object::{DecodeToObject, EncodeToObject, Key, SMTObject, Value};
 pub use update_set::UpdateSet;
@@ -33,6 +89,8 @@ pub use update_set::UpdateSet;
 pub trait NodeStore {
     fn get(&self, hash: &HashValue) -> Result<Option<Vec<u8>>>;
     fn put(&self, key: HashValue, node: Vec<u8>) -> Result<()>;
+    /// Writes the encoded nodes of an update, which should be done atomically. The nodes are
+    /// ordered by hash, not by their position in the tree.
     fn write_nodes(&self, nodes: BTreeMap<HashValue, Vec<u8>>) -> Result<()>;
 }
 
@@ -53,6 +111,43 @@ where
     }
 }
 
+// The nodes are stored under their hash, so the nodes reachable from a root never change.
+impl<K, V, NS> Versioned<K, V> for NS
+where
+    NS: NodeStore,
+    K: Key,
+    V: Value,
+{
+}
+
+/// Store the tree nodes, reading them asynchronously. Only reads are needed to iterate a tree with
+/// [`SMTStream`].
+#[cfg(feature = "async")]
+pub trait AsyncNodeStore {
+    fn get(
+        &self,
+        hash: &HashValue,
+    ) -> impl std::future::Future<Output = Result<Option<Vec<u8>>>> + Send;
+}
+
+#[cfg(feature = "async")]
+impl<K, V, NS> AsyncTreeReader<K, V> for NS
+where
+    NS: AsyncNodeStore + Sync,
+    K: Key,
+    V: Value,
+{
+    async fn get_node(&self, node_key: &NodeKey) -> Result<Node<K, V>> {
+        if node_key == &*SPARSE_MERKLE_PLACEHOLDER_HASH {
+            return Ok(Node::new_null());
+        }
+        match self.get(node_key).await? {
+            Some(v) => Node::<K, V>::decode(&v),
+            None => Err(SmtError::NodeNotFound(*node_key).into()),
+        }
+    }
+}
+
 #[derive(Default, Clone)]
 pub struct InMemoryNodeStore {
     inner: Arc<RwLock<HashMap<HashValue, Vec<u8>>>>,
@@ -82,12 +177,32 @@ impl NodeStore for InMemoryNodeStore {
     }
 }
 
-/// Sparse Merkle Tree
-pub struct SMTree<K, V, NS> {
-    node_store: NS,
+#[cfg(feature = "async")]
+impl AsyncNodeStore for InMemoryNodeStore {
+    async fn get(&self, hash: &HashValue) -> Result<Option<Vec<u8>>> {
+        NodeStore::get(self, hash)
+    }
+}
+
+/// An iterator of [`SMTree::split_iter`], over one subtree of the root.
+type SplitIterator<K, V, NS, H> = SMTIntoIterator<K, V, ObservedTreeReader<NS>, H>;
+
+/// Sparse Merkle Tree, whose nodes are hashed with `H`.
+pub struct SMTree<K, V, NS, H = Sha3TreeHasher> {
+    /// The node store, reporting its reads to the observer set by `with_observer`, if any.
+    reader: ObservedTreeReader<NS>,
     root_hash: RwLock<HashValue>,
+    /// The value of the keys absent from the tree, see `with_default_value`.
+    default_value: Option<SMTObject<V>>,
+    /// The reader of the values of the detached leaves, see `with_value_reader`.
+    value_reader: Option<Arc<dyn ValueReader<V>>>,
+    /// The roots pinned by the iterators of the tree, see `pinned_roots`.
+    pinned_roots: PinnedRoots,
+    /// Whether the internal nodes are written along with the leaf counts of their children.
+    leaf_counts: bool,
     key: PhantomData<K>,
     value: PhantomData<V>,
+    hasher: PhantomData<H>,
 }
 
 impl<K, V, NS> SMTree<K, V, NS>
@@ -98,15 +213,124 @@ where
 {
     /// Construct a new smt tree from provided `state_root_hash` with underline `node_store`
     pub fn new(node_store: NS, root_hash: Option<HashValue>) -> Self {
-        let state_root_hash = root_hash.unwrap_or(*SPARSE_MERKLE_PLACEHOLDER_HASH);
+        Self::new_with_hasher(node_store, root_hash)
+    }
+}
+
+impl<K, V, NS, H> SMTree<K, V, NS, H>
+where
+    K: Key,
+    V: Value,
+    NS: NodeStore,
+    H: TreeHasher,
+{
+    /// Same as `new`, but the nodes are hashed with `H` instead of the default
+    /// [`Sha3TreeHasher`]. A tree must always be opened with the hasher it was written with.
+    pub fn new_with_hasher(node_store: NS, root_hash: Option<HashValue>) -> Self {
+        let state_root_hash = root_hash.unwrap_or(H::SPARSE_MERKLE_PLACEHOLDER);
         SMTree {
-            node_store,
+            reader: ObservedTreeReader::new(node_store, None),
             root_hash: RwLock::new(state_root_hash),
+            default_value: None,
+            value_reader: None,
+            pinned_roots: PinnedRoots::new(),
+            leaf_counts: false,
             key: PhantomData,
             value: PhantomData,
+            hasher: PhantomData,
         }
     }
 
+    /// Builds the tree holding exactly `kvs` in `node_store`, in a single pass rather than one
+    /// update per key. The pairs must be sorted by the hash of their keys with `H`, the order in
+    /// which the iterators of the tree yield them. An out of order or duplicated key is an error.
+    pub fn from_sorted<I>(node_store: NS, kvs: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        let (root_hash, node_batch) = build_from_sorted::<K, V, H>(
+            kvs.into_iter()
+                .map(|(k, v)| (k.into_object(), v.into_object())),
+        )?;
+        Self::from_node_batch(node_store, root_hash, node_batch)
+    }
+
+    /// Builds the tree holding `kvs`, in any order, in `node_store`, like `from_sorted`. If a key
+    /// appears several times, the tree holds the value of its last pair.
+    pub fn from_pairs<I>(node_store: NS, kvs: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        let (root_hash, node_batch) = from_pairs::<K, V, H>(
+            kvs.into_iter()
+                .map(|(k, v)| (k.into_object(), v.into_object())),
+        )?;
+        Self::from_node_batch(node_store, root_hash, node_batch)
+    }
+
+    fn from_node_batch(
+        node_store: NS,
+        root_hash: HashValue,
+        node_batch: NodeBatch<K, V>,
+    ) -> Result<Self> {
+        let node_map = node_batch
+            .into_iter()
+            .map(|(nk, n)| Ok((nk, n.encode()?)))
+            .collect::<Result<BTreeMap<_, _>>>()?;
+        node_store.write_nodes(node_map)?;
+        Ok(Self::new_with_hasher(node_store, Some(root_hash)))
+    }
+
+    /// Makes `default_value` the value of the keys absent from the tree: `get` returns it for
+    /// them, and putting it deletes the key instead of storing a leaf. Only these two change, the
+    /// tree is the same as without a default value, so its root hash is the same, the proofs show
+    /// the keys with the default value as absent, and the iterators skip them.
+    pub fn with_default_value(mut self, default_value: V) -> Self {
+        self.default_value = Some(default_value.into_object());
+        self
+    }
+
+    /// Reports the reads of the tree to `observer`: the nodes read, the depths the lookups reach,
+    /// and the leaves the iterators yield along with their depths. The iterators read through an
+    /// [`ObservedTreeReader`], which their types name as their reader.
+    pub fn with_observer(mut self, observer: Arc<dyn Observer>) -> Self {
+        self.reader = ObservedTreeReader::new(self.reader.into_inner(), Some(observer));
+        self
+    }
+
+    /// Reads the values of the detached leaves the iterators yield with `value_reader`, for a node
+    /// store keeping the values apart from the nodes, see [`LeafNode::new_detached`]. The other
+    /// reads of values, such as `get`, fail on a detached leaf.
+    pub fn with_value_reader(mut self, value_reader: Arc<dyn ValueReader<V>>) -> Self {
+        self.value_reader = Some(value_reader);
+        self
+    }
+
+    /// Writes the internal nodes of the updates along with the leaf counts of their children, see
+    /// [`Node::encode_with_leaf_counts`], for the iterators to give a `size_hint` and `nth_leaf`
+    /// to skip subtrees. The nodes and the root hashes are the same as without the counts.
+    ///
+    /// The counted nodes have their own layout, which the versions of this crate predating it can
+    /// not decode, so a store must only be opted in once all its readers are upgraded. Nothing
+    /// else is needed to migrate: the two layouts can be mixed in a store, the nodes written
+    /// before keeping unknown counts until an update writes them again.
+    pub fn with_leaf_counts(mut self) -> Self {
+        self.leaf_counts = true;
+        self
+    }
+
+    /// Returns the roots pinned by the live iterators of the tree. Each iterator pins its root
+    /// before reading it, and unpins it when dropped. A pruner running alongside should keep the
+    /// stale nodes reachable from these roots, which the iterators have yet to read.
+    pub fn pinned_roots(&self) -> &PinnedRoots {
+        &self.pinned_roots
+    }
+
+    /// Returns the value of the keys absent from the tree, if any, see `with_default_value`.
+    pub fn default_value(&self) -> Option<&V> {
+        self.default_value.as_ref().map(|value| &value.origin)
+    }
+
     /// get current root hash
     pub fn root_hash(&self) -> HashValue {
         *self.root_hash.read()
@@ -118,27 +342,115 @@ where
         self.puts((key, Some(value)))
     }
 
+    /// Same as `put`, but also returns whether the key was newly inserted or its value updated, in
+    /// which case the old value is returned. The outcome comes from the leaf met on the way down,
+    /// so there is no need for a `get` beforehand. Fails if the old value is detached from its
+    /// leaf, and on the default value of the tree, whose put is a deletion rather than an insert
+    /// or an update.
+    pub fn put_with_outcome(&self, key: K, value: V) -> Result<(HashValue, PutOutcome<V>)> {
+        let value = value.into_object();
+        ensure!(
+            self.default_value.as_ref() != Some(&value),
+            "Putting the default value deletes the key, use remove instead."
+        );
+        let tree = JellyfishMerkleTree::<K, V, _, H>::new_with_hasher(&self.reader)
+            .with_observer(self.reader.observer());
+        let (new_root, change_set, outcome) =
+            tree.put_with_outcome(Some(self.root_hash()), key.into_object(), value)?;
+        self.write_update(new_root, change_set.node_batch)?;
+        Ok((new_root, outcome))
+    }
+
+    /// Replaces the value of `key`, which must already be in the tree, with `value`. Only the
+    /// hashes on the path to its leaf are recomputed, since the shape of the tree does not change.
+    /// Fails if `key` is absent, in which case `put` has to be used. Putting the default value
+    /// deletes the key, see `with_default_value`, which goes through `remove` instead.
+    pub fn update_existing(&self, key: K, value: V) -> Result<HashValue> {
+        let value = value.into_object();
+        if self.default_value.as_ref() == Some(&value) {
+            ensure!(
+                self.contains(key.clone())?,
+                "Key does not exist in the tree."
+            );
+            return self.remove(key);
+        }
+        let (new_root, node_batch) = update_existing_leaf::<K, V, _, H>(
+            &self.reader,
+            self.root_hash(),
+            &key.into_object(),
+            value,
+        )?;
+        self.write_update(new_root, node_batch)?;
+        Ok(new_root)
+    }
+
     /// Remove key_hash's data.
     /// Same as put(K,None)
     pub fn remove(&self, key: K) -> Result<HashValue> {
         self.puts((key, None))
     }
 
-    /// Get the value of the key from the tree.
+    /// Removes the keys whose hash is between `start_hash` and `end_hash`, both inclusive, in a
+    /// single rebuild of the nodes above them rather than one update per key. The keys are in the
+    /// order of their hash, as the iterators yield them.
+    pub fn delete_range(&self, start_hash: HashValue, end_hash: HashValue) -> Result<HashValue> {
+        let (new_root, node_batch, _) =
+            delete_range::<K, V, _, H>(&self.reader, self.root_hash(), start_hash, end_hash)?;
+        self.write_update(new_root, node_batch)?;
+        Ok(new_root)
+    }
+
+    /// Get the value of the key from the tree, or the default value if the key is absent.
     pub fn get(&self, key: K) -> Result<Option<V>> {
-        Ok(self.get_with_proof(key)?.0)
+        Ok(self
+            .get_with_proof(key)?
+            .0
+            .or_else(|| self.default_value().cloned()))
+    }
+
+    /// Get the values of the keys from the tree, in the same order, or the default value for the
+    /// absent keys. The nodes shared by the paths to several keys are only read once.
+    pub fn get_many(&self, keys: Vec<K>) -> Result<Vec<Option<V>>> {
+        let keys = keys
+            .into_iter()
+            .map(|k| k.into_object())
+            .collect::<Vec<_>>();
+        let values = get_many::<K, V, _, H>(&self.reader, self.root_hash(), &keys)?;
+        Ok(values
+            .into_iter()
+            .map(|value| match value {
+                Some(value) => Some(value.origin),
+                None => self.default_value().cloned(),
+            })
+            .collect())
+    }
+
+    /// Returns `f` applied to the value of the key, or `None` if the key is absent, even if the tree
+    /// has a default value. No proof is built and the value is lent to `f` rather than returned, so
+    /// it is not cloned.
+    pub fn get_with<T>(&self, key: K, f: impl FnOnce(&V) -> T) -> Result<Option<T>> {
+        get_with::<K, V, _, H, _, _>(
+            &self.reader,
+            self.root_hash(),
+            &key.into_object(),
+            |value| f(&value.origin),
+        )
     }
 
+    /// Returns whether the key is in the tree, without reading a proof or cloning the value.
     pub fn contains(&self, key: K) -> Result<bool> {
-        self.get(key).map(|result| result.is_some())
+        contains_key::<K, V, _, H>(&self.reader, self.root_hash(), &key.into_object())
     }
 
     /// Returns the value and the corresponding merkle proof.
-    /// if the value is not applicable, return None and non-inclusion proof.
-    pub fn get_with_proof(&self, key: K) -> Result<(Option<V>, SparseMerkleProof)> {
+    /// if the value is not applicable, return None and non-inclusion proof, even if the tree has
+    /// a default value.
+    pub fn get_with_proof(&self, key: K) -> Result<(Option<V>, SparseMerkleProof<H>)> {
         let cur_root_hash = self.root_hash();
 
-        let tree: JellyfishMerkleTree<K, V, NS> = JellyfishMerkleTree::new(&self.node_store);
+        let tree: JellyfishMerkleTree<K, V, _, H> =
+            JellyfishMerkleTree::new_with_hasher(&self.reader)
+                .with_observer(self.reader.observer());
         let key = key.into_object();
         let (data, proof) = tree.get_with_proof(cur_root_hash, key)?;
         match data {
@@ -147,77 +459,434 @@ where
         }
     }
 
+    /// Same as `get_with_proof`, but tells whether the key is present, absent at an empty subtree,
+    /// or absent at the leaf of another key, see [`ProofOutcome`]. Same as `get_with_proof`, a key
+    /// is only present if it was put with a value other than the default one.
+    pub fn get_with_proof_outcome(&self, key: K) -> Result<ProofOutcome<V, H>> {
+        let tree: JellyfishMerkleTree<K, V, _, H> =
+            JellyfishMerkleTree::new_with_hasher(&self.reader)
+                .with_observer(self.reader.observer());
+        tree.get_with_proof_outcome(self.root_hash(), key.into_object())
+    }
+
+    /// Returns the proof that shows whether each of the keys exists in the tree or not.
+    pub fn get_multiproof(&self, keys: Vec<K>) -> Result<SparseMerkleMultiProof<H>> {
+        let keys = keys
+            .into_iter()
+            .map(|k| k.into_object())
+            .collect::<Vec<_>>();
+        let tree: JellyfishMerkleTree<K, V, _, H> =
+            JellyfishMerkleTree::new_with_hasher(&self.reader)
+                .with_observer(self.reader.observer());
+        tree.get_multiproof(self.root_hash(), &keys)
+    }
+
+    /// Returns the key-value pairs whose keys are between `start` and `end`, both inclusive, and
+    /// the proof that shows no pair in between is omitted.
+    /// Same as `iter`, the pairs are sorted by the hash of the key.
+    #[allow(clippy::type_complexity)]
+    pub fn get_interval_proof(
+        &self,
+        start: K,
+        end: K,
+    ) -> Result<(Vec<(K, V)>, SparseMerkleIntervalProof<H>)> {
+        let tree: JellyfishMerkleTree<K, V, _, H> =
+            JellyfishMerkleTree::new_with_hasher(&self.reader)
+                .with_observer(self.reader.observer());
+        let (leaves, proof) =
+            tree.get_interval_proof(self.root_hash(), start.into_object(), end.into_object())?;
+        let leaves = leaves
+            .into_iter()
+            .map(|(k, v)| (k.origin, v.origin))
+            .collect();
+        Ok((leaves, proof))
+    }
+
+    /// Same as `get_interval_proof`, but the interval is given by the key hashes of its ends. This
+    /// is how a peer serves the chunks of the tree to a [`SyncApplier`].
+    #[allow(clippy::type_complexity)]
+    pub fn get_key_hash_interval_proof(
+        &self,
+        start_hash: HashValue,
+        end_hash: HashValue,
+    ) -> Result<(Vec<(K, V)>, SparseMerkleIntervalProof<H>)> {
+        let tree: JellyfishMerkleTree<K, V, _, H> =
+            JellyfishMerkleTree::new_with_hasher(&self.reader)
+                .with_observer(self.reader.observer());
+        let (leaves, proof) =
+            tree.get_key_hash_interval_proof(self.root_hash(), start_hash, end_hash)?;
+        let leaves = leaves
+            .into_iter()
+            .map(|(k, v)| (k.origin, v.origin))
+            .collect();
+        Ok((leaves, proof))
+    }
+
     /// Returns the iterator of the tree for scan the tree.
     /// Note: the key in the tree is sorted by the hash of the key, not origin key.
     /// So the iterator will return the key in the hash order, the starting_key is the first key to start scan.
-    pub fn iter(&self, starting_key: Option<K>) -> Result<SMTIterator<K, V, NS>> {
-        let cur_root_hash = self.root_hash();
-        let iterator = SMTIterator::new(&self.node_store, cur_root_hash, starting_key)?;
-        Ok(iterator)
+    pub fn iter(
+        &self,
+        starting_key: Option<K>,
+    ) -> Result<SMTIterator<'_, K, V, ObservedTreeReader<NS>, H>> {
+        let root_hash = self.root_hash();
+        let pin = self.pinned_roots.pin(root_hash);
+        let iter = JellyfishMerkleIterator::new(
+            &self.reader,
+            root_hash,
+            starting_key.map(|k| k.into_object()),
+        )?
+        .with_observer(self.reader.observer())
+        .with_value_reader(self.value_reader.as_deref())
+        .with_pin(pin);
+        Ok(SMTIterator { iter })
+    }
+
+    /// Returns the iterator of the tree for scan the tree in descending order.
+    /// Same as `iter`, the keys are sorted by the hash of the key, the starting_key is the last key to start scan.
+    pub fn iter_rev(
+        &self,
+        starting_key: Option<K>,
+    ) -> Result<SMTIterator<'_, K, V, ObservedTreeReader<NS>, H>> {
+        let root_hash = self.root_hash();
+        let pin = self.pinned_roots.pin(root_hash);
+        let iter = JellyfishMerkleIterator::new_rev(
+            &self.reader,
+            root_hash,
+            starting_key.map(|k| k.into_object()),
+        )?
+        .with_observer(self.reader.observer())
+        .with_value_reader(self.value_reader.as_deref())
+        .with_pin(pin);
+        Ok(SMTIterator { iter })
+    }
+
+    /// Returns the iterator of the tree for scan the keys between `start` and `end`.
+    /// Same as `iter`, the bounds are compared by the hash of the key, not origin key.
+    pub fn range(
+        &self,
+        start: Bound<K>,
+        end: Bound<K>,
+    ) -> Result<SMTIterator<'_, K, V, ObservedTreeReader<NS>, H>> {
+        let root_hash = self.root_hash();
+        let pin = self.pinned_roots.pin(root_hash);
+        let iter = JellyfishMerkleIterator::new_range(
+            &self.reader,
+            root_hash,
+            start.map(|k| k.into_object()),
+            end.map(|k| k.into_object()),
+        )?
+        .with_observer(self.reader.observer())
+        .with_value_reader(self.value_reader.as_deref())
+        .with_pin(pin);
+        Ok(SMTIterator { iter })
+    }
+
+    /// Returns the iterator of the keys whose hash starts with the nibbles of `prefix`, in the
+    /// order of the key hashes. Only the subtree of `prefix` is read.
+    pub fn iter_prefix(
+        &self,
+        prefix: NibblePath,
+    ) -> Result<SMTIterator<'_, K, V, ObservedTreeReader<NS>, H>> {
+        let root_hash = self.root_hash();
+        let pin = self.pinned_roots.pin(root_hash);
+        let iter = JellyfishMerkleIterator::new_prefix(&self.reader, root_hash, prefix)?
+            .with_observer(self.reader.observer())
+            .with_value_reader(self.value_reader.as_deref())
+            .with_pin(pin);
+        Ok(SMTIterator { iter })
+    }
+
+    /// Returns the iterator of the shard `shard` of the tree, the keys whose hash starts with the
+    /// nibble `shard`. Only the subtree of the shard is read, and the iterators of the 16 shards
+    /// together yield every key once, in the order of `iter`.
+    pub fn iter_shard(
+        &self,
+        shard: Nibble,
+    ) -> Result<SMTIterator<'_, K, V, ObservedTreeReader<NS>, H>> {
+        let root_hash = self.root_hash();
+        let pin = self.pinned_roots.pin(root_hash);
+        let iter = JellyfishMerkleIterator::new_shard(&self.reader, root_hash, shard)?
+            .with_observer(self.reader.observer())
+            .with_value_reader(self.value_reader.as_deref())
+            .with_pin(pin);
+        Ok(SMTIterator { iter })
+    }
+
+    /// Returns an iterator continuing from `cursor`, taken by [`SMTIterator::cursor`] on an
+    /// iterator of this tree. Fails if the tree has changed since, as the cursor is bound to the
+    /// root it was taken on.
+    pub fn resume(
+        &self,
+        cursor: IteratorCursor,
+    ) -> Result<SMTIterator<'_, K, V, ObservedTreeReader<NS>, H>> {
+        let root_hash = self.root_hash();
+        let pin = self.pinned_roots.pin(root_hash);
+        let iter = JellyfishMerkleIterator::resume(&self.reader, root_hash, cursor)?
+            .with_observer(self.reader.observer())
+            .with_value_reader(self.value_reader.as_deref())
+            .with_pin(pin);
+        Ok(SMTIterator { iter })
+    }
+
+    /// Returns iterators over the key-value pairs of the tree, one for each child of the root, in
+    /// the order of the key hashes, see [`SMTIntoIterator::split`]. Each one holds a clone of the
+    /// node store, so they can be driven on different threads.
+    pub fn split_iter(&self) -> Result<Vec<SplitIterator<K, V, NS, H>>>
+    where
+        NS: Clone,
+    {
+        Ok(SMTIntoIterator::new(self.reader.clone(), self.root_hash(), None)?.split())
+    }
+
+    /// Returns an iterator over the structure of the tree, yielding an event each time the depth
+    /// first traversal of the tree enters or leaves an internal node, and for each key-value pair.
+    pub fn structure(&self) -> SMTStructureIterator<'_, K, V, ObservedTreeReader<NS>, H> {
+        SMTStructureIterator::new(&self.reader, self.root_hash())
+    }
+
+    /// Returns the number of key-value pairs in the tree, without reading any of them.
+    pub fn count_leaves(&self) -> Result<u64> {
+        count_leaves::<K, V, _, H>(&self.reader, self.root_hash())
+    }
+
+    /// Returns the number of key-value pairs of the tree at each depth, the number of internal
+    /// nodes above their leaves, to tell how balanced the tree is.
+    pub fn depth_histogram(&self) -> Result<BTreeMap<usize, u64>> {
+        depth_histogram::<K, V, _, H>(&self.reader, self.root_hash())
+    }
+
+    /// Returns the smallest key of the tree in the order of the key hashes, or `None` if the tree
+    /// is empty.
+    pub fn first_key(&self) -> Result<Option<K>> {
+        Ok(first_key::<K, V, _, H>(&self.reader, self.root_hash())?.map(|k| k.origin))
+    }
+
+    /// Returns the largest key of the tree in the order of the key hashes, or `None` if the tree
+    /// is empty.
+    pub fn last_key(&self) -> Result<Option<K>> {
+        Ok(last_key::<K, V, _, H>(&self.reader, self.root_hash())?.map(|k| k.origin))
+    }
+
+    /// Returns the largest key not above `key` and the smallest key not below it in the order of
+    /// the key hashes, both being `key` if it exists.
+    pub fn neighbors(&self, key: K) -> Result<(Option<K>, Option<K>)> {
+        let (below, above) =
+            neighbors::<K, V, _, H>(&self.reader, self.root_hash(), &key.into_object())?;
+        Ok((below.map(|k| k.origin), above.map(|k| k.origin)))
+    }
+
+    /// Returns the key-value pair at `index` in the order of the key hashes, the one the iterator
+    /// would yield after skipping `index` pairs, or `None` if the tree is not that large.
+    pub fn nth_leaf(&self, index: u64) -> Result<Option<(K, V)>> {
+        let leaf = nth_leaf::<K, V, _, H>(&self.reader, self.root_hash(), index)?;
+        Ok(leaf.map(|(k, v)| (k.origin, v.origin)))
+    }
+
+    /// Returns the key-value pairs that differ between the tree at `old_root` and the tree at
+    /// `new_root`, both stored in this tree's node store. The subtrees the two trees share are
+    /// skipped without being read.
+    pub fn diff(&self, old_root: HashValue, new_root: HashValue) -> Result<TreeDiff<K, V>> {
+        diff::<K, V, _, H>(&self.reader, old_root, new_root)
+    }
+
+    /// Returns an iterator over the key-value pairs that differ between the tree at `old_root` and
+    /// the tree at `new_root`, in the order of the key hashes. Unlike `diff`, the differences are
+    /// read as they are yielded.
+    pub fn join(
+        &self,
+        old_root: HashValue,
+        new_root: HashValue,
+    ) -> MergeJoinIterator<'_, K, V, ObservedTreeReader<NS>, H> {
+        join::<K, V, _, H>(&self.reader, old_root, new_root)
+    }
+
+    /// Returns an iterator over the key-value pairs of the tree which are absent from the tree at
+    /// `base_root` or have another value there, in the order of the key hashes. The keys deleted
+    /// since `base_root` are not yielded. The subtrees the two trees share are skipped without
+    /// being read.
+    pub fn changed_since(&self, base_root: HashValue) -> impl Iterator<Item = Result<(K, V)>> + '_ {
+        changed_since::<K, V, _, H>(&self.reader, base_root, self.root_hash())
+            .map(|result| result.map(|(k, v)| (k.origin, v.origin)))
     }
 
     /// Put kv pairs into tree and generate new state_root.
     pub fn puts<I: Into<UpdateSet<K, V>>>(&self, update_set: I) -> Result<HashValue> {
+        Ok(self.updates(update_set)?.0)
+    }
+
+    /// Returns the root hash the tree would have after `puts` with `update_set`, without building
+    /// or writing any node and without moving the root, e.g. to check a proposed state root
+    /// against the updates before applying them.
+    pub fn compute_root_after<I: Into<UpdateSet<K, V>>>(&self, update_set: I) -> Result<HashValue> {
+        let update_set: UpdateSet<K, V> = update_set.into();
+        compute_root_after::<K, V, _, H>(
+            &self.reader,
+            self.root_hash(),
+            self.normalize_updates(update_set),
+        )
+    }
+
+    /// Same as `puts`, but also returns the indices of the nodes the update made stale. Once no
+    /// reader needs the old root anymore, their nodes can be deleted with [`prune`].
+    pub fn puts_with_stale_nodes<I: Into<UpdateSet<K, V>>>(
+        &self,
+        update_set: I,
+    ) -> Result<(HashValue, StaleNodeIndexBatch)> {
         self.updates(update_set)
     }
 
-    fn updates<I: Into<UpdateSet<K, V>>>(&self, updates: I) -> Result<HashValue> {
+    fn updates<I: Into<UpdateSet<K, V>>>(
+        &self,
+        updates: I,
+    ) -> Result<(HashValue, StaleNodeIndexBatch)> {
         let updates: UpdateSet<K, V> = updates.into();
         let cur_root_hash = self.root_hash();
         if updates.is_empty() {
-            return Ok(cur_root_hash);
+            return Ok((cur_root_hash, StaleNodeIndexBatch::new()));
         }
 
-        let tree = JellyfishMerkleTree::new(&self.node_store);
-        let (new_state_root, change_set) =
-            tree.updates(Some(cur_root_hash), updates.into_updates())?;
+        let updates = self.normalize_updates(updates);
+        let tree = JellyfishMerkleTree::<K, V, _, H>::new_with_hasher(&self.reader)
+            .with_observer(self.reader.observer());
+        let (new_state_root, change_set) = tree.put_batch(Some(cur_root_hash), updates)?;
+        self.write_update(new_state_root, change_set.node_batch)?;
+        Ok((new_state_root, change_set.stale_node_index_batch))
+    }
+
+    /// Returns the updates of `update_set`, putting the default value being a deletion.
+    #[allow(clippy::type_complexity)]
+    fn normalize_updates(
+        &self,
+        update_set: UpdateSet<K, V>,
+    ) -> Vec<(SMTObject<K>, Option<SMTObject<V>>)> {
+        let mut updates = update_set.into_updates();
+        if let Some(default_value) = &self.default_value {
+            for (_, value) in updates.iter_mut() {
+                if value.as_ref() == Some(default_value) {
+                    *value = None;
+                }
+            }
+        }
+        updates
+    }
 
+    /// Writes the nodes of an update, then moves the root to `new_root`.
+    fn write_update(&self, new_root: HashValue, node_batch: NodeBatch<K, V>) -> Result<()> {
         let mut node_map = BTreeMap::new();
 
-        for (nk, n) in change_set.node_batch.into_iter() {
-            node_map.insert(nk, n.encode()?);
+        for (nk, n) in node_batch.into_iter() {
+            let encoded = if self.leaf_counts {
+                n.encode_with_leaf_counts()?
+            } else {
+                n.encode()?
+            };
+            node_map.insert(nk, encoded);
         }
 
-        self.node_store.write_nodes(node_map)?;
-        //TODO handle change_set's stale_node_index
-        *self.root_hash.write() = new_state_root;
-
-        Ok(new_state_root)
+        self.reader.inner().write_nodes(node_map)?;
+        *self.root_hash.write() = new_root;
+        Ok(())
     }
 
     pub fn is_genesis(&self) -> bool {
-        self.root_hash() == *SPARSE_MERKLE_PLACEHOLDER_HASH
+        H::is_empty_root(self.root_hash())
     }
 }
 
-pub struct SMTIterator<'a, K, V, R>
+pub struct SMTIterator<'a, K, V, R, H = Sha3TreeHasher>
 where
     R: TreeReader<K, V>,
 {
-    iter: JellyfishMerkleIterator<'a, K, V, R>,
+    iter: JellyfishMerkleIterator<'a, K, V, R, H>,
+}
+
+impl<'a, K: Clone, V: Clone, R: TreeReader<K, V>, H> Clone for SMTIterator<'a, K, V, R, H> {
+    fn clone(&self) -> Self {
+        Self {
+            iter: self.iter.clone(),
+        }
+    }
 }
 
 impl<'a, K, V, R> SMTIterator<'a, K, V, R>
 where
     K: Key,
     V: Value,
-    R: TreeReader<K, V>,
+    R: Versioned<K, V>,
 {
-    pub fn new(reader: &'a R, root_hash: HashValue, starting_key: Option<K>) -> Result<Self>
-    where
-        R: TreeReader<K, V>,
-    {
+    pub fn new(reader: &'a R, root_hash: HashValue, starting_key: Option<K>) -> Result<Self> {
         let iter =
             JellyfishMerkleIterator::new(reader, root_hash, starting_key.map(|k| k.into_object()))?;
         Ok(SMTIterator { iter })
     }
+
+    pub fn new_rev(reader: &'a R, root_hash: HashValue, starting_key: Option<K>) -> Result<Self> {
+        let iter = JellyfishMerkleIterator::new_rev(
+            reader,
+            root_hash,
+            starting_key.map(|k| k.into_object()),
+        )?;
+        Ok(SMTIterator { iter })
+    }
+
+    pub fn new_range(
+        reader: &'a R,
+        root_hash: HashValue,
+        start: Bound<K>,
+        end: Bound<K>,
+    ) -> Result<Self> {
+        let iter = JellyfishMerkleIterator::new_range(
+            reader,
+            root_hash,
+            start.map(|k| k.into_object()),
+            end.map(|k| k.into_object()),
+        )?;
+        Ok(SMTIterator { iter })
+    }
 }
 
-impl<'a, K, V, R> Iterator for SMTIterator<'a, K, V, R>
+impl<'a, K, V, R, H> SMTIterator<'a, K, V, R, H>
 where
     K: Key,
     V: Value,
     R: TreeReader<K, V>,
+    H: TreeHasher,
+{
+    /// Moves the iterator so the following `next` call yields the same pair as the first `next`
+    /// call of an iterator started at `key`, keeping its end bound. The keys are compared by their
+    /// hash, as in `SMTree::iter`.
+    pub fn seek(&mut self, key: K) -> Result<()> {
+        self.iter.seek(&key.into_object())
+    }
+
+    /// Returns the position of the iterator, to continue from it later with [`SMTree::resume`].
+    pub fn cursor(&self) -> IteratorCursor {
+        self.iter.cursor()
+    }
+
+    /// Returns an iterator which only yields the keys, without cloning the values.
+    pub fn keys(self) -> SMTKeyIterator<'a, K, V, R, H> {
+        SMTKeyIterator {
+            iter: self.iter.keys(),
+        }
+    }
+
+    /// Returns an iterator which also yields the depth of each leaf, the number of internal nodes
+    /// on the path from the root to it.
+    pub fn with_depth(self) -> SMTDepthIterator<'a, K, V, R, H> {
+        SMTDepthIterator {
+            iter: self.iter.with_depth(),
+        }
+    }
+}
+
+impl<'a, K, V, R, H> Iterator for SMTIterator<'a, K, V, R, H>
+where
+    K: Key,
+    V: Value,
+    R: TreeReader<K, V>,
+    H: TreeHasher,
 {
     type Item = Result<(K, V)>;
 
@@ -227,4 +896,280 @@ where
             Err(e) => Err(e),
         })
     }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+impl<'a, K, V, R, H> DoubleEndedIterator for SMTIterator<'a, K, V, R, H>
+where
+    K: Key,
+    V: Value,
+    R: TreeReader<K, V>,
+    H: TreeHasher,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.iter.next_back().map(|result| match result {
+            Ok((k, v)) => Ok((k.origin, v.origin)),
+            Err(e) => Err(e),
+        })
+    }
+}
+
+/// An iterator over the key-value pairs of a tree owning its reader, which it drops once the
+/// iteration is over. Unlike [`SMTIterator`], it can be split into iterators over the subtrees of
+/// the root, to be driven on a thread pool.
+pub struct SMTIntoIterator<K, V, R, H = Sha3TreeHasher>
+where
+    R: TreeReader<K, V>,
+{
+    iter: JellyfishMerkleIntoIterator<K, V, R, H>,
+}
+
+impl<K, V, R, H> SMTIntoIterator<K, V, R, H>
+where
+    K: Key,
+    V: Value,
+    R: TreeReader<K, V>,
+    H: TreeHasher,
+{
+    /// Same as `SMTIterator::new`, but the iterator owns `reader`.
+    pub fn new(reader: R, root_hash: HashValue, starting_key: Option<K>) -> Result<Self> {
+        let starting_key = match starting_key {
+            Some(key) => key.into_object().merkle_hash_with::<H>(),
+            None => HashValue::zero(),
+        };
+        let iter = JellyfishMerkleIntoIterator::new(reader, root_hash, starting_key)?;
+        Ok(SMTIntoIterator { iter })
+    }
+
+    /// Splits the iterator into up to 16 iterators, one for each child of the root with keys left
+    /// to yield, in the order of their nibbles. The concatenation of their outputs is the output
+    /// of this iterator. The reader is cloned for each of them.
+    pub fn split(self) -> Vec<Self>
+    where
+        R: Clone,
+    {
+        self.iter
+            .split()
+            .into_iter()
+            .map(|iter| SMTIntoIterator { iter })
+            .collect()
+    }
+}
+
+impl<K, V, R, H> Iterator for SMTIntoIterator<K, V, R, H>
+where
+    K: Key,
+    V: Value,
+    R: TreeReader<K, V>,
+    H: TreeHasher,
+{
+    type Item = Result<(K, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter
+            .next()
+            .map(|result| result.map(|(k, v)| (k.origin, v.origin)))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+pub struct SMTKeyIterator<'a, K, V, R, H = Sha3TreeHasher>
+where
+    R: TreeReader<K, V>,
+{
+    iter: JellyfishMerkleKeyIterator<'a, K, V, R, H>,
+}
+
+impl<'a, K: Clone, V: Clone, R: TreeReader<K, V>, H> Clone for SMTKeyIterator<'a, K, V, R, H> {
+    fn clone(&self) -> Self {
+        Self {
+            iter: self.iter.clone(),
+        }
+    }
+}
+
+impl<'a, K, V, R, H> Iterator for SMTKeyIterator<'a, K, V, R, H>
+where
+    K: Key,
+    V: Value,
+    R: TreeReader<K, V>,
+    H: TreeHasher,
+{
+    type Item = Result<K>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next().map(|result| result.map(|k| k.origin))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+impl<'a, K, V, R, H> DoubleEndedIterator for SMTKeyIterator<'a, K, V, R, H>
+where
+    K: Key,
+    V: Value,
+    R: TreeReader<K, V>,
+    H: TreeHasher,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.iter.next_back().map(|result| result.map(|k| k.origin))
+    }
+}
+
+pub struct SMTDepthIterator<'a, K, V, R, H = Sha3TreeHasher>
+where
+    R: TreeReader<K, V>,
+{
+    iter: JellyfishMerkleDepthIterator<'a, K, V, R, H>,
+}
+
+impl<'a, K: Clone, V: Clone, R: TreeReader<K, V>, H> Clone for SMTDepthIterator<'a, K, V, R, H> {
+    fn clone(&self) -> Self {
+        Self {
+            iter: self.iter.clone(),
+        }
+    }
+}
+
+impl<'a, K, V, R, H> Iterator for SMTDepthIterator<'a, K, V, R, H>
+where
+    K: Key,
+    V: Value,
+    R: TreeReader<K, V>,
+    H: TreeHasher,
+{
+    type Item = Result<(K, V, usize)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter
+            .next()
+            .map(|result| result.map(|(k, v, depth)| (k.origin, v.origin, depth)))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+impl<'a, K, V, R, H> DoubleEndedIterator for SMTDepthIterator<'a, K, V, R, H>
+where
+    K: Key,
+    V: Value,
+    R: TreeReader<K, V>,
+    H: TreeHasher,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.iter
+            .next_back()
+            .map(|result| result.map(|(k, v, depth)| (k.origin, v.origin, depth)))
+    }
+}
+
+pub struct SMTStructureIterator<'a, K, V, R, H = Sha3TreeHasher>
+where
+    R: TreeReader<K, V>,
+{
+    iter: JellyfishMerkleStructureIterator<'a, K, V, R, H>,
+}
+
+impl<'a, K, V, R, H> SMTStructureIterator<'a, K, V, R, H>
+where
+    K: Key,
+    V: Value,
+    R: TreeReader<K, V>,
+    H: TreeHasher,
+{
+    pub fn new(reader: &'a R, root_hash: HashValue) -> Self {
+        SMTStructureIterator {
+            iter: JellyfishMerkleStructureIterator::new(reader, root_hash),
+        }
+    }
+}
+
+impl<'a, K, V, R, H> Iterator for SMTStructureIterator<'a, K, V, R, H>
+where
+    K: Key,
+    V: Value,
+    R: TreeReader<K, V>,
+    H: TreeHasher,
+{
+    type Item = Result<StructuralEvent<K, V>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter
+            .next()
+            .map(|result| result.map(|event| event.map_leaf(|k, v| (k.origin, v.origin))))
+    }
+}
+
+/// The asynchronous counterpart of [`SMTIterator`], reading the nodes from an [`AsyncNodeStore`].
+#[cfg(feature = "async")]
+pub struct SMTStream<'a, K, V, R> {
+    stream: JellyfishMerkleStream<'a, K, V, R>,
+}
+
+#[cfg(feature = "async")]
+impl<'a, K, V, R> SMTStream<'a, K, V, R>
+where
+    K: Key,
+    V: Value,
+    R: AsyncTreeReader<K, V>,
+{
+    pub async fn new(reader: &'a R, root_hash: HashValue, starting_key: Option<K>) -> Result<Self> {
+        let stream =
+            JellyfishMerkleStream::new(reader, root_hash, starting_key.map(|k| k.into_object()))
+                .await?;
+        Ok(SMTStream { stream })
+    }
+
+    pub async fn new_rev(
+        reader: &'a R,
+        root_hash: HashValue,
+        starting_key: Option<K>,
+    ) -> Result<Self> {
+        let stream = JellyfishMerkleStream::new_rev(
+            reader,
+            root_hash,
+            starting_key.map(|k| k.into_object()),
+        )
+        .await?;
+        Ok(SMTStream { stream })
+    }
+}
+
+#[cfg(feature = "async")]
+impl<'a, K, V, R> SMTStream<'a, K, V, R>
+where
+    K: Key + Send + 'a,
+    V: Value + Send + 'a,
+    R: AsyncTreeReader<K, V> + Sync,
+{
+    /// Returns the next key-value pair, or `None` once all of them have been yielded.
+    pub async fn next(&mut self) -> Option<Result<(K, V)>> {
+        std::future::poll_fn(|cx| Pin::new(&mut *self).poll_next(cx)).await
+    }
+}
+
+#[cfg(feature = "async")]
+impl<'a, K, V, R> Stream for SMTStream<'a, K, V, R>
+where
+    K: Key + Send + 'a,
+    V: Value + Send + 'a,
+    R: AsyncTreeReader<K, V> + Sync,
+{
+    type Item = Result<(K, V)>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.get_mut().stream)
+            .poll_next(cx)
+            .map(|item| item.map(|result| result.map(|(k, v)| (k.origin, v.origin))))
+    }
 }
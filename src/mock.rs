@@ -0,0 +1,11 @@
+// Copyright (c) RoochNetwork
+// SPDX-License-Identifier: Apache-2.0
+
+//! Test fixtures, enabled by the `testing` feature: [`MockTreeStore`], an in-memory store of tree
+//! nodes, along with the storage traits it implements and the types they use.
+
+pub use crate::jellyfish_merkle::{
+    mock_tree_store::{MockTestStore, MockTreeStore, TestKey, TestValue},
+    node_type::{Node, NodeKey},
+    NodeBatch, StaleNodeIndex, StaleNodeIndexBatch, TreeReader, TreeUpdateBatch, TreeWriter,
+};
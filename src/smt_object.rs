@@ -4,7 +4,10 @@
 // Copyright (c) The Starcoin Core Contributors
 // SPDX-License-Identifier: Apache-2.0
 
-use crate::{jellyfish_merkle::hash::SMTHash, HashValue};
+use crate::{
+    jellyfish_merkle::hash::{HashCache, SMTHash, Sha3TreeHasher, TreeHasher},
+    HashValue,
+};
 use anyhow::Result;
 #[cfg(any(test, feature = "fuzzing"))]
 use proptest_derive::Arbitrary;
@@ -12,8 +15,14 @@ use serde::{
     de::{self, DeserializeOwned},
     Deserialize, Serialize,
 };
-use std::{cell::Cell, fmt};
-
+use std::fmt;
+
+/// The keys of a tree. A key is encoded to the `raw` bytes of an [`SMTObject`], of any length,
+/// and placed in the tree by the hash of that encoding, which is a [`HashValue`] of
+/// `HashValue::LENGTH` bytes whatever the hasher: every key has a path of the same length, and
+/// the leaf keeps the encoding, from which the key is decoded back. The encoding of a variable
+/// length key has to tell the keys apart, as the bcs encoding of `EncodeToObject` does by
+/// prefixing a byte vector or a string with its length, see [`SMTObject::new_with_key`].
 pub trait Key: std::cmp::Ord + Clone + EncodeToObject + DecodeToObject {}
 
 impl<T: std::cmp::Ord + Clone + EncodeToObject + DecodeToObject> Key for T {}
@@ -57,7 +66,9 @@ where
 pub struct SMTObject<T> {
     pub origin: T,
     pub raw: Vec<u8>,
-    cached_hash: Cell<Option<HashValue>>,
+    /// The hash of `raw`, computed by the first `merkle_hash` call and returned by the following
+    /// ones. Clones keep it, and it does not take part in equality.
+    cached_hash: HashCache,
 }
 
 impl<T> SMTObject<T> {
@@ -65,7 +76,7 @@ impl<T> SMTObject<T> {
         SMTObject {
             origin,
             raw,
-            cached_hash: Cell::new(None),
+            cached_hash: HashCache::default(),
         }
     }
 
@@ -74,7 +85,7 @@ impl<T> SMTObject<T> {
         SMTObject {
             origin,
             raw,
-            cached_hash: Cell::new(Some(hash)),
+            cached_hash: HashCache::with_hash::<Sha3TreeHasher>(hash),
         }
     }
 
@@ -86,7 +97,7 @@ impl<T> SMTObject<T> {
         SMTObject {
             origin,
             raw,
-            cached_hash: Cell::new(None),
+            cached_hash: HashCache::default(),
         }
     }
 
@@ -98,20 +109,47 @@ impl<T> SMTObject<T> {
         Ok(SMTObject {
             origin,
             raw,
-            cached_hash: Cell::new(None),
+            cached_hash: HashCache::default(),
         })
     }
 }
 
+impl SMTObject<Vec<u8>> {
+    /// Wraps the key `raw_key`, of any length, and hashes it with `H`. The bytes are encoded
+    /// prefixed with their length, so that no key is the encoding of another, e.g. of a longer key
+    /// it is a prefix of, and the hash of the encoding is the fixed length path of the key. The
+    /// hash is cached, so placing the key in a tree hashed with `H` does not hash it again.
+    pub fn new_with_key<H: TreeHasher>(raw_key: Vec<u8>) -> Self {
+        let key = Self::from_origin(raw_key);
+        key.merkle_hash_with::<H>();
+        key
+    }
+}
+
 impl<T> PartialOrd for SMTObject<T> {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(self.raw.cmp(&other.raw))
+        Some(self.cmp(other))
     }
 }
 
+/// The objects are ordered by their `merkle_hash`, the order of the keys in a
+/// tree hashed with the default hasher: sorting keys, or keeping them in a `BTreeMap`, yields
+/// them in the order the iterators visit them. For a tree hashed with another hasher `H`, sort by
+/// `merkle_hash_with::<H>` instead.
+///
+/// Equality is by content, `raw`, which the order agrees with: the objects of equal content are
+/// equal whatever their cached hash, and the objects of equal hashes are ordered by `raw`.
+///
+/// The hash cache is only ever set to the hash of `raw`, so an object keeps its place as the key
+/// of a map, although clippy's `mutable_key_type` lint flags it for the interior mutability.
 impl<T> Ord for SMTObject<T> {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.raw.cmp(&other.raw)
+        if self.raw == other.raw {
+            return std::cmp::Ordering::Equal;
+        }
+        self.merkle_hash()
+            .cmp(&other.merkle_hash())
+            .then_with(|| self.raw.cmp(&other.raw))
     }
 }
 
@@ -179,14 +217,7 @@ where
 }
 
 impl<T> SMTHash for SMTObject<T> {
-    fn merkle_hash(&self) -> HashValue {
-        match self.cached_hash.get() {
-            Some(hash) => hash,
-            None => {
-                let hash = HashValue::sha3_256_of(&self.raw);
-                self.cached_hash.set(Some(hash));
-                hash
-            }
-        }
+    fn merkle_hash_with<H: TreeHasher>(&self) -> HashValue {
+        self.cached_hash.get_or_compute::<H>(|| H::hash(&self.raw))
     }
 }
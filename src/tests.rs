@@ -2,6 +2,8 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use super::*;
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 #[test]
 fn test_smt() {
@@ -48,8 +50,1167 @@ fn test_smt() {
 
     let iter = smt.iter(None).unwrap();
     assert_eq!(iter.count(), 3);
+    assert_eq!(smt.count_leaves().unwrap(), 3);
 
     smt.remove(key2).unwrap();
     let iter = smt.iter(None).unwrap();
     assert_eq!(iter.count(), 2);
+    assert_eq!(smt.count_leaves().unwrap(), 2);
+}
+
+#[test]
+fn test_smt_diff() {
+    let node_store = InMemoryNodeStore::default();
+    let smt = SMTree::new(node_store, None);
+    let old_root = smt
+        .puts(vec![
+            ("a".to_string(), Some("1".to_string())),
+            ("b".to_string(), Some("2".to_string())),
+            ("c".to_string(), Some("3".to_string())),
+        ])
+        .unwrap();
+    let new_root = smt
+        .puts(vec![
+            ("a".to_string(), None),
+            ("b".to_string(), Some("4".to_string())),
+            ("d".to_string(), Some("5".to_string())),
+        ])
+        .unwrap();
+
+    let tree_diff = smt.diff(old_root, new_root).unwrap();
+    assert_eq!(tree_diff.added, vec![("d".to_string(), "5".to_string())]);
+    assert_eq!(tree_diff.removed, vec![("a".to_string(), "1".to_string())]);
+    assert_eq!(
+        tree_diff.modified,
+        vec![("b".to_string(), "2".to_string(), "4".to_string())]
+    );
+    assert!(smt.diff(new_root, new_root).unwrap().is_empty());
+
+    let diffs = smt
+        .join(old_root, new_root)
+        .collect::<Result<Vec<_>>>()
+        .unwrap();
+    assert_eq!(diffs.len(), 3);
+    assert!(diffs.contains(&Diff::Added("d".to_string(), "5".to_string())));
+    assert!(diffs.contains(&Diff::Removed("a".to_string(), "1".to_string())));
+    assert!(diffs.contains(&Diff::Changed(
+        "b".to_string(),
+        "2".to_string(),
+        "4".to_string()
+    )));
+    assert!(smt.join(new_root, new_root).next().is_none());
+
+    let mut changes = smt
+        .changed_since(old_root)
+        .collect::<Result<Vec<_>>>()
+        .unwrap();
+    changes.sort();
+    assert_eq!(
+        changes,
+        vec![
+            ("b".to_string(), "4".to_string()),
+            ("d".to_string(), "5".to_string())
+        ]
+    );
+    assert!(smt.changed_since(new_root).next().is_none());
+}
+
+#[test]
+fn test_smt_first_and_last_key() {
+    let node_store = InMemoryNodeStore::default();
+    let smt: SMTree<String, String, _> = SMTree::new(node_store, None);
+    assert_eq!(smt.first_key().unwrap(), None);
+    assert_eq!(smt.last_key().unwrap(), None);
+
+    smt.puts(vec![
+        ("a".to_string(), Some("1".to_string())),
+        ("b".to_string(), Some("2".to_string())),
+        ("c".to_string(), Some("3".to_string())),
+    ])
+    .unwrap();
+    let keys = smt
+        .iter(None)
+        .unwrap()
+        .keys()
+        .collect::<Result<Vec<_>>>()
+        .unwrap();
+    assert_eq!(smt.first_key().unwrap().as_ref(), keys.first());
+    assert_eq!(smt.last_key().unwrap().as_ref(), keys.last());
+    assert_eq!(
+        smt.neighbors("b".to_string()).unwrap(),
+        (Some("b".to_string()), Some("b".to_string()))
+    );
+
+    // The neighbors of an absent key are the keys around it once it is inserted.
+    smt.put("d".to_string(), "4".to_string()).unwrap();
+    let keys = smt
+        .iter(None)
+        .unwrap()
+        .keys()
+        .collect::<Result<Vec<_>>>()
+        .unwrap();
+    let index = keys.iter().position(|key| key == "d").unwrap();
+    smt.remove("d".to_string()).unwrap();
+    assert_eq!(
+        smt.neighbors("d".to_string()).unwrap(),
+        (
+            index.checked_sub(1).map(|i| keys[i].clone()),
+            keys.get(index + 1).cloned()
+        )
+    );
+}
+
+#[test]
+fn test_smt_from_sorted() {
+    let kvs = (0..100)
+        .map(|i| (i.to_string(), i.to_string()))
+        .collect::<Vec<_>>();
+    let smt: SMTree<String, String, _> = SMTree::new(InMemoryNodeStore::default(), None);
+    smt.puts(
+        kvs.iter()
+            .map(|(k, v)| (k.clone(), Some(v.clone())))
+            .collect::<Vec<_>>(),
+    )
+    .unwrap();
+    let sorted = smt.iter(None).unwrap().collect::<Result<Vec<_>>>().unwrap();
+
+    let built: SMTree<String, String, _> =
+        SMTree::from_sorted(InMemoryNodeStore::default(), sorted).unwrap();
+    assert_eq!(built.root_hash(), smt.root_hash());
+    for (k, v) in kvs {
+        assert_eq!(built.get(k).unwrap(), Some(v));
+    }
+    assert!(SMTree::<String, String, _>::from_sorted(
+        InMemoryNodeStore::default(),
+        vec![
+            ("a".to_string(), "1".to_string()),
+            ("a".to_string(), "2".to_string())
+        ],
+    )
+    .is_err());
+}
+
+#[test]
+fn test_smt_from_pairs() {
+    let smt: SMTree<String, String, _> = SMTree::from_pairs(
+        InMemoryNodeStore::default(),
+        vec![
+            ("b".to_string(), "1".to_string()),
+            ("a".to_string(), "2".to_string()),
+            ("b".to_string(), "3".to_string()),
+        ],
+    )
+    .unwrap();
+    assert_eq!(smt.get("a".to_string()).unwrap(), Some("2".to_string()));
+    assert_eq!(smt.get("b".to_string()).unwrap(), Some("3".to_string()));
+    assert_eq!(smt.count_leaves().unwrap(), 2);
+}
+
+#[test]
+fn test_inspect_nodes() {
+    let node_store = InMemoryNodeStore::default();
+    let smt: SMTree<String, String, _> = SMTree::new(node_store.clone(), None);
+    let kvs = (0..100)
+        .map(|i| (format!("key{}", i), Some(format!("value{}", i))))
+        .collect::<Vec<_>>();
+    let state_root = smt.puts(kvs).unwrap();
+
+    // Walk the tree from the stored nodes, as a tool outside the crate would.
+    let mut leaves = 0;
+    let mut node_keys = vec![state_root];
+    while let Some(node_key) = node_keys.pop() {
+        let encoded = NodeStore::get(&node_store, &node_key).unwrap().unwrap();
+        let node = Node::<String, String>::decode(&encoded).unwrap();
+        match node {
+            Node::Internal(internal_node) => {
+                let (existence_bitmap, leaf_bitmap) = internal_node.generate_bitmaps();
+                assert_eq!(
+                    existence_bitmap.count_ones() as usize,
+                    internal_node.children().count()
+                );
+                for (nibble, child) in internal_node.children() {
+                    assert_eq!(internal_node.child(nibble), Some(child));
+                    assert_eq!(leaf_bitmap >> u8::from(nibble) & 1 == 1, child.is_leaf);
+                    node_keys.push(child.hash);
+                }
+            }
+            Node::Leaf(_) => leaves += 1,
+            Node::Null => panic!("Should not reach a null node."),
+        }
+    }
+    assert_eq!(leaves, 100);
+}
+
+#[test]
+fn test_smt_structure() {
+    let smt: SMTree<String, String, _> = SMTree::new(InMemoryNodeStore::default(), None);
+    assert_eq!(smt.structure().count(), 0);
+    let kvs = (0..100)
+        .map(|i| (format!("key{}", i), Some(format!("value{}", i))))
+        .collect::<Vec<_>>();
+    smt.puts(kvs).unwrap();
+
+    let events = smt.structure().collect::<Result<Vec<_>>>().unwrap();
+    let leaves = events
+        .into_iter()
+        .filter_map(|event| match event {
+            StructuralEvent::Leaf { key, value, .. } => Some((key, value)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        leaves,
+        smt.iter(None).unwrap().collect::<Result<Vec<_>>>().unwrap()
+    );
+}
+
+#[test]
+fn test_smt_resume() {
+    let smt: SMTree<String, String, _> = SMTree::new(InMemoryNodeStore::default(), None);
+    smt.puts(
+        (0..50)
+            .map(|i| (format!("key{}", i), Some(format!("value{}", i))))
+            .collect::<Vec<_>>(),
+    )
+    .unwrap();
+    let expected = smt.iter(None).unwrap().collect::<Result<Vec<_>>>().unwrap();
+
+    // Page through the tree, 20 keys at a time.
+    let mut pages = vec![];
+    let mut cursor = smt.iter(None).unwrap().cursor();
+    loop {
+        let mut iter = smt.resume(cursor.clone()).unwrap();
+        let page = iter.by_ref().take(20).collect::<Result<Vec<_>>>().unwrap();
+        if page.is_empty() {
+            break;
+        }
+        pages.extend(page);
+        cursor = iter.cursor();
+    }
+    assert_eq!(pages, expected);
+
+    // A cursor is bound to the root it was taken on.
+    smt.put("key50".to_string(), "value50".to_string()).unwrap();
+    assert!(smt.resume(cursor).is_err());
+}
+
+#[test]
+fn test_smt_iter_pins_root() {
+    let smt: SMTree<String, String, _> = SMTree::new(InMemoryNodeStore::default(), None);
+    smt.put("key1".to_string(), "value1".to_string()).unwrap();
+    let root = smt.root_hash();
+    assert!(smt.pinned_roots().roots().is_empty());
+
+    let iter = smt.iter(None).unwrap();
+    let range = smt.range(Bound::Unbounded, Bound::Unbounded).unwrap();
+    assert_eq!(smt.pinned_roots().roots(), vec![root]);
+    // The root stays pinned while the tree moves on.
+    smt.put("key2".to_string(), "value2".to_string()).unwrap();
+    drop(iter);
+    assert!(smt.pinned_roots().is_pinned(&root));
+    drop(range);
+    assert!(!smt.pinned_roots().is_pinned(&root));
+}
+
+#[test]
+fn test_smt_iter_seek() {
+    let smt: SMTree<String, String, _> = SMTree::new(InMemoryNodeStore::default(), None);
+    smt.puts(
+        (0..20)
+            .map(|i| (format!("key{}", i), Some(format!("value{}", i))))
+            .collect::<Vec<_>>(),
+    )
+    .unwrap();
+    let pairs = smt.iter(None).unwrap().collect::<Result<Vec<_>>>().unwrap();
+
+    let mut iter = smt.iter(None).unwrap();
+    iter.seek(pairs[10].0.clone()).unwrap();
+    assert_eq!(iter.next().unwrap().unwrap(), pairs[10]);
+    // Seeking backwards yields the skipped pairs again.
+    iter.seek(pairs[3].0.clone()).unwrap();
+    assert_eq!(
+        iter.collect::<Result<Vec<_>>>().unwrap(),
+        pairs[3..].to_vec()
+    );
+}
+
+#[test]
+fn test_smt_iter_shard() {
+    let smt: SMTree<String, String, _> = SMTree::from_pairs(
+        InMemoryNodeStore::default(),
+        (0..100).map(|i| (format!("key{}", i), format!("value{}", i))),
+    )
+    .unwrap();
+    let expected = smt.iter(None).unwrap().collect::<Result<Vec<_>>>().unwrap();
+
+    // The shards together yield every pair once, in order.
+    let shards = (0..16u8)
+        .map(|shard| {
+            smt.iter_shard(Nibble::from(shard))
+                .unwrap()
+                .collect::<Result<Vec<_>>>()
+                .unwrap()
+        })
+        .collect::<Vec<_>>();
+    assert!(shards.iter().all(|shard| shard.len() < expected.len()));
+    assert_eq!(shards.concat(), expected);
+}
+
+#[test]
+fn test_smt_iter_prefix() {
+    let smt: SMTree<String, String, _> = SMTree::from_pairs(
+        InMemoryNodeStore::default(),
+        (0..100).map(|i| (format!("key{}", i), format!("value{}", i))),
+    )
+    .unwrap();
+    let expected = smt.iter(None).unwrap().collect::<Result<Vec<_>>>().unwrap();
+
+    // The scans of the 16 single nibble prefixes yield every pair once, in order.
+    let mut pairs = vec![];
+    for nibble in 0..16u8 {
+        let prefix = NibblePath::new_odd(vec![nibble << 4]);
+        let scan = smt
+            .iter_prefix(prefix)
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert!(scan.len() < expected.len());
+        pairs.extend(scan);
+    }
+    assert_eq!(pairs, expected);
+    // The empty prefix covers the whole tree.
+    assert_eq!(
+        smt.iter_prefix(NibblePath::new(vec![]))
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap(),
+        expected
+    );
+}
+
+#[test]
+fn test_smt_split_iter() {
+    let smt: SMTree<String, String, _> = SMTree::from_pairs(
+        InMemoryNodeStore::default(),
+        (0..200).map(|i| (format!("key{}", i), format!("value{}", i))),
+    )
+    .unwrap();
+    let expected = smt.iter(None).unwrap().collect::<Result<Vec<_>>>().unwrap();
+
+    let iters = smt.split_iter().unwrap();
+    assert!(iters.len() > 1);
+    let pairs = std::thread::scope(|scope| {
+        iters
+            .into_iter()
+            .map(|iter| scope.spawn(move || iter.collect::<Result<Vec<_>>>().unwrap()))
+            .collect::<Vec<_>>()
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect::<Vec<_>>()
+    });
+    assert_eq!(pairs, expected);
+}
+
+#[test]
+fn test_smt_get_with() {
+    let smt: SMTree<String, String, _> =
+        SMTree::new(InMemoryNodeStore::default(), None).with_default_value("0".to_string());
+    smt.puts(vec![
+        ("alice".to_string(), Some("10".to_string())),
+        ("bob".to_string(), Some("five".to_string())),
+    ])
+    .unwrap();
+    assert_eq!(
+        smt.get_with("alice".to_string(), |value| value.len())
+            .unwrap(),
+        Some(2)
+    );
+    assert_eq!(
+        smt.get_with("bob".to_string(), |value| value.len())
+            .unwrap(),
+        Some(4)
+    );
+    // Unlike `get`, the default value is not lent for absent keys.
+    assert_eq!(
+        smt.get_with("carol".to_string(), |value| value.len())
+            .unwrap(),
+        None
+    );
+}
+
+/// Counts the callbacks of an observer and the bytes of the nodes read.
+#[derive(Default)]
+struct CountingObserver {
+    node_reads: AtomicUsize,
+    bytes_read: AtomicUsize,
+    leaves: AtomicUsize,
+    descents: AtomicUsize,
+}
+
+impl Observer for CountingObserver {
+    fn on_node_read(&self, _node_key: &NodeKey, size: usize) {
+        self.node_reads.fetch_add(1, Ordering::Relaxed);
+        self.bytes_read.fetch_add(size, Ordering::Relaxed);
+    }
+
+    fn on_leaf_yielded(&self, _key_hash: &HashValue) {
+        self.leaves.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_descent(&self, _depth: usize) {
+        self.descents.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[test]
+fn test_smt_observer() {
+    let observer = Arc::new(CountingObserver::default());
+    let smt: SMTree<String, String, _> =
+        SMTree::new(InMemoryNodeStore::default(), None).with_observer(observer.clone());
+    smt.puts(
+        (0..20)
+            .map(|i| (i.to_string(), Some(i.to_string())))
+            .collect::<Vec<_>>(),
+    )
+    .unwrap();
+    // The update of an empty tree reads nothing.
+    assert_eq!(observer.node_reads.load(Ordering::Relaxed), 0);
+
+    smt.put("0".to_string(), "zero".to_string()).unwrap();
+    let update_reads = observer.node_reads.load(Ordering::Relaxed);
+    assert!(update_reads > 0);
+
+    assert_eq!(smt.get("0".to_string()).unwrap(), Some("zero".to_string()));
+    assert!(observer.node_reads.load(Ordering::Relaxed) > update_reads);
+    assert!(observer.bytes_read.load(Ordering::Relaxed) > 0);
+    assert_eq!(observer.descents.load(Ordering::Relaxed), 1);
+
+    assert_eq!(smt.iter(None).unwrap().count(), 20);
+    assert_eq!(observer.leaves.load(Ordering::Relaxed), 20);
+    assert_eq!(observer.descents.load(Ordering::Relaxed), 21);
+}
+
+#[test]
+fn test_smt_observer_scans() {
+    let observer = Arc::new(CountingObserver::default());
+    let smt: SMTree<String, String, _> =
+        SMTree::new(InMemoryNodeStore::default(), None).with_observer(observer.clone());
+    let old_root = smt
+        .puts(
+            (0..100)
+                .map(|i| (i.to_string(), Some(i.to_string())))
+                .collect::<Vec<_>>(),
+        )
+        .unwrap();
+    let new_root = smt.put("0".to_string(), "zero".to_string()).unwrap();
+    let node_reads = || observer.node_reads.swap(0, Ordering::Relaxed);
+
+    // A full scan reads every node of the tree, the structure iterator once each.
+    node_reads();
+    let nodes = smt
+        .structure()
+        .filter(|event| !matches!(event, Ok(StructuralEvent::ExitInternal)))
+        .count();
+    assert_eq!(node_reads(), nodes);
+    assert_eq!(smt.iter(None).unwrap().count(), 100);
+    assert!(node_reads() >= nodes);
+    assert_eq!(smt.iter_rev(None).unwrap().count(), 100);
+    assert!(node_reads() >= nodes);
+    let shards = (0..16)
+        .map(|shard| smt.iter_shard(Nibble::from(shard)).unwrap().count())
+        .sum::<usize>();
+    assert_eq!(shards, 100);
+    assert!(node_reads() >= nodes);
+    let split = smt
+        .split_iter()
+        .unwrap()
+        .into_iter()
+        .map(|iter| iter.count())
+        .sum::<usize>();
+    assert_eq!(split, 100);
+    assert!(node_reads() >= nodes);
+
+    // The partial scans read some nodes.
+    let mut iter = smt.iter(None).unwrap();
+    iter.next().unwrap().unwrap();
+    let cursor = iter.cursor();
+    assert!(node_reads() > 0);
+    assert_eq!(smt.resume(cursor).unwrap().count(), 99);
+    assert!(node_reads() > 0);
+    let keys = smt
+        .iter(None)
+        .unwrap()
+        .map(|item| item.unwrap().0)
+        .collect::<Vec<_>>();
+    node_reads();
+    assert_eq!(
+        smt.range(
+            Bound::Included(keys[10].clone()),
+            Bound::Excluded(keys[20].clone())
+        )
+        .unwrap()
+        .count(),
+        10
+    );
+    assert!(node_reads() > 0);
+    assert!(
+        smt.iter_prefix(NibblePath::new_odd(vec![0x10]))
+            .unwrap()
+            .count()
+            > 0
+    );
+    assert!(node_reads() > 0);
+    assert_eq!(smt.join(old_root, new_root).count(), 1);
+    assert!(node_reads() > 0);
+    assert_eq!(smt.changed_since(old_root).count(), 1);
+    assert!(node_reads() > 0);
+}
+
+#[test]
+fn test_smt_default_value() {
+    let smt: SMTree<String, String, _> =
+        SMTree::new(InMemoryNodeStore::default(), None).with_default_value("0".to_string());
+    assert_eq!(smt.default_value(), Some(&"0".to_string()));
+    assert_eq!(smt.get("alice".to_string()).unwrap(), Some("0".to_string()));
+
+    // Putting the default stores no leaf.
+    smt.put("alice".to_string(), "0".to_string()).unwrap();
+    assert!(smt.is_genesis());
+    assert_eq!(smt.get("alice".to_string()).unwrap(), Some("0".to_string()));
+    assert!(!smt.contains("alice".to_string()).unwrap());
+    assert_eq!(smt.count_leaves().unwrap(), 0);
+
+    let root = smt.put("alice".to_string(), "10".to_string()).unwrap();
+    assert_eq!(
+        smt.get("alice".to_string()).unwrap(),
+        Some("10".to_string())
+    );
+    let plain = SMTree::new(InMemoryNodeStore::default(), None);
+    assert_eq!(
+        plain.put("alice".to_string(), "10".to_string()).unwrap(),
+        root
+    );
+
+    // Putting the default back deletes the key, and the hashing is the same as without default.
+    smt.puts(vec![
+        ("alice".to_string(), Some("0".to_string())),
+        ("bob".to_string(), Some("5".to_string())),
+    ])
+    .unwrap();
+    plain
+        .puts(vec![
+            ("alice".to_string(), None),
+            ("bob".to_string(), Some("5".to_string())),
+        ])
+        .unwrap();
+    assert_eq!(smt.root_hash(), plain.root_hash());
+    assert_eq!(smt.count_leaves().unwrap(), 1);
+    assert_eq!(smt.get("alice".to_string()).unwrap(), Some("0".to_string()));
+    let (value, proof) = smt.get_with_proof("alice".to_string()).unwrap();
+    assert_eq!(value, None);
+    assert!(proof
+        .verify::<String, String>(smt.root_hash(), "alice".to_string(), None)
+        .is_ok());
+}
+
+#[test]
+fn test_smt_get_many() {
+    let smt: SMTree<String, String, _> = SMTree::new(InMemoryNodeStore::default(), None);
+    smt.puts(
+        (0..50)
+            .map(|i| (format!("key{}", i), Some(format!("value{}", i))))
+            .collect::<Vec<_>>(),
+    )
+    .unwrap();
+    let keys = (0..100)
+        .rev()
+        .map(|i| format!("key{}", i))
+        .collect::<Vec<_>>();
+    let expected = keys
+        .iter()
+        .map(|key| smt.get(key.clone()).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(smt.get_many(keys.clone()).unwrap(), expected);
+
+    let smt = smt.with_default_value("none".to_string());
+    let values = smt.get_many(keys).unwrap();
+    assert_eq!(values[0], Some("none".to_string()));
+    assert_eq!(values[99], Some("value0".to_string()));
+}
+
+#[test]
+fn test_smt_nth_leaf() {
+    let node_store = InMemoryNodeStore::default();
+    let smt: SMTree<String, String, _> = SMTree::new(node_store, None);
+    smt.puts(
+        (0..100)
+            .map(|i| (i.to_string(), Some(i.to_string())))
+            .collect::<Vec<_>>(),
+    )
+    .unwrap();
+    let kvs = smt.iter(None).unwrap().collect::<Result<Vec<_>>>().unwrap();
+    for index in [0, 42, 99] {
+        assert_eq!(
+            smt.nth_leaf(index).unwrap().as_ref(),
+            kvs.get(index as usize)
+        );
+    }
+    assert_eq!(smt.nth_leaf(100).unwrap(), None);
+}
+
+/// A hasher with SHA-256 in place of SHA3-256 and another placeholder, so that none of its hashes
+/// match the ones of the default hasher.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+struct Sha256TreeHasher;
+
+impl TreeHasher for Sha256TreeHasher {
+    const SPARSE_MERKLE_PLACEHOLDER: HashValue =
+        HashValue::new(*b"SHA256_SPARSE_MERKLE_PLACEHOLDER");
+
+    fn hash(data: &[u8]) -> HashValue {
+        use sha2::Digest;
+        HashValue::from_slice(sha2::Sha256::digest(data).as_slice()).expect("Should be 32 bytes.")
+    }
+
+    fn hash_leaf(key_hash: HashValue, value_hash: HashValue) -> HashValue {
+        Self::hash(&[key_hash.to_vec(), value_hash.to_vec()].concat())
+    }
+
+    fn hash_internal(left: HashValue, right: HashValue) -> HashValue {
+        Self::hash(&[left.to_vec(), right.to_vec()].concat())
+    }
+}
+
+#[test]
+fn test_default_hasher() {
+    assert_eq!(
+        Sha3TreeHasher::SPARSE_MERKLE_PLACEHOLDER,
+        jellyfish_merkle::hash::create_literal_hash("SPARSE_MERKLE_PLACEHOLDER_HASH")
+    );
+    assert_eq!(
+        Sha3TreeHasher::SPARSE_MERKLE_PLACEHOLDER,
+        *SPARSE_MERKLE_PLACEHOLDER_HASH
+    );
+    assert_eq!(
+        HashValue::SPARSE_MERKLE_PLACEHOLDER,
+        *SPARSE_MERKLE_PLACEHOLDER_HASH
+    );
+}
+
+#[test]
+fn test_smt_object_hash_cache() {
+    use crate::jellyfish_merkle::hash::SMTHash;
+
+    // The object is hashed once, then its cached hash is returned, which the precomputed hash
+    // of `new_for_test` shows.
+    let precomputed = HashValue::random();
+    let key = SMTObject::new_for_test(
+        "key".to_string(),
+        bcs::to_bytes("key").unwrap(),
+        precomputed,
+    );
+    assert_eq!(key.merkle_hash(), precomputed);
+    assert_eq!(key.merkle_hash(), precomputed);
+    // A clone keeps the cached hash.
+    assert_eq!(key.clone().merkle_hash(), precomputed);
+    // The cache does not take part in equality.
+    let fresh = "key".to_string().into_object();
+    assert_eq!(key, fresh);
+    assert_ne!(fresh.merkle_hash(), precomputed);
+    // The hash is cached for a single hasher, another one computes its own.
+    assert_eq!(
+        key.merkle_hash_with::<Sha256TreeHasher>(),
+        Sha256TreeHasher::hash(&key.raw)
+    );
+    assert_eq!(
+        key.merkle_hash_with::<Sha256TreeHasher>(),
+        fresh.merkle_hash_with::<Sha256TreeHasher>()
+    );
+}
+
+#[test]
+// The hash cache of the keys does not change their order, see the `Ord` of `SMTObject`.
+#[allow(clippy::mutable_key_type)]
+fn test_smt_object_order() {
+    let kvs = (0..100)
+        .map(|i| (i.to_string(), i.to_string()))
+        .collect::<Vec<_>>();
+    let smt: SMTree<String, String, _> =
+        SMTree::from_pairs(InMemoryNodeStore::default(), kvs.clone()).unwrap();
+    let iterated = smt
+        .iter(None)
+        .unwrap()
+        .map(|item| item.unwrap().0.into_object())
+        .collect::<Vec<_>>();
+
+    // Sorting the keys, or keeping them in a map, yields them in the order of the iterator.
+    let mut sorted = kvs
+        .iter()
+        .rev()
+        .map(|(key, _)| key.clone().into_object())
+        .collect::<Vec<_>>();
+    sorted.sort();
+    assert_eq!(sorted, iterated);
+    let map = kvs
+        .into_iter()
+        .map(|(key, value)| (key.into_object(), value))
+        .collect::<BTreeMap<_, _>>();
+    assert!(map.keys().eq(iterated.iter()));
+
+    // The order agrees with equality, whatever the cached hash.
+    let key = SMTObject::new_for_test(
+        "key".to_string(),
+        bcs::to_bytes("key").unwrap(),
+        HashValue::random(),
+    );
+    assert_eq!(
+        key.cmp(&"key".to_string().into_object()),
+        std::cmp::Ordering::Equal
+    );
+}
+
+#[test]
+fn test_smt_variable_length_keys() {
+    use crate::jellyfish_merkle::hash::SMTHash;
+
+    // Keys of differing lengths, some the prefix of others, all hash to paths of the same width.
+    let raw_keys = [
+        vec![],
+        vec![0u8],
+        vec![0u8, 0u8],
+        b"key".to_vec(),
+        b"key1".to_vec(),
+        vec![7u8; 1000],
+    ];
+    let keys = raw_keys
+        .iter()
+        .map(|raw_key| SMTObject::new_with_key::<Sha3TreeHasher>(raw_key.clone()))
+        .collect::<Vec<_>>();
+    for (raw_key, key) in raw_keys.iter().zip(&keys) {
+        assert_eq!(key.origin, *raw_key);
+        assert_eq!(
+            key.raw.len(),
+            raw_key.len() + if raw_key.len() < 128 { 1 } else { 2 }
+        );
+        assert_eq!(key.merkle_hash().to_vec().len(), HashValue::LENGTH);
+    }
+    let key_hashes = keys
+        .iter()
+        .map(|key| key.merkle_hash())
+        .collect::<BTreeSet<_>>();
+    assert_eq!(key_hashes.len(), keys.len());
+
+    let smt: SMTree<Vec<u8>, String, _> = SMTree::new(InMemoryNodeStore::default(), None);
+    let root = smt
+        .puts(
+            raw_keys
+                .iter()
+                .enumerate()
+                .map(|(i, raw_key)| (raw_key.clone(), Some(i.to_string())))
+                .collect::<Vec<_>>(),
+        )
+        .unwrap();
+    for (i, (raw_key, key)) in raw_keys.iter().zip(&keys).enumerate() {
+        let (value, proof) = smt.get_with_proof(raw_key.clone()).unwrap();
+        assert_eq!(value, Some(i.to_string()));
+        assert_eq!(proof.leaf().unwrap().0, key.merkle_hash());
+        proof
+            .verify(root, raw_key.clone(), Some(i.to_string()))
+            .unwrap();
+    }
+    // The keys are decoded back from the leaves.
+    let iterated = smt
+        .iter(None)
+        .unwrap()
+        .map(|item| item.unwrap().0)
+        .collect::<BTreeSet<_>>();
+    assert_eq!(iterated, raw_keys.into_iter().collect());
+}
+
+#[test]
+fn test_is_empty_root() {
+    let smt = SMTree::new(InMemoryNodeStore::default(), None);
+    assert_eq!(smt.root_hash(), HashValue::SPARSE_MERKLE_PLACEHOLDER);
+    assert!(is_empty_root(smt.root_hash()));
+    let state_root = smt.put("key".to_string(), "value".to_string()).unwrap();
+    assert!(!is_empty_root(state_root));
+    smt.remove("key".to_string()).unwrap();
+    assert!(is_empty_root(smt.root_hash()));
+
+    assert!(Sha256TreeHasher::is_empty_root(
+        Sha256TreeHasher::SPARSE_MERKLE_PLACEHOLDER
+    ));
+    assert!(!Sha256TreeHasher::is_empty_root(
+        HashValue::SPARSE_MERKLE_PLACEHOLDER
+    ));
+}
+
+#[test]
+fn test_smt_with_hasher() {
+    let smt: SMTree<String, String, _, Sha256TreeHasher> =
+        SMTree::new_with_hasher(InMemoryNodeStore::default(), None);
+    assert_eq!(smt.root_hash(), Sha256TreeHasher::SPARSE_MERKLE_PLACEHOLDER);
+    assert!(smt.is_genesis());
+    assert_eq!(smt.iter(None).unwrap().count(), 0);
+    assert_eq!(smt.count_leaves().unwrap(), 0);
+    let (result, proof) = smt.get_with_proof("key0".to_string()).unwrap();
+    assert_eq!(result, None);
+    assert!(proof
+        .verify::<String, String>(smt.root_hash(), "key0".to_string(), None)
+        .is_ok());
+
+    let kvs = (0..100)
+        .map(|i| (format!("key{}", i), Some(format!("value{}", i))))
+        .collect::<Vec<_>>();
+    let state_root = smt.puts(kvs.clone()).unwrap();
+    let default_smt = SMTree::new(InMemoryNodeStore::default(), None);
+    assert_ne!(state_root, default_smt.puts(kvs).unwrap());
+
+    let (result, proof) = smt.get_with_proof("key1".to_string()).unwrap();
+    assert_eq!(result, Some("value1".to_string()));
+    assert!(proof
+        .verify(state_root, "key1".to_string(), Some("value1".to_string()))
+        .is_ok());
+    // The proof does not hold with the hashes of another hasher.
+    let default_proof =
+        SparseMerkleProof::<Sha3TreeHasher>::new(proof.leaf(), proof.siblings().to_vec());
+    assert!(default_proof
+        .verify(state_root, "key1".to_string(), Some("value1".to_string()))
+        .is_err());
+
+    let keys = vec!["key2".to_string(), "key200".to_string()];
+    let proof = smt.get_multiproof(keys.clone()).unwrap();
+    let value_hashes = proof.verify(state_root, keys).unwrap();
+    assert!(value_hashes[0].is_some());
+    assert!(value_hashes[1].is_none());
+
+    // The keys are placed in the tree by their SHA-256 hashes.
+    let mut expected = (0..100)
+        .map(|i| (format!("key{}", i), format!("value{}", i)))
+        .collect::<Vec<_>>();
+    expected.sort_by_key(|(key, _)| Sha256TreeHasher::hash(&bcs::to_bytes(key).unwrap()));
+    let actual = smt.iter(None).unwrap().collect::<Result<Vec<_>>>().unwrap();
+    assert_eq!(actual, expected);
+    assert_eq!(smt.count_leaves().unwrap(), 100);
+
+    let (leaves, proof) = smt
+        .get_interval_proof(expected[10].0.clone(), expected[20].0.clone())
+        .unwrap();
+    assert_eq!(leaves, expected[10..=20]);
+    assert!(proof
+        .verify(
+            state_root,
+            expected[10].0.clone(),
+            expected[20].0.clone(),
+            leaves
+        )
+        .is_ok());
+
+    smt.remove("key1".to_string()).unwrap();
+    assert_eq!(smt.get("key1".to_string()).unwrap(), None);
+    assert_eq!(smt.count_leaves().unwrap(), 99);
+}
+
+/// Returns the root hash of a tree holding `kvs`, built with `H`.
+#[cfg(any(feature = "sha3", feature = "sha256", feature = "blake3"))]
+fn root_hash_with<H: TreeHasher>(kvs: &[(String, Option<String>)]) -> HashValue {
+    let smt: SMTree<String, String, _, H> =
+        SMTree::new_with_hasher(InMemoryNodeStore::default(), None);
+    smt.puts(kvs.to_vec()).unwrap()
+}
+
+/// Checks that a tree built with `H` has a deterministic root hash and valid proofs.
+#[cfg(any(feature = "sha3", feature = "sha256", feature = "blake3"))]
+fn check_hasher<H: TreeHasher>(kvs: &[(String, Option<String>)]) -> HashValue {
+    let state_root = root_hash_with::<H>(kvs);
+    assert_eq!(root_hash_with::<H>(kvs), state_root);
+    let mut reversed = kvs.to_vec();
+    reversed.reverse();
+    assert_eq!(root_hash_with::<H>(&reversed), state_root);
+
+    let smt: SMTree<String, String, _, H> =
+        SMTree::new_with_hasher(InMemoryNodeStore::default(), None);
+    assert_eq!(smt.root_hash(), H::SPARSE_MERKLE_PLACEHOLDER);
+    smt.puts(kvs.to_vec()).unwrap();
+    let (key, value) = &kvs[0];
+    let (result, proof) = smt.get_with_proof(key.clone()).unwrap();
+    assert_eq!(&result, value);
+    assert!(proof.verify(state_root, key.clone(), value.clone()).is_ok());
+    state_root
+}
+
+#[cfg(any(feature = "sha3", feature = "sha256", feature = "blake3"))]
+#[test]
+fn test_hashers() {
+    let kvs = (0..100)
+        .map(|i| (format!("key{}", i), Some(format!("value{}", i))))
+        .collect::<Vec<_>>();
+    let mut roots = vec![check_hasher::<Sha3TreeHasher>(&kvs)];
+    let (left, right) = (HashValue::random(), HashValue::random());
+    #[cfg(feature = "sha3")]
+    {
+        roots.push(check_hasher::<Sha3_256Hasher>(&kvs));
+        assert_ne!(
+            Sha3_256Hasher::hash_leaf(left, right),
+            Sha3_256Hasher::hash_internal(left, right)
+        );
+    }
+    #[cfg(feature = "sha256")]
+    {
+        roots.push(check_hasher::<Sha256Hasher>(&kvs));
+        assert_ne!(
+            Sha256Hasher::hash_leaf(left, right),
+            Sha256Hasher::hash_internal(left, right)
+        );
+    }
+    #[cfg(feature = "blake3")]
+    {
+        roots.push(check_hasher::<Blake3Hasher>(&kvs));
+        assert_ne!(
+            Blake3Hasher::hash_leaf(left, right),
+            Blake3Hasher::hash_internal(left, right)
+        );
+    }
+    for (i, root) in roots.iter().enumerate() {
+        assert!(roots[i + 1..].iter().all(|other| other != root));
+    }
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn test_smt_stream() {
+    use futures_core::Stream;
+    use std::{
+        future::{poll_fn, Future},
+        pin::{pin, Pin},
+        task::{Context, Poll, Waker},
+    };
+
+    // The in-memory store never returns pending futures, so polling once is enough.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        match pin!(future).poll(&mut Context::from_waker(Waker::noop())) {
+            Poll::Ready(output) => output,
+            Poll::Pending => panic!("The in-memory store should not be pending."),
+        }
+    }
+
+    let node_store = InMemoryNodeStore::default();
+    let smt = SMTree::new(node_store.clone(), None);
+    let state_root = smt
+        .puts(
+            (0..100)
+                .map(|i| (format!("key{}", i), Some(format!("value{}", i))))
+                .collect::<Vec<_>>(),
+        )
+        .unwrap();
+
+    let expected = smt.iter(None).unwrap().collect::<Result<Vec<_>>>().unwrap();
+    let mut stream = block_on(SMTStream::new(&node_store, state_root, None)).unwrap();
+    let mut actual = vec![];
+    while let Some(item) = block_on(poll_fn(|cx| Pin::new(&mut stream).poll_next(cx))) {
+        actual.push(item.unwrap());
+    }
+    assert_eq!(actual, expected);
+
+    let starting_key = expected[50].0.clone();
+    let expected = smt
+        .iter_rev(Some(starting_key.clone()))
+        .unwrap()
+        .collect::<Result<Vec<_>>>()
+        .unwrap();
+    let mut stream = block_on(SMTStream::<String, String, _>::new_rev(
+        &node_store,
+        state_root,
+        Some(starting_key),
+    ))
+    .unwrap();
+    let mut actual = vec![];
+    while let Some(item) = block_on(stream.next()) {
+        actual.push(item.unwrap());
+    }
+    assert_eq!(actual, expected);
+}
+
+#[test]
+fn test_smt_with_leaf_counts() {
+    let kvs = (0..100)
+        .map(|i| (format!("key{}", i), Some(format!("value{}", i))))
+        .collect::<Vec<_>>();
+    let plain_store = InMemoryNodeStore::default();
+    let plain: SMTree<String, String, _> = SMTree::new(plain_store.clone(), None);
+    let counted_store = InMemoryNodeStore::default();
+    let counted: SMTree<String, String, _> =
+        SMTree::new(counted_store.clone(), None).with_leaf_counts();
+    let root = plain.puts(kvs.clone()).unwrap();
+    assert_eq!(counted.puts(kvs).unwrap(), root);
+
+    // The default stays on the 0x01 layout, the counted nodes use the 0x03 one.
+    assert_eq!(
+        NodeStore::get(&plain_store, &root).unwrap().unwrap()[0],
+        0x01
+    );
+    assert_eq!(
+        NodeStore::get(&counted_store, &root).unwrap().unwrap()[0],
+        0x03
+    );
+    assert_eq!(plain.iter(None).unwrap().size_hint(), (0, None));
+    assert_eq!(counted.iter(None).unwrap().size_hint(), (100, None));
+    assert_eq!(
+        plain
+            .iter(None)
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap(),
+        counted
+            .iter(None)
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap()
+    );
+}
+
+#[test]
+fn test_smt_delete_range() {
+    let kvs = (0..100)
+        .map(|i| (format!("key{}", i), format!("value{}", i)))
+        .collect::<Vec<_>>();
+    let smt: SMTree<String, String, _> =
+        SMTree::from_pairs(InMemoryNodeStore::default(), kvs.clone()).unwrap();
+    let expected: SMTree<String, String, _> =
+        SMTree::from_pairs(InMemoryNodeStore::default(), kvs).unwrap();
+
+    let start_hash = HashValue::new([0x40; HashValue::LENGTH]);
+    let end_hash = HashValue::new([0xbf; HashValue::LENGTH]);
+    let removed = smt
+        .iter(None)
+        .unwrap()
+        .map(|result| result.unwrap().0)
+        .filter(|key| {
+            let key_hash = key.clone().into_object().merkle_hash();
+            start_hash <= key_hash && key_hash <= end_hash
+        })
+        .collect::<Vec<_>>();
+    assert!(!removed.is_empty());
+    for key in removed {
+        expected.remove(key).unwrap();
+    }
+
+    let root = smt.delete_range(start_hash, end_hash).unwrap();
+    assert_eq!(root, expected.root_hash());
+    assert_eq!(smt.root_hash(), root);
+    assert_eq!(
+        smt.iter(None).unwrap().collect::<Result<Vec<_>>>().unwrap(),
+        expected
+            .iter(None)
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap()
+    );
+}
+
+#[test]
+fn test_smt_update_existing() {
+    let kvs = (0..100)
+        .map(|i| (format!("key{}", i), format!("value{}", i)))
+        .collect::<Vec<_>>();
+    let node_store = InMemoryNodeStore::default();
+    let smt: SMTree<String, String, _> =
+        SMTree::from_pairs(node_store.clone(), kvs.clone()).unwrap();
+    let expected: SMTree<String, String, _> =
+        SMTree::from_pairs(InMemoryNodeStore::default(), kvs).unwrap();
+
+    let root = smt
+        .update_existing("key7".to_string(), "changed".to_string())
+        .unwrap();
+    assert_eq!(
+        root,
+        expected
+            .put("key7".to_string(), "changed".to_string())
+            .unwrap()
+    );
+    assert_eq!(smt.root_hash(), root);
+    assert_eq!(
+        smt.get("key7".to_string()).unwrap(),
+        Some("changed".to_string())
+    );
+
+    // An absent key is not inserted.
+    assert!(smt
+        .update_existing("key100".to_string(), "value100".to_string())
+        .is_err());
+    assert_eq!(smt.root_hash(), root);
+
+    // Updating a key to the default value deletes it.
+    let smt = SMTree::<String, String, _>::new(node_store, Some(root))
+        .with_default_value("default".to_string());
+    let root = smt
+        .update_existing("key8".to_string(), "default".to_string())
+        .unwrap();
+    assert_eq!(root, expected.remove("key8".to_string()).unwrap());
+    assert!(smt
+        .update_existing("key8".to_string(), "default".to_string())
+        .is_err());
+}
+
+#[test]
+fn test_smt_compute_root_after() {
+    let smt: SMTree<String, String, _> = SMTree::from_pairs(
+        InMemoryNodeStore::default(),
+        (0..100).map(|i| (format!("key{}", i), format!("value{}", i))),
+    )
+    .unwrap()
+    .with_default_value("default".to_string());
+    let root = smt.root_hash();
+    let updates = vec![
+        ("key1".to_string(), Some("changed".to_string())),
+        ("key2".to_string(), None),
+        ("key3".to_string(), Some("default".to_string())),
+        ("key100".to_string(), Some("value100".to_string())),
+    ];
+
+    let expected = smt.compute_root_after(updates.clone()).unwrap();
+    // Nothing is written and the root stays.
+    assert_eq!(smt.root_hash(), root);
+    assert_eq!(smt.puts(updates).unwrap(), expected);
+    assert_eq!(smt.compute_root_after(vec![]).unwrap(), expected);
+}
+
+#[test]
+fn test_smt_put_with_outcome() {
+    let smt: SMTree<String, String, _> =
+        SMTree::new(InMemoryNodeStore::default(), None).with_default_value("default".to_string());
+    let expected: SMTree<String, String, _> = SMTree::new(InMemoryNodeStore::default(), None);
+
+    let (root, outcome) = smt
+        .put_with_outcome("key".to_string(), "value1".to_string())
+        .unwrap();
+    assert_eq!(outcome, PutOutcome::Inserted);
+    assert_eq!(
+        root,
+        expected
+            .put("key".to_string(), "value1".to_string())
+            .unwrap()
+    );
+    assert_eq!(smt.root_hash(), root);
+
+    let (root, outcome) = smt
+        .put_with_outcome("key".to_string(), "value2".to_string())
+        .unwrap();
+    assert_eq!(
+        outcome,
+        PutOutcome::Updated {
+            old_value: "value1".to_string().into_object()
+        }
+    );
+    assert_eq!(smt.root_hash(), root);
+    assert_eq!(
+        smt.get("key".to_string()).unwrap(),
+        Some("value2".to_string())
+    );
+
+    // The default value is a deletion.
+    assert!(smt
+        .put_with_outcome("key".to_string(), "default".to_string())
+        .is_err());
+    assert_eq!(smt.root_hash(), root);
 }
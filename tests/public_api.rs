@@ -0,0 +1,826 @@
+// Copyright (c) RoochNetwork
+// SPDX-License-Identifier: Apache-2.0
+
+//! Uses the crate as a downstream crate does, only through the items exported by `smt`.
+
+use anyhow::Result;
+use parking_lot::{Mutex, RwLock};
+use smt::{
+    assert_consistent, commit_verified, common_prefix_bits_len, common_prefix_nibble_len,
+    export_ndjson, export_snapshot, extract_subtree, get_siblings, import_snapshot, validate,
+    BloomTreeReader, CachingTreeReader, EncodeToObject, HashValue, InMemoryNodeStore,
+    LeafWithSiblings, Nibble, NibblePath, NibbleSlice, Node, NodeBatch, NodeKey, NodeStore,
+    ProofOutcome, SMTIterator, SMTree, Sha3TreeHasher, StaleNodeIndexBatch, SyncApplier,
+    TreeReader, TreeWriter, ValidationReport, Versioned, VersionedTree, Violation, ViolationKind,
+};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+/// A node store outside of the crate, standing for a database backend.
+#[derive(Default)]
+struct ExternalStore {
+    nodes: RwLock<HashMap<NodeKey, Node<String, String>>>,
+}
+
+impl TreeReader<String, String> for ExternalStore {
+    fn get_node_option(&self, node_key: &NodeKey) -> Result<Option<Node<String, String>>> {
+        Ok(self.nodes.read().get(node_key).cloned())
+    }
+}
+
+impl TreeWriter<String, String> for ExternalStore {
+    fn write_node_batch(&self, node_batch: &NodeBatch<String, String>) -> Result<()> {
+        self.nodes.write().extend(node_batch.clone());
+        Ok(())
+    }
+
+    fn delete_node_batch(&self, node_keys: &[NodeKey]) -> Result<()> {
+        let mut nodes = self.nodes.write();
+        for node_key in node_keys {
+            nodes.remove(node_key);
+        }
+        Ok(())
+    }
+}
+
+impl Versioned<String, String> for ExternalStore {}
+
+#[test]
+fn test_external_tree_store() {
+    let store = ExternalStore::default();
+    let leaf = Node::new_leaf("key".to_string(), "value".to_string());
+    let root = leaf.hash();
+    store
+        .write_node_batch(&NodeBatch::from([(root, leaf)]))
+        .unwrap();
+
+    let pairs = SMTIterator::new(&store, root, None)
+        .unwrap()
+        .collect::<Result<Vec<_>>>()
+        .unwrap();
+    assert_eq!(pairs, vec![("key".to_string(), "value".to_string())]);
+
+    store.delete_node_batch(&[root]).unwrap();
+    assert!(store.get_node_option(&root).unwrap().is_none());
+}
+
+#[test]
+fn test_iterators_on_versioned_roots_are_isolated() {
+    let source = InMemoryNodeStore::default();
+    let smt: SMTree<String, String, _> = SMTree::from_pairs(
+        source.clone(),
+        (0..50).map(|i| (format!("key{}", i), format!("value{}", i))),
+    )
+    .unwrap();
+    let old_root = smt.root_hash();
+    let old_pairs = smt.iter(None).unwrap().collect::<Result<Vec<_>>>().unwrap();
+    smt.put("key0".to_string(), "updated".to_string()).unwrap();
+    smt.put("key50".to_string(), "value50".to_string()).unwrap();
+    let new_root = smt.root_hash();
+    let new_pairs = smt.iter(None).unwrap().collect::<Result<Vec<_>>>().unwrap();
+
+    // Both roots in a store outside of the crate, sharing their unchanged subtrees.
+    let store = ExternalStore::default();
+    for root in [old_root, new_root] {
+        let (_, node_batch) = extract_subtree::<String, String, _, Sha3TreeHasher>(
+            &source,
+            root,
+            NibblePath::new(vec![]),
+        )
+        .unwrap();
+        store.write_node_batch(&node_batch).unwrap();
+    }
+
+    // Interleaved, each iterator only sees the nodes reachable from its own root.
+    let mut old_iter = SMTIterator::new(&store, old_root, None).unwrap();
+    let mut new_iter = SMTIterator::new(&store, new_root, None).unwrap();
+    let (mut old_seen, mut new_seen) = (vec![], vec![]);
+    loop {
+        let old_next = old_iter.next().transpose().unwrap();
+        let new_next = new_iter.next().transpose().unwrap();
+        if old_next.is_none() && new_next.is_none() {
+            break;
+        }
+        old_seen.extend(old_next);
+        new_seen.extend(new_next);
+    }
+    assert_eq!(old_seen, old_pairs);
+    assert_eq!(new_seen, new_pairs);
+    assert_ne!(old_seen, new_seen);
+}
+
+/// A backend reading many nodes in a single round-trip, recording the size of each batch.
+struct MultiGetStore {
+    inner: InMemoryNodeStore,
+    batch_sizes: Mutex<Vec<usize>>,
+}
+
+impl TreeReader<String, String> for MultiGetStore {
+    fn get_node_option(&self, node_key: &NodeKey) -> Result<Option<Node<String, String>>> {
+        TreeReader::get_node_option(&self.inner, node_key)
+    }
+
+    fn get_nodes(&self, node_keys: &[NodeKey]) -> Result<Vec<Option<Node<String, String>>>> {
+        self.batch_sizes.lock().push(node_keys.len());
+        node_keys
+            .iter()
+            .map(|node_key| self.get_node_option(node_key))
+            .collect()
+    }
+}
+
+impl Versioned<String, String> for MultiGetStore {}
+
+#[test]
+fn test_iterator_reads_ahead_with_get_nodes() {
+    let inner = InMemoryNodeStore::default();
+    let smt: SMTree<String, String, _> = SMTree::from_pairs(
+        inner.clone(),
+        (0..100).map(|i| (format!("key{}", i), format!("value{}", i))),
+    )
+    .unwrap();
+    let store = MultiGetStore {
+        inner,
+        batch_sizes: Mutex::new(vec![]),
+    };
+
+    let pairs = SMTIterator::new(&store, smt.root_hash(), None)
+        .unwrap()
+        .collect::<Result<Vec<_>>>()
+        .unwrap();
+    assert_eq!(pairs.len(), 100);
+    // The leaves under an internal node are read ahead with a single call.
+    let batch_sizes = store.batch_sizes.lock();
+    assert!(!batch_sizes.is_empty());
+    assert!(batch_sizes.iter().all(|size| *size > 1));
+}
+
+#[test]
+fn test_caching_tree_reader() {
+    let store = InMemoryNodeStore::default();
+    let smt: SMTree<String, String, _> = SMTree::from_pairs(
+        store.clone(),
+        (0..100).map(|i| (format!("key{}", i), format!("value{}", i))),
+    )
+    .unwrap();
+    let reader: CachingTreeReader<String, String, _> = CachingTreeReader::new(store, 1024);
+
+    let scan = || {
+        SMTIterator::new(&reader, smt.root_hash(), None)
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap()
+    };
+    let pairs = scan();
+    assert_eq!(pairs.len(), 100);
+    let misses = reader.misses();
+    // The second scan is served from the cache.
+    assert_eq!(scan(), pairs);
+    assert_eq!(reader.misses(), misses);
+    assert!(reader.hits() > 0);
+}
+
+#[test]
+fn test_bloom_tree_reader() {
+    let store = InMemoryNodeStore::default();
+    let smt: SMTree<String, String, _> = SMTree::from_pairs(
+        store.clone(),
+        (0..100).map(|i| (format!("key{}", i), format!("value{}", i))),
+    )
+    .unwrap();
+    let root = smt.root_hash();
+    let reader: BloomTreeReader<_> = BloomTreeReader::new(store, 1000, 0.001).unwrap();
+    reader.populate::<String, String>(root).unwrap();
+
+    let present = "key42".to_string().into_object();
+    assert!(reader
+        .contains_key::<String, String>(root, &present)
+        .unwrap());
+    assert_eq!(
+        reader
+            .get::<String, String>(root, &present)
+            .unwrap()
+            .map(|value| value.origin),
+        Some("value42".to_string())
+    );
+    for i in 100..200 {
+        let absent = format!("key{}", i).into_object();
+        assert!(!reader
+            .contains_key::<String, String>(root, &absent)
+            .unwrap());
+    }
+    assert!(reader.skipped() > 0);
+}
+
+#[test]
+fn test_extract_subtree() {
+    let store = InMemoryNodeStore::default();
+    let smt: SMTree<String, String, _> = SMTree::from_pairs(
+        store.clone(),
+        (0..100).map(|i| (format!("key{}", i), format!("value{}", i))),
+    )
+    .unwrap();
+    let prefix = NibblePath::new_odd(vec![0x70]);
+    let expected = smt
+        .iter_prefix(prefix.clone())
+        .unwrap()
+        .collect::<Result<Vec<_>>>()
+        .unwrap();
+    assert!(!expected.is_empty());
+
+    let (root, node_batch) =
+        extract_subtree::<String, String, _, Sha3TreeHasher>(&store, smt.root_hash(), prefix)
+            .unwrap();
+    let extracted = ExternalStore::default();
+    extracted.write_node_batch(&node_batch).unwrap();
+    let pairs = SMTIterator::new(&extracted, root, None)
+        .unwrap()
+        .collect::<Result<Vec<_>>>()
+        .unwrap();
+    assert_eq!(pairs, expected);
+}
+
+#[test]
+fn test_commit() {
+    let source = InMemoryNodeStore::default();
+    let smt: SMTree<String, String, _> = SMTree::from_pairs(
+        source.clone(),
+        (0..10).map(|i| (format!("key{}", i), format!("value{}", i))),
+    )
+    .unwrap();
+    let root = smt.root_hash();
+    let (_, node_batch) = extract_subtree::<String, String, _, Sha3TreeHasher>(
+        &source,
+        root,
+        NibblePath::new(vec![]),
+    )
+    .unwrap();
+
+    // The batch must hold the new root.
+    let store = ExternalStore::default();
+    let mut without_root = node_batch.clone();
+    without_root.remove(&root);
+    assert!(smt::commit(&store, root, without_root, StaleNodeIndexBatch::new()).is_err());
+
+    assert_eq!(
+        smt::commit(&store, root, node_batch, StaleNodeIndexBatch::new()).unwrap(),
+        root
+    );
+    assert_eq!(
+        SMTIterator::new(&store, root, None)
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap(),
+        smt.iter(None).unwrap().collect::<Result<Vec<_>>>().unwrap()
+    );
+}
+
+/// A store losing the writes of the leaves.
+#[derive(Default)]
+struct LossyStore {
+    inner: ExternalStore,
+}
+
+impl TreeReader<String, String> for LossyStore {
+    fn get_node_option(&self, node_key: &NodeKey) -> Result<Option<Node<String, String>>> {
+        self.inner.get_node_option(node_key)
+    }
+}
+
+impl TreeWriter<String, String> for LossyStore {
+    fn write_node_batch(&self, node_batch: &NodeBatch<String, String>) -> Result<()> {
+        let kept = node_batch
+            .iter()
+            .filter(|(_, node)| !matches!(node, Node::Leaf(_)))
+            .map(|(node_key, node)| (*node_key, node.clone()))
+            .collect();
+        self.inner.write_node_batch(&kept)
+    }
+
+    fn delete_node_batch(&self, node_keys: &[NodeKey]) -> Result<()> {
+        self.inner.delete_node_batch(node_keys)
+    }
+}
+
+#[test]
+fn test_commit_verified() {
+    let source = InMemoryNodeStore::default();
+    let smt: SMTree<String, String, _> = SMTree::from_pairs(
+        source.clone(),
+        (0..10).map(|i| (format!("key{}", i), format!("value{}", i))),
+    )
+    .unwrap();
+    let root = smt.root_hash();
+    let (_, node_batch) = extract_subtree::<String, String, _, Sha3TreeHasher>(
+        &source,
+        root,
+        NibblePath::new(vec![]),
+    )
+    .unwrap();
+
+    let store = ExternalStore::default();
+    let committed = commit_verified::<_, _, _, Sha3TreeHasher>(
+        &store,
+        root,
+        node_batch.clone(),
+        StaleNodeIndexBatch::new(),
+    )
+    .unwrap();
+    assert_eq!(committed, root);
+
+    // The leaves the store lost are found missing when read back.
+    let lossy = LossyStore::default();
+    assert!(commit_verified::<_, _, _, Sha3TreeHasher>(
+        &lossy,
+        root,
+        node_batch,
+        StaleNodeIndexBatch::new()
+    )
+    .is_err());
+}
+
+#[test]
+fn test_sync_applier() {
+    let smt: SMTree<String, String, _> = SMTree::from_pairs(
+        InMemoryNodeStore::default(),
+        (0..100).map(|i| (format!("key{}", i), format!("value{}", i))),
+    )
+    .unwrap();
+    let root = smt.root_hash();
+
+    // Four chunks, split on the first byte of the key hash.
+    let store = ExternalStore::default();
+    let mut applier = SyncApplier::<String, String, _>::new(&store, root);
+    for end_byte in [0x3f, 0x7f, 0xbf, 0xff] {
+        let start_hash = applier.next_start().unwrap();
+        let mut end_hash = [0xff; HashValue::LENGTH];
+        end_hash[0] = end_byte;
+        let end_hash = HashValue::new(end_hash);
+        let (leaves, proof) = smt
+            .get_key_hash_interval_proof(start_hash, end_hash)
+            .unwrap();
+        let leaves = leaves
+            .into_iter()
+            .map(|(k, v)| (k.into_object(), v.into_object()))
+            .collect();
+        applier
+            .apply_chunk(start_hash, end_hash, leaves, &proof)
+            .unwrap();
+    }
+    assert_eq!(applier.next_start(), None);
+    assert_eq!(applier.finish().unwrap(), root);
+    assert_eq!(
+        SMTIterator::new(&store, root, None)
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap(),
+        smt.iter(None).unwrap().collect::<Result<Vec<_>>>().unwrap()
+    );
+}
+
+#[test]
+fn test_versioned_tree() {
+    let store = InMemoryNodeStore::default();
+    let smt: SMTree<String, String, _> = SMTree::new(store.clone(), None);
+    let mut versions = VersionedTree::<String, String, _>::new(&store);
+    for version in 1..=3 {
+        let root = smt
+            .put(format!("key{}", version), format!("value{}", version))
+            .unwrap();
+        versions.insert_root(version * 10, root);
+    }
+
+    assert_eq!(versions.get_as_of("key2".to_string(), 15).unwrap(), None);
+    assert_eq!(
+        versions.get_as_of("key2".to_string(), 25).unwrap(),
+        Some("value2".to_string())
+    );
+    let iter: SMTIterator<String, String, _> = versions.iter_as_of(29, None).unwrap();
+    let mut pairs = iter.collect::<Result<Vec<_>>>().unwrap();
+    pairs.sort();
+    assert_eq!(
+        pairs,
+        vec![
+            ("key1".to_string(), "value1".to_string()),
+            ("key2".to_string(), "value2".to_string()),
+        ]
+    );
+    assert_eq!(versions.iter_as_of(5, None).unwrap().count(), 0);
+}
+
+#[test]
+fn test_snapshot() {
+    let source = InMemoryNodeStore::default();
+    let smt: SMTree<String, String, _> = SMTree::from_pairs(
+        source.clone(),
+        (0..100).map(|i| (format!("key{}", i), format!("value{}", i))),
+    )
+    .unwrap();
+    let mut snapshot = vec![];
+    export_snapshot::<String, String, _, Sha3TreeHasher>(&source, smt.root_hash(), &mut snapshot)
+        .unwrap();
+
+    let store = ExternalStore::default();
+    let root =
+        import_snapshot::<String, String, _, Sha3TreeHasher>(&store, &mut snapshot.as_slice())
+            .unwrap();
+    assert_eq!(root, smt.root_hash());
+    assert_eq!(
+        SMTIterator::new(&store, root, None)
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap(),
+        smt.iter(None).unwrap().collect::<Result<Vec<_>>>().unwrap()
+    );
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn test_snapshot_compressed() {
+    let source = InMemoryNodeStore::default();
+    let smt: SMTree<String, String, _> = SMTree::from_pairs(
+        source.clone(),
+        (0..100).map(|i| (format!("key{}", i), format!("value{}", i))),
+    )
+    .unwrap();
+    let mut plain = vec![];
+    export_snapshot::<String, String, _, Sha3TreeHasher>(&source, smt.root_hash(), &mut plain)
+        .unwrap();
+    let mut compressed = vec![];
+    smt::export_snapshot_compressed::<String, String, _, Sha3TreeHasher>(
+        &source,
+        smt.root_hash(),
+        3,
+        &mut compressed,
+    )
+    .unwrap();
+    assert!(compressed.len() < plain.len());
+
+    let store = ExternalStore::default();
+    let root = smt::import_snapshot_compressed::<String, String, _, Sha3TreeHasher>(
+        &store,
+        &mut compressed.as_slice(),
+    )
+    .unwrap();
+    assert_eq!(root, smt.root_hash());
+    assert_eq!(
+        SMTIterator::new(&store, root, None)
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap(),
+        smt.iter(None).unwrap().collect::<Result<Vec<_>>>().unwrap()
+    );
+}
+
+#[test]
+fn test_assert_consistent() {
+    let source = InMemoryNodeStore::default();
+    let smt: SMTree<String, String, _> = SMTree::from_pairs(
+        source.clone(),
+        (0..100).map(|i| (format!("key{}", i), format!("value{}", i))),
+    )
+    .unwrap();
+    let root = smt.root_hash();
+    let (_, node_batch) = extract_subtree::<String, String, _, Sha3TreeHasher>(
+        &source,
+        root,
+        NibblePath::new(vec![]),
+    )
+    .unwrap();
+    let migrated = ExternalStore::default();
+    migrated.write_node_batch(&node_batch).unwrap();
+    assert_consistent::<String, String, _, _, Sha3TreeHasher>(&source, &migrated, root).unwrap();
+
+    // A node lost by the migration is reported.
+    let lost = *node_batch
+        .keys()
+        .find(|node_key| **node_key != root)
+        .unwrap();
+    migrated.delete_node_batch(&[lost]).unwrap();
+    assert!(
+        assert_consistent::<String, String, _, _, Sha3TreeHasher>(&source, &migrated, root)
+            .is_err()
+    );
+}
+
+#[test]
+fn test_validate_imported_snapshot() {
+    let source = InMemoryNodeStore::default();
+    let smt: SMTree<String, String, _> = SMTree::from_pairs(
+        source.clone(),
+        (0..100).map(|i| (format!("key{}", i), format!("value{}", i))),
+    )
+    .unwrap();
+    let mut snapshot = vec![];
+    export_snapshot::<String, String, _, Sha3TreeHasher>(&source, smt.root_hash(), &mut snapshot)
+        .unwrap();
+    let store = ExternalStore::default();
+    let root =
+        import_snapshot::<String, String, _, Sha3TreeHasher>(&store, &mut snapshot.as_slice())
+            .unwrap();
+
+    let report: ValidationReport = validate::<String, String, _, Sha3TreeHasher>(&store, root);
+    assert!(report.is_valid());
+    report.ensure_valid().unwrap();
+    assert_eq!(report.num_leaves, 100);
+
+    // A leaf lost after the import is reported with the path to it.
+    let (lost, _) = store
+        .nodes
+        .read()
+        .iter()
+        .find(|(_, node)| matches!(node, Node::Leaf(_)))
+        .map(|(node_key, node)| (*node_key, node.clone()))
+        .unwrap();
+    store.delete_node_batch(&[lost]).unwrap();
+    let report = validate::<String, String, _, Sha3TreeHasher>(&store, root);
+    assert!(report.ensure_valid().is_err());
+    let violations: &[Violation] = &report.violations;
+    assert_eq!(violations.len(), 1);
+    assert_eq!(violations[0].node_key, lost);
+    assert_eq!(violations[0].kind, ViolationKind::MissingNode);
+}
+
+#[test]
+fn test_export_ndjson() {
+    let store = InMemoryNodeStore::default();
+    let smt: SMTree<String, String, _> = SMTree::from_pairs(
+        store.clone(),
+        (0..10).map(|i| (format!("key{}", i), format!("value{}", i))),
+    )
+    .unwrap();
+    let mut out = vec![];
+    let count = export_ndjson::<String, String, _, Sha3TreeHasher>(
+        &store,
+        smt.root_hash(),
+        &mut out,
+        |key, value| serde_json::json!({ "key": key.origin, "value": value.origin }),
+    )
+    .unwrap();
+    assert_eq!(count, 10);
+
+    let lines = String::from_utf8(out)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .collect::<Vec<_>>();
+    let expected = smt
+        .iter(None)
+        .unwrap()
+        .map(|result| {
+            let (key, value) = result.unwrap();
+            serde_json::json!({ "key": key, "value": value })
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(lines, expected);
+}
+
+#[test]
+fn test_get_with_proof_outcome() {
+    let smt: SMTree<String, String, _> = SMTree::from_pairs(
+        InMemoryNodeStore::default(),
+        (0..10).map(|i| (format!("key{}", i), format!("value{}", i))),
+    )
+    .unwrap();
+    let root = smt.root_hash();
+
+    let outcome = smt.get_with_proof_outcome("key0".to_string()).unwrap();
+    assert!(matches!(&outcome, ProofOutcome::Present(value, _) if value.origin == "value0"));
+    outcome
+        .verify(root, &"key0".to_string().into_object())
+        .unwrap();
+
+    // With 10 leaves under the 16 children of the root, the absent keys meet both empty subtrees
+    // and other leaves.
+    let (mut empty, mut other_leaf) = (0, 0);
+    for i in 0..100 {
+        let key = format!("absent{}", i);
+        let outcome = smt.get_with_proof_outcome(key.clone()).unwrap();
+        match &outcome {
+            ProofOutcome::AbsentEmpty(_) => empty += 1,
+            ProofOutcome::AbsentOtherLeaf(other, proof) => {
+                assert_eq!(proof.leaf(), Some(*other));
+                other_leaf += 1;
+            }
+            ProofOutcome::Present(..) => panic!("Unexpected value for {}", key),
+        }
+        outcome.verify(root, &key.clone().into_object()).unwrap();
+        assert_eq!(smt.get_with_proof(key).unwrap().1, *outcome.proof());
+    }
+    assert!(empty > 0);
+    assert!(other_leaf > 0);
+
+    // An outcome does not verify under another absence reason.
+    let key = "absent0".to_string();
+    let equivocated = match smt.get_with_proof_outcome(key.clone()).unwrap() {
+        ProofOutcome::AbsentEmpty(proof) => ProofOutcome::<String>::AbsentOtherLeaf(
+            (HashValue::random(), HashValue::random()),
+            proof,
+        ),
+        ProofOutcome::AbsentOtherLeaf(_, proof) => ProofOutcome::AbsentEmpty(proof),
+        ProofOutcome::Present(..) => unreachable!(),
+    };
+    assert!(equivocated.verify(root, &key.into_object()).is_err());
+}
+
+#[test]
+fn test_proof_path_hashes() {
+    let store = InMemoryNodeStore::default();
+    let smt: SMTree<String, String, _> = SMTree::from_pairs(
+        store.clone(),
+        (0..100).map(|i| (format!("key{}", i), format!("value{}", i))),
+    )
+    .unwrap();
+    let root = smt.root_hash();
+    for i in 0..100 {
+        let (_, proof) = smt.get_with_proof(format!("key{}", i)).unwrap();
+        let (key_hash, _) = proof.leaf().unwrap();
+        let path_hashes = proof.path_hashes(key_hash);
+        assert_eq!(path_hashes.len(), proof.siblings().len() + 1);
+        assert_eq!(*path_hashes.last().unwrap(), root);
+
+        // Every fourth node of the binary path is an internal node of the nibble path, down to
+        // the leaf at the bottom of both.
+        let mut node_key = root;
+        let mut nibble_depth = 0;
+        while let Node::Internal(internal_node) =
+            TreeReader::<String, String>::get_node(&store, &node_key).unwrap()
+        {
+            assert_eq!(
+                path_hashes[proof.siblings().len() - 4 * nibble_depth],
+                node_key
+            );
+            node_key = internal_node
+                .child(Nibble::from(key_hash.nibble(nibble_depth)))
+                .unwrap()
+                .hash;
+            nibble_depth += 1;
+        }
+        assert_eq!(path_hashes[0], node_key);
+    }
+}
+
+#[test]
+fn test_get_siblings() {
+    let store = InMemoryNodeStore::default();
+    let smt: SMTree<String, String, _> = SMTree::from_pairs(
+        store.clone(),
+        (0..100).map(|i| (format!("key{}", i), format!("value{}", i))),
+    )
+    .unwrap();
+    for key in ["key42", "key100"] {
+        let key = key.to_string();
+        let (leaf, mut siblings): LeafWithSiblings<String, String> =
+            get_siblings::<_, _, _, Sha3TreeHasher>(
+                &store,
+                smt.root_hash(),
+                &key.clone().into_object(),
+            )
+            .unwrap();
+        let (_, proof) = smt.get_with_proof(key).unwrap();
+        // The siblings are in the order of the descent, the proof has them from the leaf up.
+        siblings.reverse();
+        assert_eq!(siblings, proof.siblings());
+        assert_eq!(
+            leaf.map(|leaf| (leaf.key_hash(), leaf.value_hash())),
+            proof.leaf()
+        );
+    }
+}
+
+#[test]
+fn test_nibble_path_bits() {
+    let path = NibblePath::new_odd(vec![0xa0]);
+    assert_eq!(path.num_bits(), 4);
+    assert_eq!(
+        (0..path.num_bits())
+            .map(|i| path.bit(i))
+            .collect::<Vec<_>>(),
+        vec![true, false, true, false]
+    );
+}
+
+#[test]
+fn test_nibble_path_common_prefix() {
+    let a = NibblePath::new(vec![0x12, 0x34]);
+    let b = NibblePath::new(vec![0x12, 0x3c]);
+    assert_eq!(common_prefix_nibble_len(&a, &b), 3);
+    // 0x4 and 0xc only differ in their first bit.
+    assert_eq!(common_prefix_bits_len(&a, &b), 12);
+    assert_eq!(common_prefix_nibble_len(&a, &NibblePath::new(vec![])), 0);
+}
+
+#[test]
+fn test_nibble_slice() {
+    let key_hash = HashValue::random();
+    let owned = NibblePath::new(key_hash.to_vec());
+    let borrowed: NibbleSlice = NibblePath::from_slice(key_hash.as_ref());
+    assert_eq!(borrowed.num_nibbles(), 64);
+    assert_eq!(borrowed.num_bits(), 256);
+    assert!(borrowed.nibbles().eq(owned.nibbles()));
+    assert!(borrowed.bits().eq(owned.bits()));
+    assert!(borrowed
+        .nibbles()
+        .enumerate()
+        .all(|(i, nibble)| u8::from(nibble) == key_hash.nibble(i)));
+    assert!((0..256).all(|i| borrowed.bit(i) == owned.bit(i)));
+    assert!(owned.as_nibble_slice().nibbles().eq(borrowed.nibbles()));
+}
+
+#[test]
+fn test_nibble_path_hex() {
+    for hex in ["", "7", "12ab", "12a"] {
+        assert_eq!(NibblePath::from_hex(hex).unwrap().to_hex(), hex);
+    }
+    assert_eq!(
+        NibblePath::from_hex("12a").unwrap(),
+        NibblePath::new_odd(vec![0x12, 0xa0])
+    );
+    assert!(NibblePath::from_hex("12g").is_err());
+    assert!(NibblePath::from_hex(&"0".repeat(65)).is_err());
+}
+
+/// A node store which can delete nodes, shared by its clones.
+#[derive(Clone, Default)]
+struct PrunableStore {
+    nodes: Arc<RwLock<HashMap<HashValue, Vec<u8>>>>,
+}
+
+impl NodeStore for PrunableStore {
+    fn get(&self, hash: &HashValue) -> Result<Option<Vec<u8>>> {
+        Ok(self.nodes.read().get(hash).cloned())
+    }
+
+    fn put(&self, key: HashValue, node: Vec<u8>) -> Result<()> {
+        self.nodes.write().insert(key, node);
+        Ok(())
+    }
+
+    fn write_nodes(&self, nodes: BTreeMap<HashValue, Vec<u8>>) -> Result<()> {
+        self.nodes.write().extend(nodes);
+        Ok(())
+    }
+}
+
+impl TreeWriter<String, String> for PrunableStore {
+    fn write_node_batch(&self, node_batch: &NodeBatch<String, String>) -> Result<()> {
+        for (node_key, node) in node_batch {
+            self.put(*node_key, node.encode()?)?;
+        }
+        Ok(())
+    }
+
+    fn delete_node_batch(&self, node_keys: &[NodeKey]) -> Result<()> {
+        let mut nodes = self.nodes.write();
+        for node_key in node_keys {
+            nodes.remove(node_key);
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_prune_stale_nodes() {
+    let store = PrunableStore::default();
+    let smt: SMTree<String, String, _> = SMTree::new(store.clone(), None);
+    let pairs = (0..32)
+        .map(|i| (format!("key{}", i), format!("value{}", i)))
+        .collect::<Vec<_>>();
+    let old_root = smt
+        .puts(
+            pairs
+                .iter()
+                .cloned()
+                .map(|(k, v)| (k, Some(v)))
+                .collect::<Vec<_>>(),
+        )
+        .unwrap();
+    let nodes_before = store.nodes.read().len();
+
+    let (new_root, stale_nodes) = smt
+        .puts_with_stale_nodes(vec![("key0".to_string(), Some("changed".to_string()))])
+        .unwrap();
+    assert!(!stale_nodes.is_empty());
+    assert!(stale_nodes
+        .iter()
+        .all(|index| index.stale_since_version == new_root));
+
+    let pruned = smt::prune(
+        &store,
+        stale_nodes
+            .iter()
+            .chain(stale_nodes.iter())
+            .map(|index| index.node_key),
+    )
+    .unwrap();
+    assert_eq!(pruned, stale_nodes.len());
+    assert_eq!(store.nodes.read().len(), nodes_before);
+    assert!(store.get(&old_root).unwrap().is_none());
+
+    let mut expected = pairs;
+    expected[0].1 = "changed".to_string();
+    let mut current = smt.iter(None).unwrap().collect::<Result<Vec<_>>>().unwrap();
+    current.sort();
+    expected.sort();
+    assert_eq!(current, expected);
+}